@@ -0,0 +1,153 @@
+use crate::http_battery::{HttpBattery, HttpBatteryConfig};
+use crate::victron::{VictronBattery, VictronConfig};
+use chrono::{DateTime, Utc};
+use eyre::eyre;
+use simulator_common::{Settings, time};
+use std::time::Duration;
+
+/// How long a real battery may take to be read for the first time.
+const FIRST_READING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The battery behind the FRBC front-end: the front-end describes the battery to the CEM and follows its instructions,
+/// and the backend is what actually charges and discharges.
+///
+/// The front-end only tells the backend what power to aim for, and asks it how full it is, so the same S2 logic drives
+/// the simulated battery and a real one.
+pub trait BatteryBackend: Send {
+    /// The state of charge of the battery, from 0.0 to 1.0, unless it isn't known right now.
+    fn state_of_charge(&mut self) -> Option<f64>;
+
+    /// The power the battery charges with in W, negative when it discharges, unless it isn't known right now.
+    fn power_w(&self) -> Option<f64>;
+
+    /// Has the battery charge with `power_w`, or discharge with a negative power; 0 W makes it idle.
+    fn set_power(&mut self, power_w: f64);
+
+    /// The simulated battery, if this is one, for the events of the timeline that change it, such as a jump in its
+    /// state of charge. A real battery doesn't take those.
+    fn simulated(&mut self) -> Option<&mut SimulatedBattery> {
+        None
+    }
+}
+
+/// A simulated battery, which charges and discharges exactly as it's told, with conversion losses, in simulated time.
+pub struct SimulatedBattery {
+    state_of_charge: f64,
+    capacity_wh: f64,
+    /// The fraction of the power that reaches the cells when charging.
+    charge_efficiency: f64,
+    /// The fraction of the power the cells deliver that comes out when discharging.
+    discharge_efficiency: f64,
+    power_w: f64,
+    last_updated: DateTime<Utc>,
+}
+
+impl SimulatedBattery {
+    pub fn new(
+        state_of_charge: f64,
+        capacity_wh: f64,
+        charge_efficiency: f64,
+        discharge_efficiency: f64,
+    ) -> Self {
+        Self {
+            state_of_charge,
+            capacity_wh,
+            charge_efficiency,
+            discharge_efficiency,
+            power_w: 0.0,
+            last_updated: time::now(),
+        }
+    }
+
+    /// Jumps to the given state of charge.
+    pub fn set_state_of_charge(&mut self, state_of_charge: f64) {
+        self.update();
+        self.state_of_charge = state_of_charge;
+    }
+
+    /// Changes the usable capacity, such as when a module fails; the state of charge stays the same fraction of it.
+    pub fn set_capacity_wh(&mut self, capacity_wh: f64) {
+        self.update();
+        self.capacity_wh = capacity_wh;
+    }
+
+    /// Charges or discharges the battery with its power for the time since the last update.
+    fn update(&mut self) {
+        let now = time::now();
+        let seconds = (now - self.last_updated).num_seconds() as f64;
+        self.last_updated = now;
+
+        let cell_power_w = if self.power_w >= 0.0 {
+            self.power_w * self.charge_efficiency
+        } else {
+            self.power_w / self.discharge_efficiency
+        };
+        self.state_of_charge += cell_power_w * seconds / 3600.0 / self.capacity_wh;
+        self.state_of_charge = self.state_of_charge.clamp(0.0, 1.0);
+    }
+}
+
+impl BatteryBackend for SimulatedBattery {
+    fn state_of_charge(&mut self) -> Option<f64> {
+        self.update();
+        Some(self.state_of_charge)
+    }
+
+    fn power_w(&self) -> Option<f64> {
+        Some(self.power_w)
+    }
+
+    fn set_power(&mut self, power_w: f64) {
+        self.update();
+        self.power_w = power_w;
+    }
+
+    fn simulated(&mut self) -> Option<&mut SimulatedBattery> {
+        Some(self)
+    }
+}
+
+/// A real battery to drive instead of the simulated one, chosen with `BATTERY_BACKEND`.
+#[derive(Clone)]
+pub enum BackendConfig {
+    /// A battery with a local HTTP API (`HTTP` or `SONNEN`).
+    Http(HttpBatteryConfig),
+    /// A Victron GX device, through its MQTT interface (`VICTRON`).
+    Victron(VictronConfig),
+}
+
+impl BackendConfig {
+    /// Reads the configuration of the real battery from the given settings, unless `BATTERY_BACKEND` is `SIMULATION`.
+    pub fn from_settings(settings: &impl Settings) -> eyre::Result<Option<Self>> {
+        match settings.get("BATTERY_BACKEND").as_deref() {
+            Some("SIMULATION") | None => Ok(None),
+            Some(backend @ ("HTTP" | "SONNEN")) => Ok(Some(Self::Http(
+                HttpBatteryConfig::from_settings(settings, backend)?,
+            ))),
+            Some("VICTRON") => Ok(Some(Self::Victron(VictronConfig::from_settings(settings)?))),
+            Some(other) => Err(eyre!(
+                "Invalid value for BATTERY_BACKEND ({other}); should be SIMULATION, HTTP, SONNEN or VICTRON"
+            )),
+        }
+    }
+
+    /// Starts reading the battery, and waits until it's been read, as the CEM plans with the state of charge we
+    /// report first.
+    pub async fn start(self) -> eyre::Result<Box<dyn BatteryBackend>> {
+        let battery: Option<Box<dyn BatteryBackend>> = match self {
+            Self::Http(config) => {
+                let mut battery = HttpBattery::start(config)?;
+                let read = battery.wait_for_reading(FIRST_READING_TIMEOUT).await;
+                read.then(|| Box::new(battery) as _)
+            }
+            Self::Victron(config) => {
+                let mut battery = VictronBattery::start(config);
+                let read = battery.wait_for_reading(FIRST_READING_TIMEOUT).await;
+                read.then(|| Box::new(battery) as _)
+            }
+        };
+        battery.ok_or_else(|| {
+            eyre!("Could not read the battery within {FIRST_READING_TIMEOUT:?}; see the warnings above")
+        })
+    }
+}
@@ -1,113 +1,186 @@
+use crate::backend::{BackendConfig, BatteryBackend, SimulatedBattery};
 use chrono::{DateTime, Utc};
-use eyre::{Context, Result, eyre};
+use eyre::Result;
 use maplit::hashmap;
 use s2energy::common::{
-    Commodity, CommodityQuantity, ControlType, Duration as S2Duration, Id, InstructionStatus,
-    InstructionStatusUpdate, Message, NumberRange, PowerRange, ResourceManagerDetails, Role,
-    Transition,
+    Commodity, CommodityQuantity, ControlType, Currency, Duration as S2Duration, Id,
+    InstructionStatus, InstructionStatusUpdate, Message, NumberRange, PowerRange,
+    ResourceManagerDetails, Transition,
 };
 use s2energy::frbc::{self, LeakageBehaviourElement, OperationMode, OperationModeElement};
-use s2energy::websockets_json::S2Connection;
-use std::collections::HashMap;
-use std::str::FromStr;
-use std::sync::LazyLock;
+use simulator_common::{
+    Connection, DeviceState, RmSimulator, Scheduler, Timeline, TimelineEvent, rm_details, time,
+};
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 
-pub async fn start_mock(mut connection: S2Connection) -> eyre::Result<()> {
-    let mut simulator = Simulator::new();
-
-    connection
-        .initialize_as_rm(ResourceManagerDetails {
-            available_control_types: vec![ControlType::FillRateBasedControl],
-            currency: None,
-            firmware_version: None,
-            instruction_processing_delay: s2energy::common::Duration(10),
-            manufacturer: None,
-            message_id: Id::generate(),
-            model: None,
-            name: None,
-            provides_forecast: true,
-            provides_power_measurement_types: vec![CommodityQuantity::ElectricPower3PhaseSymmetric],
-            resource_id: Id::generate(),
-            roles: vec![Role::new(
-                s2energy::common::Commodity::Electricity,
-                s2energy::common::RoleType::EnergyConsumer,
-            )],
-            serial_number: None,
-        })
-        .await
-        .wrap_err("Error communicating initial info with CEM")?;
-
-    // Send the initial info that the CEM needs: a system description, a leakage behaviour, and a forecast
-    connection
-        .send_message(simulator.system_description())
-        .await?;
-    connection
-        .send_message(simulator.leakage_behaviour())
-        .await?;
-    connection.send_message(simulator.forecast()).await?;
-
-    let mut update_timer = tokio::time::interval(Duration::from_secs(60));
-    loop {
-        tokio::select! {
-            message = connection.receive_message() => {
-                let message = message?;
-                let updates = simulator.process_message(&message)?;
-                for update in updates {
-                    connection.send_message(update).await?;
-                }
-            },
-
-            _ = update_timer.tick() => {
-                // Send a StorageStatus message every 60 seconds
-                let update = simulator.update();
-                connection.send_message(update).await?;
-            }
-
-            _ = tokio::signal::ctrl_c() => {
-                tracing::warn!("Received Ctrl-C signal, stopping simulation.");
-                break;
-            }
-        }
-    }
+/// Configuration options for the battery simulator.
+#[derive(Clone)]
+pub struct BatteryConfig {
+    /// If set, one of the battery modules will fail after this amount of time has passed.
+    pub module_failure_after: Option<Duration>,
+    /// The currency in which the running costs of the operation modes are expressed.
+    pub currency: Currency,
+    /// The wear costs for every kWh that is charged into or discharged from the battery cells, in `currency`.
+    pub wear_cost_per_kwh: f64,
+    /// How often the battery sends its storage status.
+    pub update_interval: Duration,
+    /// How often the battery sends a new usage forecast.
+    pub forecast_interval: Duration,
+    /// How much earlier or later than `forecast_interval` a usage forecast may be sent.
+    pub jitter: Duration,
+    /// The seed the jitter is derived from.
+    pub seed: u64,
+    /// Events that happen to the battery during the simulation, such as jumps in its state of charge.
+    pub timeline: Timeline,
+    /// The usable capacity of the battery, when all its modules work.
+    pub capacity_wh: f64,
+    /// The maximum power the battery charges and discharges with, when all its modules work.
+    pub max_power_w: f64,
+    /// If set, a real battery follows the instructions of the CEM, instead of the simulated one.
+    pub backend: Option<BackendConfig>,
+}
 
-    Ok(())
+/// Start the FRBC mock battery on the given S2 connection.
+pub async fn start_mock(connection: Connection, config: BatteryConfig) -> eyre::Result<()> {
+    let backend: Box<dyn BatteryBackend> = match config.backend.clone() {
+        Some(backend) => backend.start().await?,
+        None => Box::new(SimulatedBattery::new(
+            INITIAL_FILL_LEVEL,
+            config.capacity_wh,
+            CHARGE_EFFICIENCY,
+            DISCHARGE_EFFICIENCY,
+        )),
+    };
+    let simulator = Simulator::new(&config, backend);
+    simulator_common::run(connection, simulator, config.timeline).await
 }
 
 const CHARGE_EFFICIENCY: f64 = 1.0;
 const DISCHARGE_EFFICIENCY: f64 = 1.0;
-const CAPACITY_WH: f64 = 20_000.0;
+/// The default capacity of the battery, unless `BATTERY_CAPACITY_WH` says otherwise.
+pub const CAPACITY_WH: f64 = 20_000.0;
+/// The default maximum power of the battery, unless `BATTERY_MAX_POWER_W` says otherwise.
+pub const MAX_POWER_W: f64 = 5_000.0;
+/// The battery consists of a number of identical modules, which each contribute an equal share of capacity and power.
+const NUM_MODULES: u32 = 4;
 const LEAKAGE_W: f64 = 0.5;
 const INITIAL_FILL_LEVEL: f64 = 0.5;
 
-// Generate the IDs for our operation modes.
-// These should be kept consistent during the simulation, so that's why they're const here.
-static OPERATION_MODE_IDLE: LazyLock<Id> =
-    LazyLock::new(|| Id::from_str(&uuid::Uuid::new_v4().to_string()).unwrap());
-static OPERATION_MODE_CHARGE: LazyLock<Id> =
-    LazyLock::new(|| Id::from_str(&uuid::Uuid::new_v4().to_string()).unwrap());
-static OPERATION_MODE_DISCHARGE: LazyLock<Id> =
-    LazyLock::new(|| Id::from_str(&uuid::Uuid::new_v4().to_string()).unwrap());
-static ACTUATOR_1: LazyLock<Id> =
-    LazyLock::new(|| Id::from_str(&uuid::Uuid::new_v4().to_string()).unwrap());
-
 pub struct Simulator {
     pub operation_modes: HashMap<Id, OperationMode>,
+    /// The battery that charges and discharges as instructed, which is simulated unless a real one is configured.
+    backend: Box<dyn BatteryBackend>,
+    /// The state of charge at the latest update.
     fill_level: f64,
     active_operation_mode: Id,
     operation_mode_factor: f64,
-    simulation_start: DateTime<Utc>,
-    last_updated: DateTime<Utc>,
+    /// The capacity and power of the battery when all its modules work.
+    nominal_capacity_wh: f64,
+    nominal_max_power_w: f64,
+    /// The number of modules that are still functioning.
+    healthy_modules: u32,
+    /// The fraction of the nominal capacity of the cells that can still be used, which changes through the timeline.
+    capacity_factor: f64,
+    /// Until when the battery is out of order, if it is.
+    outage_until: Option<DateTime<Utc>>,
+    /// The IDs of the idle operation mode and the actuator stay the same during the simulation. The charge and discharge
+    /// modes get new IDs whenever their properties change (e.g. when a module fails).
+    operation_mode_idle: Id,
+    actuator_id: Id,
+    operation_mode_charge: Id,
+    operation_mode_discharge: Id,
+    /// IDs of operation modes that existed earlier in the session, but have been removed since.
+    retired_operation_modes: HashSet<Id>,
+    wear_cost_per_kwh: f64,
+    /// The currency in which the running costs of the operation modes are expressed.
+    currency: Currency,
+    /// When one of the battery modules will fail, if it hasn't yet.
+    module_failure_at: Option<DateTime<Utc>>,
+    /// How often we send our storage status.
+    update_interval: Duration,
+    /// When to send a new usage forecast; the initial messages include the first one.
+    scheduler: Scheduler,
 }
 
 impl Simulator {
-    pub fn new() -> Self {
+    pub fn new(config: &BatteryConfig, mut backend: Box<dyn BatteryBackend>) -> Self {
+        let operation_mode_idle = Id::generate();
+        let mut simulator = Self {
+            fill_level: backend.state_of_charge().unwrap_or(INITIAL_FILL_LEVEL),
+            backend,
+            operation_modes: HashMap::new(),
+            active_operation_mode: operation_mode_idle.clone(),
+            operation_mode_idle,
+            actuator_id: Id::generate(),
+            operation_mode_factor: 0.5,
+            nominal_capacity_wh: config.capacity_wh,
+            nominal_max_power_w: config.max_power_w,
+            healthy_modules: NUM_MODULES,
+            capacity_factor: 1.0,
+            outage_until: None,
+            operation_mode_charge: Id::generate(),
+            operation_mode_discharge: Id::generate(),
+            retired_operation_modes: HashSet::new(),
+            wear_cost_per_kwh: config.wear_cost_per_kwh,
+            currency: config.currency,
+            module_failure_at: config.module_failure_after.map(|delay| time::now() + delay),
+            update_interval: config.update_interval,
+            scheduler: Scheduler::new(config.update_interval)
+                .every_after("forecast", config.forecast_interval)
+                .with_jitter(config.jitter, config.seed),
+        };
+        simulator.operation_modes = simulator.build_operation_modes();
+        simulator
+    }
+
+    /// The capacity of the battery, taking into account any failed modules and capacity changes.
+    fn capacity_wh(&self) -> f64 {
+        self.nominal_capacity_wh * self.capacity_factor * self.healthy_modules as f64
+            / NUM_MODULES as f64
+    }
+
+    /// The maximum (dis)charge power of the battery, taking into account any failed modules.
+    fn max_power_w(&self) -> f64 {
+        self.nominal_max_power_w * self.healthy_modules as f64 / NUM_MODULES as f64
+    }
+
+    /// The power the active operation mode and factor ask for, in W, negative when discharging.
+    fn power_w(&self) -> f64 {
+        let power_range =
+            &self.operation_modes[&self.active_operation_mode].elements[0].power_ranges[0];
+        power_range.start_of_range
+            + (power_range.end_of_range - power_range.start_of_range) * self.operation_mode_factor
+    }
+
+    /// Has the backend follow the active operation mode and factor, after they changed.
+    fn apply_operation_mode(&mut self) {
+        let power_w = self.power_w();
+        self.backend.set_power(power_w);
+    }
+
+    /// The running costs (per second) of charging or discharging the battery cells with the given power range.
+    ///
+    /// Running costs in S2 express uncertainty and aren't linked to the operation mode factor, so this spans the costs
+    /// of the lowest and the highest power in the range.
+    fn wear_costs(&self, cell_power_range_w: (f64, f64)) -> NumberRange {
+        let cost_per_ws = self.wear_cost_per_kwh / 1000. / 3600.;
+        NumberRange {
+            start_of_range: cell_power_range_w.0 * cost_per_ws,
+            end_of_range: cell_power_range_w.1 * cost_per_ws,
+        }
+    }
+
+    fn build_operation_modes(&self) -> HashMap<Id, OperationMode> {
+        let capacity = self.capacity_wh();
+        let max_power = self.max_power_w();
+
         // Define the three operation modes: idle, charging, discharging.
         let operation_mode_idle = OperationMode {
             abnormal_condition_only: false,
             diagnostic_label: Some("Idle".into()),
             elements: vec![OperationModeElement {
-                running_costs: None,
+                running_costs: Some(self.wear_costs((0., 0.))),
                 fill_rate: NumberRange {
                     start_of_range: 0.0,
                     end_of_range: 0.0,
@@ -122,17 +195,21 @@ impl Simulator {
                     end_of_range: 0.,
                 }],
             }],
-            id: OPERATION_MODE_IDLE.clone(),
+            id: self.operation_mode_idle.clone(),
         };
 
         let operation_mode_charge = OperationMode {
             abnormal_condition_only: false,
             diagnostic_label: Some("Charging battery".into()),
             elements: vec![OperationModeElement {
-                running_costs: None,
+                // Only the energy that actually reaches the cells causes wear
+                running_costs: Some(self.wear_costs((
+                    0.5 * max_power * CHARGE_EFFICIENCY,
+                    max_power * CHARGE_EFFICIENCY,
+                ))),
                 fill_rate: NumberRange {
-                    start_of_range: 0.5 * CHARGE_EFFICIENCY * ((5000.0 / CAPACITY_WH) / 3600.),
-                    end_of_range: CHARGE_EFFICIENCY * (5000.0 / CAPACITY_WH / 3600.),
+                    start_of_range: 0.5 * CHARGE_EFFICIENCY * ((max_power / capacity) / 3600.),
+                    end_of_range: CHARGE_EFFICIENCY * (max_power / capacity / 3600.),
                 },
                 fill_level_range: NumberRange {
                     start_of_range: 0.0,
@@ -140,21 +217,26 @@ impl Simulator {
                 },
                 power_ranges: vec![PowerRange {
                     commodity_quantity: CommodityQuantity::ElectricPower3PhaseSymmetric,
-                    start_of_range: 0.5 * 5000.,
-                    end_of_range: 5000.,
+                    start_of_range: 0.5 * max_power,
+                    end_of_range: max_power,
                 }],
             }],
-            id: OPERATION_MODE_CHARGE.clone(),
+            id: self.operation_mode_charge.clone(),
         };
 
         let operation_mode_discharge = OperationMode {
             abnormal_condition_only: false,
             diagnostic_label: Some("Discharging battery".into()),
             elements: vec![OperationModeElement {
-                running_costs: None,
+                // The cells need to deliver more energy than we output, due to conversion losses
+                running_costs: Some(self.wear_costs((
+                    0.5 * max_power / DISCHARGE_EFFICIENCY,
+                    max_power / DISCHARGE_EFFICIENCY,
+                ))),
+                // Discharging empties the battery, and faster than the power we output
                 fill_rate: NumberRange {
-                    start_of_range: DISCHARGE_EFFICIENCY * ((5000.0 / CAPACITY_WH) / 3600.),
-                    end_of_range: 0.5 * DISCHARGE_EFFICIENCY * (5000.0 / CAPACITY_WH / 3600.),
+                    start_of_range: -(max_power / DISCHARGE_EFFICIENCY / capacity / 3600.),
+                    end_of_range: -0.5 * (max_power / DISCHARGE_EFFICIENCY / capacity / 3600.),
                 },
                 fill_level_range: NumberRange {
                     start_of_range: 0.0,
@@ -162,24 +244,129 @@ impl Simulator {
                 },
                 power_ranges: vec![PowerRange {
                     commodity_quantity: CommodityQuantity::ElectricPower3PhaseSymmetric,
-                    start_of_range: -5000.,
-                    end_of_range: 0.5 * -5000.,
+                    start_of_range: -max_power,
+                    end_of_range: 0.5 * -max_power,
                 }],
             }],
-            id: OPERATION_MODE_DISCHARGE.clone(),
+            id: self.operation_mode_discharge.clone(),
         };
 
-        Self {
-            fill_level: INITIAL_FILL_LEVEL,
-            operation_modes: hashmap! {
-                OPERATION_MODE_IDLE.clone() => operation_mode_idle,
-                OPERATION_MODE_CHARGE.clone() => operation_mode_charge,
-                OPERATION_MODE_DISCHARGE.clone() => operation_mode_discharge,
-            },
-            active_operation_mode: OPERATION_MODE_IDLE.clone(),
-            operation_mode_factor: 0.5,
-            simulation_start: Utc::now(),
-            last_updated: Utc::now(),
+        hashmap! {
+            self.operation_mode_idle.clone() => operation_mode_idle,
+            self.operation_mode_charge.clone() => operation_mode_charge,
+            self.operation_mode_discharge.clone() => operation_mode_discharge,
+        }
+    }
+
+    /// Simulate the failure of one of the battery modules.
+    ///
+    /// This shrinks the capacity and power ranges of the battery. Because the charge and discharge operation modes change,
+    /// they are replaced by new operation modes with new IDs. If the battery was charging or discharging, it falls back to
+    /// idle until the CEM sends a new instruction. Returns the messages that should be sent to inform the CEM.
+    pub fn fail_module(&mut self) -> Vec<Message> {
+        // Make sure the fill level is up-to-date before changing the battery's properties
+        let _ = self.update();
+
+        if self.healthy_modules <= 1 {
+            tracing::warn!("Not simulating module failure: only one battery module left");
+            return vec![];
+        }
+        self.healthy_modules -= 1;
+        tracing::warn!(
+            "Simulating battery module failure; {} of {NUM_MODULES} modules left ({} Wh, {} W)",
+            self.healthy_modules,
+            self.capacity_wh(),
+            self.max_power_w()
+        );
+        self.replace_operation_modes()
+    }
+
+    /// Simulate a change of the usable capacity of the battery cells, to the given fraction of their nominal capacity.
+    ///
+    /// Like a module failure, this changes the fill rates of the charge and discharge operation modes, so they are
+    /// replaced as well. Returns the messages that should be sent to inform the CEM.
+    pub fn change_capacity(&mut self, capacity_factor: f64) -> Vec<Message> {
+        if capacity_factor <= 0.0 {
+            tracing::warn!(
+                "Not simulating a capacity change: the battery needs some capacity left"
+            );
+            return vec![];
+        }
+        // Make sure the fill level is up-to-date before changing the battery's properties
+        let _ = self.update();
+
+        self.capacity_factor = capacity_factor;
+        tracing::warn!(
+            "Simulating a capacity change to {:.0}% of the nominal capacity ({} Wh)",
+            capacity_factor * 100.,
+            self.capacity_wh()
+        );
+        self.replace_operation_modes()
+    }
+
+    /// Replaces the charge and discharge operation modes with new ones with new IDs, after the battery's properties
+    /// changed. If the battery was charging or discharging, it falls back to idle until the CEM sends a new
+    /// instruction. Returns the messages that should be sent to inform the CEM.
+    fn replace_operation_modes(&mut self) -> Vec<Message> {
+        // Replace the charge and discharge modes with new ones
+        self.retired_operation_modes
+            .insert(self.operation_mode_charge.clone());
+        self.retired_operation_modes
+            .insert(self.operation_mode_discharge.clone());
+        self.operation_mode_charge = Id::generate();
+        self.operation_mode_discharge = Id::generate();
+        self.operation_modes = self.build_operation_modes();
+        let capacity_wh = self.capacity_wh();
+        if let Some(battery) = self.backend.simulated() {
+            battery.set_capacity_wh(capacity_wh);
+        }
+
+        let mut messages: Vec<Message> = vec![
+            self.system_description().into(),
+            self.leakage_behaviour().into(),
+        ];
+
+        if !self
+            .operation_modes
+            .contains_key(&self.active_operation_mode)
+        {
+            messages.push(self.switch_to_idle().into());
+        }
+
+        messages.push(frbc::StorageStatus::new(self.fill_level).into());
+        messages
+    }
+
+    /// Simulate an outage: the battery stops (dis)charging and rejects instructions for the given time.
+    pub fn start_outage(&mut self, duration: Duration) -> Vec<Message> {
+        // Make sure the fill level is up-to-date before the battery stops
+        let storage_status = self.update();
+        self.outage_until = Some(time::now() + duration);
+        tracing::warn!("Simulating an outage of {duration:?}");
+
+        let mut messages = vec![];
+        if self.active_operation_mode != self.operation_mode_idle {
+            messages.push(self.switch_to_idle().into());
+        }
+        messages.push(storage_status.into());
+        messages
+    }
+
+    /// Switches to the idle operation mode, and returns the actuator status that tells the CEM about it.
+    fn switch_to_idle(&mut self) -> frbc::ActuatorStatus {
+        let previous_operation_mode = std::mem::replace(
+            &mut self.active_operation_mode,
+            self.operation_mode_idle.clone(),
+        );
+        self.operation_mode_factor = 0.0;
+        self.apply_operation_mode();
+        frbc::ActuatorStatus {
+            active_operation_mode_id: self.active_operation_mode.clone(),
+            actuator_id: self.actuator_id.clone(),
+            message_id: Id::generate(),
+            operation_mode_factor: self.operation_mode_factor,
+            previous_operation_mode_id: Some(previous_operation_mode),
+            transition_timestamp: Some(time::now()),
         }
     }
 
@@ -199,12 +386,8 @@ impl Simulator {
 
         let actuator_description = frbc::ActuatorDescription {
             diagnostic_label: None,
-            id: ACTUATOR_1.clone(),
-            operation_modes: self
-                .operation_modes
-                .iter()
-                .map(|(_, mode)| mode.clone())
-                .collect(),
+            id: self.actuator_id.clone(),
+            operation_modes: self.operation_modes.values().cloned().collect(),
             supported_commodities: vec![Commodity::Electricity],
             timers: vec![],
             transitions: vec![
@@ -212,20 +395,20 @@ impl Simulator {
                 Transition::new(
                     false,
                     vec![],
-                    OPERATION_MODE_IDLE.clone(),
+                    self.operation_mode_idle.clone(),
                     Id::generate(),
                     vec![],
-                    OPERATION_MODE_CHARGE.clone(),
+                    self.operation_mode_charge.clone(),
                     None,
                     None,
                 ),
                 Transition::new(
                     false,
                     vec![],
-                    OPERATION_MODE_CHARGE.clone(),
+                    self.operation_mode_charge.clone(),
                     Id::generate(),
                     vec![],
-                    OPERATION_MODE_IDLE.clone(),
+                    self.operation_mode_idle.clone(),
                     None,
                     None,
                 ),
@@ -233,39 +416,34 @@ impl Simulator {
                 Transition::new(
                     false,
                     vec![],
-                    OPERATION_MODE_IDLE.clone(),
+                    self.operation_mode_idle.clone(),
                     Id::generate(),
                     vec![],
-                    OPERATION_MODE_DISCHARGE.clone(),
+                    self.operation_mode_discharge.clone(),
                     None,
                     None,
                 ),
                 Transition::new(
                     false,
                     vec![],
-                    OPERATION_MODE_DISCHARGE.clone(),
+                    self.operation_mode_discharge.clone(),
                     Id::generate(),
                     vec![],
-                    OPERATION_MODE_IDLE.clone(),
+                    self.operation_mode_idle.clone(),
                     None,
                     None,
                 ),
             ],
         };
 
-        frbc::SystemDescription::new(vec![actuator_description], storage_description, Utc::now())
+        frbc::SystemDescription::new(vec![actuator_description], storage_description, time::now())
     }
 
     pub fn update(&mut self) -> frbc::StorageStatus {
-        // Update the fill level based on our current operation mode
-        let delta_time = Utc::now() - self.last_updated;
-        self.last_updated = Utc::now();
-
-        let fill_rates = &self.operation_modes[&self.active_operation_mode].elements[0].fill_rate;
-        let fill_rate = fill_rates.start_of_range
-            + (fill_rates.end_of_range - fill_rates.start_of_range) * self.operation_mode_factor;
-        self.fill_level += fill_rate * delta_time.num_seconds() as f64;
-        self.fill_level = self.fill_level.clamp(0.0, 1.0);
+        // Take the fill level from the battery; if a real one couldn't be read lately, we report the last one we know.
+        if let Some(state_of_charge) = self.backend.state_of_charge() {
+            self.fill_level = state_of_charge;
+        }
 
         frbc::StorageStatus::new(self.fill_level)
     }
@@ -277,10 +455,10 @@ impl Simulator {
                     start_of_range: 0.0,
                     end_of_range: 1.0,
                 },
-                leakage_rate: (LEAKAGE_W / CAPACITY_WH) / 3600.,
+                leakage_rate: (LEAKAGE_W / self.capacity_wh()) / 3600.,
             }],
             message_id: Id::generate(),
-            valid_from: Utc::now(),
+            valid_from: time::now(),
         }
     }
 
@@ -300,53 +478,119 @@ impl Simulator {
                 };
                 24
             ],
-            Utc::now(),
+            time::now(),
         )
     }
+}
+
+impl RmSimulator for Simulator {
+    fn resource_manager_details(&self) -> ResourceManagerDetails {
+        ResourceManagerDetails {
+            currency: Some(self.currency),
+            ..rm_details::battery()
+        }
+    }
+
+    fn initial_messages(&mut self, _control_type: ControlType) -> Result<Vec<Message>> {
+        // Send the initial info that the CEM needs: a system description, a leakage behaviour, a forecast, and our
+        // current status
+        let actuator_status = frbc::ActuatorStatus {
+            active_operation_mode_id: self.active_operation_mode.clone(),
+            actuator_id: self.actuator_id.clone(),
+            message_id: Id::generate(),
+            operation_mode_factor: self.operation_mode_factor,
+            previous_operation_mode_id: None,
+            transition_timestamp: None,
+        };
+        Ok(vec![
+            self.system_description().into(),
+            self.leakage_behaviour().into(),
+            self.forecast().into(),
+            actuator_status.into(),
+            self.update().into(),
+        ])
+    }
 
-    pub fn process_message(&mut self, msg: &Message) -> Result<Vec<Message>> {
+    fn deactivate_control_type(&mut self) {
+        // Account for the time spent in the current operation mode before going back to idle.
+        self.update();
+        self.active_operation_mode = self.operation_mode_idle.clone();
+        self.apply_operation_mode();
+    }
+
+    fn on_frbc_instruction(&mut self, instruction: &frbc::Instruction) -> Result<Vec<Message>> {
         // Ensure our fill level is always up-to-date
         let storage_status = self.update();
 
         let last_operation_mode = self.active_operation_mode.clone();
-        if let Message::FrbcInstruction(instruction) = msg {
+        if self.outage_until.is_some() {
+            tracing::warn!("Rejecting instruction during an outage");
+            let status = InstructionStatusUpdate {
+                instruction_id: instruction.id.clone(),
+                message_id: Id::generate(),
+                status_type: InstructionStatus::Rejected,
+                timestamp: time::now(),
+            };
+            return Ok(vec![status.into()]);
+        } else if instruction.actuator_id != self.actuator_id
+            || !(0.0..=1.0).contains(&instruction.operation_mode_factor)
+        {
+            // The CEM sent an instruction that can't be right, such as a factor of 1.5 or NaN
+            tracing::warn!(
+                "Rejecting instruction for actuator {:?} with operation mode factor {}",
+                instruction.actuator_id,
+                instruction.operation_mode_factor
+            );
+            let status = InstructionStatusUpdate {
+                instruction_id: instruction.id.clone(),
+                message_id: Id::generate(),
+                status_type: InstructionStatus::Rejected,
+                timestamp: time::now(),
+            };
+            return Ok(vec![status.into()]);
+        } else if self
+            .operation_modes
+            .contains_key(&instruction.operation_mode)
+        {
+            // Switch operation modes and adjust the operation mode factor
+            self.active_operation_mode = instruction.operation_mode.clone();
+            self.operation_mode_factor = instruction.operation_mode_factor;
+            self.apply_operation_mode();
+        } else {
+            // CEM requested a nonexistent operation mode, so report back an error
             if self
-                .operation_modes
-                .contains_key(&instruction.operation_mode)
+                .retired_operation_modes
+                .contains(&instruction.operation_mode)
             {
-                // Switch operation modes and adjust the operation mode factor
-                self.active_operation_mode = instruction.operation_mode.clone();
-                self.operation_mode_factor = instruction.operation_mode_factor;
-            } else {
-                // CEM requested a nonexistent operation mode, so report back an error
-                let status = InstructionStatusUpdate {
-                    instruction_id: msg.id().unwrap(),
-                    message_id: Id::generate(),
-                    status_type: InstructionStatus::Rejected,
-                    timestamp: Utc::now(),
-                };
-                return Ok(vec![status.into()]);
+                tracing::warn!(
+                    "Rejecting instruction for operation mode {:?}, which is no longer available",
+                    instruction.operation_mode
+                );
             }
-        } else {
-            // Ignore any messagess we get that aren't FRBC.Instruction
-            return Ok(vec![]);
+            let status = InstructionStatusUpdate {
+                instruction_id: instruction.id.clone(),
+                message_id: Id::generate(),
+                status_type: InstructionStatus::Rejected,
+                timestamp: time::now(),
+            };
+            return Ok(vec![status.into()]);
         }
 
         // Send the CEM back our current status after switching operation modes
         let instruction_status = InstructionStatusUpdate {
-            instruction_id: msg.id().unwrap(),
+            instruction_id: instruction.id.clone(),
             message_id: Id::generate(),
             status_type: InstructionStatus::Succeeded,
-            timestamp: Utc::now(),
+            timestamp: time::now(),
         };
 
         let actuator_status = frbc::ActuatorStatus {
             active_operation_mode_id: self.active_operation_mode.clone(),
-            actuator_id: ACTUATOR_1.clone(),
+            actuator_id: self.actuator_id.clone(),
             message_id: Id::generate(),
             operation_mode_factor: self.operation_mode_factor,
             previous_operation_mode_id: Some(last_operation_mode),
-            transition_timestamp: Some(Utc::now()),
+            transition_timestamp: Some(time::now()),
         };
 
         Ok(vec![
@@ -355,4 +599,67 @@ impl Simulator {
             storage_status.into(),
         ])
     }
+
+    async fn periodic_update(&mut self) -> Result<Vec<Message>> {
+        if self.module_failure_at.is_some_and(|at| time::now() >= at) {
+            // Simulate a failing module: the CEM needs a new system description to know what we can still do.
+            self.module_failure_at = None;
+            return Ok(self.fail_module());
+        }
+        if self.outage_until.is_some_and(|until| time::now() >= until) {
+            tracing::info!("The outage is over, the battery accepts instructions again");
+            self.outage_until = None;
+        }
+
+        // Send a StorageStatus message every update (every minute by default), and a new forecast every hour by default
+        let due = self.scheduler.tick();
+        let mut messages = vec![self.update().into()];
+        if due.contains(&"forecast") {
+            messages.push(self.forecast().into());
+        }
+        Ok(messages)
+    }
+
+    fn update_interval(&self) -> Duration {
+        self.update_interval
+    }
+
+    fn device_state(&self) -> DeviceState {
+        let operation_mode = &self.operation_modes[&self.active_operation_mode];
+        DeviceState {
+            state_of_charge: Some(self.fill_level),
+            operation_mode: operation_mode.diagnostic_label.clone(),
+            // A real battery may not deliver exactly what it's asked for, so we show what it measures.
+            power_w: self.backend.power_w(),
+            rated_power_w: Some(self.max_power_w()),
+            ..DeviceState::default()
+        }
+    }
+
+    fn handle_event(&mut self, event: &TimelineEvent) -> Result<Vec<Message>> {
+        match *event {
+            TimelineEvent::StateOfCharge { .. } | TimelineEvent::Capacity { .. }
+                if self.backend.simulated().is_none() =>
+            {
+                tracing::warn!(
+                    "Ignoring timeline event {event:?}, which can't happen to a real battery"
+                );
+                Ok(vec![])
+            }
+            TimelineEvent::StateOfCharge { value } => {
+                if let Some(battery) = self.backend.simulated() {
+                    battery.set_state_of_charge(value);
+                }
+                Ok(vec![self.update().into()])
+            }
+            TimelineEvent::Capacity { value } => Ok(self.change_capacity(value)),
+            TimelineEvent::Outage { duration } => Ok(self.start_outage(duration)),
+            _ => {
+                tracing::warn!(
+                    "Ignoring timeline event {event:?}, which doesn't apply to a battery"
+                );
+                Ok(vec![])
+            }
+        }
+    }
 }
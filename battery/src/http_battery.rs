@@ -0,0 +1,331 @@
+use crate::backend::BatteryBackend;
+use eyre::{Context, OptionExt, eyre};
+use serde_json::{Value, json};
+use simulator_common::Settings;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// A real home battery with a local HTTP API, configured with the `BATTERY_*` settings.
+#[derive(Clone)]
+pub struct HttpBatteryConfig {
+    /// The base URL of the API, such as `http://192.168.1.30`.
+    url: String,
+    /// The token the API asks for, if any.
+    token: Option<String>,
+    api: Api,
+    /// How often the battery is read.
+    poll_interval: Duration,
+}
+
+/// How the API of the battery works.
+#[derive(Clone)]
+enum Api {
+    /// A JSON API of your own, such as a small service in front of the battery: `GET` on the status path returns the
+    /// state of charge and power, and `POST` on the setpoint path takes `{"power_w": ...}`, positive to charge.
+    Generic {
+        status_path: String,
+        state_of_charge: Field,
+        power: Field,
+        setpoint_path: String,
+    },
+    /// The JSON API (v2) of a sonnenBatterie, which is put in manual mode to take setpoints.
+    Sonnen,
+}
+
+/// Where a number is in the JSON the API returns, and what a unit of it is worth.
+#[derive(Clone)]
+struct Field {
+    /// A JSON pointer, such as `/battery/soc`.
+    pointer: String,
+    scale: f64,
+}
+
+impl Field {
+    fn read(&self, status: &Value) -> eyre::Result<f64> {
+        let value = status
+            .pointer(&self.pointer)
+            .ok_or_else(|| eyre!("The status of the battery has no {}", self.pointer))?;
+        let number = match value {
+            Value::String(text) => text.parse().ok(),
+            value => value.as_f64(),
+        };
+        let number = number.ok_or_else(|| {
+            eyre!(
+                "{} in the status of the battery is not a number",
+                self.pointer
+            )
+        })?;
+        Ok(number * self.scale)
+    }
+}
+
+impl HttpBatteryConfig {
+    /// Reads the configuration of the battery from the given settings, for `backend` `HTTP` or `SONNEN`.
+    pub fn from_settings(settings: &impl Settings, backend: &str) -> eyre::Result<Self> {
+        let api = match backend {
+            "SONNEN" => Api::Sonnen,
+            _ => {
+                let field = |name: &str, default_pointer: &str| -> eyre::Result<Field> {
+                    let scale = settings.get_or(&format!("{name}_SCALE"), 1.0)?;
+                    if scale == 0.0 {
+                        return Err(eyre!("{name}_SCALE should not be 0"));
+                    }
+                    Ok(Field {
+                        pointer: settings
+                            .get(&format!("{name}_POINTER"))
+                            .unwrap_or_else(|| default_pointer.into()),
+                        scale,
+                    })
+                };
+                Api::Generic {
+                    status_path: settings
+                        .get("BATTERY_STATUS_PATH")
+                        .unwrap_or_else(|| "/status".into()),
+                    state_of_charge: field("BATTERY_SOC", "/state_of_charge")?,
+                    power: field("BATTERY_POWER", "/power_w")?,
+                    setpoint_path: settings
+                        .get("BATTERY_SETPOINT_PATH")
+                        .unwrap_or_else(|| "/setpoint".into()),
+                }
+            }
+        };
+
+        let url = settings.get("BATTERY_API_URL").ok_or_else(|| {
+            eyre!("BATTERY_BACKEND is {backend}, but the URL of the battery is not set in BATTERY_API_URL")
+        })?;
+        let token = settings.get("BATTERY_API_TOKEN");
+        if matches!(api, Api::Sonnen) && token.is_none() {
+            return Err(eyre!(
+                "BATTERY_BACKEND is SONNEN, but the API token of the battery is not set in BATTERY_API_TOKEN"
+            ));
+        }
+        let poll_interval = Duration::from_secs(settings.get_or("BATTERY_POLL_INTERVAL", 5)?);
+        if poll_interval.is_zero() {
+            return Err(eyre!("BATTERY_POLL_INTERVAL should be at least 1 second"));
+        }
+
+        Ok(Self {
+            url: url.trim_end_matches('/').into(),
+            token,
+            api,
+            poll_interval,
+        })
+    }
+
+    fn request(
+        &self,
+        client: &reqwest::Client,
+        method: reqwest::Method,
+        path: &str,
+    ) -> reqwest::RequestBuilder {
+        let request = client.request(method, format!("{}{path}", self.url));
+        match (&self.api, &self.token) {
+            (Api::Sonnen, Some(token)) => request.header("Auth-Token", token),
+            (Api::Generic { .. }, Some(token)) => request.bearer_auth(token),
+            (_, None) => request,
+        }
+    }
+
+    /// Gets the battery ready to take setpoints, which a sonnenBatterie only does in manual mode.
+    async fn prepare(&self, client: &reqwest::Client) -> eyre::Result<()> {
+        if let Api::Sonnen = self.api {
+            self.request(client, reqwest::Method::PUT, "/api/v2/configurations")
+                .json(&json!({"EM_OperatingMode": "1"}))
+                .send()
+                .await?
+                .error_for_status()
+                .wrap_err("Could not put the battery in manual mode")?;
+        }
+        Ok(())
+    }
+
+    /// Reads the state of charge, from 0.0 to 1.0, and the power the battery charges with, in W.
+    async fn read(&self, client: &reqwest::Client) -> eyre::Result<(f64, f64)> {
+        let path = match &self.api {
+            Api::Generic { status_path, .. } => status_path.as_str(),
+            Api::Sonnen => "/api/v2/status",
+        };
+        let status: Value = self
+            .request(client, reqwest::Method::GET, path)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match &self.api {
+            Api::Generic {
+                state_of_charge,
+                power,
+                ..
+            } => Ok((state_of_charge.read(&status)?, power.read(&status)?)),
+            // The sonnenBatterie gives its state of charge in percent, and its power as positive when it discharges.
+            Api::Sonnen => {
+                let state_of_charge = status["USOC"]
+                    .as_f64()
+                    .ok_or_eyre("The status of the battery has no USOC")?;
+                let power_w = status["Pac_total_W"]
+                    .as_f64()
+                    .ok_or_eyre("The status of the battery has no Pac_total_W")?;
+                Ok((state_of_charge / 100.0, -power_w))
+            }
+        }
+    }
+
+    /// Has the battery charge with `power_w`, or discharge with a negative power.
+    async fn write_setpoint(&self, client: &reqwest::Client, power_w: f64) -> eyre::Result<()> {
+        let request = match &self.api {
+            Api::Generic { setpoint_path, .. } => self
+                .request(client, reqwest::Method::POST, setpoint_path)
+                .json(&json!({"power_w": power_w})),
+            Api::Sonnen => {
+                let direction = if power_w >= 0.0 {
+                    "charge"
+                } else {
+                    "discharge"
+                };
+                let path = format!("/api/v2/setpoint/{direction}/{:.0}", power_w.abs());
+                self.request(client, reqwest::Method::POST, &path)
+            }
+        };
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// A reading of the battery.
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    state_of_charge: f64,
+    /// The power the battery charges with, in W, negative when it discharges.
+    power_w: f64,
+    at: Instant,
+}
+
+/// A real home battery, read and controlled through its local HTTP API.
+///
+/// The battery is polled in the background, so the front-end can read the latest state and set the power without
+/// waiting for the battery. When the battery can't be reached, it's tried again at the next poll, and its setpoint is
+/// written again.
+pub struct HttpBattery {
+    readings: watch::Receiver<Option<Reading>>,
+    setpoint: watch::Sender<f64>,
+    poll_interval: Duration,
+}
+
+impl HttpBattery {
+    /// Starts polling the battery, which is told to be idle until the CEM instructs it; this stops when the
+    /// `HttpBattery` is dropped.
+    pub fn start(config: HttpBatteryConfig) -> eyre::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .wrap_err("Could not set up HTTP client")?;
+        let (readings_sender, readings) = watch::channel(None);
+        let (setpoint, setpoint_receiver) = watch::channel(0.0);
+        let poll_interval = config.poll_interval;
+        tokio::spawn(poll(config, client, readings_sender, setpoint_receiver));
+
+        Ok(Self {
+            readings,
+            setpoint,
+            poll_interval,
+        })
+    }
+
+    /// Waits until the battery has been read, so the CEM gets its real state of charge from the start. Returns
+    /// whether it was read within `timeout`.
+    pub async fn wait_for_reading(&mut self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, self.readings.wait_for(Option::is_some))
+            .await
+            .is_ok_and(|read| read.is_ok())
+    }
+
+    /// The latest reading, unless there hasn't been one for a few polls.
+    fn reading(&self) -> Option<Reading> {
+        let reading = (*self.readings.borrow())?;
+        (reading.at.elapsed() < 3 * self.poll_interval).then_some(reading)
+    }
+}
+
+impl BatteryBackend for HttpBattery {
+    fn state_of_charge(&mut self) -> Option<f64> {
+        self.reading().map(|reading| reading.state_of_charge)
+    }
+
+    fn power_w(&self) -> Option<f64> {
+        self.reading().map(|reading| reading.power_w)
+    }
+
+    fn set_power(&mut self, power_w: f64) {
+        self.setpoint.send_if_modified(|setpoint| {
+            let modified = *setpoint != power_w;
+            *setpoint = power_w;
+            modified
+        });
+    }
+}
+
+/// Reads the battery every poll interval, and writes its setpoint whenever that changes.
+async fn poll(
+    config: HttpBatteryConfig,
+    client: reqwest::Client,
+    readings: watch::Sender<Option<Reading>>,
+    mut setpoint: watch::Receiver<f64>,
+) {
+    // Whether the battery is ready to take setpoints, since it was last reachable.
+    let mut prepared = false;
+    // The setpoint the battery has, if it's been written since it was last reachable.
+    let mut written_setpoint = None;
+    let mut interval = tokio::time::interval(config.poll_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            changed = setpoint.changed() => if changed.is_err() {
+                // The simulator stopped.
+                return;
+            },
+        }
+
+        let power_w = *setpoint.borrow_and_update();
+        let result = async {
+            if !prepared {
+                config.prepare(&client).await?;
+                tracing::info!("Connected to the battery at {}", config.url);
+                prepared = true;
+            }
+            if written_setpoint != Some(power_w) {
+                tracing::info!("Setting the battery to {power_w:.0} W");
+                config
+                    .write_setpoint(&client, power_w)
+                    .await
+                    .wrap_err("Could not write the setpoint of the battery")?;
+                written_setpoint = Some(power_w);
+            }
+            let (state_of_charge, power_w) = config
+                .read(&client)
+                .await
+                .wrap_err("Could not read the status of the battery")?;
+            tracing::debug!(
+                "The battery is at {:.1}% and charges with {power_w:.0} W",
+                state_of_charge * 100.0
+            );
+            readings.send_replace(Some(Reading {
+                state_of_charge: state_of_charge.clamp(0.0, 1.0),
+                power_w,
+                at: Instant::now(),
+            }));
+            eyre::Ok(())
+        }
+        .await;
+
+        if let Err(error) = result {
+            // The battery may have restarted, so prepare it and write the setpoint again at the next poll.
+            tracing::warn!(
+                "No connection with the battery at {}: {error:#}",
+                config.url
+            );
+            prepared = false;
+            written_setpoint = None;
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use backend::BackendConfig;
+use battery_simulator::BatteryConfig;
+use eyre::{Context, eyre};
+use s2energy::common::Currency;
+use simulator_common::{Connection, Settings, Timeline};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+mod backend;
+mod battery_simulator;
+mod http_battery;
+mod victron;
+
+/// Runs the battery simulator with the given settings, until it's stopped with Ctrl-C or SIGTERM.
+pub async fn run(settings: &impl Settings) -> eyre::Result<()> {
+    simulator_common::time::init(settings)?;
+
+    // The battery only supports FRBC, so that's the default.
+    let control_type = settings
+        .get("CONTROL_TYPE")
+        .unwrap_or_else(|| "FRBC".into());
+
+    let module_failure_after = settings
+        .get("MODULE_FAILURE_AFTER")
+        .map(|secs| secs.parse().map(Duration::from_secs))
+        .transpose()
+        .wrap_err("Could not parse MODULE_FAILURE_AFTER as a number of seconds")?;
+    let currency = match settings.get("CURRENCY") {
+        Some(currency) => currency.parse().map_err(|_| {
+            eyre!("Invalid value for CURRENCY ({currency}); should be an ISO 4217 code such as EUR")
+        })?,
+        None => Currency::Eur,
+    };
+    let wear_cost_per_kwh = settings
+        .get("WEAR_COST_PER_KWH")
+        .map(|cost| cost.parse())
+        .transpose()
+        .wrap_err("Could not parse WEAR_COST_PER_KWH as a number")?
+        .unwrap_or(0.03);
+    let update_interval = Duration::from_secs(settings.get_or("UPDATE_INTERVAL", 60)?);
+    if update_interval.is_zero() {
+        return Err(eyre!("UPDATE_INTERVAL should be at least 1 second"));
+    }
+    let forecast_interval = Duration::from_secs(settings.get_or("FORECAST_INTERVAL", 60 * 60)?);
+    if forecast_interval.is_zero() {
+        return Err(eyre!("FORECAST_INTERVAL should be at least 1 second"));
+    }
+    let jitter = Duration::from_secs(settings.get_or("JITTER", 0)?);
+    // The jitter is the only random behaviour of the battery, so there's no seed to log without it.
+    let seed = if jitter.is_zero() {
+        0
+    } else {
+        simulator_common::random::seed(settings)?
+    };
+    let timeline = match settings.get("TIMELINE_PATH") {
+        Some(path) => Timeline::from_path(path)?,
+        None => Timeline::default(),
+    };
+    let capacity_wh = settings.get_or("BATTERY_CAPACITY_WH", battery_simulator::CAPACITY_WH)?;
+    if capacity_wh <= 0.0 {
+        return Err(eyre!("BATTERY_CAPACITY_WH should be more than 0"));
+    }
+    let max_power_w = settings.get_or("BATTERY_MAX_POWER_W", battery_simulator::MAX_POWER_W)?;
+    if max_power_w <= 0.0 {
+        return Err(eyre!("BATTERY_MAX_POWER_W should be more than 0"));
+    }
+    let backend = BackendConfig::from_settings(settings)?;
+    let config = BatteryConfig {
+        module_failure_after,
+        currency,
+        wear_cost_per_kwh,
+        update_interval,
+        forecast_interval,
+        jitter,
+        seed,
+        timeline,
+        capacity_wh,
+        max_power_w,
+        backend,
+    };
+
+    let instances: usize = settings.get_or("INSTANCES", 1)?;
+    if instances == 0 {
+        return Err(eyre!("INSTANCES should be at least 1"));
+    }
+    if config.backend.is_some() {
+        // A real battery can't lose a module on command, and there's only one of it.
+        if config.module_failure_after.is_some() {
+            return Err(eyre!(
+                "MODULE_FAILURE_AFTER can't be used with a real battery in BATTERY_BACKEND"
+            ));
+        }
+        if instances > 1 {
+            return Err(eyre!(
+                "INSTANCES can't be more than 1 with a real battery in BATTERY_BACKEND"
+            ));
+        }
+    }
+    for setting in ["HTTP_ADDRESS", "LISTEN_ADDRESS", "MOSAIK_ADDRESS"] {
+        if instances > 1 && settings.get(setting).is_some() {
+            return Err(eyre!(
+                "{setting} can't be used with more than one instance, as they would share the address"
+            ));
+        }
+    }
+    for setting in ["TUI", "COMMANDS"] {
+        if instances > 1 && settings.get_or(setting, false)? {
+            return Err(eyre!(
+                "{setting} can't be used with more than one instance, as they would share the terminal"
+            ));
+        }
+    }
+    if instances > 1 {
+        return run_instances(settings, &control_type, config, instances).await;
+    }
+
+    let connection = simulator_common::connect(settings).await?;
+    start(connection, &control_type, config).await
+}
+
+/// Runs `instances` batteries with their own connection and state, so a CEM can be tested with many RMs at once.
+///
+/// The instances connect one after the other, so the CEM isn't flooded with connections at the same moment. They keep
+/// running when one of them stops.
+async fn run_instances(
+    settings: &impl Settings,
+    control_type: &str,
+    config: BatteryConfig,
+    instances: usize,
+) -> eyre::Result<()> {
+    let mut tasks = JoinSet::new();
+    for number in 1..=instances {
+        let instance_settings = InstanceSettings { number, settings };
+        let connection = simulator_common::connect(&instance_settings)
+            .await
+            .wrap_err_with(|| format!("Could not connect instance {number}"))?;
+        let control_type = control_type.to_string();
+        let config = config.clone();
+        tasks.spawn(
+            async move { (number, start(connection, &control_type, config).await) }
+                .instrument(tracing::info_span!("instance", number)),
+        );
+    }
+    tracing::info!("Started {instances} batteries");
+
+    let mut failed = 0;
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((_, Ok(()))) => {}
+            Ok((number, Err(error))) => {
+                tracing::error!("Instance {number} stopped: {error:#}");
+                failed += 1;
+            }
+            Err(error) => {
+                tracing::error!("An instance crashed: {error}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(eyre!(
+            "{failed} of {instances} instances stopped with an error"
+        ));
+    }
+    Ok(())
+}
+
+async fn start(
+    connection: Connection,
+    control_type: &str,
+    config: BatteryConfig,
+) -> eyre::Result<()> {
+    match control_type.to_uppercase().as_str() {
+        "FRBC" => battery_simulator::start_mock(connection, config).await,
+        other => Err(eyre!(
+            "Invalid value for CONTROL TYPE ({other}); should FRBC"
+        )),
+    }
+}
+
+/// The settings of one of several instances, which get their own MQTT topics and recordings.
+struct InstanceSettings<'a, S> {
+    number: usize,
+    settings: &'a S,
+}
+
+impl<S: Settings> Settings for InstanceSettings<'_, S> {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "MQTT_TOPIC_PREFIX" => {
+                let prefix = self
+                    .settings
+                    .get(name)
+                    .unwrap_or_else(|| "s2-simulator".into());
+                Some(format!("{}/{}", prefix.trim_end_matches('/'), self.number))
+            }
+            "RECORDING_DIRECTORY" | "CSV_DIRECTORY" => self
+                .settings
+                .get(name)
+                .map(|directory| format!("{directory}/{}", self.number)),
+            _ => self.settings.get(name),
+        }
+    }
+}
@@ -1,28 +1,14 @@
-use eyre::{eyre, Context};
-
-mod battery_simulator;
+use simulator_common::{ConfigFile, EnvSettings, Settings};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    tracing_subscriber::fmt().init();
-
-    let connection = s2energy::websockets_json::connect_as_client(
-        std::env::var("CEM_URL")
-            .wrap_err("Could not read CEM URL from environment variable CEM_URL")?,
-    )
-    .await?;
-
-    let control_type = std::env::var("CONTROL_TYPE")
-        .wrap_err("Could not read control type from environment variable CONTROL_TYPE")?;
-    
-    match control_type.as_str() {
-        "FRBC" => battery_simulator::start_mock(connection).await?,
-        other => {
-            return Err(eyre!(
-                "Invalid value for CONTROL TYPE ({other}); should FRBC"
-            ));
-        }
-    }
-
-    Ok(())
+    // The battery is configured through environment variables; see docker-compose.yml for the available options.
+    // They can also be set in a configuration file, in which case the environment variables take precedence.
+    let config_file = match std::env::var("CONFIG_PATH") {
+        Ok(path) => ConfigFile::from_path(path)?,
+        Err(_) => ConfigFile::default(),
+    };
+    let settings = EnvSettings.or(config_file);
+    let _telemetry = simulator_common::telemetry::init(&settings, "battery")?;
+    battery::run(&settings).await
 }
@@ -0,0 +1,314 @@
+use crate::backend::BatteryBackend;
+use eyre::{Context, eyre};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS};
+use serde_json::{Value, json};
+use simulator_common::Settings;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How often the GX device is asked to keep publishing; it stops a minute after the last request.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long to wait before reconnecting when the connection with the GX device is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A Victron GX device (such as a Cerbo GX) with a battery and a Multi or Quattro in ESS mode 3, configured with the
+/// `VICTRON_*` settings.
+#[derive(Clone)]
+pub struct VictronConfig {
+    host: String,
+    port: u16,
+    /// The portal ID of the GX device, which is in all its topics; found on the broker if not set.
+    portal_id: Option<String>,
+    /// The VE.Bus instance of the inverter/charger; the first one on the broker if not set.
+    vebus_instance: Option<u32>,
+}
+
+impl VictronConfig {
+    /// Reads the configuration of the GX device from the given settings.
+    pub fn from_settings(settings: &impl Settings) -> eyre::Result<Self> {
+        let broker = settings.get("VICTRON_BROKER").ok_or_else(|| {
+            eyre!("BATTERY_BACKEND is VICTRON, but the address of the GX device is not set in VICTRON_BROKER")
+        })?;
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .wrap_err_with(|| format!("Invalid port in VICTRON_BROKER ({broker})"))?;
+                (host.to_string(), port)
+            }
+            None => (broker, 1883),
+        };
+        let vebus_instance = settings
+            .get("VICTRON_VEBUS_INSTANCE")
+            .map(|instance| {
+                instance.parse().map_err(|_| {
+                    eyre!("Invalid value for VICTRON_VEBUS_INSTANCE ({instance}); should be a number such as 276")
+                })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            host,
+            port,
+            portal_id: settings.get("VICTRON_PORTAL_ID"),
+            vebus_instance,
+        })
+    }
+}
+
+/// A reading of one value on the GX device.
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    value: f64,
+    at: Instant,
+}
+
+/// The latest readings of the battery, which the GX device publishes separately.
+#[derive(Debug, Clone, Copy, Default)]
+struct Readings {
+    state_of_charge: Option<Reading>,
+    /// The power the battery charges with, in W, negative when it discharges.
+    power_w: Option<Reading>,
+}
+
+/// A battery behind a Victron GX device, read and controlled through the MQTT broker on the GX device.
+///
+/// The GX device publishes the state of the system on `N/<portal ID>/...` while it's asked to every so often, and takes
+/// writes on `W/<portal ID>/...`. The state of charge and power come from the battery monitor the system uses, and the
+/// setpoint is written as the AC power setpoint of the inverter/charger in ESS mode 3, divided over its phases. It's
+/// written again every keepalive, as the inverter/charger stops following a setpoint that isn't renewed.
+pub struct VictronBattery {
+    readings: watch::Receiver<Readings>,
+    setpoint: watch::Sender<f64>,
+}
+
+impl VictronBattery {
+    /// Connects to the GX device, which is told to keep the battery idle until the CEM instructs it; this stops when
+    /// the `VictronBattery` is dropped.
+    pub fn start(config: VictronConfig) -> Self {
+        let client_id = format!("s2-battery-{}", std::process::id());
+        let mut options = MqttOptions::new(client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, event_loop) = AsyncClient::new(options, 100);
+        let (readings_sender, readings) = watch::channel(Readings::default());
+        let (setpoint, setpoint_receiver) = watch::channel(0.0);
+        let gx = Gx {
+            client,
+            portal_id: config.portal_id.clone(),
+            vebus: None,
+            warned_vebus: false,
+            readings: readings_sender,
+        };
+        tokio::spawn(gx.run(config, event_loop, setpoint_receiver));
+
+        Self { readings, setpoint }
+    }
+
+    /// Waits until the state of charge has been read, so the CEM gets the real one from the start. Returns whether it
+    /// was read within `timeout`.
+    pub async fn wait_for_reading(&mut self, timeout: Duration) -> bool {
+        let read = self
+            .readings
+            .wait_for(|readings| readings.state_of_charge.is_some());
+        tokio::time::timeout(timeout, read)
+            .await
+            .is_ok_and(|read| read.is_ok())
+    }
+
+    /// The latest value of a reading, unless it hasn't been published for a few keepalives.
+    fn latest(reading: Option<Reading>) -> Option<f64> {
+        let reading = reading?;
+        (reading.at.elapsed() < 3 * KEEPALIVE_INTERVAL).then_some(reading.value)
+    }
+}
+
+impl BatteryBackend for VictronBattery {
+    fn state_of_charge(&mut self) -> Option<f64> {
+        Self::latest(self.readings.borrow().state_of_charge)
+    }
+
+    fn power_w(&self) -> Option<f64> {
+        Self::latest(self.readings.borrow().power_w)
+    }
+
+    fn set_power(&mut self, power_w: f64) {
+        self.setpoint.send_if_modified(|setpoint| {
+            let modified = *setpoint != power_w;
+            *setpoint = power_w;
+            modified
+        });
+    }
+}
+
+/// The connection with the GX device, kept up in the background.
+struct Gx {
+    client: AsyncClient,
+    portal_id: Option<String>,
+    /// The VE.Bus instance and number of phases of the inverter/charger, once it's been found.
+    vebus: Option<(u32, u32)>,
+    /// Whether we warned that no inverter/charger has been found, so the battery can't be controlled.
+    warned_vebus: bool,
+    readings: watch::Sender<Readings>,
+}
+
+impl Gx {
+    async fn run(
+        mut self,
+        config: VictronConfig,
+        mut event_loop: rumqttc::EventLoop,
+        mut setpoint: watch::Receiver<f64>,
+    ) {
+        let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+        // The first tick is right away, but the keepalive is sent when subscribing.
+        keepalive.tick().await;
+        loop {
+            tokio::select! {
+                event = event_loop.poll() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        tracing::info!("Connected to Victron GX device {}:{}", config.host, config.port);
+                        // The session isn't kept by the broker, so everything is subscribed to again.
+                        match self.portal_id.clone() {
+                            Some(portal_id) => self.subscribe(&portal_id, config.vebus_instance),
+                            None => self.try_subscribe("N/+/system/0/Serial"),
+                        }
+                        // The GX device may have restarted, and forgotten the setpoint.
+                        self.write_setpoint(*setpoint.borrow());
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        self.handle(&publish, config.vebus_instance, *setpoint.borrow());
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        tracing::warn!(
+                            "No connection with Victron GX device {}:{}: {error}",
+                            config.host,
+                            config.port
+                        );
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                },
+                _ = keepalive.tick() => {
+                    if let Some(portal_id) = &self.portal_id {
+                        self.try_publish(format!("R/{portal_id}/keepalive"), String::new());
+                        if self.vebus.is_none() && !self.warned_vebus {
+                            tracing::warn!(
+                                "No inverter/charger found on the GX device, so the battery can't be controlled yet"
+                            );
+                            self.warned_vebus = true;
+                        }
+                    }
+                    self.write_setpoint(*setpoint.borrow());
+                }
+                changed = setpoint.changed() => match changed {
+                    Ok(()) => {
+                        let power_w = *setpoint.borrow_and_update();
+                        tracing::info!("Setting the battery to {power_w:.0} W");
+                        self.write_setpoint(power_w);
+                    }
+                    // The simulator stopped.
+                    Err(_) => return,
+                },
+            }
+        }
+    }
+
+    /// Subscribes to the values we need from the GX device, and asks it to publish them.
+    fn subscribe(&self, portal_id: &str, vebus_instance: Option<u32>) {
+        let vebus_instance = vebus_instance.map_or("+".into(), |instance| instance.to_string());
+        for topic in [
+            format!("N/{portal_id}/system/0/Dc/Battery/Soc"),
+            format!("N/{portal_id}/system/0/Dc/Battery/Power"),
+            format!("N/{portal_id}/vebus/{vebus_instance}/Ac/NumberOfPhases"),
+        ] {
+            self.try_subscribe(&topic);
+        }
+        self.try_publish(format!("R/{portal_id}/keepalive"), String::new());
+    }
+
+    fn handle(&mut self, publish: &Publish, vebus_instance: Option<u32>, setpoint_w: f64) {
+        let topic: Vec<&str> = publish.topic.split('/').collect();
+        // Values that aren't available, such as the state of charge without a battery monitor, are null.
+        let value = serde_json::from_slice::<Value>(&publish.payload)
+            .ok()
+            .and_then(|payload| payload["value"].as_f64());
+        match topic.as_slice() {
+            ["N", portal_id, "system", "0", "Serial"] if self.portal_id.is_none() => {
+                tracing::info!("Found Victron GX device with portal ID {portal_id}");
+                self.portal_id = Some(portal_id.to_string());
+                self.subscribe(portal_id, vebus_instance);
+            }
+            [_, _, "system", "0", "Dc", "Battery", "Soc"] => {
+                if let Some(state_of_charge) = value {
+                    self.readings.send_modify(|readings| {
+                        readings.state_of_charge = Some(Reading {
+                            value: (state_of_charge / 100.0).clamp(0.0, 1.0),
+                            at: Instant::now(),
+                        })
+                    });
+                }
+            }
+            [_, _, "system", "0", "Dc", "Battery", "Power"] => {
+                if let Some(power_w) = value {
+                    self.readings.send_modify(|readings| {
+                        readings.power_w = Some(Reading {
+                            value: power_w,
+                            at: Instant::now(),
+                        })
+                    });
+                }
+            }
+            [_, _, "vebus", instance, "Ac", "NumberOfPhases"] => {
+                let (Ok(instance), Some(phases)) = (instance.parse(), value) else {
+                    return;
+                };
+                let phases = (phases as u32).max(1);
+                match self.vebus {
+                    None => {
+                        tracing::info!(
+                            "Found Victron inverter/charger with VE.Bus instance {instance} on {phases} phase(s)"
+                        );
+                        self.vebus = Some((instance, phases));
+                        self.write_setpoint(setpoint_w);
+                    }
+                    Some((found, _)) if found == instance => self.vebus = Some((instance, phases)),
+                    // Only the first inverter/charger is controlled.
+                    Some(_) => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Has the inverter/charger charge the battery with `power_w`, or discharge it with a negative power.
+    ///
+    /// The setpoint in ESS mode 3 is the power the inverter/charger takes from its AC input, per phase.
+    fn write_setpoint(&self, power_w: f64) {
+        let (Some(portal_id), Some((instance, phases))) = (&self.portal_id, self.vebus) else {
+            // It's written once the inverter/charger is found.
+            return;
+        };
+        let phase_power_w = (power_w / phases as f64).round();
+        for phase in 1..=phases {
+            self.try_publish(
+                format!("W/{portal_id}/vebus/{instance}/Hub4/L{phase}/AcPowerSetpoint"),
+                json!({"value": phase_power_w}).to_string(),
+            );
+        }
+    }
+
+    fn try_subscribe(&self, topic: &str) {
+        if let Err(error) = self.client.try_subscribe(topic, QoS::AtMostOnce) {
+            tracing::warn!("Could not subscribe to {topic} on the GX device: {error}");
+        }
+    }
+
+    fn try_publish(&self, topic: String, payload: String) {
+        // While the GX device is unreachable, the queue fills up; the setpoint is written again once it's back.
+        if let Err(error) = self
+            .client
+            .try_publish(&topic, QoS::AtMostOnce, false, payload)
+        {
+            tracing::debug!("Could not publish to {topic} on the GX device: {error}");
+        }
+    }
+}
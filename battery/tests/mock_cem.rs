@@ -0,0 +1,98 @@
+use conformance::MockCem;
+use s2energy::common::{ControlType, Id, InstructionStatus, SessionRequest, SessionRequestType};
+use s2energy::frbc;
+use simulator_common::Settings;
+use std::collections::HashMap;
+
+struct TestSettings(HashMap<&'static str, String>);
+
+impl Settings for TestSettings {
+    fn get(&self, name: &str) -> Option<String> {
+        self.0.get(name).cloned()
+    }
+}
+
+#[tokio::test]
+async fn battery_follows_frbc_instructions() -> eyre::Result<()> {
+    let mut cem = MockCem::bind("127.0.0.1:0").await?;
+    let settings = TestSettings(HashMap::from([("CEM_URL", cem.url()?)]));
+    let battery = tokio::spawn(async move { battery::run(&settings).await });
+
+    cem.accept().await?;
+    let details = cem.handshake().await?;
+    assert!(
+        details
+            .available_control_types
+            .contains(&ControlType::FillRateBasedControl)
+    );
+    cem.select_control_type(ControlType::FillRateBasedControl)
+        .await?;
+
+    let description = cem.expect_message::<frbc::SystemDescription>().await?;
+    let actuator = &description.actuators[0];
+    let charge = actuator
+        .operation_modes
+        .iter()
+        .find(|operation_mode| {
+            operation_mode.diagnostic_label.as_deref() == Some("Charging battery")
+        })
+        .expect("the battery should be able to charge");
+    cem.expect_message::<frbc::ActuatorStatus>().await?;
+
+    let instruction_id = cem
+        .send_frbc_instruction(actuator.id.clone(), charge.id.clone(), 1.0)
+        .await?;
+    assert_eq!(
+        cem.expect_instruction_status(&instruction_id).await?,
+        InstructionStatus::Succeeded
+    );
+    let status = cem.expect_message::<frbc::ActuatorStatus>().await?;
+    assert_eq!(status.active_operation_mode_id, charge.id);
+
+    let unknown_operation_mode = s2energy::common::Id::generate();
+    let instruction_id = cem
+        .send_frbc_instruction(actuator.id.clone(), unknown_operation_mode, 1.0)
+        .await?;
+    assert_eq!(
+        cem.expect_instruction_status(&instruction_id).await?,
+        InstructionStatus::Rejected
+    );
+
+    cem.terminate().await?;
+    battery.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn battery_reconnects_when_the_cem_asks_to() -> eyre::Result<()> {
+    let mut cem = MockCem::bind("127.0.0.1:0").await?;
+    let settings = TestSettings(HashMap::from([
+        ("CEM_URL", cem.url()?),
+        ("RECONNECT_DELAY", "0".into()),
+    ]));
+    let battery = tokio::spawn(async move { battery::run(&settings).await });
+
+    cem.accept().await?;
+    let details = cem.handshake().await?;
+    cem.select_control_type(ControlType::FillRateBasedControl)
+        .await?;
+    cem.expect_message::<frbc::SystemDescription>().await?;
+    cem.send(SessionRequest {
+        diagnostic_label: Some("moving to another server".into()),
+        message_id: Id::generate(),
+        request: SessionRequestType::Reconnect,
+    })
+    .await?;
+
+    // The battery sets up a new session, as the same RM.
+    cem.accept().await?;
+    let reconnected = cem.handshake().await?;
+    assert_eq!(reconnected.resource_id, details.resource_id);
+    cem.select_control_type(ControlType::FillRateBasedControl)
+        .await?;
+    cem.expect_message::<frbc::SystemDescription>().await?;
+
+    cem.terminate().await?;
+    battery.abort();
+    Ok(())
+}
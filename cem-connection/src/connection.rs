@@ -0,0 +1,160 @@
+use futures_util::{SinkExt, StreamExt};
+use s2energy::common::{Id, Message, ReceptionStatus, ReceptionStatusValues};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::WebSocketStream;
+
+/// A message the RM sent.
+pub struct Received {
+    /// The `message_type` of the message, such as `FRBC.SystemDescription`.
+    pub message_type: String,
+    pub message: Message,
+}
+
+/// The CEM side of the connection with an RM.
+///
+/// Unlike `S2Connection` from the s2energy crate, this keeps everything the RM sends, including reception statuses and
+/// messages that aren't valid S2, so they can be inspected afterwards. Like a CEM, it acknowledges every message the RM
+/// sends with a reception status.
+pub struct RmConnection {
+    socket: WebSocketStream<TcpStream>,
+    /// The valid S2 messages the RM sent, except reception statuses, in the order they were received.
+    pub received: Vec<Received>,
+    /// Descriptions of the messages the RM sent that aren't valid S2.
+    pub invalid: Vec<String>,
+    /// The reception statuses the RM sent, by the ID of the message they acknowledge.
+    pub reception_statuses: HashMap<Id, ReceptionStatusValues>,
+    /// Whether the RM closed the connection.
+    pub closed: bool,
+}
+
+impl RmConnection {
+    pub fn new(socket: WebSocketStream<TcpStream>) -> Self {
+        Self {
+            socket,
+            received: Vec::new(),
+            invalid: Vec::new(),
+            reception_statuses: HashMap::new(),
+            closed: false,
+        }
+    }
+
+    /// Sends a message to the RM, and returns its message ID.
+    pub async fn send(&mut self, message: impl Into<Message>) -> eyre::Result<Option<Id>> {
+        let message = message.into();
+        self.send_text(&serde_json::to_string(&message)?).await;
+        Ok(message.id())
+    }
+
+    /// Sends text to the RM as is, to test how it handles messages that aren't valid S2.
+    ///
+    /// If the connection has failed, this marks it as closed instead of returning an error, so the caller can find out
+    /// that the RM disconnected.
+    pub async fn send_text(&mut self, text: &str) {
+        if self.closed {
+            return;
+        }
+        if let Err(error) = self.socket.send(WebSocketMessage::Text(text.into())).await {
+            tracing::warn!("Could not send a message to the RM: {error}");
+            self.closed = true;
+        }
+    }
+
+    /// Receives messages until `condition` holds for the messages received so far, or until the timeout expires.
+    ///
+    /// Returns whether the condition holds. This returns early if the RM closes the connection.
+    pub async fn wait_until(
+        &mut self,
+        timeout: Duration,
+        condition: impl Fn(&Self) -> bool,
+    ) -> eyre::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        while !condition(self) {
+            if self.closed || !self.receive_until(deadline).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Receives messages until the timeout expires, or until the RM closes the connection.
+    pub async fn receive_for(&mut self, timeout: Duration) -> eyre::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !self.closed && self.receive_until(deadline).await? {}
+        Ok(())
+    }
+
+    /// Receives and processes a single WebSocket message, unless the deadline passes first.
+    ///
+    /// Returns whether a message was received.
+    async fn receive_until(&mut self, deadline: Instant) -> eyre::Result<bool> {
+        let next = match tokio::time::timeout_at(deadline, self.socket.next()).await {
+            Ok(next) => next,
+            Err(_) => return Ok(false),
+        };
+        let text = match next {
+            Some(Ok(WebSocketMessage::Text(text))) => text,
+            Some(Ok(WebSocketMessage::Binary(_))) => {
+                self.invalid
+                    .push("a binary WebSocket message; S2 messages are text".into());
+                return Ok(true);
+            }
+            Some(Ok(WebSocketMessage::Close(_))) | None => {
+                self.closed = true;
+                return Ok(false);
+            }
+            // Pings and pongs are handled by tungstenite
+            Some(Ok(_)) => return Ok(true),
+            Some(Err(error)) => {
+                self.closed = true;
+                tracing::warn!("The connection with the RM failed: {error}");
+                return Ok(false);
+            }
+        };
+
+        let json: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(json) => json,
+            Err(error) => {
+                self.invalid.push(format!("invalid JSON ({error}): {text}"));
+                return Ok(true);
+            }
+        };
+        let message_type = json["message_type"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let message: Message = match serde_json::from_value(json) {
+            Ok(message) => message,
+            Err(error) => {
+                self.invalid.push(format!(
+                    "an invalid {message_type} message ({error}): {text}"
+                ));
+                return Ok(true);
+            }
+        };
+
+        if let Message::ReceptionStatus(status) = &message {
+            self.reception_statuses
+                .insert(status.subject_message_id.clone(), status.status);
+        } else {
+            if let Some(id) = message.id() {
+                self.send(ReceptionStatus::new(None, ReceptionStatusValues::Ok, id))
+                    .await?;
+            }
+            self.received.push(Received {
+                message_type,
+                message,
+            });
+        }
+        Ok(true)
+    }
+
+    /// Closes the connection.
+    pub async fn close(&mut self) {
+        // The RM may have closed the connection already, so errors don't matter here.
+        let _ = self.socket.close(None).await;
+    }
+}
@@ -0,0 +1,24 @@
+use clap::ValueEnum;
+use s2energy::common::ControlType;
+
+/// A control type as a command line argument of the tools that act as a CEM.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ControlTypeArg {
+    Frbc,
+    Ombc,
+    Pebc,
+    Ppbc,
+    Ddbc,
+}
+
+impl From<ControlTypeArg> for ControlType {
+    fn from(value: ControlTypeArg) -> Self {
+        match value {
+            ControlTypeArg::Frbc => ControlType::FillRateBasedControl,
+            ControlTypeArg::Ombc => ControlType::OperationModeBasedControl,
+            ControlTypeArg::Pebc => ControlType::PowerEnvelopeBasedControl,
+            ControlTypeArg::Ppbc => ControlType::PowerProfileBasedControl,
+            ControlTypeArg::Ddbc => ControlType::DemandDrivenBasedControl,
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! The CEM side of an S2 connection with a resource manager.
+//!
+//! [`RmConnection`] is shared by the reference CEM in `cem` and the tools in `conformance` that act as a CEM, so the
+//! CEM doesn't depend on the test tools. [`ControlTypeArg`] lets their command lines select a control type.
+
+mod connection;
+mod control_type;
+
+pub use connection::{Received, RmConnection};
+pub use control_type::ControlTypeArg;
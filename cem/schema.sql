@@ -0,0 +1,79 @@
+-- The tables the CEM stores its sessions, the measurements of the RMs and its instructions in, with `--postgres-url`.
+--
+-- The CEM runs this when it starts, so it creates the tables in a new database and leaves those that are there alone.
+-- Every timestamp is in UTC. Powers are in W and positive when an RM consumes, like in S2.
+
+-- Every session with an RM, from the moment it connects. The row is updated whenever the session state changes.
+CREATE TABLE IF NOT EXISTS sessions (
+    id bigserial PRIMARY KEY,
+    -- The address the RM connected from, as `ip:port`.
+    rm_address text NOT NULL,
+    started_at timestamptz NOT NULL,
+    -- When the session ended, and why; NULL while it's going on, or when the CEM stopped before it could tell.
+    ended_at timestamptz,
+    end_reason text,
+    -- The resource ID and name in the ResourceManagerDetails of the RM, once it sent them.
+    resource_id text,
+    rm_name text,
+    -- The control type the CEM selected, such as `FILL_RATE_BASED_CONTROL`, and the S2 version of the session.
+    control_type text,
+    protocol_version text,
+    -- When the earlier session started that this session picked up from, if the CEM remembers RMs.
+    resumed_from timestamptz,
+    -- Everything the CEM knows about the session, as on the monitoring API: the latest message of every type, the
+    -- instructions in effect, the plan, and so on.
+    state jsonb NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS sessions_resource_id ON sessions (resource_id, started_at);
+
+-- The values of every PowerMeasurement the RMs send, one row per commodity quantity.
+CREATE TABLE IF NOT EXISTS measurements (
+    session_id bigint NOT NULL REFERENCES sessions (id),
+    -- The measurement_timestamp of the measurement.
+    measured_at timestamptz NOT NULL,
+    -- Such as `ELECTRIC.POWER.L1` or `HEAT.THERMAL_POWER`.
+    commodity_quantity text NOT NULL,
+    value double precision NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS measurements_session ON measurements (session_id, measured_at);
+
+-- Every instruction the CEM sends, whether it decided on it or it was sent by hand.
+CREATE TABLE IF NOT EXISTS instructions (
+    -- The ID of the instruction.
+    id text PRIMARY KEY,
+    session_id bigint NOT NULL REFERENCES sessions (id),
+    -- Such as `FRBC.Instruction`.
+    message_type text NOT NULL,
+    sent_at timestamptz NOT NULL,
+    -- When the RM should carry it out, if the instruction says.
+    execution_time timestamptz,
+    -- The whole instruction, as sent.
+    message jsonb NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS instructions_session ON instructions (session_id, sent_at);
+
+-- Every InstructionStatusUpdate the RMs send. The instruction isn't necessarily in `instructions`, such as when an RM
+-- reports on an instruction it got before the CEM stored them.
+CREATE TABLE IF NOT EXISTS instruction_statuses (
+    instruction_id text NOT NULL,
+    session_id bigint NOT NULL REFERENCES sessions (id),
+    -- Such as `ACCEPTED` or `SUCCEEDED`.
+    status text NOT NULL,
+    -- The timestamp of the update.
+    at timestamptz NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS instruction_statuses_instruction ON instruction_statuses (instruction_id, at);
+
+-- With the TimescaleDB extension, the measurements are a hypertable, partitioned by time. Install the extension in the
+-- database before the CEM first starts, as a table that has rows can't be made into a hypertable.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+        PERFORM create_hypertable('measurements', 'measured_at', if_not_exists => TRUE);
+    END IF;
+END
+$$;
@@ -0,0 +1,129 @@
+use std::collections::HashSet;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+
+/// The query parameter an RM can put its pairing token in, for RMs that can't send an `Authorization` header.
+const TOKEN_PARAMETER: &str = "token";
+
+/// Which RMs the CEM admits, like a CEM in a home only controls the devices that were paired with it.
+///
+/// An RM is admitted if it connects with one of the pairing tokens, as an `Authorization: Bearer` header or in the
+/// `token` query parameter, or if its resource ID is on the allowlist. Without pairing tokens and an allowlist, the CEM
+/// admits every RM. An RM that isn't admitted is either turned away, or quarantined: it stays connected, but the CEM
+/// selects no control type and leaves it out of its strategies until it's admitted through the API, which adds its
+/// resource ID to the allowlist.
+pub(crate) struct Admission {
+    tokens: Vec<String>,
+    /// The resource IDs of the RMs that are admitted without a token, or `None` if every RM is admitted.
+    allowed: Option<watch::Sender<HashSet<String>>>,
+    /// Whether RMs that aren't admitted are quarantined rather than turned away.
+    pub(crate) quarantine: bool,
+}
+
+impl Admission {
+    pub(crate) fn new(tokens: Vec<String>, allowed: Option<Vec<String>>, quarantine: bool) -> Self {
+        let allowed = (!tokens.is_empty() || allowed.is_some())
+            .then(|| watch::Sender::new(allowed.unwrap_or_default().into_iter().collect()));
+        Self {
+            tokens,
+            allowed,
+            quarantine,
+        }
+    }
+
+    /// Whether the RM with this resource ID is admitted without a token.
+    pub(crate) fn allows(&self, resource_id: &str) -> bool {
+        self.allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.borrow().contains(resource_id))
+    }
+
+    /// Adds the RM with this resource ID to the allowlist, which admits it if it's in quarantine, and from then on
+    /// whenever it connects, until the CEM restarts.
+    pub(crate) fn allow(&self, resource_id: String) {
+        if let Some(allowed) = &self.allowed {
+            allowed.send_modify(|allowed| {
+                allowed.insert(resource_id);
+            });
+        }
+    }
+
+    /// Returns a receiver that's notified when an RM is added to the allowlist, if there is one.
+    pub(crate) fn subscribe(&self) -> Option<watch::Receiver<HashSet<String>>> {
+        self.allowed.as_ref().map(watch::Sender::subscribe)
+    }
+}
+
+/// Checks the token an RM connects with while its WebSocket connection is set up, and records whether it's one of the
+/// pairing tokens.
+///
+/// An RM with a token that isn't one of the pairing tokens is turned away with 401 Unauthorized right away, since it was
+/// paired with another CEM or has a typo in its settings. Without pairing tokens, the CEM ignores the tokens RMs send.
+pub(crate) struct TokenCheck<'a> {
+    pub(crate) admission: &'a Admission,
+    pub(crate) paired: &'a mut bool,
+}
+
+impl Callback for TokenCheck<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let tokens = &self.admission.tokens;
+        match token(request) {
+            Some(token) if tokens.contains(&token) => *self.paired = true,
+            Some(_) if !tokens.is_empty() => {
+                let mut response =
+                    ErrorResponse::new(Some("The token isn't a pairing token of this CEM".into()));
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                return Err(response);
+            }
+            _ => {}
+        }
+        Ok(response)
+    }
+}
+
+/// The token in the `Authorization` header of the request, or otherwise in its query.
+fn token(request: &Request) -> Option<String> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return Some(token.trim().to_string());
+    }
+    request.uri().query()?.split('&').find_map(|parameter| {
+        let value = parameter.strip_prefix(TOKEN_PARAMETER)?.strip_prefix('=')?;
+        Some(percent_decode(value))
+    })
+}
+
+/// Decodes a query parameter like the simulators encode their token; bytes that don't decode are kept as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
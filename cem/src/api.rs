@@ -0,0 +1,305 @@
+use crate::admission::Admission;
+use crate::forecast::{self, SiteForecast};
+use crate::influx::InfluxSink;
+use crate::postgres::PostgresStore;
+use crate::state::SessionState;
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::Utc;
+use eyre::Context;
+use prices::Prices;
+use s2energy::common::Message;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// How many instructions from the API or the console may wait for a session to send them.
+const QUEUED_INSTRUCTIONS: usize = 16;
+
+/// The state of every session that's going on, which the sessions keep up-to-date for the monitoring API, and the way
+/// to pass the sessions instructions and admit RMs in quarantine from the dashboard and the console. It also holds the
+/// day-ahead prices that every session decides with, and the prices of demand response events that replace them, and the
+/// InfluxDB and PostgreSQL database the sessions write to.
+pub(crate) struct Sessions {
+    states: Mutex<BTreeMap<usize, SessionState>>,
+    /// Where the instructions for every session that's set up go, by session number.
+    instructions: Mutex<HashMap<usize, mpsc::Sender<Message>>>,
+    pub(crate) admission: Admission,
+    /// The day-ahead prices the site has, if the CEM follows prices.
+    prices: Mutex<Option<Prices>>,
+    /// The prices of the demand response events, which replace the day-ahead prices for as long as they last.
+    event_prices: Mutex<Vec<Prices>>,
+    /// Where the sessions write the measurements and instructions to, if anywhere.
+    pub(crate) influx: Option<InfluxSink>,
+    /// Where the sessions store their state, the measurements and instructions, if anywhere.
+    pub(crate) postgres: Option<PostgresStore>,
+}
+
+impl Sessions {
+    pub(crate) fn new(
+        admission: Admission,
+        influx: Option<InfluxSink>,
+        postgres: Option<PostgresStore>,
+    ) -> Self {
+        Self {
+            states: Mutex::default(),
+            instructions: Mutex::default(),
+            admission,
+            prices: Mutex::default(),
+            event_prices: Mutex::default(),
+            influx,
+            postgres,
+        }
+    }
+
+    /// Lets the API pass instructions to a session that's set up, and returns the receiver they arrive on.
+    pub(crate) fn add(&self, session: usize) -> mpsc::Receiver<Message> {
+        let (sender, receiver) = mpsc::channel(QUEUED_INSTRUCTIONS);
+        self.instructions.lock().unwrap().insert(session, sender);
+        receiver
+    }
+
+    /// Replaces what the API shows for a session.
+    pub(crate) fn update(&self, session: usize, state: &SessionState) {
+        self.states.lock().unwrap().insert(session, state.clone());
+    }
+
+    /// What the whole site expects from now on, from the latest forecasts of the RMs.
+    pub(crate) fn forecast(&self) -> SiteForecast {
+        let states = self.states.lock().unwrap();
+        let states = states.iter().map(|(session, state)| (*session, state));
+        forecast::aggregate(states, Utc::now())
+    }
+
+    /// The state of every session that's going on, by session number.
+    pub(crate) fn states(&self) -> BTreeMap<usize, SessionState> {
+        self.states.lock().unwrap().clone()
+    }
+
+    /// Passes an instruction to a session, which sends it to the RM right away.
+    pub(crate) fn instruct(
+        &self,
+        session: usize,
+        instruction: Message,
+    ) -> Result<(), InstructError> {
+        if !self.states.lock().unwrap().contains_key(&session) {
+            return Err(InstructError::NoSession(session));
+        }
+        let sender = self.instructions.lock().unwrap().get(&session).cloned();
+        let sender = sender.ok_or(InstructError::NotSetUp)?;
+        sender.try_send(instruction).map_err(|error| match error {
+            mpsc::error::TrySendError::Full(_) => InstructError::Full,
+            mpsc::error::TrySendError::Closed(_) => InstructError::Ended(session),
+        })
+    }
+
+    /// Replaces the day-ahead prices, as the market publishes more of them.
+    pub(crate) fn set_prices(&self, prices: Prices) {
+        *self.prices.lock().unwrap() = Some(prices);
+    }
+
+    /// Replaces the prices of the demand response events, as events are announced, changed or cancelled.
+    pub(crate) fn set_event_prices(&self, prices: Vec<Prices>) {
+        *self.event_prices.lock().unwrap() = prices;
+    }
+
+    /// The day-ahead prices the site has, if any, with the prices of the demand response events where they overlap.
+    pub(crate) fn prices(&self) -> Option<Prices> {
+        let prices = self.prices.lock().unwrap().clone()?;
+        let event_prices = self.event_prices.lock().unwrap();
+        Some(event_prices.iter().fold(prices, |prices, event| {
+            match event.between(prices.start(), prices.end()) {
+                Some(event) => prices.join(&event),
+                None => prices,
+            }
+        }))
+    }
+
+    /// Forgets about a session that ended.
+    pub(crate) fn remove(&self, session: usize) {
+        self.states.lock().unwrap().remove(&session);
+        self.instructions.lock().unwrap().remove(&session);
+    }
+}
+
+/// Why a session can't take an instruction.
+#[derive(Debug)]
+pub(crate) enum InstructError {
+    NoSession(usize),
+    NotSetUp,
+    /// The session has too many instructions waiting already.
+    Full,
+    Ended(usize),
+}
+
+impl fmt::Display for InstructError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSession(session) => write!(f, "There's no session {session}"),
+            Self::NotSetUp => write!(f, "The session isn't set up yet"),
+            Self::Full => write!(f, "The session has too many instructions to send already"),
+            Self::Ended(session) => write!(f, "Session {session} ended"),
+        }
+    }
+}
+
+impl std::error::Error for InstructError {}
+
+/// A connected RM, as the API shows it.
+#[derive(Serialize)]
+struct Rm {
+    /// The number of the session with the RM, which identifies it in `/api/rms/{session}`.
+    session: usize,
+    #[serde(flatten)]
+    state: SessionState,
+}
+
+/// Starts an HTTP server on the given address with the dashboard and the monitoring API.
+pub(crate) async fn serve(address: &str, sessions: Arc<Sessions>) -> eyre::Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .wrap_err_with(|| format!("Could not start the API server on {address}"))?;
+    let router = routes().with_state(sessions);
+    tracing::info!(
+        "Serving the dashboard on http://{address}/, and the monitoring API on /api/rms"
+    );
+    tokio::spawn(async move {
+        if let Err(error) = axum::serve(listener, router).await {
+            tracing::error!("The API server stopped: {error}");
+        }
+    });
+    Ok(())
+}
+
+/// The monitoring API:
+///
+/// - `/api/rms` responds with every RM that's connected: its details, the control type, the latest message of every
+///   type it sent, such as its measurements and status, and the instructions the CEM sent it that are in effect;
+/// - `/api/rms/{session}` responds with one of them, or 404 if there's no such session (anymore);
+/// - `POST /api/rms/{session}/instructions` takes an S2 instruction as JSON, like `{"message_type": "OMBC.Instruction",
+///   ...}`, and the session sends it to the RM right away. The CEM then leaves the RM to the instructions from the API
+///   for a while, before it instructs the RM itself again;
+/// - `POST /api/rms/{session}/admit` admits an RM in quarantine, and adds its resource ID to the allowlist;
+/// - `/api/forecast` responds with what the whole site expects for the next 24 hours per quarter of an hour: the power
+///   forecasts of the RMs added up, and the usage forecasts of the FRBC storages.
+///
+/// The dashboard at `/` shows the same, and sends instructions by hand.
+fn routes() -> Router<Arc<Sessions>> {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/api/rms", get(rms))
+        .route("/api/rms/{session}", get(rm))
+        .route("/api/rms/{session}/instructions", post(instruct))
+        .route("/api/rms/{session}/admit", post(admit))
+        .route("/api/forecast", get(site_forecast))
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+async fn rms(State(sessions): State<Arc<Sessions>>) -> Json<Vec<Rm>> {
+    let states = sessions.states.lock().unwrap();
+    let rms = states
+        .iter()
+        .map(|(session, state)| Rm {
+            session: *session,
+            state: state.clone(),
+        })
+        .collect();
+    Json(rms)
+}
+
+async fn site_forecast(State(sessions): State<Arc<Sessions>>) -> Json<SiteForecast> {
+    Json(sessions.forecast())
+}
+
+async fn rm(
+    State(sessions): State<Arc<Sessions>>,
+    Path(session): Path<usize>,
+) -> Result<Json<Rm>, StatusCode> {
+    let states = sessions.states.lock().unwrap();
+    let state = states.get(&session).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(Rm {
+        session,
+        state: state.clone(),
+    }))
+}
+
+async fn instruct(
+    State(sessions): State<Arc<Sessions>>,
+    Path(session): Path<usize>,
+    body: Bytes,
+) -> (StatusCode, String) {
+    let instruction: Message = match serde_json::from_slice(&body) {
+        Ok(message) => message,
+        Err(error) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("The body should be an S2 message: {error}"),
+            )
+        }
+    };
+    if !is_instruction(&instruction) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "The message should be an instruction, such as an FRBC.Instruction".into(),
+        );
+    }
+    let Err(error) = sessions.instruct(session, instruction) else {
+        return (
+            StatusCode::ACCEPTED,
+            "Sending the instruction to the RM".into(),
+        );
+    };
+    let status = match error {
+        InstructError::NoSession(_) | InstructError::Ended(_) => StatusCode::NOT_FOUND,
+        InstructError::NotSetUp => StatusCode::CONFLICT,
+        InstructError::Full => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, error.to_string())
+}
+
+async fn admit(
+    State(sessions): State<Arc<Sessions>>,
+    Path(session): Path<usize>,
+) -> (StatusCode, String) {
+    let resource_id = {
+        let states = sessions.states.lock().unwrap();
+        let Some(state) = states.get(&session) else {
+            return (
+                StatusCode::NOT_FOUND,
+                format!("There's no session {session}"),
+            );
+        };
+        match &state.rm_details {
+            Some(details) if state.quarantined => details.resource_id.to_string(),
+            _ => return (StatusCode::CONFLICT, "The RM isn't in quarantine".into()),
+        }
+    };
+    sessions.admission.allow(resource_id.clone());
+    (
+        StatusCode::OK,
+        format!("Admitted the RM with resource ID {resource_id}"),
+    )
+}
+
+pub(crate) fn is_instruction(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::FrbcInstruction(_)
+            | Message::OmbcInstruction(_)
+            | Message::PebcInstruction(_)
+            | Message::PpbcScheduleInstruction(_)
+            | Message::PpbcStartInterruptionInstruction(_)
+            | Message::PpbcEndInterruptionInstruction(_)
+            | Message::DdbcInstruction(_)
+    )
+}
@@ -0,0 +1,182 @@
+use chrono::Utc;
+use s2energy::common::{Id, Message};
+use s2energy::frbc;
+
+/// How much an operation mode factor may differ from the one the battery already has before it gets a new instruction.
+const FACTOR_TOLERANCE: f64 = 0.01;
+
+/// What the CEM knows about an FRBC battery, or another FRBC storage such as an EV, to instruct its first actuator.
+#[derive(Default)]
+pub(crate) struct Battery {
+    description: Option<frbc::SystemDescription>,
+    fill_level: Option<f64>,
+    /// The fill levels the RM wants to reach, such as an EV that should be charged by the morning.
+    target_profile: Option<frbc::FillLevelTargetProfile>,
+    /// The operation mode the actuator is in and its factor, as last reported or instructed.
+    active: Option<(Id, f64)>,
+}
+
+/// What an operation mode of a battery does at a given fill level.
+pub(crate) struct ModeAtLevel<'a> {
+    pub(crate) mode: &'a frbc::OperationMode,
+    /// The power at factor 0 and at factor 1, in W.
+    pub(crate) power: (f64, f64),
+    /// The fill rate at factor 0 and at factor 1, in fill level per second.
+    pub(crate) fill_rate: (f64, f64),
+    /// The costs of running the mode besides the energy, such as wear, per second. S2 gives these as a range for how
+    /// uncertain they are, not as costs that change with the factor, so this is the upper end of the range.
+    pub(crate) running_costs: f64,
+}
+
+impl ModeAtLevel<'_> {
+    pub(crate) fn power(&self, factor: f64) -> f64 {
+        self.power.0 + factor * (self.power.1 - self.power.0)
+    }
+
+    pub(crate) fn fill_rate(&self, factor: f64) -> f64 {
+        self.fill_rate.0 + factor * (self.fill_rate.1 - self.fill_rate.0)
+    }
+
+    /// The lowest and highest power of the mode, in W.
+    fn power_range(&self) -> (f64, f64) {
+        (
+            self.power.0.min(self.power.1),
+            self.power.0.max(self.power.1),
+        )
+    }
+
+    /// The factor at which the mode has the given power, as far as it can.
+    fn factor_for(&self, power: f64) -> f64 {
+        if self.power.0 == self.power.1 {
+            0.0
+        } else {
+            ((power - self.power.0) / (self.power.1 - self.power.0)).clamp(0.0, 1.0)
+        }
+    }
+}
+
+impl Battery {
+    pub(crate) fn update(&mut self, message: &Message) {
+        match message {
+            Message::FrbcSystemDescription(description) => {
+                self.description = Some(description.clone())
+            }
+            Message::FrbcStorageStatus(status) => self.fill_level = Some(status.present_fill_level),
+            Message::FrbcFillLevelTargetProfile(profile) => {
+                self.target_profile = Some(profile.clone())
+            }
+            Message::FrbcActuatorStatus(status) => {
+                self.active = Some((
+                    status.active_operation_mode_id.clone(),
+                    status.operation_mode_factor,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn fill_level(&self) -> Option<f64> {
+        self.fill_level
+    }
+
+    pub(crate) fn target_profile(&self) -> Option<&frbc::FillLevelTargetProfile> {
+        self.target_profile.as_ref()
+    }
+
+    /// The lowest and highest fill level of the storage, once the RM described it.
+    pub(crate) fn fill_level_range(&self) -> Option<(f64, f64)> {
+        let range = &self.description.as_ref()?.storage.fill_level_range;
+        Some((range.start_of_range, range.end_of_range))
+    }
+
+    /// What the operation modes of the first actuator do at `fill_level`, leaving out those for abnormal conditions.
+    pub(crate) fn modes_at(&self, fill_level: f64) -> Vec<ModeAtLevel<'_>> {
+        let Some(actuator) = self
+            .description
+            .as_ref()
+            .and_then(|description| description.actuators.first())
+        else {
+            return Vec::new();
+        };
+        actuator
+            .operation_modes
+            .iter()
+            .filter(|mode| !mode.abnormal_condition_only)
+            .filter_map(|mode| {
+                let element = mode.elements.iter().find(|element| {
+                    element.fill_level_range.start_of_range <= fill_level
+                        && fill_level <= element.fill_level_range.end_of_range
+                })?;
+                let power = |end: fn(&s2energy::common::PowerRange) -> f64| {
+                    element.power_ranges.iter().map(end).sum::<f64>()
+                };
+                Some(ModeAtLevel {
+                    mode,
+                    power: (
+                        power(|range| range.start_of_range),
+                        power(|range| range.end_of_range),
+                    ),
+                    fill_rate: (
+                        element.fill_rate.start_of_range,
+                        element.fill_rate.end_of_range,
+                    ),
+                    running_costs: element
+                        .running_costs
+                        .as_ref()
+                        .map_or(0.0, |costs| costs.start_of_range.max(costs.end_of_range)),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns an instruction that gets the power of the battery as close to `target` as it can without exceeding it,
+    /// with the power it's instructed to, unless the battery is already there.
+    ///
+    /// If the battery can't go as low as `target`, it's instructed to the lowest power it can.
+    pub(crate) fn instruction_for_power(
+        &mut self,
+        target: f64,
+    ) -> Option<(frbc::Instruction, f64)> {
+        let modes = self.modes_at(self.fill_level?);
+        let best = modes
+            .iter()
+            .filter(|mode| mode.power_range().0 <= target)
+            .map(|mode| (mode, mode.power_range().1.min(target)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        let lowest = || {
+            modes
+                .iter()
+                .map(|mode| (mode, mode.power_range().0))
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        };
+        let (mode, power) = best.or_else(lowest)?;
+        let (mode_id, factor) = (mode.mode.id.clone(), mode.factor_for(power));
+        let instruction = self.instruction(mode_id, factor)?;
+        Some((instruction, power))
+    }
+
+    /// Returns an instruction to switch the first actuator to `operation_mode` with `factor`, unless it's already
+    /// there.
+    pub(crate) fn instruction(
+        &mut self,
+        operation_mode: Id,
+        factor: f64,
+    ) -> Option<frbc::Instruction> {
+        let actuator_id = self.description.as_ref()?.actuators.first()?.id.clone();
+        let unchanged = self.active.as_ref().is_some_and(|(active, active_factor)| {
+            *active == operation_mode && (active_factor - factor).abs() < FACTOR_TOLERANCE
+        });
+        if unchanged {
+            return None;
+        }
+        self.active = Some((operation_mode.clone(), factor));
+        Some(frbc::Instruction::new(
+            false,
+            actuator_id,
+            Utc::now(),
+            Id::generate(),
+            operation_mode,
+            factor,
+        ))
+    }
+}
@@ -0,0 +1,444 @@
+use crate::battery::Battery;
+use crate::day_ahead::{self, DayAhead, Plan};
+use crate::ddbc::DdbcDevice;
+use crate::grid_limits::{GridLimits, Share};
+use crate::ombc::OmbcDevice;
+use crate::peak_shaving::{self, PeakShaving};
+use crate::pebc::PebcDevice;
+use crate::ppbc::PpbcAppliance;
+use crate::server::Options;
+use crate::state::PlannedPower;
+use crate::strategy::{CemStrategy, Decision, Rm, Site};
+use crate::tariffs::Tariffs;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use s2energy::common::{ControlType, Message};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// The strategies that come with the CEM, which the options choose: keeping the site under the power limit, using what
+/// it produces itself or following the prices with the FRBC storages and OMBC devices, keeping the PEBC RMs within the
+/// grid limits, scheduling the PPBC appliances and supplying the demand of the DDBC devices.
+pub(crate) struct Bundled {
+    peak_shaving: Option<PeakShaving>,
+    /// Whether the FRBC storages and OMBC devices follow the day-ahead prices.
+    follows_prices: bool,
+    /// Whether the storages are planned for the prices with a linear program.
+    lp_planner: bool,
+    grid_limits: Option<GridLimits>,
+    tariffs: Tariffs,
+    /// The devices the CEM instructs, by session number.
+    devices: Mutex<HashMap<usize, Devices>>,
+}
+
+/// The device of one RM, for the control type that was selected.
+#[derive(Default)]
+struct Devices {
+    /// The battery, if the RM is an FRBC storage and the CEM instructs it.
+    battery: Option<Battery>,
+    /// The device, if the RM controls it with OMBC and the CEM instructs it.
+    ombc: Option<OmbcDevice>,
+    /// The device, if the RM controls it with PEBC and the CEM keeps it within the grid limits.
+    pebc: Option<PebcDevice>,
+    /// The appliance, if the RM controls it with PPBC; the CEM always schedules its power sequences, since it doesn't
+    /// run otherwise.
+    ppbc: Option<PpbcAppliance>,
+    /// The device, if the RM controls it with DDBC; the CEM always instructs it, since it has a demand to supply.
+    ddbc: Option<DdbcDevice>,
+    /// Whether the CEM should plan the RM again for the prices, because the RM changed.
+    plan_due: bool,
+    /// The hour the CEM last planned the RM in; it plans again every hour.
+    planned_in: Option<DateTime<Utc>>,
+}
+
+impl Bundled {
+    pub(crate) fn new(options: &Options) -> Self {
+        let peak_shaving = if options.self_consumption {
+            Some(PeakShaving::self_consumption())
+        } else {
+            options.power_limit.map(PeakShaving::new)
+        };
+        // The demand response events may set grid limits of their own.
+        let grid_limits = (options.power_limit.is_some()
+            || options.feed_in_limit.is_some()
+            || options.openadr.is_some())
+        .then(|| GridLimits::new(options.power_limit, options.feed_in_limit));
+        Self {
+            peak_shaving,
+            follows_prices: options.prices.is_some(),
+            lp_planner: options.lp_planner,
+            grid_limits,
+            tariffs: Tariffs {
+                gas: options.gas_price,
+                heat: options.heat_price,
+            },
+            devices: Mutex::default(),
+        }
+    }
+
+    /// Lowers the grid limits to those of the demand response events going on, for as long as they last: the PEBC RMs
+    /// get power envelopes for them, and with a power limit, the batteries, OMBC loads and DDBC devices keep the site
+    /// under the consumption limit too.
+    pub(crate) fn set_event_limits(&self, limits: Share) {
+        if let Some(grid_limits) = &self.grid_limits {
+            grid_limits.set_event_limits(limits);
+        }
+        if let Some(peak_shaving) = &self.peak_shaving {
+            peak_shaving.set_event_limit(limits.consumption);
+        }
+    }
+
+    /// Instructs the battery to aim for its share of keeping the site under the power limit, or of using what the site
+    /// produces itself, if it isn't already.
+    fn shave_peaks(&self, rm: &Rm<'_>, devices: &mut Devices, decision: &mut Decision) {
+        let (Some(peak_shaving), Some(battery)) = (&self.peak_shaving, &mut devices.battery) else {
+            return;
+        };
+        let Some((instruction, power)) =
+            battery.instruction_for_power(peak_shaving.battery_target())
+        else {
+            return;
+        };
+        tracing::info!(
+            "Instructing the battery at {} to {power:.0} W, {}",
+            rm.state.rm_address,
+            peak_shaving.goal()
+        );
+        decision.instructions.push(instruction.into());
+    }
+
+    /// Instructs the OMBC load to the operation mode that uses the most power that fits under the power limit, given
+    /// what the rest of the site uses, if it isn't already.
+    fn limit_load(&self, rm: &Rm<'_>, devices: &mut Devices, decision: &mut Decision) {
+        let (Some(peak_shaving), Some(device)) = (&self.peak_shaving, &mut devices.ombc) else {
+            return;
+        };
+        let Some((operation_mode, factor, power)) = device.fit(peak_shaving.room(rm.session))
+        else {
+            return;
+        };
+        let Some(instruction) = device.instruction(operation_mode.clone(), factor) else {
+            return;
+        };
+        tracing::info!(
+            "Instructing the RM at {} to operation mode {operation_mode:?} with factor {factor:.2}, at {power:.0} W, {}",
+            rm.state.rm_address,
+            peak_shaving.goal()
+        );
+        decision.instructions.push(instruction.into());
+    }
+
+    /// Plans the RM for the prices when that's due, and instructs it to do what the plan says for now, if it isn't
+    /// already.
+    fn follow_prices(
+        &self,
+        rm: &Rm<'_>,
+        day_ahead: Option<&DayAhead>,
+        devices: &mut Devices,
+        decision: &mut Decision,
+    ) {
+        let Some(day_ahead) = day_ahead else {
+            return;
+        };
+        let now = Utc::now();
+        let hour = now.duration_trunc(TimeDelta::hours(1)).unwrap_or(now);
+        if !devices.plan_due && devices.planned_in == Some(hour) {
+            return;
+        }
+        let (plan, instruction): (Plan, Option<Message>) =
+            match (&mut devices.battery, &mut devices.ombc) {
+                (Some(battery), _) => {
+                    let Some(plan) = day_ahead.plan_storage(battery, now) else {
+                        return;
+                    };
+                    let instruction = battery.instruction(plan.operation_mode.clone(), plan.factor);
+                    (plan, instruction.map(Message::from))
+                }
+                (None, Some(device)) => {
+                    let Some(plan) = day_ahead.plan_ombc(device, &self.tariffs, now) else {
+                        return;
+                    };
+                    let instruction = device.instruction(plan.operation_mode.clone(), plan.factor);
+                    (plan, instruction.map(Message::from))
+                }
+                (None, None) => return,
+            };
+        devices.plan_due = false;
+        devices.planned_in = Some(hour);
+        decision.planned_power = Some(
+            plan.power
+                .iter()
+                .map(|(start, power)| PlannedPower {
+                    start: *start,
+                    power: *power,
+                })
+                .collect(),
+        );
+        if let Some(instruction) = instruction {
+            tracing::info!(
+                "Instructing the RM at {} to {:.0} W, following a plan for the next 24 hours that costs {:.2}",
+                rm.state.rm_address,
+                plan.power[0].1,
+                plan.cost
+            );
+            decision.instructions.push(instruction);
+        }
+    }
+
+    /// Sends the PEBC RM power envelopes for its share of the grid limits, when that or its power constraints changed.
+    fn limit_envelopes(&self, rm: &Rm<'_>, devices: &mut Devices, decision: &mut Decision) {
+        let (Some(grid_limits), Some(pebc)) = (&self.grid_limits, &mut devices.pebc) else {
+            return;
+        };
+        let share = grid_limits.share();
+        let Some(instruction) = pebc.instruction(share) else {
+            return;
+        };
+        let limits = instruction
+            .power_envelopes
+            .iter()
+            .map(|envelope| {
+                let element = &envelope.power_envelope_elements[0];
+                format!(
+                    "{:?} between {:.0} W and {:.0} W",
+                    envelope.commodity_quantity, element.lower_limit, element.upper_limit
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        tracing::info!(
+            "Sending the RM at {} power envelopes to stay within the grid limits: {limits}",
+            rm.state.rm_address
+        );
+        decision.instructions.push(instruction.into());
+    }
+
+    /// Schedules the power sequences of the PPBC appliance, when it sent a power profile that isn't scheduled yet.
+    fn schedule_appliance(
+        &self,
+        rm: &Rm<'_>,
+        day_ahead: Option<&DayAhead>,
+        devices: &mut Devices,
+        decision: &mut Decision,
+    ) {
+        let Some(ppbc) = &mut devices.ppbc else {
+            return;
+        };
+        let grid_limits = self
+            .grid_limits
+            .as_ref()
+            .map(|grid_limits| (grid_limits, rm.session));
+        let scheduled = ppbc.schedule(day_ahead, grid_limits, Utc::now());
+        if scheduled.is_empty() {
+            return;
+        }
+        let mut planned = Vec::new();
+        for sequence in scheduled {
+            let cost = sequence
+                .cost
+                .map(|cost| format!(", which costs {cost:.2}"))
+                .unwrap_or_default();
+            tracing::info!(
+                "Scheduling power sequence {:?} of the RM at {} from {} to {}{cost}",
+                sequence.instruction.power_sequence_id,
+                rm.state.rm_address,
+                sequence.instruction.execution_time,
+                sequence.end
+            );
+            decision.instructions.push(sequence.instruction.into());
+            planned.extend(sequence.power);
+            planned.push((sequence.end, 0.0));
+        }
+        if let Some(grid_limits) = &self.grid_limits {
+            let end = planned.last().map(|(end, _)| *end).unwrap_or_default();
+            grid_limits.schedule(rm.session, planned.clone(), end);
+        }
+        decision.planned_power = Some(
+            planned
+                .into_iter()
+                .map(|(start, power)| PlannedPower { start, power })
+                .collect(),
+        );
+    }
+
+    /// Instructs the DDBC device to supply its demand in the operation mode the CEM picks for now, if it isn't already
+    /// in it.
+    fn supply_demand(
+        &self,
+        rm: &Rm<'_>,
+        day_ahead: Option<&DayAhead>,
+        devices: &mut Devices,
+        decision: &mut Decision,
+    ) {
+        let Some(ddbc) = &mut devices.ddbc else {
+            return;
+        };
+        let room = self
+            .peak_shaving
+            .as_ref()
+            .map(|peak_shaving| peak_shaving.room(rm.session));
+        let price = day_ahead.map(|day_ahead| day_ahead.price(Utc::now()));
+        let Some(option) = ddbc.choose(room, price, &self.tariffs) else {
+            return;
+        };
+        let Some(instruction) = ddbc.instruction(option.operation_mode.clone(), option.factor)
+        else {
+            return;
+        };
+        tracing::info!(
+            "Instructing the RM at {} to operation mode {:?} with factor {:.2}, to supply {} at {:.0} W",
+            rm.state.rm_address,
+            option.operation_mode,
+            option.factor,
+            option.supply,
+            option.power
+        );
+        decision.instructions.push(instruction.into());
+    }
+}
+
+impl CemStrategy for Bundled {
+    fn add(&self, rm: &Rm<'_>) -> Option<watch::Receiver<()>> {
+        let control_type = rm.state.control_type?;
+        let instructed = self.peak_shaving.is_some() || self.follows_prices;
+        let mut devices = Devices {
+            plan_due: true,
+            ..Devices::default()
+        };
+        match control_type {
+            ControlType::FillRateBasedControl if instructed => {
+                devices.battery = Some(Battery::default());
+            }
+            ControlType::OperationModeBasedControl if instructed => {
+                devices.ombc = Some(OmbcDevice::default());
+            }
+            ControlType::PowerEnvelopeBasedControl if self.grid_limits.is_some() => {
+                devices.pebc = Some(PebcDevice::default());
+            }
+            ControlType::PowerProfileBasedControl => devices.ppbc = Some(PpbcAppliance::default()),
+            ControlType::DemandDrivenBasedControl => devices.ddbc = Some(DdbcDevice::default()),
+            _ => {}
+        }
+        // The power the battery should aim for, the room the DDBC device and OMBC load have and the share of the grid
+        // limits of the PEBC RM change with the rest of the site.
+        let changed = match (&self.peak_shaving, &self.grid_limits) {
+            (Some(peak_shaving), _) if devices.battery.is_some() => {
+                Some(peak_shaving.add_battery(rm.session))
+            }
+            (Some(peak_shaving), _) if devices.ddbc.is_some() || devices.ombc.is_some() => {
+                Some(peak_shaving.subscribe())
+            }
+            (_, Some(grid_limits)) if devices.pebc.is_some() => {
+                Some(grid_limits.add_rm(rm.session))
+            }
+            _ => None,
+        };
+        self.devices.lock().unwrap().insert(rm.session, devices);
+        changed
+    }
+
+    fn receive(&self, rm: &Rm<'_>, message: &Message) {
+        let mut all_devices = self.devices.lock().unwrap();
+        let devices = all_devices.get_mut(&rm.session);
+        let battery = devices
+            .as_ref()
+            .is_some_and(|devices| devices.battery.is_some());
+        if let Some(devices) = devices {
+            devices.update(rm, message);
+        }
+        drop(all_devices);
+        // What an RM in quarantine measures doesn't count until it's admitted, and the power of a battery is what
+        // the strategy controls, so it doesn't count as measured.
+        let peak_shaving = self
+            .peak_shaving
+            .as_ref()
+            .filter(|_| !rm.state.quarantined && !battery);
+        match (peak_shaving, message) {
+            (Some(peak_shaving), Message::PowerMeasurement(measurement)) => {
+                if let Some(power) = peak_shaving::electric_power(&measurement.values) {
+                    peak_shaving.measure(rm.session, power);
+                }
+            }
+            (Some(peak_shaving), Message::PowerForecast(forecast)) => {
+                peak_shaving.forecast(rm.session, forecast.clone());
+            }
+            _ => {}
+        }
+    }
+
+    fn decide(&self, rm: &Rm<'_>, site: &Site<'_>) -> Decision {
+        let mut decision = Decision::default();
+        // The prices the site has now, which are updated as the market publishes them.
+        let day_ahead = site
+            .prices
+            .clone()
+            .map(|prices| DayAhead::new(prices, self.lp_planner));
+        let day_ahead = day_ahead.as_ref();
+        let mut all_devices = self.devices.lock().unwrap();
+        let Some(devices) = all_devices.get_mut(&rm.session) else {
+            return decision;
+        };
+        self.shave_peaks(rm, devices, &mut decision);
+        self.limit_load(rm, devices, &mut decision);
+        self.follow_prices(rm, day_ahead, devices, &mut decision);
+        self.limit_envelopes(rm, devices, &mut decision);
+        self.schedule_appliance(rm, day_ahead, devices, &mut decision);
+        self.supply_demand(rm, day_ahead, devices, &mut decision);
+        decision
+    }
+
+    /// The DDBC device also picks its operation mode again, and the others are planned again, when the price changes.
+    fn next_decision(&self, _rm: &Rm<'_>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.follows_prices.then(|| day_ahead::next_hour(now))
+    }
+
+    fn remove(&self, session: usize) {
+        self.devices.lock().unwrap().remove(&session);
+        if let Some(peak_shaving) = &self.peak_shaving {
+            peak_shaving.remove(session);
+        }
+        if let Some(grid_limits) = &self.grid_limits {
+            grid_limits.remove(session);
+        }
+    }
+}
+
+impl Devices {
+    /// Passes a message from the RM on to the device the CEM instructs, if any.
+    fn update(&mut self, rm: &Rm<'_>, message: &Message) {
+        if matches!(
+            message,
+            Message::FrbcSystemDescription(_)
+                | Message::FrbcFillLevelTargetProfile(_)
+                | Message::OmbcSystemDescription(_)
+        ) {
+            self.plan_due = true;
+        }
+        if let Some(battery) = &mut self.battery {
+            battery.update(message);
+        }
+        if let Some(ombc) = &mut self.ombc {
+            ombc.update(message);
+        }
+        if let Some(pebc) = &mut self.pebc {
+            pebc.update(message);
+        }
+        if let Some(ddbc) = &mut self.ddbc {
+            ddbc.update(message);
+        }
+        if let Some(ppbc) = &mut self.ppbc {
+            for container in ppbc.update(message) {
+                let progress = container
+                    .progress
+                    .map(|progress| format!(", {} s in", *progress / 1000))
+                    .unwrap_or_default();
+                tracing::info!(
+                    "Sequence container {:?} of the RM at {} is {:?}{progress}",
+                    container.sequence_container_id,
+                    rm.state.rm_address,
+                    container.status
+                );
+            }
+        }
+    }
+}
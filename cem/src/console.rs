@@ -0,0 +1,398 @@
+use crate::api::{self, Sessions};
+use crate::pebc;
+use crate::state::SessionState;
+use chrono::{TimeDelta, Utc};
+use eyre::{bail, eyre, Context};
+use s2energy::common::{Duration, Id, Message};
+use s2energy::{ddbc, frbc, ombc, pebc as s2_pebc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// How long the power envelopes of the `pebc` command last if it doesn't say.
+const DEFAULT_ENVELOPE_MINUTES: i64 = 60;
+
+const HELP: &str = "\
+Commands:
+  list                                    the connected RMs, with their operation modes
+  frbc <rm> <mode> [factor]               an FRBC instruction; the mode can also be charge, discharge or idle
+  ombc <rm> <mode> [factor]               an OMBC instruction
+  ddbc <rm> <mode> [factor]               a DDBC instruction
+  pebc <rm> <lower> <upper> [minutes]     a power envelope in W for every electric commodity quantity
+  send <rm> <json>                        any instruction, as S2 JSON
+  help                                    this list
+
+An RM is its session number, or the start of its name or resource ID. A mode is the ID of an operation mode, or the
+start of its label. The factor is 1 if it's left out. The CEM leaves an RM it got an instruction for by hand alone for
+a while, like with the instructions from the API.";
+
+/// Reads commands from the terminal until it closes, to list the connected RMs and send them instructions by hand.
+pub(crate) async fn run(sessions: Arc<Sessions>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    println!("The console is ready; type help for the commands");
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(error) => {
+                tracing::warn!("Could not read from the console: {error}");
+                return;
+            }
+        };
+        match execute(&sessions, &line) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{output}"),
+            Err(error) => println!("{error:#}"),
+        }
+    }
+}
+
+/// Carries out one command, and returns what to print.
+fn execute(sessions: &Sessions, line: &str) -> eyre::Result<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((command, arguments)) = words.split_first() else {
+        return Ok(String::new());
+    };
+    let states = sessions.states();
+    match *command {
+        "help" => Ok(HELP.into()),
+        "list" => Ok(list(&states)),
+        "frbc" | "ombc" | "ddbc" | "pebc" | "send" => {
+            let Some(rm) = arguments.first() else {
+                bail!("Which RM? Type list to see them");
+            };
+            let (session, state) = find_rm(&states, rm)?;
+            let arguments = &arguments[1..];
+            let instruction = match *command {
+                "frbc" => frbc_instruction(state, arguments)?,
+                "ombc" => ombc_instruction(state, arguments)?,
+                "ddbc" => ddbc_instruction(state, arguments)?,
+                "pebc" => pebc_instruction(state, arguments)?,
+                _ => {
+                    // The JSON is what follows the RM, as it was typed.
+                    let json = line.trim_start()[command.len()..].trim_start()[rm.len()..].trim();
+                    let message: Message =
+                        serde_json::from_str(json).wrap_err("That's not an S2 message")?;
+                    if !api::is_instruction(&message) {
+                        bail!("The message should be an instruction, such as an FRBC.Instruction");
+                    }
+                    message
+                }
+            };
+            sessions.instruct(session, instruction)?;
+            Ok(format!(
+                "Sending the instruction to the RM of session {session}"
+            ))
+        }
+        _ => bail!("There's no command {command}; type help for the commands"),
+    }
+}
+
+/// A line for every connected RM, and an indented one with the operation modes it has, if any.
+fn list(states: &BTreeMap<usize, SessionState>) -> String {
+    if states.is_empty() {
+        return "No RMs are connected".into();
+    }
+    let mut lines = Vec::new();
+    for (session, state) in states {
+        let name = state.rm_details.as_ref().map_or("?".into(), |details| {
+            format!(
+                "{} ({})",
+                details.name.as_deref().unwrap_or("no name"),
+                *details.resource_id
+            )
+        });
+        let control_type = state
+            .control_type
+            .map_or("no control type yet".into(), |control_type| {
+                format!("{control_type:?}")
+            });
+        let mut line = format!("{session}: {name} at {}, {control_type}", state.rm_address);
+        if state.quarantined {
+            line.push_str(", in quarantine");
+        }
+        if let Some(until) = state.manual_until {
+            line.push_str(&format!(
+                ", instructed by hand until {} UTC",
+                until.format("%H:%M")
+            ));
+        }
+        lines.push(line);
+        let modes: Vec<String> = operation_modes(state)
+            .into_iter()
+            .map(|(id, label)| match label {
+                Some(label) => format!("{label} ({id})"),
+                None => id.to_string(),
+            })
+            .collect();
+        if !modes.is_empty() {
+            lines.push(format!("    operation modes: {}", modes.join(", ")));
+        }
+    }
+    lines.join("\n")
+}
+
+/// The IDs and labels of the operation modes in the latest system description of the RM.
+fn operation_modes(state: &SessionState) -> Vec<(&str, Option<&str>)> {
+    let mut modes = Vec::new();
+    for message in state.latest_messages.values() {
+        match message {
+            Message::FrbcSystemDescription(description) => {
+                for mode in description
+                    .actuators
+                    .iter()
+                    .flat_map(|actuator| &actuator.operation_modes)
+                {
+                    modes.push((mode.id.as_str(), mode.diagnostic_label.as_deref()));
+                }
+            }
+            Message::OmbcSystemDescription(description) => {
+                for mode in &description.operation_modes {
+                    modes.push((mode.id.as_str(), mode.diagnostic_label.as_deref()));
+                }
+            }
+            Message::DdbcSystemDescription(description) => {
+                for mode in description
+                    .actuators
+                    .iter()
+                    .flat_map(|actuator| &actuator.operation_modes)
+                {
+                    modes.push((mode.id.as_str(), mode.diagnostic_label.as_deref()));
+                }
+            }
+            _ => {}
+        }
+    }
+    modes
+}
+
+/// The session with the RM `name` refers to: its session number, or the start of its name or resource ID.
+fn find_rm<'a>(
+    states: &'a BTreeMap<usize, SessionState>,
+    name: &str,
+) -> eyre::Result<(usize, &'a SessionState)> {
+    if let Ok(session) = name.parse() {
+        let state = states
+            .get(&session)
+            .ok_or_else(|| eyre!("There's no session {session}"))?;
+        return Ok((session, state));
+    }
+    let matches: Vec<(usize, &SessionState)> = states
+        .iter()
+        .filter(|(_, state)| {
+            state.rm_details.as_ref().is_some_and(|details| {
+                starts_with(&details.resource_id, name)
+                    || details
+                        .name
+                        .as_deref()
+                        .is_some_and(|rm_name| starts_with(rm_name, name))
+            })
+        })
+        .map(|(session, state)| (*session, state))
+        .collect();
+    match matches[..] {
+        [found] => Ok(found),
+        [] => bail!("There's no RM {name}; type list to see them"),
+        _ => bail!("There's more than one RM {name}; use its session number"),
+    }
+}
+
+/// The one operation mode `name` refers to, out of `modes` with their ID and label: the one with that ID, or that
+/// label or one that starts with it, ignoring case. The modes that `keyword` says match are taken too.
+fn find_mode<'a, T>(
+    modes: impl IntoIterator<Item = (&'a str, Option<&'a str>, T)>,
+    name: &str,
+    keyword: impl Fn(&T) -> bool,
+) -> eyre::Result<T> {
+    let mut labels = Vec::new();
+    let mut matches = Vec::new();
+    for (id, label, mode) in modes {
+        labels.push(label.unwrap_or(id));
+        if id == name || label.is_some_and(|label| starts_with(label, name)) || keyword(&mode) {
+            matches.push(mode);
+        }
+    }
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 if labels.is_empty() => bail!("The RM hasn't described its operation modes yet"),
+        0 => bail!(
+            "There's no operation mode {name}; the RM has {}",
+            labels.join(", ")
+        ),
+        _ => bail!("There's more than one operation mode {name}; use its ID"),
+    }
+}
+
+fn starts_with(text: &str, start: &str) -> bool {
+    text.to_lowercase().starts_with(&start.to_lowercase())
+}
+
+/// The operation mode and factor in `arguments`, with a factor of 1 if there isn't one.
+fn mode_and_factor<'a>(arguments: &[&'a str]) -> eyre::Result<(&'a str, f64)> {
+    let (mode, factor) = match arguments {
+        [mode] => (*mode, 1.0),
+        [mode, factor] => {
+            let factor: f64 = factor
+                .parse()
+                .wrap_err_with(|| format!("The factor {factor} isn't a number"))?;
+            (*mode, factor)
+        }
+        _ => bail!("Give an operation mode, and optionally a factor"),
+    };
+    if !(0.0..=1.0).contains(&factor) {
+        bail!("The factor should be from 0 to 1");
+    }
+    Ok((mode, factor))
+}
+
+fn frbc_instruction(state: &SessionState, arguments: &[&str]) -> eyre::Result<Message> {
+    let (name, factor) = mode_and_factor(arguments)?;
+    let Some(Message::FrbcSystemDescription(description)) =
+        state.latest_messages.get("FRBC.SystemDescription")
+    else {
+        bail!("The RM hasn't sent an FRBC.SystemDescription");
+    };
+    let modes = description.actuators.iter().flat_map(|actuator| {
+        actuator.operation_modes.iter().map(move |mode| {
+            let label = mode.diagnostic_label.as_deref();
+            (mode.id.as_str(), label, (&actuator.id, mode))
+        })
+    });
+    let (actuator_id, mode) = find_mode(modes, name, |(_, mode)| {
+        direction(mode).is_some_and(|direction| direction.eq_ignore_ascii_case(name))
+    })?;
+    Ok(frbc::Instruction::new(
+        false,
+        actuator_id.clone(),
+        Utc::now(),
+        Id::generate(),
+        mode.id.clone(),
+        factor,
+    )
+    .into())
+}
+
+/// Whether an FRBC operation mode fills the storage, empties it, or neither, as `charge`, `discharge` or `idle`.
+fn direction(mode: &frbc::OperationMode) -> Option<&'static str> {
+    let rates = mode.elements.iter().flat_map(|element| {
+        [
+            element.fill_rate.start_of_range,
+            element.fill_rate.end_of_range,
+        ]
+    });
+    let (lowest, highest) = rates.fold(
+        (f64::INFINITY, f64::NEG_INFINITY),
+        |(lowest, highest), rate| (lowest.min(rate), highest.max(rate)),
+    );
+    if lowest == 0.0 && highest == 0.0 {
+        Some("idle")
+    } else if lowest >= 0.0 && highest > 0.0 {
+        Some("charge")
+    } else if highest <= 0.0 && lowest < 0.0 {
+        Some("discharge")
+    } else {
+        None
+    }
+}
+
+fn ombc_instruction(state: &SessionState, arguments: &[&str]) -> eyre::Result<Message> {
+    let (name, factor) = mode_and_factor(arguments)?;
+    let Some(Message::OmbcSystemDescription(description)) =
+        state.latest_messages.get("OMBC.SystemDescription")
+    else {
+        bail!("The RM hasn't sent an OMBC.SystemDescription");
+    };
+    let modes = description
+        .operation_modes
+        .iter()
+        .map(|mode| (mode.id.as_str(), mode.diagnostic_label.as_deref(), mode));
+    let mode = find_mode(modes, name, |_| false)?;
+    Ok(ombc::Instruction::new(false, Utc::now(), Id::generate(), factor, mode.id.clone()).into())
+}
+
+fn ddbc_instruction(state: &SessionState, arguments: &[&str]) -> eyre::Result<Message> {
+    let (name, factor) = mode_and_factor(arguments)?;
+    let Some(Message::DdbcSystemDescription(description)) =
+        state.latest_messages.get("DDBC.SystemDescription")
+    else {
+        bail!("The RM hasn't sent a DDBC.SystemDescription");
+    };
+    let modes = description.actuators.iter().flat_map(|actuator| {
+        actuator.operation_modes.iter().map(move |mode| {
+            let label = mode.diagnostic_label.as_deref();
+            (mode.id.as_str(), label, (&actuator.id, mode))
+        })
+    });
+    let (actuator_id, mode) = find_mode(modes, name, |_| false)?;
+    Ok(Message::DdbcInstruction(ddbc::Instruction {
+        abnormal_condition: false,
+        actuator_id: actuator_id.clone(),
+        execution_time: Utc::now(),
+        id: Id::generate(),
+        message_id: Id::generate(),
+        operation_mode_factor: factor,
+        operation_mode_id: mode.id.clone(),
+    }))
+}
+
+/// An instruction with a power envelope from `lower` to `upper` W for every electric commodity quantity the RM allows
+/// limits for, which lasts for the given minutes.
+fn pebc_instruction(state: &SessionState, arguments: &[&str]) -> eyre::Result<Message> {
+    let number = |text: &str| -> eyre::Result<f64> {
+        text.parse()
+            .wrap_err_with(|| format!("{text} isn't a number"))
+    };
+    let (lower, upper, minutes) = match arguments {
+        [lower, upper] => (number(lower)?, number(upper)?, DEFAULT_ENVELOPE_MINUTES),
+        [lower, upper, minutes] => {
+            let minutes = minutes
+                .parse()
+                .wrap_err_with(|| format!("{minutes} isn't a number of minutes"))?;
+            (number(lower)?, number(upper)?, minutes)
+        }
+        _ => bail!("Give a lower and an upper limit in W, and optionally for how many minutes"),
+    };
+    if lower > upper {
+        bail!("The lower limit should be under the upper limit");
+    }
+    if minutes <= 0 {
+        bail!("The envelope should last for at least a minute");
+    }
+    let Some(Message::PebcPowerConstraints(constraints)) =
+        state.latest_messages.get("PEBC.PowerConstraints")
+    else {
+        bail!("The RM hasn't sent PEBC.PowerConstraints");
+    };
+    let mut quantities: Vec<_> = constraints
+        .allowed_limit_ranges
+        .iter()
+        .map(|range| range.commodity_quantity)
+        .filter(|quantity| pebc::is_electric_power(*quantity))
+        .collect();
+    quantities.sort();
+    quantities.dedup();
+    if quantities.is_empty() {
+        bail!("The RM allows no limits for electric power");
+    }
+    let duration = TimeDelta::minutes(minutes).num_milliseconds() as u64;
+    let power_envelopes = quantities
+        .into_iter()
+        .map(|quantity| s2_pebc::PowerEnvelope {
+            commodity_quantity: quantity,
+            id: Id::generate(),
+            power_envelope_elements: vec![s2_pebc::PowerEnvelopeElement {
+                duration: Duration(duration),
+                lower_limit: lower,
+                upper_limit: upper,
+            }],
+        })
+        .collect();
+    Ok(Message::PebcInstruction(s2_pebc::Instruction {
+        abnormal_condition: false,
+        execution_time: Utc::now(),
+        id: Id::generate(),
+        message_id: Id::generate(),
+        power_constraints_id: constraints.id.clone(),
+        power_envelopes,
+    }))
+}
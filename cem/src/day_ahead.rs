@@ -0,0 +1,357 @@
+use crate::battery::Battery;
+use crate::ombc::{self, OmbcDevice};
+use crate::optimizer;
+use crate::tariffs::Tariffs;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use prices::Prices;
+use s2energy::common::Id;
+
+/// How long ahead the CEM plans.
+const HORIZON: TimeDelta = TimeDelta::hours(24);
+/// The number of steps the fill level range of a storage is divided in for planning.
+const FILL_LEVEL_STEPS: usize = 100;
+/// The operation mode factors the planner considers: off, half and full.
+const FACTORS: [f64; 3] = [0.0, 0.5, 1.0];
+
+/// Minimizes what the site pays for energy, given the day-ahead prices.
+///
+/// Every hour, and whenever an RM describes itself again, the CEM plans the next 24 hours for every RM it controls, and
+/// instructs it to do what the plan says for now. An FRBC storage, such as a battery or an EV, charges in the cheap
+/// hours and discharges in the expensive ones, as far as its fill level allows and the difference in price makes up
+/// for the running costs of its operation modes, such as wear. It ends the day at least as full as it
+/// started, so the plan doesn't empty the battery at the end of every horizon, unless the RM sent a fill level target
+/// profile, such as an EV that should be charged by the morning: then it meets the targets instead. An OMBC device
+/// picks the cheapest operation mode every hour, so a PV installation is curtailed when the price is negative, and a
+/// device that runs on gas or electricity, such as a CHP, runs on whichever is cheaper with the gas price.
+///
+/// The storages are planned with dynamic programming, or optionally with a linear program, in [`crate::optimizer`].
+pub(crate) struct DayAhead {
+    /// The day-ahead prices published so far; past them, the plan repeats the prices of the same time a day earlier.
+    prices: Prices,
+    /// Whether the storages are planned with a linear program, rather than with dynamic programming.
+    linear_program: bool,
+}
+
+/// The cheapest plan for an RM, for the next 24 hours.
+pub(crate) struct Plan {
+    /// The operation mode the RM should be in now, and its factor.
+    pub(crate) operation_mode: Id,
+    pub(crate) factor: f64,
+    /// The power the RM is planned to have from the start of every hour, in W.
+    pub(crate) power: Vec<(DateTime<Utc>, f64)>,
+    /// What the energy of the plan costs, in the currency of the prices.
+    pub(crate) cost: f64,
+}
+
+/// Something the planner can do with a storage at a fill level, for a whole slot.
+struct Action {
+    operation_mode: Id,
+    factor: f64,
+    power: f64,
+    fill_rate: f64,
+    /// Per second.
+    running_costs: f64,
+}
+
+impl DayAhead {
+    pub(crate) fn new(prices: Prices, linear_program: bool) -> Self {
+        Self {
+            prices,
+            linear_program,
+        }
+    }
+
+    /// The price of energy at `time`, per kWh: the mean price of its hour, since the CEM plans per hour, while some
+    /// markets have a price for every quarter.
+    pub(crate) fn price(&self, time: DateTime<Utc>) -> f64 {
+        let hour = time.duration_trunc(TimeDelta::hours(1)).unwrap_or(time);
+        self.prices
+            .mean(hour, hour + TimeDelta::hours(1))
+            .unwrap_or_else(|| self.prices.repeated_price_at(time))
+    }
+
+    /// What running at `power` W from `start` for `duration` costs, following the prices of the hours it spans.
+    pub(crate) fn cost(&self, start: DateTime<Utc>, duration: TimeDelta, power: f64) -> f64 {
+        hours(start, start + duration)
+            .into_iter()
+            .map(|(time, seconds)| energy_cost(self.price(time), power, seconds))
+            .sum()
+    }
+
+    /// Plans a storage with dynamic programming over its fill level, or with the linear program of
+    /// [`optimizer::plan_storage`] if the CEM should, and returns the cheapest plan; `None` until the RM described the
+    /// storage and reported its fill level.
+    pub(crate) fn plan_storage(&self, battery: &Battery, now: DateTime<Utc>) -> Option<Plan> {
+        if self.linear_program {
+            return optimizer::plan_storage(self, battery, now);
+        }
+        let (lowest, highest) = battery.fill_level_range()?;
+        if highest <= lowest {
+            return None;
+        }
+        let grid = Grid {
+            lowest,
+            step: (highest - lowest) / FILL_LEVEL_STEPS as f64,
+        };
+        let start = grid.index(battery.fill_level()?);
+        let actions: Vec<Vec<Action>> = (0..=FILL_LEVEL_STEPS)
+            .map(|index| {
+                battery
+                    .modes_at(grid.level(index))
+                    .iter()
+                    .flat_map(|mode| {
+                        FACTORS.map(|factor| Action {
+                            operation_mode: mode.mode.id.clone(),
+                            factor,
+                            power: mode.power(factor),
+                            fill_rate: mode.fill_rate(factor),
+                            running_costs: mode.running_costs,
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+        if actions[start].is_empty() {
+            return None;
+        }
+
+        let slots = slots(now);
+        let targets = targets(battery, &slots, start, &grid);
+        let (cost, choices) = self
+            .plan(&slots, &actions, &targets, start, &grid)
+            .or_else(|| {
+                // Planning without the targets is better than not planning at all.
+                tracing::warn!(
+                    "The storage can't meet its fill level targets, so the CEM plans without them"
+                );
+                self.plan(&slots, &actions, &vec![None; slots.len() + 1], start, &grid)
+            })?;
+
+        let mut current = start;
+        let mut power = Vec::new();
+        for (slot, (time, seconds)) in slots.iter().enumerate() {
+            let action = &actions[current][choices[slot][current]];
+            power.push((*time, action.power));
+            current = grid.index(grid.level(current) + action.fill_rate * seconds);
+        }
+        let first = &actions[start][choices[0][start]];
+        Some(Plan {
+            operation_mode: first.operation_mode.clone(),
+            factor: first.factor,
+            power,
+            cost,
+        })
+    }
+
+    /// Returns the cheapest cost from fill level `start`, and the action to take at every slot and fill level; `None`
+    /// if the targets can't be met.
+    fn plan(
+        &self,
+        slots: &[(DateTime<Utc>, f64)],
+        actions: &[Vec<Action>],
+        targets: &[Option<(usize, usize)>],
+        start: usize,
+        grid: &Grid,
+    ) -> Option<(f64, Vec<Vec<usize>>)> {
+        let allowed = |slot: usize, index: usize| {
+            targets[slot].is_none_or(|(lowest, highest)| lowest <= index && index <= highest)
+        };
+        let bounds = grid.level(0)..=grid.level(FILL_LEVEL_STEPS);
+        let mut costs: Vec<f64> = (0..=FILL_LEVEL_STEPS)
+            .map(|index| {
+                if allowed(slots.len(), index) {
+                    0.0
+                } else {
+                    f64::INFINITY
+                }
+            })
+            .collect();
+        let mut choices = vec![vec![0; FILL_LEVEL_STEPS + 1]; slots.len()];
+        for (slot, (time, seconds)) in slots.iter().enumerate().rev() {
+            let price = self.price(*time);
+            let next_costs = costs;
+            costs = (0..=FILL_LEVEL_STEPS)
+                .map(|current| {
+                    let mut best = f64::INFINITY;
+                    if !allowed(slot, current) {
+                        return best;
+                    }
+                    let level = grid.level(current);
+                    for (choice, action) in actions[current].iter().enumerate() {
+                        // The energy follows the fill level the storage ends up at: a storage that gets full or
+                        // empty within the slot stops there, and rounding to the grid doesn't make energy for free.
+                        let unbounded = level + action.fill_rate * seconds;
+                        let next = grid.index(unbounded.clamp(*bounds.start(), *bounds.end()));
+                        let share = if unbounded == level {
+                            1.0
+                        } else {
+                            (grid.level(next) - level) / (unbounded - level)
+                        };
+                        let cost = energy_cost(price, action.power, seconds * share)
+                            + action.running_costs * seconds * share
+                            + next_costs[next];
+                        if cost < best {
+                            best = cost;
+                            choices[slot][current] = choice;
+                        }
+                    }
+                    best
+                })
+                .collect();
+        }
+        costs[start].is_finite().then_some((costs[start], choices))
+    }
+
+    /// Plans an OMBC device: the cheapest operation mode for every hour, at factor 0 or 1, counting the gas and heat
+    /// it uses at the `tariffs` too. `None` until the RM described the device.
+    pub(crate) fn plan_ombc(
+        &self,
+        device: &OmbcDevice,
+        tariffs: &Tariffs,
+        now: DateTime<Utc>,
+    ) -> Option<Plan> {
+        // Every option has its electric power in W, and what its other commodities cost per second.
+        let options: Vec<(Id, f64, f64, f64)> = device
+            .operation_modes()
+            .flat_map(|mode| {
+                // Running at full power comes first, so it wins when the price is zero.
+                [1.0, 0.0].map(|factor| {
+                    let cost_rate = tariffs.cost_rate(&ombc::values(mode, factor));
+                    (
+                        mode.id.clone(),
+                        factor,
+                        ombc::electric_power(mode, factor),
+                        cost_rate,
+                    )
+                })
+            })
+            .collect();
+        let mut plan: Option<Plan> = None;
+        for (start, seconds) in slots(now) {
+            let price = self.price(start);
+            let cost = |(_, _, power, cost_rate): &(Id, f64, f64, f64)| {
+                energy_cost(price, *power, seconds) + cost_rate * seconds
+            };
+            let option = options.iter().min_by(|a, b| cost(a).total_cmp(&cost(b)))?;
+            let cost = cost(option);
+            let (operation_mode, factor, power, _) = option;
+            match &mut plan {
+                Some(plan) => {
+                    plan.power.push((start, *power));
+                    plan.cost += cost;
+                }
+                None => {
+                    plan = Some(Plan {
+                        operation_mode: operation_mode.clone(),
+                        factor: *factor,
+                        power: vec![(start, *power)],
+                        cost,
+                    })
+                }
+            }
+        }
+        plan
+    }
+}
+
+/// The slots of the horizon, as their start and their length in seconds: the rest of the current hour, then every
+/// hour, and the part of the hour the horizon ends in.
+pub(crate) fn slots(now: DateTime<Utc>) -> Vec<(DateTime<Utc>, f64)> {
+    hours(now, now + HORIZON)
+}
+
+/// Splits the time from `start` to `end` at every whole hour, into the start and the length in seconds of every part.
+fn hours(mut start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<(DateTime<Utc>, f64)> {
+    let mut slots = Vec::new();
+    while start < end {
+        let next_hour = start
+            .duration_trunc(TimeDelta::hours(1))
+            .map_or(end, |hour| hour + TimeDelta::hours(1))
+            .min(end);
+        slots.push((start, (next_hour - start).as_seconds_f64()));
+        start = next_hour;
+    }
+    slots
+}
+
+/// The start of the next hour, when the CEM plans again.
+pub(crate) fn next_hour(now: DateTime<Utc>) -> DateTime<Utc> {
+    now.duration_trunc(TimeDelta::hours(1))
+        .map_or(now, |hour| hour + TimeDelta::hours(1))
+}
+
+/// What running at `power` W for `seconds` costs at `price` per kWh.
+pub(crate) fn energy_cost(price: f64, power: f64, seconds: f64) -> f64 {
+    price * power / 1000.0 * seconds / 3600.0
+}
+
+/// The fill levels of a storage the planner considers: its fill level range in [`FILL_LEVEL_STEPS`] equal steps.
+struct Grid {
+    lowest: f64,
+    step: f64,
+}
+
+impl Grid {
+    fn level(&self, index: usize) -> f64 {
+        self.lowest + index as f64 * self.step
+    }
+
+    /// The index of the closest fill level.
+    fn index(&self, level: f64) -> usize {
+        (((level - self.lowest) / self.step).round().max(0.0) as usize).min(FILL_LEVEL_STEPS)
+    }
+}
+
+/// The lowest and highest fill level index the storage may be at, at the start of every slot and at the end of the
+/// horizon.
+fn targets(
+    battery: &Battery,
+    slots: &[(DateTime<Utc>, f64)],
+    start: usize,
+    grid: &Grid,
+) -> Vec<Option<(usize, usize)>> {
+    let Some(levels) = target_levels(battery, slots) else {
+        // Without targets, the storage ends at least as full as it started.
+        let mut targets = vec![None; slots.len() + 1];
+        targets[slots.len()] = Some((start, FILL_LEVEL_STEPS));
+        return targets;
+    };
+    levels
+        .into_iter()
+        .map(|range| {
+            range.map(|(lowest, highest)| {
+                let lowest = grid.index(lowest);
+                // Rounding may put the lowest index above the highest for a narrow range.
+                (lowest, grid.index(highest).max(lowest))
+            })
+        })
+        .collect()
+}
+
+/// The lowest and highest fill level the fill level target profile of the storage allows at the start of every slot
+/// and at the end of the horizon, except now, since where the storage is now can't be helped. `None` if the RM sent no
+/// profile.
+pub(crate) fn target_levels(
+    battery: &Battery,
+    slots: &[(DateTime<Utc>, f64)],
+) -> Option<Vec<Option<(f64, f64)>>> {
+    let profile = battery.target_profile()?;
+    let end = slots
+        .last()
+        .map(|(time, seconds)| *time + TimeDelta::milliseconds((seconds * 1000.0) as i64));
+    let times = slots.iter().map(|(time, _)| *time).chain(end);
+    let mut targets: Vec<_> = times
+        .map(|time| {
+            let mut element_start = profile.start_time;
+            profile.elements.iter().find_map(|element| {
+                let element_end = element_start + TimeDelta::milliseconds(*element.duration as i64);
+                let covers = element_start <= time && time < element_end;
+                element_start = element_end;
+                let range = &element.fill_level_range;
+                covers.then_some((range.start_of_range, range.end_of_range))
+            })
+        })
+        .collect();
+    targets[0] = None;
+    Some(targets)
+}
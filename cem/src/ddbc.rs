@@ -0,0 +1,158 @@
+use crate::peak_shaving;
+use crate::tariffs::Tariffs;
+use chrono::Utc;
+use s2energy::common::{Id, Message, NumberRange, PowerValue};
+use s2energy::ddbc;
+
+/// How much an operation mode factor may differ from the one the actuator already has before it gets a new
+/// instruction.
+const FACTOR_TOLERANCE: f64 = 0.01;
+
+/// What the CEM knows about a DDBC device, such as an electrolyzer, a furnace or a hybrid heat pump, to instruct it.
+///
+/// The CEM instructs the first actuator of the device, like it only instructs the first storage of an FRBC RM. It
+/// doesn't take timers and transitions into account: the RM rejects an instruction it can't follow yet.
+#[derive(Default)]
+pub(crate) struct DdbcDevice {
+    description: Option<ddbc::SystemDescription>,
+    /// The operation mode the actuator is in and its factor, as last reported or instructed.
+    active: Option<(Id, f64)>,
+}
+
+/// An operation mode of the actuator, at the factor where it supplies as little of the present demand as it can.
+pub(crate) struct DdbcOption {
+    pub(crate) operation_mode: Id,
+    pub(crate) factor: f64,
+    /// The supply rate at the factor.
+    pub(crate) supply: f64,
+    /// How much of the demand the operation mode can't supply, or 0 if it can supply all of it.
+    shortfall: f64,
+    /// The electric power at the factor, in W.
+    pub(crate) power: f64,
+    /// Per second: the upper end of the running costs of the operation mode, plus the gas and heat it uses at the
+    /// tariffs.
+    running_costs: f64,
+}
+
+impl DdbcDevice {
+    pub(crate) fn update(&mut self, message: &Message) {
+        match message {
+            Message::DdbcSystemDescription(description) => {
+                self.description = Some(description.clone())
+            }
+            Message::DdbcActuatorStatus(status)
+                if self
+                    .actuator()
+                    .is_some_and(|actuator| actuator.id == status.actuator_id) =>
+            {
+                self.active = Some((
+                    status.active_operation_mode_id.clone(),
+                    status.operation_mode_factor,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    fn actuator(&self) -> Option<&ddbc::ActuatorDescription> {
+        self.description.as_ref()?.actuators.first()
+    }
+
+    /// Picks the operation mode that supplies the present demand, and otherwise comes closest.
+    ///
+    /// This reduces the electric demand of the device as far as the demand it has to supply allows: every operation
+    /// mode supplies the lowest rate the demand allows, and of the operation modes that supply the demand, the CEM
+    /// prefers those whose power fits in `room`, the power the site has left under the limit. Of those, it picks the one
+    /// with the lowest running costs, plus what its electricity costs at `price` per kWh with day-ahead prices and what
+    /// its gas and heat cost at the `tariffs`, and then the one that uses the least power. A hybrid heat pump thus
+    /// switches to gas when the site has no room for the heat pump, or when electricity costs more than the gas it
+    /// takes for the same heat. `None` until the RM described the device.
+    pub(crate) fn choose(
+        &self,
+        room: Option<f64>,
+        price: Option<f64>,
+        tariffs: &Tariffs,
+    ) -> Option<DdbcOption> {
+        let description = self.description.as_ref()?;
+        let demand = low(&description.present_demand_rate);
+        let cost = |option: &DdbcOption| {
+            let energy = price.map_or(0.0, |price| price * option.power / 1000.0 / 3600.0);
+            option.running_costs + energy
+        };
+        self.actuator()?
+            .operation_modes
+            .iter()
+            .filter(|mode| !mode.abnormal_condition_only)
+            .map(|mode| option(mode, demand, tariffs))
+            .min_by(|a, b| {
+                let fits = |option: &DdbcOption| room.is_none_or(|room| option.power <= room);
+                a.shortfall
+                    .total_cmp(&b.shortfall)
+                    .then(fits(b).cmp(&fits(a)))
+                    .then(cost(a).total_cmp(&cost(b)))
+                    .then(a.power.total_cmp(&b.power))
+            })
+    }
+
+    /// Returns an instruction to switch the actuator to `operation_mode` with `factor`, unless it's already there.
+    pub(crate) fn instruction(
+        &mut self,
+        operation_mode: Id,
+        factor: f64,
+    ) -> Option<ddbc::Instruction> {
+        let actuator_id = self.actuator()?.id.clone();
+        let unchanged = self.active.as_ref().is_some_and(|(active, active_factor)| {
+            *active == operation_mode && (active_factor - factor).abs() < FACTOR_TOLERANCE
+        });
+        if unchanged {
+            return None;
+        }
+        self.active = Some((operation_mode.clone(), factor));
+        Some(ddbc::Instruction {
+            abnormal_condition: false,
+            actuator_id,
+            execution_time: Utc::now(),
+            id: Id::generate(),
+            message_id: Id::generate(),
+            operation_mode_factor: factor,
+            operation_mode_id: operation_mode,
+        })
+    }
+}
+
+/// An operation mode at the factor where its supply rate is closest to `demand`, without going under it if it can.
+fn option(mode: &ddbc::OperationMode, demand: f64, tariffs: &Tariffs) -> DdbcOption {
+    let range = &mode.supply_range;
+    let supply = demand.clamp(low(range), high(range));
+    let span = range.end_of_range - range.start_of_range;
+    let factor = if span == 0.0 {
+        0.0
+    } else {
+        ((supply - range.start_of_range) / span).clamp(0.0, 1.0)
+    };
+    let values: Vec<PowerValue> = mode
+        .power_ranges
+        .iter()
+        .map(|range| PowerValue {
+            commodity_quantity: range.commodity_quantity,
+            value: range.start_of_range + factor * (range.end_of_range - range.start_of_range),
+        })
+        .collect();
+    DdbcOption {
+        operation_mode: mode.id.clone(),
+        factor,
+        supply,
+        shortfall: (demand - supply).max(0.0),
+        power: peak_shaving::electric_power(&values).unwrap_or(0.0),
+        // S2 gives the running costs as a range for how uncertain they are, not as costs that change with the factor.
+        running_costs: mode.running_costs.as_ref().map_or(0.0, high) + tariffs.cost_rate(&values),
+    }
+}
+
+fn low(range: &NumberRange) -> f64 {
+    range.start_of_range.min(range.end_of_range)
+}
+
+fn high(range: &NumberRange) -> f64 {
+    range.start_of_range.max(range.end_of_range)
+}
@@ -0,0 +1,156 @@
+use crate::peak_shaving;
+use crate::state::SessionState;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use s2energy::common::{Message, PowerValue};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// How far ahead the site forecast looks.
+const HORIZON: TimeDelta = TimeDelta::hours(24);
+/// The length of the slots of the site forecast: a quarter of an hour, like the imbalance settlement period.
+const STEP: TimeDelta = TimeDelta::minutes(15);
+
+/// What the whole site expects for the next 24 hours, from the latest forecasts of every RM, per quarter of an hour.
+#[derive(Debug, Clone, Serialize)]
+pub struct SiteForecast {
+    /// The power the RMs expect together in every slot, from their `PowerForecast`s.
+    pub power: Vec<PowerSlot>,
+    /// What every FRBC storage expects to use in every slot, from its `FRBC.UsageForecast`. These are in the unit of
+    /// the fill level of each storage, so they can't be added up like the power.
+    pub usage: Vec<StorageUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PowerSlot {
+    pub start: DateTime<Utc>,
+    /// The electric power the site is expected to have, in W: positive to consume and negative to produce. `None`
+    /// when no RM forecasts electric power for the slot.
+    pub electric_power: Option<f64>,
+    /// The expected value of every commodity quantity the RMs forecast, added up, such as `HEAT.THERMAL_POWER`.
+    pub commodity_quantities: BTreeMap<String, f64>,
+    /// How many RMs forecast something for the slot.
+    pub rms: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageUsage {
+    /// The number of the session with the RM of the storage.
+    pub session: usize,
+    /// The expected usage rate in every slot, or `None` where the forecast doesn't cover it.
+    pub usage_rate: Vec<Option<f64>>,
+}
+
+/// Adds up the latest power forecasts of the RMs in `states`, by session number, and lines up their usage forecasts,
+/// for the 24 hours from the start of the quarter `now` is in. The first slot takes what the forecasts expect for now,
+/// since they usually start then. The RMs in quarantine are left out.
+pub(crate) fn aggregate<'a>(
+    states: impl IntoIterator<Item = (usize, &'a SessionState)>,
+    now: DateTime<Utc>,
+) -> SiteForecast {
+    let start = now.duration_trunc(STEP).unwrap_or(now);
+    let starts: Vec<DateTime<Utc>> = (0..HORIZON.num_seconds() / STEP.num_seconds())
+        .map(|step| start + STEP * step as i32)
+        .collect();
+    let mut power: Vec<PowerSlot> = starts
+        .iter()
+        .map(|start| PowerSlot {
+            start: *start,
+            electric_power: None,
+            commodity_quantities: BTreeMap::new(),
+            rms: 0,
+        })
+        .collect();
+    let mut usage = Vec::new();
+    for (session, state) in states {
+        if state.quarantined {
+            continue;
+        }
+        if let Some(Message::PowerForecast(forecast)) = state.latest_messages.get("PowerForecast") {
+            for slot in &mut power {
+                let durations = forecast.elements.iter().map(|element| *element.duration);
+                let Some(index) = covering(forecast.start_time, durations, slot.start.max(now))
+                else {
+                    continue;
+                };
+                let values: Vec<PowerValue> = forecast.elements[index]
+                    .power_values
+                    .iter()
+                    .map(|value| PowerValue {
+                        commodity_quantity: value.commodity_quantity,
+                        value: value.value_expected,
+                    })
+                    .collect();
+                for value in &values {
+                    *slot
+                        .commodity_quantities
+                        .entry(value.commodity_quantity.to_string())
+                        .or_default() += value.value;
+                }
+                if let Some(electric) = peak_shaving::electric_power(&values) {
+                    *slot.electric_power.get_or_insert(0.0) += electric;
+                }
+                slot.rms += 1;
+            }
+        }
+        if let Some(Message::FrbcUsageForecast(forecast)) =
+            state.latest_messages.get("FRBC.UsageForecast")
+        {
+            let usage_rate = starts
+                .iter()
+                .map(|start| {
+                    let durations = forecast.elements.iter().map(|element| *element.duration);
+                    let index = covering(forecast.start_time, durations, (*start).max(now))?;
+                    Some(forecast.elements[index].usage_rate_expected)
+                })
+                .collect();
+            usage.push(StorageUsage {
+                session,
+                usage_rate,
+            });
+        }
+    }
+    SiteForecast { power, usage }
+}
+
+impl SiteForecast {
+    /// A summary for the log: the lowest and highest power the site expects, and the energy it expects to take from the
+    /// grid on balance. `None` if no RM forecasts electric power.
+    pub(crate) fn summary(&self) -> Option<String> {
+        let powers: Vec<(DateTime<Utc>, f64)> = self
+            .power
+            .iter()
+            .filter_map(|slot| Some((slot.start, slot.electric_power?)))
+            .collect();
+        // The earliest slot wins a tie.
+        let highest = powers.iter().rev().max_by(|a, b| a.1.total_cmp(&b.1))?;
+        let lowest = powers.iter().min_by(|a, b| a.1.total_cmp(&b.1))?;
+        let hours = STEP.as_seconds_f64() / 3600.0;
+        let energy: f64 = powers.iter().map(|(_, power)| power * hours / 1000.0).sum();
+        Some(format!(
+            "the site expects between {:.0} W (at {} UTC) and {:.0} W (at {} UTC) in the next 24 hours, {energy:.1} kWh \
+            on balance",
+            lowest.1,
+            lowest.0.format("%H:%M"),
+            highest.1,
+            highest.0.format("%H:%M"),
+        ))
+    }
+}
+
+/// The index of the element of a forecast that starts at `start` with elements of `durations` in ms, that covers
+/// `time`.
+pub(crate) fn covering(
+    start: DateTime<Utc>,
+    durations: impl Iterator<Item = u64>,
+    time: DateTime<Utc>,
+) -> Option<usize> {
+    let mut element_start = start;
+    for (index, duration) in durations.enumerate() {
+        let end = element_start + TimeDelta::milliseconds(duration as i64);
+        if element_start <= time && time < end {
+            return Some(index);
+        }
+        element_start = end;
+    }
+    None
+}
@@ -0,0 +1,165 @@
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// Keeps the PEBC RMs that are connected within the limits of the grid connection, with power envelopes, and keeps
+/// track of what the PPBC appliances are scheduled to use, so they don't run at the same time when that takes the site
+/// over the power limit.
+///
+/// The PEBC RMs share the limits equally: with a feed-in limit of 3000 W and two PV installations, each may feed in
+/// 1500 W. A demand response event can lower the limits for as long as it lasts.
+pub(crate) struct GridLimits {
+    /// The most power the site may take from the grid, in W.
+    consumption: Option<f64>,
+    /// The most power the site may feed into the grid, in W.
+    feed_in: Option<f64>,
+    /// The limits of the demand response events going on, if any, where they're lower.
+    event_limits: Mutex<Share>,
+    /// The numbers of the sessions with a PEBC RM.
+    rms: Mutex<HashSet<usize>>,
+    /// What every PPBC appliance is scheduled to use, by session number.
+    appliances: Mutex<HashMap<usize, Schedule>>,
+    /// Tells the PEBC sessions that their share of the limits changed.
+    changed: watch::Sender<()>,
+}
+
+/// The power of an appliance from the start of every part of its schedule until the end, in W.
+struct Schedule {
+    power: Vec<(DateTime<Utc>, f64)>,
+    end: DateTime<Utc>,
+}
+
+impl Schedule {
+    fn power_at(&self, time: DateTime<Utc>) -> f64 {
+        if time >= self.end {
+            return 0.0;
+        }
+        self.power
+            .iter()
+            .take_while(|(start, _)| *start <= time)
+            .last()
+            .map_or(0.0, |(_, power)| *power)
+    }
+}
+
+/// The share of the grid limits for one PEBC RM, in W; `None` where there's no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Share {
+    pub(crate) consumption: Option<f64>,
+    pub(crate) feed_in: Option<f64>,
+}
+
+impl GridLimits {
+    pub(crate) fn new(consumption: Option<f64>, feed_in: Option<f64>) -> Self {
+        Self {
+            consumption,
+            feed_in,
+            event_limits: Mutex::new(Share {
+                consumption: None,
+                feed_in: None,
+            }),
+            rms: Mutex::new(HashSet::new()),
+            appliances: Mutex::new(HashMap::new()),
+            changed: watch::Sender::new(()),
+        }
+    }
+
+    /// Registers the PEBC RM of a session, and returns a receiver that's notified when its share changes.
+    pub(crate) fn add_rm(&self, session: usize) -> watch::Receiver<()> {
+        self.rms.lock().unwrap().insert(session);
+        self.changed.send_replace(());
+        self.changed.subscribe()
+    }
+
+    /// Forgets about a session that ended.
+    pub(crate) fn remove(&self, session: usize) {
+        self.appliances.lock().unwrap().remove(&session);
+        if self.rms.lock().unwrap().remove(&session) {
+            self.changed.send_replace(());
+        }
+    }
+
+    /// Whether the appliance of a session can run with `power` from the start of every part until `end`, without taking
+    /// the site over the power limit together with what the other appliances are scheduled to use.
+    pub(crate) fn fits(
+        &self,
+        session: usize,
+        power: &[(DateTime<Utc>, f64)],
+        end: DateTime<Utc>,
+    ) -> bool {
+        let Some(limit) = self.limits().consumption else {
+            return true;
+        };
+        let appliances = self.appliances.lock().unwrap();
+        let others: Vec<_> = appliances
+            .iter()
+            .filter(|(other, _)| **other != session)
+            .map(|(_, schedule)| schedule)
+            .collect();
+        let ends = power.iter().skip(1).map(|(start, _)| *start).chain([end]);
+        power.iter().zip(ends).all(|((start, power), part_end)| {
+            // What the others use only changes where one of their parts starts.
+            let changes = others
+                .iter()
+                .flat_map(|schedule| schedule.power.iter().map(|(time, _)| *time))
+                .filter(|time| start < time && *time < part_end);
+            [*start].into_iter().chain(changes).all(|time| {
+                let others: f64 = others.iter().map(|schedule| schedule.power_at(time)).sum();
+                power + others <= limit
+            })
+        })
+    }
+
+    /// Keeps track of what the appliance of a session is scheduled to use, replacing what it was scheduled for before.
+    pub(crate) fn schedule(
+        &self,
+        session: usize,
+        power: Vec<(DateTime<Utc>, f64)>,
+        end: DateTime<Utc>,
+    ) {
+        self.appliances
+            .lock()
+            .unwrap()
+            .insert(session, Schedule { power, end });
+    }
+
+    /// Replaces the limits of the demand response events going on, and tells the PEBC sessions if that changes their
+    /// share.
+    pub(crate) fn set_event_limits(&self, limits: Share) {
+        let mut event_limits = self.event_limits.lock().unwrap();
+        if *event_limits == limits {
+            return;
+        }
+        *event_limits = limits;
+        drop(event_limits);
+        self.changed.send_replace(());
+    }
+
+    /// The limits of the whole site: the lowest of those of the options and of the demand response events.
+    fn limits(&self) -> Share {
+        let event_limits = *self.event_limits.lock().unwrap();
+        Share {
+            consumption: lowest(self.consumption, event_limits.consumption),
+            feed_in: lowest(self.feed_in, event_limits.feed_in),
+        }
+    }
+
+    /// The share of the limits for every PEBC RM.
+    pub(crate) fn share(&self) -> Share {
+        let rms = self.rms.lock().unwrap().len().max(1) as f64;
+        let limits = self.limits();
+        Share {
+            consumption: limits.consumption.map(|limit| limit / rms),
+            feed_in: limits.feed_in.map(|limit| limit / rms),
+        }
+    }
+}
+
+/// The lowest of two limits, where `None` is no limit.
+pub(crate) fn lowest(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
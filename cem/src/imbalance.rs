@@ -0,0 +1,185 @@
+use crate::forecast;
+use crate::peak_shaving;
+use crate::pebc;
+use crate::state::{Imbalance, SessionState};
+use chrono::{DateTime, TimeDelta, Utc};
+use s2energy::common::{Message, PowerMeasurement, PowerRange, PowerValue};
+use s2energy::{ddbc, frbc, ombc};
+
+/// How far the measured power may be from what the instructions imply before the RM counts as deviating, in W, for
+/// the small powers where the relative tolerance is less.
+const TOLERANCE: f64 = 100.0;
+/// How far the measured power may be from what the instructions imply before the RM counts as deviating, as a share
+/// of that power.
+const RELATIVE_TOLERANCE: f64 = 0.05;
+/// How long an RM gets to follow an instruction, besides the instruction processing delay it told the CEM about,
+/// before its measurements are compared with it.
+const SETTLING_TIME: TimeDelta = TimeDelta::seconds(10);
+
+impl Imbalance {
+    /// The power the instructions imply, for the log.
+    pub(crate) fn expected(&self) -> String {
+        if self.expected_lowest == self.expected_highest {
+            format!("{:.0} W", self.expected_lowest)
+        } else {
+            format!(
+                "between {:.0} W and {:.0} W",
+                self.expected_lowest, self.expected_highest
+            )
+        }
+    }
+}
+
+/// Compares the electric power the RM measured with the power the instruction in effect implies, and keeps track of
+/// the imbalance in the state. Measurements from before the RM had time to follow the instruction are left out.
+///
+/// Returns whether the RM is deviating now, if that changed.
+pub(crate) fn track(state: &mut SessionState, measurement: &PowerMeasurement) -> Option<bool> {
+    let measured = peak_shaving::electric_power(&measurement.values)?;
+    let (lower, upper) = expected_power(state, measurement.measurement_timestamp)?;
+    // `clamp` panics on envelope limits the wrong way around, which a strategy of your own could send.
+    let (lowest, highest) = (lower.min(upper), lower.max(upper));
+    let deviation = measured - measured.clamp(lowest, highest);
+    let was_deviating = state
+        .imbalance
+        .as_ref()
+        .is_some_and(|imbalance| imbalance.deviating);
+    let expected = lowest.abs().max(highest.abs());
+    let deviating = deviation.abs() > TOLERANCE.max(RELATIVE_TOLERANCE * expected);
+    let (mean, measurements) = state.imbalance.as_ref().map_or((0.0, 0), |imbalance| {
+        (imbalance.mean_absolute_deviation, imbalance.measurements)
+    });
+    let imbalance = Imbalance {
+        measured_at: measurement.measurement_timestamp,
+        measured_power: measured,
+        expected_lowest: lowest,
+        expected_highest: highest,
+        deviation,
+        deviating,
+        mean_absolute_deviation: (mean * measurements as f64 + deviation.abs())
+            / (measurements + 1) as f64,
+        measurements: measurements + 1,
+    };
+    state.imbalance = Some(imbalance);
+    (deviating != was_deviating).then_some(deviating)
+}
+
+/// The lowest and highest electric power the instruction in effect implies at `time`, in W: the power of the
+/// operation mode at its factor for FRBC, OMBC and DDBC, or the power envelope for PEBC. `None` if there's no such
+/// instruction, or the RM may still be following it.
+fn expected_power(state: &SessionState, time: DateTime<Utc>) -> Option<(f64, f64)> {
+    let delay = state
+        .rm_details
+        .as_ref()
+        .map_or(0, |details| *details.instruction_processing_delay);
+    let settled = |execution_time: DateTime<Utc>| {
+        time >= execution_time + TimeDelta::milliseconds(delay as i64) + SETTLING_TIME
+    };
+    state
+        .active_instructions
+        .iter()
+        .rev()
+        .find_map(|instruction| match instruction {
+            Message::FrbcInstruction(instruction) if settled(instruction.execution_time) => {
+                frbc_power(state, instruction)
+            }
+            Message::OmbcInstruction(instruction) if settled(instruction.execution_time) => {
+                ombc_power(state, instruction)
+            }
+            Message::DdbcInstruction(instruction) if settled(instruction.execution_time) => {
+                ddbc_power(state, instruction)
+            }
+            Message::PebcInstruction(instruction) if settled(instruction.execution_time) => {
+                envelope(instruction, time)
+            }
+            _ => None,
+        })
+}
+
+fn frbc_power(state: &SessionState, instruction: &frbc::Instruction) -> Option<(f64, f64)> {
+    let Some(Message::FrbcSystemDescription(description)) =
+        state.latest_messages.get("FRBC.SystemDescription")
+    else {
+        return None;
+    };
+    let mode = description
+        .actuators
+        .iter()
+        .filter(|actuator| actuator.id == instruction.actuator_id)
+        .flat_map(|actuator| &actuator.operation_modes)
+        .find(|mode| mode.id == instruction.operation_mode)?;
+    let fill_level = match state.latest_messages.get("FRBC.StorageStatus") {
+        Some(Message::FrbcStorageStatus(status)) => Some(status.present_fill_level),
+        _ => None,
+    };
+    // The element for the fill level the storage is at, or the first if the CEM doesn't know that.
+    let element = mode
+        .elements
+        .iter()
+        .find(|element| {
+            let range = &element.fill_level_range;
+            fill_level
+                .is_some_and(|level| range.start_of_range <= level && level <= range.end_of_range)
+        })
+        .or(mode.elements.first())?;
+    point(&element.power_ranges, instruction.operation_mode_factor)
+}
+
+fn ombc_power(state: &SessionState, instruction: &ombc::Instruction) -> Option<(f64, f64)> {
+    let Some(Message::OmbcSystemDescription(description)) =
+        state.latest_messages.get("OMBC.SystemDescription")
+    else {
+        return None;
+    };
+    let mode = description
+        .operation_modes
+        .iter()
+        .find(|mode| mode.id == instruction.operation_mode_id)?;
+    point(&mode.power_ranges, instruction.operation_mode_factor)
+}
+
+fn ddbc_power(state: &SessionState, instruction: &ddbc::Instruction) -> Option<(f64, f64)> {
+    let Some(Message::DdbcSystemDescription(description)) =
+        state.latest_messages.get("DDBC.SystemDescription")
+    else {
+        return None;
+    };
+    let mode = description
+        .actuators
+        .iter()
+        .filter(|actuator| actuator.id == instruction.actuator_id)
+        .flat_map(|actuator| &actuator.operation_modes)
+        .find(|mode| mode.id == instruction.operation_mode_id)?;
+    point(&mode.power_ranges, instruction.operation_mode_factor)
+}
+
+/// The electric power of the power ranges of an operation mode at `factor`, as the lowest and highest power.
+fn point(ranges: &[PowerRange], factor: f64) -> Option<(f64, f64)> {
+    let values: Vec<PowerValue> = ranges
+        .iter()
+        .map(|range| PowerValue {
+            commodity_quantity: range.commodity_quantity,
+            value: range.start_of_range + factor * (range.end_of_range - range.start_of_range),
+        })
+        .collect();
+    let power = peak_shaving::electric_power(&values)?;
+    Some((power, power))
+}
+
+/// The lower and upper limits of the electric power envelopes at `time`, added up; `None` if they don't cover it.
+fn envelope(instruction: &s2energy::pebc::Instruction, time: DateTime<Utc>) -> Option<(f64, f64)> {
+    let mut limits = None;
+    let envelopes = instruction
+        .power_envelopes
+        .iter()
+        .filter(|envelope| pebc::is_electric_power(envelope.commodity_quantity));
+    for envelope in envelopes {
+        let elements = &envelope.power_envelope_elements;
+        let durations = elements.iter().map(|element| *element.duration);
+        let element = &elements[forecast::covering(instruction.execution_time, durations, time)?];
+        let (lowest, highest) = limits.get_or_insert((0.0, 0.0));
+        *lowest += element.lower_limit;
+        *highest += element.upper_limit;
+    }
+    limits
+}
@@ -0,0 +1,237 @@
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Context};
+use s2energy::common::Message;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// How often the points are written to InfluxDB, in one request.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How many points are kept while InfluxDB can't be reached; the oldest are dropped beyond that.
+const MAX_PENDING: usize = 10_000;
+/// How long a write may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How to reach the InfluxDB to write what the RMs measure and what the CEM instructs them to.
+#[derive(Debug, Clone)]
+pub struct Influx {
+    /// The URL of InfluxDB, such as `http://localhost:8086`.
+    pub url: String,
+    /// The bucket to write to; for InfluxDB 1.8, `database/retention-policy`.
+    pub bucket: String,
+    /// The organization the bucket belongs to, if InfluxDB needs one.
+    pub org: Option<String>,
+    /// The API token to write with, if InfluxDB needs one.
+    pub token: Option<String>,
+}
+
+/// Writes what goes on in the sessions to InfluxDB in its line protocol, so the site can be followed and plotted in
+/// Grafana, next to what the simulators write.
+///
+/// Every point is tagged with `source=cem` and the resource ID of the RM in `rm`, and has one of these measurements:
+///
+/// - `power_measurement`: the power measurements of the RMs, with a `value` per `commodity_quantity`;
+/// - `fill_level`: the fill level in the storage status of the FRBC RMs, as a `value`;
+/// - `instruction`: the instructions the CEM sends, with their `message_type` and `id`;
+/// - `instruction_status`: the status updates the RMs send for them, with their `status` and `instruction_id`.
+///
+/// The points are written in the background; while InfluxDB can't be reached, they're kept up to a point and written
+/// once it's back.
+#[derive(Clone)]
+pub(crate) struct InfluxSink {
+    points: UnboundedSender<String>,
+}
+
+impl InfluxSink {
+    /// Starts writing to InfluxDB in the background.
+    pub(crate) fn start(options: &Influx) -> eyre::Result<Self> {
+        let writer = Writer::new(options)?;
+        let (points, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(writer.run(receiver));
+        tracing::info!(
+            "Writing the measurements and instructions to InfluxDB at {}",
+            options.url
+        );
+        Ok(Self { points })
+    }
+
+    /// Writes a message from an RM, if it's a measurement, a storage status or the status of an instruction.
+    pub(crate) fn write_received(&self, rm: &str, message: &Message) {
+        let Ok(message) = serde_json::to_value(message) else {
+            return;
+        };
+        let tags = format!(",source=cem{}", tag("rm", rm));
+        match message["message_type"].as_str().unwrap_or_default() {
+            "PowerMeasurement" => {
+                let at = timestamp(&message["measurement_timestamp"]);
+                for value in message["values"].as_array().into_iter().flatten() {
+                    let (Some(quantity), Some(value)) = (
+                        value["commodity_quantity"].as_str(),
+                        value["value"].as_f64(),
+                    ) else {
+                        continue;
+                    };
+                    let tags = format!("{tags}{}", tag("commodity_quantity", quantity));
+                    let fields = number("value", value).into_iter().collect();
+                    self.write("power_measurement", &tags, fields, at);
+                }
+            }
+            "FRBC.StorageStatus" => {
+                if let Some(fill_level) = message["present_fill_level"].as_f64() {
+                    let fields = number("value", fill_level).into_iter().collect();
+                    self.write("fill_level", &tags, fields, Utc::now());
+                }
+            }
+            "InstructionStatusUpdate" => {
+                let status = message["status_type"].as_str().unwrap_or_default();
+                let tags = format!("{tags}{}", tag("status", status));
+                let instruction_id = message["instruction_id"].as_str().unwrap_or_default();
+                let fields = vec![text("instruction_id", instruction_id)];
+                self.write(
+                    "instruction_status",
+                    &tags,
+                    fields,
+                    timestamp(&message["timestamp"]),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes an instruction the CEM sent to an RM.
+    pub(crate) fn write_instruction(&self, rm: &str, instruction: &Message) {
+        let Ok(instruction) = serde_json::to_value(instruction) else {
+            return;
+        };
+        let message_type = instruction["message_type"].as_str().unwrap_or_default();
+        let tags = format!(
+            ",source=cem{}{}",
+            tag("rm", rm),
+            tag("message_type", message_type)
+        );
+        let id = instruction["id"].as_str().unwrap_or_default();
+        self.write("instruction", &tags, vec![text("id", id)], Utc::now());
+    }
+
+    fn write(&self, measurement: &str, tags: &str, fields: Vec<String>, at: DateTime<Utc>) {
+        // A point needs at least one field.
+        if fields.is_empty() {
+            return;
+        }
+        let line = format!(
+            "{measurement}{tags} {} {}",
+            fields.join(","),
+            at.timestamp_millis()
+        );
+        // The writer only stops when the CEM does.
+        let _ = self.points.send(line);
+    }
+}
+
+/// Writes the points to InfluxDB in batches, in the background.
+struct Writer {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    token: Option<String>,
+}
+
+impl Writer {
+    fn new(options: &Influx) -> eyre::Result<Self> {
+        // InfluxDB 2 and 3 take the points at this endpoint, and so does InfluxDB 1.8 for compatibility.
+        let url = format!("{}/api/v2/write", options.url.trim_end_matches('/'));
+        let mut url = reqwest::Url::parse(&url)
+            .wrap_err_with(|| format!("Invalid InfluxDB URL ({})", options.url))?;
+        url.query_pairs_mut()
+            .append_pair("bucket", &options.bucket)
+            .append_pair("precision", "ms");
+        if let Some(org) = &options.org {
+            url.query_pairs_mut().append_pair("org", org);
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            client,
+            url,
+            token: options.token.clone(),
+        })
+    }
+
+    /// Writes the points that came in every [`FLUSH_INTERVAL`], until the CEM stops.
+    async fn run(self, mut points: UnboundedReceiver<String>) {
+        let mut pending: Vec<String> = Vec::new();
+        let mut failing = false;
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                point = points.recv() => match point {
+                    Some(point) => pending.push(point),
+                    None => return,
+                },
+                _ = flush.tick(), if !pending.is_empty() => {
+                    match self.write(&pending).await {
+                        Ok(()) => {
+                            if failing {
+                                tracing::info!("Writing to InfluxDB again");
+                                failing = false;
+                            }
+                            pending.clear();
+                        }
+                        Err(error) => {
+                            // Warn once, rather than every second while InfluxDB is down.
+                            if !failing {
+                                tracing::warn!("Could not write to InfluxDB: {error:#}");
+                                failing = true;
+                            }
+                            let excess = pending.len().saturating_sub(MAX_PENDING);
+                            pending.drain(..excess);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn write(&self, points: &[String]) -> eyre::Result<()> {
+        let mut request = self.client.post(self.url.clone()).body(points.join("\n"));
+        if let Some(token) = &self.token {
+            request = request.header(reqwest::header::AUTHORIZATION, format!("Token {token}"));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(eyre!("InfluxDB answered {status}: {}", body.trim()));
+        }
+        Ok(())
+    }
+}
+
+/// A tag in line protocol, with the comma that separates it from what comes before.
+fn tag(key: &str, value: &str) -> String {
+    let value = value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ");
+    format!(",{key}={value}")
+}
+
+/// A number field in line protocol, unless the number can't be written.
+fn number(key: &str, value: f64) -> Option<String> {
+    value.is_finite().then(|| format!("{key}={value}"))
+}
+
+/// A string field in line protocol.
+fn text(key: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("{key}=\"{value}\"")
+}
+
+/// The time in a message, or now if it doesn't have a valid one.
+fn timestamp(value: &Value) -> DateTime<Utc> {
+    value
+        .as_str()
+        .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+        .map_or_else(Utc::now, |timestamp| timestamp.to_utc())
+}
@@ -0,0 +1,53 @@
+//! A reference CEM, to try the simulators in this repository and other S2 resource managers without a CEM of your own.
+//!
+//! [`run`] accepts every RM that connects, or only those paired with it by a token or their resource ID, performs the
+//! handshake, selects a control type and keeps track of what each RM tells about itself in a [`SessionState`], which it
+//! can dump to a JSON file per session and serve on an HTTP API, or list on an interactive console that also sends the
+//! RMs instructions by hand. It can also remember every RM, to resume its session when the RM connects again after the
+//! CEM restarted. It compares the power every RM measures with the power its instructions imply, and keeps track of the
+//! [`Imbalance`]. With a power limit, it instructs the FRBC batteries and OMBC loads that connect to keep the power of
+//! the whole site under that limit; for self-consumption, it instructs the batteries to charge with what the PV feeds
+//! in and discharge to cover what the site uses. With day-ahead prices, it instead plans the FRBC storages and OMBC
+//! devices that connect to minimize what the site pays for energy. The PEBC RMs that connect get power envelopes that
+//! keep them within the power limit and the feed-in limit, and the power sequences of the PPBC appliances are scheduled
+//! when they cost the least and keep the site under the limit. The DDBC devices supply their demand in the cheapest
+//! operation mode that fits under the limit. With gas and heat prices, the CEM weighs those commodities against
+//! electricity for the devices that use them. As an [`OpenAdr`] VEN, the CEM takes part in the demand response
+//! programs of a utility: the capacity limits of their events lower the grid limits, and their prices replace the
+//! day-ahead prices, for as long as the events last. These strategies implement [`CemStrategy`], which you can
+//! implement to plug in a strategy of your own. To plot a run in Grafana, the CEM can write the measurements of the RMs
+//! and its instructions to [`Influx`], and to build on them, it can store the sessions, measurements and instructions
+//! in PostgreSQL.
+
+mod admission;
+mod api;
+mod battery;
+mod bundled;
+mod console;
+mod day_ahead;
+mod ddbc;
+mod forecast;
+mod grid_limits;
+mod imbalance;
+mod influx;
+mod ombc;
+mod openadr;
+mod optimizer;
+mod peak_shaving;
+mod pebc;
+mod postgres;
+mod ppbc;
+mod price_feed;
+mod server;
+mod session;
+mod simplex;
+mod state;
+mod strategy;
+mod tariffs;
+
+pub use forecast::{PowerSlot, SiteForecast, StorageUsage};
+pub use influx::Influx;
+pub use openadr::OpenAdr;
+pub use server::{run, Options};
+pub use state::{Imbalance, PlannedPower, SessionState};
+pub use strategy::{CemStrategy, Decision, Rm, Site};
@@ -0,0 +1,112 @@
+use crate::peak_shaving;
+use chrono::Utc;
+use s2energy::common::{Id, Message, PowerValue};
+use s2energy::ombc;
+
+/// How much an operation mode factor may differ from the one the device already has before it gets a new instruction.
+const FACTOR_TOLERANCE: f64 = 0.01;
+
+/// What the CEM knows about an OMBC device, such as a PV installation that can be curtailed or a load that can run at
+/// a lower power, to instruct it.
+#[derive(Default)]
+pub(crate) struct OmbcDevice {
+    description: Option<ombc::SystemDescription>,
+    /// The operation mode the device is in and its factor, as last reported or instructed.
+    active: Option<(Id, f64)>,
+}
+
+impl OmbcDevice {
+    pub(crate) fn update(&mut self, message: &Message) {
+        match message {
+            Message::OmbcSystemDescription(description) => {
+                self.description = Some(description.clone())
+            }
+            Message::OmbcStatus(status) => {
+                self.active = Some((
+                    status.active_operation_mode_id.clone(),
+                    status.operation_mode_factor,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    /// The operation modes of the device, leaving out those for abnormal conditions.
+    pub(crate) fn operation_modes(&self) -> impl Iterator<Item = &ombc::OperationMode> {
+        self.description
+            .iter()
+            .flat_map(|description| &description.operation_modes)
+            .filter(|mode| !mode.abnormal_condition_only)
+    }
+
+    /// Picks the operation mode and factor that use the most power that fits in `room`, the power the site has left
+    /// under the limit, or the least power if none fits, and returns them with that power in W.
+    ///
+    /// `None` until the RM described the device, or if it's no load: a device that only produces, such as a PV
+    /// installation, can't take the site over a power limit, and is kept under a feed-in limit with PEBC instead.
+    pub(crate) fn fit(&self, room: f64) -> Option<(Id, f64, f64)> {
+        let options: Vec<(Id, f64, f64)> = self
+            .operation_modes()
+            .flat_map(|mode| {
+                let low = electric_power(mode, 0.0);
+                let high = electric_power(mode, 1.0);
+                // The power changes linearly with the factor, so there's a factor where it just fits.
+                let fitting = if high == low {
+                    1.0
+                } else {
+                    ((room - low) / (high - low)).clamp(0.0, 1.0)
+                };
+                [0.0, fitting, 1.0]
+                    .map(|factor| (mode.id.clone(), factor, electric_power(mode, factor)))
+            })
+            .collect();
+        if options.iter().all(|(_, _, power)| *power <= 0.0) {
+            return None;
+        }
+        let by_power = |a: &&(Id, f64, f64), b: &&(Id, f64, f64)| a.2.total_cmp(&b.2);
+        options
+            .iter()
+            .filter(|(_, _, power)| *power <= room)
+            .max_by(by_power)
+            .or_else(|| options.iter().min_by(by_power))
+            .cloned()
+    }
+
+    /// Returns an instruction to switch the device to `operation_mode` with `factor`, unless it's already there.
+    pub(crate) fn instruction(
+        &mut self,
+        operation_mode: Id,
+        factor: f64,
+    ) -> Option<ombc::Instruction> {
+        let unchanged = self.active.as_ref().is_some_and(|(active, active_factor)| {
+            *active == operation_mode && (active_factor - factor).abs() < FACTOR_TOLERANCE
+        });
+        if unchanged {
+            return None;
+        }
+        self.active = Some((operation_mode.clone(), factor));
+        Some(ombc::Instruction::new(
+            false,
+            Utc::now(),
+            Id::generate(),
+            factor,
+            operation_mode,
+        ))
+    }
+}
+
+/// The power values of an operation mode at `factor`.
+pub(crate) fn values(mode: &ombc::OperationMode, factor: f64) -> Vec<PowerValue> {
+    mode.power_ranges
+        .iter()
+        .map(|range| PowerValue {
+            commodity_quantity: range.commodity_quantity,
+            value: range.start_of_range + factor * (range.end_of_range - range.start_of_range),
+        })
+        .collect()
+}
+
+/// The electric power of an operation mode at `factor`, in W.
+pub(crate) fn electric_power(mode: &ombc::OperationMode, factor: f64) -> f64 {
+    peak_shaving::electric_power(&values(mode, factor)).unwrap_or(0.0)
+}
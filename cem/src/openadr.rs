@@ -0,0 +1,464 @@
+use crate::api::Sessions;
+use crate::bundled::Bundled;
+use crate::grid_limits::{lowest, Share};
+use chrono::{DateTime, TimeDelta, Utc};
+use eyre::{bail, eyre, Context, OptionExt};
+use prices::Prices;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How to reach the VTN of a utility or aggregator, to take part in its demand response programs as an OpenADR 3 VEN.
+#[derive(Debug, Clone)]
+pub struct OpenAdr {
+    /// The base URL of the API of the VTN, such as `https://vtn.example.com/openadr3/3.0.1`.
+    pub vtn_url: String,
+    /// The client ID and secret the VEN gets an access token with, if the VTN asks for one.
+    pub credentials: Option<(String, String)>,
+    /// Where to get the access token [default: `auth/token` on the VTN]
+    pub token_url: Option<String>,
+    /// The name of the program to follow the events of; if not set, the CEM follows every event the VTN shows it.
+    pub program: Option<String>,
+    /// How often to ask the VTN for its events.
+    pub poll_interval: Duration,
+}
+
+/// Takes part in the demand response programs of a VTN as an OpenADR 3 VEN: asks the VTN for its events, and passes
+/// what they ask of the site on to the strategies for as long as each interval of an event lasts.
+///
+/// An `IMPORT_CAPACITY_LIMIT` or `EXPORT_CAPACITY_LIMIT` lowers the limits of the grid connection, which the bundled
+/// strategies keep the site within. Where events overlap, the lowest limit holds. A `PRICE` replaces the day-ahead
+/// price for its interval, which every strategy plans with, also one of your own; where events overlap, the one with
+/// the highest priority holds. Other payloads, such as `SIMPLE` levels, are ignored.
+pub(crate) struct Ven {
+    options: OpenAdr,
+    client: reqwest::Client,
+    /// The access token the VTN gave, if any yet.
+    token: Option<String>,
+    /// The ID of the program of the options, once the VTN told it.
+    program_id: Option<String>,
+    /// The bundled strategies, which keep the site within the limits of the events; a strategy of your own only gets
+    /// their prices.
+    bundled: Option<Arc<Bundled>>,
+    sessions: Arc<Sessions>,
+    /// What the intervals of the events ask of the site.
+    signals: Vec<Signal>,
+    /// The limits of the events that were passed on last.
+    limits: Share,
+    /// Why the events the VEN can't make sense of were left out last time.
+    ignored: Vec<String>,
+}
+
+/// What an interval of an event asks of the site, from its start until its end.
+#[derive(Debug, Clone, PartialEq)]
+struct Signal {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// The priority of the event, where 0 is the highest.
+    priority: u64,
+    /// The most power the site may take from the grid, in W.
+    import_limit: Option<f64>,
+    /// The most power the site may feed into the grid, in W.
+    export_limit: Option<f64>,
+    /// The price per kWh.
+    price: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Program {
+    id: String,
+    program_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Event {
+    event_name: Option<String>,
+    id: Option<String>,
+    priority: Option<u64>,
+    #[serde(default)]
+    payload_descriptors: Vec<PayloadDescriptor>,
+    /// The period of the intervals that don't have one of their own.
+    interval_period: Option<IntervalPeriod>,
+    intervals: Vec<Interval>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PayloadDescriptor {
+    payload_type: String,
+    units: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IntervalPeriod {
+    start: DateTime<Utc>,
+    /// An ISO 8601 duration; without one, the interval doesn't end.
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Interval {
+    interval_period: Option<IntervalPeriod>,
+    payloads: Vec<Payload>,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    #[serde(rename = "type")]
+    payload_type: String,
+    values: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+impl Ven {
+    pub(crate) fn new(
+        options: OpenAdr,
+        bundled: Option<Arc<Bundled>>,
+        sessions: Arc<Sessions>,
+    ) -> eyre::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .wrap_err("Could not set up HTTP client")?;
+        Ok(Self {
+            options,
+            client,
+            token: None,
+            program_id: None,
+            bundled,
+            sessions,
+            signals: Vec::new(),
+            limits: Share {
+                consumption: None,
+                feed_in: None,
+            },
+            ignored: Vec::new(),
+        })
+    }
+
+    /// Asks the VTN for its events, and keeps what their intervals ask of the site. An event the VEN can't make sense
+    /// of is left out, so the others still hold.
+    pub(crate) async fn update(&mut self) -> eyre::Result<()> {
+        if let (Some(name), None) = (self.options.program.clone(), &self.program_id) {
+            let programs: Vec<Program> = self.get("programs", &[]).await?;
+            let program = programs
+                .into_iter()
+                .find(|program| program.program_name == name)
+                .ok_or_else(|| eyre!("The VTN has no program {name}"))?;
+            self.program_id = Some(program.id);
+        }
+        let query: Vec<_> = self
+            .program_id
+            .iter()
+            .map(|id| ("programID", id.clone()))
+            .collect();
+        let events: Vec<Event> = self.get("events", &query).await?;
+        let mut signals = Vec::new();
+        let mut ignored = Vec::new();
+        for event in &events {
+            match event.signals() {
+                Ok(event_signals) => signals.extend(event_signals),
+                Err(error) => ignored.push(format!(
+                    "Ignoring OpenADR event {}: {error:#}",
+                    event.name()
+                )),
+            }
+        }
+        // The VTN keeps showing the same events, which are only worth a warning the first time.
+        for message in &ignored {
+            if self.ignored.contains(message) {
+                tracing::debug!("{message}");
+            } else {
+                tracing::warn!("{message}");
+            }
+        }
+        self.ignored = ignored;
+        if signals != self.signals {
+            tracing::info!(
+                "Got {} OpenADR events from the VTN, with {} intervals that ask something of the site",
+                events.len(),
+                signals.len()
+            );
+        }
+        self.signals = signals;
+        Ok(())
+    }
+
+    /// Passes on what the events ask of the site at `now`: their limits to the bundled strategies, and their prices to
+    /// the sessions.
+    pub(crate) fn apply(&mut self, now: DateTime<Utc>) {
+        let current = self
+            .signals
+            .iter()
+            .filter(|signal| signal.start <= now && now < signal.end);
+        let no_limits = Share {
+            consumption: None,
+            feed_in: None,
+        };
+        let limits = current.fold(no_limits, |limits, signal| Share {
+            consumption: lowest(limits.consumption, signal.import_limit),
+            feed_in: lowest(limits.feed_in, signal.export_limit),
+        });
+        if limits != self.limits {
+            let limit = |limit: Option<f64>| {
+                limit.map_or("no limit".into(), |limit| format!("{limit:.0} W"))
+            };
+            tracing::info!(
+                "The OpenADR events now limit what the site takes from the grid to {} and what it feeds in to {}",
+                limit(limits.consumption),
+                limit(limits.feed_in)
+            );
+            if self.bundled.is_none() {
+                tracing::warn!(
+                    "A strategy of your own doesn't get the limits of the OpenADR events"
+                );
+            }
+            self.limits = limits;
+        }
+        if let Some(bundled) = &self.bundled {
+            bundled.set_event_limits(limits);
+        }
+
+        // The events that take precedence come last, so their prices replace those of the others.
+        let mut priced: Vec<_> = self
+            .signals
+            .iter()
+            .filter(|signal| signal.end > now)
+            .collect();
+        priced.sort_by_key(|signal| std::cmp::Reverse(signal.priority));
+        let prices = priced
+            .into_iter()
+            .filter_map(|signal| {
+                let price = signal.price?;
+                Prices::new(BTreeMap::from([(signal.start, price)]), signal.end).ok()
+            })
+            .collect();
+        self.sessions.set_event_prices(prices);
+    }
+
+    /// Asks the VTN for its events every poll interval, and passes on what they ask of the site whenever an interval
+    /// starts or ends. When the VTN can't be reached, the events it gave last still hold.
+    pub(crate) async fn run(mut self) {
+        let poll_interval =
+            TimeDelta::from_std(self.options.poll_interval).unwrap_or(TimeDelta::days(1));
+        let mut next_poll = Utc::now() + poll_interval;
+        loop {
+            let now = Utc::now();
+            let next = self
+                .next_change(now)
+                .map_or(next_poll, |change| change.min(next_poll));
+            tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+            let now = Utc::now();
+            if now >= next_poll {
+                if let Err(error) = self.update().await {
+                    tracing::warn!("Could not get the OpenADR events from the VTN: {error:#}");
+                }
+                next_poll = now + poll_interval;
+            }
+            self.apply(Utc::now());
+        }
+    }
+
+    /// When the next interval starts or ends after `now`, if any does.
+    fn next_change(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.signals
+            .iter()
+            .flat_map(|signal| [signal.start, signal.end])
+            .filter(|time| *time > now)
+            .min()
+    }
+
+    /// Gets `path` from the API of the VTN, with an access token if the VTN asks for one.
+    async fn get<T: DeserializeOwned>(
+        &mut self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> eyre::Result<T> {
+        if self.token.is_none() && self.options.credentials.is_some() {
+            self.token = Some(self.authenticate().await?);
+        }
+        let url = format!("{}/{path}", self.options.vtn_url.trim_end_matches('/'));
+        let mut request = self.client.get(&url).query(query);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .await
+            .wrap_err_with(|| format!("Could not reach the VTN at {url}"))?;
+        let status = response.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            // The token may have expired, so the next request gets a new one.
+            self.token = None;
+        }
+        if !status.is_success() {
+            bail!("The VTN returned {status} for {url}");
+        }
+        response
+            .json()
+            .await
+            .wrap_err_with(|| format!("Could not parse the response of the VTN for {url}"))
+    }
+
+    /// Gets an access token with the client credentials of the options.
+    async fn authenticate(&self) -> eyre::Result<String> {
+        let (client_id, client_secret) = self
+            .options
+            .credentials
+            .as_ref()
+            .ok_or_eyre("There are no client credentials")?;
+        let url = match &self.options.token_url {
+            Some(url) => url.clone(),
+            None => format!("{}/auth/token", self.options.vtn_url.trim_end_matches('/')),
+        };
+        let response = self
+            .client
+            .post(&url)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+            ])
+            .send()
+            .await
+            .wrap_err_with(|| format!("Could not reach the token endpoint at {url}"))?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("The token endpoint at {url} returned {status}");
+        }
+        let token: Token = response
+            .json()
+            .await
+            .wrap_err("Could not parse the access token")?;
+        Ok(token.access_token)
+    }
+}
+
+impl Event {
+    /// The name of the event for the log, or its ID if it has no name.
+    fn name(&self) -> &str {
+        self.event_name
+            .as_deref()
+            .or(self.id.as_deref())
+            .unwrap_or("without a name")
+    }
+
+    /// What the intervals of the event ask of the site. An interval without a period of its own starts where the one
+    /// before it ended, or at the start of the event, and lasts as long as the period of the event says.
+    fn signals(&self) -> eyre::Result<Vec<Signal>> {
+        let mut signals = Vec::new();
+        let mut next_start = self.interval_period.as_ref().map(|period| period.start);
+        for interval in &self.intervals {
+            let (start, period) = match (&interval.interval_period, &self.interval_period) {
+                (Some(period), _) => (period.start, period),
+                (None, Some(period)) => (next_start.unwrap_or(period.start), period),
+                (None, None) => bail!("It has an interval without a period"),
+            };
+            let end = match &period.duration {
+                Some(duration) => start
+                    .checked_add_signed(parse_duration(duration)?)
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC),
+                None => DateTime::<Utc>::MAX_UTC,
+            };
+            next_start = Some(end);
+
+            let mut signal = Signal {
+                start,
+                end,
+                priority: self.priority.unwrap_or(u64::MAX),
+                import_limit: None,
+                export_limit: None,
+                price: None,
+            };
+            for payload in &interval.payloads {
+                let Some(value) = payload.values.first().and_then(serde_json::Value::as_f64) else {
+                    bail!("Its {} payload has no number", payload.payload_type);
+                };
+                let units = self
+                    .payload_descriptors
+                    .iter()
+                    .find(|descriptor| descriptor.payload_type == payload.payload_type)
+                    .and_then(|descriptor| descriptor.units.as_deref());
+                match payload.payload_type.as_str() {
+                    "IMPORT_CAPACITY_LIMIT" => signal.import_limit = Some(watts(value, units)?),
+                    "EXPORT_CAPACITY_LIMIT" => signal.export_limit = Some(watts(value, units)?),
+                    "PRICE" => signal.price = Some(per_kwh(value, units)?),
+                    other => {
+                        tracing::debug!("Ignoring {other} payload of OpenADR event {}", self.name())
+                    }
+                }
+            }
+            if signal.import_limit.is_some()
+                || signal.export_limit.is_some()
+                || signal.price.is_some()
+            {
+                signals.push(signal);
+            }
+        }
+        Ok(signals)
+    }
+}
+
+/// A capacity limit in W, from a value in the units of its payload descriptor, which are kW by default.
+fn watts(value: f64, units: Option<&str>) -> eyre::Result<f64> {
+    match units.unwrap_or("KW").to_ascii_uppercase().as_str() {
+        "KW" => Ok(value * 1000.0),
+        "W" => Ok(value),
+        "MW" => Ok(value * 1_000_000.0),
+        units => bail!("Invalid units for a capacity limit ({units}); should be W, KW or MW"),
+    }
+}
+
+/// A price per kWh, from a value in the units of its payload descriptor, which are per kWh by default.
+fn per_kwh(value: f64, units: Option<&str>) -> eyre::Result<f64> {
+    match units.unwrap_or("KWH").to_ascii_uppercase().as_str() {
+        "KWH" => Ok(value),
+        "WH" => Ok(value * 1000.0),
+        "MWH" => Ok(value / 1000.0),
+        units => bail!("Invalid units for a price ({units}); should be WH, KWH or MWH"),
+    }
+}
+
+/// Parses an ISO 8601 duration like `PT1H`, `PT15M` or `P1D`, as OpenADR gives them. A year counts as 365 days and a
+/// month as 30, which only matters for durations like `P9999Y` that OpenADR uses for intervals that don't end.
+fn parse_duration(text: &str) -> eyre::Result<TimeDelta> {
+    let invalid = || eyre!("Invalid duration {text}");
+    let rest = text.strip_prefix('P').ok_or_else(invalid)?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+    const DAY: f64 = 24.0 * 60.0 * 60.0;
+    let date_units = [
+        ('Y', 365.0 * DAY),
+        ('M', 30.0 * DAY),
+        ('W', 7.0 * DAY),
+        ('D', DAY),
+    ];
+    let time_units = [('H', 60.0 * 60.0), ('M', 60.0), ('S', 1.0)];
+    let mut seconds = 0.0;
+    let mut found = false;
+    for (mut part, units) in [(date, &date_units[..]), (time, &time_units[..])] {
+        for (designator, unit) in units {
+            if let Some((number, after)) = part.split_once(*designator) {
+                let number: f64 = number.parse().map_err(|_| invalid())?;
+                seconds += number * unit;
+                part = after;
+                found = true;
+            }
+        }
+        if !part.is_empty() {
+            return Err(invalid());
+        }
+    }
+    if !found {
+        return Err(invalid());
+    }
+    TimeDelta::try_milliseconds((seconds * 1000.0) as i64).ok_or_else(invalid)
+}
@@ -0,0 +1,235 @@
+use crate::battery::Battery;
+use crate::day_ahead::{self, DayAhead, Plan};
+use crate::simplex::{self, Constraint, Relation};
+use chrono::{DateTime, Utc};
+
+/// Plans a storage with a linear program, and returns the cheapest plan; `None` until the RM described the storage and
+/// reported its fill level, or if the storage can't stay within its fill level range.
+///
+/// For every slot of the horizon, the linear program chooses how long the storage spends in every operation mode at
+/// factor 0 and at factor 1, which together make up the whole slot. That finds the optimum over the fill level as a
+/// continuum, rather than the steps and factors the dynamic programming planner tries, so it plans a storage that
+/// gets full or empty within a slot exactly. It takes the operation modes as they are at the fill level the storage
+/// is at now, though, so it doesn't follow a storage that charges slower when it's nearly full.
+///
+/// The storage stays within its fill level range and meets the targets of its fill level target profile, or without
+/// one, ends the day at least as full as it started, like with the dynamic programming planner. For now, the storage
+/// goes to the operation mode it spends the most of the first slot in, at the factor that matches that part of the
+/// slot.
+pub(crate) fn plan_storage(
+    day_ahead: &DayAhead,
+    battery: &Battery,
+    now: DateTime<Utc>,
+) -> Option<Plan> {
+    let (lowest, highest) = battery.fill_level_range()?;
+    let fill_level = battery.fill_level()?;
+    let modes = battery.modes_at(fill_level.clamp(lowest, highest));
+    if modes.is_empty() {
+        return None;
+    }
+    let slots = day_ahead::slots(now);
+    // Two variables per operation mode per slot: the share of the slot at factor 0, and at factor 1.
+    let per_slot = modes.len() * 2;
+    let variables = slots.len() * per_slot;
+    let variable = |slot: usize, mode: usize, factor: usize| slot * per_slot + mode * 2 + factor;
+    let at_factor =
+        |values: (f64, f64), factor: usize| if factor == 0 { values.0 } else { values.1 };
+
+    let mut objective = vec![0.0; variables];
+    for (slot, (time, seconds)) in slots.iter().enumerate() {
+        let price = day_ahead.price(*time);
+        for (index, mode) in modes.iter().enumerate() {
+            for factor in [0, 1] {
+                let power = at_factor(mode.power, factor);
+                objective[variable(slot, index, factor)] =
+                    day_ahead::energy_cost(price, power, *seconds) + mode.running_costs * seconds;
+            }
+        }
+    }
+    // The constraints, given the lowest and highest fill level the storage may have at the end of every slot.
+    let constraints = |allowed: &dyn Fn(usize) -> (f64, f64)| {
+        let mut constraints = Vec::new();
+        // The fill level the storage gets to from now on, by how it spends every slot up to the current one.
+        let mut filled = vec![0.0; variables];
+        for (slot, (_, seconds)) in slots.iter().enumerate() {
+            let mut whole_slot = vec![0.0; variables];
+            for (index, mode) in modes.iter().enumerate() {
+                for factor in [0, 1] {
+                    let column = variable(slot, index, factor);
+                    filled[column] = at_factor(mode.fill_rate, factor) * seconds;
+                    whole_slot[column] = 1.0;
+                }
+            }
+            constraints.push(Constraint {
+                coefficients: whole_slot,
+                relation: Relation::Equal,
+                bound: 1.0,
+            });
+            let (low, high) = allowed(slot);
+            constraints.push(Constraint {
+                coefficients: filled.clone(),
+                relation: Relation::AtLeast,
+                bound: low - fill_level,
+            });
+            constraints.push(Constraint {
+                coefficients: filled.clone(),
+                relation: Relation::AtMost,
+                bound: high - fill_level,
+            });
+        }
+        constraints
+    };
+    let targets = day_ahead::target_levels(battery, &slots);
+    let with_targets = |slot: usize| match &targets {
+        Some(targets) => targets[slot + 1].map_or((lowest, highest), |(low, high)| {
+            (low.max(lowest), high.min(highest))
+        }),
+        // Without targets, the storage ends at least as full as it started.
+        None if slot + 1 == slots.len() => (fill_level.clamp(lowest, highest), highest),
+        None => (lowest, highest),
+    };
+    let values = simplex::minimize(&objective, &constraints(&with_targets)).or_else(|| {
+        // Planning without the targets is better than not planning at all.
+        tracing::warn!(
+            "The storage can't meet its fill level targets, so the CEM plans without them"
+        );
+        simplex::minimize(&objective, &constraints(&|_| (lowest, highest)))
+    })?;
+
+    let cost = objective
+        .iter()
+        .zip(&values)
+        .map(|(cost, value)| cost * value)
+        .sum();
+    let power = slots
+        .iter()
+        .enumerate()
+        .map(|(slot, (time, _))| {
+            let power = modes
+                .iter()
+                .enumerate()
+                .flat_map(|(index, mode)| {
+                    [0, 1].map(|factor| {
+                        at_factor(mode.power, factor) * values[variable(slot, index, factor)]
+                    })
+                })
+                .sum();
+            (*time, power)
+        })
+        .collect();
+    let shares = |index: usize| (values[variable(0, index, 0)], values[variable(0, index, 1)]);
+    let (first, (at_zero, at_one)) = (0..modes.len())
+        .map(|index| (index, shares(index)))
+        .max_by(|(_, a), (_, b)| (a.0 + a.1).total_cmp(&(b.0 + b.1)))?;
+    Some(Plan {
+        operation_mode: modes[first].mode.id.clone(),
+        factor: (at_one / (at_zero + at_one)).clamp(0.0, 1.0),
+        power,
+        cost,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use prices::Prices;
+    use s2energy::common::Message;
+    use serde_json::json;
+
+    /// The capacity of the battery of the tests, in Wh, with its fill level in percent.
+    const CAPACITY_WH: f64 = 10_000.0;
+    const MAX_POWER_W: f64 = 5000.0;
+
+    /// A lossless battery at `fill_level` percent, which charges and discharges at up to [`MAX_POWER_W`].
+    fn battery(fill_level: f64) -> Battery {
+        // Percent per second at full power.
+        let fill_rate = MAX_POWER_W / CAPACITY_WH * 100.0 / 3600.0;
+        let mode = |id: &str, power: f64, fill_rate: f64| {
+            json!({
+                "id": id,
+                "elements": [{
+                    "fill_level_range": {"start_of_range": 0.0, "end_of_range": 100.0},
+                    "fill_rate": {"start_of_range": 0.0, "end_of_range": fill_rate},
+                    "power_ranges": [{
+                        "start_of_range": 0.0,
+                        "end_of_range": power,
+                        "commodity_quantity": "ELECTRIC.POWER.L1",
+                    }],
+                }],
+                "abnormal_condition_only": false,
+            })
+        };
+        let description = json!({
+            "message_type": "FRBC.SystemDescription",
+            "message_id": "00000000-0000-0000-0000-000000000001",
+            "valid_from": "2025-06-01T00:00:00Z",
+            "actuators": [{
+                "id": "00000000-0000-0000-0000-000000000002",
+                "supported_commodities": ["ELECTRICITY"],
+                "operation_modes": [
+                    mode("00000000-0000-0000-0000-000000000003", MAX_POWER_W, fill_rate),
+                    mode("00000000-0000-0000-0000-000000000004", -MAX_POWER_W, -fill_rate),
+                ],
+                "transitions": [],
+                "timers": [],
+            }],
+            "storage": {
+                "fill_level_range": {"start_of_range": 0.0, "end_of_range": 100.0},
+                "provides_leakage_behaviour": false,
+                "provides_fill_level_target_profile": false,
+                "provides_usage_forecast": false,
+            },
+        });
+        let status = json!({
+            "message_type": "FRBC.StorageStatus",
+            "message_id": "00000000-0000-0000-0000-000000000005",
+            "present_fill_level": fill_level,
+        });
+        let mut battery = Battery::default();
+        for message in [description, status] {
+            battery.update(&serde_json::from_value::<Message>(message).unwrap());
+        }
+        battery
+    }
+
+    #[test]
+    fn keeps_the_battery_within_its_fill_level_range() {
+        let now = "2025-06-01T00:00:00Z".parse().unwrap();
+        // Free energy every other few hours, so the cheapest plan fills and empties the battery as far as it can.
+        let prices = (0..24)
+            .map(|hour| {
+                (
+                    now + TimeDelta::hours(hour),
+                    if hour % 6 < 3 { 0.0 } else { 1.0 },
+                )
+            })
+            .collect();
+        let prices = Prices::new(prices, now + TimeDelta::hours(24)).unwrap();
+        let day_ahead = DayAhead::new(prices, true);
+        let start = 50.0;
+
+        let plan =
+            plan_storage(&day_ahead, &battery(start), now).expect("the battery should get a plan");
+
+        let mut fill_level = start;
+        let (mut lowest, mut highest) = (start, start);
+        for (slot, (time, seconds)) in day_ahead::slots(now).iter().enumerate() {
+            assert_eq!(plan.power[slot].0, *time);
+            fill_level += plan.power[slot].1 / CAPACITY_WH * 100.0 * seconds / 3600.0;
+            (lowest, highest) = (lowest.min(fill_level), highest.max(fill_level));
+        }
+        assert!(
+            lowest > -1e-6 && highest < 100.0 + 1e-6,
+            "the fill level should stay from 0 to 100%, but went from {lowest} to {highest}"
+        );
+        assert!(
+            lowest < 1.0 && highest > 99.0,
+            "the plan should use the whole fill level range, but only went from {lowest} to {highest}"
+        );
+        assert!(
+            fill_level > start - 1e-6,
+            "the battery should end at least as full as it started, but ended at {fill_level}"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use s2energy::common::{CommodityQuantity, PowerForecast, PowerForecastValue, PowerValue};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How old the latest measurement of an RM may get before its forecast is used instead.
+const STALE_MEASUREMENT: Duration = Duration::from_secs(5 * 60);
+
+/// Keeps the power of the whole site under a limit, with the FRBC batteries that are connected.
+///
+/// Every session reports the power its RM measures, except the batteries: their power is what this strategy controls.
+/// The batteries together aim for the power that brings the site to the limit: they discharge when the rest of the site
+/// uses more than the limit, and charge with the room that's left under the limit otherwise. They share the work
+/// equally.
+///
+/// With a limit of 0 W, this maximizes self-consumption: the batteries charge with what the PV feeds in, and discharge
+/// to cover what the site uses, so the site neither takes from the grid nor feeds into it. Where an RM such as a PV
+/// installation hasn't measured anything yet, or not for a while, its forecast for now stands in for its measurement.
+///
+/// The DDBC devices and OMBC loads that connect reduce what they use when the rest of the site leaves no room under the
+/// limit, so the site stays within the capacity of its grid connection, as in congestion management. A demand response
+/// event can lower the limit for as long as it lasts.
+pub(crate) struct PeakShaving {
+    /// The limit for the power of the site, in W.
+    limit: f64,
+    /// The limit of the demand response events going on, if any, in W.
+    event_limit: Mutex<Option<f64>>,
+    /// What the batteries are instructed for, for the log.
+    goal: String,
+    site: Mutex<Site>,
+    /// Tells the battery sessions that the power they should aim for changed.
+    changed: watch::Sender<()>,
+}
+
+#[derive(Default)]
+struct Site {
+    /// The latest power every RM that isn't a battery measured, by session number, and when the CEM got it.
+    measured: HashMap<usize, (f64, Instant)>,
+    /// The latest forecast of every RM that isn't a battery, by session number.
+    forecasts: HashMap<usize, PowerForecast>,
+    /// The numbers of the sessions with a battery.
+    batteries: HashSet<usize>,
+}
+
+impl PeakShaving {
+    pub(crate) fn new(limit: f64) -> Self {
+        Self {
+            limit,
+            event_limit: Mutex::new(None),
+            goal: format!("to keep the site under {limit} W"),
+            site: Mutex::new(Site::default()),
+            changed: watch::Sender::new(()),
+        }
+    }
+
+    /// Keeps the power of the site at 0 W, so it uses what the PV produces itself.
+    pub(crate) fn self_consumption() -> Self {
+        Self {
+            goal: "to use what the site produces itself".into(),
+            ..Self::new(0.0)
+        }
+    }
+
+    pub(crate) fn goal(&self) -> String {
+        match *self.event_limit.lock().unwrap() {
+            Some(limit) if limit < self.limit => {
+                format!("to keep the site under {limit} W during a demand response event")
+            }
+            _ => self.goal.clone(),
+        }
+    }
+
+    /// The limit for the power of the site now, in W.
+    fn limit(&self) -> f64 {
+        let event_limit = *self.event_limit.lock().unwrap();
+        event_limit.map_or(self.limit, |limit| limit.min(self.limit))
+    }
+
+    /// Replaces the limit of the demand response events going on, and tells the sessions if that changes it.
+    pub(crate) fn set_event_limit(&self, limit: Option<f64>) {
+        let mut event_limit = self.event_limit.lock().unwrap();
+        if *event_limit == limit {
+            return;
+        }
+        *event_limit = limit;
+        drop(event_limit);
+        self.changed.send_replace(());
+    }
+
+    /// Registers the battery of a session, and returns a receiver that's notified when it should aim for another power.
+    pub(crate) fn add_battery(&self, session: usize) -> watch::Receiver<()> {
+        self.site.lock().unwrap().batteries.insert(session);
+        self.changed.send_replace(());
+        self.changed.subscribe()
+    }
+
+    /// Returns a receiver that's notified when the power of the site may have changed, for a session that isn't a
+    /// battery.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Keeps track of the power the RM of a session measured.
+    pub(crate) fn measure(&self, session: usize, power: f64) {
+        let mut site = self.site.lock().unwrap();
+        site.measured.insert(session, (power, Instant::now()));
+        drop(site);
+        self.changed.send_replace(());
+    }
+
+    /// Keeps track of the forecast of the RM of a session.
+    pub(crate) fn forecast(&self, session: usize, forecast: PowerForecast) {
+        self.site
+            .lock()
+            .unwrap()
+            .forecasts
+            .insert(session, forecast);
+        self.changed.send_replace(());
+    }
+
+    /// Forgets about a session that ended.
+    pub(crate) fn remove(&self, session: usize) {
+        let mut site = self.site.lock().unwrap();
+        site.measured.remove(&session);
+        site.forecasts.remove(&session);
+        site.batteries.remove(&session);
+        drop(site);
+        self.changed.send_replace(());
+    }
+
+    /// The power every battery should aim for, in W: positive to charge and negative to discharge.
+    pub(crate) fn battery_target(&self) -> f64 {
+        let site = self.site.lock().unwrap();
+        (self.limit() - site.power(None)) / site.batteries.len().max(1) as f64
+    }
+
+    /// The power the RM of a session can use without taking the site over the limit, in W, given what the rest of the
+    /// site uses.
+    pub(crate) fn room(&self, session: usize) -> f64 {
+        self.limit() - self.site.lock().unwrap().power(Some(session))
+    }
+}
+
+impl Site {
+    /// The power of the site without the batteries, and without the RM of session `except`, if any, in W.
+    fn power(&self, except: Option<usize>) -> f64 {
+        let now = Utc::now();
+        let sessions: HashSet<_> = self.measured.keys().chain(self.forecasts.keys()).collect();
+        sessions
+            .into_iter()
+            .filter(|session| Some(**session) != except)
+            .filter_map(|session| {
+                let measured = self
+                    .measured
+                    .get(session)
+                    .filter(|(_, at)| at.elapsed() < STALE_MEASUREMENT)
+                    .map(|(power, _)| *power);
+                let forecast = || expected_power(self.forecasts.get(session)?, now);
+                let stale = || self.measured.get(session).map(|(power, _)| *power);
+                measured.or_else(forecast).or_else(stale)
+            })
+            .sum()
+    }
+}
+
+/// The electric power a forecast expects at `time`, in W, or `None` if it doesn't cover that time.
+fn expected_power(forecast: &PowerForecast, time: DateTime<Utc>) -> Option<f64> {
+    let mut start = forecast.start_time;
+    let element = forecast.elements.iter().find(|element| {
+        let end = start + TimeDelta::milliseconds(*element.duration as i64);
+        let covers = start <= time && time < end;
+        start = end;
+        covers
+    })?;
+    expected_electric_power(&element.power_values)
+}
+
+/// The electric power forecast values expect, in W, like [`electric_power`].
+pub(crate) fn expected_electric_power(values: &[PowerForecastValue]) -> Option<f64> {
+    let values: Vec<PowerValue> = values
+        .iter()
+        .map(|value| PowerValue {
+            commodity_quantity: value.commodity_quantity,
+            value: value.value_expected,
+        })
+        .collect();
+    electric_power(&values)
+}
+
+/// The electric power in a measurement, in W, or `None` if it doesn't measure electric power.
+///
+/// The symmetric three-phase quantity is the power of all phases together; otherwise the phases are added up.
+pub(crate) fn electric_power(values: &[PowerValue]) -> Option<f64> {
+    let value = |quantity: CommodityQuantity| {
+        values
+            .iter()
+            .find(|value| value.commodity_quantity == quantity)
+            .map(|value| value.value)
+    };
+    value(CommodityQuantity::ElectricPower3PhaseSymmetric).or_else(|| {
+        [
+            CommodityQuantity::ElectricPowerL1,
+            CommodityQuantity::ElectricPowerL2,
+            CommodityQuantity::ElectricPowerL3,
+        ]
+        .into_iter()
+        .filter_map(value)
+        .reduce(|total, power| total + power)
+    })
+}
@@ -0,0 +1,148 @@
+use crate::grid_limits::Share;
+use chrono::{TimeDelta, Utc};
+use s2energy::common::{CommodityQuantity, Duration, Id, Message, NumberRange};
+use s2energy::pebc;
+
+/// What the CEM knows about a PEBC RM, such as a curtailable PV installation, to send it power envelopes.
+#[derive(Default)]
+pub(crate) struct PebcDevice {
+    constraints: Option<pebc::PowerConstraints>,
+    /// The power constraints the latest instruction referred to, and the share of the grid limits it was for.
+    instructed: Option<(Id, Share)>,
+}
+
+impl PebcDevice {
+    pub(crate) fn update(&mut self, message: &Message) {
+        if let Message::PebcPowerConstraints(constraints) = message {
+            self.constraints = Some(constraints.clone());
+        }
+    }
+
+    /// Returns an instruction with a power envelope for every electric commodity quantity the RM allows limits for,
+    /// which keeps the RM within `share` as far as its power constraints allow, unless it already got one for the same
+    /// constraints and share.
+    ///
+    /// The envelopes last until the power constraints expire, or for a day if they don't. Limits for the separate phases
+    /// are split equally over the phases.
+    pub(crate) fn instruction(&mut self, share: Share) -> Option<pebc::Instruction> {
+        let constraints = self.constraints.as_ref()?;
+        if self
+            .instructed
+            .as_ref()
+            .is_some_and(|(id, instructed)| *id == constraints.id && *instructed == share)
+        {
+            return None;
+        }
+        let now = Utc::now();
+        let until = constraints.valid_until.unwrap_or(now + TimeDelta::days(1));
+        let duration = (until - now).num_milliseconds();
+        if duration <= 0 {
+            return None;
+        }
+
+        let ranges = |quantity: CommodityQuantity, limit_type: pebc::PowerEnvelopeLimitType| {
+            constraints
+                .allowed_limit_ranges
+                .iter()
+                .filter(move |range| {
+                    range.commodity_quantity == quantity
+                        && range.limit_type == limit_type
+                        && !range.abnormal_condition_only
+                })
+                .map(|range| &range.range_boundary)
+        };
+        let mut quantities: Vec<CommodityQuantity> = constraints
+            .allowed_limit_ranges
+            .iter()
+            .map(|range| range.commodity_quantity)
+            .filter(|quantity| is_electric_power(*quantity))
+            .collect();
+        quantities.sort();
+        quantities.dedup();
+        let phases = quantities
+            .iter()
+            .filter(|quantity| **quantity != CommodityQuantity::ElectricPower3PhaseSymmetric)
+            .count()
+            .max(1) as f64;
+
+        let power_envelopes: Vec<pebc::PowerEnvelope> = quantities
+            .into_iter()
+            .filter_map(|quantity| {
+                let per_quantity = |limit: f64| {
+                    if quantity == CommodityQuantity::ElectricPower3PhaseSymmetric {
+                        limit
+                    } else {
+                        limit / phases
+                    }
+                };
+                let lower = -share.feed_in.map_or(f64::INFINITY, per_quantity);
+                let upper = share.consumption.map_or(f64::INFINITY, per_quantity);
+                let lower = closest(
+                    ranges(quantity, pebc::PowerEnvelopeLimitType::LowerLimit),
+                    lower,
+                )?;
+                let upper = closest(
+                    ranges(quantity, pebc::PowerEnvelopeLimitType::UpperLimit),
+                    upper,
+                )?;
+                // Where the allowed ranges leave no lower limit under the upper limit, there's no envelope that works.
+                (lower <= upper).then(|| pebc::PowerEnvelope {
+                    commodity_quantity: quantity,
+                    id: Id::generate(),
+                    power_envelope_elements: vec![pebc::PowerEnvelopeElement {
+                        duration: Duration(duration as u64),
+                        lower_limit: lower,
+                        upper_limit: upper,
+                    }],
+                })
+            })
+            .collect();
+        if power_envelopes.is_empty() {
+            return None;
+        }
+        self.instructed = Some((constraints.id.clone(), share));
+        Some(pebc::Instruction {
+            abnormal_condition: false,
+            execution_time: now,
+            id: Id::generate(),
+            message_id: Id::generate(),
+            power_constraints_id: constraints.id.clone(),
+            power_envelopes,
+        })
+    }
+}
+
+pub(crate) fn is_electric_power(quantity: CommodityQuantity) -> bool {
+    matches!(
+        quantity,
+        CommodityQuantity::ElectricPowerL1
+            | CommodityQuantity::ElectricPowerL2
+            | CommodityQuantity::ElectricPowerL3
+            | CommodityQuantity::ElectricPower3PhaseSymmetric
+    )
+}
+
+/// The value in `ranges` that's closest to `value`, or `None` if there are no ranges. For an infinite `value`, that's
+/// the lowest or highest value in the ranges.
+fn closest<'a>(ranges: impl Iterator<Item = &'a NumberRange>, value: f64) -> Option<f64> {
+    ranges
+        .map(|range| {
+            let (start, end) = if range.start_of_range <= range.end_of_range {
+                (range.start_of_range, range.end_of_range)
+            } else {
+                (range.end_of_range, range.start_of_range)
+            };
+            value.clamp(start, end)
+        })
+        .min_by(|a, b| {
+            let towards_value = if value < 0.0 {
+                a.total_cmp(b)
+            } else {
+                b.total_cmp(a)
+            };
+            (a - value)
+                .abs()
+                .total_cmp(&(b - value).abs())
+                .then(towards_value)
+        })
+}
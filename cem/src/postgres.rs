@@ -0,0 +1,333 @@
+use crate::state::SessionState;
+use chrono::{DateTime, Utc};
+use eyre::Context;
+use s2energy::common::Message;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_postgres::{Client, NoTls};
+
+/// The tables the CEM writes to, which are created when it starts.
+const SCHEMA: &str = include_str!("../schema.sql");
+/// How often the records are written to the database, in one transaction.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How many records are kept while the database can't be reached; the oldest are dropped beyond that.
+const MAX_PENDING: usize = 10_000;
+
+/// Stores the sessions, the measurements of the RMs and the instructions of the CEM in PostgreSQL (or TimescaleDB),
+/// in the tables of `schema.sql`, so they outlive the CEM and can be queried.
+///
+/// The records are written in the background; while the database can't be reached, they're kept up to a point and
+/// written once the CEM connected again.
+#[derive(Clone)]
+pub(crate) struct PostgresStore {
+    records: UnboundedSender<Record>,
+}
+
+/// Something to store, from the session with the given number.
+enum Record {
+    /// The session started, or its state changed.
+    Session {
+        session: usize,
+        state: Box<SessionState>,
+    },
+    Measurement {
+        session: usize,
+        measured_at: DateTime<Utc>,
+        commodity_quantity: String,
+        value: f64,
+    },
+    Instruction {
+        session: usize,
+        id: String,
+        message_type: String,
+        sent_at: DateTime<Utc>,
+        execution_time: Option<DateTime<Utc>>,
+        message: Value,
+    },
+    InstructionStatus {
+        session: usize,
+        instruction_id: String,
+        status: String,
+        at: DateTime<Utc>,
+    },
+}
+
+impl PostgresStore {
+    /// Connects to the database with the given connection string, creates the tables if they aren't there and starts
+    /// writing to them in the background.
+    pub(crate) async fn connect(url: &str) -> eyre::Result<Self> {
+        let config: tokio_postgres::Config = url
+            .parse()
+            .wrap_err("Invalid PostgreSQL connection string")?;
+        let client = connect(&config)
+            .await
+            .wrap_err("Could not connect to PostgreSQL")?;
+        // Without the notices about the tables that are there already.
+        client
+            .batch_execute(&format!("SET client_min_messages = warning;\n{SCHEMA}"))
+            .await
+            .wrap_err("Could not create the tables in PostgreSQL")?;
+        let (records, receiver) = mpsc::unbounded_channel();
+        let writer = Writer {
+            config,
+            client: Some(client),
+            sessions: HashMap::new(),
+        };
+        tokio::spawn(writer.run(receiver));
+        tracing::info!("Storing the sessions, measurements and instructions in PostgreSQL");
+        Ok(Self { records })
+    }
+
+    /// Stores the state of a session, when it starts and whenever it changes.
+    pub(crate) fn write_session(&self, session: usize, state: &SessionState) {
+        self.write(Record::Session {
+            session,
+            state: Box::new(state.clone()),
+        });
+    }
+
+    /// Stores a message from an RM, if it's a measurement or the status of an instruction.
+    pub(crate) fn write_received(&self, session: usize, message: &Message) {
+        match message {
+            Message::PowerMeasurement(measurement) => {
+                for value in &measurement.values {
+                    self.write(Record::Measurement {
+                        session,
+                        measured_at: measurement.measurement_timestamp,
+                        commodity_quantity: value.commodity_quantity.to_string(),
+                        value: value.value,
+                    });
+                }
+            }
+            Message::InstructionStatusUpdate(update) => {
+                self.write(Record::InstructionStatus {
+                    session,
+                    instruction_id: update.instruction_id.to_string(),
+                    status: update.status_type.to_string(),
+                    at: update.timestamp,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Stores an instruction the CEM sent to an RM.
+    pub(crate) fn write_instruction(&self, session: usize, instruction: &Message) {
+        let Ok(message) = serde_json::to_value(instruction) else {
+            return;
+        };
+        let text = |key: &str| message[key].as_str().unwrap_or_default().to_string();
+        let execution_time = message["execution_time"]
+            .as_str()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.to_utc());
+        self.write(Record::Instruction {
+            session,
+            id: text("id"),
+            message_type: text("message_type"),
+            sent_at: Utc::now(),
+            execution_time,
+            message,
+        });
+    }
+
+    fn write(&self, record: Record) {
+        // The writer only stops when the CEM does.
+        let _ = self.records.send(record);
+    }
+}
+
+/// Writes the records to the database in batches, in the background.
+struct Writer {
+    config: tokio_postgres::Config,
+    /// `None` while the database can't be reached.
+    client: Option<Client>,
+    /// The ID in the `sessions` table of every session that's going on, by session number.
+    sessions: HashMap<usize, i64>,
+}
+
+impl Writer {
+    /// Writes the records that came in every [`FLUSH_INTERVAL`], until the CEM stops.
+    async fn run(mut self, mut records: UnboundedReceiver<Record>) {
+        let mut pending: Vec<Record> = Vec::new();
+        let mut failing = false;
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                record = records.recv() => match record {
+                    Some(record) => pending.push(record),
+                    None => return,
+                },
+                _ = flush.tick(), if !pending.is_empty() => {
+                    match self.write(&pending).await {
+                        Ok(()) => {
+                            if failing {
+                                tracing::info!("Writing to PostgreSQL again");
+                                failing = false;
+                            }
+                            pending.clear();
+                        }
+                        // The records are written again once the CEM connected again.
+                        Err(error) if self.client.as_ref().is_none_or(Client::is_closed) => {
+                            // Warn once, rather than every second while the database is down.
+                            if !failing {
+                                tracing::warn!("Could not write to PostgreSQL: {error:#}");
+                                failing = true;
+                            }
+                            self.client = None;
+                            let excess = pending.len().saturating_sub(MAX_PENDING);
+                            pending.drain(..excess);
+                        }
+                        // Writing them again would fail the same way.
+                        Err(error) => {
+                            tracing::warn!(
+                                "Could not write {} records to PostgreSQL: {error:#}",
+                                pending.len()
+                            );
+                            pending.clear();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the records in one transaction, connecting to the database first if the connection was lost.
+    async fn write(&mut self, records: &[Record]) -> eyre::Result<()> {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => connect(&self.config).await?,
+        };
+        let client = self.client.insert(client);
+        let transaction = client.transaction().await?;
+        // The sessions that started in this batch are only known once it's committed.
+        let mut started = HashMap::new();
+        // Only the latest state of every session is written.
+        let mut states = HashMap::new();
+        for record in records {
+            let session = match record {
+                Record::Session { session, .. }
+                | Record::Measurement { session, .. }
+                | Record::Instruction { session, .. }
+                | Record::InstructionStatus { session, .. } => *session,
+            };
+            let id = self
+                .sessions
+                .get(&session)
+                .or(started.get(&session))
+                .copied();
+            let id = match (record, id) {
+                (Record::Session { state, .. }, Some(id)) => {
+                    states.insert(id, state);
+                    continue;
+                }
+                // A session that ended and isn't known was written already.
+                (Record::Session { state, .. }, None) if state.ended_at.is_none() => {
+                    let row = transaction
+                        .query_one(
+                            "INSERT INTO sessions (rm_address, started_at, state) VALUES ($1, $2, $3) RETURNING id",
+                            &[
+                                &state.rm_address.to_string(),
+                                &state.started_at,
+                                &serde_json::to_value(state)?,
+                            ],
+                        )
+                        .await?;
+                    let id: i64 = row.get(0);
+                    started.insert(session, id);
+                    states.insert(id, state);
+                    continue;
+                }
+                (_, Some(id)) => id,
+                // Its start was dropped while the database couldn't be reached.
+                (_, None) => continue,
+            };
+            match record {
+                Record::Session { .. } => {}
+                Record::Measurement {
+                    measured_at,
+                    commodity_quantity,
+                    value,
+                    ..
+                } => {
+                    transaction
+                        .execute(
+                            "INSERT INTO measurements (session_id, measured_at, commodity_quantity, value) \
+                            VALUES ($1, $2, $3, $4)",
+                            &[&id, measured_at, commodity_quantity, value],
+                        )
+                        .await?;
+                }
+                Record::Instruction {
+                    id: instruction_id,
+                    message_type,
+                    sent_at,
+                    execution_time,
+                    message,
+                    ..
+                } => {
+                    transaction
+                        .execute(
+                            "INSERT INTO instructions (id, session_id, message_type, sent_at, execution_time, message) \
+                            VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (id) DO NOTHING",
+                            &[instruction_id, &id, message_type, sent_at, execution_time, message],
+                        )
+                        .await?;
+                }
+                Record::InstructionStatus {
+                    instruction_id,
+                    status,
+                    at,
+                    ..
+                } => {
+                    transaction
+                        .execute(
+                            "INSERT INTO instruction_statuses (instruction_id, session_id, status, at) \
+                            VALUES ($1, $2, $3, $4)",
+                            &[instruction_id, &id, status, at],
+                        )
+                        .await?;
+                }
+            }
+        }
+        for (id, state) in &states {
+            let details = state.rm_details.as_ref();
+            transaction
+                .execute(
+                    "UPDATE sessions SET ended_at = $2, end_reason = $3, resource_id = $4, rm_name = $5, \
+                    control_type = $6, protocol_version = $7, resumed_from = $8, state = $9 WHERE id = $1",
+                    &[
+                        id,
+                        &state.ended_at,
+                        &state.end_reason,
+                        &details.map(|details| details.resource_id.to_string()),
+                        &details.and_then(|details| details.name.clone()),
+                        &state.control_type.map(|control_type| control_type.to_string()),
+                        &state.protocol_version,
+                        &state.resumed_from,
+                        &serde_json::to_value(state)?,
+                    ],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        self.sessions.extend(started);
+        // Nothing is written for a session after it ended.
+        self.sessions
+            .retain(|_, id| states.get(id).is_none_or(|state| state.ended_at.is_none()));
+        Ok(())
+    }
+}
+
+/// Connects to the database, and keeps the connection going in the background.
+async fn connect(config: &tokio_postgres::Config) -> eyre::Result<Client> {
+    let (client, connection) = config.connect(NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(error) = connection.await {
+            tracing::debug!("The connection with PostgreSQL ended: {error}");
+        }
+    });
+    Ok(client)
+}
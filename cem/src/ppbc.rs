@@ -0,0 +1,218 @@
+use crate::day_ahead::DayAhead;
+use crate::grid_limits::GridLimits;
+use crate::peak_shaving;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use s2energy::common::{Id, Message};
+use s2energy::ppbc;
+use std::collections::HashMap;
+
+/// How far apart the start times are that the CEM considers for a power sequence: every quarter of an hour.
+const START_STEP: TimeDelta = TimeDelta::minutes(15);
+
+/// What the CEM knows about a PPBC appliance, such as a washing machine or a dishwasher, to schedule its power
+/// sequences.
+#[derive(Default)]
+pub(crate) struct PpbcAppliance {
+    definition: Option<ppbc::PowerProfileDefinition>,
+    /// The latest status of every sequence container, by its ID.
+    statuses: HashMap<Id, ppbc::PowerSequenceStatus>,
+    /// Whether the CEM still has to schedule the latest power profile.
+    schedule_due: bool,
+}
+
+/// A power sequence the CEM scheduled.
+pub(crate) struct Scheduled {
+    pub(crate) instruction: ppbc::ScheduleInstruction,
+    pub(crate) end: DateTime<Utc>,
+    /// The power of the sequence from the start of every element, in W.
+    pub(crate) power: Vec<(DateTime<Utc>, f64)>,
+    /// What the energy of the sequence costs at the day-ahead prices, if the CEM has those.
+    pub(crate) cost: Option<f64>,
+}
+
+/// A sequence of a container the CEM could pick, with a start time.
+struct Candidate<'a> {
+    sequence: &'a ppbc::PowerSequence,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    power: Vec<(DateTime<Utc>, f64)>,
+    /// Whether the sequence would take the site over the power limit.
+    over_limit: bool,
+    cost: Option<f64>,
+}
+
+impl PpbcAppliance {
+    /// Keeps track of the power profile and the status of its sequence containers, and returns the statuses that
+    /// changed.
+    pub(crate) fn update(&mut self, message: &Message) -> Vec<ppbc::PowerSequenceContainerStatus> {
+        match message {
+            Message::PpbcPowerProfileDefinition(definition) => {
+                self.definition = Some(definition.clone());
+                self.statuses.clear();
+                self.schedule_due = true;
+                Vec::new()
+            }
+            Message::PpbcPowerProfileStatus(status) => status
+                .sequence_container_status
+                .iter()
+                .filter(|container| {
+                    let previous = self
+                        .statuses
+                        .insert(container.sequence_container_id.clone(), container.status);
+                    previous != Some(container.status)
+                })
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Schedules the sequence containers of the latest power profile that aren't scheduled yet, once, and returns the
+    /// sequences it picked.
+    ///
+    /// The containers run one after another, within the time the power profile allows. For every container, the CEM
+    /// picks the sequence and start time that cost the least at the day-ahead prices, or the earliest without prices.
+    /// With a power limit, start times where the appliance would take the site over the limit, together with what the
+    /// other appliances are scheduled to use, are only picked if there's no other way.
+    pub(crate) fn schedule(
+        &mut self,
+        day_ahead: Option<&DayAhead>,
+        grid_limits: Option<(&GridLimits, usize)>,
+        now: DateTime<Utc>,
+    ) -> Vec<Scheduled> {
+        let Some(definition) = self.definition.as_ref().filter(|_| self.schedule_due) else {
+            return Vec::new();
+        };
+        self.schedule_due = false;
+        let containers: Vec<_> = definition
+            .power_sequences_containers
+            .iter()
+            .filter(|container| {
+                self.statuses
+                    .get(&container.id)
+                    .is_none_or(|status| *status == ppbc::PowerSequenceStatus::NotScheduled)
+            })
+            .collect();
+        // The shortest each container can take, to leave room for the containers after it.
+        let shortest: Vec<TimeDelta> = containers
+            .iter()
+            .map(|container| sequences(container).map(duration).min().unwrap_or_default())
+            .collect();
+
+        let mut scheduled = Vec::new();
+        let mut earliest = now.max(definition.start_time);
+        for (index, container) in containers.iter().enumerate() {
+            let after: TimeDelta = shortest[index + 1..].iter().sum();
+            let best = sequences(container)
+                .flat_map(|sequence| {
+                    let mut latest = definition.end_time - duration(sequence) - after;
+                    if let (Some(pause), true) = (&sequence.max_pause_before, index > 0) {
+                        latest = latest.min(earliest + TimeDelta::milliseconds(**pause as i64));
+                    }
+                    // If the profile doesn't leave enough time, the sequence starts as early as it can.
+                    let latest = latest.max(earliest);
+                    // The start times after the earliest are whole quarters, like the prices change on whole hours.
+                    let first_step = earliest.duration_trunc(START_STEP).unwrap_or(earliest);
+                    let starts = [earliest]
+                        .into_iter()
+                        .chain((1..).map(move |step| first_step + START_STEP * step))
+                        .take_while(move |start| *start <= latest);
+                    starts.map(move |start| (sequence, start))
+                })
+                .map(|(sequence, start)| {
+                    let power = power(sequence, start);
+                    let end = start + duration(sequence);
+                    let over_limit = grid_limits
+                        .is_some_and(|(limits, session)| !limits.fits(session, &power, end));
+                    let cost = day_ahead.map(|day_ahead| {
+                        sequence
+                            .elements
+                            .iter()
+                            .zip(&power)
+                            .map(|(element, (start, power))| {
+                                day_ahead.cost(
+                                    *start,
+                                    TimeDelta::milliseconds(*element.duration as i64),
+                                    *power,
+                                )
+                            })
+                            .sum::<f64>()
+                    });
+                    Candidate {
+                        sequence,
+                        start,
+                        end,
+                        power,
+                        over_limit,
+                        cost,
+                    }
+                })
+                // The earliest start wins a tie, because it comes first.
+                .min_by(|a, b| {
+                    a.over_limit
+                        .cmp(&b.over_limit)
+                        .then(a.cost.unwrap_or(0.0).total_cmp(&b.cost.unwrap_or(0.0)))
+                });
+            let Some(Candidate {
+                sequence,
+                start,
+                end,
+                power,
+                cost,
+                ..
+            }) = best
+            else {
+                continue;
+            };
+            earliest = end;
+            scheduled.push(Scheduled {
+                instruction: ppbc::ScheduleInstruction {
+                    abnormal_condition: false,
+                    execution_time: start,
+                    id: Id::generate(),
+                    message_id: Id::generate(),
+                    power_profile_id: definition.id.clone(),
+                    power_sequence_id: sequence.id.clone(),
+                    sequence_container_id: container.id.clone(),
+                },
+                end,
+                power,
+                cost,
+            });
+        }
+        scheduled
+    }
+}
+
+/// The sequences of a container the CEM may pick, leaving out those for abnormal conditions.
+fn sequences(
+    container: &ppbc::PowerSequenceContainer,
+) -> impl Iterator<Item = &ppbc::PowerSequence> {
+    container
+        .power_sequences
+        .iter()
+        .filter(|sequence| !sequence.abnormal_condition_only)
+}
+
+fn duration(sequence: &ppbc::PowerSequence) -> TimeDelta {
+    sequence
+        .elements
+        .iter()
+        .map(|element| TimeDelta::milliseconds(*element.duration as i64))
+        .sum()
+}
+
+/// The power of a sequence that starts at `start`, from the start of every element, in W.
+fn power(sequence: &ppbc::PowerSequence, start: DateTime<Utc>) -> Vec<(DateTime<Utc>, f64)> {
+    let mut time = start;
+    sequence
+        .elements
+        .iter()
+        .map(|element| {
+            let power = peak_shaving::expected_electric_power(&element.power_values).unwrap_or(0.0);
+            let element_start = time;
+            time += TimeDelta::milliseconds(*element.duration as i64);
+            (element_start, power)
+        })
+        .collect()
+}
@@ -0,0 +1,85 @@
+use crate::api::Sessions;
+use crate::day_ahead;
+use chrono::{DateTime, NaiveDate, Utc};
+use eyre::{Context, OptionExt};
+use prices::{PriceSource, Prices};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Keeps the day-ahead prices of the site up-to-date from the price source of the options: the prices of today, and
+/// those of tomorrow once the market publishes them, which the sessions decide with.
+pub(crate) struct PriceFeed {
+    source: Arc<dyn PriceSource>,
+    sessions: Arc<Sessions>,
+    /// The prices of today and tomorrow in UTC, as far as they're published.
+    days: BTreeMap<NaiveDate, Prices>,
+}
+
+impl PriceFeed {
+    pub(crate) fn new(source: Arc<dyn PriceSource>, sessions: Arc<Sessions>) -> Self {
+        Self {
+            source,
+            sessions,
+            days: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the prices of today and tomorrow that the feed doesn't have for the whole day yet, and passes all the
+    /// prices it has on to the sessions. Returns an error if there are no prices for today.
+    pub(crate) async fn update(&mut self, now: DateTime<Utc>) -> eyre::Result<()> {
+        let today = now.date_naive();
+        let tomorrow = today.succ_opt().ok_or_eyre("Invalid date")?;
+        self.days.retain(|date, _| *date >= today);
+        let mut result = Ok(());
+        for date in [today, tomorrow] {
+            if self
+                .days
+                .get(&date)
+                .is_some_and(|prices| prices.day(date).is_some())
+            {
+                continue;
+            }
+            match self.source.day_ahead(date).await {
+                Ok(prices) => {
+                    if self.days.get(&date) != Some(&prices) {
+                        let all = || prices.iter().map(|(_, price)| price);
+                        tracing::info!(
+                            "Got the day-ahead prices of {date} until {}, from {:.4} to {:.4} per kWh",
+                            prices.end(),
+                            all().fold(f64::INFINITY, f64::min),
+                            all().fold(f64::NEG_INFINITY, f64::max)
+                        );
+                    }
+                    self.days.insert(date, prices);
+                }
+                Err(error) if date == today && !self.days.contains_key(&today) => {
+                    result = Err(error);
+                }
+                // The prices of tomorrow aren't there until the market publishes them.
+                Err(error) => tracing::debug!("No day-ahead prices of {date} yet: {error:#}"),
+            }
+        }
+        let prices = self
+            .days
+            .values()
+            .cloned()
+            .reduce(|prices, next| prices.join(&next));
+        if let Some(prices) = prices {
+            self.sessions.set_prices(prices);
+        }
+        result.wrap_err_with(|| format!("There are no day-ahead prices for {today}"))
+    }
+
+    /// Updates the prices at the start of every hour. Without prices for today, the sessions keep deciding with the
+    /// last prices there were.
+    pub(crate) async fn run(mut self) {
+        loop {
+            let now = Utc::now();
+            let next = day_ahead::next_hour(now);
+            tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+            if let Err(error) = self.update(Utc::now()).await {
+                tracing::warn!("Could not update the day-ahead prices: {error:#}");
+            }
+        }
+    }
+}
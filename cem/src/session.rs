@@ -0,0 +1,574 @@
+use crate::admission::TokenCheck;
+use crate::api::Sessions;
+use crate::imbalance;
+use crate::server::Options;
+use crate::state::SessionState;
+use crate::strategy::{CemStrategy, Rm, Site};
+use cem_connection::RmConnection;
+use chrono::{TimeDelta, Utc};
+use eyre::{bail, eyre, Context};
+use s2energy::common::{
+    ControlType, EnergyManagementRole, Handshake, HandshakeResponse, Id, Message, PowerMeasurement,
+    ReceptionStatusValues, SelectControlType, SessionRequest, SessionRequestType,
+};
+use semver::VersionReq;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+/// How long to wait for the RM at a time; the session just goes on when the RM stays quiet for longer.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the CEM leaves an RM to the instructions sent through the API, before it instructs the RM itself again.
+const MANUAL_HOLD: TimeDelta = TimeDelta::minutes(15);
+
+/// Runs the session with an RM that just connected, until either side ends it.
+pub(crate) async fn run(
+    stream: TcpStream,
+    address: SocketAddr,
+    number: usize,
+    options: &Options,
+    strategy: Arc<dyn CemStrategy>,
+    sessions: Arc<Sessions>,
+    mut stopped: watch::Receiver<bool>,
+) -> eyre::Result<()> {
+    let mut paired = false;
+    let check = TokenCheck {
+        admission: &sessions.admission,
+        paired: &mut paired,
+    };
+    let socket = tokio_tungstenite::accept_hdr_async(stream, check)
+        .await
+        .wrap_err("Could not set up a WebSocket connection")?;
+    let state = SessionState::new(address);
+    let dump_path = options.state_directory.as_ref().map(|directory| {
+        directory.join(format!(
+            "session-{}-{number}.json",
+            state.started_at.format("%Y%m%dT%H%M%S%.3fZ")
+        ))
+    });
+    let mut session = Session {
+        connection: RmConnection::new(socket),
+        state,
+        dump_path,
+        resume_path: None,
+        number,
+        sessions,
+        strategy,
+        strategy_changed: None,
+    };
+    if let Some(postgres) = &session.sessions.postgres {
+        postgres.write_session(number, &session.state);
+    }
+    let result = async {
+        if session.set_up(options, paired, &mut stopped).await? {
+            session.follow(stopped).await?;
+        }
+        Ok(())
+    }
+    .await;
+    // The server logs the error, so it's only dumped here.
+    if let Err(error) = &result {
+        session.state.end(format!("{error:#}"));
+        session.publish();
+    }
+    session.sessions.remove(number);
+    session.strategy.remove(number);
+    result
+}
+
+/// The session with one RM.
+struct Session {
+    connection: RmConnection,
+    state: SessionState,
+    /// The file the state is dumped to after every change, if any.
+    dump_path: Option<PathBuf>,
+    /// The file the CEM remembers the RM in, once it knows the resource ID of the RM, if it remembers RMs.
+    resume_path: Option<PathBuf>,
+    /// The number of the session, which identifies it to the strategy and on the monitoring API.
+    number: usize,
+    sessions: Arc<Sessions>,
+    /// What decides the instructions for the RM.
+    strategy: Arc<dyn CemStrategy>,
+    /// Notified when the strategy should decide for the RM again, once the RM is added to it, if the strategy does so.
+    strategy_changed: Option<watch::Receiver<()>>,
+}
+
+impl Session {
+    /// Performs the handshake, admits the RM and selects a control type. The RM is admitted right away if it's
+    /// `paired`, because it connected with a pairing token.
+    ///
+    /// Returns whether the session is set up, or ended while the RM was in quarantine.
+    async fn set_up(
+        &mut self,
+        options: &Options,
+        paired: bool,
+        stopped: &mut watch::Receiver<bool>,
+    ) -> eyre::Result<bool> {
+        let connection = &mut self.connection;
+        connection
+            .wait_until(options.timeout, |connection| {
+                !connection.received.is_empty()
+            })
+            .await?;
+        let handshake = match connection
+            .received
+            .first()
+            .map(|received| &received.message)
+        {
+            Some(Message::Handshake(handshake)) if handshake.role == EnergyManagementRole::Rm => {
+                handshake.clone()
+            }
+            Some(_) => bail!("The RM didn't start the session with a Handshake"),
+            None => bail!("The RM sent no Handshake within {:?}", options.timeout),
+        };
+        let version = s2energy::s2_schema_version();
+        let compatible = handshake
+            .supported_protocol_versions
+            .iter()
+            .any(|supported| {
+                VersionReq::parse(supported).is_ok_and(|requirement| requirement.matches(&version))
+            });
+        if !compatible {
+            let reason = format!(
+                "The RM supports S2 versions {}, but this CEM speaks {version}",
+                handshake.supported_protocol_versions.join(", ")
+            );
+            self.terminate(&reason).await?;
+            bail!(reason);
+        }
+        let connection = &mut self.connection;
+        connection
+            .send(Handshake::new(
+                EnergyManagementRole::Cem,
+                vec![version.to_string()],
+            ))
+            .await?;
+        connection
+            .send(HandshakeResponse::new(version.to_string()))
+            .await?;
+        self.state.protocol_version = Some(version.to_string());
+
+        let rm_details = |connection: &RmConnection| {
+            connection
+                .received
+                .iter()
+                .find_map(|received| match &received.message {
+                    Message::ResourceManagerDetails(details) => Some(details.clone()),
+                    _ => None,
+                })
+        };
+        connection
+            .wait_until(options.timeout, |connection| {
+                rm_details(connection).is_some()
+            })
+            .await?;
+        let Some(rm_details) = rm_details(connection) else {
+            bail!(
+                "The RM sent no ResourceManagerDetails within {:?}",
+                options.timeout
+            );
+        };
+        if !paired && !self.admit(&rm_details.resource_id, stopped).await? {
+            return Ok(false);
+        }
+        let earlier = self.recall(&rm_details.resource_id, options);
+        let available = &rm_details.available_control_types;
+        let control_type = match options.control_type {
+            Some(control_type) if available.contains(&control_type) => control_type,
+            Some(control_type) => {
+                let reason = format!("The RM doesn't offer {control_type:?}, only {available:?}");
+                self.terminate(&reason).await?;
+                bail!(reason);
+            }
+            // An RM that connects again gets the control type it had.
+            None => earlier
+                .as_ref()
+                .and_then(|earlier| earlier.control_type)
+                .filter(|control_type| available.contains(control_type))
+                .or_else(|| {
+                    available
+                        .iter()
+                        .find(|control_type| **control_type != ControlType::NoSelection)
+                        .copied()
+                })
+                .ok_or_else(|| eyre!("The RM offers no control type"))?,
+        };
+        self.connection
+            .send(SelectControlType::new(control_type))
+            .await?;
+        self.state.control_type = Some(control_type);
+        self.strategy_changed = self.strategy.add(&self.rm());
+        tracing::info!(
+            "Set up a session with the RM at {} ({}), with control type {control_type:?} and S2 version {version}",
+            self.state.rm_address,
+            rm_details.name.as_deref().unwrap_or("no name"),
+        );
+        if let Some(earlier) = earlier.filter(|earlier| earlier.control_type == Some(control_type))
+        {
+            tracing::info!(
+                "Resuming the session with the RM at {} that started at {}",
+                self.state.rm_address,
+                earlier.started_at
+            );
+            self.state.resume(earlier);
+            let messages: Vec<Message> = self.state.latest_messages.values().cloned().collect();
+            for message in &messages {
+                self.strategy.receive(&self.rm(), message);
+            }
+        }
+        self.process();
+        Ok(true)
+    }
+
+    /// Turns the RM away if the CEM doesn't admit it, or keeps it in quarantine until it's admitted through the API.
+    ///
+    /// Returns whether the RM is admitted, or the session ended while it was in quarantine.
+    async fn admit(
+        &mut self,
+        resource_id: &str,
+        stopped: &mut watch::Receiver<bool>,
+    ) -> eyre::Result<bool> {
+        let sessions = self.sessions.clone();
+        let admission = &sessions.admission;
+        if admission.allows(resource_id) {
+            return Ok(true);
+        }
+        if !admission.quarantine {
+            let reason =
+                format!("The RM with resource ID {resource_id} isn't paired with this CEM");
+            self.terminate(&reason).await?;
+            bail!(reason);
+        }
+        let Some(mut allowed) = admission.subscribe() else {
+            return Ok(true);
+        };
+        tracing::warn!(
+            "The RM at {} with resource ID {resource_id} isn't paired with this CEM, so it's in quarantine until it's \
+            admitted through the API",
+            self.state.rm_address
+        );
+        self.state.quarantined = true;
+        self.publish();
+        while !admission.allows(resource_id) {
+            if let Some(reason) = self.process() {
+                self.connection.close().await;
+                self.end(reason);
+                return Ok(false);
+            }
+            if self.connection.closed {
+                self.end("The RM closed the connection".into());
+                return Ok(false);
+            }
+            tokio::select! {
+                received = self.connection.wait_until(IDLE_TIMEOUT, has_news) => {
+                    received?;
+                }
+                _ = allowed.changed() => {}
+                _ = stopped.changed() => {
+                    let reason = "The CEM is shutting down";
+                    self.terminate(reason).await?;
+                    self.end(reason.into());
+                    return Ok(false);
+                }
+            }
+        }
+        tracing::info!(
+            "Admitted the RM at {} with resource ID {resource_id}",
+            self.state.rm_address
+        );
+        self.state.quarantined = false;
+        Ok(true)
+    }
+
+    /// Reads what the CEM remembers about the RM from its earlier session, if it remembers RMs, and remembers this
+    /// session in the same file from now on.
+    fn recall(&mut self, resource_id: &str, options: &Options) -> Option<SessionState> {
+        let directory = options.resume_directory.as_ref()?;
+        let file_name: String = resource_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = directory.join(format!("rm-{file_name}.json"));
+        self.resume_path = Some(path.clone());
+        let earlier = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(eyre::Report::from),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(error) => Err(error.into()),
+        };
+        // The RM can start over, so the session goes on.
+        earlier
+            .inspect_err(|error| {
+                tracing::warn!(
+                    "Could not read what the CEM remembers about the RM from {}: {error}",
+                    path.display()
+                )
+            })
+            .ok()
+    }
+
+    /// Keeps track of what the RM sends and instructs it as the strategy decides, until the RM ends the session or the
+    /// CEM is stopped.
+    async fn follow(&mut self, mut stopped: watch::Receiver<bool>) -> eyre::Result<()> {
+        let mut strategy_changed = self.strategy_changed.take();
+        let mut manual = self.sessions.add(self.number);
+        loop {
+            if let Some(reason) = self.process() {
+                self.connection.close().await;
+                self.end(reason);
+                return Ok(());
+            }
+            if self.connection.closed {
+                self.end("The RM closed the connection".into());
+                return Ok(());
+            }
+            let held_for = self
+                .state
+                .manual_until
+                .and_then(|until| (until - Utc::now()).to_std().ok());
+            if held_for.is_none() {
+                self.state.manual_until = None;
+                self.decide().await?;
+            }
+            let decide_in = self
+                .strategy
+                .next_decision(&self.rm(), Utc::now())
+                .map(|at| (at - Utc::now()).to_std().unwrap_or_default());
+            tokio::select! {
+                received = self.connection.wait_until(IDLE_TIMEOUT, has_news) => {
+                    received?;
+                }
+                // The strategy decides again at the start of the next iteration.
+                _ = notified(&mut strategy_changed) => {}
+                _ = tokio::time::sleep(decide_in.unwrap_or_default()), if decide_in.is_some() => {}
+                _ = tokio::time::sleep(held_for.unwrap_or_default()), if held_for.is_some() => {}
+                Some(instruction) = manual.recv() => {
+                    tracing::info!(
+                        "Sending the RM at {} an instruction by hand, and leaving it to the instructions by hand for {} minutes",
+                        self.state.rm_address,
+                        MANUAL_HOLD.num_minutes()
+                    );
+                    self.instruct(instruction).await?;
+                    self.state.manual_until = Some(Utc::now() + MANUAL_HOLD);
+                    self.publish();
+                }
+                _ = stopped.changed() => {
+                    let reason = "The CEM is shutting down";
+                    self.terminate(reason).await?;
+                    self.end(reason.into());
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Moves what the RM sent into the state, and dumps it if anything changed.
+    ///
+    /// Returns why the session ended, if the RM asked to end it.
+    fn process(&mut self) -> Option<String> {
+        if !has_news(&self.connection) {
+            return None;
+        }
+        let mut end_reason = None;
+        let mut forecast_changed = false;
+        let rm = self.influx_rm();
+        for received in std::mem::take(&mut self.connection.received) {
+            forecast_changed |= matches!(
+                received.message,
+                Message::PowerForecast(_) | Message::FrbcUsageForecast(_)
+            );
+            if let Message::SessionRequest(request) = &received.message {
+                let label = request
+                    .diagnostic_label
+                    .as_deref()
+                    .unwrap_or("no reason given");
+                end_reason = Some(match request.request {
+                    SessionRequestType::Terminate => {
+                        format!("The RM terminated the session ({label})")
+                    }
+                    SessionRequestType::Reconnect => format!("The RM asked to reconnect ({label})"),
+                });
+            }
+            self.strategy.receive(&self.rm(), &received.message);
+            if let Some(influx) = &self.sessions.influx {
+                influx.write_received(&rm, &received.message);
+            }
+            if let Some(postgres) = &self.sessions.postgres {
+                postgres.write_received(self.number, &received.message);
+            }
+            if let Message::PowerMeasurement(measurement) = &received.message {
+                self.track_imbalance(measurement);
+            }
+            self.state.record(received.message_type, received.message);
+        }
+        for (message_id, status) in self.connection.reception_statuses.drain() {
+            if status != ReceptionStatusValues::Ok {
+                tracing::warn!(
+                    "The RM at {} answered {status:?} to message {message_id:?}",
+                    self.state.rm_address
+                );
+                self.state.rejected_messages += 1;
+            }
+        }
+        for invalid in self.connection.invalid.drain(..) {
+            tracing::warn!("The RM at {} sent {invalid}", self.state.rm_address);
+            self.state.invalid_messages += 1;
+        }
+        self.publish();
+        if forecast_changed && !self.state.quarantined {
+            if let Some(summary) = self.sessions.forecast().summary() {
+                tracing::info!(
+                    "Got a new forecast from the RM at {}: {summary}",
+                    self.state.rm_address
+                );
+            }
+        }
+        end_reason
+    }
+
+    /// Compares a power measurement with the instructions in effect, and logs when the RM starts or stops deviating
+    /// from them.
+    fn track_imbalance(&mut self, measurement: &PowerMeasurement) {
+        let Some(deviating) = imbalance::track(&mut self.state, measurement) else {
+            return;
+        };
+        let Some(imbalance) = &self.state.imbalance else {
+            return;
+        };
+        if deviating {
+            tracing::warn!(
+                "The RM at {} measured {:.0} W, but the instructions in effect imply {}",
+                self.state.rm_address,
+                imbalance.measured_power,
+                imbalance.expected()
+            );
+        } else {
+            tracing::info!(
+                "The RM at {} measured {:.0} W, in line with the instructions in effect again",
+                self.state.rm_address,
+                imbalance.measured_power
+            );
+        }
+    }
+
+    /// Asks the strategy what to send the RM now, and sends it.
+    async fn decide(&mut self) -> eyre::Result<()> {
+        let site = Site::new(&self.sessions);
+        let decision = self.strategy.decide(&self.rm(), &site);
+        if decision.instructions.is_empty() && decision.planned_power.is_none() {
+            return Ok(());
+        }
+        for instruction in decision.instructions {
+            self.instruct(instruction).await?;
+        }
+        if let Some(planned_power) = decision.planned_power {
+            self.state.planned_power = planned_power;
+        }
+        self.publish();
+        Ok(())
+    }
+
+    /// The RM, as the strategy sees it.
+    fn rm(&self) -> Rm<'_> {
+        Rm {
+            session: self.number,
+            state: &self.state,
+        }
+    }
+
+    /// Sends the RM an instruction, and keeps track of it in the state.
+    async fn instruct(&mut self, instruction: impl Into<Message>) -> eyre::Result<()> {
+        let instruction = instruction.into();
+        self.connection.send(instruction.clone()).await?;
+        if let Some(influx) = &self.sessions.influx {
+            influx.write_instruction(&self.influx_rm(), &instruction);
+        }
+        if let Some(postgres) = &self.sessions.postgres {
+            postgres.write_instruction(self.number, &instruction);
+        }
+        self.state.instructed(instruction);
+        Ok(())
+    }
+
+    /// How the RM is told apart in InfluxDB: by its resource ID, or by its address until it sent its details.
+    fn influx_rm(&self) -> String {
+        match &self.state.rm_details {
+            Some(details) => details.resource_id.to_string(),
+            None => self.state.rm_address.to_string(),
+        }
+    }
+
+    /// Asks the RM to terminate the session, and closes the connection.
+    async fn terminate(&mut self, reason: &str) -> eyre::Result<()> {
+        if !self.connection.closed {
+            self.connection
+                .send(SessionRequest {
+                    diagnostic_label: Some(reason.into()),
+                    message_id: Id::generate(),
+                    request: SessionRequestType::Terminate,
+                })
+                .await?;
+            self.connection.close().await;
+        }
+        Ok(())
+    }
+
+    /// Marks the session as ended, and dumps its final state.
+    fn end(&mut self, reason: String) {
+        tracing::info!(
+            "Session with the RM at {} ended: {reason}",
+            self.state.rm_address
+        );
+        self.state.end(reason);
+        self.publish();
+    }
+
+    /// Shows the state on the monitoring API, and writes it to the dump file, the file the RM is remembered in and
+    /// PostgreSQL, if any. The files are replaced at once, so readers never see half a state.
+    fn publish(&self) {
+        self.sessions.update(self.number, &self.state);
+        if let Some(postgres) = &self.sessions.postgres {
+            postgres.write_session(self.number, &self.state);
+        }
+        for path in [&self.dump_path, &self.resume_path].into_iter().flatten() {
+            let temporary = path.with_extension("json.tmp");
+            let written = serde_json::to_vec_pretty(&self.state)
+                .map_err(eyre::Report::from)
+                .and_then(|json| Ok(std::fs::write(&temporary, json)?))
+                .and_then(|()| Ok(std::fs::rename(&temporary, path)?));
+            // The session matters more than its files, so it goes on.
+            if let Err(error) = written {
+                tracing::warn!(
+                    "Could not write the session state to {}: {error}",
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+/// Waits until `receiver` is notified; forever if there's no receiver.
+async fn notified(receiver: &mut Option<watch::Receiver<()>>) {
+    match receiver {
+        Some(receiver) => {
+            // The sender lives as long as the CEM, so this can't fail.
+            let _ = receiver.changed().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether the RM sent something that [`Session::process`] should look at.
+fn has_news(connection: &RmConnection) -> bool {
+    !connection.received.is_empty()
+        || !connection.reception_statuses.is_empty()
+        || !connection.invalid.is_empty()
+}
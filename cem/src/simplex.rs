@@ -0,0 +1,287 @@
+/// How close to zero a number has to be to count as zero.
+const EPSILON: f64 = 1e-9;
+/// How many pivots the solver makes before it gives up; Bland's rule can't cycle, but it can take long.
+const MAX_PIVOTS: usize = 100_000;
+
+/// How the left-hand side of a [`Constraint`] relates to its bound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Relation {
+    AtMost,
+    AtLeast,
+    Equal,
+}
+
+/// A linear constraint: the sum of every coefficient times its variable relates to `bound` as `relation` says.
+#[derive(Debug, Clone)]
+pub(crate) struct Constraint {
+    pub(crate) coefficients: Vec<f64>,
+    pub(crate) relation: Relation,
+    pub(crate) bound: f64,
+}
+
+/// Finds the non-negative values of the variables that minimize the sum of every coefficient of `objective` times its
+/// variable, under `constraints`, with the two-phase simplex method. `None` if no values meet the constraints, or the
+/// objective has no minimum.
+///
+/// This is a plain dense implementation with Bland's rule, which is fine for the few hundred variables of a day-ahead
+/// plan.
+pub(crate) fn minimize(objective: &[f64], constraints: &[Constraint]) -> Option<Vec<f64>> {
+    let variables = objective.len();
+    // Every bound is made non-negative, so the slack and artificial variables start out feasible.
+    let constraints: Vec<Constraint> = constraints
+        .iter()
+        .map(|constraint| {
+            if constraint.bound >= 0.0 {
+                return constraint.clone();
+            }
+            Constraint {
+                coefficients: constraint.coefficients.iter().map(|value| -value).collect(),
+                relation: match constraint.relation {
+                    Relation::AtMost => Relation::AtLeast,
+                    Relation::AtLeast => Relation::AtMost,
+                    Relation::Equal => Relation::Equal,
+                },
+                bound: -constraint.bound,
+            }
+        })
+        .collect();
+    let slacks = constraints
+        .iter()
+        .filter(|constraint| constraint.relation != Relation::Equal)
+        .count();
+    let artificials = constraints
+        .iter()
+        .filter(|constraint| constraint.relation != Relation::AtMost)
+        .count();
+    let columns = variables + slacks + artificials;
+
+    let mut tableau = Tableau {
+        rows: Vec::with_capacity(constraints.len()),
+        basis: Vec::with_capacity(constraints.len()),
+        costs: vec![0.0; columns + 1],
+    };
+    let (mut slack, mut artificial) = (variables, variables + slacks);
+    for constraint in &constraints {
+        let mut row = vec![0.0; columns + 1];
+        row[..variables].copy_from_slice(&constraint.coefficients[..variables]);
+        row[columns] = constraint.bound;
+        match constraint.relation {
+            Relation::AtMost => {
+                row[slack] = 1.0;
+                tableau.basis.push(slack);
+                slack += 1;
+            }
+            Relation::AtLeast => {
+                row[slack] = -1.0;
+                row[artificial] = 1.0;
+                tableau.basis.push(artificial);
+                slack += 1;
+                artificial += 1;
+            }
+            Relation::Equal => {
+                row[artificial] = 1.0;
+                tableau.basis.push(artificial);
+                artificial += 1;
+            }
+        }
+        tableau.rows.push(row);
+    }
+
+    // Phase 1 minimizes the sum of the artificial variables, to find values that meet the constraints.
+    let mut phase_one = vec![0.0; columns];
+    phase_one[variables + slacks..].fill(1.0);
+    tableau.set_objective(&phase_one);
+    tableau.solve(columns)?;
+    let scale = constraints
+        .iter()
+        .map(|constraint| constraint.bound)
+        .sum::<f64>()
+        .max(1.0);
+    if -tableau.costs[columns] > EPSILON.sqrt() * scale {
+        return None;
+    }
+    // The artificial variables that are still in the basis are zero, and leave it where another variable can
+    // take their place; the rows where none can are redundant.
+    for row in 0..tableau.rows.len() {
+        if tableau.basis[row] >= variables + slacks {
+            let column =
+                (0..variables + slacks).find(|column| tableau.rows[row][*column].abs() > EPSILON);
+            if let Some(column) = column {
+                tableau.pivot(row, column);
+            }
+        }
+    }
+
+    // Phase 2 minimizes the objective, without letting the artificial variables back in.
+    let mut phase_two = vec![0.0; columns];
+    phase_two[..variables].copy_from_slice(objective);
+    tableau.set_objective(&phase_two);
+    tableau.solve(variables + slacks)?;
+
+    let mut values = vec![0.0; variables];
+    for (row, basic) in tableau.basis.iter().enumerate() {
+        if *basic < variables {
+            values[*basic] = tableau.rows[row][columns];
+        }
+    }
+    Some(values)
+}
+
+struct Tableau {
+    /// The constraints, with the value of the basic variable of every row in the last column.
+    rows: Vec<Vec<f64>>,
+    /// The basic variable of every row.
+    basis: Vec<usize>,
+    /// The reduced cost of every column, with minus the value of the objective in the last column.
+    costs: Vec<f64>,
+}
+
+impl Tableau {
+    /// Replaces the objective, expressed in the variables that aren't in the basis.
+    fn set_objective(&mut self, objective: &[f64]) {
+        self.costs[..objective.len()].copy_from_slice(objective);
+        *self.costs.last_mut().unwrap() = 0.0;
+        for (row, basic) in self.rows.iter().zip(&self.basis) {
+            let cost = objective[*basic];
+            if cost != 0.0 {
+                for (total, value) in self.costs.iter_mut().zip(row) {
+                    *total -= cost * value;
+                }
+            }
+        }
+    }
+
+    /// Pivots until no column before `columns` can lower the objective. `None` if the objective has no minimum, or it
+    /// takes too long.
+    fn solve(&mut self, columns: usize) -> Option<()> {
+        for _ in 0..MAX_PIVOTS {
+            let Some(column) = (0..columns).find(|column| self.costs[*column] < -EPSILON) else {
+                return Some(());
+            };
+            let last = self.costs.len() - 1;
+            let row = (0..self.rows.len())
+                .filter(|row| self.rows[*row][column] > EPSILON)
+                .min_by(|a, b| {
+                    let ratio = |row: usize| self.rows[row][last] / self.rows[row][column];
+                    ratio(*a)
+                        .total_cmp(&ratio(*b))
+                        .then(self.basis[*a].cmp(&self.basis[*b]))
+                })?;
+            self.pivot(row, column);
+        }
+        None
+    }
+
+    /// Makes the variable of `column` the basic variable of `row`.
+    fn pivot(&mut self, row: usize, column: usize) {
+        let pivot = self.rows[row][column];
+        for value in &mut self.rows[row] {
+            *value /= pivot;
+        }
+        let pivot_row = self.rows[row].clone();
+        let eliminate = |target: &mut Vec<f64>| {
+            let factor = target[column];
+            if factor != 0.0 {
+                for (value, pivot_value) in target.iter_mut().zip(&pivot_row) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        };
+        for (other, target) in self.rows.iter_mut().enumerate() {
+            if other != row {
+                eliminate(target);
+            }
+        }
+        eliminate(&mut self.costs);
+        self.basis[row] = column;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constraint(coefficients: &[f64], relation: Relation, bound: f64) -> Constraint {
+        Constraint {
+            coefficients: coefficients.to_vec(),
+            relation,
+            bound,
+        }
+    }
+
+    fn assert_values(values: Option<Vec<f64>>, expected: &[f64]) {
+        let values = values.expect("the linear program should have a minimum");
+        assert!(
+            values
+                .iter()
+                .zip(expected)
+                .all(|(value, expected)| (value - expected).abs() < 1e-6),
+            "expected {expected:?}, but got {values:?}"
+        );
+    }
+
+    #[test]
+    fn finds_the_optimum_under_upper_bounds() {
+        // Maximize 3x + 5y, the example of Hillier and Lieberman.
+        let constraints = [
+            constraint(&[1.0, 0.0], Relation::AtMost, 4.0),
+            constraint(&[0.0, 2.0], Relation::AtMost, 12.0),
+            constraint(&[3.0, 2.0], Relation::AtMost, 18.0),
+        ];
+        assert_values(minimize(&[-3.0, -5.0], &constraints), &[2.0, 6.0]);
+    }
+
+    #[test]
+    fn finds_the_optimum_under_lower_bounds_and_equalities() {
+        let constraints = [
+            constraint(&[1.0, 2.0], Relation::AtLeast, 4.0),
+            constraint(&[1.0, -1.0], Relation::Equal, 1.0),
+        ];
+        assert_values(minimize(&[1.0, 1.0], &constraints), &[2.0, 1.0]);
+    }
+
+    #[test]
+    fn turns_negative_bounds_around() {
+        // -x <= -2 is x >= 2.
+        let constraints = [constraint(&[-1.0], Relation::AtMost, -2.0)];
+        assert_values(minimize(&[1.0], &constraints), &[2.0]);
+    }
+
+    #[test]
+    fn leaves_out_redundant_equalities() {
+        let constraints = [
+            constraint(&[1.0, 1.0], Relation::Equal, 2.0),
+            constraint(&[2.0, 2.0], Relation::Equal, 4.0),
+        ];
+        assert_values(minimize(&[1.0, 0.0], &constraints), &[0.0, 2.0]);
+    }
+
+    #[test]
+    fn finds_nothing_when_the_constraints_contradict() {
+        let constraints = [
+            constraint(&[1.0, 1.0], Relation::AtMost, 1.0),
+            constraint(&[1.0, 1.0], Relation::AtLeast, 2.0),
+        ];
+        assert_eq!(minimize(&[1.0, 1.0], &constraints), None);
+    }
+
+    #[test]
+    fn finds_nothing_when_the_objective_has_no_minimum() {
+        let constraints = [constraint(&[1.0, -1.0], Relation::AtMost, 1.0)];
+        assert_eq!(minimize(&[-1.0, 0.0], &constraints), None);
+    }
+
+    #[test]
+    fn does_not_cycle_on_a_degenerate_program() {
+        // Beale's example, on which the simplex method cycles without an anti-cycling rule such as Bland's.
+        let constraints = [
+            constraint(&[0.25, -8.0, -1.0, 9.0], Relation::AtMost, 0.0),
+            constraint(&[0.5, -12.0, -0.5, 3.0], Relation::AtMost, 0.0),
+            constraint(&[0.0, 0.0, 1.0, 0.0], Relation::AtMost, 1.0),
+        ];
+        assert_values(
+            minimize(&[-0.75, 20.0, -0.5, 6.0], &constraints),
+            &[1.0, 0.0, 1.0, 0.0],
+        );
+    }
+}
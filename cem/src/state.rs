@@ -0,0 +1,147 @@
+use chrono::{DateTime, Utc};
+use s2energy::common::{ControlType, Message, ResourceManagerDetails};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// What the CEM knows about the session with one RM, as dumped to the state directory and remembered in the resume
+/// directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionState {
+    /// The address the RM connected from.
+    pub rm_address: SocketAddr,
+    pub started_at: DateTime<Utc>,
+    /// When the earlier session with the RM started that this session picked up from, if any.
+    pub resumed_from: Option<DateTime<Utc>>,
+    /// When the session ended; `None` while it's going on.
+    pub ended_at: Option<DateTime<Utc>>,
+    /// Why the session ended, such as the RM closing the connection.
+    pub end_reason: Option<String>,
+    /// The S2 version agreed on in the handshake.
+    pub protocol_version: Option<String>,
+    /// The details the RM sent most recently.
+    pub rm_details: Option<ResourceManagerDetails>,
+    /// The control type the CEM selected.
+    pub control_type: Option<ControlType>,
+    /// Whether the RM isn't paired with the CEM and waits in quarantine to be admitted.
+    pub quarantined: bool,
+    /// The number of valid messages the RM sent, besides reception statuses.
+    pub messages_received: usize,
+    /// The number of messages the RM sent that aren't valid S2.
+    pub invalid_messages: usize,
+    /// The number of messages from the CEM that the RM acknowledged with a status other than OK.
+    pub rejected_messages: usize,
+    /// The number of instructions the CEM sent.
+    pub instructions_sent: usize,
+    /// The instructions the CEM sent that are still in effect, oldest first.
+    pub active_instructions: Vec<Message>,
+    /// Until when the CEM leaves the RM to the instructions sent by hand through the API, instead of instructing it
+    /// itself.
+    pub manual_until: Option<DateTime<Utc>>,
+    /// What the CEM plans the RM to do with its power for the next 24 hours, if it follows prices.
+    pub planned_power: Vec<PlannedPower>,
+    /// How the latest power measurement of the RM compares with the power the instructions in effect imply, once
+    /// the RM had time to follow them; `None` until there's such a measurement.
+    pub imbalance: Option<Imbalance>,
+    /// The latest message of every type the RM sent, by message type, such as `FRBC.StorageStatus`.
+    pub latest_messages: BTreeMap<String, Message>,
+}
+
+/// The power the CEM plans an RM to have from a point in time on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedPower {
+    pub start: DateTime<Utc>,
+    /// In W: positive to consume and negative to produce.
+    pub power: f64,
+}
+
+/// How far the electric power an RM measures is from the power the instructions in effect imply, in W: positive to
+/// consume and negative to produce.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Imbalance {
+    pub measured_at: DateTime<Utc>,
+    pub measured_power: f64,
+    /// The lowest and highest power the instructions imply: the same for an operation mode, but the limits of the
+    /// envelope for a PEBC instruction.
+    pub expected_lowest: f64,
+    pub expected_highest: f64,
+    /// How far the measured power is above the highest, or below the lowest, expected power.
+    pub deviation: f64,
+    /// Whether the deviation is more than the CEM tolerates: 5% of the expected power, but at least 100 W.
+    pub deviating: bool,
+    /// The mean of the absolute deviations of all measurements compared so far.
+    pub mean_absolute_deviation: f64,
+    /// How many measurements were compared so far.
+    pub measurements: u64,
+}
+
+impl SessionState {
+    pub(crate) fn new(rm_address: SocketAddr) -> Self {
+        Self {
+            rm_address,
+            started_at: Utc::now(),
+            resumed_from: None,
+            ended_at: None,
+            end_reason: None,
+            protocol_version: None,
+            rm_details: None,
+            control_type: None,
+            quarantined: false,
+            messages_received: 0,
+            invalid_messages: 0,
+            rejected_messages: 0,
+            instructions_sent: 0,
+            active_instructions: Vec::new(),
+            manual_until: None,
+            planned_power: Vec::new(),
+            imbalance: None,
+            latest_messages: BTreeMap::new(),
+        }
+    }
+
+    /// Keeps track of a valid message the RM sent.
+    pub(crate) fn record(&mut self, message_type: String, message: Message) {
+        self.messages_received += 1;
+        if let Message::ResourceManagerDetails(details) = &message {
+            self.rm_details = Some(details.clone());
+        }
+        self.latest_messages.insert(message_type, message);
+    }
+
+    /// Keeps track of an instruction the CEM sent.
+    ///
+    /// It replaces the instructions of the same type the CEM sent before, except that a PPBC schedule instruction only
+    /// replaces the one for the same sequence container.
+    pub(crate) fn instructed(&mut self, instruction: Message) {
+        self.instructions_sent += 1;
+        self.active_instructions
+            .retain(|active| match (active, &instruction) {
+                (
+                    Message::PpbcScheduleInstruction(active),
+                    Message::PpbcScheduleInstruction(new),
+                ) => active.sequence_container_id != new.sequence_container_id,
+                _ => std::mem::discriminant(active) != std::mem::discriminant(&instruction),
+            });
+        self.active_instructions.push(instruction);
+    }
+
+    /// Picks up what the CEM knew about the RM in an earlier session: the latest message of every type it sent, such as
+    /// its system description, the instructions in effect and the plan. The messages the RM sent in this session win.
+    pub(crate) fn resume(&mut self, earlier: SessionState) {
+        self.resumed_from = Some(earlier.started_at);
+        for (message_type, message) in earlier.latest_messages {
+            self.latest_messages.entry(message_type).or_insert(message);
+        }
+        self.active_instructions = earlier.active_instructions;
+        self.manual_until = earlier.manual_until.filter(|until| *until > Utc::now());
+        self.planned_power = earlier.planned_power;
+    }
+
+    /// Marks the session as ended, unless it already did.
+    pub(crate) fn end(&mut self, reason: String) {
+        if self.ended_at.is_none() {
+            self.ended_at = Some(Utc::now());
+            self.end_reason = Some(reason);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::api::Sessions;
+use crate::forecast::SiteForecast;
+use crate::state::{PlannedPower, SessionState};
+use chrono::{DateTime, Utc};
+use prices::Prices;
+use s2energy::common::Message;
+use std::collections::BTreeMap;
+use tokio::sync::watch;
+
+/// How the CEM decides what to instruct the RMs, so you can try an optimization of your own without touching how the
+/// CEM talks to the RMs: set it as [`Options::strategy`](crate::Options::strategy) and [`run`](crate::run) the CEM.
+///
+/// The session with every RM takes the strategy along: it passes on what the RM sends, and asks the strategy what to
+/// send the RM whenever the RM sent something, the strategy notified it, or the time the strategy asked for came. The
+/// session sends the RM the instructions the strategy decides on, and keeps track of them in its [`SessionState`].
+/// While an RM is in quarantine, or left to instructions sent by hand, the strategy doesn't decide for it.
+///
+/// The strategy is shared by all sessions, so it keeps what it needs to know about every RM by session number. The
+/// bundled strategies, which follow the power limit, self-consumption and prices of the options, work the same way.
+///
+/// ```no_run
+/// use cem::{CemStrategy, Decision, Rm, Site};
+/// use s2energy::common::{Id, Message};
+/// use s2energy::ombc;
+///
+/// /// Runs every OMBC device in its first operation mode, at full power.
+/// struct FirstMode;
+///
+/// impl CemStrategy for FirstMode {
+///     fn decide(&self, rm: &Rm<'_>, _site: &Site<'_>) -> Decision {
+///         let Some(Message::OmbcSystemDescription(description)) =
+///             rm.state.latest_messages.get("OMBC.SystemDescription")
+///         else {
+///             return Decision::default();
+///         };
+///         let Some(mode) = description.operation_modes.first() else {
+///             return Decision::default();
+///         };
+///         // Sending the same instruction again is up to the strategy, so this one checks what's in effect.
+///         if rm.state.active_instructions.iter().any(|active| {
+///             matches!(active, Message::OmbcInstruction(active) if active.operation_mode_id == mode.id)
+///         }) {
+///             return Decision::default();
+///         }
+///         let instruction = ombc::Instruction::new(false, chrono::Utc::now(), Id::generate(), 1.0, mode.id.clone());
+///         Decision::instruct(instruction.into())
+///     }
+/// }
+/// ```
+pub trait CemStrategy: Send + Sync {
+    /// Starts to take the RM of a session into account, once its control type is selected.
+    ///
+    /// Returns a receiver to notify when the strategy should decide for the RM again, because something changed
+    /// elsewhere on the site, if it should.
+    fn add(&self, rm: &Rm<'_>) -> Option<watch::Receiver<()>> {
+        let _ = rm;
+        None
+    }
+
+    /// Takes a message the RM sent into account, before the session keeps track of it in its state. This is also called
+    /// for the messages an RM sends before its control type is selected, and while it's in quarantine.
+    fn receive(&self, rm: &Rm<'_>, message: &Message) {
+        let _ = (rm, message);
+    }
+
+    /// Decides what to send the RM now.
+    fn decide(&self, rm: &Rm<'_>, site: &Site<'_>) -> Decision;
+
+    /// When to decide for the RM again even if nothing changes, if ever, such as when the price changes.
+    fn next_decision(&self, rm: &Rm<'_>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let _ = (rm, now);
+        None
+    }
+
+    /// Forgets about the RM of a session that ended.
+    fn remove(&self, session: usize) {
+        let _ = session;
+    }
+}
+
+/// The RM a strategy decides for.
+pub struct Rm<'a> {
+    /// The number of the session with the RM, which identifies it on the monitoring API.
+    pub session: usize,
+    /// What the CEM knows about the RM: its details, control type, latest message of every type, such as its system
+    /// description, measurements and forecasts, and the instructions in effect.
+    pub state: &'a SessionState,
+}
+
+/// The whole site, as a strategy sees it while it decides for an RM.
+pub struct Site<'a> {
+    sessions: &'a Sessions,
+    /// The day-ahead prices published so far, per kWh, if the CEM has prices. They come from the price source of the
+    /// options, and grow as the market publishes the prices of the next day.
+    pub prices: Option<Prices>,
+    pub now: DateTime<Utc>,
+}
+
+impl<'a> Site<'a> {
+    pub(crate) fn new(sessions: &'a Sessions) -> Self {
+        Self {
+            sessions,
+            prices: sessions.prices(),
+            now: Utc::now(),
+        }
+    }
+
+    /// What the CEM knows about every RM that's connected, by session number, as of the latest message each sent.
+    pub fn states(&self) -> BTreeMap<usize, SessionState> {
+        self.sessions.states()
+    }
+
+    /// What the whole site expects for the next 24 hours, from the latest forecasts of the RMs.
+    pub fn forecast(&self) -> SiteForecast {
+        self.sessions.forecast()
+    }
+
+    /// The price of energy per kWh at `time`, if the CEM has prices. Past the prices published so far, that's the price
+    /// at the same time on the last day there is one.
+    pub fn price(&self, time: DateTime<Utc>) -> Option<f64> {
+        self.prices
+            .as_ref()
+            .map(|prices| prices.repeated_price_at(time))
+    }
+}
+
+/// What a strategy decided to send an RM.
+#[derive(Debug, Default)]
+pub struct Decision {
+    /// The instructions to send the RM, in this order.
+    pub instructions: Vec<Message>,
+    /// The power the strategy plans the RM to use from now on, if it made a new plan, which the monitoring API and the
+    /// dashboard show.
+    pub planned_power: Option<Vec<PlannedPower>>,
+}
+
+impl Decision {
+    /// A decision to send the RM one instruction.
+    pub fn instruct(instruction: Message) -> Self {
+        Self {
+            instructions: vec![instruction],
+            planned_power: None,
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use s2energy::common::{CommodityQuantity, PowerValue};
+
+/// The prices of the commodities besides electricity, so the CEM can weigh them against electricity for the devices
+/// that can use either, like a hybrid heat pump that heats with electricity or with gas.
+///
+/// The quantities are in the units of S2: natural gas flows in l/s, and thermal power, such as from a district heating
+/// network, is in W. Like electric power, they're positive for what a device uses and negative for what it produces.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Tariffs {
+    /// The price of natural gas per m³.
+    pub(crate) gas: Option<f64>,
+    /// The price of heat per kWh.
+    pub(crate) heat: Option<f64>,
+}
+
+impl Tariffs {
+    /// What the gas and heat in `values` cost per second, at the prices that are set.
+    pub(crate) fn cost_rate(&self, values: &[PowerValue]) -> f64 {
+        values
+            .iter()
+            .map(|value| match value.commodity_quantity {
+                CommodityQuantity::NaturalGasFlowRate => {
+                    self.gas.map_or(0.0, |price| price * value.value / 1000.0)
+                }
+                CommodityQuantity::HeatThermalPower => self
+                    .heat
+                    .map_or(0.0, |price| price * value.value / 1000.0 / 3600.0),
+                _ => 0.0,
+            })
+            .sum()
+    }
+}
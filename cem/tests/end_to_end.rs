@@ -0,0 +1,320 @@
+use prices::StaticCurve;
+use serde_json::Value;
+use simulator_common::Settings;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// How long a session may take to get through the exchange a test waits for.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(20);
+
+struct TestSettings(HashMap<&'static str, String>);
+
+impl Settings for TestSettings {
+    fn get(&self, name: &str) -> Option<String> {
+        self.0.get(name).cloned()
+    }
+}
+
+/// A message in the recording of the simulator, as it went over the WebSocket.
+struct Recorded {
+    sent: bool,
+    message: Value,
+}
+
+impl Recorded {
+    /// The direction and type of the message, like `sent FRBC.SystemDescription`.
+    fn summary(&self) -> String {
+        let direction = if self.sent { "sent" } else { "received" };
+        format!(
+            "{direction} {}",
+            self.message["message_type"].as_str().unwrap_or_default()
+        )
+    }
+}
+
+/// The options of a CEM that does nothing but what a test asks of it.
+fn options() -> cem::Options {
+    cem::Options {
+        control_type: None,
+        timeout: Duration::from_secs(10),
+        state_directory: None,
+        power_limit: None,
+        feed_in_limit: None,
+        self_consumption: false,
+        prices: None,
+        lp_planner: false,
+        gas_price: None,
+        heat_price: None,
+        resume_directory: None,
+        api_address: None,
+        pairing_tokens: Vec::new(),
+        allowed_rms: None,
+        quarantine: false,
+        console: false,
+        strategy: None,
+        openadr: None,
+        influx: None,
+        postgres_url: None,
+    }
+}
+
+/// Runs the CEM with `options` and a simulator, started by `simulator` with the settings to connect to the CEM, both
+/// in this runtime, until the recording of the simulator is `done`. Returns every message in the recording.
+async fn exchange<F>(
+    options: cem::Options,
+    simulator: impl FnOnce(TestSettings) -> JoinHandle<eyre::Result<()>>,
+    settings: &[(&'static str, &str)],
+    done: F,
+) -> eyre::Result<Vec<Recorded>>
+where
+    F: Fn(&[Recorded]) -> bool,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("ws://{}", listener.local_addr()?);
+    let cem = tokio::spawn(cem::run(listener, options));
+    let recording_directory = tempfile::tempdir()?;
+    let mut settings: HashMap<_, _> = settings
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+    settings.insert("CEM_URL", url);
+    settings.insert("UPDATE_INTERVAL", "1".into());
+    settings.insert(
+        "RECORDING_DIRECTORY",
+        recording_directory.path().display().to_string(),
+    );
+    let simulator = simulator(TestSettings(settings));
+
+    let deadline = tokio::time::Instant::now() + EXCHANGE_TIMEOUT;
+    let recording = loop {
+        let recording = read_recording(recording_directory.path())?;
+        if done(&recording) {
+            break recording;
+        }
+        if simulator.is_finished() || cem.is_finished() || tokio::time::Instant::now() > deadline {
+            let summaries: Vec<_> = recording.iter().map(Recorded::summary).collect();
+            simulator.abort();
+            cem.abort();
+            eyre::bail!("The session didn't get through the exchange: {summaries:?}");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    simulator.abort();
+    cem.abort();
+    Ok(recording)
+}
+
+/// Reads the recording of the session in the given directory, which is empty until the simulator connected. The
+/// reception statuses are left out, as they cross the other messages at their own pace.
+fn read_recording(directory: &Path) -> eyre::Result<Vec<Recorded>> {
+    let mut recording = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let text = std::fs::read_to_string(entry?.path())?;
+        // The simulator may be writing the last line.
+        for line in text
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        {
+            if line["message"]["message_type"] == "ReceptionStatus" {
+                continue;
+            }
+            recording.push(Recorded {
+                sent: line["direction"] == "sent",
+                message: line["message"].clone(),
+            });
+        }
+    }
+    Ok(recording)
+}
+
+fn battery(settings: TestSettings) -> JoinHandle<eyre::Result<()>> {
+    tokio::spawn(async move { battery::run(&settings).await })
+}
+
+fn pv_installation(settings: TestSettings) -> JoinHandle<eyre::Result<()>> {
+    tokio::spawn(async move { pv_installation::run(&settings).await })
+}
+
+/// Whether the RM reported on an instruction it received.
+fn instruction_handled(recording: &[Recorded]) -> bool {
+    recording.iter().any(|recorded| {
+        recorded.sent && recorded.message["message_type"] == "InstructionStatusUpdate"
+    })
+}
+
+/// Whether the RM sent more than one power measurement, so its periodic updates go on after the initial messages.
+fn measured_twice(recording: &[Recorded]) -> bool {
+    let measurements = recording
+        .iter()
+        .filter(|recorded| recorded.sent && recorded.message["message_type"] == "PowerMeasurement");
+    measurements.count() > 1
+}
+
+/// Checks that the session starts with the handshake, in which the CEM selects `control_type`, and the initial messages
+/// of the RM for it.
+fn assert_session_start(recording: &[Recorded], control_type: &str, initial_messages: &[&str]) {
+    let summaries: Vec<_> = recording.iter().map(Recorded::summary).collect();
+    let mut expected = vec![
+        "sent Handshake".to_string(),
+        "received Handshake".into(),
+        "received HandshakeResponse".into(),
+        "sent ResourceManagerDetails".into(),
+        "received SelectControlType".into(),
+    ];
+    expected.extend(
+        initial_messages
+            .iter()
+            .map(|message_type| format!("sent {message_type}")),
+    );
+    assert!(
+        summaries.starts_with(&expected),
+        "expected the session to start with {expected:?}, but it went {summaries:?}"
+    );
+    assert_eq!(recording[4].message["control_type"], control_type);
+}
+
+/// Checks that the CEM sent an instruction of the given type, and the RM accepted it.
+fn assert_instruction_accepted(recording: &[Recorded], message_type: &str) -> Value {
+    let instruction = recording
+        .iter()
+        .find(|recorded| !recorded.sent && recorded.message["message_type"] == message_type)
+        .unwrap_or_else(|| panic!("the CEM should have sent a {message_type}"))
+        .message
+        .clone();
+    let update = recording
+        .iter()
+        .find(|recorded| {
+            recorded.sent
+                && recorded.message["message_type"] == "InstructionStatusUpdate"
+                && recorded.message["instruction_id"] == instruction["id"]
+        })
+        .expect("the RM should have reported on the instruction");
+    assert!(
+        ["ACCEPTED", "STARTED", "SUCCEEDED"]
+            .contains(&update.message["status_type"].as_str().unwrap_or_default()),
+        "the RM should have accepted the instruction, but it reported {}",
+        update.message
+    );
+    instruction
+}
+
+#[tokio::test]
+async fn battery_keeps_the_site_under_the_power_limit_with_frbc() -> eyre::Result<()> {
+    let options = cem::Options {
+        power_limit: Some(5000.0),
+        ..options()
+    };
+    let recording = exchange(options, battery, &[], instruction_handled).await?;
+
+    assert_session_start(
+        &recording,
+        "FILL_RATE_BASED_CONTROL",
+        &[
+            "FRBC.SystemDescription",
+            "FRBC.LeakageBehaviour",
+            "FRBC.UsageForecast",
+            "FRBC.ActuatorStatus",
+            "FRBC.StorageStatus",
+        ],
+    );
+    let instruction = assert_instruction_accepted(&recording, "FRBC.Instruction");
+    let description = &recording[5].message;
+    let operation_modes = description["actuators"][0]["operation_modes"].as_array();
+    assert!(
+        operation_modes
+            .into_iter()
+            .flatten()
+            .any(|operation_mode| operation_mode["id"] == instruction["operation_mode"]),
+        "the CEM should have picked an operation mode of the battery, not {}",
+        instruction["operation_mode"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn pv_installation_gets_a_power_envelope_with_pebc() -> eyre::Result<()> {
+    let options = cem::Options {
+        power_limit: Some(5000.0),
+        feed_in_limit: Some(2000.0),
+        ..options()
+    };
+    let settings = [("CONTROL_TYPE", "PEBC")];
+    let recording = exchange(options, pv_installation, &settings, instruction_handled).await?;
+
+    assert_session_start(
+        &recording,
+        "POWER_ENVELOPE_BASED_CONTROL",
+        &["PEBC.PowerConstraints"],
+    );
+    let instruction = assert_instruction_accepted(&recording, "PEBC.Instruction");
+    let constraints = &recording[5].message;
+    assert_eq!(instruction["power_constraints_id"], constraints["id"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn pv_installation_is_curtailed_for_negative_prices_with_ombc() -> eyre::Result<()> {
+    let options = cem::Options {
+        prices: Some(Arc::new(StaticCurve::new(vec![-0.10; 24])?)),
+        ..options()
+    };
+    let settings = [("CONTROL_TYPE", "OMBC")];
+    // The PV installation reports the operation mode it switched to after the instruction.
+    let status_after_instruction = |recording: &[Recorded]| {
+        let mut after_instruction = recording
+            .iter()
+            .skip_while(|recorded| recorded.message["message_type"] != "InstructionStatusUpdate");
+        after_instruction.any(|recorded| recorded.message["message_type"] == "OMBC.Status")
+    };
+    let recording = exchange(
+        options,
+        pv_installation,
+        &settings,
+        status_after_instruction,
+    )
+    .await?;
+
+    assert_session_start(
+        &recording,
+        "OPERATION_MODE_BASED_CONTROL",
+        &["OMBC.SystemDescription", "OMBC.Status"],
+    );
+    let instruction = assert_instruction_accepted(&recording, "OMBC.Instruction");
+    let operation_modes = &recording[5].message["operation_modes"];
+    assert_ne!(
+        instruction["operation_mode_id"], operation_modes[0]["id"],
+        "the CEM should have curtailed the PV installation, rather than let it produce all it can"
+    );
+    let status = recording
+        .iter()
+        .rev()
+        .find(|recorded| recorded.sent && recorded.message["message_type"] == "OMBC.Status")
+        .expect("the PV installation should have sent its status");
+    assert_eq!(
+        status.message["active_operation_mode_id"],
+        instruction["operation_mode_id"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn uncontrollable_pv_installation_only_measures() -> eyre::Result<()> {
+    let settings = [("CONTROL_TYPE", "NOT_CONTROLABLE")];
+    let recording = exchange(options(), pv_installation, &settings, measured_twice).await?;
+
+    assert_session_start(&recording, "NOT_CONTROLABLE", &[]);
+    assert!(
+        recording[5..].iter().all(|recorded| recorded.sent
+            && ["PowerMeasurement", "PowerForecast"].contains(
+                &recorded.message["message_type"]
+                    .as_str()
+                    .unwrap_or_default()
+            )),
+        "the CEM has nothing to instruct, and the RM nothing to describe"
+    );
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use clap::Parser;
+use conformance::{ControlTypeArg, LoadOptions};
+use eyre::Context;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Benchmarks S2 resource managers by acting as a CEM for many of them at once.
+///
+/// Every RM that connects gets a session, and then a steady stream of instructions. When the load test is over, this
+/// prints how many messages the RMs acknowledged, and how long the reception statuses and instruction status updates
+/// took.
+#[derive(Parser, Debug)]
+#[command(name = "s2-load", version)]
+struct Cli {
+    /// The address to listen on for the RMs.
+    #[arg(long, env = "LISTEN_ADDRESS", default_value = "0.0.0.0:8080")]
+    listen: String,
+    /// The control type to select [default: the first one each RM offers]
+    #[arg(long, env = "CONTROL_TYPE", value_enum)]
+    control_type: Option<ControlTypeArg>,
+    /// How many instructions to send to every RM per second.
+    #[arg(long, env = "RATE", default_value_t = 1.0)]
+    rate: f64,
+    /// How long to run the load test, in seconds [default: until Ctrl-C is pressed]
+    #[arg(long, env = "DURATION")]
+    duration: Option<u64>,
+    /// How long to wait for an RM to set up its session, in seconds.
+    #[arg(long, env = "TIMEOUT", default_value_t = 10)]
+    timeout: u64,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt().init();
+    let cli = Cli::parse();
+    let options = LoadOptions {
+        control_type: cli.control_type.map(Into::into),
+        rate: cli.rate,
+        duration: cli.duration.map(Duration::from_secs),
+        timeout: Duration::from_secs(cli.timeout),
+    };
+
+    let listener = TcpListener::bind(&cli.listen)
+        .await
+        .wrap_err_with(|| format!("Could not listen on {}", cli.listen))?;
+    tracing::info!("Waiting for RMs to connect on ws://{}", cli.listen);
+    let report = conformance::run_load(listener, options).await?;
+    println!("{report}");
+    Ok(())
+}
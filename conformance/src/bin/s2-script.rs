@@ -0,0 +1,62 @@
+use clap::Parser;
+use conformance::{ControlTypeArg, RmConnection, Script};
+use eyre::Context;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Tests an S2 resource manager by acting as a CEM that follows a script.
+///
+/// This waits for a single RM to connect, sets up a session, runs the steps of the YAML script, such as waiting for a
+/// message, sending an instruction and expecting its status update, and prints whether every step passed. It exits with
+/// a non-zero status if any step failed.
+#[derive(Parser, Debug)]
+#[command(name = "s2-script", version)]
+struct Cli {
+    /// The YAML script to run.
+    script: PathBuf,
+    /// The address to listen on for the RM.
+    #[arg(long, env = "LISTEN_ADDRESS", default_value = "0.0.0.0:8080")]
+    listen: String,
+    /// The control type to select [default: the first one the RM offers]
+    #[arg(long, env = "CONTROL_TYPE", value_enum)]
+    control_type: Option<ControlTypeArg>,
+    /// How long to wait for a response from the RM, in seconds, where the script doesn't say.
+    #[arg(long, env = "TIMEOUT", default_value_t = 10)]
+    timeout: u64,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<ExitCode> {
+    tracing_subscriber::fmt().init();
+    let cli = Cli::parse();
+    let yaml = std::fs::read_to_string(&cli.script)
+        .wrap_err_with(|| format!("Could not read the script {}", cli.script.display()))?;
+    let script = Script::from_yaml(&yaml)?;
+
+    let listener = TcpListener::bind(&cli.listen)
+        .await
+        .wrap_err_with(|| format!("Could not listen on {}", cli.listen))?;
+    tracing::info!("Waiting for an RM to connect on ws://{}", cli.listen);
+    let (stream, address) = listener.accept().await?;
+    let socket = tokio_tungstenite::accept_async(stream)
+        .await
+        .wrap_err("Could not set up a WebSocket connection with the RM")?;
+    tracing::info!("RM connected from {address}, running the script");
+
+    let mut connection = RmConnection::new(socket);
+    let report = conformance::run_script(
+        &mut connection,
+        &script,
+        cli.control_type.map(Into::into),
+        Duration::from_secs(cli.timeout),
+    )
+    .await?;
+    println!("{report}");
+    if report.has_failures() {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
@@ -0,0 +1,565 @@
+use crate::report::{Outcome, Report};
+use crate::robustness;
+use cem_connection::RmConnection;
+use chrono::Utc;
+use s2energy::common::{
+    ControlType, EnergyManagementRole, Handshake, HandshakeResponse, Id, InstructionStatus,
+    Message, ReceptionStatusValues, ResourceManagerDetails, SelectControlType, SessionRequest,
+    SessionRequestType,
+};
+use s2energy::{frbc, ombc, pebc};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// How long to wait for the last reception statuses at the end of the session.
+const RECEPTION_STATUS_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Options for a conformance run.
+pub struct Options {
+    /// The control type to select; if not set, the first control type the RM offers is selected.
+    pub control_type: Option<ControlType>,
+    /// How long to wait for a response from the RM.
+    pub timeout: Duration,
+    /// Whether to also send malformed and invalid messages, to check that the RM handles them gracefully.
+    pub robustness: bool,
+}
+
+/// The messages sent to the RM, with whether a reception status other than OK is acceptable.
+#[derive(Default)]
+pub(crate) struct Sent {
+    messages: Vec<(Id, String, bool)>,
+}
+
+impl Sent {
+    pub(crate) fn expect_ok(&mut self, id: Option<Id>, message_type: &str) {
+        if let Some(id) = id {
+            self.messages.push((id, message_type.into(), false));
+        }
+    }
+
+    pub(crate) fn allow_errors(&mut self, id: Option<Id>, message_type: &str) {
+        if let Some(id) = id {
+            self.messages.push((id, message_type.into(), true));
+        }
+    }
+}
+
+/// Runs all checks against the RM on the other end of the connection, which has just connected.
+pub async fn run_checks(connection: &mut RmConnection, options: &Options) -> eyre::Result<Report> {
+    let mut report = Report::default();
+    let mut sent = Sent::default();
+
+    // Later checks depend on the session being set up, so skip them if that fails.
+    if let Some(rm_details) = handshake(connection, options, &mut report, &mut sent).await? {
+        if let Some(control_type) =
+            select_control_type(connection, options, &rm_details, &mut report, &mut sent).await?
+        {
+            check_initial_messages(connection, options, control_type, &mut report).await?;
+            check_instructions(connection, options, control_type, &mut report, &mut sent).await?;
+            if options.robustness {
+                robustness::check_robustness(
+                    connection,
+                    options,
+                    control_type,
+                    &mut report,
+                    &mut sent,
+                )
+                .await?;
+            }
+            check_id_consistency(connection, &mut report);
+        }
+    }
+
+    let terminate = SessionRequest {
+        diagnostic_label: Some("Conformance checks finished".into()),
+        message_id: Id::generate(),
+        request: SessionRequestType::Terminate,
+    };
+    if !connection.closed {
+        connection
+            .receive_for(RECEPTION_STATUS_GRACE_PERIOD)
+            .await?;
+    }
+    check_reception_statuses(connection, &sent, &mut report);
+    report.check(
+        "All messages from the RM are valid S2 messages",
+        connection.invalid.is_empty(),
+        || format!("The RM sent {}", connection.invalid.join("; ")),
+    );
+
+    if !connection.closed {
+        connection.send(terminate).await?;
+        connection.close().await;
+    }
+    Ok(report)
+}
+
+/// Performs the handshake, and returns the details of the RM if it succeeded.
+async fn handshake(
+    connection: &mut RmConnection,
+    options: &Options,
+    report: &mut Report,
+    sent: &mut Sent,
+) -> eyre::Result<Option<ResourceManagerDetails>> {
+    let check = "The RM starts the session with a Handshake";
+    connection
+        .wait_until(options.timeout, |connection| {
+            !connection.received.is_empty()
+        })
+        .await?;
+    let supported_versions = match connection.received.first() {
+        Some(received) => match &received.message {
+            Message::Handshake(handshake) => {
+                report.check(check, handshake.role == EnergyManagementRole::Rm, || {
+                    format!("The Handshake has role {:?} instead of RM", handshake.role)
+                });
+                handshake.supported_protocol_versions.clone()
+            }
+            _ => {
+                let reason = format!("The first message is a {}", received.message_type);
+                report.add(check, Outcome::Fail(reason));
+                return Ok(None);
+            }
+        },
+        None => {
+            let reason = format!("The RM sent nothing within {:?}", options.timeout);
+            report.add(check, Outcome::Fail(reason));
+            return Ok(None);
+        }
+    };
+
+    let version = s2energy::s2_schema_version().to_string();
+    report.check(
+        format!("The RM supports S2 version {version}"),
+        supported_versions.contains(&version),
+        || format!("The RM only supports {supported_versions:?}"),
+    );
+    let id = connection
+        .send(Handshake::new(
+            EnergyManagementRole::Cem,
+            vec![version.clone()],
+        ))
+        .await?;
+    sent.expect_ok(id, "Handshake");
+    let handshake_done_at = connection.received.len();
+    let id = connection.send(HandshakeResponse::new(version)).await?;
+    sent.expect_ok(id, "HandshakeResponse");
+
+    let check = "The RM sends its ResourceManagerDetails after the handshake";
+    connection
+        .wait_until(options.timeout, |connection| {
+            connection
+                .received
+                .iter()
+                .any(|received| matches!(received.message, Message::ResourceManagerDetails(_)))
+        })
+        .await?;
+    let Some((index, rm_details)) =
+        connection
+            .received
+            .iter()
+            .enumerate()
+            .find_map(|(index, received)| match &received.message {
+                Message::ResourceManagerDetails(details) => Some((index, details.clone())),
+                _ => None,
+            })
+    else {
+        let reason = format!(
+            "The RM sent no ResourceManagerDetails within {:?}",
+            options.timeout
+        );
+        report.add(check, Outcome::Fail(reason));
+        return Ok(None);
+    };
+    report.check(check, index >= handshake_done_at, || {
+        "The RM sent its ResourceManagerDetails before it received the HandshakeResponse".into()
+    });
+    report.check(
+        "The RM offers at least one control type",
+        !rm_details.available_control_types.is_empty(),
+        || "available_control_types is empty".into(),
+    );
+    Ok(Some(rm_details))
+}
+
+/// Selects the control type to test, and returns it if the RM offers it.
+async fn select_control_type(
+    connection: &mut RmConnection,
+    options: &Options,
+    rm_details: &ResourceManagerDetails,
+    report: &mut Report,
+    sent: &mut Sent,
+) -> eyre::Result<Option<ControlType>> {
+    let available = &rm_details.available_control_types;
+    let control_type = match options.control_type {
+        Some(control_type) if available.contains(&control_type) => control_type,
+        Some(control_type) => {
+            let reason = format!("The RM only offers {available:?}");
+            report.add(
+                format!("The RM offers {control_type:?}"),
+                Outcome::Fail(reason),
+            );
+            return Ok(None);
+        }
+        None => match available
+            .iter()
+            .find(|control_type| **control_type != ControlType::NoSelection)
+        {
+            Some(control_type) => *control_type,
+            None => return Ok(None),
+        },
+    };
+
+    let id = connection
+        .send(SelectControlType::new(control_type))
+        .await?;
+    sent.expect_ok(id, "SelectControlType");
+    Ok(Some(control_type))
+}
+
+/// Checks that the RM sends the messages the CEM needs after it selected a control type.
+async fn check_initial_messages(
+    connection: &mut RmConnection,
+    options: &Options,
+    control_type: ControlType,
+    report: &mut Report,
+) -> eyre::Result<()> {
+    let required: &[&str] = match control_type {
+        ControlType::FillRateBasedControl => &[
+            "FRBC.SystemDescription",
+            "FRBC.ActuatorStatus",
+            "FRBC.StorageStatus",
+        ],
+        ControlType::OperationModeBasedControl => &["OMBC.SystemDescription", "OMBC.Status"],
+        ControlType::PowerEnvelopeBasedControl => &["PEBC.PowerConstraints"],
+        ControlType::PowerProfileBasedControl => &["PPBC.PowerProfileDefinition"],
+        ControlType::DemandDrivenBasedControl => &["DDBC.SystemDescription"],
+        ControlType::NotControlable | ControlType::NoSelection => &[],
+    };
+    if required.is_empty() {
+        report.skip(
+            "The RM sends the initial messages for its control type",
+            format!("{control_type:?} has no mandatory initial messages"),
+        );
+        return Ok(());
+    }
+
+    let has = |connection: &RmConnection, message_type: &str| {
+        connection
+            .received
+            .iter()
+            .any(|received| received.message_type == message_type)
+    };
+    connection
+        .wait_until(options.timeout, |connection| {
+            required
+                .iter()
+                .all(|message_type| has(connection, message_type))
+        })
+        .await?;
+    for message_type in required {
+        report.check(
+            format!("The RM sends {message_type} after {control_type:?} is selected"),
+            has(connection, message_type),
+            || format!("No {message_type} within {:?}", options.timeout),
+        );
+    }
+    Ok(())
+}
+
+/// An instruction to send to the RM, with its instruction ID.
+type TestInstruction = (Id, Message);
+
+/// Sends a valid instruction and one that refers to an unknown ID, and checks how the RM responds to them.
+async fn check_instructions(
+    connection: &mut RmConnection,
+    options: &Options,
+    control_type: ControlType,
+    report: &mut Report,
+    sent: &mut Sent,
+) -> eyre::Result<()> {
+    let valid_check = "The RM accepts a valid instruction";
+    let invalid_check = "The RM rejects an instruction that refers to an unknown ID";
+    let (valid, invalid): (Option<TestInstruction>, Option<TestInstruction>) = match control_type {
+        ControlType::FillRateBasedControl => {
+            let Some((actuator_id, operation_mode, factor)) = current_frbc_state(connection) else {
+                report.skip(valid_check, "The RM sent no FRBC.SystemDescription");
+                report.skip(invalid_check, "The RM sent no FRBC.SystemDescription");
+                return Ok(());
+            };
+            let instruction = |operation_mode, factor| {
+                let id = Id::generate();
+                let instruction = frbc::Instruction::new(
+                    false,
+                    actuator_id.clone(),
+                    Utc::now(),
+                    id.clone(),
+                    operation_mode,
+                    factor,
+                );
+                (id, instruction.into())
+            };
+            (
+                Some(instruction(operation_mode, factor)),
+                Some(instruction(Id::generate(), 0.0)),
+            )
+        }
+        ControlType::OperationModeBasedControl => {
+            let Some((operation_mode, factor)) = current_ombc_state(connection) else {
+                report.skip(valid_check, "The RM sent no OMBC.SystemDescription");
+                report.skip(invalid_check, "The RM sent no OMBC.SystemDescription");
+                return Ok(());
+            };
+            let instruction = |operation_mode, factor| {
+                let id = Id::generate();
+                let instruction =
+                    ombc::Instruction::new(false, Utc::now(), id.clone(), factor, operation_mode);
+                (id, instruction.into())
+            };
+            (
+                Some(instruction(operation_mode, factor)),
+                Some(instruction(Id::generate(), 0.0)),
+            )
+        }
+        ControlType::PowerEnvelopeBasedControl => {
+            let id = Id::generate();
+            let instruction =
+                pebc::Instruction::new(false, Utc::now(), id.clone(), Id::generate(), vec![]);
+            (None, Some((id, instruction.into())))
+        }
+        _ => (None, None),
+    };
+
+    match valid {
+        Some((id, instruction)) => {
+            let message_id = connection.send(instruction).await?;
+            sent.expect_ok(message_id, "a valid instruction");
+            let statuses = instruction_statuses(connection, options, &id).await?;
+            report.check(
+                valid_check,
+                !statuses.is_empty()
+                    && !statuses.iter().any(|status| {
+                        matches!(
+                            status,
+                            InstructionStatus::Rejected | InstructionStatus::Aborted
+                        )
+                    }),
+                || describe_statuses(&statuses, options),
+            );
+        }
+        None => report.skip(valid_check, unsupported_reason(control_type)),
+    }
+
+    match invalid {
+        Some((id, instruction)) => {
+            let message_id = connection.send(instruction).await?;
+            // An RM may also signal the unknown ID in the reception status.
+            sent.allow_errors(message_id, "an instruction with an unknown ID");
+            let statuses = instruction_statuses(connection, options, &id).await?;
+            report.check(
+                invalid_check,
+                statuses.contains(&InstructionStatus::Rejected),
+                || describe_statuses(&statuses, options),
+            );
+        }
+        None => report.skip(invalid_check, unsupported_reason(control_type)),
+    }
+    Ok(())
+}
+
+fn unsupported_reason(control_type: ControlType) -> String {
+    match control_type {
+        ControlType::NotControlable | ControlType::NoSelection => {
+            format!("{control_type:?} has no instructions")
+        }
+        _ => format!("Sending this instruction for {control_type:?} isn't supported yet"),
+    }
+}
+
+/// Returns the first actuator of the latest FRBC system description, with its active operation mode and factor.
+pub(crate) fn current_frbc_state(connection: &RmConnection) -> Option<(Id, Id, f64)> {
+    let actuator =
+        connection
+            .received
+            .iter()
+            .rev()
+            .find_map(|received| match &received.message {
+                Message::FrbcSystemDescription(description) => description.actuators.first(),
+                _ => None,
+            })?;
+    let status = connection
+        .received
+        .iter()
+        .rev()
+        .find_map(|received| match &received.message {
+            Message::FrbcActuatorStatus(status) if status.actuator_id == actuator.id => {
+                Some(status)
+            }
+            _ => None,
+        });
+    match status {
+        Some(status) => Some((
+            actuator.id.clone(),
+            status.active_operation_mode_id.clone(),
+            status.operation_mode_factor,
+        )),
+        None => Some((
+            actuator.id.clone(),
+            actuator.operation_modes.first()?.id.clone(),
+            0.0,
+        )),
+    }
+}
+
+/// Returns the active operation mode and factor of the RM, according to the latest OMBC messages.
+pub(crate) fn current_ombc_state(connection: &RmConnection) -> Option<(Id, f64)> {
+    let description =
+        connection
+            .received
+            .iter()
+            .rev()
+            .find_map(|received| match &received.message {
+                Message::OmbcSystemDescription(description) => Some(description),
+                _ => None,
+            })?;
+    let status = connection
+        .received
+        .iter()
+        .rev()
+        .find_map(|received| match &received.message {
+            Message::OmbcStatus(status) => Some(status),
+            _ => None,
+        });
+    match status {
+        Some(status) => Some((
+            status.active_operation_mode_id.clone(),
+            status.operation_mode_factor,
+        )),
+        None => Some((description.operation_modes.first()?.id.clone(), 0.0)),
+    }
+}
+
+/// Waits for an instruction status update for the given instruction, and returns all statuses received for it.
+pub(crate) async fn instruction_statuses(
+    connection: &mut RmConnection,
+    options: &Options,
+    instruction_id: &Id,
+) -> eyre::Result<Vec<InstructionStatus>> {
+    let statuses = |connection: &RmConnection| -> Vec<InstructionStatus> {
+        connection
+            .received
+            .iter()
+            .filter_map(|received| match &received.message {
+                Message::InstructionStatusUpdate(update)
+                    if update.instruction_id == *instruction_id =>
+                {
+                    Some(update.status_type)
+                }
+                _ => None,
+            })
+            .collect()
+    };
+    connection
+        .wait_until(options.timeout, |connection| {
+            !statuses(connection).is_empty()
+        })
+        .await?;
+    Ok(statuses(connection))
+}
+
+fn describe_statuses(statuses: &[InstructionStatus], options: &Options) -> String {
+    if statuses.is_empty() {
+        format!(
+            "No InstructionStatusUpdate for the instruction (with its `id` as `instruction_id`) within {:?}",
+            options.timeout
+        )
+    } else {
+        format!("The RM sent the statuses {statuses:?}")
+    }
+}
+
+/// Checks that IDs are unique, and that status messages refer to IDs the RM described earlier.
+fn check_id_consistency(connection: &RmConnection, report: &mut Report) {
+    let mut seen = HashSet::new();
+    let duplicates: Vec<_> = connection
+        .received
+        .iter()
+        .filter_map(|received| received.message.id())
+        .filter(|id| !seen.insert(id.clone()))
+        .collect();
+    report.check(
+        "The RM uses a new message ID for every message",
+        duplicates.is_empty(),
+        || format!("Message IDs used more than once: {duplicates:?}"),
+    );
+
+    let mut operation_modes = HashSet::new();
+    let mut unknown = Vec::new();
+    let mut has_statuses = false;
+    for received in &connection.received {
+        match &received.message {
+            Message::FrbcSystemDescription(description) => {
+                for actuator in &description.actuators {
+                    for operation_mode in &actuator.operation_modes {
+                        operation_modes
+                            .insert((Some(actuator.id.clone()), operation_mode.id.clone()));
+                    }
+                }
+            }
+            Message::OmbcSystemDescription(description) => {
+                for operation_mode in &description.operation_modes {
+                    operation_modes.insert((None, operation_mode.id.clone()));
+                }
+            }
+            Message::FrbcActuatorStatus(status) => {
+                has_statuses = true;
+                let key = (
+                    Some(status.actuator_id.clone()),
+                    status.active_operation_mode_id.clone(),
+                );
+                if !operation_modes.contains(&key) {
+                    unknown.push(format!(
+                        "FRBC.ActuatorStatus with actuator {:?} and operation mode {:?}",
+                        status.actuator_id, status.active_operation_mode_id
+                    ));
+                }
+            }
+            Message::OmbcStatus(status) => {
+                has_statuses = true;
+                if !operation_modes.contains(&(None, status.active_operation_mode_id.clone())) {
+                    unknown.push(format!(
+                        "OMBC.Status with operation mode {:?}",
+                        status.active_operation_mode_id
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    let check = "Status messages refer to operation modes from an earlier system description";
+    if has_statuses {
+        report.check(check, unknown.is_empty(), || {
+            format!("Unknown IDs in {}", unknown.join("; "))
+        });
+    } else {
+        report.skip(check, "The RM sent no FRBC.ActuatorStatus or OMBC.Status");
+    }
+}
+
+/// Checks that the RM acknowledged every message it was sent.
+fn check_reception_statuses(connection: &RmConnection, sent: &Sent, report: &mut Report) {
+    let mut problems = Vec::new();
+    for (id, message_type, allow_errors) in &sent.messages {
+        match connection.reception_statuses.get(id) {
+            None => problems.push(format!("no ReceptionStatus for {message_type}")),
+            Some(ReceptionStatusValues::Ok) => {}
+            Some(_) if *allow_errors => {}
+            Some(status) => problems.push(format!("status {status:?} for {message_type}")),
+        }
+    }
+    report.check(
+        "The RM acknowledges every message with a ReceptionStatus",
+        problems.is_empty(),
+        || problems.join("; "),
+    );
+}
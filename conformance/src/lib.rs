@@ -0,0 +1,22 @@
+//! Tools to test S2 resource managers by acting as a CEM.
+//!
+//! [`run_checks`] runs the checks of the `s2-conformance` tool against an RM and returns a [`Report`], and [`MockCem`]
+//! lets you script a session yourself, for example in the integration tests of your own RM. [`run_load`] puts many RMs
+//! under load at once, for the `s2-load` tool, and [`run_script`] runs a [`Script`] of steps against an RM, for the
+//! `s2-script` tool.
+
+mod checks;
+mod load;
+mod message;
+mod mock_cem;
+mod report;
+mod robustness;
+mod script;
+
+pub use checks::{run_checks, Options};
+pub use cem_connection::{ControlTypeArg, Received, RmConnection};
+pub use load::{run_load, LoadOptions, LoadReport};
+pub use message::S2Message;
+pub use mock_cem::MockCem;
+pub use report::{Outcome, Report};
+pub use script::{run_script, Script};
@@ -0,0 +1,534 @@
+use cem_connection::RmConnection;
+use chrono::Utc;
+use eyre::{bail, Context};
+use s2energy::common::{
+    CommodityQuantity, ControlType, EnergyManagementRole, Handshake, HandshakeResponse, Id,
+    InstructionStatus, Message, ReceptionStatusValues, SelectControlType, SessionRequest,
+    SessionRequestType,
+};
+use s2energy::{frbc, ombc, pebc};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// How often the load generator logs how the load test is going.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for the last reception statuses and status updates at the end of a session.
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+/// How long to wait before accepting connections again after that failed.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How long the power envelopes in PEBC instructions last.
+const ENVELOPE_DURATION_MS: u64 = 3_600_000;
+
+/// Options for a load test.
+pub struct LoadOptions {
+    /// The control type to select; if not set, the first control type each RM offers is selected.
+    pub control_type: Option<ControlType>,
+    /// How many instructions to send to every RM per second.
+    pub rate: f64,
+    /// How long to send instructions; if not set, the load test runs until the user presses Ctrl-C.
+    pub duration: Option<Duration>,
+    /// How long to wait for an RM to set up its session.
+    pub timeout: Duration,
+}
+
+/// The results of a load test.
+#[derive(Debug, Default, Clone)]
+pub struct LoadReport {
+    /// The number of RMs that set up a session.
+    pub sessions: usize,
+    /// The number of RMs that failed to set up a session, or lost their connection during the load test.
+    pub failed_sessions: usize,
+    /// The number of instructions sent.
+    pub instructions: usize,
+    /// The time between sending a message and receiving its reception status, for every acknowledged message.
+    pub reception_latencies: Vec<Duration>,
+    /// The number of messages the RMs acknowledged with a status other than OK.
+    pub rejected_messages: usize,
+    /// The time between sending an instruction and receiving its first status update, for every instruction.
+    pub instruction_latencies: Vec<Duration>,
+    /// The number of instructions the RMs rejected or aborted.
+    pub rejected_instructions: usize,
+    /// The number of messages without a reception status at the end of the load test.
+    pub unacknowledged_messages: usize,
+    /// The number of instructions without a status update at the end of the load test.
+    pub instructions_without_status: usize,
+}
+
+impl LoadReport {
+    /// A single line that sums up the load test so far.
+    fn progress(&self) -> String {
+        format!(
+            "{} sessions set up, {} failed, {} instructions sent, reception status latency {}",
+            self.sessions,
+            self.failed_sessions,
+            self.instructions,
+            Percentiles::of(&self.reception_latencies),
+        )
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Sessions:               {} set up, {} failed",
+            self.sessions, self.failed_sessions
+        )?;
+        writeln!(f, "Instructions sent:      {}", self.instructions)?;
+        writeln!(
+            f,
+            "Reception statuses:     {} ({} not OK, {} missing)",
+            self.reception_latencies.len(),
+            self.rejected_messages,
+            self.unacknowledged_messages
+        )?;
+        writeln!(
+            f,
+            "  latency               {}",
+            Percentiles::of(&self.reception_latencies)
+        )?;
+        writeln!(
+            f,
+            "Instruction statuses:   {} ({} rejected or aborted, {} missing)",
+            self.instruction_latencies.len(),
+            self.rejected_instructions,
+            self.instructions_without_status
+        )?;
+        write!(
+            f,
+            "  latency               {}",
+            Percentiles::of(&self.instruction_latencies)
+        )
+    }
+}
+
+/// The median, 90th and 99th percentile and maximum of a set of latencies.
+struct Percentiles(Option<[Duration; 4]>);
+
+impl Percentiles {
+    fn of(latencies: &[Duration]) -> Self {
+        if latencies.is_empty() {
+            return Self(None);
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort_unstable();
+        let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+        Self(Some([
+            percentile(0.5),
+            percentile(0.9),
+            percentile(0.99),
+            sorted[sorted.len() - 1],
+        ]))
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.;
+        match self.0 {
+            Some([p50, p90, p99, max]) => write!(
+                f,
+                "p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+                ms(p50),
+                ms(p90),
+                ms(p99),
+                ms(max)
+            ),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+/// Accepts every RM that connects on `listener`, and sends each of them instructions at the configured rate.
+///
+/// The instructions repeat what the RM is already doing, so any RM can execute them. For every message, the latency
+/// until its reception status arrives is measured, and for every instruction the latency until its first status
+/// update. When the load test is over, every RM is asked to terminate its session.
+pub async fn run_load(listener: TcpListener, options: LoadOptions) -> eyre::Result<LoadReport> {
+    if !(options.rate > 0.0 && options.rate.is_finite()) {
+        bail!("The rate should be a positive number of instructions per second");
+    }
+    let options = Arc::new(options);
+    let report = Arc::new(Mutex::new(LoadReport::default()));
+    let (stop, stopped) = watch::channel(false);
+    let mut sessions = JoinSet::new();
+
+    let end = async {
+        match options.duration {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(end);
+    let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+    progress.tick().await;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, address)) => {
+                    let options = options.clone();
+                    let report = report.clone();
+                    let stopped = stopped.clone();
+                    sessions.spawn(async move {
+                        if let Err(error) = run_session(stream, &options, &report, stopped).await {
+                            tracing::warn!("Session with the RM at {address} failed: {error:#}");
+                            report.lock().unwrap().failed_sessions += 1;
+                        }
+                    });
+                }
+                // For example when there are too many open files; the RMs that are connected keep going.
+                Err(error) => {
+                    tracing::warn!("Could not accept a connection: {error}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            },
+            _ = progress.tick() => tracing::info!("{}", report.lock().unwrap().progress()),
+            _ = &mut end => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    tracing::info!(
+        "Stopping the load test, waiting for {} sessions to end",
+        sessions.len()
+    );
+    stop.send_replace(true);
+    while sessions.join_next().await.is_some() {}
+    let report = report.lock().unwrap().clone();
+    Ok(report)
+}
+
+/// Sets up a session with an RM that just connected, and sends it instructions until the load test is over.
+async fn run_session(
+    stream: TcpStream,
+    options: &LoadOptions,
+    report: &Mutex<LoadReport>,
+    mut stopped: watch::Receiver<bool>,
+) -> eyre::Result<()> {
+    let socket = tokio_tungstenite::accept_async(stream)
+        .await
+        .wrap_err("Could not set up a WebSocket connection")?;
+    let mut session = Session {
+        connection: RmConnection::new(socket),
+        target: Target::default(),
+        awaiting_reception: HashMap::new(),
+        awaiting_status: HashMap::new(),
+    };
+    let control_type = session.set_up(options).await?;
+    report.lock().unwrap().sessions += 1;
+
+    let interval = Duration::from_secs_f64(1.0 / options.rate);
+    let mut next_instruction = Instant::now();
+    while !*stopped.borrow() {
+        if session.connection.closed {
+            bail!("The RM closed the connection");
+        }
+        if Instant::now() >= next_instruction {
+            next_instruction += interval;
+            if let Some((instruction_id, instruction)) = session.target.instruction(control_type) {
+                let sent_at = Instant::now();
+                if let Some(message_id) = session.connection.send(instruction).await? {
+                    session.awaiting_reception.insert(message_id, sent_at);
+                }
+                session.awaiting_status.insert(instruction_id, sent_at);
+                report.lock().unwrap().instructions += 1;
+            }
+            continue;
+        }
+
+        let remaining = next_instruction - Instant::now();
+        tokio::select! {
+            received = session.connection.wait_until(remaining, Session::has_news) => {
+                received?;
+            }
+            _ = stopped.changed() => {}
+        }
+        session.process(report);
+    }
+
+    // Give the RM a moment to respond to the last instructions, so they don't count as missing.
+    session.connection.receive_for(GRACE_PERIOD).await?;
+    session.process(report);
+    {
+        let mut report = report.lock().unwrap();
+        report.unacknowledged_messages += session.awaiting_reception.len();
+        report.instructions_without_status += session.awaiting_status.len();
+    }
+    if !session.connection.closed {
+        session
+            .connection
+            .send(SessionRequest {
+                diagnostic_label: Some("Load test finished".into()),
+                message_id: Id::generate(),
+                request: SessionRequestType::Terminate,
+            })
+            .await?;
+        session.connection.close().await;
+    }
+    Ok(())
+}
+
+/// The session with one of the RMs under load.
+struct Session {
+    connection: RmConnection,
+    target: Target,
+    /// When the messages without a reception status were sent, by message ID.
+    awaiting_reception: HashMap<Id, Instant>,
+    /// When the instructions without a status update were sent, by instruction ID.
+    awaiting_status: HashMap<Id, Instant>,
+}
+
+impl Session {
+    /// Performs the handshake and selects the control type, which is returned.
+    async fn set_up(&mut self, options: &LoadOptions) -> eyre::Result<ControlType> {
+        let connection = &mut self.connection;
+        connection
+            .wait_until(options.timeout, |connection| {
+                !connection.received.is_empty()
+            })
+            .await?;
+        match connection
+            .received
+            .first()
+            .map(|received| &received.message)
+        {
+            Some(Message::Handshake(handshake)) if handshake.role == EnergyManagementRole::Rm => {}
+            Some(_) => bail!("The RM didn't start the session with a Handshake"),
+            None => bail!("The RM sent no Handshake within {:?}", options.timeout),
+        }
+        let version = s2energy::s2_schema_version().to_string();
+        connection
+            .send(Handshake::new(
+                EnergyManagementRole::Cem,
+                vec![version.clone()],
+            ))
+            .await?;
+        connection.send(HandshakeResponse::new(version)).await?;
+
+        let rm_details = |connection: &RmConnection| {
+            connection
+                .received
+                .iter()
+                .find_map(|received| match &received.message {
+                    Message::ResourceManagerDetails(details) => Some(details.clone()),
+                    _ => None,
+                })
+        };
+        connection
+            .wait_until(options.timeout, |connection| {
+                rm_details(connection).is_some()
+            })
+            .await?;
+        let Some(rm_details) = rm_details(connection) else {
+            bail!(
+                "The RM sent no ResourceManagerDetails within {:?}",
+                options.timeout
+            );
+        };
+        let available = &rm_details.available_control_types;
+        let control_type = match options.control_type {
+            Some(control_type) if available.contains(&control_type) => control_type,
+            Some(control_type) => {
+                bail!("The RM doesn't offer {control_type:?}, only {available:?}")
+            }
+            None => *available
+                .iter()
+                .find(|control_type| **control_type != ControlType::NoSelection)
+                .ok_or_else(|| eyre::eyre!("The RM offers no control type"))?,
+        };
+        connection
+            .send(SelectControlType::new(control_type))
+            .await?;
+        connection.received.clear();
+        Ok(control_type)
+    }
+
+    /// Whether the RM sent something that [`process`](Self::process) should look at.
+    fn has_news(connection: &RmConnection) -> bool {
+        !connection.received.is_empty() || !connection.reception_statuses.is_empty()
+    }
+
+    /// Records the latencies of the responses the RM sent, and keeps track of its state.
+    ///
+    /// The messages are removed from the connection afterwards, so a long load test doesn't use ever more memory.
+    fn process(&mut self, report: &Mutex<LoadReport>) {
+        let now = Instant::now();
+        let mut report = report.lock().unwrap();
+        for (message_id, status) in self.connection.reception_statuses.drain() {
+            if let Some(sent_at) = self.awaiting_reception.remove(&message_id) {
+                report.reception_latencies.push(now - sent_at);
+                if status != ReceptionStatusValues::Ok {
+                    report.rejected_messages += 1;
+                }
+            }
+        }
+        for received in self.connection.received.drain(..) {
+            if let Message::InstructionStatusUpdate(update) = &received.message {
+                if let Some(sent_at) = self.awaiting_status.remove(&update.instruction_id) {
+                    report.instruction_latencies.push(now - sent_at);
+                    if matches!(
+                        update.status_type,
+                        InstructionStatus::Rejected | InstructionStatus::Aborted
+                    ) {
+                        report.rejected_instructions += 1;
+                    }
+                }
+            }
+            self.target.update(&received.message);
+        }
+    }
+}
+
+/// What the load generator knows about an RM, to send it instructions it can execute.
+#[derive(Default)]
+struct Target {
+    /// The first FRBC actuator, with its active operation mode and factor.
+    frbc: Option<(Id, Id, f64)>,
+    /// The active OMBC operation mode and factor.
+    ombc: Option<(Id, f64)>,
+    /// The latest PEBC power constraints.
+    pebc: Option<pebc::PowerConstraints>,
+}
+
+impl Target {
+    fn update(&mut self, message: &Message) {
+        match message {
+            Message::FrbcSystemDescription(description) => {
+                let Some(actuator) = description.actuators.first() else {
+                    return;
+                };
+                // Operation modes may get new IDs, in which case the actuator status with the new ID follows.
+                let known = self.frbc.as_ref().is_some_and(|(actuator_id, mode, _)| {
+                    *actuator_id == actuator.id
+                        && actuator.operation_modes.iter().any(|m| m.id == *mode)
+                });
+                if !known {
+                    self.frbc = actuator
+                        .operation_modes
+                        .first()
+                        .map(|mode| (actuator.id.clone(), mode.id.clone(), 0.0));
+                }
+            }
+            Message::FrbcActuatorStatus(status) => {
+                if let Some((actuator_id, mode, factor)) = &mut self.frbc {
+                    if *actuator_id == status.actuator_id {
+                        *mode = status.active_operation_mode_id.clone();
+                        *factor = status.operation_mode_factor;
+                    }
+                }
+            }
+            Message::OmbcSystemDescription(description) => {
+                let known = self.ombc.as_ref().is_some_and(|(mode, _)| {
+                    description.operation_modes.iter().any(|m| m.id == *mode)
+                });
+                if !known {
+                    self.ombc = description
+                        .operation_modes
+                        .first()
+                        .map(|mode| (mode.id.clone(), 0.0));
+                }
+            }
+            Message::OmbcStatus(status) => {
+                self.ombc = Some((
+                    status.active_operation_mode_id.clone(),
+                    status.operation_mode_factor,
+                ));
+            }
+            Message::PebcPowerConstraints(constraints) => self.pebc = Some(constraints.clone()),
+            _ => {}
+        }
+    }
+
+    /// Returns an instruction that keeps the RM doing what it does, with its instruction ID, once the RM has told
+    /// enough about itself.
+    fn instruction(&self, control_type: ControlType) -> Option<(Id, Message)> {
+        let id = Id::generate();
+        let instruction: Message = match control_type {
+            ControlType::FillRateBasedControl => {
+                let (actuator_id, operation_mode, factor) = self.frbc.clone()?;
+                frbc::Instruction::new(
+                    false,
+                    actuator_id,
+                    Utc::now(),
+                    id.clone(),
+                    operation_mode,
+                    factor,
+                )
+                .into()
+            }
+            ControlType::OperationModeBasedControl => {
+                let (operation_mode, factor) = self.ombc.clone()?;
+                ombc::Instruction::new(false, Utc::now(), id.clone(), factor, operation_mode).into()
+            }
+            ControlType::PowerEnvelopeBasedControl => {
+                let constraints = self.pebc.as_ref()?;
+                pebc::Instruction::new(
+                    false,
+                    Utc::now(),
+                    id.clone(),
+                    constraints.id.clone(),
+                    widest_envelopes(constraints),
+                )
+                .into()
+            }
+            _ => return None,
+        };
+        Some((id, instruction))
+    }
+}
+
+/// Power envelopes with the widest limits the power constraints allow, which don't restrict the RM.
+fn widest_envelopes(constraints: &pebc::PowerConstraints) -> Vec<pebc::PowerEnvelope> {
+    let mut commodity_quantities: Vec<CommodityQuantity> = Vec::new();
+    for range in &constraints.allowed_limit_ranges {
+        if !commodity_quantities.contains(&range.commodity_quantity) {
+            commodity_quantities.push(range.commodity_quantity);
+        }
+    }
+    commodity_quantities
+        .into_iter()
+        .filter_map(|commodity_quantity| {
+            let limits = |limit_type: pebc::PowerEnvelopeLimitType| {
+                constraints
+                    .allowed_limit_ranges
+                    .iter()
+                    .filter(move |range| {
+                        range.commodity_quantity == commodity_quantity
+                            && range.limit_type == limit_type
+                            && !range.abnormal_condition_only
+                    })
+            };
+            let lower_limit = limits(pebc::PowerEnvelopeLimitType::LowerLimit)
+                .map(|range| {
+                    range
+                        .range_boundary
+                        .start_of_range
+                        .min(range.range_boundary.end_of_range)
+                })
+                .reduce(f64::min)?;
+            let upper_limit = limits(pebc::PowerEnvelopeLimitType::UpperLimit)
+                .map(|range| {
+                    range
+                        .range_boundary
+                        .start_of_range
+                        .max(range.range_boundary.end_of_range)
+                })
+                .reduce(f64::max)?;
+            (lower_limit <= upper_limit).then(|| pebc::PowerEnvelope {
+                commodity_quantity,
+                id: Id::generate(),
+                power_envelope_elements: vec![pebc::PowerEnvelopeElement {
+                    duration: s2energy::common::Duration(ENVELOPE_DURATION_MS),
+                    lower_limit,
+                    upper_limit,
+                }],
+            })
+        })
+        .collect()
+}
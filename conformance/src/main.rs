@@ -0,0 +1,57 @@
+use clap::Parser;
+use conformance::{ControlTypeArg, Options, RmConnection};
+use eyre::Context;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Checks whether an S2 resource manager follows the protocol, by acting as a CEM for it.
+///
+/// This waits for a single RM to connect, runs all checks, prints a report and exits with a non-zero status if any
+/// check failed.
+#[derive(Parser, Debug)]
+#[command(name = "s2-conformance", version)]
+struct Cli {
+    /// The address to listen on for the RM.
+    #[arg(long, env = "LISTEN_ADDRESS", default_value = "0.0.0.0:8080")]
+    listen: String,
+    /// The control type to select [default: the first one the RM offers]
+    #[arg(long, env = "CONTROL_TYPE", value_enum)]
+    control_type: Option<ControlTypeArg>,
+    /// How long to wait for a response from the RM, in seconds.
+    #[arg(long, env = "TIMEOUT", default_value_t = 10)]
+    timeout: u64,
+    /// Also send malformed, out-of-order and invalid messages, and check that the RM rejects them gracefully.
+    #[arg(long, env = "ROBUSTNESS")]
+    robustness: bool,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<ExitCode> {
+    tracing_subscriber::fmt().init();
+    let cli = Cli::parse();
+    let options = Options {
+        control_type: cli.control_type.map(Into::into),
+        timeout: Duration::from_secs(cli.timeout),
+        robustness: cli.robustness,
+    };
+
+    let listener = TcpListener::bind(&cli.listen)
+        .await
+        .wrap_err_with(|| format!("Could not listen on {}", cli.listen))?;
+    tracing::info!("Waiting for an RM to connect on ws://{}", cli.listen);
+    let (stream, address) = listener.accept().await?;
+    let socket = tokio_tungstenite::accept_async(stream)
+        .await
+        .wrap_err("Could not set up a WebSocket connection with the RM")?;
+    tracing::info!("RM connected from {address}, running checks");
+
+    let mut connection = RmConnection::new(socket);
+    let report = conformance::run_checks(&mut connection, &options).await?;
+    println!("{report}");
+    if report.has_failures() {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
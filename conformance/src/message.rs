@@ -0,0 +1,83 @@
+use s2energy::common::Message;
+use s2energy::common::{
+    Handshake, HandshakeResponse, InstructionStatusUpdate, PowerForecast, PowerMeasurement,
+    ReceptionStatus, ResourceManagerDetails, RevokeObject, SelectControlType, SessionRequest,
+};
+use s2energy::{ddbc, frbc, ombc, pebc, ppbc};
+
+/// A single kind of S2 message, such as [`frbc::SystemDescription`].
+///
+/// This lets [`MockCem::expect_message`](crate::MockCem::expect_message) pick messages of a given type out of the
+/// [`Message`] enum.
+pub trait S2Message: Into<Message> + Sized {
+    /// The `message_type` of this kind of message, such as `FRBC.SystemDescription`.
+    const MESSAGE_TYPE: &'static str;
+
+    /// Returns a reference to the message if it is of this kind.
+    fn from_message_ref(message: &Message) -> Option<&Self>;
+
+    /// Returns the message if it is of this kind.
+    fn from_message(message: Message) -> Option<Self>;
+}
+
+macro_rules! s2_messages {
+    ($($message_type:literal => $variant:ident($type:ty),)*) => {
+        $(
+            impl S2Message for $type {
+                const MESSAGE_TYPE: &'static str = $message_type;
+
+                fn from_message_ref(message: &Message) -> Option<&Self> {
+                    match message {
+                        Message::$variant(message) => Some(message),
+                        _ => None,
+                    }
+                }
+
+                fn from_message(message: Message) -> Option<Self> {
+                    match message {
+                        Message::$variant(message) => Some(message),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+s2_messages! {
+    "Handshake" => Handshake(Handshake),
+    "HandshakeResponse" => HandshakeResponse(HandshakeResponse),
+    "ResourceManagerDetails" => ResourceManagerDetails(ResourceManagerDetails),
+    "SelectControlType" => SelectControlType(SelectControlType),
+    "SessionRequest" => SessionRequest(SessionRequest),
+    "ReceptionStatus" => ReceptionStatus(ReceptionStatus),
+    "RevokeObject" => RevokeObject(RevokeObject),
+    "InstructionStatusUpdate" => InstructionStatusUpdate(InstructionStatusUpdate),
+    "PowerMeasurement" => PowerMeasurement(PowerMeasurement),
+    "PowerForecast" => PowerForecast(PowerForecast),
+    "FRBC.SystemDescription" => FrbcSystemDescription(frbc::SystemDescription),
+    "FRBC.ActuatorStatus" => FrbcActuatorStatus(frbc::ActuatorStatus),
+    "FRBC.StorageStatus" => FrbcStorageStatus(frbc::StorageStatus),
+    "FRBC.LeakageBehaviour" => FrbcLeakageBehaviour(frbc::LeakageBehaviour),
+    "FRBC.UsageForecast" => FrbcUsageForecast(frbc::UsageForecast),
+    "FRBC.FillLevelTargetProfile" => FrbcFillLevelTargetProfile(frbc::FillLevelTargetProfile),
+    "FRBC.TimerStatus" => FrbcTimerStatus(frbc::TimerStatus),
+    "FRBC.Instruction" => FrbcInstruction(frbc::Instruction),
+    "OMBC.SystemDescription" => OmbcSystemDescription(ombc::SystemDescription),
+    "OMBC.Status" => OmbcStatus(ombc::Status),
+    "OMBC.TimerStatus" => OmbcTimerStatus(ombc::TimerStatus),
+    "OMBC.Instruction" => OmbcInstruction(ombc::Instruction),
+    "PEBC.PowerConstraints" => PebcPowerConstraints(pebc::PowerConstraints),
+    "PEBC.EnergyConstraint" => PebcEnergyConstraint(pebc::EnergyConstraint),
+    "PEBC.Instruction" => PebcInstruction(pebc::Instruction),
+    "PPBC.PowerProfileDefinition" => PpbcPowerProfileDefinition(ppbc::PowerProfileDefinition),
+    "PPBC.PowerProfileStatus" => PpbcPowerProfileStatus(ppbc::PowerProfileStatus),
+    "PPBC.ScheduleInstruction" => PpbcScheduleInstruction(ppbc::ScheduleInstruction),
+    "PPBC.StartInterruptionInstruction" => PpbcStartInterruptionInstruction(ppbc::StartInterruptionInstruction),
+    "PPBC.EndInterruptionInstruction" => PpbcEndInterruptionInstruction(ppbc::EndInterruptionInstruction),
+    "DDBC.SystemDescription" => DdbcSystemDescription(ddbc::SystemDescription),
+    "DDBC.ActuatorStatus" => DdbcActuatorStatus(ddbc::ActuatorStatus),
+    "DDBC.AverageDemandRateForecast" => DdbcAverageDemandRateForecast(ddbc::AverageDemandRateForecast),
+    "DDBC.TimerStatus" => DdbcTimerStatus(ddbc::TimerStatus),
+    "DDBC.Instruction" => DdbcInstruction(ddbc::Instruction),
+}
@@ -0,0 +1,293 @@
+use crate::message::S2Message;
+use cem_connection::RmConnection;
+use chrono::Utc;
+use eyre::{bail, eyre, Context};
+use s2energy::common::{
+    ControlType, EnergyManagementRole, Handshake, HandshakeResponse, Id, InstructionStatus,
+    InstructionStatusUpdate, Message, ReceptionStatusValues, ResourceManagerDetails,
+    SelectControlType, SessionRequest, SessionRequestType,
+};
+use s2energy::{frbc, ombc, pebc};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::time::Instant;
+
+/// A CEM for integration tests of resource managers.
+///
+/// The mock CEM listens for a single RM and lets a test script the session: it sends messages on request, and waits
+/// for the messages the test expects. Like a real CEM, it acknowledges every message the RM sends with a reception
+/// status. Every expectation fails with an error if the RM doesn't send the message within the timeout.
+///
+/// ```no_run
+/// # async fn example() -> eyre::Result<()> {
+/// use conformance::MockCem;
+/// use s2energy::common::ControlType;
+/// use s2energy::frbc;
+///
+/// let mut cem = MockCem::bind("127.0.0.1:0").await?;
+/// // Start your RM here, connecting to `cem.url()`
+/// cem.accept().await?;
+/// cem.handshake().await?;
+/// cem.select_control_type(ControlType::FillRateBasedControl).await?;
+/// let description = cem.expect_message::<frbc::SystemDescription>().await?;
+/// let actuator = &description.actuators[0];
+/// let instruction_id = cem
+///     .send_frbc_instruction(actuator.id.clone(), actuator.operation_modes[0].id.clone(), 1.0)
+///     .await?;
+/// cem.expect_instruction_status(&instruction_id).await?;
+/// cem.terminate().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MockCem {
+    listener: TcpListener,
+    connection: Option<RmConnection>,
+    /// The messages the RM sent that no expectation has returned yet, in the order they were received.
+    unexpected: Vec<Message>,
+    timeout: Duration,
+}
+
+impl MockCem {
+    /// Listens for an RM on the given address; use port 0 to pick a free port.
+    pub async fn bind(address: impl ToSocketAddrs) -> eyre::Result<Self> {
+        let listener = TcpListener::bind(address)
+            .await
+            .wrap_err("Could not start the mock CEM")?;
+        Ok(Self {
+            listener,
+            connection: None,
+            unexpected: Vec::new(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// Sets how long expectations wait for the RM [default: 10 seconds].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the address the mock CEM listens on.
+    pub fn local_addr(&self) -> eyre::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Returns the WebSocket URL the RM should connect to.
+    pub fn url(&self) -> eyre::Result<String> {
+        Ok(format!("ws://{}", self.local_addr()?))
+    }
+
+    /// Waits for the RM to connect.
+    pub async fn accept(&mut self) -> eyre::Result<()> {
+        let (stream, _) = tokio::time::timeout(self.timeout, self.listener.accept())
+            .await
+            .map_err(|_| eyre!("The RM didn't connect within {:?}", self.timeout))??;
+        let socket = tokio_tungstenite::accept_async(stream)
+            .await
+            .wrap_err("Could not set up a WebSocket connection with the RM")?;
+        self.connection = Some(RmConnection::new(socket));
+        self.unexpected.clear();
+        Ok(())
+    }
+
+    /// Performs the handshake as a CEM, and returns the details of the RM.
+    pub async fn handshake(&mut self) -> eyre::Result<ResourceManagerDetails> {
+        let handshake: Handshake = self.expect_message().await?;
+        if handshake.role != EnergyManagementRole::Rm {
+            bail!("The RM sent a Handshake with role {:?}", handshake.role);
+        }
+        let version = s2energy::s2_schema_version().to_string();
+        self.send(Handshake::new(
+            EnergyManagementRole::Cem,
+            vec![version.clone()],
+        ))
+        .await?;
+        self.send(HandshakeResponse::new(version)).await?;
+        self.expect_message().await
+    }
+
+    /// Selects the control type the RM should use.
+    pub async fn select_control_type(&mut self, control_type: ControlType) -> eyre::Result<()> {
+        self.send(SelectControlType::new(control_type)).await?;
+        Ok(())
+    }
+
+    /// Sends a message to the RM, and returns its message ID.
+    pub async fn send(&mut self, message: impl Into<Message>) -> eyre::Result<Option<Id>> {
+        self.connection()?.send(message).await
+    }
+
+    /// Sends an FRBC instruction that should be executed right away, and returns its instruction ID.
+    pub async fn send_frbc_instruction(
+        &mut self,
+        actuator_id: Id,
+        operation_mode: Id,
+        operation_mode_factor: f64,
+    ) -> eyre::Result<Id> {
+        let id = Id::generate();
+        self.send(frbc::Instruction::new(
+            false,
+            actuator_id,
+            Utc::now(),
+            id.clone(),
+            operation_mode,
+            operation_mode_factor,
+        ))
+        .await?;
+        Ok(id)
+    }
+
+    /// Sends an OMBC instruction that should be executed right away, and returns its instruction ID.
+    pub async fn send_ombc_instruction(
+        &mut self,
+        operation_mode: Id,
+        operation_mode_factor: f64,
+    ) -> eyre::Result<Id> {
+        let id = Id::generate();
+        self.send(ombc::Instruction::new(
+            false,
+            Utc::now(),
+            id.clone(),
+            operation_mode_factor,
+            operation_mode,
+        ))
+        .await?;
+        Ok(id)
+    }
+
+    /// Sends a PEBC instruction that should be executed right away, and returns its instruction ID.
+    pub async fn send_pebc_instruction(
+        &mut self,
+        power_constraints_id: Id,
+        power_envelopes: Vec<pebc::PowerEnvelope>,
+    ) -> eyre::Result<Id> {
+        let id = Id::generate();
+        self.send(pebc::Instruction::new(
+            false,
+            Utc::now(),
+            id.clone(),
+            power_constraints_id,
+            power_envelopes,
+        ))
+        .await?;
+        Ok(id)
+    }
+
+    /// Waits for the next message of the given type that the RM sends, or returns the first one it already sent.
+    ///
+    /// Messages of other types are kept for later expectations.
+    pub async fn expect_message<T: S2Message>(&mut self) -> eyre::Result<T> {
+        self.expect_message_where(|_: &T| true).await
+    }
+
+    /// Like [`expect_message`](Self::expect_message), but only for messages for which `condition` holds.
+    pub async fn expect_message_where<T: S2Message>(
+        &mut self,
+        condition: impl Fn(&T) -> bool,
+    ) -> eyre::Result<T> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let connection = self.connection.as_mut().ok_or_else(not_connected)?;
+            self.unexpected.extend(
+                connection
+                    .received
+                    .drain(..)
+                    .map(|received| received.message),
+            );
+            let index = self
+                .unexpected
+                .iter()
+                .position(|message| T::from_message_ref(message).is_some_and(&condition));
+            if let Some(index) = index {
+                let message = T::from_message(self.unexpected.remove(index));
+                return Ok(message.expect("the message was just checked to be of this type"));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let received = connection
+                .wait_until(remaining, |connection| !connection.received.is_empty())
+                .await?;
+            if !received {
+                let reason = if connection.closed {
+                    "the RM closed the connection".into()
+                } else {
+                    format!("it didn't arrive within {:?}", self.timeout)
+                };
+                bail!("Expected a {} message, but {reason}", T::MESSAGE_TYPE);
+            }
+        }
+    }
+
+    /// Waits for a status update for the given instruction, and returns its status.
+    pub async fn expect_instruction_status(
+        &mut self,
+        instruction_id: &Id,
+    ) -> eyre::Result<InstructionStatus> {
+        let update = self
+            .expect_message_where(|update: &InstructionStatusUpdate| {
+                update.instruction_id == *instruction_id
+            })
+            .await
+            .wrap_err_with(|| format!("No status update for instruction {instruction_id:?}"))?;
+        Ok(update.status_type)
+    }
+
+    /// Waits for the reception status of the message with the given ID, and returns it.
+    pub async fn expect_reception_status(
+        &mut self,
+        message_id: &Id,
+    ) -> eyre::Result<ReceptionStatusValues> {
+        let timeout = self.timeout;
+        let connection = self.connection()?;
+        connection
+            .wait_until(timeout, |connection| {
+                connection.reception_statuses.contains_key(message_id)
+            })
+            .await?;
+        connection
+            .reception_statuses
+            .get(message_id)
+            .cloned()
+            .ok_or_else(|| {
+                eyre!("No reception status for message {message_id:?} within {timeout:?}")
+            })
+    }
+
+    /// Returns the messages the RM sent that no expectation has returned yet, and forgets about them.
+    pub fn take_unexpected(&mut self) -> Vec<Message> {
+        if let Some(connection) = &mut self.connection {
+            self.unexpected.extend(
+                connection
+                    .received
+                    .drain(..)
+                    .map(|received| received.message),
+            );
+        }
+        std::mem::take(&mut self.unexpected)
+    }
+
+    /// Terminates the session and closes the connection.
+    pub async fn terminate(&mut self) -> eyre::Result<()> {
+        let mut connection = self.connection.take().ok_or_else(not_connected)?;
+        if !connection.closed {
+            connection
+                .send(SessionRequest {
+                    diagnostic_label: None,
+                    message_id: Id::generate(),
+                    request: SessionRequestType::Terminate,
+                })
+                .await?;
+            connection.close().await;
+        }
+        Ok(())
+    }
+
+    fn connection(&mut self) -> eyre::Result<&mut RmConnection> {
+        self.connection.as_mut().ok_or_else(not_connected)
+    }
+}
+
+fn not_connected() -> eyre::Report {
+    eyre!("No RM is connected to the mock CEM; call `accept` first")
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+/// The outcome of a single check.
+#[derive(Debug)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The check doesn't apply to this RM, or couldn't be done because an earlier step failed.
+    Skip(String),
+}
+
+/// The results of all checks on an RM.
+#[derive(Debug, Default)]
+pub struct Report {
+    results: Vec<(String, Outcome)>,
+}
+
+impl Report {
+    pub fn add(&mut self, check: impl Into<String>, outcome: Outcome) {
+        self.results.push((check.into(), outcome));
+    }
+
+    /// Adds a check that passes if `passed` is true, and otherwise fails for the given reason.
+    pub fn check(
+        &mut self,
+        check: impl Into<String>,
+        passed: bool,
+        reason: impl FnOnce() -> String,
+    ) {
+        let outcome = if passed {
+            Outcome::Pass
+        } else {
+            Outcome::Fail(reason())
+        };
+        self.add(check, outcome);
+    }
+
+    pub fn skip(&mut self, check: impl Into<String>, reason: impl Into<String>) {
+        self.add(check, Outcome::Skip(reason.into()));
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.results
+            .iter()
+            .any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+        for (check, outcome) in &self.results {
+            match outcome {
+                Outcome::Pass => {
+                    passed += 1;
+                    writeln!(f, "PASS  {check}")?;
+                }
+                Outcome::Fail(reason) => {
+                    failed += 1;
+                    writeln!(f, "FAIL  {check}\n      {reason}")?;
+                }
+                Outcome::Skip(reason) => {
+                    skipped += 1;
+                    writeln!(f, "SKIP  {check}\n      {reason}")?;
+                }
+            }
+        }
+        write!(f, "\n{passed} passed, {failed} failed, {skipped} skipped")
+    }
+}
@@ -0,0 +1,290 @@
+use crate::checks::{current_frbc_state, current_ombc_state, instruction_statuses, Options, Sent};
+use crate::report::{Outcome, Report};
+use cem_connection::RmConnection;
+use chrono::Utc;
+use s2energy::common::{
+    ControlType, EnergyManagementRole, Handshake, HandshakeResponse, Id, InstructionStatus,
+    Message, ReceptionStatus, ReceptionStatusValues,
+};
+use s2energy::{frbc, ombc, pebc};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// How long to wait before checking that the RM is still connected after a message without an ID.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Sends malformed, out-of-order and semantically invalid messages, and checks that the RM rejects them and keeps
+/// the session going.
+pub(crate) async fn check_robustness(
+    connection: &mut RmConnection,
+    options: &Options,
+    control_type: ControlType,
+    report: &mut Report,
+    sent: &mut Sent,
+) -> eyre::Result<()> {
+    // Messages that aren't JSON have no ID, so the RM can only ignore them.
+    connection
+        .send_text(r#"{"message_type": "FRBC.Instruction", "message_id": "#)
+        .await;
+    connection.receive_for(SETTLE_TIME).await?;
+    report.check(
+        "The RM survives a message that isn't valid JSON",
+        !connection.closed,
+        || "The RM closed the connection".into(),
+    );
+
+    let id = Id::generate();
+    let unknown_type = json!({"message_type": "FRBC.Teleport", "message_id": id});
+    check_invalid_message(
+        connection,
+        options,
+        report,
+        "an unknown message type",
+        &id,
+        unknown_type,
+    )
+    .await?;
+
+    let id = Id::generate();
+    let missing_fields = json!({"message_type": "FRBC.Instruction", "message_id": id});
+    check_invalid_message(
+        connection,
+        options,
+        report,
+        "an instruction without its required fields",
+        &id,
+        missing_fields,
+    )
+    .await?;
+
+    let mut nan_factor = to_json(frbc::Instruction::new(
+        false,
+        Id::generate(),
+        Utc::now(),
+        Id::generate(),
+        Id::generate(),
+        0.5,
+    ))?;
+    nan_factor["operation_mode_factor"] = json!("NaN");
+    check_invalid_message(
+        connection,
+        options,
+        report,
+        "an instruction with a NaN factor",
+        &message_id(&nan_factor),
+        nan_factor,
+    )
+    .await?;
+
+    let mut negative_duration = to_json(pebc::Instruction::new(
+        false,
+        Utc::now(),
+        Id::generate(),
+        Id::generate(),
+        vec![pebc::PowerEnvelope {
+            commodity_quantity: s2energy::common::CommodityQuantity::ElectricPowerL1,
+            id: Id::generate(),
+            power_envelope_elements: vec![pebc::PowerEnvelopeElement {
+                duration: s2energy::common::Duration(1000),
+                lower_limit: 0.0,
+                upper_limit: 0.0,
+            }],
+        }],
+    ))?;
+    negative_duration["power_envelopes"][0]["power_envelope_elements"][0]["duration"] =
+        json!(-1000);
+    check_invalid_message(
+        connection,
+        options,
+        report,
+        "an instruction with a negative duration",
+        &message_id(&negative_duration),
+        negative_duration,
+    )
+    .await?;
+
+    // The handshake is over, so these messages are out of order; the RM may ignore them, but should acknowledge them.
+    let version = s2energy::s2_schema_version().to_string();
+    let id = connection
+        .send(Handshake::new(
+            EnergyManagementRole::Cem,
+            vec![version.clone()],
+        ))
+        .await?;
+    sent.allow_errors(id, "a Handshake during the session");
+    let id = connection.send(HandshakeResponse::new(version)).await?;
+    sent.allow_errors(id, "a HandshakeResponse during the session");
+    connection
+        .send(ReceptionStatus::new(
+            Some("Reception status for a message that was never sent".into()),
+            ReceptionStatusValues::InvalidData,
+            Id::generate(),
+        ))
+        .await?;
+    connection.receive_for(SETTLE_TIME).await?;
+    report.check(
+        "The RM survives messages that are out of order",
+        !connection.closed,
+        || "The RM closed the connection".into(),
+    );
+
+    check_invalid_instructions(connection, options, control_type, report, sent).await?;
+
+    // After all this, the RM should still be in sync with the CEM.
+    let check = "The RM still follows valid instructions afterwards";
+    let instruction = match control_type {
+        ControlType::FillRateBasedControl => {
+            current_frbc_state(connection).map(|(actuator_id, operation_mode, factor)| {
+                let id = Id::generate();
+                let instruction = frbc::Instruction::new(
+                    false,
+                    actuator_id,
+                    Utc::now(),
+                    id.clone(),
+                    operation_mode,
+                    factor,
+                );
+                (id, Message::from(instruction))
+            })
+        }
+        ControlType::OperationModeBasedControl => {
+            current_ombc_state(connection).map(|(operation_mode, factor)| {
+                let id = Id::generate();
+                let instruction =
+                    ombc::Instruction::new(false, Utc::now(), id.clone(), factor, operation_mode);
+                (id, Message::from(instruction))
+            })
+        }
+        _ => None,
+    };
+    match instruction {
+        Some((id, instruction)) => {
+            let message_id = connection.send(instruction).await?;
+            sent.expect_ok(message_id, "a valid instruction after invalid messages");
+            let statuses = instruction_statuses(connection, options, &id).await?;
+            report.check(
+                check,
+                statuses.iter().any(|status| {
+                    !matches!(
+                        status,
+                        InstructionStatus::Rejected | InstructionStatus::Aborted
+                    )
+                }),
+                || format!("The RM sent the statuses {statuses:?}"),
+            );
+        }
+        None => report.check(check, !connection.closed, || {
+            "The RM closed the connection".into()
+        }),
+    }
+    Ok(())
+}
+
+/// Sends instructions that are valid S2, but can't be executed.
+async fn check_invalid_instructions(
+    connection: &mut RmConnection,
+    options: &Options,
+    control_type: ControlType,
+    report: &mut Report,
+    sent: &mut Sent,
+) -> eyre::Result<()> {
+    let mut instructions: Vec<(&str, Id, Message)> = Vec::new();
+    match control_type {
+        ControlType::FillRateBasedControl => {
+            if let Some((actuator_id, operation_mode, _)) = current_frbc_state(connection) {
+                let id = Id::generate();
+                let instruction = frbc::Instruction::new(
+                    false,
+                    Id::generate(),
+                    Utc::now(),
+                    id.clone(),
+                    operation_mode.clone(),
+                    0.0,
+                );
+                instructions.push(("an unknown actuator", id, instruction.into()));
+                let id = Id::generate();
+                let instruction = frbc::Instruction::new(
+                    false,
+                    actuator_id,
+                    Utc::now(),
+                    id.clone(),
+                    operation_mode,
+                    2.5,
+                );
+                instructions.push(("an operation mode factor of 2.5", id, instruction.into()));
+            }
+        }
+        ControlType::OperationModeBasedControl => {
+            if let Some((operation_mode, _)) = current_ombc_state(connection) {
+                let id = Id::generate();
+                let instruction =
+                    ombc::Instruction::new(false, Utc::now(), id.clone(), -1.0, operation_mode);
+                instructions.push(("an operation mode factor of -1", id, instruction.into()));
+            }
+        }
+        ControlType::PowerEnvelopeBasedControl => {
+            let id = Id::generate();
+            let instruction =
+                pebc::Instruction::new(false, Utc::now(), id.clone(), Id::generate(), vec![]);
+            instructions.push(("unknown power constraints", id, instruction.into()));
+        }
+        _ => {}
+    }
+    if instructions.is_empty() {
+        report.skip(
+            "The RM rejects instructions it can't execute",
+            format!("No invalid instructions for {control_type:?} yet"),
+        );
+    }
+
+    for (description, id, instruction) in instructions {
+        let check = format!("The RM rejects an instruction with {description}");
+        let message_id = connection.send(instruction).await?;
+        sent.allow_errors(message_id.clone(), "an instruction that can't be executed");
+        let statuses = instruction_statuses(connection, options, &id).await?;
+        let rejected_on_reception = message_id
+            .and_then(|id| connection.reception_statuses.get(&id))
+            .is_some_and(|status| *status != ReceptionStatusValues::Ok);
+        report.check(
+            check,
+            statuses.contains(&InstructionStatus::Rejected) || rejected_on_reception,
+            || format!("The RM sent the statuses {statuses:?}"),
+        );
+    }
+    Ok(())
+}
+
+/// Sends a message that isn't valid S2 as JSON, and checks that the RM responds with an error reception status.
+async fn check_invalid_message(
+    connection: &mut RmConnection,
+    options: &Options,
+    report: &mut Report,
+    description: &str,
+    id: &Id,
+    message: Value,
+) -> eyre::Result<()> {
+    let check = format!("The RM rejects {description} with a reception status");
+    connection.send_text(&message.to_string()).await;
+    connection
+        .wait_until(options.timeout, |connection| {
+            connection.reception_statuses.contains_key(id)
+        })
+        .await?;
+    let outcome = match connection.reception_statuses.get(id) {
+        Some(ReceptionStatusValues::Ok) => Outcome::Fail("The RM reported status Ok".into()),
+        Some(_) => Outcome::Pass,
+        None if connection.closed => Outcome::Fail("The RM closed the connection".into()),
+        None => Outcome::Fail(format!("No reception status within {:?}", options.timeout)),
+    };
+    report.add(check, outcome);
+    Ok(())
+}
+
+fn to_json(message: impl Into<Message>) -> eyre::Result<Value> {
+    Ok(serde_json::to_value(message.into())?)
+}
+
+fn message_id(message: &Value) -> Id {
+    serde_json::from_value(message["message_id"].clone())
+        .expect("messages serialized from s2energy types have a valid message ID")
+}
@@ -0,0 +1,500 @@
+use crate::report::{Outcome, Report};
+use cem_connection::RmConnection;
+use chrono::{SecondsFormat, Utc};
+use eyre::{bail, eyre, Context};
+use s2energy::common::{
+    ControlType, EnergyManagementRole, Handshake, HandshakeResponse, Id, Message,
+    ReceptionStatusValues, SelectControlType, SessionRequest, SessionRequestType,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A script of steps to run against an RM, as a CEM, read from YAML.
+///
+/// Every step does one thing:
+///
+/// - `expect: FRBC.ActuatorStatus` waits for a message of that type (`within` a duration, or the timeout), optionally
+///   `matching` a JSON object: the message must have the same values for the fields in it. Every message satisfies
+///   one expectation at most, the oldest first, so messages the RM sent before the step count too.
+/// - `send: {message_type: FRBC.Instruction, ...}` sends a message, `at` a time after the session was set up if given,
+///   and waits for the RM to acknowledge it with a reception status of OK. The message gets a `message_id` if it
+///   doesn't have one.
+/// - `wait: 30s` waits, while acknowledging what the RM sends.
+///
+/// Durations are seconds, or a number followed by `ms`, `s`, `m` or `h`. Strings in messages to send and to match can
+/// refer to `{{uuid}}`, a new ID, `{{now}}`, the current time, `{{sent.<field>}}`, a field of the message sent last,
+/// and `{{<message type>.<field>}}`, a field of the latest message of that type the RM sent, such as
+/// `{{FRBC.SystemDescription.actuators.0.id}}`. A step can have a `name` for the report.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    name: Option<String>,
+    expect: Option<String>,
+    matching: Option<Value>,
+    within: Option<ScriptDuration>,
+    send: Option<Value>,
+    at: Option<ScriptDuration>,
+    wait: Option<ScriptDuration>,
+}
+
+/// What a step does, once it's checked that it does one thing.
+enum Action<'a> {
+    Expect {
+        message_type: &'a str,
+        matching: Option<&'a Value>,
+        within: Option<Duration>,
+    },
+    Send {
+        message: &'a Value,
+        at: Option<Duration>,
+    },
+    Wait(Duration),
+}
+
+/// A duration in a script: seconds, or a string like `5m`.
+#[derive(Debug, Clone, Copy)]
+struct ScriptDuration(Duration);
+
+impl<'de> Deserialize<'de> for ScriptDuration {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(f64),
+            Text(String),
+        }
+        let seconds = match Raw::deserialize(deserializer)? {
+            Raw::Seconds(seconds) => seconds,
+            Raw::Text(text) => parse_duration(&text).map_err(serde::de::Error::custom)?,
+        };
+        Duration::try_from_secs_f64(seconds)
+            .map(ScriptDuration)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parses a duration like `500ms`, `10s`, `5m` or `1h` into seconds.
+fn parse_duration(text: &str) -> Result<f64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|character: char| !(character.is_ascii_digit() || character == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("`{text}` isn't a duration like 10s or 5m"))?;
+    let unit = match unit.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        unit => {
+            return Err(format!(
+                "Unknown unit `{unit}` in `{text}`; use ms, s, m or h"
+            ))
+        }
+    };
+    Ok(number * unit)
+}
+
+impl Script {
+    /// Reads a script from YAML, and checks that every step does one thing.
+    pub fn from_yaml(yaml: &str) -> eyre::Result<Self> {
+        let script: Self = serde_yaml::from_str(yaml).wrap_err("The script isn't valid")?;
+        for (index, step) in script.steps.iter().enumerate() {
+            step.action()
+                .wrap_err_with(|| format!("Step {} isn't valid", index + 1))?;
+        }
+        Ok(script)
+    }
+}
+
+impl Step {
+    fn action(&self) -> eyre::Result<Action<'_>> {
+        let action = match (&self.expect, &self.send, &self.wait) {
+            (Some(message_type), None, None) => Action::Expect {
+                message_type,
+                matching: self.matching.as_ref(),
+                within: self.within.map(|within| within.0),
+            },
+            (None, Some(message), None) => Action::Send {
+                message,
+                at: self.at.map(|at| at.0),
+            },
+            (None, None, Some(wait)) => Action::Wait(wait.0),
+            _ => bail!("A step needs exactly one of `expect`, `send` and `wait`"),
+        };
+        if self.expect.is_none() && (self.matching.is_some() || self.within.is_some()) {
+            bail!("Only an `expect` step can have `matching` and `within`");
+        }
+        if self.send.is_none() && self.at.is_some() {
+            bail!("Only a `send` step can have `at`");
+        }
+        Ok(action)
+    }
+}
+
+impl fmt::Display for Action<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Expect {
+                message_type,
+                within,
+                ..
+            } => {
+                write!(f, "The RM sends {message_type}")?;
+                if let Some(within) = within {
+                    write!(f, " within {within:?}")?;
+                }
+                Ok(())
+            }
+            Action::Send { message, at } => {
+                let message_type = message["message_type"].as_str().unwrap_or("a message");
+                write!(f, "The RM accepts {message_type}")?;
+                if let Some(at) = at {
+                    write!(f, " sent at t+{at:?}")?;
+                }
+                Ok(())
+            }
+            Action::Wait(duration) => write!(f, "Wait {duration:?}"),
+        }
+    }
+}
+
+/// What the script has seen of the session so far.
+#[derive(Default)]
+struct Session {
+    /// The messages the RM sent that no expectation took yet, as JSON, in the order they were received.
+    pending: Vec<Value>,
+    /// The latest message of every type the RM sent, by message type.
+    latest: HashMap<String, Value>,
+    /// The message the script sent last.
+    sent: Option<Value>,
+}
+
+impl Session {
+    fn take_received(&mut self, connection: &mut RmConnection) -> eyre::Result<()> {
+        for received in connection.received.drain(..) {
+            let json = serde_json::to_value(&received.message)?;
+            self.latest.insert(received.message_type, json.clone());
+            self.pending.push(json);
+        }
+        Ok(())
+    }
+
+    /// Replaces the placeholders in the strings in `value`.
+    fn resolve(&self, value: &Value) -> Result<Value, String> {
+        Ok(match value {
+            Value::String(text) => self.resolve_text(text)?,
+            Value::Array(values) => Value::Array(
+                values
+                    .iter()
+                    .map(|value| self.resolve(value))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), self.resolve(value)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+            value => value.clone(),
+        })
+    }
+
+    /// A string that's only a placeholder becomes the value it refers to, such as a number; otherwise the placeholders
+    /// are replaced by text.
+    fn resolve_text(&self, text: &str) -> Result<Value, String> {
+        let whole = text
+            .strip_prefix("{{")
+            .and_then(|rest| rest.strip_suffix("}}"))
+            .filter(|inner| !inner.contains("{{"));
+        if let Some(placeholder) = whole {
+            return self.placeholder(placeholder.trim());
+        }
+        let mut resolved = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("`{text}` has a `{{{{` without `}}}}`"))?;
+            resolved.push_str(&rest[..start]);
+            match self.placeholder(rest[start + 2..start + end].trim())? {
+                Value::String(value) => resolved.push_str(&value),
+                value => resolved.push_str(&value.to_string()),
+            }
+            rest = &rest[start + end + 2..];
+        }
+        resolved.push_str(rest);
+        Ok(Value::String(resolved))
+    }
+
+    fn placeholder(&self, placeholder: &str) -> Result<Value, String> {
+        match placeholder {
+            "uuid" => return Ok(new_id()),
+            "now" => {
+                let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+                return Ok(Value::String(now));
+            }
+            _ => {}
+        }
+        let (message, path) = match placeholder.strip_prefix("sent.") {
+            Some(path) => (self.sent.as_ref(), path),
+            None => {
+                // Message types have a dot themselves, like FRBC.SystemDescription, so the longest one that fits wins.
+                let message_type = self
+                    .latest
+                    .keys()
+                    .filter(|message_type| placeholder.starts_with(&format!("{message_type}.")))
+                    .max_by_key(|message_type| message_type.len())
+                    .ok_or_else(|| {
+                        format!("`{{{{{placeholder}}}}}` refers to no message the RM sent")
+                    })?;
+                (
+                    self.latest.get(message_type),
+                    &placeholder[message_type.len() + 1..],
+                )
+            }
+        };
+        let message =
+            message.ok_or_else(|| format!("`{{{{{placeholder}}}}}` refers to no message"))?;
+        path.split('.')
+            .try_fold(message, |value, key| match value {
+                Value::Array(values) => key
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| values.get(index)),
+                value => value.get(key),
+            })
+            .cloned()
+            .ok_or_else(|| format!("The message has no `{path}` for `{{{{{placeholder}}}}}`"))
+    }
+}
+
+fn new_id() -> Value {
+    serde_json::to_value(Id::generate()).expect("an ID is a string")
+}
+
+/// Whether `value` has the same values as `expected` for all fields in it.
+fn matches(value: &Value, expected: &Value) -> bool {
+    match (value, expected) {
+        (Value::Object(fields), Value::Object(expected)) => {
+            expected.iter().all(|(key, expected)| {
+                fields
+                    .get(key)
+                    .is_some_and(|field| matches(field, expected))
+            })
+        }
+        (Value::Number(number), Value::Number(expected)) => number.as_f64() == expected.as_f64(),
+        (value, expected) => value == expected,
+    }
+}
+
+/// Sets up a session with the RM on the other end of the connection, which has just connected, runs the steps of the
+/// script and returns a report with the outcome of every step.
+///
+/// After a step fails, the rest of the steps are skipped, since they usually depend on it.
+pub async fn run_script(
+    connection: &mut RmConnection,
+    script: &Script,
+    control_type: Option<ControlType>,
+    timeout: Duration,
+) -> eyre::Result<Report> {
+    let mut report = Report::default();
+    let setup = "The RM sets up a session";
+    let start = match set_up(connection, control_type, timeout).await {
+        Ok(control_type) => {
+            report.add(format!("{setup} with {control_type:?}"), Outcome::Pass);
+            Some(Instant::now())
+        }
+        Err(error) => {
+            report.add(setup, Outcome::Fail(format!("{error:#}")));
+            None
+        }
+    };
+
+    let mut session = Session::default();
+    let mut failed = start.is_none();
+    for (index, step) in script.steps.iter().enumerate() {
+        let action = step
+            .action()
+            .expect("the steps were checked when reading the script");
+        let label = format!(
+            "{}. {}",
+            index + 1,
+            step.name.clone().unwrap_or_else(|| action.to_string())
+        );
+        let Some(start) = start.filter(|_| !failed) else {
+            report.skip(label, "An earlier step failed");
+            continue;
+        };
+        let outcome = run_step(connection, &mut session, &action, start, timeout).await?;
+        failed = matches!(outcome, Outcome::Fail(_));
+        report.add(label, outcome);
+    }
+
+    if !connection.closed {
+        connection
+            .send(SessionRequest {
+                diagnostic_label: Some("The script finished".into()),
+                message_id: Id::generate(),
+                request: SessionRequestType::Terminate,
+            })
+            .await?;
+        connection.close().await;
+    }
+    Ok(report)
+}
+
+/// Performs the handshake and selects a control type, and returns the control type.
+async fn set_up(
+    connection: &mut RmConnection,
+    control_type: Option<ControlType>,
+    timeout: Duration,
+) -> eyre::Result<ControlType> {
+    let is_handshake = |connection: &RmConnection| {
+        connection
+            .received
+            .iter()
+            .any(|received| matches!(received.message, Message::Handshake(_)))
+    };
+    if !connection.wait_until(timeout, is_handshake).await? {
+        bail!("The RM sent no Handshake within {timeout:?}");
+    }
+    let version = s2energy::s2_schema_version().to_string();
+    connection
+        .send(Handshake::new(
+            EnergyManagementRole::Cem,
+            vec![version.clone()],
+        ))
+        .await?;
+    connection.send(HandshakeResponse::new(version)).await?;
+
+    let rm_details = |connection: &RmConnection| {
+        connection
+            .received
+            .iter()
+            .find_map(|received| match &received.message {
+                Message::ResourceManagerDetails(details) => Some(details.clone()),
+                _ => None,
+            })
+    };
+    connection
+        .wait_until(timeout, |connection| rm_details(connection).is_some())
+        .await?;
+    let rm_details = rm_details(connection)
+        .ok_or_else(|| eyre!("The RM sent no ResourceManagerDetails within {timeout:?}"))?;
+    let available = &rm_details.available_control_types;
+    let control_type = match control_type {
+        Some(control_type) if available.contains(&control_type) => control_type,
+        Some(control_type) => bail!("The RM doesn't offer {control_type:?}, only {available:?}"),
+        None => *available
+            .iter()
+            .find(|control_type| **control_type != ControlType::NoSelection)
+            .ok_or_else(|| eyre!("The RM offers no control type"))?,
+    };
+    connection
+        .send(SelectControlType::new(control_type))
+        .await?;
+    Ok(control_type)
+}
+
+async fn run_step(
+    connection: &mut RmConnection,
+    session: &mut Session,
+    action: &Action<'_>,
+    start: Instant,
+    timeout: Duration,
+) -> eyre::Result<Outcome> {
+    match action {
+        Action::Expect {
+            message_type,
+            matching,
+            within,
+        } => {
+            let within = within.unwrap_or(timeout);
+            let deadline = Instant::now() + within;
+            loop {
+                session.take_received(connection)?;
+                let matching = match matching.map(|matching| session.resolve(matching)) {
+                    Some(Ok(matching)) => Some(matching),
+                    Some(Err(problem)) => return Ok(Outcome::Fail(problem)),
+                    None => None,
+                };
+                let index = session.pending.iter().position(|message| {
+                    message["message_type"] == **message_type
+                        && matching
+                            .as_ref()
+                            .is_none_or(|matching| matches(message, matching))
+                });
+                if let Some(index) = index {
+                    session.pending.remove(index);
+                    return Ok(Outcome::Pass);
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let received = connection
+                    .wait_until(remaining, |connection| !connection.received.is_empty())
+                    .await?;
+                if !received {
+                    let reason = if connection.closed {
+                        "The RM closed the connection".into()
+                    } else {
+                        format!("No matching {message_type} within {within:?}")
+                    };
+                    return Ok(Outcome::Fail(reason));
+                }
+            }
+        }
+        Action::Send { message, at } => {
+            if let Some(at) = at {
+                connection
+                    .receive_for((start + *at).saturating_duration_since(Instant::now()))
+                    .await?;
+            }
+            session.take_received(connection)?;
+            let mut message = match session.resolve(message) {
+                Ok(message) => message,
+                Err(problem) => return Ok(Outcome::Fail(problem)),
+            };
+            if let Value::Object(fields) = &mut message {
+                fields.entry("message_id").or_insert_with(new_id);
+            }
+            let parsed: Message = match serde_json::from_value(message.clone()) {
+                Ok(parsed) => parsed,
+                Err(error) => {
+                    return Ok(Outcome::Fail(format!(
+                        "The message to send isn't valid S2: {error}"
+                    )))
+                }
+            };
+            session.sent = Some(message);
+            let Some(id) = connection.send(parsed).await? else {
+                return Ok(Outcome::Pass);
+            };
+            connection
+                .wait_until(timeout, |connection| {
+                    connection.reception_statuses.contains_key(&id)
+                })
+                .await?;
+            Ok(match connection.reception_statuses.get(&id) {
+                Some(ReceptionStatusValues::Ok) => Outcome::Pass,
+                Some(status) => Outcome::Fail(format!("The RM answered {status:?}")),
+                None => Outcome::Fail(format!("No reception status within {timeout:?}")),
+            })
+        }
+        Action::Wait(duration) => {
+            connection.receive_for(*duration).await?;
+            Ok(Outcome::Pass)
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use eyre::Context;
+use rcgen::{CertificateParams, DistinguishedName, DnType, IsCa, KeyIdMethod, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use sha1::{Digest, Sha1};
+use std::path::Path;
+use x509_parser::extensions::ParsedExtension;
+
+/// The certificate the gateway identifies itself with to the EEBus device, and its private key.
+pub struct Identity {
+    pub certificate: CertificateDer<'static>,
+    pub key: PrivateKeyDer<'static>,
+    /// The subject key identifier (SKI) of the certificate, in hexadecimal, which the device knows the gateway by.
+    pub ski: String,
+}
+
+impl Identity {
+    /// Loads the private key at `path`, or generates one and stores it there if there's none yet, and makes a
+    /// self-signed certificate for it.
+    ///
+    /// EEBus devices trust each other by the SKI of their certificates rather than by a certificate authority. The
+    /// SKI is derived from the key, so the gateway keeps its SKI as long as it keeps its key, even though the
+    /// certificate is made again at every start.
+    pub fn load_or_generate(path: &Path) -> eyre::Result<Self> {
+        let key_pair = if path.exists() {
+            let pem = std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("Could not read the EEBus key {}", path.display()))?;
+            KeyPair::from_pem(&pem)
+                .wrap_err_with(|| format!("Could not parse the EEBus key {}", path.display()))?
+        } else {
+            let key_pair = KeyPair::generate()?;
+            std::fs::write(path, key_pair.serialize_pem())
+                .wrap_err_with(|| format!("Could not store the EEBus key in {}", path.display()))?;
+            tracing::info!("Generated a new EEBus key in {}", path.display());
+            key_pair
+        };
+
+        // SHIP requires the SKI to be the SHA-1 hash of the public key.
+        let ski = Sha1::digest(key_pair.public_key_raw()).to_vec();
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "S2 EEBus gateway");
+        params.is_ca = IsCa::ExplicitNoCa;
+        params.key_identifier_method = KeyIdMethod::PreSpecified(ski.clone());
+        let certificate = params
+            .self_signed(&key_pair)
+            .wrap_err("Could not make a certificate for the EEBus key")?;
+
+        Ok(Self {
+            certificate: certificate.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+            ski: hex(&ski),
+        })
+    }
+}
+
+/// Reads the subject key identifier from a certificate, in hexadecimal.
+pub fn ski(certificate: &CertificateDer<'_>) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    certificate
+        .extensions()
+        .iter()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::SubjectKeyIdentifier(key_identifier) => Some(hex(key_identifier.0)),
+            _ => None,
+        })
+}
+
+/// Writes bytes in lowercase hexadecimal, which is how EEBus devices show SKIs.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
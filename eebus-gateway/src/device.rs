@@ -0,0 +1,390 @@
+use crate::certificate::Identity;
+use crate::ship::ShipConnection;
+use crate::spine::{self, feature, LocalDevice};
+use serde_json::{json, Value};
+use simulator_common::LimitableDevice;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How often the heartbeat is sent, which the device expects to keep its limit.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(4);
+/// How long to wait before connecting again when the connection with the device is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// How to reach the EEBus device.
+pub struct DeviceConfig {
+    /// The SHIP URL of the device, such as `wss://192.168.1.20:4712/ship/`.
+    pub url: String,
+    /// The SKI of the device, in lowercase hexadecimal.
+    pub ski: String,
+    pub identity: Identity,
+    /// How often the power of the device is read, in case it doesn't notify the gateway of changes.
+    pub poll_interval: Duration,
+}
+
+/// A power reading of the device.
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    /// The power the device consumes, in W.
+    power_w: f64,
+    at: Instant,
+}
+
+/// The connection with an EEBus device, such as a heat pump or a wallbox, which limits its power consumption (LPC)
+/// and reports its power (MPC).
+///
+/// Like an inverter in the PV installation, the device is talked to in the background, so the gateway can read the
+/// latest power and set a limit without waiting for the device. When the connection is lost, the device is connected
+/// to again, and its limit is written again.
+pub struct EebusDevice {
+    readings: watch::Receiver<Option<Reading>>,
+    limit: watch::Sender<Option<f64>>,
+    poll_interval: Duration,
+}
+
+impl EebusDevice {
+    /// Starts connecting to the device; this stops when the `EebusDevice` is dropped.
+    pub fn start(config: DeviceConfig) -> Self {
+        let (readings_sender, readings) = watch::channel(None);
+        let (limit, limit_receiver) = watch::channel(None);
+        let poll_interval = config.poll_interval;
+        tokio::spawn(connect(config, readings_sender, limit_receiver));
+
+        Self {
+            readings,
+            limit,
+            poll_interval,
+        }
+    }
+}
+
+impl LimitableDevice for EebusDevice {
+    fn name(&self) -> &str {
+        "EEBus device"
+    }
+
+    /// The power the device consumed at the latest reading, in W, unless there hasn't been a reading for a few polls.
+    fn power_w(&self) -> Option<f64> {
+        let reading = (*self.readings.borrow())?;
+        (reading.at.elapsed() < 3 * self.poll_interval).then_some(reading.power_w)
+    }
+
+    /// Writes the limit to the device, which is what the LPC use case of EEBus is for.
+    fn set_limit(&self, limit_w: Option<f64>) {
+        self.limit.send_if_modified(|limit| {
+            let modified = *limit != limit_w;
+            *limit = limit_w;
+            modified
+        });
+    }
+}
+
+/// Keeps a connection with the device, and connects again whenever it's lost.
+async fn connect(
+    config: DeviceConfig,
+    readings: watch::Sender<Option<Reading>>,
+    mut limit: watch::Receiver<Option<f64>>,
+) {
+    loop {
+        let result = async {
+            let connection =
+                ShipConnection::connect(&config.url, &config.ski, &config.identity).await?;
+            tracing::info!("Connected to EEBus device {}", config.url);
+            let mut session = Session::new(connection, &config.identity.ski);
+            let result = session.run(&config, &readings, &mut limit).await;
+            session.connection.close().await;
+            result
+        }
+        .await;
+        match result {
+            // The gateway stopped.
+            Ok(()) => return,
+            Err(error) => {
+                tracing::warn!("No connection with EEBus device {}: {error:#}", config.url)
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = readings.closed() => return,
+        }
+    }
+}
+
+/// A connection with the device, with what the gateway found out about it so far.
+struct Session {
+    connection: ShipConnection,
+    local: LocalDevice,
+    /// The address of the load control server of the device, once it's been discovered.
+    load_control: Option<Value>,
+    /// The address of the measurement server of the device, once it's been discovered.
+    measurement: Option<Value>,
+    /// The ID of the limit on the active power consumption, from the limit descriptions.
+    limit_id: Option<u64>,
+    /// The ID of the measurement of the total AC power, from the measurement descriptions.
+    measurement_id: Option<u64>,
+    /// The limit the device has, if it's been written in this session; `Some(None)` means it has no limit.
+    written_limit: Option<Option<f64>>,
+}
+
+impl Session {
+    fn new(connection: ShipConnection, ski: &str) -> Self {
+        Self {
+            connection,
+            local: LocalDevice::new(ski),
+            load_control: None,
+            measurement: None,
+            limit_id: None,
+            measurement_id: None,
+            written_limit: None,
+        }
+    }
+
+    /// Discovers the device, and then exchanges datagrams with it until the gateway stops or the connection is lost.
+    async fn run(
+        &mut self,
+        config: &DeviceConfig,
+        readings: &watch::Sender<Option<Reading>>,
+        limit: &mut watch::Receiver<Option<f64>>,
+    ) -> eyre::Result<()> {
+        let node_management = json!({"entity": [0], "feature": 0});
+        let read = self.local.read(
+            feature::NODE_MANAGEMENT,
+            &node_management,
+            "nodeManagementDetailedDiscoveryData",
+        );
+        self.connection.send(read).await?;
+
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        let mut poll = tokio::time::interval(config.poll_interval);
+        loop {
+            tokio::select! {
+                datagram = self.connection.receive() => self.handle(datagram?, readings).await?,
+                _ = heartbeat.tick() => {
+                    for datagram in self.local.heartbeats() {
+                        self.connection.send(datagram).await?;
+                    }
+                }
+                _ = poll.tick() => {
+                    if let Some(measurement) = &self.measurement {
+                        if self.measurement_id.is_some() {
+                            let read = self.local.read(feature::MEASUREMENT, measurement, "measurementListData");
+                            self.connection.send(read).await?;
+                        }
+                    }
+                }
+                changed = limit.changed() => if changed.is_err() {
+                    return Ok(());
+                },
+            }
+
+            let limit_w = *limit.borrow_and_update();
+            if self.written_limit != Some(limit_w) {
+                self.write_limit(limit_w).await?;
+            }
+        }
+    }
+
+    /// Handles a datagram from the device: answers it if it asks something of the gateway, and takes in what it tells.
+    async fn handle(
+        &mut self,
+        datagram: Value,
+        readings: &watch::Sender<Option<Reading>>,
+    ) -> eyre::Result<()> {
+        if let Some(answer) = self.local.answer(&datagram) {
+            self.connection.send(answer).await?;
+            return Ok(());
+        }
+        let Some((function, data)) = spine::command(&datagram) else {
+            return Ok(());
+        };
+
+        match function {
+            "nodeManagementDetailedDiscoveryData" => self.discover(data).await?,
+            "loadControlLimitDescriptionListData" => {
+                let descriptions = data["loadControlLimitDescriptionData"].as_array();
+                let limit = descriptions.into_iter().flatten().find(|description| {
+                    description["scopeType"] == "activePowerLimit"
+                        && description["limitDirection"] != "produce"
+                });
+                self.limit_id = limit.and_then(|limit| limit["limitId"].as_u64());
+                match self.limit_id {
+                    Some(limit_id) => {
+                        tracing::info!("The EEBus device takes power limits as limit {limit_id}")
+                    }
+                    None => tracing::warn!(
+                        "The EEBus device has no limit on its active power, so it can't be limited"
+                    ),
+                }
+                self.written_limit = None;
+            }
+            "measurementDescriptionListData" => {
+                let descriptions = data["measurementDescriptionData"].as_array();
+                let measurement = descriptions.into_iter().flatten().find(|description| {
+                    description["measurementType"] == "power"
+                        && description["scopeType"] == "acPowerTotal"
+                });
+                self.measurement_id =
+                    measurement.and_then(|measurement| measurement["measurementId"].as_u64());
+                if self.measurement_id.is_none() {
+                    tracing::warn!("The EEBus device doesn't measure its total AC power");
+                }
+            }
+            "measurementListData" => {
+                let measurements = data["measurementData"].as_array();
+                let power_w = measurements
+                    .into_iter()
+                    .flatten()
+                    .find(|measurement| {
+                        self.measurement_id.is_some()
+                            && measurement["measurementId"].as_u64() == self.measurement_id
+                    })
+                    .and_then(|measurement| spine::scaled_number(&measurement["value"]));
+                if let Some(power_w) = power_w {
+                    tracing::debug!("The EEBus device consumes {power_w:.0} W");
+                    readings.send_replace(Some(Reading {
+                        power_w,
+                        at: Instant::now(),
+                    }));
+                }
+            }
+            "resultData" => {
+                if data["errorNumber"]
+                    .as_u64()
+                    .is_some_and(|error_number| error_number != 0)
+                {
+                    tracing::warn!("The EEBus device returned an error: {data}");
+                }
+            }
+            _ => tracing::debug!("Ignoring {function} from the EEBus device"),
+        }
+        Ok(())
+    }
+
+    /// Finds the load control and measurement features of the device, subscribes to them, and reads what they are.
+    async fn discover(&mut self, data: &Value) -> eyre::Result<()> {
+        let features = data["featureInformation"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let server = |feature_type: &str, entity: Option<&Value>| {
+            features
+                .iter()
+                .map(|feature| &feature["description"])
+                .find(|description| {
+                    description["featureType"] == feature_type
+                        && description["role"] == "server"
+                        && entity
+                            .is_none_or(|entity| description["featureAddress"]["entity"] == *entity)
+                })
+                .map(|description| description["featureAddress"].clone())
+        };
+        // The measurements should be of the entity that's limited, such as the compressor of a heat pump.
+        let load_control = server("LoadControl", None);
+        let measurement = load_control
+            .as_ref()
+            .and_then(|load_control| server("Measurement", Some(&load_control["entity"])))
+            .or_else(|| server("Measurement", None));
+
+        let entity_type = load_control.as_ref().and_then(|load_control| {
+            let entities = data["entityInformation"].as_array()?;
+            let entity = entities.iter().find(|entity| {
+                entity["description"]["entityAddress"]["entity"] == load_control["entity"]
+            })?;
+            entity["description"]["entityType"].as_str()
+        });
+        tracing::info!(
+            "Found EEBus device {} ({})",
+            data["deviceInformation"]["description"]["deviceAddress"]["device"],
+            entity_type.unwrap_or("without load control")
+        );
+
+        let node_management = json!({
+            "device": data["deviceInformation"]["description"]["deviceAddress"]["device"],
+            "entity": [0],
+            "feature": 0,
+        });
+        for (server, client, feature_type, description) in [
+            (
+                &load_control,
+                feature::LOAD_CONTROL,
+                "LoadControl",
+                "loadControlLimitDescriptionListData",
+            ),
+            (
+                &measurement,
+                feature::MEASUREMENT,
+                "Measurement",
+                "measurementDescriptionListData",
+            ),
+        ] {
+            let Some(server) = server else {
+                tracing::warn!("The EEBus device has no {feature_type} server");
+                continue;
+            };
+            let subscription = json!({"subscriptionRequest": {
+                "clientAddress": self.local.feature_address(client),
+                "serverAddress": server,
+                "serverFeatureType": feature_type,
+            }});
+            let call = self.local.call(
+                feature::NODE_MANAGEMENT,
+                &node_management,
+                "nodeManagementSubscriptionRequestCall",
+                subscription,
+            );
+            self.connection.send(call).await?;
+            // The device only takes limits from a client that's bound to its load control.
+            if client == feature::LOAD_CONTROL {
+                let binding = json!({"bindingRequest": {
+                    "clientAddress": self.local.feature_address(client),
+                    "serverAddress": server,
+                    "serverFeatureType": feature_type,
+                }});
+                let call = self.local.call(
+                    feature::NODE_MANAGEMENT,
+                    &node_management,
+                    "nodeManagementBindingRequestCall",
+                    binding,
+                );
+                self.connection.send(call).await?;
+            }
+            let read = self.local.read(client, server, description);
+            self.connection.send(read).await?;
+        }
+
+        self.load_control = load_control;
+        self.measurement = measurement;
+        Ok(())
+    }
+
+    /// Writes the limit to the device, once it's known which of its limits to write.
+    async fn write_limit(&mut self, limit_w: Option<f64>) -> eyre::Result<()> {
+        let (Some(load_control), Some(limit_id)) = (&self.load_control, self.limit_id) else {
+            return Ok(());
+        };
+        let limit = match limit_w {
+            Some(limit_w) => {
+                tracing::info!("Limiting the EEBus device to {limit_w:.0} W");
+                json!({
+                    "limitId": limit_id,
+                    "isLimitActive": true,
+                    "value": {"number": limit_w.round() as i64, "scale": 0},
+                })
+            }
+            None => {
+                tracing::info!("Lifting the limit of the EEBus device");
+                json!({"limitId": limit_id, "isLimitActive": false})
+            }
+        };
+        let write = self.local.write_partial(
+            feature::LOAD_CONTROL,
+            load_control,
+            "loadControlLimitListData",
+            json!({"loadControlLimitData": [limit]}),
+        );
+        self.connection.send(write).await?;
+        self.written_limit = Some(limit_w);
+        Ok(())
+    }
+}
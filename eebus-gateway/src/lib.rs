@@ -0,0 +1,69 @@
+use certificate::Identity;
+use device::{DeviceConfig, EebusDevice};
+use eyre::eyre;
+use simulator_common::{LimitedConsumer, Settings, Timeline};
+use std::path::Path;
+use std::time::Duration;
+
+mod certificate;
+mod device;
+mod ship;
+mod spine;
+
+/// Runs the gateway with the given settings, until it's stopped with Ctrl-C or SIGTERM.
+///
+/// Unlike the simulators, the gateway follows a real device, so it always runs in real time.
+pub async fn run(settings: &impl Settings) -> eyre::Result<()> {
+    // Read the configuration before connecting, so problems with it are reported right away.
+    let url = settings
+        .get("EEBUS_URL")
+        .ok_or_else(|| eyre!("Could not read the SHIP URL of the EEBus device from EEBUS_URL"))?;
+    let ski = settings
+        .get("EEBUS_SKI")
+        .ok_or_else(|| eyre!("Could not read the SKI of the EEBus device from EEBUS_SKI"))?;
+    // Devices show their SKI in different ways, such as in groups or with colons.
+    let ski: String = ski
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .collect::<String>()
+        .to_lowercase();
+    if ski.len() != 40 {
+        return Err(eyre!(
+            "Invalid value for EEBUS_SKI; should be the 40 hexadecimal digits of the SKI of the EEBus device"
+        ));
+    }
+    let max_power_w: f64 = settings.get_or("MAX_POWER_W", 11000.0)?;
+    if max_power_w <= 0.0 {
+        return Err(eyre!("MAX_POWER_W should be more than 0"));
+    }
+    let update_interval = Duration::from_secs(settings.get_or("UPDATE_INTERVAL", 10)?);
+    if update_interval.is_zero() {
+        return Err(eyre!("UPDATE_INTERVAL should be at least 1 second"));
+    }
+    let poll_interval = Duration::from_secs(settings.get_or("EEBUS_POLL_INTERVAL", 10)?);
+    if poll_interval.is_zero() {
+        return Err(eyre!("EEBUS_POLL_INTERVAL should be at least 1 second"));
+    }
+    let key_path = settings
+        .get("EEBUS_KEY_PATH")
+        .unwrap_or_else(|| "eebus-key.pem".into());
+    let identity = Identity::load_or_generate(Path::new(&key_path))?;
+    tracing::info!(
+        "The gateway has SKI {}; trust it in the app of the EEBus device",
+        identity.ski
+    );
+
+    let device = EebusDevice::start(DeviceConfig {
+        url,
+        ski,
+        identity,
+        poll_interval,
+    });
+    let connection = simulator_common::connect(settings).await?;
+    simulator_common::run(
+        connection,
+        LimitedConsumer::new(device, max_power_w, update_interval),
+        Timeline::default(),
+    )
+    .await
+}
@@ -0,0 +1,386 @@
+use crate::certificate::{self, Identity};
+use eyre::{bail, eyre, Context};
+use futures_util::{SinkExt, StreamExt};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::http::Uri;
+use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::WebSocketStream;
+
+/// The port EEBus devices listen on, unless they announce another one.
+const DEFAULT_PORT: u16 = 4712;
+/// How long the device may take to answer a step of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the device may take to decide whether it trusts the gateway. Some devices ask the user first.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The first byte of every SHIP message, which tells what kind of message it is.
+const INIT: u8 = 0;
+const CONTROL: u8 = 1;
+const DATA: u8 = 2;
+const END: u8 = 3;
+
+/// A SHIP connection with an EEBus device, over which SPINE datagrams are exchanged.
+///
+/// SHIP runs over a WebSocket with mutual TLS, in which the device and the gateway trust each other by the SKI of their
+/// certificates. After a handshake in which both sides say they trust each other and agree on the protocol version,
+/// every data message carries one SPINE datagram.
+pub struct ShipConnection {
+    socket: WebSocketStream<TlsStream<TcpStream>>,
+}
+
+impl ShipConnection {
+    /// Connects to the device at `url` (such as `wss://192.168.1.20:4712/ship/`), which must have the SKI `remote_ski`,
+    /// and goes through the SHIP handshake.
+    pub async fn connect(url: &str, remote_ski: &str, identity: &Identity) -> eyre::Result<Self> {
+        let uri: Uri = url
+            .parse()
+            .wrap_err_with(|| format!("Could not parse EEBUS_URL ({url}) as a URL"))?;
+        let host = uri
+            .host()
+            .ok_or_else(|| eyre!("EEBUS_URL ({url}) has no host"))?
+            .trim_matches(['[', ']']);
+        let port = uri.port_u16().unwrap_or(DEFAULT_PORT);
+        let path = match uri.path() {
+            "" | "/" => "/ship/",
+            path => path,
+        };
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = SkiVerifier {
+            ski: remote_ski.to_string(),
+            algorithms: provider.signature_verification_algorithms,
+        };
+        let tls_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(vec![identity.certificate.clone()], identity.key.clone_key())?;
+
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| eyre!("Timed out connecting to EEBus device {host}:{port}"))?
+            .wrap_err_with(|| format!("Could not connect to EEBus device {host}:{port}"))?;
+        stream.set_nodelay(true)?;
+        let server_name = ServerName::try_from(host.to_string())?;
+        let stream = TlsConnector::from(Arc::new(tls_config))
+            .connect(server_name, stream)
+            .await
+            .wrap_err_with(|| format!("Could not set up TLS with EEBus device {host}:{port}"))?;
+
+        let mut request = format!("wss://{host}:{port}{path}").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("ship"));
+        let (socket, _) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .wrap_err("Could not open a WebSocket with the EEBus device")?;
+
+        let mut connection = Self { socket };
+        connection
+            .handshake(&identity.ski)
+            .await
+            .wrap_err("The SHIP handshake with the EEBus device failed")?;
+        Ok(connection)
+    }
+
+    /// Goes through the steps of the SHIP handshake, in which the gateway is the client.
+    async fn handshake(&mut self, ski: &str) -> eyre::Result<()> {
+        // Connection mode initialisation: both sides send the same two bytes.
+        self.send_raw(vec![INIT, 0]).await?;
+        let init = self.receive_raw(HANDSHAKE_TIMEOUT).await?;
+        if init != [INIT, 0] {
+            bail!("The device didn't start SHIP, but sent {init:?}");
+        }
+
+        // Hello: both sides say whether they trust the other. A device that doesn't trust the gateway yet may wait for
+        // its user to do so.
+        self.send_control(json!({"connectionHello": {"phase": "ready", "waiting": HELLO_TIMEOUT.as_millis() as u64}}))
+            .await?;
+        loop {
+            let message = self.receive_control(HELLO_TIMEOUT).await?;
+            match message["connectionHello"]["phase"].as_str() {
+                Some("ready") => break,
+                Some("pending") => {
+                    tracing::info!("Waiting for the EEBus device to trust the gateway (SKI {ski})")
+                }
+                Some("aborted") => {
+                    bail!("The device doesn't trust the gateway; trust SKI {ski} on the device")
+                }
+                _ => bail!("Expected a connectionHello, but got {message}"),
+            }
+        }
+
+        // Protocol handshake: the gateway announces the highest version it supports, and confirms the device's choice
+        // by sending it back.
+        self.send_control(json!({"messageProtocolHandshake": {
+            "handshakeType": "announceMax",
+            "version": {"major": 1, "minor": 0},
+            "formats": {"format": ["JSON-UTF8"]},
+        }}))
+        .await?;
+        let selection = self.receive_control(HANDSHAKE_TIMEOUT).await?;
+        let handshake = &selection["messageProtocolHandshake"];
+        if handshake["handshakeType"] != "select" || handshake["version"]["major"] != 1 {
+            bail!("The device didn't select SHIP version 1, but sent {selection}");
+        }
+        self.send_control(selection).await?;
+
+        // PIN verification isn't supported, so the device has to trust the gateway by its SKI.
+        self.send_control(json!({"connectionPinState": {"pinState": "none"}}))
+            .await?;
+        let pin_state = self.receive_control(HANDSHAKE_TIMEOUT).await?;
+        match pin_state["connectionPinState"]["pinState"].as_str() {
+            Some("none") | Some("pinOk") => {}
+            Some(state) => {
+                bail!("The device requires a PIN ({state}), which the gateway doesn't support")
+            }
+            None => bail!("Expected a connectionPinState, but got {pin_state}"),
+        }
+
+        // Access methods: both sides ask for and tell the other how to reach them.
+        self.send_control(json!({"accessMethodsRequest": {}}))
+            .await?;
+        let mut has_access_methods = false;
+        let mut sent_access_methods = false;
+        while !(has_access_methods && sent_access_methods) {
+            let message = self.receive_control(HANDSHAKE_TIMEOUT).await?;
+            if message.get("accessMethodsRequest").is_some() {
+                self.send_control(
+                    json!({"accessMethods": {"id": format!("S2-EEBus-Gateway-{ski}")}}),
+                )
+                .await?;
+                sent_access_methods = true;
+            } else if let Some(access_methods) = message.get("accessMethods") {
+                tracing::debug!("The EEBus device has access methods {access_methods}");
+                has_access_methods = true;
+            } else {
+                bail!("Expected access methods, but got {message}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a SPINE datagram to the device.
+    pub async fn send(&mut self, datagram: Value) -> eyre::Result<()> {
+        tracing::debug!("Sending SPINE datagram {datagram}");
+        // Unlike the rest of the message, the payload is a plain object around the datagram.
+        let message = json!({"data": [
+            {"header": [{"protocolId": "ee1.0"}]},
+            {"payload": {"datagram": to_eebus(datagram)}},
+        ]});
+        self.send_message(DATA, message).await
+    }
+
+    /// Receives the next SPINE datagram from the device.
+    pub async fn receive(&mut self) -> eyre::Result<Value> {
+        loop {
+            let (kind, mut message) = self.receive_message(None).await?;
+            match kind {
+                DATA => {
+                    let datagram = message["data"]["payload"]["datagram"].take();
+                    tracing::debug!("Received SPINE datagram {datagram}");
+                    return Ok(datagram);
+                }
+                END => bail!("The EEBus device closed the SHIP connection: {message}"),
+                _ => tracing::debug!("Ignoring SHIP message {message}"),
+            }
+        }
+    }
+
+    /// Closes the connection, telling the device it's on purpose.
+    pub async fn close(&mut self) {
+        let close = json!({"connectionClose": {"phase": "announce"}});
+        let _ = self.send_message(END, to_eebus_message(close)).await;
+        let _ = self.socket.close(None).await;
+    }
+
+    async fn send_control(&mut self, message: Value) -> eyre::Result<()> {
+        self.send_message(CONTROL, to_eebus_message(message)).await
+    }
+
+    async fn receive_control(&mut self, timeout: Duration) -> eyre::Result<Value> {
+        match self.receive_message(Some(timeout)).await? {
+            (CONTROL, message) => Ok(message),
+            (END, message) => bail!("The EEBus device closed the SHIP connection: {message}"),
+            (_, message) => bail!("Expected a SHIP control message, but got {message}"),
+        }
+    }
+
+    /// Sends a message that's already in EEBus JSON.
+    async fn send_message(&mut self, kind: u8, message: Value) -> eyre::Result<()> {
+        let mut bytes = vec![kind];
+        serde_json::to_writer(&mut bytes, &message)?;
+        self.send_raw(bytes).await
+    }
+
+    async fn receive_message(&mut self, timeout: Option<Duration>) -> eyre::Result<(u8, Value)> {
+        let bytes = match timeout {
+            Some(timeout) => self.receive_raw(timeout).await?,
+            None => self.receive_frame().await?,
+        };
+        let Some((&kind, json)) = bytes.split_first() else {
+            bail!("Got an empty SHIP message");
+        };
+        let message: Value = serde_json::from_slice(json).wrap_err_with(|| {
+            format!(
+                "Could not parse SHIP message {}",
+                String::from_utf8_lossy(json)
+            )
+        })?;
+        Ok((kind, from_eebus(message)))
+    }
+
+    async fn send_raw(&mut self, bytes: Vec<u8>) -> eyre::Result<()> {
+        self.socket
+            .send(WebSocketMessage::Binary(bytes))
+            .await
+            .wrap_err("Could not send to the EEBus device")
+    }
+
+    async fn receive_raw(&mut self, timeout: Duration) -> eyre::Result<Vec<u8>> {
+        tokio::time::timeout(timeout, self.receive_frame())
+            .await
+            .map_err(|_| eyre!("The EEBus device didn't respond in time"))?
+    }
+
+    /// Receives the next binary frame, which is what every SHIP message is sent as.
+    async fn receive_frame(&mut self) -> eyre::Result<Vec<u8>> {
+        loop {
+            match self.socket.next().await {
+                Some(Ok(WebSocketMessage::Binary(bytes))) => return Ok(bytes),
+                Some(Ok(WebSocketMessage::Close(frame))) => {
+                    bail!("The EEBus device closed the connection: {frame:?}")
+                }
+                Some(Ok(_)) => {}
+                Some(Err(error)) => {
+                    return Err(error).wrap_err("Lost the connection with the EEBus device")
+                }
+                None => bail!("The EEBus device closed the connection"),
+            }
+        }
+    }
+}
+
+/// Trusts the device by the SKI of its certificate, like EEBus devices do, instead of by a certificate authority.
+#[derive(Debug)]
+struct SkiVerifier {
+    ski: String,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for SkiVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match certificate::ski(end_entity) {
+            Some(ski) if ski == self.ski => Ok(ServerCertVerified::assertion()),
+            Some(ski) => Err(rustls::Error::General(format!(
+                "the EEBus device has SKI {ski} instead of the {} in EEBUS_SKI",
+                self.ski
+            ))),
+            None => Err(rustls::Error::General(
+                "the certificate of the EEBus device has no SKI".into(),
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Converts a message to the JSON that EEBus uses, in which every object is an array of objects with one field each.
+/// Only the message itself stays an object.
+fn to_eebus_message(message: Value) -> Value {
+    match message {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, to_eebus(value)))
+                .collect(),
+        ),
+        message => to_eebus(message),
+    }
+}
+
+fn to_eebus(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Array(
+            fields
+                .into_iter()
+                .map(|(name, value)| Value::Object(Map::from_iter([(name, to_eebus(value))])))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(to_eebus).collect()),
+        value => value,
+    }
+}
+
+/// Converts the JSON that EEBus uses back to plain JSON objects.
+///
+/// An array of objects with one field each is an object, and any other array is a list. Lists of objects are arrays
+/// of arrays in EEBus JSON, so the two can't be confused.
+fn from_eebus(value: Value) -> Value {
+    match value {
+        Value::Array(items)
+            if !items.is_empty()
+                && items
+                    .iter()
+                    .all(|item| item.as_object().is_some_and(|fields| fields.len() == 1)) =>
+        {
+            Value::Object(
+                items
+                    .into_iter()
+                    .flat_map(|item| match item {
+                        Value::Object(fields) => fields,
+                        _ => Map::new(),
+                    })
+                    .map(|(name, value)| (name, from_eebus(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(from_eebus).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, from_eebus(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
@@ -0,0 +1,300 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+
+/// The version of SPINE the gateway speaks.
+const SPECIFICATION_VERSION: &str = "1.3.0";
+/// How long the device may go without a heartbeat of the gateway before it falls back to its failsafe limit.
+const HEARTBEAT_TIMEOUT: &str = "PT60S";
+
+/// The error number of a result that says all went well.
+const NO_ERROR: u64 = 0;
+/// The error number of a result for a command the gateway doesn't support.
+const COMMAND_NOT_SUPPORTED: u64 = 6;
+
+/// The entity of the gateway that has its features, as opposed to entity 0 with the node management.
+pub const CEM_ENTITY: u64 = 1;
+
+/// The features of the gateway, by their number.
+pub mod feature {
+    /// Lets the device discover the gateway, and subscribe and bind to its features.
+    pub const NODE_MANAGEMENT: u64 = 0;
+    /// The device diagnosis server, which sends the heartbeat that tells the device the gateway is still there.
+    pub const HEARTBEAT: u64 = 1;
+    /// The load control client, which writes the limit of the device.
+    pub const LOAD_CONTROL: u64 = 2;
+    /// The measurement client, which reads the power of the device.
+    pub const MEASUREMENT: u64 = 3;
+}
+
+/// The gateway as a SPINE device, which the EEBus device sees as an energy management system that limits its power
+/// consumption (the LPC use case) and monitors it (the MPC use case).
+///
+/// Datagrams are plain JSON here; the SHIP connection converts them to and from EEBus JSON.
+pub struct LocalDevice {
+    /// The SPINE address of the gateway.
+    address: String,
+    msg_counter: u64,
+    heartbeat_counter: u64,
+    /// The addresses of the clients that subscribed to the heartbeat.
+    heartbeat_subscribers: Vec<Value>,
+}
+
+impl LocalDevice {
+    pub fn new(ski: &str) -> Self {
+        Self {
+            address: format!("d:_i:S2_EEBus-Gateway-{ski}"),
+            msg_counter: 0,
+            heartbeat_counter: 0,
+            heartbeat_subscribers: Vec::new(),
+        }
+    }
+
+    /// The address of a feature of the gateway.
+    pub fn feature_address(&self, feature: u64) -> Value {
+        let entity = if feature == feature::NODE_MANAGEMENT {
+            0
+        } else {
+            CEM_ENTITY
+        };
+        json!({"device": self.address, "entity": [entity], "feature": feature})
+    }
+
+    /// A datagram that reads `function` from a feature of the device.
+    pub fn read(&mut self, feature: u64, destination: &Value, function: &str) -> Value {
+        let command = json!({function: {}});
+        self.datagram("read", feature, destination, command, None, false)
+    }
+
+    /// A datagram that calls `function` on a feature of the device, such as a subscription request.
+    pub fn call(
+        &mut self,
+        feature: u64,
+        destination: &Value,
+        function: &str,
+        data: Value,
+    ) -> Value {
+        let command = json!({function: data});
+        self.datagram("call", feature, destination, command, None, true)
+    }
+
+    /// A datagram that writes only the given entries of `function` on a feature of the device.
+    pub fn write_partial(
+        &mut self,
+        feature: u64,
+        destination: &Value,
+        function: &str,
+        data: Value,
+    ) -> Value {
+        let command = json!({
+            "function": function,
+            "filter": [{"cmdControl": {"partial": {}}}],
+            function: data,
+        });
+        self.datagram("write", feature, destination, command, None, true)
+    }
+
+    /// The heartbeat datagrams for the clients that subscribed to it.
+    pub fn heartbeats(&mut self) -> Vec<Value> {
+        self.heartbeat_counter += 1;
+        let heartbeat = self.heartbeat();
+        self.heartbeat_subscribers
+            .clone()
+            .iter()
+            .map(|subscriber| {
+                let command = json!({"deviceDiagnosisHeartbeatData": heartbeat});
+                self.datagram(
+                    "notify",
+                    feature::HEARTBEAT,
+                    subscriber,
+                    command,
+                    None,
+                    false,
+                )
+            })
+            .collect()
+    }
+
+    /// Answers a datagram from the device that asks something of the gateway, such as reading its features or
+    /// subscribing to them. Replies to what the gateway asked itself don't need an answer.
+    pub fn answer(&mut self, datagram: &Value) -> Option<Value> {
+        let header = &datagram["header"];
+        let (function, data) = command(datagram)?;
+        let (classifier, command) = match header["cmdClassifier"].as_str()? {
+            "read" => match function {
+                "nodeManagementDetailedDiscoveryData" => {
+                    ("reply", json!({function: self.discovery_data()}))
+                }
+                "nodeManagementUseCaseData" => ("reply", json!({function: self.use_case_data()})),
+                "deviceDiagnosisHeartbeatData" => ("reply", json!({function: self.heartbeat()})),
+                _ => ("result", result(COMMAND_NOT_SUPPORTED)),
+            },
+            "call" => match function {
+                "nodeManagementSubscriptionRequestCall" => {
+                    let request = &data["subscriptionRequest"];
+                    if request["serverAddress"]["feature"] == feature::HEARTBEAT
+                        && !self
+                            .heartbeat_subscribers
+                            .contains(&request["clientAddress"])
+                    {
+                        self.heartbeat_subscribers
+                            .push(request["clientAddress"].clone());
+                    }
+                    ("result", result(NO_ERROR))
+                }
+                "nodeManagementSubscriptionDeleteCall" => {
+                    let client = &data["subscriptionDelete"]["clientAddress"];
+                    self.heartbeat_subscribers
+                        .retain(|subscriber| subscriber != client);
+                    ("result", result(NO_ERROR))
+                }
+                // The gateway has nothing the device could bind to and change, so the binding doesn't matter.
+                "nodeManagementBindingRequestCall" | "nodeManagementBindingDeleteCall" => {
+                    ("result", result(NO_ERROR))
+                }
+                _ => ("result", result(COMMAND_NOT_SUPPORTED)),
+            },
+            "write" => ("result", result(COMMAND_NOT_SUPPORTED)),
+            _ => return None,
+        };
+
+        let source = header["addressDestination"]["feature"].as_u64()?;
+        let reference = header["msgCounter"].as_u64();
+        Some(self.datagram(
+            classifier,
+            source,
+            &header["addressSource"],
+            command,
+            reference,
+            false,
+        ))
+    }
+
+    fn datagram(
+        &mut self,
+        classifier: &str,
+        feature: u64,
+        destination: &Value,
+        command: Value,
+        reference: Option<u64>,
+        ack_request: bool,
+    ) -> Value {
+        self.msg_counter += 1;
+        let mut header = json!({
+            "specificationVersion": SPECIFICATION_VERSION,
+            "addressSource": self.feature_address(feature),
+            "addressDestination": destination,
+            "msgCounter": self.msg_counter,
+            "cmdClassifier": classifier,
+        });
+        if let Some(reference) = reference {
+            header["msgCounterReference"] = reference.into();
+        }
+        if ack_request {
+            header["ackRequest"] = true.into();
+        }
+        json!({"header": header, "payload": {"cmd": [command]}})
+    }
+
+    /// What the gateway is and what features it has.
+    fn discovery_data(&self) -> Value {
+        let feature = |feature: u64, feature_type: &str, role: &str, functions: &[&str]| {
+            json!({"description": {
+                "featureAddress": self.feature_address(feature),
+                "featureType": feature_type,
+                "role": role,
+                "supportedFunction": functions
+                    .iter()
+                    .map(|function| json!({"function": function, "possibleOperations": {"read": {}}}))
+                    .collect::<Vec<_>>(),
+            }})
+        };
+        json!({
+            "specificationVersionList": {"specificationVersion": [SPECIFICATION_VERSION]},
+            "deviceInformation": {"description": {
+                "deviceAddress": {"device": self.address},
+                "deviceType": "EnergyManagementSystem",
+                "networkFeatureSet": "smart",
+            }},
+            "entityInformation": [
+                {"description": {
+                    "entityAddress": {"device": self.address, "entity": [0]},
+                    "entityType": "DeviceInformation",
+                }},
+                {"description": {
+                    "entityAddress": {"device": self.address, "entity": [CEM_ENTITY]},
+                    "entityType": "CEM",
+                }},
+            ],
+            "featureInformation": [
+                feature(
+                    feature::NODE_MANAGEMENT,
+                    "NodeManagement",
+                    "special",
+                    &["nodeManagementDetailedDiscoveryData", "nodeManagementUseCaseData"],
+                ),
+                feature(feature::HEARTBEAT, "DeviceDiagnosis", "server", &["deviceDiagnosisHeartbeatData"]),
+                feature(feature::LOAD_CONTROL, "LoadControl", "client", &[]),
+                feature(feature::MEASUREMENT, "Measurement", "client", &[]),
+            ],
+        })
+    }
+
+    /// The use cases the gateway takes part in.
+    fn use_case_data(&self) -> Value {
+        let address = json!({"device": self.address, "entity": [CEM_ENTITY]});
+        json!({"useCaseInformation": [
+            {
+                "address": address,
+                "actor": "EnergyGuard",
+                "useCaseSupport": [{
+                    "useCaseName": "limitationOfPowerConsumption",
+                    "useCaseVersion": "1.0.0",
+                    "scenarioSupport": [1, 2, 3, 4],
+                }],
+            },
+            {
+                "address": address,
+                "actor": "MonitoringAppliance",
+                "useCaseSupport": [{
+                    "useCaseName": "monitoringOfPowerConsumption",
+                    "useCaseVersion": "1.0.0",
+                    "scenarioSupport": [1, 2, 3, 4],
+                }],
+            },
+        ]})
+    }
+
+    fn heartbeat(&self) -> Value {
+        json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "heartbeatCounter": self.heartbeat_counter,
+            "heartbeatTimeout": HEARTBEAT_TIMEOUT,
+        })
+    }
+}
+
+/// The function of the command in a datagram and its data, such as `measurementListData` and the measurements.
+pub fn command(datagram: &Value) -> Option<(&str, &Value)> {
+    let command = datagram["payload"]["cmd"].get(0)?.as_object()?;
+    command
+        .iter()
+        .find(|(name, _)| {
+            !matches!(
+                name.as_str(),
+                "function" | "filter" | "manufacturerSpecificExtension"
+            )
+        })
+        .map(|(name, data)| (name.as_str(), data))
+}
+
+/// A number with a scale as SPINE sends it, such as 42 with scale 2 for 4200.
+pub fn scaled_number(value: &Value) -> Option<f64> {
+    let number = value["number"].as_f64()?;
+    let scale = value["scale"].as_i64().unwrap_or_default();
+    Some(number * 10f64.powi(scale as i32))
+}
+
+/// The data of a result datagram.
+fn result(error_number: u64) -> Value {
+    json!({"resultData": {"errorNumber": error_number}})
+}
@@ -0,0 +1,456 @@
+use eyre::{eyre, Context};
+use futures_util::{SinkExt, StreamExt};
+use s2energy::common::ResourceManagerDetails;
+use serde_json::{json, Map, Value};
+use simulator_common::LimitableDevice;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// The cluster with the vendor, product and serial number of the device, on endpoint 0.
+const BASIC_INFORMATION: u32 = 0x0028;
+/// The cluster with the power the device measures.
+const ELECTRICAL_POWER_MEASUREMENT: u32 = 0x0090;
+/// The cluster the power of the device is adjusted with.
+const DEVICE_ENERGY_MANAGEMENT: u32 = 0x0098;
+/// The cluster EV chargers are enabled and limited with.
+const ENERGY_EVSE: u32 = 0x0099;
+
+/// How long to wait before connecting again when the connection with the Matter server is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// How long a power adjustment lasts. The device goes back to its own plan when it ends, so when the bridge or its
+/// connection goes down, the device isn't held back for long.
+const ADJUSTMENT_DURATION: Duration = Duration::from_secs(180);
+/// How often a power adjustment is requested again, well before it ends.
+const ADJUSTMENT_REFRESH: Duration = Duration::from_secs(60);
+/// The cause of the power adjustments: the CEM optimizes the site (`LocalOptimization`).
+const ADJUSTMENT_CAUSE: u8 = 0;
+/// The timeout of the timed interaction the commands of the EVSE cluster require, in ms.
+const TIMED_REQUEST_TIMEOUT_MS: u64 = 3000;
+/// The lowest current an EV charges with (IEC 61851), in A.
+const EVSE_MIN_CURRENT_A: f64 = 6.0;
+/// The charging current an EVSE is enabled with without a limit, in A; it keeps to the capacity of its circuit and
+/// the cable anyway.
+const EVSE_MAX_CURRENT_A: f64 = 80.0;
+/// The voltage the charging current is calculated from, in V.
+const PHASE_VOLTAGE_V: f64 = 230.0;
+
+/// How the power of the device is limited.
+pub enum Control {
+    /// With a power adjustment of the Device Energy Management cluster (`MATTER_CONTROL=POWER_ADJUSTMENT`), which
+    /// any energy smart appliance can support.
+    PowerAdjustment,
+    /// With the maximum charging current of the Energy EVSE cluster (`MATTER_CONTROL=EVSE`), on the given number of
+    /// phases.
+    Evse { phases: u32 },
+}
+
+/// How to reach the Matter device.
+pub struct DeviceConfig {
+    /// The WebSocket URL of the Matter server, such as `ws://localhost:5580/ws`.
+    pub url: String,
+    /// The node ID the device got when it was commissioned.
+    pub node_id: u64,
+    /// The endpoint of the device with its energy management clusters.
+    pub endpoint: u64,
+    pub control: Control,
+}
+
+/// What the Matter server knows about the device.
+#[derive(Debug, Clone, Default)]
+pub struct NodeInfo {
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// The most the device can consume according to its Device Energy Management cluster, in W.
+    pub max_power_w: Option<f64>,
+}
+
+/// What the bridge knows about the device, from the latest data of the Matter server.
+#[derive(Debug, Clone, Default)]
+struct State {
+    info: Option<NodeInfo>,
+    /// The power the device consumes in W, while the device is reachable.
+    power_w: Option<f64>,
+}
+
+/// A Matter device, such as an EV charger or a heat pump, reached through a Matter server, which limits its power
+/// consumption and reports its power.
+///
+/// The bridge isn't a Matter controller itself: the device is commissioned with, and talked to through, the Matter
+/// server of the Open Home Foundation (python-matter-server, which Home Assistant uses), over its WebSocket API. The
+/// server keeps the device subscribed, and passes on every change of its attributes. When the connection with the
+/// server is lost, it's connected to again, and the limit is written again.
+pub struct MatterDevice {
+    state: watch::Receiver<State>,
+    limit: watch::Sender<Option<f64>>,
+}
+
+impl MatterDevice {
+    /// Starts connecting to the Matter server; this stops when the `MatterDevice` is dropped.
+    pub fn start(config: DeviceConfig) -> Self {
+        let (state_sender, state) = watch::channel(State::default());
+        let (limit, limit_receiver) = watch::channel(None);
+        tokio::spawn(connect(config, state_sender, limit_receiver));
+        Self { state, limit }
+    }
+
+    /// Waits until the Matter server has told about the device, so the CEM can be told what it is. Returns `None` if
+    /// that doesn't happen within `timeout`.
+    pub async fn wait_for_node(&mut self, timeout: Duration) -> Option<NodeInfo> {
+        let state =
+            tokio::time::timeout(timeout, self.state.wait_for(|state| state.info.is_some())).await;
+        state.ok()?.ok()?.info.clone()
+    }
+}
+
+impl LimitableDevice for MatterDevice {
+    fn name(&self) -> &str {
+        "Matter device"
+    }
+
+    /// Adds the vendor, product and serial number the Matter server told about.
+    fn describe(&self, details: ResourceManagerDetails) -> ResourceManagerDetails {
+        let info = self.state.borrow().info.clone().unwrap_or_default();
+        ResourceManagerDetails {
+            manufacturer: info.vendor,
+            model: info.product,
+            serial_number: info.serial_number,
+            ..details
+        }
+    }
+
+    /// The power the device consumes in W, unless it isn't reachable or doesn't measure its power.
+    fn power_w(&self) -> Option<f64> {
+        self.state.borrow().power_w
+    }
+
+    /// Passes the limit on to the device, as a power adjustment or as the charging current of an EVSE.
+    fn set_limit(&self, limit_w: Option<f64>) {
+        self.limit.send_if_modified(|limit| {
+            let modified = *limit != limit_w;
+            *limit = limit_w;
+            modified
+        });
+    }
+}
+
+/// Keeps a connection with the Matter server, and connects again whenever it's lost.
+async fn connect(
+    config: DeviceConfig,
+    state: watch::Sender<State>,
+    mut limit: watch::Receiver<Option<f64>>,
+) {
+    loop {
+        let result = async {
+            let (socket, _) = tokio_tungstenite::connect_async(&config.url)
+                .await
+                .wrap_err_with(|| format!("Could not connect to {}", config.url))?;
+            tracing::info!("Connected to Matter server {}", config.url);
+            Session::new(socket, &config).run(&state, &mut limit).await
+        }
+        .await;
+        state.send_modify(|state| state.power_w = None);
+        match result {
+            // The bridge stopped.
+            Ok(()) => return,
+            Err(error) => tracing::warn!(
+                "No connection with Matter node {}: {error:#}",
+                config.node_id
+            ),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = state.closed() => return,
+        }
+    }
+}
+
+/// A connection with the Matter server, with what it told about the device so far.
+struct Session<'a> {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    config: &'a DeviceConfig,
+    /// The ID of the latest command sent to the server.
+    message_id: u64,
+    /// The ID of the command that starts listening to the server, which is answered with all nodes.
+    listening_id: Option<String>,
+    /// The attributes of the device by their path, such as `1/144/8` for the active power on endpoint 1, once the
+    /// server told about the device.
+    attributes: Option<Map<String, Value>>,
+    /// Whether the server can reach the device.
+    available: bool,
+    /// The limit the device has, if it's been written in this session; `Some(None)` means it has no limit.
+    written_limit: Option<Option<f64>>,
+}
+
+impl<'a> Session<'a> {
+    fn new(socket: WebSocketStream<MaybeTlsStream<TcpStream>>, config: &'a DeviceConfig) -> Self {
+        Self {
+            socket,
+            config,
+            message_id: 0,
+            listening_id: None,
+            attributes: None,
+            available: false,
+            written_limit: None,
+        }
+    }
+
+    /// Listens to the server, and follows the device until the bridge stops or the connection is lost.
+    async fn run(
+        &mut self,
+        state: &watch::Sender<State>,
+        limit: &mut watch::Receiver<Option<f64>>,
+    ) -> eyre::Result<()> {
+        self.listening_id = Some(self.send("start_listening", json!({})).await?);
+
+        let mut refresh = tokio::time::interval(ADJUSTMENT_REFRESH);
+        loop {
+            tokio::select! {
+                message = self.receive() => {
+                    self.handle(message?)?;
+                    state.send_replace(self.state());
+                }
+                _ = refresh.tick() => {
+                    // A power adjustment ends by itself, so it's requested again while the limit lasts.
+                    let adjusted = self.written_limit.flatten().is_some();
+                    if matches!(self.config.control, Control::PowerAdjustment) && adjusted {
+                        self.written_limit = None;
+                    }
+                }
+                changed = limit.changed() => if changed.is_err() {
+                    return Ok(());
+                },
+            }
+
+            let limit_w = *limit.borrow_and_update();
+            if self.attributes.is_some() && self.written_limit != Some(limit_w) {
+                self.write_limit(limit_w).await?;
+            }
+        }
+    }
+
+    /// Sends a command to the server, and returns its message ID.
+    async fn send(&mut self, command: &str, args: Value) -> eyre::Result<String> {
+        self.message_id += 1;
+        let message_id = self.message_id.to_string();
+        let message = json!({"message_id": message_id, "command": command, "args": args});
+        self.socket
+            .send(WebSocketMessage::Text(message.to_string()))
+            .await?;
+        Ok(message_id)
+    }
+
+    /// Receives the next message from the server.
+    async fn receive(&mut self) -> eyre::Result<Value> {
+        loop {
+            match self.socket.next().await {
+                Some(Ok(WebSocketMessage::Text(text))) => {
+                    return serde_json::from_str(&text)
+                        .wrap_err("Invalid message from the Matter server")
+                }
+                Some(Ok(WebSocketMessage::Close(_))) | None => {
+                    return Err(eyre!("The Matter server closed the connection"))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Err(error.into()),
+            }
+        }
+    }
+
+    /// Takes in a message from the server: an answer to a command, or an event.
+    fn handle(&mut self, message: Value) -> eyre::Result<()> {
+        let node_id = self.config.node_id;
+        if let Some(message_id) = message["message_id"].as_str() {
+            let listening = self.listening_id.as_deref() == Some(message_id);
+            if message.get("error_code").is_some() {
+                let details = message["details"].as_str().unwrap_or_default();
+                if listening {
+                    return Err(eyre!("The Matter server didn't start listening: {details}"));
+                }
+                tracing::warn!(
+                    "The Matter server couldn't pass a command on to the device: {details}"
+                );
+            } else if listening {
+                let nodes = message["result"].as_array().into_iter().flatten();
+                let node = nodes
+                    .into_iter()
+                    .find(|node| node["node_id"].as_u64() == Some(node_id))
+                    .ok_or_else(|| {
+                        eyre!(
+                            "The Matter server has no node {node_id}; commission the device first"
+                        )
+                    })?;
+                self.update_node(node)?;
+            }
+            return Ok(());
+        }
+
+        let data = &message["data"];
+        match message["event"].as_str() {
+            Some("node_added" | "node_updated") if data["node_id"].as_u64() == Some(node_id) => {
+                self.update_node(data)?;
+            }
+            Some("attribute_updated") if data[0].as_u64() == Some(node_id) => {
+                if let (Some(attributes), Some(path)) = (self.attributes.as_mut(), data[1].as_str())
+                {
+                    attributes.insert(path.to_string(), data[2].clone());
+                }
+            }
+            Some("node_removed") if data.as_u64() == Some(node_id) => {
+                return Err(eyre!("The device was removed from the Matter server"));
+            }
+            Some("server_shutdown") => return Err(eyre!("The Matter server shut down")),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Takes in all data of the device, and checks that it has the cluster its power is limited with.
+    fn update_node(&mut self, node: &Value) -> eyre::Result<()> {
+        let attributes = node["attributes"].as_object().cloned().unwrap_or_default();
+        let (cluster, name) = match self.config.control {
+            Control::PowerAdjustment => (DEVICE_ENERGY_MANAGEMENT, "Device Energy Management"),
+            Control::Evse { .. } => (ENERGY_EVSE, "Energy EVSE"),
+        };
+        let prefix = format!("{}/{cluster}/", self.config.endpoint);
+        if !attributes.keys().any(|path| path.starts_with(&prefix)) {
+            return Err(eyre!(
+                "Endpoint {} of node {} has no {name} cluster; set MATTER_ENDPOINT to the endpoint of the device",
+                self.config.endpoint,
+                self.config.node_id
+            ));
+        }
+        self.available = node["available"].as_bool().unwrap_or(true);
+        if !self.available {
+            tracing::warn!("The Matter server can't reach node {}", self.config.node_id);
+        }
+        self.attributes = Some(attributes);
+        Ok(())
+    }
+
+    /// The value of an attribute of the device, from its endpoint and its cluster and attribute IDs.
+    fn attribute(&self, endpoint: u64, cluster: u32, attribute: u32) -> Option<&Value> {
+        self.attributes
+            .as_ref()?
+            .get(&format!("{endpoint}/{cluster}/{attribute}"))
+    }
+
+    /// What the bridge knows about the device now.
+    fn state(&self) -> State {
+        let Some(_) = self.attributes else {
+            return State::default();
+        };
+        let text = |attribute| {
+            let value = self
+                .attribute(0, BASIC_INFORMATION, attribute)?
+                .as_str()?
+                .trim();
+            (!value.is_empty()).then(|| value.to_string())
+        };
+        // Matter gives power in mW.
+        let milliwatts = |cluster, attribute| {
+            self.attribute(self.config.endpoint, cluster, attribute)?
+                .as_f64()
+        };
+        State {
+            info: Some(NodeInfo {
+                vendor: text(0x0001),
+                product: text(0x0003),
+                serial_number: text(0x000F),
+                // AbsMaxPower
+                max_power_w: milliwatts(DEVICE_ENERGY_MANAGEMENT, 0x0004)
+                    .map(|power| power / 1000.0),
+            }),
+            // ActivePower, which is positive when the device consumes.
+            power_w: milliwatts(ELECTRICAL_POWER_MEASUREMENT, 0x0008)
+                .filter(|_| self.available)
+                .map(|power| power / 1000.0),
+        }
+    }
+
+    /// Passes the limit on to the device with the cluster it's limited with.
+    async fn write_limit(&mut self, limit_w: Option<f64>) -> eyre::Result<()> {
+        match (&self.config.control, limit_w) {
+            (Control::PowerAdjustment, Some(limit_w)) => {
+                tracing::info!("Adjusting the power of the Matter device to {limit_w:.0} W");
+                let payload = json!({
+                    "power": (limit_w * 1000.0).round() as i64,
+                    "duration": ADJUSTMENT_DURATION.as_secs(),
+                    "cause": ADJUSTMENT_CAUSE,
+                });
+                self.device_command(
+                    DEVICE_ENERGY_MANAGEMENT,
+                    "PowerAdjustRequest",
+                    payload,
+                    false,
+                )
+                .await?;
+            }
+            (Control::PowerAdjustment, None) => {
+                // An adjustment from an earlier connection ends by itself, so only ours is cancelled.
+                if self.written_limit.flatten().is_some() {
+                    tracing::info!("Cancelling the power adjustment of the Matter device");
+                    self.device_command(
+                        DEVICE_ENERGY_MANAGEMENT,
+                        "CancelPowerAdjustRequest",
+                        json!({}),
+                        false,
+                    )
+                    .await?;
+                }
+            }
+            (&Control::Evse { phases }, limit_w) => {
+                let current_a = limit_w.map_or(EVSE_MAX_CURRENT_A, |limit_w| {
+                    limit_w / (PHASE_VOLTAGE_V * f64::from(phases))
+                });
+                if current_a < EVSE_MIN_CURRENT_A {
+                    tracing::info!(
+                        "Pausing the Matter EVSE, as {current_a:.1} A is too little to charge with"
+                    );
+                    self.device_command(ENERGY_EVSE, "Disable", json!({}), true)
+                        .await?;
+                } else {
+                    match limit_w {
+                        Some(_) => tracing::info!("Limiting the Matter EVSE to {current_a:.1} A"),
+                        None => tracing::info!("Lifting the limit of the Matter EVSE"),
+                    }
+                    let payload = json!({
+                        "chargingEnabledUntil": null,
+                        "minimumChargeCurrent": (EVSE_MIN_CURRENT_A * 1000.0) as i64,
+                        "maximumChargeCurrent": (current_a * 1000.0).floor() as i64,
+                    });
+                    self.device_command(ENERGY_EVSE, "EnableCharging", payload, true)
+                        .await?;
+                }
+            }
+        }
+        self.written_limit = Some(limit_w);
+        Ok(())
+    }
+
+    /// Sends a command to the cluster of the device on its endpoint. The server answers whether the device took it,
+    /// which is only logged when it didn't.
+    async fn device_command(
+        &mut self,
+        cluster: u32,
+        name: &str,
+        payload: Value,
+        timed: bool,
+    ) -> eyre::Result<()> {
+        let args = json!({
+            "node_id": self.config.node_id,
+            "endpoint_id": self.config.endpoint,
+            "cluster_id": cluster,
+            "command_name": name,
+            "payload": payload,
+            "response_type": null,
+            "timed_request_timeout_ms": timed.then_some(TIMED_REQUEST_TIMEOUT_MS),
+        });
+        self.send("device_command", args).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use device::{Control, DeviceConfig, MatterDevice};
+use eyre::{eyre, Context};
+use simulator_common::{LimitedConsumer, Settings, Timeline};
+use std::time::Duration;
+
+mod device;
+
+/// The highest limit when neither `MAX_POWER_W` nor the device tell what the device consumes at most, in W.
+const DEFAULT_MAX_POWER_W: f64 = 11000.0;
+/// How long the Matter server may take to tell about the device.
+const NODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the bridge with the given settings, until it's stopped with Ctrl-C or SIGTERM.
+///
+/// Unlike the simulators, the bridge follows a real device, so it always runs in real time.
+pub async fn run(settings: &impl Settings) -> eyre::Result<()> {
+    // Read the configuration before connecting, so problems with it are reported right away.
+    let url = settings
+        .get("MATTER_SERVER_URL")
+        .unwrap_or_else(|| "ws://localhost:5580/ws".into());
+    let node_id = settings
+        .get("MATTER_NODE_ID")
+        .ok_or_else(|| {
+            eyre!("Could not read the node ID of the Matter device from MATTER_NODE_ID")
+        })?
+        .parse()
+        .wrap_err("Could not parse MATTER_NODE_ID as a whole number")?;
+    let endpoint = settings.get_or("MATTER_ENDPOINT", 1)?;
+    let control = match settings.get("MATTER_CONTROL").as_deref() {
+        Some("POWER_ADJUSTMENT") | None => Control::PowerAdjustment,
+        Some("EVSE") => {
+            let phases = settings.get_or("EVSE_PHASES", 3)?;
+            if !(1..=3).contains(&phases) {
+                return Err(eyre!(
+                    "Invalid value for EVSE_PHASES ({phases}); should be 1, 2 or 3"
+                ));
+            }
+            Control::Evse { phases }
+        }
+        Some(other) => {
+            return Err(eyre!(
+                "Invalid value for MATTER_CONTROL ({other}); should be POWER_ADJUSTMENT or EVSE"
+            ))
+        }
+    };
+    let max_power_w: Option<f64> = settings
+        .get("MAX_POWER_W")
+        .map(|power| power.parse())
+        .transpose()
+        .wrap_err("Could not parse MAX_POWER_W as a number")?;
+    if max_power_w.is_some_and(|power| power <= 0.0) {
+        return Err(eyre!("MAX_POWER_W should be more than 0"));
+    }
+    let update_interval = Duration::from_secs(settings.get_or("UPDATE_INTERVAL", 10)?);
+    if update_interval.is_zero() {
+        return Err(eyre!("UPDATE_INTERVAL should be at least 1 second"));
+    }
+
+    // The CEM is told what the device is when the session starts, so the Matter server is asked first.
+    let mut device = MatterDevice::start(DeviceConfig {
+        url,
+        node_id,
+        endpoint,
+        control,
+    });
+    let info = device.wait_for_node(NODE_TIMEOUT).await.ok_or_else(|| {
+        eyre!("No data on Matter node {node_id} within {NODE_TIMEOUT:?}; see the warnings above")
+    })?;
+    tracing::info!(
+        "Found Matter node {node_id} ({})",
+        info.product.as_deref().unwrap_or("unknown product")
+    );
+    // Without MAX_POWER_W, the most the device says it can consume is the highest limit.
+    let max_power_w = max_power_w
+        .or(info.max_power_w.filter(|&power| power > 0.0))
+        .unwrap_or(DEFAULT_MAX_POWER_W);
+
+    let connection = simulator_common::connect(settings).await?;
+    simulator_common::run(
+        connection,
+        LimitedConsumer::new(device, max_power_w, update_interval),
+        Timeline::default(),
+    )
+    .await
+}
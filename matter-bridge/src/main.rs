@@ -0,0 +1,14 @@
+use simulator_common::{ConfigFile, EnvSettings, Settings};
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    // The bridge is configured through environment variables; see docker-compose.yml for the available options.
+    // They can also be set in a configuration file, in which case the environment variables take precedence.
+    let config_file = match std::env::var("CONFIG_PATH") {
+        Ok(path) => ConfigFile::from_path(path)?,
+        Err(_) => ConfigFile::default(),
+    };
+    let settings = EnvSettings.or(config_file);
+    let _telemetry = simulator_common::telemetry::init(&settings, "matter-bridge")?;
+    matter_bridge::run(&settings).await
+}
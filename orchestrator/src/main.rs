@@ -0,0 +1,158 @@
+use clap::Parser;
+use eyre::{bail, eyre};
+use simulator_common::{telemetry, ConfigFile, Settings};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+/// Runs several simulated devices in one process, each with its own S2 connection with the CEM.
+///
+/// The devices are listed in a TOML or YAML file under `devices`, each with a `type` (`battery` or `pv`), an optional
+/// `name` and its own settings. The settings outside of `devices` apply to every device; see orchestrator-example.yaml.
+#[derive(Parser, Debug)]
+#[command(name = "orchestrator", version)]
+struct Cli {
+    /// The configuration file with the devices.
+    #[arg(env = "CONFIG_PATH")]
+    config: PathBuf,
+}
+
+/// The kinds of devices the orchestrator can run.
+#[derive(Debug, Clone, Copy)]
+enum DeviceType {
+    Battery,
+    Pv,
+}
+
+impl DeviceType {
+    fn from_settings(settings: &impl Settings) -> eyre::Result<Self> {
+        match settings.get("TYPE").as_deref() {
+            Some("battery") => Ok(Self::Battery),
+            Some("pv") => Ok(Self::Pv),
+            Some(other) => bail!("Unknown device type {other}; should be battery or pv"),
+            None => bail!("Every device needs a type (battery or pv)"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Battery => "battery",
+            Self::Pv => "pv",
+        }
+    }
+}
+
+/// Settings that differ per device unless they're set explicitly, so the devices don't get in each other's way.
+struct DeviceDefaults {
+    mqtt_topic_prefix: String,
+    /// A subdirectory of the recording directory at the top level, if there is one.
+    recording_directory: Option<String>,
+    /// A subdirectory of the CSV directory at the top level, if there is one.
+    csv_directory: Option<String>,
+}
+
+impl Settings for DeviceDefaults {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "MQTT_TOPIC_PREFIX" => Some(self.mqtt_topic_prefix.clone()),
+            "RECORDING_DIRECTORY" => self.recording_directory.clone(),
+            "CSV_DIRECTORY" => self.csv_directory.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<ExitCode> {
+    let cli = Cli::parse();
+    let config = ConfigFile::from_path(&cli.config)?;
+    let _telemetry = telemetry::init(&config, "orchestrator")?;
+    if config.devices().is_empty() {
+        return Err(eyre!(
+            "{} doesn't list any devices under devices",
+            cli.config.display()
+        ));
+    }
+
+    // Check all devices before starting any, so a typo doesn't leave half of them running.
+    let mut devices = Vec::new();
+    for (index, device) in config.devices().iter().enumerate() {
+        let device_type = DeviceType::from_settings(device)
+            .map_err(|error| eyre!("Invalid device {}: {error}", index + 1))?;
+        for setting in ["TUI", "COMMANDS"] {
+            if device.clone().or(config.clone()).get_or(setting, false)? {
+                return Err(eyre!(
+                    "{setting} can't be used with the orchestrator, as the devices would share the terminal"
+                ));
+            }
+        }
+        if device
+            .clone()
+            .or(config.clone())
+            .get("MOSAIK_ADDRESS")
+            .is_some()
+        {
+            return Err(eyre!(
+                "MOSAIK_ADDRESS can't be used with the orchestrator, as the devices would share the simulated time"
+            ));
+        }
+        let name = device
+            .get("NAME")
+            .unwrap_or_else(|| format!("{}-{}", device_type.name(), index + 1));
+        devices.push((device_type, name, device.clone()));
+    }
+
+    let mut tasks = JoinSet::new();
+    for (device_type, name, device) in devices {
+        let parent_prefix = config
+            .get("MQTT_TOPIC_PREFIX")
+            .unwrap_or_else(|| "s2-simulator".into());
+        // Files are named after the time they're created, so devices that write at the same moment need a directory of
+        // their own.
+        let subdirectory = |setting| {
+            config
+                .get(setting)
+                .map(|directory| format!("{directory}/{name}"))
+        };
+        let defaults = DeviceDefaults {
+            mqtt_topic_prefix: format!("{parent_prefix}/{name}"),
+            recording_directory: subdirectory("RECORDING_DIRECTORY"),
+            csv_directory: subdirectory("CSV_DIRECTORY"),
+        };
+        let settings = device.or(defaults).or(config.clone());
+        let span = tracing::info_span!("device", name);
+        tasks.spawn(
+            async move {
+                tracing::info!("Starting {} simulator", device_type.name());
+                let result = match device_type {
+                    DeviceType::Battery => battery::run(&settings).await,
+                    DeviceType::Pv => pv_installation::run(&settings).await,
+                };
+                (name, result)
+            }
+            .instrument(span),
+        );
+    }
+
+    // A device that stops doesn't affect the others; they keep running until the orchestrator is stopped.
+    let mut failed = false;
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((name, Ok(()))) => tracing::info!("Device {name} stopped"),
+            Ok((name, Err(error))) => {
+                tracing::error!("Device {name} stopped: {error:#}");
+                failed = true;
+            }
+            Err(error) => {
+                tracing::error!("A device crashed: {error}");
+                failed = true;
+            }
+        }
+    }
+    Ok(if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
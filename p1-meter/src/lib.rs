@@ -0,0 +1,45 @@
+use eyre::eyre;
+use meter::SmartMeter;
+use p1::{P1Config, P1Port};
+use simulator_common::{Settings, Timeline};
+use std::time::Duration;
+
+mod meter;
+mod p1;
+
+/// How long the smart meter may take to send its first telegram.
+const FIRST_TELEGRAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the RM with the given settings, until it's stopped with Ctrl-C or SIGTERM.
+///
+/// Unlike the simulators, the RM reads a real meter, so it always runs in real time.
+pub async fn run(settings: &impl Settings) -> eyre::Result<()> {
+    // Read the configuration before connecting, so problems with it are reported right away.
+    let config = P1Config::from_settings(settings)?;
+    let update_interval = Duration::from_secs(settings.get_or("UPDATE_INTERVAL", 10)?);
+    if update_interval.is_zero() {
+        return Err(eyre!("UPDATE_INTERVAL should be at least 1 second"));
+    }
+
+    // The CEM is told which phases are measured when the session starts, so the meter is read first.
+    let mut port = P1Port::start(config);
+    let telegram = port
+        .wait_for_telegram(FIRST_TELEGRAM_TIMEOUT)
+        .await
+        .ok_or_else(|| {
+            eyre!("No telegram from the smart meter within {FIRST_TELEGRAM_TIMEOUT:?}; see the warnings above")
+        })?;
+    tracing::info!(
+        "Found smart meter {} measuring {} phase(s)",
+        telegram.identification,
+        telegram.phase_power_w.len()
+    );
+
+    let connection = simulator_common::connect(settings).await?;
+    simulator_common::run(
+        connection,
+        SmartMeter::new(port, telegram, update_interval),
+        Timeline::default(),
+    )
+    .await
+}
@@ -0,0 +1,122 @@
+use crate::p1::{P1Port, Telegram};
+use s2energy::common::{
+    Commodity, CommodityQuantity, ControlType, Id, Message, PowerMeasurement, PowerValue,
+    ResourceManagerDetails, Role, RoleType,
+};
+use simulator_common::{rm_details, time, DeviceState, RmSimulator};
+use std::time::Duration;
+
+/// The commodity quantities of the phases, in the order the meter reports them.
+const PHASES: [CommodityQuantity; 3] = [
+    CommodityQuantity::ElectricPowerL1,
+    CommodityQuantity::ElectricPowerL2,
+    CommodityQuantity::ElectricPowerL3,
+];
+
+/// An RM for the smart meter of the grid connection, which reports the power the site takes from the grid.
+///
+/// The CEM can't control the meter, so it's `NOT_CONTROLABLE`, and it sends no forecasts. Its power measurements have
+/// the power per phase, for the phases the meter reported in its first telegram, which is what most CEM algorithms
+/// balance against. A meter that doesn't report the power per phase is measured in total.
+pub struct SmartMeter {
+    port: P1Port,
+    /// The identification and equipment identifier of the meter, from its first telegram.
+    first_telegram: Telegram,
+    /// The number of phases the power is measured on, if the meter reports them.
+    phases: usize,
+    update_interval: Duration,
+    /// The power in the latest measurement.
+    last_power_w: Option<f64>,
+}
+
+impl SmartMeter {
+    pub fn new(port: P1Port, first_telegram: Telegram, update_interval: Duration) -> Self {
+        Self {
+            port,
+            phases: first_telegram.phase_power_w.len(),
+            first_telegram,
+            update_interval,
+            last_power_w: None,
+        }
+    }
+
+    fn commodity_quantities(&self) -> Vec<CommodityQuantity> {
+        match self.phases {
+            0 => vec![CommodityQuantity::ElectricPower3PhaseSymmetric],
+            phases => PHASES[..phases].to_vec(),
+        }
+    }
+
+    fn power_values(&self, telegram: &Telegram) -> Option<Vec<PowerValue>> {
+        if self.phases == 0 {
+            return Some(vec![PowerValue {
+                commodity_quantity: CommodityQuantity::ElectricPower3PhaseSymmetric,
+                value: telegram.power_w,
+            }]);
+        }
+        // We told the CEM which phases are measured, so a telegram without them can't be sent.
+        if telegram.phase_power_w.len() != self.phases {
+            return None;
+        }
+        let values = PHASES
+            .iter()
+            .zip(&telegram.phase_power_w)
+            .map(|(&commodity_quantity, &value)| PowerValue {
+                commodity_quantity,
+                value,
+            })
+            .collect();
+        Some(values)
+    }
+}
+
+impl RmSimulator for SmartMeter {
+    fn resource_manager_details(&self) -> ResourceManagerDetails {
+        ResourceManagerDetails {
+            name: Some("Smart meter".into()),
+            model: Some(self.first_telegram.identification.clone()),
+            serial_number: self.first_telegram.equipment_id.clone(),
+            provides_forecast: false,
+            provides_power_measurement_types: self.commodity_quantities(),
+            // The site behind the meter takes power from the grid, and may return it.
+            roles: vec![
+                Role::new(Commodity::Electricity, RoleType::EnergyConsumer),
+                Role::new(Commodity::Electricity, RoleType::EnergyProducer),
+            ],
+            ..rm_details::new(vec![ControlType::NotControlable], RoleType::EnergyConsumer)
+        }
+    }
+
+    fn initial_messages(&mut self, _control_type: ControlType) -> eyre::Result<Vec<Message>> {
+        // Measurements are sent with the periodic updates, so there is nothing else the CEM needs.
+        Ok(vec![])
+    }
+
+    /// Sends the power in the latest telegram every update.
+    async fn periodic_update(&mut self) -> eyre::Result<Vec<Message>> {
+        let telegram = self.port.latest();
+        self.last_power_w = telegram.as_ref().map(|telegram| telegram.power_w);
+        let Some(values) = telegram.and_then(|telegram| self.power_values(&telegram)) else {
+            tracing::warn!("The smart meter hasn't sent its power per phase recently, so no power measurement is sent");
+            return Ok(vec![]);
+        };
+        let power_measurement = PowerMeasurement {
+            measurement_timestamp: time::now(),
+            message_id: Id::generate(),
+            values,
+        };
+        tracing::info!("Sending power measurement: {power_measurement:?}");
+        Ok(vec![power_measurement.into()])
+    }
+
+    fn update_interval(&self) -> Duration {
+        self.update_interval
+    }
+
+    fn device_state(&self) -> DeviceState {
+        DeviceState {
+            power_w: self.last_power_w,
+            ..DeviceState::default()
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use eyre::{eyre, Context};
+use simulator_common::Settings;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_serial::{DataBits, Parity, SerialPortBuilderExt};
+
+/// How long to wait before opening the P1 port again when it's lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long a telegram counts as the latest reading; meters send one every 1 (DSMR 5) to 10 seconds (DSMR 4 and
+/// older).
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+/// Where the telegrams of the smart meter come from, configured with the `P1_*` settings.
+pub enum P1Config {
+    /// A serial port with a P1 cable, such as `/dev/ttyUSB0`.
+    Serial { path: String, baud_rate: u32 },
+    /// A P1 dongle or a serial-to-network server such as ser2net, which streams the telegrams over TCP.
+    Tcp { address: String },
+}
+
+impl P1Config {
+    /// Reads where the telegrams come from from the given settings.
+    pub fn from_settings(settings: &impl Settings) -> eyre::Result<Self> {
+        match (settings.get("P1_SERIAL_PORT"), settings.get("P1_ADDRESS")) {
+            (Some(path), None) => {
+                let baud_rate = settings.get_or("P1_BAUD_RATE", 115200)?;
+                if baud_rate == 0 {
+                    return Err(eyre!("P1_BAUD_RATE should be more than 0"));
+                }
+                Ok(Self::Serial { path, baud_rate })
+            }
+            (None, Some(address)) => Ok(Self::Tcp { address }),
+            (Some(_), Some(_)) => Err(eyre!(
+                "P1_SERIAL_PORT and P1_ADDRESS can't both be set; the telegrams come from a serial port or over TCP"
+            )),
+            (None, None) => Err(eyre!(
+                "Could not read where the smart meter is from P1_SERIAL_PORT or P1_ADDRESS"
+            )),
+        }
+    }
+
+    /// Opens the serial port or connects to the P1 dongle.
+    async fn open(&self) -> eyre::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        match self {
+            Self::Serial { path, baud_rate } => {
+                // DSMR 2.2 and 3 meters send at 9600 baud with 7 data bits and even parity, and later ones at 115200
+                // baud with 8 data bits and no parity.
+                let (data_bits, parity) = match baud_rate {
+                    9600 => (DataBits::Seven, Parity::Even),
+                    _ => (DataBits::Eight, Parity::None),
+                };
+                let port = tokio_serial::new(path, *baud_rate)
+                    .data_bits(data_bits)
+                    .parity(parity)
+                    .open_native_async()
+                    .wrap_err_with(|| format!("Could not open serial port {path}"))?;
+                Ok(Box::new(port))
+            }
+            Self::Tcp { address } => {
+                let stream = TcpStream::connect(address)
+                    .await
+                    .wrap_err_with(|| format!("Could not connect to {address}"))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for P1Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Serial { path, .. } => write!(f, "{path}"),
+            Self::Tcp { address } => write!(f, "{address}"),
+        }
+    }
+}
+
+/// A telegram of the smart meter, with what the RM needs from it.
+#[derive(Debug, Clone)]
+pub struct Telegram {
+    /// The identification of the meter in the header of the telegram, such as `ISk5\2MT382-1000`.
+    pub identification: String,
+    /// The equipment identifier of the meter, usually its serial number.
+    pub equipment_id: Option<String>,
+    /// The power taken from the grid in W, negative when power is returned to it.
+    pub power_w: f64,
+    /// The same per phase, for the phases the meter reports: only L1 on a single-phase connection.
+    pub phase_power_w: Vec<f64>,
+    at: Instant,
+}
+
+/// The P1 port of a Dutch or Belgian smart meter (DSMR or e-MUCS), read in the background.
+///
+/// The meter sends a telegram with its readings every few seconds. Telegrams of DSMR 4 and later end with a CRC, and
+/// those that don't match it are dropped, as they're damaged. When the port is lost, it's opened again.
+pub struct P1Port {
+    telegrams: watch::Receiver<Option<Telegram>>,
+}
+
+impl P1Port {
+    /// Starts reading the P1 port; this stops when the `P1Port` is dropped.
+    pub fn start(config: P1Config) -> Self {
+        let (sender, telegrams) = watch::channel(None);
+        tokio::spawn(read(config, sender));
+        Self { telegrams }
+    }
+
+    /// Waits for the first telegram, so the CEM is told which phases are measured. Returns `None` if it doesn't come
+    /// within `timeout`.
+    pub async fn wait_for_telegram(&mut self, timeout: Duration) -> Option<Telegram> {
+        let telegram =
+            tokio::time::timeout(timeout, self.telegrams.wait_for(Option::is_some)).await;
+        telegram.ok()?.ok()?.clone()
+    }
+
+    /// The latest telegram, unless there hasn't been one for a while.
+    pub fn latest(&self) -> Option<Telegram> {
+        let telegram = self.telegrams.borrow().clone()?;
+        (telegram.at.elapsed() < STALE_AFTER).then_some(telegram)
+    }
+}
+
+/// Reads telegrams from the P1 port until the `P1Port` is dropped, opening it again whenever it's lost.
+async fn read(config: P1Config, telegrams: watch::Sender<Option<Telegram>>) {
+    loop {
+        let result = async {
+            let port = config.open().await?;
+            tracing::info!("Reading the smart meter on {config}");
+            read_telegrams(port, &telegrams).await
+        };
+        tokio::select! {
+            result = result => match result {
+                Ok(()) => tracing::warn!("The smart meter on {config} closed the connection"),
+                Err(error) => tracing::warn!("No connection with the smart meter on {config}: {error:#}"),
+            },
+            // The RM stopped.
+            _ = telegrams.closed() => return,
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Reads telegrams until the end of the stream, and publishes every valid one.
+async fn read_telegrams(
+    port: impl AsyncRead + Unpin,
+    telegrams: &watch::Sender<Option<Telegram>>,
+) -> eyre::Result<()> {
+    let mut port = BufReader::new(port);
+    let mut line = Vec::new();
+    // The telegram so far, from its header on; we may start reading halfway through one.
+    let mut telegram: Option<Vec<u8>> = None;
+    loop {
+        line.clear();
+        if port.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+        if line.starts_with(b"/") {
+            telegram = Some(Vec::new());
+        }
+        let Some(data) = telegram.as_mut() else {
+            continue;
+        };
+        data.extend_from_slice(&line);
+        if line.starts_with(b"!") {
+            let data = telegram.take().unwrap_or_default();
+            match parse(&data) {
+                Ok(parsed) => {
+                    tracing::debug!(
+                        "The smart meter measured {:.0} W ({:?})",
+                        parsed.power_w,
+                        parsed.phase_power_w
+                    );
+                    telegrams.send_replace(Some(parsed));
+                }
+                Err(error) => tracing::warn!("Dropping a telegram of the smart meter: {error}"),
+            }
+        }
+    }
+}
+
+/// Parses a whole telegram, from the `/` of its header up to and including the line with the `!` and its CRC.
+fn parse(data: &[u8]) -> eyre::Result<Telegram> {
+    let end = data
+        .iter()
+        .position(|&byte| byte == b'!')
+        .ok_or_else(|| eyre!("it has no end"))?;
+    // The CRC covers everything from the `/` up to and including the `!`; DSMR 2.2 and 3 don't send one.
+    let crc = String::from_utf8_lossy(&data[end + 1..]).trim().to_string();
+    if !crc.is_empty() {
+        let expected = u16::from_str_radix(&crc, 16).map_err(|_| eyre!("invalid CRC ({crc})"))?;
+        let actual = crc16(&data[..=end]);
+        if actual != expected {
+            return Err(eyre!(
+                "its CRC is {actual:04X}, while it should be {expected:04X}"
+            ));
+        }
+    }
+
+    let text = String::from_utf8_lossy(&data[..end]);
+    let mut lines = text.lines();
+    let identification = lines
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('/')
+        .trim()
+        .to_string();
+    let mut equipment_id = None;
+    let mut delivered_w = None;
+    let mut returned_w = None;
+    // The power taken from and returned to the grid on L1, L2 and L3.
+    let mut phases = [(None, None); 3];
+    for line in lines {
+        let Some((obis, rest)) = line.split_once('(') else {
+            continue;
+        };
+        let value = rest.split(')').next().unwrap_or_default();
+        // The power is given in kW, such as `01.193*kW`.
+        let power_w = || {
+            let (number, _unit) = value.split_once('*')?;
+            number.parse::<f64>().ok().map(|kw| kw * 1000.0)
+        };
+        match obis {
+            "0-0:96.1.1" => equipment_id = decode_hex(value).or(equipment_id),
+            "1-0:1.7.0" => delivered_w = power_w(),
+            "1-0:2.7.0" => returned_w = power_w(),
+            "1-0:21.7.0" => phases[0].0 = power_w(),
+            "1-0:22.7.0" => phases[0].1 = power_w(),
+            "1-0:41.7.0" => phases[1].0 = power_w(),
+            "1-0:42.7.0" => phases[1].1 = power_w(),
+            "1-0:61.7.0" => phases[2].0 = power_w(),
+            "1-0:62.7.0" => phases[2].1 = power_w(),
+            _ => {}
+        }
+    }
+
+    // Meters only report the phases they're connected to, and some only report what's taken from the grid per phase.
+    let phase_power_w: Vec<f64> = phases
+        .iter()
+        .map_while(|&(delivered, returned)| Some(delivered? - returned.unwrap_or(0.0)))
+        .collect();
+    let power_w = match (delivered_w, returned_w) {
+        (Some(delivered), returned) => delivered - returned.unwrap_or(0.0),
+        (None, _) if !phase_power_w.is_empty() => phase_power_w.iter().sum(),
+        (None, _) => return Err(eyre!("it has no power (1-0:1.7.0)")),
+    };
+
+    Ok(Telegram {
+        identification,
+        equipment_id,
+        power_w,
+        phase_power_w,
+        at: Instant::now(),
+    })
+}
+
+/// The CRC16 of a telegram: CRC-16/ARC, with polynomial 0x8005 and least significant bit first.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Decodes the equipment identifier, which is sent as the hexadecimal codes of its characters.
+fn decode_hex(value: &str) -> Option<String> {
+    let bytes = (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let id = String::from_utf8(bytes).ok()?;
+    (!id.is_empty()).then_some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A telegram of a DSMR 5 meter with three phases, up to and including the `!`, with `\n` for the `\r\n` the
+    /// meter ends its lines with. Its CRC is 6EEE.
+    const TELEGRAM: &str = "/ISk5\\2MT382-1000
+
+1-3:0.2.8(50)
+0-0:1.0.0(170102192002W)
+0-0:96.1.1(4B384547303034303436333935353037)
+1-0:1.8.1(000004.426*kWh)
+1-0:1.8.2(000002.399*kWh)
+1-0:2.8.1(000002.444*kWh)
+1-0:2.8.2(000000.000*kWh)
+0-0:96.14.0(0002)
+1-0:1.7.0(00.244*kW)
+1-0:2.7.0(00.000*kW)
+0-0:96.7.21(00013)
+0-0:96.7.9(00000)
+1-0:99.97.0(0)(0-0:96.7.19)
+1-0:32.32.0(00000)
+1-0:52.32.0(00000)
+1-0:72.32.0(00000)
+1-0:32.36.0(00000)
+1-0:52.36.0(00000)
+1-0:72.36.0(00000)
+0-0:96.13.0()
+1-0:32.7.0(0230.0*V)
+1-0:52.7.0(0230.0*V)
+1-0:72.7.0(0229.0*V)
+1-0:31.7.0(0.48*A)
+1-0:51.7.0(0.44*A)
+1-0:71.7.0(0.86*A)
+1-0:21.7.0(00.070*kW)
+1-0:41.7.0(00.032*kW)
+1-0:61.7.0(00.142*kW)
+1-0:22.7.0(00.000*kW)
+1-0:42.7.0(00.000*kW)
+1-0:62.7.0(00.000*kW)
+0-1:24.1.0(003)
+0-1:96.1.0(3232323241424344313233343536373839)
+0-1:24.2.1(170102161005W)(00000.107*m3)
+0-2:24.1.0(003)
+0-2:96.1.0()
+!";
+
+    /// The bytes the meter sends for `text`, with the CRC on the last line if there is one.
+    fn telegram(text: &str, crc: &str) -> Vec<u8> {
+        format!("{text}{crc}\n").replace('\n', "\r\n").into_bytes()
+    }
+
+    /// `TELEGRAM` without the lines of the given OBIS codes, with the CRC of what's left.
+    fn without(obis: &[&str]) -> Vec<u8> {
+        let text: Vec<_> = TELEGRAM
+            .lines()
+            .filter(|line| {
+                !obis
+                    .iter()
+                    .any(|obis| line.starts_with(&format!("{obis}(")))
+            })
+            .collect();
+        let text = text.join("\n");
+        // The CRC covers everything but the line break after the `!`.
+        let data = telegram(&text, "");
+        let crc = crc16(&data[..data.len() - 2]);
+        telegram(&text, &format!("{crc:04X}"))
+    }
+
+    #[test]
+    fn computes_the_crc_of_a_telegram() {
+        let data = telegram(TELEGRAM, "");
+        assert_eq!(crc16(&data[..data.len() - 2]), 0x6EEE);
+    }
+
+    #[test]
+    fn parses_a_telegram_with_a_valid_crc() {
+        let parsed = parse(&telegram(TELEGRAM, "6EEE")).unwrap();
+        assert_eq!(parsed.identification, "ISk5\\2MT382-1000");
+        assert_eq!(parsed.equipment_id.as_deref(), Some("K8EG004046395507"));
+        assert_eq!(parsed.power_w, 244.0);
+        assert_eq!(parsed.phase_power_w, [70.0, 32.0, 142.0]);
+    }
+
+    #[test]
+    fn drops_a_telegram_with_a_corrupted_crc() {
+        let corrupted = TELEGRAM.replace("1-0:1.7.0(00.244*kW)", "1-0:1.7.0(00.245*kW)");
+        let error = parse(&telegram(&corrupted, "6EEE")).unwrap_err();
+        assert!(error.to_string().contains("CRC"), "{error}");
+        assert!(parse(&telegram(TELEGRAM, "6EEF")).is_err());
+        assert!(parse(&telegram(TELEGRAM, "XYZ")).is_err());
+    }
+
+    #[test]
+    fn parses_a_telegram_without_a_crc() {
+        // DSMR 2.2 and 3 meters don't send one.
+        let parsed = parse(&telegram(TELEGRAM, "")).unwrap();
+        assert_eq!(parsed.power_w, 244.0);
+    }
+
+    #[test]
+    fn reports_the_phases_the_meter_is_connected_to() {
+        let single_phase = without(&["1-0:41.7.0", "1-0:42.7.0", "1-0:61.7.0", "1-0:62.7.0"]);
+        assert_eq!(parse(&single_phase).unwrap().phase_power_w, [70.0]);
+        // The phases after a missing one are left out, so every value still belongs to the phase it's reported for.
+        let without_l2 = without(&["1-0:41.7.0"]);
+        assert_eq!(parse(&without_l2).unwrap().phase_power_w, [70.0]);
+    }
+
+    #[test]
+    fn reports_the_power_per_phase_without_what_is_returned() {
+        let delivered_only = without(&["1-0:22.7.0", "1-0:42.7.0", "1-0:62.7.0"]);
+        let parsed = parse(&delivered_only).unwrap();
+        assert_eq!(parsed.phase_power_w, [70.0, 32.0, 142.0]);
+    }
+
+    #[test]
+    fn adds_up_the_phases_without_the_total_power() {
+        let parsed = parse(&without(&["1-0:1.7.0", "1-0:2.7.0"])).unwrap();
+        assert!((parsed.power_w - 244.0).abs() < 1e-9, "{}", parsed.power_w);
+        let error = parse(&without(&[
+            "1-0:1.7.0",
+            "1-0:21.7.0",
+            "1-0:41.7.0",
+            "1-0:61.7.0",
+        ]))
+        .unwrap_err();
+        assert!(error.to_string().contains("no power"), "{error}");
+    }
+}
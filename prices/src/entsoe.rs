@@ -0,0 +1,255 @@
+use crate::{PriceFuture, PriceSource, Prices};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use eyre::{bail, eyre, Context, OptionExt};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const ENTSOE_URL: &str = "https://web-api.tp.entsoe.eu/api";
+/// The document type of day-ahead prices on the transparency platform.
+const DAY_AHEAD_PRICES: &str = "A44";
+/// The days of the European day-ahead market run from midnight to midnight in CET, or CEST in summer.
+const MARKET_TIME_ZONE: Tz = chrono_tz::Europe::Amsterdam;
+
+/// The EIC codes of some common bidding zones, so they can be given by name.
+const BIDDING_ZONES: &[(&str, &str)] = &[
+    ("AT", "10YAT-APG------L"),
+    ("BE", "10YBE----------2"),
+    ("CH", "10YCH-SWISSGRIDZ"),
+    ("DE-LU", "10Y1001A1001A82H"),
+    ("DK1", "10YDK-1--------W"),
+    ("DK2", "10YDK-2--------M"),
+    ("ES", "10YES-REE------0"),
+    ("FI", "10YFI-1--------U"),
+    ("FR", "10YFR-RTE------C"),
+    ("NL", "10YNL----------L"),
+    ("NO1", "10YNO-1--------2"),
+    ("PL", "10YPL-AREA-----S"),
+    ("PT", "10YPT-REN------W"),
+    ("SE3", "10Y1001A1001A46L"),
+];
+
+/// Fetches day-ahead prices from the [ENTSO-E transparency platform](https://transparency.entsoe.eu), which needs a
+/// security token: register on the platform and ask for API access to get one.
+///
+/// The platform publishes the prices of the next day around noon, in EUR per MWh, for every hour or quarter of an hour
+/// depending on the market; these come as the price per kWh.
+pub struct Entsoe {
+    client: reqwest::Client,
+    token: String,
+    /// The EIC code of the bidding zone.
+    zone: String,
+}
+
+impl Entsoe {
+    /// Fetches the prices of a bidding zone, given by name, such as `NL` or `DE-LU`, or by its EIC code.
+    pub fn new(token: impl Into<String>, zone: &str) -> eyre::Result<Self> {
+        let zone = BIDDING_ZONES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(zone))
+            .map(|(_, code)| code.to_string())
+            .or_else(|| zone.starts_with("10Y").then(|| zone.to_string()))
+            .ok_or_else(|| {
+                let names: Vec<_> = BIDDING_ZONES.iter().map(|(name, _)| *name).collect();
+                eyre!(
+                    "Unknown bidding zone {zone}; give its EIC code, or one of {}",
+                    names.join(", ")
+                )
+            })?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .wrap_err("Could not set up HTTP client")?;
+
+        Ok(Self {
+            client,
+            token: token.into(),
+            zone,
+        })
+    }
+
+    /// Fetches the prices of the market day `date`, which starts at midnight in CET or CEST, so on the day before in
+    /// UTC.
+    async fn fetch(&self, date: NaiveDate) -> eyre::Result<Prices> {
+        let start = market_day_start(date)?;
+        let end = market_day_start(date.succ_opt().ok_or_eyre("Invalid date")?)?;
+        let period = |time: DateTime<Utc>| time.format("%Y%m%d%H%M").to_string();
+        let response = self
+            .client
+            .get(ENTSOE_URL)
+            .query(&[
+                ("securityToken", self.token.clone()),
+                ("documentType", DAY_AHEAD_PRICES.into()),
+                ("in_Domain", self.zone.clone()),
+                ("out_Domain", self.zone.clone()),
+                ("periodStart", period(start)),
+                ("periodEnd", period(end)),
+            ])
+            .send()
+            .await
+            // The URL has the security token in it.
+            .map_err(reqwest::Error::without_url)
+            .wrap_err("Could not reach the ENTSO-E transparency platform")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .wrap_err("Could not read the response from the ENTSO-E transparency platform")?;
+        let document = match roxmltree::Document::parse(&body) {
+            Ok(document) => document,
+            Err(_) if !status.is_success() => {
+                bail!("The ENTSO-E transparency platform returned {status}")
+            }
+            Err(error) => {
+                return Err(error).wrap_err(
+                    "Could not parse the response from the ENTSO-E transparency platform",
+                )
+            }
+        };
+        // Errors, including that there are no prices for the period, come as an acknowledgement with a reason.
+        if let Some(reason) = document
+            .descendants()
+            .find(|node| node.has_tag_name("Reason"))
+            .and_then(|reason| child_text(reason, "text"))
+        {
+            bail!("The ENTSO-E transparency platform returned {status}: {reason}");
+        }
+        if !status.is_success() {
+            bail!("The ENTSO-E transparency platform returned {status}");
+        }
+        parse_prices(&document)
+    }
+}
+
+impl PriceSource for Entsoe {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        Box::pin(async move {
+            // A day in UTC starts on the market day of the same date, and its last hours are on the next one, which
+            // isn't published until around noon.
+            let prices = self.fetch(date).await?;
+            let next = date.succ_opt().ok_or_eyre("Invalid date")?;
+            let prices = match self.fetch(next).await {
+                Ok(next) => prices.join(&next),
+                Err(error) => {
+                    tracing::debug!("No prices for the market day {next} yet: {error:#}");
+                    prices
+                }
+            };
+            let midnight = date
+                .and_hms_opt(0, 0, 0)
+                .ok_or_eyre("Invalid date")?
+                .and_utc();
+            prices
+                .between(midnight, midnight + TimeDelta::days(1))
+                .ok_or_else(|| {
+                    eyre!("The ENTSO-E transparency platform has no prices for {date} yet")
+                })
+        })
+    }
+}
+
+/// Reads the prices from a publication market document, which has a time series with periods of points at a fixed
+/// resolution. A point that's left out has the same price as the one before it.
+fn parse_prices(document: &roxmltree::Document) -> eyre::Result<Prices> {
+    let mut prices = BTreeMap::new();
+    let mut end = None;
+    for period in document
+        .descendants()
+        .filter(|node| node.has_tag_name("Period"))
+    {
+        let interval = period
+            .children()
+            .find(|node| node.has_tag_name("timeInterval"))
+            .ok_or_eyre("A period has no time interval")?;
+        let start = parse_time(child_text(interval, "start").ok_or_eyre("A period has no start")?)?;
+        let period_end =
+            parse_time(child_text(interval, "end").ok_or_eyre("A period has no end")?)?;
+        let resolution = parse_resolution(
+            child_text(period, "resolution").ok_or_eyre("A period has no resolution")?,
+        )?;
+
+        let mut points = BTreeMap::new();
+        for point in period.children().filter(|node| node.has_tag_name("Point")) {
+            let position: i32 = child_text(point, "position")
+                .ok_or_eyre("A point has no position")?
+                .parse()
+                .wrap_err("Invalid position")?;
+            let price: f64 = child_text(point, "price.amount")
+                .ok_or_eyre("A point has no price")?
+                .parse()
+                .wrap_err("Invalid price")?;
+            points.insert(position, price);
+        }
+        let mut time = start;
+        let mut price = None;
+        for position in 1.. {
+            if time >= period_end {
+                break;
+            }
+            price = points.get(&position).copied().or(price);
+            // The prices come per MWh, and a series with several classifications repeats them.
+            if let Some(price) = price {
+                prices.entry(time).or_insert(price / 1000.0);
+            }
+            time += resolution;
+        }
+        end = end.max(Some(period_end));
+    }
+    Prices::new(prices, end.ok_or_eyre("The response has no prices")?)
+}
+
+/// When the market day `date` starts, in UTC.
+fn market_day_start(date: NaiveDate) -> eyre::Result<DateTime<Utc>> {
+    let midnight = date.and_hms_opt(0, 0, 0).ok_or_eyre("Invalid date")?;
+    // Summer time starts and ends at night, but never at midnight, so every market day has a single start.
+    MARKET_TIME_ZONE
+        .from_local_datetime(&midnight)
+        .single()
+        .map(|start| start.to_utc())
+        .ok_or_eyre("Invalid date")
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.has_tag_name(name))
+        .and_then(|child| child.text())
+}
+
+/// Parses a time like `2025-06-01T22:00Z`, which the platform gives without seconds.
+fn parse_time(text: &str) -> eyre::Result<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%MZ")
+        .map(|time| time.and_utc())
+        .or_else(|_| DateTime::parse_from_rfc3339(text).map(|time| time.to_utc()))
+        .wrap_err_with(|| format!("Invalid time {text}"))
+}
+
+/// Parses a resolution like `PT15M` or `PT60M`.
+fn parse_resolution(text: &str) -> eyre::Result<TimeDelta> {
+    text.strip_prefix("PT")
+        .and_then(|minutes| minutes.strip_suffix('M'))
+        .and_then(|minutes| minutes.parse().ok())
+        .filter(|minutes| *minutes > 0)
+        .map(TimeDelta::minutes)
+        .ok_or_else(|| eyre!("Unsupported resolution {text}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(date: &str) -> String {
+        market_day_start(date.parse().unwrap())
+            .unwrap()
+            .to_rfc3339()
+    }
+
+    #[test]
+    fn market_days_start_at_midnight_in_cet_or_cest() {
+        assert_eq!(start("2025-01-15"), "2025-01-14T23:00:00+00:00");
+        assert_eq!(start("2025-06-01"), "2025-05-31T22:00:00+00:00");
+        // Summer time starts on the night of 30 March, so that market day is 23 hours long.
+        assert_eq!(start("2025-03-30"), "2025-03-29T23:00:00+00:00");
+        assert_eq!(start("2025-03-31"), "2025-03-30T22:00:00+00:00");
+    }
+}
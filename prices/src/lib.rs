@@ -0,0 +1,295 @@
+//! Day-ahead electricity prices, for the CEM and for simulators that take the cost of energy into account.
+//!
+//! A [`PriceSource`] gets the prices of a day, so the CEM works the same wherever they come from: a fixed
+//! [`StaticCurve`], a [`PriceFile`], or a market. [`Entsoe`] fetches the day-ahead prices of a bidding zone from the
+//! [ENTSO-E transparency platform](https://transparency.entsoe.eu), which publishes them for every European market, and
+//! [`NordPool`] from the Nord Pool data portal. [`Cached`] keeps the prices of a market in a directory, so a demo that
+//! restarts doesn't ask for them again, and [`Fallback`] takes them from a CSV file when the market can't be reached.
+//! Without market data, [`Synthetic`] generates realistic prices from a seed, which the `s2-price-signal` tool writes
+//! to a CSV file.
+//! Either way, the prices come as [`Prices`], which also reads and writes that CSV format: a header row, followed by
+//! rows with an RFC 3339 timestamp and the price per kWh from then on, such as:
+//!
+//! ```csv
+//! timestamp,price
+//! 2025-06-01T00:00:00Z,0.0912
+//! 2025-06-01T01:00:00Z,0.0874
+//! ```
+
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use eyre::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+mod entsoe;
+mod nord_pool;
+mod source;
+mod synthetic;
+
+pub use entsoe::Entsoe;
+pub use nord_pool::NordPool;
+pub use source::{Cached, Fallback, PriceFile, PriceFuture, PriceSource, StaticCurve};
+pub use synthetic::Synthetic;
+
+/// Energy prices per kWh over a period of time, such as the day-ahead prices of a day: every price holds from its
+/// start until the next one starts, or the period ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prices {
+    prices: BTreeMap<DateTime<Utc>, f64>,
+    end: DateTime<Utc>,
+}
+
+impl Prices {
+    /// Prices with the given starts, up to `end`. Returns an error if there are no prices, or they don't start before
+    /// `end`.
+    pub fn new(prices: BTreeMap<DateTime<Utc>, f64>, end: DateTime<Utc>) -> eyre::Result<Self> {
+        match prices.keys().next_back() {
+            None => bail!("There are no prices"),
+            Some(last) if *last >= end => bail!("The prices start after they end, at {end}"),
+            Some(_) => Ok(Self { prices, end }),
+        }
+    }
+
+    /// Reads prices in the CSV format, with a header row followed by rows with an RFC 3339 timestamp and a price per
+    /// kWh. The last price holds for as long as the shortest one.
+    pub fn from_csv(reader: impl std::io::Read) -> eyre::Result<Self> {
+        let mut csv_reader = csv::Reader::from_reader(reader);
+        let headers = csv_reader
+            .headers()
+            .wrap_err("Could not read the header row")?
+            .clone();
+
+        let mut prices = BTreeMap::new();
+        for record in csv_reader.records() {
+            let record = record.wrap_err("Could not read row")?;
+            let line = record
+                .position()
+                .map(|position| position.line())
+                .unwrap_or_default();
+            let row: PriceRow = record.deserialize(Some(&headers)).wrap_err_with(|| {
+                format!("Malformed row on line {line}; expected an RFC 3339 timestamp and a number")
+            })?;
+            if prices.insert(row.timestamp, row.price).is_some() {
+                bail!(
+                    "Timestamp {} on line {line} occurs more than once",
+                    row.timestamp
+                );
+            }
+        }
+
+        let starts: Vec<_> = prices.keys().collect();
+        let shortest = starts
+            .windows(2)
+            .map(|pair| *pair[1] - *pair[0])
+            .min()
+            .unwrap_or(TimeDelta::hours(1));
+        let Some(last) = starts.last() else {
+            bail!("The file does not contain any prices");
+        };
+        let end = **last + shortest;
+        Self::new(prices, end)
+    }
+
+    /// Reads prices from the CSV file at `path`.
+    pub fn from_path(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("Could not open price file {}", path.display()))?;
+        Self::from_csv(file).wrap_err_with(|| format!("Invalid price file {}", path.display()))
+    }
+
+    /// Writes the prices in the CSV format that [`Prices::from_csv`] reads.
+    pub fn to_csv(&self, writer: impl std::io::Write) -> eyre::Result<()> {
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        for (timestamp, price) in &self.prices {
+            csv_writer.serialize(PriceRow {
+                timestamp: *timestamp,
+                price: *price,
+            })?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the prices to a CSV file at `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> eyre::Result<()> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .wrap_err_with(|| format!("Could not create price file {}", path.display()))?;
+        self.to_csv(file)
+            .wrap_err_with(|| format!("Could not write price file {}", path.display()))
+    }
+
+    /// When the first price starts.
+    pub fn start(&self) -> DateTime<Utc> {
+        *self
+            .prices
+            .keys()
+            .next()
+            .expect("There is at least one price")
+    }
+
+    /// When the last price ends.
+    pub fn end(&self) -> DateTime<Utc> {
+        self.end
+    }
+
+    /// Every price per kWh, with when it starts.
+    pub fn iter(&self) -> impl Iterator<Item = (DateTime<Utc>, f64)> + '_ {
+        self.prices.iter().map(|(start, price)| (*start, *price))
+    }
+
+    /// The price per kWh at `time`, if there's a price for it.
+    pub fn price_at(&self, time: DateTime<Utc>) -> Option<f64> {
+        if time >= self.end {
+            return None;
+        }
+        self.prices
+            .range(..=time)
+            .next_back()
+            .map(|(_, price)| *price)
+    }
+
+    /// The mean price per kWh from `from` until `to`, weighted by how long every price holds, if there are prices for
+    /// all of that time.
+    pub fn mean(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<f64> {
+        if from < self.start() || to > self.end || from >= to {
+            return None;
+        }
+        let mut total = 0.0;
+        let mut prices = self.prices.range(..to).peekable();
+        while let Some((start, price)) = prices.next() {
+            let end = prices.peek().map_or(self.end, |(next, _)| **next);
+            let overlap = end.min(to) - (*start).max(from);
+            if overlap > TimeDelta::zero() {
+                total += price * overlap.as_seconds_f64();
+            }
+        }
+        Some(total / (to - from).as_seconds_f64())
+    }
+
+    /// The mean price per kWh of every hour of `date` in UTC, from midnight, like the CEM takes them; `None` unless
+    /// there are prices for the whole day.
+    pub fn hourly(&self, date: NaiveDate) -> Option<Vec<f64>> {
+        let midnight = date.and_hms_opt(0, 0, 0)?.and_utc();
+        (0..24)
+            .map(|hour| {
+                let start = midnight + TimeDelta::hours(hour);
+                self.mean(start, start + TimeDelta::hours(1))
+            })
+            .collect()
+    }
+
+    /// The price per kWh at `time`, or where there's none, the price at the same time of day on the nearest day that
+    /// has one, such as to plan past the prices that are published so far. That's the nearest price if the prices
+    /// don't cover that time on any day.
+    pub fn repeated_price_at(&self, time: DateTime<Utc>) -> f64 {
+        let mut same_time = time;
+        while same_time >= self.end {
+            same_time -= TimeDelta::days(1);
+        }
+        while same_time < self.start() {
+            same_time += TimeDelta::days(1);
+        }
+        self.price_at(same_time).unwrap_or_else(|| {
+            let (_, last) = self
+                .prices
+                .iter()
+                .next_back()
+                .expect("There is at least one price");
+            *last
+        })
+    }
+
+    /// Whether there are prices for all of the time from `from` until `to`.
+    pub fn covers(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+        self.start() <= from && to <= self.end
+    }
+
+    /// The prices from `from` until `to` only, as far as there are prices for that time; `None` if there are none.
+    pub fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<Prices> {
+        let start = from.max(self.start());
+        let end = to.min(self.end);
+        if start >= end {
+            return None;
+        }
+        // The price that holds at the start starts the period.
+        let mut prices: BTreeMap<_, _> = self
+            .prices
+            .range(start..end)
+            .map(|(start, price)| (*start, *price))
+            .collect();
+        prices.insert(start, self.price_at(start)?);
+        Some(Self { prices, end })
+    }
+
+    /// The prices of `date` in UTC only, if there are prices for the whole day.
+    pub fn day(&self, date: NaiveDate) -> Option<Prices> {
+        let midnight = date.and_hms_opt(0, 0, 0)?.and_utc();
+        let end = midnight + TimeDelta::days(1);
+        if !self.covers(midnight, end) {
+            return None;
+        }
+        self.between(midnight, end)
+    }
+
+    /// These prices together with `other`, which take precedence where they overlap. If there's time between them,
+    /// the last price before it holds.
+    pub fn join(&self, other: &Prices) -> Prices {
+        let mut prices = self
+            .between(self.start(), other.start())
+            .map_or_else(BTreeMap::new, |before| before.prices);
+        prices.extend(&other.prices);
+        if let Some(after) = self.between(other.end, self.end) {
+            prices.extend(after.prices);
+        }
+        Self {
+            prices,
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// The last day in UTC there are prices for the whole of, if any.
+    pub fn last_day(&self) -> Option<NaiveDate> {
+        let first = self.start().date_naive();
+        let mut date = self.end.date_naive();
+        while date >= first {
+            if self.day(date).is_some() {
+                return Some(date);
+            }
+            date = date.pred_opt()?;
+        }
+        None
+    }
+
+    /// The prices of `date` in UTC, or if there are none for the whole day, those of the last day there are, moved to
+    /// `date`, such as to run a demo today with the prices of a day in a file.
+    pub fn day_or_last(&self, date: NaiveDate) -> Option<Prices> {
+        self.day(date).or_else(|| {
+            let last_day = self.last_day()?;
+            Some(
+                self.day(last_day)?
+                    .moved(date.signed_duration_since(last_day)),
+            )
+        })
+    }
+
+    /// The same prices, moved by `offset`, such as to use the prices of an earlier day for today.
+    pub fn moved(&self, offset: TimeDelta) -> Prices {
+        Self {
+            prices: self
+                .prices
+                .iter()
+                .map(|(start, price)| (*start + offset, *price))
+                .collect(),
+            end: self.end + offset,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PriceRow {
+    timestamp: DateTime<Utc>,
+    price: f64,
+}
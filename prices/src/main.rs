@@ -0,0 +1,66 @@
+use chrono::{NaiveDate, Utc};
+use clap::Parser;
+use eyre::Context;
+use prices::Synthetic;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Generates synthetic but realistic hourly day-ahead prices, with a duck curve, evening peaks and negative-price
+/// events, and writes them as CSV, which the CEM takes with `--price-file`.
+///
+/// The same seed gives the same prices, so an experiment can be repeated.
+#[derive(Parser, Debug)]
+#[command(name = "s2-price-signal", version)]
+struct Cli {
+    /// The first day to generate the prices of, in UTC [default: today]
+    #[arg(long, env = "START")]
+    start: Option<NaiveDate>,
+    /// How many days to generate the prices of.
+    #[arg(long, env = "DAYS", default_value_t = 7)]
+    days: u32,
+    /// The seed to derive the prices from [default: a new one, which is logged]
+    #[arg(long, env = "SEED")]
+    seed: Option<u64>,
+    /// The mean price per kWh of a weekday without sun or wind, which the peaks and dips scale with.
+    #[arg(long, env = "BASE_PRICE", default_value_t = 0.09)]
+    base_price: f64,
+    /// The chance of a negative-price event on a sunny day, from 0 to 1.
+    #[arg(long, env = "NEGATIVE_CHANCE", default_value_t = 0.2)]
+    negative_chance: f64,
+    /// Write the prices to this file, instead of to stdout.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    let cli = Cli::parse();
+    let seed = cli.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+    });
+    tracing::info!("Using seed {seed}; pass --seed {seed} to generate the same prices");
+
+    let synthetic = Synthetic::new(seed)
+        .with_base(cli.base_price)
+        .with_negative_chance(cli.negative_chance);
+    let start = cli.start.unwrap_or_else(|| Utc::now().date_naive());
+    let Some(prices) = start
+        .iter_days()
+        .take(cli.days as usize)
+        .map(|date| synthetic.day(date))
+        .reduce(|prices, next| prices.join(&next))
+    else {
+        eyre::bail!("There are no days to generate the prices of");
+    };
+
+    match &cli.output {
+        Some(path) => prices.save(path),
+        None => prices
+            .to_csv(std::io::stdout().lock())
+            .wrap_err("Could not write the prices"),
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{PriceFuture, PriceSource, Prices};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use eyre::{bail, eyre, Context, OptionExt};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+const NORD_POOL_URL: &str = "https://dataportal-api.nordpoolgroup.com/api/DayAheadPrices";
+
+/// The delivery areas that Nord Pool names differently from the bidding zones of [`crate::Entsoe`].
+const DELIVERY_AREAS: &[(&str, &str)] = &[("DE-LU", "GER")];
+
+/// Fetches day-ahead prices from the [Nord Pool](https://data.nordpoolgroup.com) data portal, which needs no account.
+///
+/// Nord Pool publishes the prices of the next day in CET around noon, per MWh, for the Nordic and Baltic markets and
+/// some others, such as `NL`, `BE`, `FR` and `GER`; these come as the price per kWh.
+pub struct NordPool {
+    client: reqwest::Client,
+    area: String,
+    currency: String,
+}
+
+impl NordPool {
+    /// Fetches the prices of a delivery area, such as `NL` or `SE3`, in a currency such as `EUR`.
+    pub fn new(area: &str, currency: &str) -> eyre::Result<Self> {
+        let area = DELIVERY_AREAS
+            .iter()
+            .find(|(zone, _)| zone.eq_ignore_ascii_case(area))
+            .map_or(area, |(_, area)| area)
+            .to_uppercase();
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .wrap_err("Could not set up HTTP client")?;
+
+        Ok(Self {
+            client,
+            area,
+            currency: currency.to_uppercase(),
+        })
+    }
+
+    /// Fetches the prices of the market day `date` in CET; `None` if they aren't published yet.
+    async fn fetch(&self, date: NaiveDate) -> eyre::Result<Option<Prices>> {
+        let response = self
+            .client
+            .get(NORD_POOL_URL)
+            .query(&[
+                ("date", date.to_string()),
+                ("market", "DayAhead".into()),
+                ("deliveryArea", self.area.clone()),
+                ("currency", self.currency.clone()),
+            ])
+            .send()
+            .await
+            .wrap_err("Could not reach the Nord Pool data portal")?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            bail!("The Nord Pool data portal returned {status}");
+        }
+        let day: DayAheadPrices = response
+            .json()
+            .await
+            .wrap_err("Could not parse the response from the Nord Pool data portal")?;
+
+        let mut prices = BTreeMap::new();
+        let mut end = None;
+        for entry in day.multi_area_entries {
+            let price = entry.entry_per_area.get(&self.area).ok_or_else(|| {
+                eyre!(
+                    "The Nord Pool data portal has no prices for delivery area {}",
+                    self.area
+                )
+            })?;
+            prices.insert(entry.delivery_start, price / 1000.0);
+            end = end.max(Some(entry.delivery_end));
+        }
+        let Some(end) = end else {
+            return Ok(None);
+        };
+        Prices::new(prices, end).map(Some)
+    }
+}
+
+impl PriceSource for NordPool {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        Box::pin(async move {
+            // A day in UTC starts on the market day of the same date in CET, and its last hours are on the next one.
+            let prices = self
+                .fetch(date)
+                .await?
+                .ok_or_else(|| eyre!("The Nord Pool data portal has no prices for {date} yet"))?;
+            let next = date.succ_opt().ok_or_eyre("Invalid date")?;
+            let prices = match self.fetch(next).await? {
+                Some(next) => prices.join(&next),
+                None => prices,
+            };
+            let midnight = date
+                .and_hms_opt(0, 0, 0)
+                .ok_or_eyre("Invalid date")?
+                .and_utc();
+            prices
+                .between(midnight, midnight + TimeDelta::days(1))
+                .ok_or_else(|| eyre!("The Nord Pool data portal has no prices for {date} yet"))
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DayAheadPrices {
+    multi_area_entries: Vec<AreaEntries>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AreaEntries {
+    delivery_start: DateTime<Utc>,
+    delivery_end: DateTime<Utc>,
+    entry_per_area: HashMap<String, f64>,
+}
@@ -0,0 +1,172 @@
+use crate::Prices;
+use chrono::{NaiveDate, TimeDelta, Utc};
+use eyre::{bail, eyre, Context, OptionExt};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+/// The prices a [`PriceSource`] gets, once they're there.
+pub type PriceFuture<'a> = Pin<Box<dyn Future<Output = eyre::Result<Prices>> + Send + 'a>>;
+
+/// Where the day-ahead prices come from, such as a fixed curve, a CSV file or a market, so the CEM and the simulators
+/// work the same with each of them, and choose one when they start.
+///
+/// A market publishes the prices of a day around noon the day before, for the day in its own time zone. Until the
+/// prices of the next day are published, the prices of a day in UTC may thus lack its last hours.
+pub trait PriceSource: Send + Sync {
+    /// The prices of `date` in UTC, as far as they're published. Returns an error if there are none yet.
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_>;
+}
+
+impl<S: PriceSource + ?Sized> PriceSource for Box<S> {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        (**self).day_ahead(date)
+    }
+}
+
+/// The same price for every hour of every day in UTC.
+pub struct StaticCurve {
+    hourly: Vec<f64>,
+}
+
+impl StaticCurve {
+    /// A curve with the price per kWh of every hour of the day in UTC, from midnight.
+    pub fn new(hourly: Vec<f64>) -> eyre::Result<Self> {
+        if hourly.len() != 24 {
+            bail!(
+                "Got {} prices, but a price curve needs 24: one for every hour of the day in UTC",
+                hourly.len()
+            );
+        }
+        Ok(Self { hourly })
+    }
+}
+
+impl PriceSource for StaticCurve {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        Box::pin(async move {
+            let midnight = date
+                .and_hms_opt(0, 0, 0)
+                .ok_or_eyre("Invalid date")?
+                .and_utc();
+            let prices: BTreeMap<_, _> = self
+                .hourly
+                .iter()
+                .enumerate()
+                .map(|(hour, price)| (midnight + TimeDelta::hours(hour as i64), *price))
+                .collect();
+            Prices::new(prices, midnight + TimeDelta::days(1))
+        })
+    }
+}
+
+/// The prices in a CSV file, which is read once. For a day the file doesn't cover, the prices are those of the last
+/// day in the file, so a demo can run today with the prices of a day in the past.
+pub struct PriceFile {
+    path: PathBuf,
+    prices: Prices,
+}
+
+impl PriceFile {
+    pub fn open(path: impl Into<PathBuf>) -> eyre::Result<Self> {
+        let path = path.into();
+        let prices = Prices::from_path(&path)?;
+        if prices.last_day().is_none() {
+            bail!("{} has no prices for a whole day", path.display());
+        }
+        Ok(Self { path, prices })
+    }
+}
+
+impl PriceSource for PriceFile {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        Box::pin(async move {
+            if self.prices.day(date).is_none() {
+                tracing::warn!(
+                    "{} has no prices for {date}, so those of its last day are used instead",
+                    self.path.display()
+                );
+            }
+            self.prices
+                .day_or_last(date)
+                .ok_or_else(|| eyre!("{} has no prices for a whole day", self.path.display()))
+        })
+    }
+}
+
+/// Keeps the prices of every whole day from another source in a CSV file in a directory, and takes them from there
+/// when it has them, so a demo that restarts doesn't ask a market for them again.
+pub struct Cached {
+    source: Box<dyn PriceSource>,
+    directory: PathBuf,
+    /// What the files start with, such as the market and bidding zone.
+    name: String,
+}
+
+impl Cached {
+    pub fn new(
+        source: impl PriceSource + 'static,
+        directory: impl Into<PathBuf>,
+        name: impl Into<String>,
+    ) -> Self {
+        Self {
+            source: Box::new(source),
+            directory: directory.into(),
+            name: name.into(),
+        }
+    }
+}
+
+impl PriceSource for Cached {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        Box::pin(async move {
+            let cached = self.directory.join(format!("{}-{date}.csv", self.name));
+            if cached.exists() {
+                return Prices::from_path(&cached);
+            }
+            let prices = self.source.day_ahead(date).await?;
+            // Only a whole day is final.
+            if prices.day(date).is_some() {
+                let saved = std::fs::create_dir_all(&self.directory)
+                    .wrap_err("Could not create the price cache")
+                    .and_then(|()| prices.save(&cached));
+                if let Err(error) = saved {
+                    tracing::warn!("Could not cache the prices of {date}: {error:#}");
+                }
+            }
+            Ok(prices)
+        })
+    }
+}
+
+/// Takes the prices from another source when the first one fails, such as a CSV file when a market can't be reached.
+/// The days after today aren't published yet, so those don't fall back.
+pub struct Fallback {
+    source: Box<dyn PriceSource>,
+    fallback: Box<dyn PriceSource>,
+}
+
+impl Fallback {
+    pub fn new(source: impl PriceSource + 'static, fallback: impl PriceSource + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+            fallback: Box::new(fallback),
+        }
+    }
+}
+
+impl PriceSource for Fallback {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        Box::pin(async move {
+            match self.source.day_ahead(date).await {
+                Ok(prices) => Ok(prices),
+                Err(error) if date > Utc::now().date_naive() => Err(error),
+                Err(error) => {
+                    tracing::warn!("Could not get the prices of {date}, so they come from the fallback: {error:#}");
+                    self.fallback.day_ahead(date).await
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{PriceFuture, PriceSource, Prices};
+use chrono::{Datelike, NaiveDate, TimeDelta, Weekday};
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+const GOLDEN_GAMMA: u64 = 0x9E3779B97F4A7C15;
+/// The lowest price the European day-ahead markets allow, per kWh.
+const LOWEST_PRICE: f64 = -0.5;
+/// How many random values a day takes: a few for the day, and one for the noise of every hour.
+const VALUES_PER_DAY: i64 = 32;
+
+/// Generates hourly day-ahead prices that look like those of a European market with a lot of solar and wind power,
+/// for when there are no market prices, such as to try the CEM offline or to compare strategies over many days.
+///
+/// Every hour starts at the base price for the day, which varies from day to day and is lower on weekends. On top of
+/// that come a morning peak and a higher evening peak, which are higher in winter; the duck curve, a dip around noon
+/// that's deeper on sunny days and in summer; lower prices at night and on windy days, which are more common in winter;
+/// and some noise. On a sunny day, a negative-price event may push the hours around noon well below zero, like when
+/// the sun and the wind produce more than the market takes. The hours are in UTC, with the peaks where they are in
+/// Central Europe.
+///
+/// The prices are derived from a seed and the date, like the weather of the PV installation, so a day gets the same
+/// prices for the same seed whenever it's asked for.
+#[derive(Debug, Clone)]
+pub struct Synthetic {
+    seed: u64,
+    base: f64,
+    negative_chance: f64,
+}
+
+impl Synthetic {
+    /// Prices with a base of 0.09 per kWh, and a negative-price event on one in five sunny days.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            base: 0.09,
+            negative_chance: 0.2,
+        }
+    }
+
+    /// Sets the mean price per kWh of a weekday without sun or wind, which the peaks and dips scale with.
+    pub fn with_base(mut self, base: f64) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Sets the chance of a negative-price event on a sunny day, from 0 to 1.
+    pub fn with_negative_chance(mut self, chance: f64) -> Self {
+        self.negative_chance = chance;
+        self
+    }
+
+    /// The prices of every hour of `date` in UTC.
+    pub fn day(&self, date: NaiveDate) -> Prices {
+        let first = date.num_days_from_ce() as i64 * VALUES_PER_DAY;
+        let random = |index: i64| random(self.seed, first + index);
+        let base = self.base;
+
+        // 1 in mid-January and 0 in mid-July.
+        let winter = 0.5 + 0.5 * (2.0 * PI * (date.ordinal() as f64 - 15.0) / 365.0).cos();
+        let weekend = matches!(date.weekday(), Weekday::Sat | Weekday::Sun);
+        let level = (0.75 + 0.5 * random(0)) * if weekend { 0.85 } else { 1.0 };
+        let solar = (0.2 + 0.8 * (1.0 - winter)) * random(1);
+        let wind = random(2).powi(2) * (0.5 + 0.5 * winter);
+        let negative_event = random(3) < self.negative_chance * solar;
+        let event_depth = base * (0.3 + 1.2 * random(4));
+
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+        let prices = (0..24)
+            .map(|hour| {
+                let time = hour as f64 + 0.5;
+                let mut price = base * level
+                    + base * (0.3 + 0.3 * winter) * bump(time, 6.5, 1.5)
+                    + base * (0.5 + 0.6 * winter) * bump(time, 17.5, 2.0)
+                    - base * 0.2 * bump(time, 2.0, 2.5)
+                    - base * 1.1 * solar * bump(time, 11.0, 2.5)
+                    - base * 0.6 * wind
+                    + base * 0.16 * (random(8 + hour) - 0.5);
+                if negative_event {
+                    price -= event_depth * bump(time, 11.0, 2.0);
+                }
+                // Markets price per MWh, with two decimals; adding zero turns -0 into 0.
+                let price = (price.max(LOWEST_PRICE) * 100_000.0).round() / 100_000.0 + 0.0;
+                (midnight + TimeDelta::hours(hour), price)
+            })
+            .collect::<BTreeMap<_, _>>();
+        Prices {
+            prices,
+            end: midnight + TimeDelta::days(1),
+        }
+    }
+}
+
+impl PriceSource for Synthetic {
+    fn day_ahead(&self, date: NaiveDate) -> PriceFuture<'_> {
+        Box::pin(async move { Ok(self.day(date)) })
+    }
+}
+
+/// A bell curve around `center`, from 1 there to almost 0 a few times `width` away, over the hours of the day.
+fn bump(time: f64, center: f64, width: f64) -> f64 {
+    (-((time - center) / width).powi(2) / 2.0).exp()
+}
+
+/// A pseudo-random value from 0.0 to 1.0 for `index`, derived from the seed with SplitMix64, like the simulators do.
+fn random(seed: u64, index: i64) -> f64 {
+    let mut z = seed.wrapping_add((index as u64).wrapping_mul(GOLDEN_GAMMA));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,158 @@
+use crate::inverter::{Inverter, InverterConfig};
+use chrono::{DateTime, Utc};
+use eyre::eyre;
+use simulator_common::{time, Settings};
+
+/// The PV installation behind the PEBC front-end: the front-end describes the installation to the CEM and follows its
+/// power envelopes, and the backend is what actually produces.
+///
+/// The front-end only tells the backend the most it may produce, and asks it how much it produces, so the same S2
+/// logic drives the simulated installation and a real inverter. Forecasts come from the production model either way.
+pub trait PvBackend: Send + Sync {
+    /// The AC power the installation produces in W (positive when producing), unless it isn't known right now.
+    fn power_w(&mut self) -> Option<f64>;
+
+    /// Limits the AC power the installation produces to `limit_w`, or lifts the limit with `None`.
+    fn set_limit(&mut self, limit_w: Option<f64>);
+
+    /// How much less the installation produced than it could at the latest reading because of its limit, in W, if
+    /// that's known. A real inverter can't tell what it could produce.
+    fn curtailment_w(&self) -> Option<f64> {
+        None
+    }
+
+    /// The simulated installation, if this is one, which produces what the production model makes available. A real
+    /// installation produces what the sun gives it.
+    fn simulated(&mut self) -> Option<&mut SimulatedPv> {
+        None
+    }
+}
+
+/// A simulated inverter, which produces what the production model makes available, within its limit.
+///
+/// With the `DEFER` consequence type, the energy it couldn't produce because of its limit is produced later, on top of
+/// what's available, as soon as the limit and the maximum output of the inverter allow it.
+pub struct SimulatedPv {
+    /// The power the panels make available, after derating and clipping, in W.
+    available_w: f64,
+    limit_w: Option<f64>,
+    /// The maximum AC output of the inverter, in W.
+    ac_limit_w: f64,
+    /// Whether curtailed energy is produced later, or vanishes.
+    defer: bool,
+    /// Energy that was curtailed, but still needs to be produced (only with `defer`).
+    deferred_energy_wh: f64,
+    /// The last time the power was calculated, used to keep track of deferred energy.
+    last_updated: DateTime<Utc>,
+    last_curtailment_w: Option<f64>,
+}
+
+impl SimulatedPv {
+    pub fn new(ac_limit_w: f64, defer: bool) -> Self {
+        Self {
+            available_w: 0.0,
+            limit_w: None,
+            ac_limit_w,
+            defer,
+            deferred_energy_wh: 0.0,
+            last_updated: time::now(),
+            last_curtailment_w: None,
+        }
+    }
+
+    /// Sets the power the production model makes available right now, in W.
+    pub fn set_available_power_w(&mut self, available_w: f64) {
+        self.available_w = available_w;
+    }
+}
+
+impl PvBackend for SimulatedPv {
+    fn power_w(&mut self) -> Option<f64> {
+        let now = time::now();
+        let elapsed_hours = (now - self.last_updated).num_milliseconds() as f64 / 3_600_000.;
+        self.last_updated = now;
+
+        let max_output_w = self.limit_w.unwrap_or(f64::INFINITY).max(0.0);
+        let mut power_w = self.available_w.min(max_output_w);
+        if self.defer {
+            let curtailed_w = self.available_w - power_w;
+            if curtailed_w > 0.0 {
+                // Store the energy we couldn't produce, so we can produce it later.
+                self.deferred_energy_wh += curtailed_w * elapsed_hours;
+            } else if self.deferred_energy_wh > 0.0 && elapsed_hours > 0.0 {
+                // Produce deferred energy on top of what's available, within the limit and the maximum output.
+                let headroom_w = max_output_w.min(self.ac_limit_w) - power_w;
+                let release_w = headroom_w
+                    .min(self.deferred_energy_wh / elapsed_hours)
+                    .max(0.0);
+                power_w += release_w;
+                self.deferred_energy_wh =
+                    (self.deferred_energy_wh - release_w * elapsed_hours).max(0.0);
+            }
+            tracing::info!("Deferred energy: {:.1} Wh", self.deferred_energy_wh);
+        }
+
+        // Producing deferred energy makes the power higher than what's available, which isn't curtailment.
+        self.last_curtailment_w = Some((self.available_w - power_w).max(0.0));
+        Some(power_w)
+    }
+
+    fn set_limit(&mut self, limit_w: Option<f64>) {
+        self.limit_w = limit_w;
+    }
+
+    fn curtailment_w(&self) -> Option<f64> {
+        self.last_curtailment_w
+    }
+
+    fn simulated(&mut self) -> Option<&mut SimulatedPv> {
+        Some(self)
+    }
+}
+
+impl PvBackend for Inverter {
+    fn power_w(&mut self) -> Option<f64> {
+        Inverter::power_w(self)
+    }
+
+    fn set_limit(&mut self, limit_w: Option<f64>) {
+        Inverter::set_limit(self, limit_w);
+    }
+}
+
+/// A real inverter to drive instead of the simulated one, chosen with `PV_BACKEND`.
+///
+/// To support another kind of inverter, such as one with a vendor HTTP API, add a variant with its configuration, read
+/// it in [`from_settings`](BackendConfig::from_settings), and start a [`PvBackend`] for it in
+/// [`start`](BackendConfig::start).
+pub enum BackendConfig {
+    /// An inverter over Modbus TCP, with the given registers (`MODBUS`) or its SunSpec models (`SUNSPEC`).
+    Modbus(InverterConfig),
+}
+
+impl BackendConfig {
+    /// Reads the configuration of the real inverter from the given settings, unless `PV_BACKEND` is `SIMULATION`.
+    ///
+    /// The rated power of the inverter is what its limit is set to to lift it.
+    pub fn from_settings(
+        settings: &impl Settings,
+        rated_power_w: f64,
+    ) -> eyre::Result<Option<Self>> {
+        match settings.get("PV_BACKEND").as_deref() {
+            Some("SIMULATION") | None => Ok(None),
+            Some(backend @ ("MODBUS" | "SUNSPEC")) => Ok(Some(Self::Modbus(
+                InverterConfig::from_settings(settings, backend, rated_power_w)?,
+            ))),
+            Some(other) => Err(eyre!(
+                "Invalid value for PV_BACKEND ({other}); should be SIMULATION, MODBUS or SUNSPEC"
+            )),
+        }
+    }
+
+    /// Starts reading the inverter in the background.
+    pub fn start(self) -> Box<dyn PvBackend> {
+        match self {
+            Self::Modbus(config) => Box::new(Inverter::start(config)),
+        }
+    }
+}
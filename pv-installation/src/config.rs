@@ -0,0 +1,483 @@
+use crate::backend::BackendConfig;
+use crate::open_meteo::OpenMeteoClient;
+use crate::production::{
+    total_peak_power_w, InverterDerating, Location, PanelOrientation, ProductionModel, PvString,
+    WeatherModel,
+};
+use crate::scenario::Scenario;
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Context};
+use s2energy::common::{CommodityQuantity, PowerForecastValue, PowerValue};
+use s2energy::pebc::PowerEnvelopeConsequenceType;
+use simulator_common::{random, Settings, Timeline};
+use std::time::Duration;
+
+/// Configuration of the PV simulators.
+pub struct PvConfig {
+    /// The model used to determine how much the installation produces.
+    pub model: ProductionModel,
+    /// If set, the PEBC simulator measures and curtails a real inverter instead of simulating its production.
+    pub backend: Option<BackendConfig>,
+    /// Transient events that affect production at specific moments in simulated time.
+    pub scenario: Scenario,
+    /// Events that happen to the installation during the simulation, such as outages.
+    pub timeline: Timeline,
+    /// The moment in simulated time at which the simulation starts.
+    pub simulation_start: DateTime<Utc>,
+    /// The peak power of the installation, in W.
+    pub peak_power_w: f64,
+    /// The fraction of production that remains in every month (January first), to account for soiling and snow.
+    pub monthly_derating: [f64; 12],
+    /// The maximum AC output of the inverter, in W. Production above this is clipped.
+    pub inverter_ac_limit_w: f64,
+    /// If set, the inverter limits its output when it gets too hot.
+    pub inverter_derating: Option<InverterDerating>,
+    /// How the installation is connected to the grid.
+    pub phases: PhaseConfiguration,
+    /// Commodity quantities reported in power measurements on top of the active power of the installation.
+    pub additional_measurements: AdditionalMeasurements,
+    /// If set, forecasts are fetched from Open-Meteo instead of being derived from the production model.
+    pub open_meteo: Option<OpenMeteoClient>,
+    /// How uncertain the forecasts are, which determines the percentile ranges sent with every forecast value.
+    pub forecast_uncertainty: ForecastUncertainty,
+    /// If set, the PEBC simulator sends energy constraints that limit curtailment to this amount of energy per day, in Wh.
+    pub max_curtailed_energy_wh: Option<f64>,
+    /// What happens to energy that is curtailed by the PEBC simulator: it either vanishes, or is produced later.
+    pub consequence_type: PowerEnvelopeConsequenceType,
+    /// How long the power constraints sent by the PEBC simulator are valid; they are renewed before they expire.
+    pub power_constraints_validity: Duration,
+    /// How often a power measurement is sent.
+    pub update_interval: Duration,
+    /// How often a new forecast is sent.
+    pub forecast_interval: Duration,
+    /// How much earlier or later than `forecast_interval` a forecast may be sent.
+    pub jitter: Duration,
+    /// The seed the random behaviour of the simulator is derived from.
+    pub seed: u64,
+}
+
+impl PvConfig {
+    /// Reads the configuration from the given settings.
+    pub fn from_settings(settings: &impl Settings) -> eyre::Result<Self> {
+        let panel = panel_from_settings(settings)?;
+        let location = location_from_settings(settings)?;
+        // Every run has different clouds, unless a seed is set to repeat an earlier run.
+        let seed = random::seed(settings)?;
+        let weather = WeatherModel::new(seed, location);
+        let model = match settings.get("PV_MODEL").as_deref() {
+            Some("PROFILE") | None => match settings.get("PV_PROFILE_PATH") {
+                Some(path) => ProductionModel::profile_from_path(path, weather)?,
+                None => ProductionModel::builtin_profile(weather),
+            },
+            Some("SYNTHETIC") => ProductionModel::Synthetic(weather),
+            Some("PHYSICAL") => ProductionModel::Physical { weather, panel },
+            Some("STRINGS") => {
+                let strings = settings.get("PV_STRINGS").ok_or_else(|| {
+                    eyre!("PV_MODEL is STRINGS, but the strings are not set in PV_STRINGS")
+                })?;
+                ProductionModel::Strings(parse_strings(&strings, seed, location)?)
+            }
+            Some(other) => {
+                return Err(eyre!(
+                    "Invalid value for PV_MODEL ({other}); should be PROFILE, SYNTHETIC, PHYSICAL or STRINGS"
+                ));
+            }
+        };
+
+        let scenario = match settings.get("SCENARIO_PATH") {
+            Some(path) => Scenario::from_path(path)?,
+            None => Scenario::default(),
+        };
+        let timeline = match settings.get("TIMELINE_PATH") {
+            Some(path) => Timeline::from_path(path)?,
+            None => Timeline::default(),
+        };
+
+        // By default, start at noon so there's always some interesting production data, unless a real inverter produces
+        // it, as then the forecasts should be for now.
+        let simulation_start = settings.get("SIMULATION_START").unwrap_or_else(|| {
+            match settings.get("PV_BACKEND").as_deref() {
+                Some("SIMULATION") | None => "2030-01-01T12:00:00Z".into(),
+                Some(_) => "NOW".into(),
+            }
+        });
+        let simulation_start = match simulation_start.as_str() {
+            "NOW" => simulator_common::time::now(),
+            timestamp => DateTime::parse_from_rfc3339(timestamp)
+                .wrap_err("Could not parse SIMULATION_START as an RFC 3339 timestamp or NOW")?
+                .into(),
+        };
+
+        // With multiple strings, the peak power of the installation is the sum of the strings.
+        let peak_power_w = match &model {
+            ProductionModel::Strings(strings) => total_peak_power_w(strings),
+            _ => settings.get_or("PEAK_POWER_W", 2000.0)?,
+        };
+        // By default, the inverter is sized to the peak power of the panels, so it never clips.
+        let inverter_ac_limit_w = settings.get_or("INVERTER_AC_LIMIT_W", peak_power_w)?;
+        if inverter_ac_limit_w <= 0.0 {
+            return Err(eyre!("INVERTER_AC_LIMIT_W should be positive"));
+        }
+        let inverter_derating = settings
+            .get("INVERTER_DERATING_TEMPERATURE")
+            .map(|temperature| temperature.parse())
+            .transpose()
+            .wrap_err("Could not parse INVERTER_DERATING_TEMPERATURE as a number")?
+            .map(|start_temperature| InverterDerating { start_temperature });
+        let backend =
+            BackendConfig::from_settings(settings, peak_power_w.min(inverter_ac_limit_w))?;
+        let open_meteo = match settings.get("FORECAST_SOURCE").as_deref() {
+            Some("MODEL") | None => None,
+            Some("OPEN_METEO") => Some(OpenMeteoClient::new(location, panel, peak_power_w)),
+            Some(other) => {
+                return Err(eyre!(
+                    "Invalid value for FORECAST_SOURCE ({other}); should be MODEL or OPEN_METEO"
+                ));
+            }
+        };
+
+        let forecast_uncertainty = ForecastUncertainty {
+            relative_std_dev: settings
+                .get("FORECAST_UNCERTAINTY")
+                .map(|uncertainty| uncertainty.parse())
+                .transpose()
+                .wrap_err("Could not parse FORECAST_UNCERTAINTY as a number")?,
+        };
+        if forecast_uncertainty
+            .relative_std_dev
+            .is_some_and(|std_dev| std_dev < 0.0)
+        {
+            return Err(eyre!("FORECAST_UNCERTAINTY should not be negative"));
+        }
+
+        let monthly_derating = match settings.get("MONTHLY_DERATING") {
+            Some(factors) => parse_monthly_derating(&factors)?,
+            None => [1.0; 12],
+        };
+
+        let max_curtailed_energy_wh = settings
+            .get("MAX_CURTAILED_ENERGY_WH")
+            .map(|energy| energy.parse())
+            .transpose()
+            .wrap_err("Could not parse MAX_CURTAILED_ENERGY_WH as a number")?;
+
+        let consequence_type = match settings.get("CONSEQUENCE_TYPE") {
+            Some(consequence_type) => consequence_type.parse().map_err(|_| {
+                eyre!("Invalid value for CONSEQUENCE_TYPE ({consequence_type}); should be VANISH or DEFER")
+            })?,
+            None => PowerEnvelopeConsequenceType::Vanish,
+        };
+
+        let phases = match settings.get("PHASES").as_deref() {
+            Some("SINGLE") | None => PhaseConfiguration::SinglePhase,
+            Some("THREE") => PhaseConfiguration::ThreePhase,
+            Some("THREE_SYMMETRIC") => PhaseConfiguration::ThreePhaseSymmetric,
+            Some(other) => {
+                return Err(eyre!(
+                    "Invalid value for PHASES ({other}); should be SINGLE, THREE or THREE_SYMMETRIC"
+                ));
+            }
+        };
+
+        let additional_measurements = match settings.get("ADDITIONAL_MEASUREMENTS") {
+            Some(quantities) => AdditionalMeasurements::parse(&quantities, phases)?,
+            None => AdditionalMeasurements::default(),
+        };
+
+        let update_interval = Duration::from_secs(settings.get_or("UPDATE_INTERVAL", 60)?);
+        if update_interval.is_zero() {
+            return Err(eyre!("UPDATE_INTERVAL should be at least 1 second"));
+        }
+        let forecast_interval = Duration::from_secs(settings.get_or("FORECAST_INTERVAL", 60 * 60)?);
+        if forecast_interval.is_zero() {
+            return Err(eyre!("FORECAST_INTERVAL should be at least 1 second"));
+        }
+        let jitter = Duration::from_secs(settings.get_or("JITTER", 0)?);
+        let power_constraints_validity =
+            Duration::from_secs(settings.get_or("POWER_CONSTRAINTS_VALIDITY", 60 * 60)?);
+        if power_constraints_validity.is_zero() {
+            return Err(eyre!(
+                "POWER_CONSTRAINTS_VALIDITY should be at least 1 second"
+            ));
+        }
+
+        Ok(Self {
+            model,
+            backend,
+            scenario,
+            timeline,
+            simulation_start,
+            peak_power_w,
+            monthly_derating,
+            inverter_ac_limit_w,
+            inverter_derating,
+            phases,
+            additional_measurements,
+            open_meteo,
+            forecast_uncertainty,
+            max_curtailed_energy_wh,
+            consequence_type,
+            power_constraints_validity,
+            update_interval,
+            forecast_interval,
+            jitter,
+            seed,
+        })
+    }
+}
+
+/// How the installation is connected to the grid, which determines the commodity quantities it reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseConfiguration {
+    /// Connected to a single phase (L1).
+    SinglePhase,
+    /// Connected to all three phases, reporting the power on each phase separately.
+    ThreePhase,
+    /// Connected to all three phases, reporting the total power assuming the phases are balanced.
+    ThreePhaseSymmetric,
+}
+
+impl PhaseConfiguration {
+    /// The commodity quantities the installation reports.
+    pub fn commodity_quantities(&self) -> Vec<CommodityQuantity> {
+        match self {
+            Self::SinglePhase => vec![CommodityQuantity::ElectricPowerL1],
+            Self::ThreePhase => vec![
+                CommodityQuantity::ElectricPowerL1,
+                CommodityQuantity::ElectricPowerL2,
+                CommodityQuantity::ElectricPowerL3,
+            ],
+            Self::ThreePhaseSymmetric => vec![CommodityQuantity::ElectricPower3PhaseSymmetric],
+        }
+    }
+
+    /// The factor between the total power of the installation and the power for one of its commodity quantities.
+    pub fn phase_factor(&self) -> f64 {
+        match self {
+            Self::ThreePhase => 3.0,
+            Self::SinglePhase | Self::ThreePhaseSymmetric => 1.0,
+        }
+    }
+
+    /// Splits the total power of the installation over the commodity quantities it reports.
+    pub fn split_power(&self, total_power: f64) -> Vec<(CommodityQuantity, f64)> {
+        self.commodity_quantities()
+            .into_iter()
+            .map(|commodity_quantity| (commodity_quantity, total_power / self.phase_factor()))
+            .collect()
+    }
+}
+
+/// Reads the orientation of the panels from the `PANEL_TILT` and `PANEL_AZIMUTH` settings.
+pub fn panel_from_settings(settings: &impl Settings) -> eyre::Result<PanelOrientation> {
+    Ok(PanelOrientation {
+        tilt: settings.get_or("PANEL_TILT", 35.0)?,
+        azimuth: settings.get_or("PANEL_AZIMUTH", 180.0)?,
+    })
+}
+
+/// Reads the location of the installation from the `LATITUDE` and `LONGITUDE` settings.
+pub fn location_from_settings(settings: &impl Settings) -> eyre::Result<Location> {
+    Ok(Location {
+        latitude: settings.get_or("LATITUDE", Location::default().latitude)?,
+        longitude: settings.get_or("LONGITUDE", Location::default().longitude)?,
+    })
+}
+
+/// Commodity quantities the installation reports in its power measurements, on top of its active power.
+///
+/// This is useful to test CEMs that have to deal with multiple values per measurement, some of which they might not
+/// expect. Note that S2 has no commodity quantity for reactive power, so that can't be reported.
+#[derive(Debug, Clone, Default)]
+pub struct AdditionalMeasurements {
+    commodity_quantities: Vec<CommodityQuantity>,
+}
+
+impl AdditionalMeasurements {
+    /// Parses a comma-separated list of S2 commodity quantities (e.g. `HEAT.TEMPERATURE,ELECTRIC.POWER.L2`).
+    fn parse(value: &str, phases: PhaseConfiguration) -> eyre::Result<Self> {
+        let mut commodity_quantities = Vec::new();
+        for quantity in value.split(',').map(str::trim).filter(|q| !q.is_empty()) {
+            let commodity_quantity: CommodityQuantity = quantity.parse().map_err(|_| {
+                eyre!("Invalid commodity quantity in ADDITIONAL_MEASUREMENTS ({quantity})")
+            })?;
+            match commodity_quantity {
+                CommodityQuantity::ElectricPowerL1
+                | CommodityQuantity::ElectricPowerL2
+                | CommodityQuantity::ElectricPowerL3
+                | CommodityQuantity::ElectricPower3PhaseSymmetric
+                | CommodityQuantity::HeatTemperature => {}
+                _ => {
+                    return Err(eyre!(
+                        "The PV simulator can't measure {quantity}; ADDITIONAL_MEASUREMENTS supports electric power and HEAT.TEMPERATURE"
+                    ));
+                }
+            }
+            if phases.commodity_quantities().contains(&commodity_quantity)
+                || commodity_quantities.contains(&commodity_quantity)
+            {
+                return Err(eyre!(
+                    "{quantity} occurs more than once in the measured commodity quantities (check PHASES and ADDITIONAL_MEASUREMENTS)"
+                ));
+            }
+            commodity_quantities.push(commodity_quantity);
+        }
+
+        Ok(Self {
+            commodity_quantities,
+        })
+    }
+
+    /// The additional commodity quantities, in the order they were configured.
+    pub fn commodity_quantities(&self) -> &[CommodityQuantity] {
+        &self.commodity_quantities
+    }
+
+    /// Returns the values for the additional commodity quantities.
+    ///
+    /// `total_power` is the active power of the whole installation (negative when producing), which is reported for
+    /// the symmetric three-phase quantity. Phases the installation isn't connected to are reported as 0 W, and
+    /// `HEAT.TEMPERATURE` is the temperature of the panels in °C.
+    pub fn values(&self, total_power: f64, panel_temperature: f64) -> Vec<PowerValue> {
+        self.commodity_quantities
+            .iter()
+            .map(|commodity_quantity| PowerValue {
+                commodity_quantity: *commodity_quantity,
+                value: match commodity_quantity {
+                    CommodityQuantity::ElectricPower3PhaseSymmetric => total_power,
+                    CommodityQuantity::HeatTemperature => panel_temperature,
+                    _ => 0.0,
+                },
+            })
+            .collect()
+    }
+}
+
+/// A simple model of forecast uncertainty, used to fill in the percentile ranges of forecast values.
+///
+/// Forecast errors are assumed to be normally distributed, with a standard deviation that is a fraction of the expected
+/// value for the first hour and grows with the square root of the forecast horizon.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ForecastUncertainty {
+    /// The standard deviation of the forecast error for the first hour, as a fraction of the expected value. If not set,
+    /// forecasts only contain the expected value.
+    relative_std_dev: Option<f64>,
+}
+
+impl ForecastUncertainty {
+    /// Returns a forecast value for the given hour of the forecast (0 is the first hour).
+    ///
+    /// `expected` is negative when producing, and `max_production` is the largest amount the installation can produce
+    /// for this commodity quantity (a positive number), which is used as the limit of the forecast.
+    pub fn forecast_value(
+        &self,
+        commodity_quantity: CommodityQuantity,
+        expected: f64,
+        max_production: f64,
+        hour: usize,
+    ) -> PowerForecastValue {
+        let Some(relative_std_dev) = self.relative_std_dev else {
+            return PowerForecastValue::new(
+                commodity_quantity,
+                expected,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+        };
+
+        // Production is negative in S2, so the lower values are the ones where we produce more than expected.
+        let std_dev = relative_std_dev * expected.abs() * (hour as f64 + 1.0).sqrt();
+        let clamp = |value: f64| value.clamp(-max_production, 0.0);
+        PowerForecastValue::new(
+            commodity_quantity,
+            expected,
+            Some(clamp(expected - std_dev)),
+            Some(clamp(expected - 1.96 * std_dev)),
+            Some(-max_production),
+            Some(clamp(expected + std_dev)),
+            Some(clamp(expected + 1.96 * std_dev)),
+            Some(0.0),
+        )
+    }
+}
+
+/// Parses a comma-separated list of strings of panels.
+///
+/// Every string is specified as `name:peak_power_w:tilt:azimuth` to calculate its production from the orientation of
+/// its panels, or as `name:peak_power_w:profile_path` to read its production from a profile. Every string gets its own
+/// weather model, so shading differs between strings.
+fn parse_strings(value: &str, seed: u64, location: Location) -> eyre::Result<Vec<PvString>> {
+    let mut strings = Vec::new();
+    for (index, spec) in value.split(',').map(str::trim).enumerate() {
+        let fields: Vec<_> = spec.split(':').collect();
+        let invalid = || {
+            eyre!(
+                "Invalid string in PV_STRINGS ({spec}); should be name:peak_power_w:tilt:azimuth or name:peak_power_w:profile_path"
+            )
+        };
+        if fields[0].is_empty() {
+            return Err(invalid());
+        }
+        let peak_power_w: f64 = fields
+            .get(1)
+            .ok_or_else(invalid)?
+            .parse()
+            .wrap_err_with(|| format!("Could not parse the peak power of string {spec}"))?;
+        if !(peak_power_w.is_finite() && peak_power_w > 0.0) {
+            return Err(eyre!(
+                "Invalid peak power for string {spec} in PV_STRINGS ({peak_power_w}); should be positive"
+            ));
+        }
+        let weather = WeatherModel::new(seed.wrapping_add(index as u64 + 1), location);
+        let model = match fields[2..] {
+            [tilt, azimuth] => ProductionModel::Physical {
+                weather,
+                panel: PanelOrientation {
+                    tilt: tilt
+                        .parse()
+                        .wrap_err_with(|| format!("Could not parse the tilt of string {spec}"))?,
+                    azimuth: azimuth.parse().wrap_err_with(|| {
+                        format!("Could not parse the azimuth of string {spec}")
+                    })?,
+                },
+            },
+            [path] => ProductionModel::profile_from_path(path, weather)?,
+            _ => return Err(invalid()),
+        };
+
+        strings.push(PvString {
+            name: fields[0].to_string(),
+            peak_power_w,
+            model,
+        });
+    }
+
+    Ok(strings)
+}
+
+/// Parses a comma-separated list of 12 derating factors (January first), each from 0.0 to 1.0.
+fn parse_monthly_derating(value: &str) -> eyre::Result<[f64; 12]> {
+    let factors = value
+        .split(',')
+        .map(|factor| factor.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err_with(|| {
+            format!("Could not parse MONTHLY_DERATING ({value}) as a list of numbers")
+        })?;
+    if factors.iter().any(|factor| !(0.0..=1.0).contains(factor)) {
+        return Err(eyre!(
+            "Invalid value for MONTHLY_DERATING ({value}); factors should be from 0.0 to 1.0"
+        ));
+    }
+
+    factors.try_into().map_err(|factors: Vec<f64>| {
+        eyre!(
+            "Invalid value for MONTHLY_DERATING ({value}); expected 12 factors, one for each month, but got {}",
+            factors.len()
+        )
+    })
+}
@@ -0,0 +1,267 @@
+use crate::config::{AdditionalMeasurements, ForecastUncertainty, PhaseConfiguration, PvConfig};
+use crate::open_meteo::OpenMeteoClient;
+use crate::production::{production_from_irradiance, InverterDerating, ProductionModel};
+use crate::scenario::{Scenario, ScenarioEvent, ScenarioEventKind};
+use chrono::{DateTime, Datelike, DurationRound, TimeDelta, Utc};
+use s2energy::common::{
+    CommodityQuantity, Duration as S2Duration, Id, Message, PowerForecast, PowerForecastElement,
+    PowerMeasurement, PowerValue,
+};
+use simulator_common::{time, TimelineEvent};
+
+/// The PV installation as the simulators see it, whatever control type the CEM uses: how much the panels produce after
+/// derating, scenario events and clipping, and the measurements and forecasts that follow from that.
+///
+/// The simulators only add what depends on the control type, such as curtailment and the instructions of the CEM.
+pub struct PvInstallation {
+    model: ProductionModel,
+    /// Transient events that affect production on top of the production model.
+    scenario: Scenario,
+    /// The last simulated time we checked for scenario events that started.
+    last_scenario_check: DateTime<Utc>,
+    /// The production model is scaled from 0.0 to 1.0, so we use this multiplier to turn it into Watts.
+    pub peak_power_w: f64,
+    /// The fraction of production that remains in every month (January first), due to soiling and snow.
+    monthly_derating: [f64; 12],
+    /// The maximum AC output of the inverter, in W.
+    pub inverter_ac_limit_w: f64,
+    /// If set, the inverter limits its output when it gets too hot.
+    inverter_derating: Option<InverterDerating>,
+    /// How the installation is connected to the grid.
+    pub phases: PhaseConfiguration,
+    /// Quantities that are measured on top of the power per phase.
+    additional_measurements: AdditionalMeasurements,
+    /// How uncertain our forecasts are.
+    forecast_uncertainty: ForecastUncertainty,
+    /// If set, forecasts are fetched from Open-Meteo instead of being derived from the production model.
+    open_meteo: Option<OpenMeteoClient>,
+    /// The delta between real time and simulated time.
+    time_delta: TimeDelta,
+}
+
+impl PvInstallation {
+    /// Takes the installation from the given configuration; the settings of the simulators are left in it.
+    pub fn new(config: PvConfig) -> Self {
+        // Calculate the time delta between simulated and real time.
+        let time_delta = config.simulation_start - time::now();
+
+        Self {
+            model: config.model,
+            scenario: config.scenario,
+            last_scenario_check: config.simulation_start,
+            peak_power_w: config.peak_power_w,
+            monthly_derating: config.monthly_derating,
+            inverter_ac_limit_w: config.inverter_ac_limit_w,
+            inverter_derating: config.inverter_derating,
+            phases: config.phases,
+            additional_measurements: config.additional_measurements,
+            forecast_uncertainty: config.forecast_uncertainty,
+            open_meteo: config.open_meteo,
+            time_delta,
+        }
+    }
+
+    /// The current simulated time.
+    pub fn simulated_now(&self) -> DateTime<Utc> {
+        time::now() + self.time_delta
+    }
+
+    /// The most the installation can deliver, in W: its peak power, unless the inverter clips it.
+    pub fn rated_power_w(&self) -> f64 {
+        self.peak_power_w.min(self.inverter_ac_limit_w)
+    }
+
+    /// The commodity quantities we report in our power measurements.
+    pub fn measured_commodity_quantities(&self) -> Vec<CommodityQuantity> {
+        [
+            self.phases.commodity_quantities().as_slice(),
+            self.additional_measurements.commodity_quantities(),
+        ]
+        .concat()
+    }
+
+    /// Returns the production at the given simulated time as a fraction of peak power, as delivered by the inverter.
+    pub fn production_at(&self, time: DateTime<Utc>) -> f64 {
+        self.adjust_production(self.model.production_at(time), time)
+    }
+
+    /// Returns the current production in W, as delivered by the inverter.
+    pub fn current_production_w(&self) -> f64 {
+        self.production_at(self.simulated_now()) * self.peak_power_w
+    }
+
+    /// Applies seasonal derating, scenario events, inverter clipping and inverter temperature derating to the
+    /// production of the panels at the given simulated time.
+    ///
+    /// Production is a fraction of peak power, both before and after adjusting.
+    fn adjust_production(&self, production: f64, time: DateTime<Utc>) -> f64 {
+        let production = production * self.monthly_derating[time.month0() as usize];
+        let production = self.scenario.apply(production, time, self.simulated_now());
+        let max_output = match &self.inverter_derating {
+            Some(derating) => {
+                let load = production * self.peak_power_w / self.inverter_ac_limit_w;
+                derating.max_output(self.model.ambient_temperature_at(time), load.min(1.0))
+            }
+            None => 1.0,
+        };
+        production.min(max_output * self.inverter_ac_limit_w / self.peak_power_w)
+    }
+
+    /// Returns the scenario events that started since the last time this was called.
+    pub fn new_scenario_events(&mut self) -> Vec<ScenarioEvent> {
+        let simulated_current_time = self.simulated_now();
+        let events = self
+            .scenario
+            .events_started_between(self.last_scenario_check, simulated_current_time)
+            .cloned()
+            .collect();
+        self.last_scenario_check = simulated_current_time;
+        events
+    }
+
+    /// Logs the current production of every string of panels, before derating and clipping.
+    pub fn log_string_production(&self) {
+        let simulated_current_time = self.simulated_now();
+        for string in self.model.strings() {
+            tracing::info!(
+                "String {} is producing {:.0} W",
+                string.name,
+                string.production_at(simulated_current_time)
+            );
+        }
+    }
+
+    /// Returns the current temperature of the panels, in °C.
+    pub fn panel_temperature(&self) -> f64 {
+        self.model.panel_temperature_at(self.simulated_now())
+    }
+
+    /// The simulated time the forecasts start at: the start of the next hour.
+    fn forecast_start(&self) -> DateTime<Utc> {
+        self.simulated_now()
+            .duration_trunc(TimeDelta::hours(1))
+            .unwrap()
+            + TimeDelta::hours(1)
+    }
+
+    /// Returns a 24h forecast of production in W: a `Vec` with 24 elements, one for each hour in order, starting at the
+    /// next hour.
+    ///
+    /// The forecast is for the production without curtailment, so the CEM can decide how much to curtail.
+    pub async fn get_24h_forecast(&self) -> Vec<f64> {
+        let forecast_start = self.forecast_start();
+        if let Some(open_meteo) = &self.open_meteo {
+            match open_meteo.get_24h_forecast().await {
+                Ok(forecast) => {
+                    // Open-Meteo only knows about the weather, so apply derating, scenario events and clipping on top.
+                    return forecast
+                        .iter()
+                        .enumerate()
+                        .map(|(hour, value)| {
+                            let time = forecast_start + TimeDelta::hours(hour as i64);
+                            self.adjust_production(value / self.peak_power_w, time)
+                                * self.peak_power_w
+                        })
+                        .collect();
+                }
+                Err(err) => tracing::warn!(
+                    "Could not fetch forecast from Open-Meteo, using the production model instead: {err:?}"
+                ),
+            }
+        }
+
+        (0..24)
+            .map(|offset| {
+                let offset_time = forecast_start + TimeDelta::hours(offset);
+                self.production_at(offset_time) * self.peak_power_w
+            })
+            .collect()
+    }
+
+    /// Returns a measurement of the given power of the whole installation (negative when producing), split over the
+    /// phases, with the additional measurements on top.
+    pub fn power_measurement(&self, power_w: f64) -> PowerMeasurement {
+        PowerMeasurement {
+            measurement_timestamp: time::now(),
+            message_id: Id::generate(),
+            values: self
+                .phases
+                .split_power(power_w)
+                .into_iter()
+                .map(|(commodity_quantity, value)| PowerValue {
+                    commodity_quantity,
+                    value,
+                })
+                .chain(
+                    self.additional_measurements
+                        .values(power_w, self.panel_temperature()),
+                )
+                .collect(),
+        }
+    }
+
+    /// Returns a forecast of our power for the next 24 hours, with the percentile ranges of our forecast uncertainty.
+    pub async fn power_forecast(&self) -> PowerForecast {
+        let max_production_per_phase = self.rated_power_w() / self.phases.phase_factor();
+        let forecast_elements = self
+            .get_24h_forecast()
+            .await
+            .iter()
+            .enumerate()
+            .map(|(hour, &forecast_value)| PowerForecastElement {
+                duration: S2Duration(1000 * 60 * 60),
+                // Production is negative in S2, so -forecast_value.
+                power_values: self
+                    .phases
+                    .split_power(-forecast_value)
+                    .into_iter()
+                    .map(|(commodity_quantity, value)| {
+                        self.forecast_uncertainty.forecast_value(
+                            commodity_quantity,
+                            value,
+                            max_production_per_phase,
+                            hour,
+                        )
+                    })
+                    .collect(),
+            })
+            .collect();
+        PowerForecast {
+            elements: forecast_elements,
+            message_id: Id::generate(),
+            // The forecast starts at the next hour of simulated time, whichever source it comes from.
+            start_time: self.forecast_start() - self.time_delta,
+        }
+    }
+
+    /// Applies the given event from the timeline to the production, by adding it to the scenario from now on.
+    pub fn handle_event(&mut self, event: &TimelineEvent) -> eyre::Result<Vec<Message>> {
+        let start = self.simulated_now();
+        let (duration, kind) = match *event {
+            // An outage is simulated as an inverter trip, so it's handled like the events in the scenario.
+            TimelineEvent::Outage { duration } => (duration, ScenarioEventKind::InverterTrip),
+            TimelineEvent::Irradiance { w_per_m2, duration } => (
+                duration,
+                ScenarioEventKind::Production {
+                    production: production_from_irradiance(w_per_m2),
+                },
+            ),
+            TimelineEvent::Cloud {
+                transmittance,
+                duration,
+            } => (duration, ScenarioEventKind::Cloud { transmittance }),
+            _ => {
+                tracing::warn!(
+                    "Ignoring timeline event {event:?}, which doesn't apply to a PV installation"
+                );
+                return Ok(vec![]);
+            }
+        };
+        self.scenario.add_event(ScenarioEvent {
+            start,
+            end: start + duration,
+            kind,
+        });
+        Ok(vec![])
+    }
+}
@@ -0,0 +1,329 @@
+use crate::modbus::{ModbusClient, Register, RegisterFormat, RegisterKind};
+use crate::sunspec::SunSpecInverter;
+use eyre::{eyre, Context};
+use simulator_common::Settings;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// A real inverter that's read and controlled over Modbus TCP, configured with the `MODBUS_*` settings, or with
+/// SunSpec.
+pub struct InverterConfig {
+    /// The address of the inverter, as `host:port` or just the host.
+    address: String,
+    unit_id: u8,
+    /// Where the power and limit of the inverter are.
+    register_map: RegisterMap,
+    /// The rated power of the inverter, in W, which the limit is set to to lift it.
+    rated_power_w: f64,
+    /// How often the power is read.
+    poll_interval: Duration,
+}
+
+impl InverterConfig {
+    /// Reads the configuration of the inverter from the given settings, for `backend` `MODBUS` or `SUNSPEC`.
+    pub fn from_settings(
+        settings: &impl Settings,
+        backend: &str,
+        rated_power_w: f64,
+    ) -> eyre::Result<Self> {
+        let register_map = match backend {
+            "SUNSPEC" => RegisterMap::SunSpec {
+                base_address: settings
+                    .get("SUNSPEC_BASE_ADDRESS")
+                    .map(|address| address.parse())
+                    .transpose()
+                    .wrap_err("Could not parse SUNSPEC_BASE_ADDRESS as a register address")?,
+            },
+            _ => RegisterMap::from_settings(settings)?,
+        };
+
+        let address = settings.get("MODBUS_ADDRESS").ok_or_else(|| {
+            eyre!("PV_BACKEND is {backend}, but the address of the inverter is not set in MODBUS_ADDRESS")
+        })?;
+        let poll_interval = Duration::from_secs(settings.get_or("MODBUS_POLL_INTERVAL", 5)?);
+        if poll_interval.is_zero() {
+            return Err(eyre!("MODBUS_POLL_INTERVAL should be at least 1 second"));
+        }
+
+        Ok(Self {
+            address,
+            unit_id: settings.get_or("MODBUS_UNIT_ID", 1)?,
+            register_map,
+            rated_power_w,
+            poll_interval,
+        })
+    }
+}
+
+/// Where the power and limit of an inverter are in its registers.
+enum RegisterMap {
+    /// In the registers of the `MODBUS_*` settings.
+    Registers {
+        /// The register with the AC power the inverter produces.
+        power: Register,
+        /// If set, the holding register with the most the inverter may produce, which curtails it.
+        limit: Option<Register>,
+    },
+    /// In the SunSpec models of the inverter, which start at the base address, or else at one of the usual ones.
+    SunSpec { base_address: Option<u16> },
+}
+
+impl RegisterMap {
+    /// Reads the registers from the `MODBUS_*` settings.
+    fn from_settings(settings: &impl Settings) -> eyre::Result<Self> {
+        let power_address = settings.get("MODBUS_POWER_REGISTER").ok_or_else(|| {
+            eyre!(
+                "PV_BACKEND is MODBUS, but the power register is not set in MODBUS_POWER_REGISTER"
+            )
+        })?;
+        let power = Register {
+            kind: register_kind(settings, "MODBUS_POWER_REGISTER_TYPE")?,
+            address: power_address
+                .parse()
+                .wrap_err("Could not parse MODBUS_POWER_REGISTER as a register address")?,
+            format: register_format(settings, "MODBUS_POWER_FORMAT", RegisterFormat::I16)?,
+            scale: settings.get_or("MODBUS_POWER_SCALE", 1.0)?,
+        };
+        let limit = settings
+            .get("MODBUS_LIMIT_REGISTER")
+            .map(|address| -> eyre::Result<_> {
+                Ok(Register {
+                    kind: RegisterKind::Holding,
+                    address: address
+                        .parse()
+                        .wrap_err("Could not parse MODBUS_LIMIT_REGISTER as a register address")?,
+                    format: register_format(settings, "MODBUS_LIMIT_FORMAT", RegisterFormat::U16)?,
+                    scale: settings.get_or("MODBUS_LIMIT_SCALE", 1.0)?,
+                })
+            })
+            .transpose()?;
+        if power.scale == 0.0 || limit.is_some_and(|limit| limit.scale == 0.0) {
+            return Err(eyre!(
+                "MODBUS_POWER_SCALE and MODBUS_LIMIT_SCALE should not be 0"
+            ));
+        }
+
+        Ok(Self::Registers { power, limit })
+    }
+}
+
+/// The inverter the poll task is connected to, with the registers it found.
+enum Device {
+    Registers {
+        power: Register,
+        limit: Option<Register>,
+        rated_power_w: f64,
+    },
+    SunSpec(SunSpecInverter),
+}
+
+impl Device {
+    /// Finds the registers of the inverter that was just connected to.
+    async fn find(client: &mut ModbusClient, config: &InverterConfig) -> eyre::Result<Self> {
+        match config.register_map {
+            RegisterMap::Registers { power, limit } => Ok(Self::Registers {
+                power,
+                limit,
+                rated_power_w: config.rated_power_w,
+            }),
+            RegisterMap::SunSpec { base_address } => {
+                SunSpecInverter::discover(client, base_address, config.rated_power_w)
+                    .await
+                    .map(Self::SunSpec)
+            }
+        }
+    }
+
+    /// Whether the inverter can be curtailed.
+    fn can_limit(&self) -> bool {
+        match self {
+            Self::Registers { limit, .. } => limit.is_some(),
+            Self::SunSpec(inverter) => inverter.can_limit(),
+        }
+    }
+
+    /// Reads the AC power the inverter produces, in W.
+    async fn read_power_w(&self, client: &mut ModbusClient) -> eyre::Result<f64> {
+        match self {
+            Self::Registers { power, .. } => client.read(power).await,
+            Self::SunSpec(inverter) => inverter.read_power_w(client).await,
+        }
+    }
+
+    /// Limits the AC power of the inverter to `limit_w`, or lifts the limit with `None`.
+    async fn write_limit(
+        &self,
+        client: &mut ModbusClient,
+        limit_w: Option<f64>,
+    ) -> eyre::Result<()> {
+        match self {
+            Self::Registers {
+                limit: Some(limit),
+                rated_power_w,
+                ..
+            } => {
+                client
+                    .write(limit, limit_w.unwrap_or(*rated_power_w).max(0.0))
+                    .await
+            }
+            Self::Registers { limit: None, .. } => Ok(()),
+            Self::SunSpec(inverter) => inverter.write_limit(client, limit_w).await,
+        }
+    }
+}
+
+/// Reads a register kind from the given setting; holding registers by default.
+fn register_kind(settings: &impl Settings, name: &str) -> eyre::Result<RegisterKind> {
+    match settings.get(name).as_deref() {
+        Some("HOLDING") | None => Ok(RegisterKind::Holding),
+        Some("INPUT") => Ok(RegisterKind::Input),
+        Some(other) => Err(eyre!(
+            "Invalid value for {name} ({other}); should be HOLDING or INPUT"
+        )),
+    }
+}
+
+/// Reads a register format from the given setting.
+fn register_format(
+    settings: &impl Settings,
+    name: &str,
+    default: RegisterFormat,
+) -> eyre::Result<RegisterFormat> {
+    match settings.get(name).as_deref() {
+        None => Ok(default),
+        Some("U16") => Ok(RegisterFormat::U16),
+        Some("I16") => Ok(RegisterFormat::I16),
+        Some("U32") => Ok(RegisterFormat::U32),
+        Some("I32") => Ok(RegisterFormat::I32),
+        Some("F32") => Ok(RegisterFormat::F32),
+        Some(other) => Err(eyre!(
+            "Invalid value for {name} ({other}); should be U16, I16, U32, I32 or F32"
+        )),
+    }
+}
+
+/// A power reading of the inverter.
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    /// The AC power the inverter produces, in W (positive when producing).
+    power_w: f64,
+    at: Instant,
+}
+
+/// The connection with a real inverter, which takes the place of the production model for the measurements.
+///
+/// The inverter is polled in the background, so the simulator can read the latest power and set a limit without
+/// waiting for the inverter. When the connection is lost, the inverter is reconnected at the next poll, and its limit
+/// is written again.
+pub struct Inverter {
+    readings: watch::Receiver<Option<Reading>>,
+    limit: watch::Sender<Option<f64>>,
+    poll_interval: Duration,
+}
+
+impl Inverter {
+    /// Starts polling the inverter; this stops when the `Inverter` is dropped.
+    pub fn start(config: InverterConfig) -> Self {
+        let (readings_sender, readings) = watch::channel(None);
+        let (limit, limit_receiver) = watch::channel(None);
+        let poll_interval = config.poll_interval;
+        tokio::spawn(poll(config, readings_sender, limit_receiver));
+
+        Self {
+            readings,
+            limit,
+            poll_interval,
+        }
+    }
+
+    /// The AC power the inverter produced at the latest reading, in W (positive when producing), unless there hasn't
+    /// been a reading for a few polls.
+    pub fn power_w(&self) -> Option<f64> {
+        let reading = (*self.readings.borrow())?;
+        (reading.at.elapsed() < 3 * self.poll_interval).then_some(reading.power_w)
+    }
+
+    /// Limits the AC power the inverter produces to `limit_w`, or lifts the limit with `None`.
+    pub fn set_limit(&self, limit_w: Option<f64>) {
+        self.limit.send_if_modified(|limit| {
+            let modified = *limit != limit_w;
+            *limit = limit_w;
+            modified
+        });
+    }
+}
+
+/// Reads the power of the inverter every poll interval, and writes its limit whenever that changes.
+async fn poll(
+    config: InverterConfig,
+    readings: watch::Sender<Option<Reading>>,
+    mut limit: watch::Receiver<Option<f64>>,
+) {
+    let mut connection = None;
+    // The limit the inverter has, if it's been written since connecting; `Some(None)` means it has no limit.
+    let mut written_limit = None;
+    let mut interval = tokio::time::interval(config.poll_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            changed = limit.changed() => if changed.is_err() {
+                // The simulator stopped.
+                return;
+            },
+        }
+
+        if connection.is_none() {
+            let connected = async {
+                let mut client = ModbusClient::connect(&config.address, config.unit_id).await?;
+                let device = Device::find(&mut client, &config).await?;
+                eyre::Ok((client, device))
+            };
+            match connected.await {
+                Ok(connected) => {
+                    tracing::info!("Connected to inverter {}", config.address);
+                    connection = Some(connected);
+                    written_limit = None;
+                }
+                Err(error) => {
+                    tracing::warn!("No connection with inverter {}: {error:#}", config.address);
+                    continue;
+                }
+            }
+        }
+        let Some((client, device)) = &mut connection else {
+            continue;
+        };
+
+        let limit_w = *limit.borrow_and_update();
+        let result = async {
+            if device.can_limit() && written_limit != Some(limit_w) {
+                match limit_w {
+                    Some(limit_w) => tracing::info!("Limiting the inverter to {limit_w:.0} W"),
+                    None => tracing::info!("Lifting the limit of the inverter"),
+                }
+                device
+                    .write_limit(client, limit_w)
+                    .await
+                    .wrap_err("Could not write the limit of the inverter")?;
+                written_limit = Some(limit_w);
+            }
+            let power_w = device
+                .read_power_w(client)
+                .await
+                .wrap_err("Could not read the power of the inverter")?;
+            tracing::debug!("The inverter produces {power_w:.0} W");
+            readings.send_replace(Some(Reading {
+                power_w,
+                at: Instant::now(),
+            }));
+            eyre::Ok(())
+        }
+        .await;
+
+        if let Err(error) = result {
+            // The connection may be broken, so connect again at the next poll.
+            tracing::warn!("{error:#}");
+            connection = None;
+        }
+    }
+}
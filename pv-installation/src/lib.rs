@@ -0,0 +1,71 @@
+use config::PvConfig;
+use eyre::{eyre, Context};
+use simulator_common::Settings;
+use std::path::Path;
+
+mod backend;
+mod config;
+mod installation;
+mod inverter;
+mod modbus;
+mod open_meteo;
+mod production;
+mod profile_generator;
+mod pv_simulator_ombc;
+mod pv_simulator_pebc;
+mod pv_simulator_simple;
+mod scenario;
+mod sunspec;
+
+/// Runs the PV simulator with the given settings, until it's stopped with Ctrl-C or SIGTERM.
+pub async fn run(settings: &impl Settings) -> eyre::Result<()> {
+    simulator_common::time::init(settings)?;
+
+    // Read the configuration before connecting, so problems with it are reported right away.
+    let config = PvConfig::from_settings(settings)?;
+    let control_type = settings
+        .get("CONTROL_TYPE")
+        .ok_or_else(|| eyre!("Could not read control type from CONTROL_TYPE"))?;
+
+    if config.backend.is_some() && !control_type.eq_ignore_ascii_case("PEBC") {
+        return Err(eyre!(
+            "PV_BACKEND is set to a real inverter, which can only be controlled with CONTROL_TYPE PEBC"
+        ));
+    }
+
+    let connection = simulator_common::connect(settings).await?;
+
+    match control_type.to_uppercase().as_str() {
+        "PEBC" => pv_simulator_pebc::start_mock(connection, config).await?,
+        "OMBC" => pv_simulator_ombc::start_mock(connection, config).await?,
+        "NOT_CONTROLABLE" => pv_simulator_simple::start_mock(connection, config).await?,
+        other => {
+            return Err(eyre!(
+                "Invalid value for CONTROL TYPE ({other}); should PEBC, OMBC or NOT_CONTROLABLE"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a profile generated from the TMY data in the given file to stdout.
+///
+/// The location and orientation of the panels are read from the same settings the simulator uses.
+pub fn generate_profile(
+    tmy_path: impl AsRef<Path>,
+    year: i32,
+    settings: &impl Settings,
+) -> eyre::Result<()> {
+    let tmy_path = tmy_path.as_ref();
+    let tmy = std::fs::File::open(tmy_path)
+        .wrap_err_with(|| format!("Could not open TMY data {}", tmy_path.display()))?;
+    profile_generator::generate_profile(
+        tmy,
+        std::io::stdout().lock(),
+        year,
+        config::location_from_settings(settings)?,
+        config::panel_from_settings(settings)?,
+    )
+    .wrap_err_with(|| format!("Could not generate a profile from {}", tmy_path.display()))
+}
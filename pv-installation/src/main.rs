@@ -1,30 +1,51 @@
 use eyre::{eyre, Context};
-
-mod pv_simulator_pebc;
-mod pv_simulator_simple;
+use simulator_common::{ConfigFile, EnvSettings, Settings};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    tracing_subscriber::fmt().init();
+    // Instead of running the simulator, we can also generate a profile from TMY data.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--generate-profile") {
+        return generate_profile(&args[2..]);
+    }
 
-    let connection = s2energy::websockets_json::connect_as_client(
-        std::env::var("CEM_URL")
-            .wrap_err("Could not read CEM URL from environment variable CEM_URL")?,
-    )
-    .await?;
+    // The simulator is configured through environment variables; see docker-compose.yml for the available options.
+    // They can also be set in a configuration file, in which case the environment variables take precedence.
+    let settings = settings()?;
+    let _telemetry = simulator_common::telemetry::init(&settings, "pv-installation")?;
+    pv_installation::run(&settings).await
+}
 
-    let control_type = std::env::var("CONTROL_TYPE")
-        .wrap_err("Could not read control type from environment variable CONTROL_TYPE")?;
-    
-    match control_type.as_str() {
-        "PEBC" => pv_simulator_pebc::start_mock(connection).await?,
-        "NOT_CONTROLABLE" => pv_simulator_simple::start_mock(connection).await?,
-        other => {
+/// Writes a profile generated from the TMY data in the given file to stdout.
+///
+/// Usage: `pv-installation --generate-profile <tmy.csv> [year]`. The location and orientation of the panels are read
+/// from the same settings the simulator uses.
+fn generate_profile(args: &[String]) -> eyre::Result<()> {
+    let [path, rest @ ..] = args else {
+        return Err(eyre!(
+            "Usage: pv-installation --generate-profile <tmy.csv> [year]"
+        ));
+    };
+    let year = match rest {
+        [] => 2030,
+        [year] => year
+            .parse()
+            .wrap_err_with(|| format!("Invalid year ({year})"))?,
+        _ => {
             return Err(eyre!(
-                "Invalid value for CONTROL TYPE ({other}); should PEBC or NOT_CONTROLABLE"
-            ));
+                "Usage: pv-installation --generate-profile <tmy.csv> [year]"
+            ))
         }
-    }
+    };
+
+    pv_installation::generate_profile(path, year, &settings()?)
+}
 
-    Ok(())
+/// Returns the settings from the environment variables, falling back to the configuration file in `CONFIG_PATH`.
+fn settings() -> eyre::Result<impl Settings> {
+    let config_file = match std::env::var("CONFIG_PATH") {
+        Ok(path) => ConfigFile::from_path(path)?,
+        Err(_) => ConfigFile::default(),
+    };
+    Ok(EnvSettings.or(config_file))
 }
@@ -0,0 +1,227 @@
+use eyre::{bail, eyre, Context};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The port Modbus TCP devices listen on, unless they're configured otherwise.
+const DEFAULT_PORT: u16 = 502;
+/// How long to wait for a device to respond to a request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// The kind of register a value is in: holding registers can be read and written, input registers can only be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterKind {
+    Holding,
+    Input,
+}
+
+/// How a number is stored in one or two registers. Values of two registers have the high word first, as most devices
+/// do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterFormat {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl RegisterFormat {
+    /// The number of registers a value takes.
+    pub fn len(&self) -> u16 {
+        match self {
+            Self::U16 | Self::I16 => 1,
+            Self::U32 | Self::I32 | Self::F32 => 2,
+        }
+    }
+
+    /// Decodes a value from the registers it's stored in.
+    pub fn decode(&self, registers: &[u16]) -> f64 {
+        let word = |index: usize| registers.get(index).copied().unwrap_or_default();
+        let double = || (word(0) as u32) << 16 | word(1) as u32;
+        match self {
+            Self::U16 => word(0) as f64,
+            Self::I16 => word(0) as i16 as f64,
+            Self::U32 => double() as f64,
+            Self::I32 => double() as i32 as f64,
+            Self::F32 => f32::from_bits(double()) as f64,
+        }
+    }
+
+    /// Encodes a value into the registers to store it in, rounding it for the integer formats.
+    pub fn encode(&self, value: f64) -> Vec<u16> {
+        let split = |double: u32| vec![(double >> 16) as u16, double as u16];
+        match self {
+            Self::U16 => vec![value.round() as u16],
+            Self::I16 => vec![value.round() as i16 as u16],
+            Self::U32 => split(value.round() as u32),
+            Self::I32 => split(value.round() as i32 as u32),
+            Self::F32 => split((value as f32).to_bits()),
+        }
+    }
+}
+
+/// A number in a device, at a register address (counting from 0, as sent in requests).
+#[derive(Debug, Clone, Copy)]
+pub struct Register {
+    pub kind: RegisterKind,
+    pub address: u16,
+    pub format: RegisterFormat,
+    /// What a unit of the value in the register is worth, such as 10 for a register in units of 10 W.
+    pub scale: f64,
+}
+
+/// A minimal Modbus TCP client, with only what's needed to read and control an inverter: reading holding and input
+/// registers, and writing holding registers.
+pub struct ModbusClient {
+    stream: TcpStream,
+    /// The unit identifier of the device, which a gateway uses to pass requests on to the right device.
+    unit_id: u8,
+    transaction_id: u16,
+}
+
+impl ModbusClient {
+    /// Connects to the device at `address`, as `host:port` or just the host to use port 502.
+    pub async fn connect(address: &str, unit_id: u8) -> eyre::Result<Self> {
+        let address = match address.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => address.to_string(),
+            _ => format!("{address}:{DEFAULT_PORT}"),
+        };
+        let stream = tokio::time::timeout(TIMEOUT, TcpStream::connect(&address))
+            .await
+            .map_err(|_| eyre!("Timed out connecting to Modbus device {address}"))?
+            .wrap_err_with(|| format!("Could not connect to Modbus device {address}"))?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            stream,
+            unit_id,
+            transaction_id: 0,
+        })
+    }
+
+    /// Reads `count` registers of the given kind, starting at `address`.
+    pub async fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> eyre::Result<Vec<u16>> {
+        let function = match kind {
+            RegisterKind::Holding => READ_HOLDING_REGISTERS,
+            RegisterKind::Input => READ_INPUT_REGISTERS,
+        };
+        let mut request = address.to_be_bytes().to_vec();
+        request.extend(count.to_be_bytes());
+        let response = self
+            .request(function, &request)
+            .await
+            .wrap_err_with(|| format!("Could not read {count} registers at {address}"))?;
+
+        // The response is the number of bytes that follow, and then the registers.
+        let registers = response.get(1..).unwrap_or_default();
+        if response.first().copied() != Some(count as u8 * 2)
+            || registers.len() != count as usize * 2
+        {
+            bail!("Got an invalid response reading {count} registers at {address}");
+        }
+        Ok(registers
+            .chunks_exact(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .collect())
+    }
+
+    /// Writes `values` to the holding registers starting at `address`.
+    pub async fn write_registers(&mut self, address: u16, values: &[u16]) -> eyre::Result<()> {
+        let mut request = address.to_be_bytes().to_vec();
+        request.extend((values.len() as u16).to_be_bytes());
+        request.push(values.len() as u8 * 2);
+        request.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+        self.request(WRITE_MULTIPLE_REGISTERS, &request)
+            .await
+            .wrap_err_with(|| format!("Could not write {} registers at {address}", values.len()))?;
+        Ok(())
+    }
+
+    /// Reads a register and returns its value times its scale.
+    pub async fn read(&mut self, register: &Register) -> eyre::Result<f64> {
+        let registers = self
+            .read_registers(register.kind, register.address, register.format.len())
+            .await?;
+        Ok(register.format.decode(&registers) * register.scale)
+    }
+
+    /// Writes `value` to a holding register, in units of its scale.
+    pub async fn write(&mut self, register: &Register, value: f64) -> eyre::Result<()> {
+        if register.kind != RegisterKind::Holding {
+            bail!(
+                "Register {} is an input register, which can't be written",
+                register.address
+            );
+        }
+        self.write_registers(
+            register.address,
+            &register.format.encode(value / register.scale),
+        )
+        .await
+    }
+
+    /// Sends a request with the given function code and data, and returns the data of the response.
+    async fn request(&mut self, function: u8, data: &[u8]) -> eyre::Result<Vec<u8>> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        tokio::time::timeout(TIMEOUT, self.exchange(function, data))
+            .await
+            .map_err(|_| eyre!("The Modbus device didn't respond in time"))?
+    }
+
+    async fn exchange(&mut self, function: u8, data: &[u8]) -> eyre::Result<Vec<u8>> {
+        // The MBAP header: the transaction, protocol 0, the length of the rest, and the unit.
+        let mut frame = self.transaction_id.to_be_bytes().to_vec();
+        frame.extend(0u16.to_be_bytes());
+        frame.extend((data.len() as u16 + 2).to_be_bytes());
+        frame.push(self.unit_id);
+        frame.push(function);
+        frame.extend(data);
+        self.stream.write_all(&frame).await?;
+
+        let mut header = [0; 7];
+        self.stream.read_exact(&mut header).await?;
+        let transaction_id = u16::from_be_bytes([header[0], header[1]]);
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if transaction_id != self.transaction_id || length < 2 {
+            bail!("Got an invalid response from the Modbus device");
+        }
+        let mut pdu = vec![0; length - 1];
+        self.stream.read_exact(&mut pdu).await?;
+
+        match pdu[0] {
+            code if code == function => Ok(pdu.split_off(1)),
+            code if code == function | 0x80 => {
+                let exception = pdu.get(1).copied().unwrap_or_default();
+                Err(eyre!(
+                    "The Modbus device returned {}",
+                    exception_name(exception)
+                ))
+            }
+            code => bail!("Got a response for function {code} to function {function}"),
+        }
+    }
+}
+
+/// A description of a Modbus exception code.
+fn exception_name(code: u8) -> String {
+    match code {
+        0x01 => "an illegal function exception".into(),
+        0x02 => "an illegal data address exception".into(),
+        0x03 => "an illegal data value exception".into(),
+        0x04 => "a device failure exception".into(),
+        0x06 => "a device busy exception".into(),
+        0x0A => "a gateway path unavailable exception".into(),
+        0x0B => "a gateway target failed to respond exception".into(),
+        code => format!("exception {code}"),
+    }
+}
@@ -0,0 +1,100 @@
+use crate::production::{Location, PanelOrientation, STC_IRRADIANCE_W_M2, SYSTEM_LOSSES};
+use chrono::{DateTime, DurationRound, TimeDelta};
+use eyre::{eyre, Context};
+use serde::Deserialize;
+use simulator_common::time;
+use std::time::Duration;
+
+const OPEN_METEO_URL: &str = "https://api.open-meteo.com/v1/forecast";
+
+/// Fetches irradiance forecasts for the installation from [Open-Meteo](https://open-meteo.com) and turns them into
+/// production forecasts.
+///
+/// Unlike the production models, this gives an actual forecast for the real location of the installation, including all
+/// the errors real forecasts have.
+pub struct OpenMeteoClient {
+    client: reqwest::Client,
+    location: Location,
+    panel: PanelOrientation,
+    peak_power_w: f64,
+}
+
+impl OpenMeteoClient {
+    pub fn new(location: Location, panel: PanelOrientation, peak_power_w: f64) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Could not set up HTTP client");
+
+        Self {
+            client,
+            location,
+            panel,
+            peak_power_w,
+        }
+    }
+
+    /// Returns a 24h forecast of production in W: a `Vec` with 24 elements, one for each hour in order, starting at the next hour.
+    pub async fn get_24h_forecast(&self) -> eyre::Result<Vec<f64>> {
+        // Open-Meteo measures the panel azimuth from the south (-90 is east, 90 is west)
+        let azimuth = self.panel.azimuth - 180.0;
+        let response: OpenMeteoResponse = self
+            .client
+            .get(OPEN_METEO_URL)
+            .query(&[
+                ("latitude", self.location.latitude.to_string()),
+                ("longitude", self.location.longitude.to_string()),
+                ("tilt", self.panel.tilt.to_string()),
+                ("azimuth", azimuth.to_string()),
+                ("hourly", "global_tilted_irradiance".into()),
+                ("forecast_days", "2".into()),
+                ("timeformat", "unixtime".into()),
+                ("timezone", "UTC".into()),
+            ])
+            .send()
+            .await
+            .wrap_err("Could not reach Open-Meteo")?
+            .error_for_status()
+            .wrap_err("Open-Meteo returned an error")?
+            .json()
+            .await
+            .wrap_err("Could not parse the response from Open-Meteo")?;
+
+        let next_hour = time::now().duration_trunc(TimeDelta::hours(1))? + TimeDelta::hours(1);
+        let forecast: Vec<f64> = response
+            .hourly
+            .time
+            .iter()
+            .zip(&response.hourly.global_tilted_irradiance)
+            .filter(|(&timestamp, _)| {
+                DateTime::from_timestamp(timestamp, 0).is_some_and(|time| time >= next_hour)
+            })
+            .take(24)
+            .map(|(_, irradiance)| {
+                let irradiance = irradiance.unwrap_or(0.0);
+                (irradiance * (1.0 - SYSTEM_LOSSES) / STC_IRRADIANCE_W_M2).clamp(0.0, 1.0)
+                    * self.peak_power_w
+            })
+            .collect();
+
+        if forecast.len() < 24 {
+            return Err(eyre!(
+                "Open-Meteo returned only {} hours of forecast",
+                forecast.len()
+            ));
+        }
+
+        Ok(forecast)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenMeteoResponse {
+    hourly: HourlyForecast,
+}
+
+#[derive(Deserialize, Debug)]
+struct HourlyForecast {
+    time: Vec<i64>,
+    global_tilted_irradiance: Vec<Option<f64>>,
+}
@@ -0,0 +1,423 @@
+use chrono::{DateTime, Datelike, DurationRound, TimeDelta, Timelike, Utc};
+use eyre::{bail, Context};
+use serde::{Deserialize, Serialize};
+use simulator_common::random;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The irradiance at standard test conditions, at which panels produce their peak power.
+pub const STC_IRRADIANCE_W_M2: f64 = 1000.;
+
+/// The fraction of the light hitting the ground that is reflected (typical for grass).
+const GROUND_ALBEDO: f64 = 0.2;
+
+/// The nominal operating cell temperature: the temperature panels reach at 800 W/m² and an ambient temperature of 20 °C.
+const NOCT_C: f64 = 45.;
+
+/// Losses in wiring, inverter and due to panel temperature, as a fraction of the DC production at STC.
+pub const SYSTEM_LOSSES: f64 = 0.14;
+
+/// Returns the production of panels with the given irradiance on them (in W/m²), as a fraction of peak power.
+pub fn production_from_irradiance(irradiance_w_m2: f64) -> f64 {
+    (irradiance_w_m2 * (1.0 - SYSTEM_LOSSES) / STC_IRRADIANCE_W_M2).clamp(0.0, 1.0)
+}
+
+/// Determines how much the PV installation produces at a given moment in (simulated) time.
+///
+/// Production is expressed as a fraction of the peak power of the installation, from 0.0 to 1.0.
+pub enum ProductionModel {
+    /// Look up production in an hourly profile. Timestamps that are not in the profile are filled in by the weather model.
+    Profile {
+        profile: HashMap<DateTime<Utc>, f64>,
+        fallback: WeatherModel,
+    },
+    /// Calculate production from a clear-sky irradiance model with stochastic cloud cover.
+    Synthetic(WeatherModel),
+    /// Calculate production from the irradiance on the panels, based on the position of the sun relative to the panels.
+    Physical {
+        weather: WeatherModel,
+        panel: PanelOrientation,
+    },
+    /// Add up the production of several strings of panels, each with its own production model.
+    Strings(Vec<PvString>),
+}
+
+impl ProductionModel {
+    /// Use the profile in `solar.csv` that is compiled into the simulator.
+    pub fn builtin_profile(fallback: WeatherModel) -> Self {
+        let profile = parse_profile(include_str!("solar.csv").as_bytes())
+            .expect("The built-in profile should be valid");
+
+        Self::Profile { profile, fallback }
+    }
+
+    /// Use a profile read from the CSV file at the given path.
+    ///
+    /// The file should have the same format as `solar.csv`: a header row, followed by rows with an RFC 3339 timestamp
+    /// on a whole hour and the production at that time as a fraction of peak power.
+    pub fn profile_from_path(path: impl AsRef<Path>, fallback: WeatherModel) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("Could not open PV profile {}", path.display()))?;
+        let profile = parse_profile(file)
+            .wrap_err_with(|| format!("Invalid PV profile {}", path.display()))?;
+
+        Ok(Self::Profile { profile, fallback })
+    }
+
+    /// Returns the production at the given time, as a fraction of peak power.
+    pub fn production_at(&self, time: DateTime<Utc>) -> f64 {
+        match self {
+            Self::Profile { profile, fallback } => {
+                let rounded_time = time.duration_round(TimeDelta::hours(1)).unwrap();
+                match profile.get(&rounded_time) {
+                    Some(value) => *value,
+                    None => fallback.production_at(time),
+                }
+            }
+            Self::Synthetic(weather) => weather.production_at(time),
+            Self::Physical { weather, panel } => {
+                production_from_irradiance(weather.plane_of_array_irradiance(time, panel))
+            }
+            Self::Strings(strings) => {
+                let production_w: f64 = strings
+                    .iter()
+                    .map(|string| string.production_at(time))
+                    .sum();
+                production_w / total_peak_power_w(strings)
+            }
+        }
+    }
+
+    /// The strings of panels this model consists of; empty if the model doesn't distinguish strings.
+    pub fn strings(&self) -> &[PvString] {
+        match self {
+            Self::Strings(strings) => strings,
+            _ => &[],
+        }
+    }
+
+    /// Returns the estimated temperature of the panels at the given time, in °C.
+    ///
+    /// Panels heat up above the ambient temperature in proportion to the irradiance they receive (the NOCT model).
+    pub fn panel_temperature_at(&self, time: DateTime<Utc>) -> f64 {
+        let weather = match self {
+            Self::Profile { fallback, .. } => fallback,
+            Self::Synthetic(weather) | Self::Physical { weather, .. } => weather,
+            Self::Strings(strings) => {
+                // Average the temperature of the strings, weighted by their size.
+                let weighted_temperature: f64 = strings
+                    .iter()
+                    .map(|string| string.model.panel_temperature_at(time) * string.peak_power_w)
+                    .sum();
+                return weighted_temperature / total_peak_power_w(strings);
+            }
+        };
+        let irradiance = self.production_at(time) * STC_IRRADIANCE_W_M2;
+        weather.ambient_temperature(time) + (NOCT_C - 20.0) / 800.0 * irradiance
+    }
+
+    /// Returns the air temperature around the installation at the given time, in °C.
+    pub fn ambient_temperature_at(&self, time: DateTime<Utc>) -> f64 {
+        match self {
+            Self::Profile { fallback, .. } => fallback.ambient_temperature(time),
+            Self::Synthetic(weather) | Self::Physical { weather, .. } => {
+                weather.ambient_temperature(time)
+            }
+            // All strings are in the same place, so they have the same ambient temperature.
+            Self::Strings(strings) => strings
+                .first()
+                .map(|string| string.model.ambient_temperature_at(time))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Models how an inverter reduces its output when it gets too hot.
+///
+/// The inverter heats up above the ambient temperature depending on its load, and above a certain temperature it
+/// limits its output to protect itself.
+#[derive(Debug, Clone, Copy)]
+pub struct InverterDerating {
+    /// The temperature of the inverter above which it starts derating, in °C.
+    pub start_temperature: f64,
+}
+
+impl InverterDerating {
+    /// How much the inverter heats up above the ambient temperature at full load, in °C.
+    const SELF_HEATING_C: f64 = 25.;
+    /// How much the output of the inverter is reduced per °C above the start temperature, as a fraction of its rated
+    /// output.
+    const DERATING_PER_C: f64 = 0.025;
+
+    /// Returns the maximum output of the inverter as a fraction of its rated output, given the ambient temperature
+    /// (in °C) and the load of the inverter (as a fraction of its rated output).
+    pub fn max_output(&self, ambient_temperature: f64, load: f64) -> f64 {
+        let inverter_temperature = ambient_temperature + Self::SELF_HEATING_C * load;
+        let excess_temperature = (inverter_temperature - self.start_temperature).max(0.0);
+        (1.0 - Self::DERATING_PER_C * excess_temperature).max(0.0)
+    }
+}
+
+/// A string of panels, connected to its own MPPT tracker on the inverter.
+pub struct PvString {
+    /// A name for the string, used in diagnostics.
+    pub name: String,
+    /// The peak power of the panels in the string, in W.
+    pub peak_power_w: f64,
+    /// The model that determines how much the string produces, as a fraction of its own peak power.
+    pub model: ProductionModel,
+}
+
+impl PvString {
+    /// Returns the production of the string at the given time, in W.
+    pub fn production_at(&self, time: DateTime<Utc>) -> f64 {
+        self.model.production_at(time) * self.peak_power_w
+    }
+}
+
+/// Returns the combined peak power of the given strings, in W.
+pub fn total_peak_power_w(strings: &[PvString]) -> f64 {
+    strings.iter().map(|string| string.peak_power_w).sum()
+}
+
+/// The geographical location of the installation, in degrees.
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Default for Location {
+    /// Roughly the center of the Netherlands.
+    fn default() -> Self {
+        Self {
+            latitude: 52.1,
+            longitude: 5.2,
+        }
+    }
+}
+
+/// The orientation of the panels, in degrees.
+#[derive(Debug, Clone, Copy)]
+pub struct PanelOrientation {
+    /// The angle between the panels and the ground (0 is flat, 90 is vertical).
+    pub tilt: f64,
+    /// The compass direction the panels are facing (90 is east, 180 is south, 270 is west).
+    pub azimuth: f64,
+}
+
+/// A simple weather model: clear-sky irradiance based on the position of the sun, with stochastic cloud cover on top.
+///
+/// The cloud cover is derived from a seed rather than drawn from a random number generator as time passes, so the model
+/// gives the same answer for a moment in time regardless of whether it's asked for a forecast or a measurement.
+pub struct WeatherModel {
+    seed: u64,
+    location: Location,
+}
+
+impl WeatherModel {
+    pub fn new(seed: u64, location: Location) -> Self {
+        Self { seed, location }
+    }
+
+    /// Returns the production of a horizontal installation at the given time, as a fraction of peak power.
+    pub fn production_at(&self, time: DateTime<Utc>) -> f64 {
+        let irradiance = self.plane_of_array_irradiance(
+            time,
+            &PanelOrientation {
+                tilt: 0.0,
+                azimuth: 180.0,
+            },
+        );
+        (irradiance / STC_IRRADIANCE_W_M2).clamp(0.0, 1.0)
+    }
+
+    /// Returns the irradiance on panels with the given orientation at the given time, in W/m².
+    ///
+    /// This combines the direct light from the sun with diffuse light from the sky and light reflected by the ground,
+    /// and reduces the total based on the cloud cover.
+    pub fn plane_of_array_irradiance(&self, time: DateTime<Utc>, panel: &PanelOrientation) -> f64 {
+        let sun = SolarPosition::at(time, self.location);
+        let Some((direct_normal, diffuse_horizontal)) = clear_sky_irradiance(sun.elevation) else {
+            return 0.0;
+        };
+
+        // Kasten & Czeplak: cloud cover reduces irradiance by up to 75%
+        let cloud_factor = 1.0 - 0.75 * self.cloud_cover(time).powf(3.4);
+        transpose_to_plane(&sun, direct_normal, diffuse_horizontal, panel) * cloud_factor
+    }
+
+    /// Returns the air temperature at the given time, in °C.
+    ///
+    /// This follows a seasonal cycle with a daily cycle on top (coldest around 03:00 and warmest around 15:00 solar
+    /// time), which is roughly right for a temperate climate.
+    pub fn ambient_temperature(&self, time: DateTime<Utc>) -> f64 {
+        // The coldest day is mid-January in the northern hemisphere, and mid-July in the southern hemisphere.
+        let coldest_day = if self.location.latitude >= 0.0 {
+            15.0
+        } else {
+            197.0
+        };
+        let day_of_year = time.ordinal() as f64;
+        let seasonal =
+            10.0 - 8.0 * (2.0 * std::f64::consts::PI * (day_of_year - coldest_day) / 365.0).cos();
+
+        let utc_hours = time.num_seconds_from_midnight() as f64 / 3600.;
+        let solar_hours = utc_hours + self.location.longitude / 15.0;
+        let daily = -4.0 * (2.0 * std::f64::consts::PI * (solar_hours - 3.0) / 24.0).cos();
+
+        seasonal + daily
+    }
+
+    /// Returns the cloud cover (0.0 is a clear sky, 1.0 is fully overcast) at the given time.
+    ///
+    /// A random cloud cover is picked for every hour, and values in between are smoothly interpolated.
+    pub fn cloud_cover(&self, time: DateTime<Utc>) -> f64 {
+        let hours = time.timestamp() as f64 / 3600.;
+        let hour = hours.floor();
+        let t = hours - hour;
+        let smooth_t = (1.0 - (t * std::f64::consts::PI).cos()) / 2.0;
+
+        let start = random::value_at(self.seed, hour as i64);
+        let end = random::value_at(self.seed, hour as i64 + 1);
+        start + (end - start) * smooth_t
+    }
+}
+
+/// Returns the irradiance on panels with the given orientation at the given time and location, in W/m², based on
+/// measured direct normal and diffuse horizontal irradiance (also in W/m²).
+pub fn measured_plane_of_array_irradiance(
+    time: DateTime<Utc>,
+    location: Location,
+    direct_normal: f64,
+    diffuse_horizontal: f64,
+    panel: &PanelOrientation,
+) -> f64 {
+    let sun = SolarPosition::at(time, location);
+    if sun.elevation <= 0.0 {
+        return 0.0;
+    }
+    transpose_to_plane(&sun, direct_normal, diffuse_horizontal, panel)
+}
+
+/// Combines the direct light from the sun with diffuse light from the sky and light reflected by the ground, to get
+/// the irradiance on panels with the given orientation in W/m².
+fn transpose_to_plane(
+    sun: &SolarPosition,
+    direct_normal: f64,
+    diffuse_horizontal: f64,
+    panel: &PanelOrientation,
+) -> f64 {
+    let global_horizontal = direct_normal * sun.elevation.sin() + diffuse_horizontal;
+
+    let tilt = panel.tilt.to_radians();
+    let cos_angle_of_incidence = sun.elevation.sin() * tilt.cos()
+        + sun.elevation.cos() * tilt.sin() * (sun.azimuth - panel.azimuth.to_radians()).cos();
+    let direct = direct_normal * cos_angle_of_incidence.max(0.0);
+    let diffuse = diffuse_horizontal * (1.0 + tilt.cos()) / 2.0;
+    let reflected = global_horizontal * GROUND_ALBEDO * (1.0 - tilt.cos()) / 2.0;
+    direct + diffuse + reflected
+}
+
+/// Returns the clear-sky direct normal and diffuse horizontal irradiance for the given solar elevation, in W/m².
+///
+/// This uses the Meinel model for direct irradiance with diffuse irradiance as a fixed fraction of it, which is not
+/// very accurate, but good enough to get plausible days and seasons. Returns `None` if the sun is below the horizon.
+fn clear_sky_irradiance(elevation: f64) -> Option<(f64, f64)> {
+    let sin_elevation = elevation.sin();
+    if sin_elevation <= 0.0 {
+        return None;
+    }
+
+    let air_mass = 1.0 / sin_elevation;
+    let direct_normal = 1353.0 * 0.7_f64.powf(air_mass.powf(0.678));
+    Some((direct_normal, 0.1 * direct_normal * sin_elevation))
+}
+
+/// The position of the sun in the sky, in radians.
+struct SolarPosition {
+    /// The angle of the sun above the horizon.
+    elevation: f64,
+    /// The compass direction of the sun, measured clockwise from the north.
+    azimuth: f64,
+}
+
+impl SolarPosition {
+    fn at(time: DateTime<Utc>, location: Location) -> Self {
+        let day_of_year = time.ordinal() as f64;
+        let declination = 23.45_f64.to_radians()
+            * (2.0 * std::f64::consts::PI * (284.0 + day_of_year) / 365.0).sin();
+
+        // Local solar time, ignoring the equation of time
+        let utc_hours = time.num_seconds_from_midnight() as f64 / 3600.;
+        let solar_hours = utc_hours + location.longitude / 15.0;
+        let hour_angle = (15.0 * (solar_hours - 12.0)).to_radians();
+
+        let latitude = location.latitude.to_radians();
+        let elevation = (latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * hour_angle.cos())
+        .asin();
+        // Azimuth measured from the south (positive towards the west), converted to a compass direction
+        let azimuth_from_south = hour_angle
+            .sin()
+            .atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos());
+
+        Self {
+            elevation,
+            azimuth: azimuth_from_south + std::f64::consts::PI,
+        }
+    }
+}
+
+/// Parses a production profile from CSV, validating every row.
+fn parse_profile(reader: impl std::io::Read) -> eyre::Result<HashMap<DateTime<Utc>, f64>> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader
+        .headers()
+        .wrap_err("Could not read the header row")?
+        .clone();
+
+    let mut profile = HashMap::new();
+    for record in csv_reader.records() {
+        let record = record.wrap_err("Could not read row")?;
+        let line = record
+            .position()
+            .map(|position| position.line())
+            .unwrap_or_default();
+        let row: ProfileRow = record.deserialize(Some(&headers)).wrap_err_with(|| {
+            format!("Malformed row on line {line}; expected an RFC 3339 timestamp and a number")
+        })?;
+
+        if !(0.0..=1.0).contains(&row.value) {
+            bail!(
+                "Value {} on line {line} is out of range; values should be a fraction of peak power (0.0 to 1.0)",
+                row.value
+            );
+        }
+        if row.timestamp.duration_trunc(TimeDelta::hours(1))? != row.timestamp {
+            bail!(
+                "Timestamp {} on line {line} is not on a whole hour; the profile should contain hourly values",
+                row.timestamp
+            );
+        }
+        if profile.insert(row.timestamp, row.value).is_some() {
+            bail!(
+                "Timestamp {} on line {line} occurs more than once",
+                row.timestamp
+            );
+        }
+    }
+
+    if profile.is_empty() {
+        bail!("The profile does not contain any rows");
+    }
+
+    Ok(profile)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProfileRow {
+    pub timestamp: DateTime<Utc>,
+    pub value: f64,
+}
@@ -0,0 +1,88 @@
+use crate::production::{
+    measured_plane_of_array_irradiance, Location, PanelOrientation, ProfileRow,
+    STC_IRRADIANCE_W_M2, SYSTEM_LOSSES,
+};
+use chrono::{NaiveDate, TimeZone, Utc};
+use eyre::{bail, eyre, Context};
+use serde::Deserialize;
+use std::io::{Read, Write};
+
+/// Generates a year-long hourly production profile from typical meteorological year (TMY) data.
+///
+/// The TMY data should be a CSV file with a `month,day,hour,dni,dhi` header, followed by one row per hour with the
+/// hour in UTC (0 to 23) and the direct normal and diffuse horizontal irradiance in W/m². The profile is written to
+/// `output` in the same format as `solar.csv`, with timestamps in the given year. February 29th is skipped if `year`
+/// is not a leap year.
+pub fn generate_profile(
+    tmy: impl Read,
+    output: impl Write,
+    year: i32,
+    location: Location,
+    panel: PanelOrientation,
+) -> eyre::Result<()> {
+    let mut csv_reader = csv::Reader::from_reader(tmy);
+    let headers = csv_reader
+        .headers()
+        .wrap_err("Could not read the header row")?
+        .clone();
+    let mut csv_writer = csv::Writer::from_writer(output);
+
+    let mut rows = 0;
+    for record in csv_reader.records() {
+        let record = record.wrap_err("Could not read row")?;
+        let line = record
+            .position()
+            .map(|position| position.line())
+            .unwrap_or_default();
+        let row: TmyRow = record.deserialize(Some(&headers)).wrap_err_with(|| {
+            format!("Malformed row on line {line}; expected a month, day, hour, DNI and DHI")
+        })?;
+
+        if row.dni < 0.0 || row.dhi < 0.0 {
+            bail!("Negative irradiance on line {line}");
+        }
+        let Some(date) = NaiveDate::from_ymd_opt(year, row.month, row.day) else {
+            if row.month == 2 && row.day == 29 {
+                continue;
+            }
+            bail!(
+                "Invalid date on line {line}: month {}, day {}",
+                row.month,
+                row.day
+            );
+        };
+        let timestamp = date
+            .and_hms_opt(row.hour, 0, 0)
+            .map(|time| Utc.from_utc_datetime(&time))
+            .ok_or_else(|| {
+                eyre!(
+                    "Invalid hour {} on line {line}; should be 0 to 23",
+                    row.hour
+                )
+            })?;
+
+        let irradiance =
+            measured_plane_of_array_irradiance(timestamp, location, row.dni, row.dhi, &panel);
+        let value = (irradiance * (1.0 - SYSTEM_LOSSES) / STC_IRRADIANCE_W_M2).clamp(0.0, 1.0);
+        csv_writer.serialize(ProfileRow { timestamp, value })?;
+        rows += 1;
+    }
+
+    if rows == 0 {
+        bail!("The TMY data does not contain any rows");
+    }
+
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct TmyRow {
+    month: u32,
+    day: u32,
+    hour: u32,
+    /// Direct normal irradiance, in W/m².
+    dni: f64,
+    /// Diffuse horizontal irradiance, in W/m².
+    dhi: f64,
+}
@@ -0,0 +1,325 @@
+use crate::config::PvConfig;
+use crate::installation::PvInstallation;
+use s2energy::common::{
+    ControlType, Id, InstructionStatus, InstructionStatusUpdate, Message, PowerRange,
+    ResourceManagerDetails, Transition,
+};
+use s2energy::ombc;
+use simulator_common::{
+    rm_details, time, Connection, DeviceState, RmSimulator, Scheduler, TimelineEvent,
+};
+use std::time::Duration;
+
+/// The curtailment levels the installation supports, as the maximum production as a fraction of peak power.
+///
+/// These are the steps many grid codes use for curtailment by the grid operator.
+const CURTAILMENT_LEVELS: [f64; 4] = [1.0, 0.6, 0.3, 0.0];
+
+/// Start the OMBC mock PV Panel on the given S2 connection.
+pub async fn start_mock(connection: Connection, mut config: PvConfig) -> eyre::Result<()> {
+    let timeline = std::mem::take(&mut config.timeline);
+    simulator_common::run(connection, PvSimulator::new(config), timeline).await
+}
+
+/// A simulator for a PV panel that can be curtailed in a few discrete steps.
+///
+/// This can be used to retrieve current power generation and a 24h forecast.
+/// In real usecases, this would be replaced by communication with the inverter or panel itself.
+struct PvSimulator {
+    installation: PvInstallation,
+    /// How often we send a power measurement.
+    update_interval: Duration,
+    /// When to send a new forecast: every hour, or right away when a scenario event starts.
+    scheduler: Scheduler,
+    /// The IDs of our operation modes, with the curtailment level they correspond to.
+    operation_modes: Vec<(Id, f64)>,
+    /// The operation mode we are currently in.
+    active_operation_mode: Id,
+    /// The production (in W) the latest system description we sent was based on.
+    expected_production_w: f64,
+}
+
+impl PvSimulator {
+    pub fn new(config: PvConfig) -> Self {
+        let operation_modes: Vec<_> = CURTAILMENT_LEVELS
+            .iter()
+            .map(|&level| (Id::generate(), level))
+            .collect();
+        // Start without any curtailment.
+        let active_operation_mode = operation_modes[0].0.clone();
+
+        Self {
+            update_interval: config.update_interval,
+            scheduler: Scheduler::new(config.update_interval)
+                .every("forecast", config.forecast_interval)
+                .with_jitter(config.jitter, config.seed),
+            installation: PvInstallation::new(config),
+            operation_modes,
+            active_operation_mode,
+            expected_production_w: 0.0,
+        }
+    }
+
+    pub fn get_current_power(&self) -> f64 {
+        // Production is negative in S2, so we negate the production of our model.
+        let production = self
+            .installation
+            .current_production_w()
+            .min(self.max_production_w());
+        -production
+    }
+
+    /// Returns how much less we produce (in W) than we could without curtailment.
+    fn get_curtailed_power(&self) -> f64 {
+        let production = self.installation.current_production_w();
+        production - production.min(self.max_production_w())
+    }
+
+    /// Returns the maximum production (in W) allowed by the active operation mode.
+    fn max_production_w(&self) -> f64 {
+        self.active_curtailment_level() * self.installation.peak_power_w
+    }
+
+    /// Returns the maximum production (as a fraction of peak power) allowed by the active operation mode.
+    fn active_curtailment_level(&self) -> f64 {
+        self.operation_modes
+            .iter()
+            .find(|(id, _)| *id == self.active_operation_mode)
+            .map(|(_, level)| *level)
+            .unwrap_or(1.0)
+    }
+
+    /// Returns the system description to send to the CEM, with an operation mode for every curtailment level.
+    ///
+    /// The power of each operation mode is what we expect to produce with that curtailment level, based on the current
+    /// production. Every operation mode can be reached from every other operation mode.
+    pub fn get_system_description(&mut self) -> ombc::SystemDescription {
+        self.expected_production_w = self.expected_production_w();
+
+        let operation_modes = self
+            .operation_modes
+            .iter()
+            .map(|(id, level)| {
+                // Production is negative in S2.
+                let power = -self
+                    .expected_production_w
+                    .min(level * self.installation.peak_power_w)
+                    / self.installation.phases.phase_factor();
+                ombc::OperationMode {
+                    abnormal_condition_only: false,
+                    diagnostic_label: Some(format!(
+                        "Produce at most {}% of peak power",
+                        level * 100.
+                    )),
+                    id: id.clone(),
+                    power_ranges: self
+                        .installation
+                        .phases
+                        .commodity_quantities()
+                        .into_iter()
+                        .map(|commodity_quantity| PowerRange {
+                            commodity_quantity,
+                            start_of_range: power,
+                            end_of_range: power,
+                        })
+                        .collect(),
+                    running_costs: None,
+                }
+            })
+            .collect();
+
+        let transitions = self
+            .operation_modes
+            .iter()
+            .flat_map(|(from, _)| {
+                self.operation_modes
+                    .iter()
+                    .filter(move |(to, _)| to != from)
+                    .map(move |(to, _)| Transition {
+                        abnormal_condition_only: false,
+                        blocking_timers: Vec::new(),
+                        from: from.clone(),
+                        id: Id::generate(),
+                        start_timers: Vec::new(),
+                        to: to.clone(),
+                        transition_costs: None,
+                        transition_duration: None,
+                    })
+            })
+            .collect();
+
+        ombc::SystemDescription {
+            message_id: Id::generate(),
+            operation_modes,
+            timers: Vec::new(),
+            transitions,
+            valid_from: time::now(),
+        }
+    }
+
+    /// Returns whether the production we expect has changed enough that the latest system description is outdated.
+    pub fn system_description_outdated(&self) -> bool {
+        self.expected_production_w() != self.expected_production_w
+    }
+
+    /// Returns the production we currently expect without curtailment, rounded up to 100 W.
+    fn expected_production_w(&self) -> f64 {
+        let production = self.installation.current_production_w();
+        ((production / 100.0).ceil() * 100.0).min(self.installation.inverter_ac_limit_w)
+    }
+
+    /// Switches to the operation mode in the given instruction.
+    ///
+    /// Returns a description of the problem if the instruction can't be followed.
+    pub fn process_instruction(&mut self, instruction: &ombc::Instruction) -> Result<(), String> {
+        if !self
+            .operation_modes
+            .iter()
+            .any(|(id, _)| *id == instruction.operation_mode_id)
+        {
+            return Err(format!(
+                "it refers to unknown operation mode {:?}",
+                instruction.operation_mode_id
+            ));
+        }
+        if !(0.0..=1.0).contains(&instruction.operation_mode_factor) {
+            return Err(format!(
+                "operation mode factor {} is outside of 0.0 to 1.0",
+                instruction.operation_mode_factor
+            ));
+        }
+
+        self.active_operation_mode = instruction.operation_mode_id.clone();
+        tracing::info!(
+            "Curtailing production to {}% of peak power",
+            self.active_curtailment_level() * 100.
+        );
+        Ok(())
+    }
+
+    /// Returns the status to send to the CEM after switching from `previous_operation_mode`.
+    pub fn get_status(&self, previous_operation_mode: Option<Id>) -> ombc::Status {
+        let transition_timestamp = previous_operation_mode.as_ref().map(|_| time::now());
+        ombc::Status {
+            active_operation_mode_id: self.active_operation_mode.clone(),
+            message_id: Id::generate(),
+            // Our operation modes don't have a range of power, so the factor doesn't matter.
+            operation_mode_factor: 1.0,
+            previous_operation_mode_id: previous_operation_mode,
+            transition_timestamp,
+        }
+    }
+}
+
+impl RmSimulator for PvSimulator {
+    fn resource_manager_details(&self) -> ResourceManagerDetails {
+        // Send ResourceManagerDetails to indicate some of our properties.
+        ResourceManagerDetails {
+            provides_power_measurement_types: self.installation.measured_commodity_quantities(),
+            ..rm_details::pv(ControlType::OperationModeBasedControl)
+        }
+    }
+
+    fn initial_messages(&mut self, _control_type: ControlType) -> eyre::Result<Vec<Message>> {
+        // Communicate our curtailment levels to the CEM, and tell it we start without curtailment.
+        Ok(vec![
+            self.get_system_description().into(),
+            self.get_status(None).into(),
+        ])
+    }
+
+    fn deactivate_control_type(&mut self) {
+        // Go back to the operation mode without curtailment.
+        self.active_operation_mode = self.operation_modes[0].0.clone();
+    }
+
+    fn on_ombc_instruction(
+        &mut self,
+        instruction: &ombc::Instruction,
+    ) -> eyre::Result<Vec<Message>> {
+        let previous_operation_mode = self.active_operation_mode.clone();
+        if let Err(reason) = self.process_instruction(instruction) {
+            tracing::warn!("Rejecting instruction {:?}: {reason}", instruction.id);
+            let instruction_status = InstructionStatusUpdate {
+                instruction_id: instruction.id.clone(),
+                message_id: Id::generate(),
+                status_type: InstructionStatus::Rejected,
+                timestamp: time::now(),
+            };
+            return Ok(vec![instruction_status.into()]);
+        }
+
+        // Confirm the instruction, and report the operation mode we switched to.
+        let instruction_status = InstructionStatusUpdate {
+            instruction_id: instruction.id.clone(),
+            message_id: Id::generate(),
+            status_type: InstructionStatus::Succeeded,
+            timestamp: time::now(),
+        };
+        Ok(vec![
+            instruction_status.into(),
+            self.get_status(Some(previous_operation_mode)).into(),
+        ])
+    }
+
+    /// Sends a power measurement every update (every minute by default), and a new forecast every hour.
+    async fn periodic_update(&mut self) -> eyre::Result<Vec<Message>> {
+        let mut messages = Vec::new();
+
+        // Scenario events change our production, so the CEM needs a new forecast right away.
+        let new_events = self.installation.new_scenario_events();
+        for event in &new_events {
+            tracing::info!("Scenario event started: {event:?}");
+        }
+        if !new_events.is_empty() {
+            self.scheduler.reset_immediately("forecast");
+        }
+        let due = self.scheduler.tick();
+
+        // Send a measurement of current power production.
+        let power_measurement = self
+            .installation
+            .power_measurement(self.get_current_power());
+        tracing::info!("Sending power measurement: {power_measurement:?}");
+        messages.push(power_measurement.into());
+        self.installation.log_string_production();
+
+        // The power of our operation modes depends on the sun, so tell the CEM when it has changed.
+        if self.system_description_outdated() {
+            let system_description = self.get_system_description();
+            tracing::info!(
+                "Expected production changed, sending new system description: {system_description:?}"
+            );
+            messages.push(system_description.into());
+        }
+
+        if due.contains(&"forecast") {
+            // Send a new forecast for the next 24 hours.
+            let forecast = self.installation.power_forecast().await;
+            tracing::info!("Sending power forecast: {forecast:?}");
+            messages.push(forecast.into());
+        }
+
+        Ok(messages)
+    }
+    fn update_interval(&self) -> Duration {
+        self.update_interval
+    }
+
+    fn device_state(&self) -> DeviceState {
+        DeviceState {
+            operation_mode: Some(format!(
+                "Up to {}% of peak power",
+                self.active_curtailment_level() * 100.
+            )),
+            power_w: Some(self.get_current_power()),
+            curtailment_w: Some(self.get_curtailed_power()),
+            rated_power_w: Some(self.installation.rated_power_w()),
+            ..DeviceState::default()
+        }
+    }
+
+    fn handle_event(&mut self, event: &TimelineEvent) -> eyre::Result<Vec<Message>> {
+        self.installation.handle_event(event)
+    }
+}
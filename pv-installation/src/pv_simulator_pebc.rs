@@ -1,271 +1,550 @@
-use chrono::{DateTime, DurationRound, TimeDelta, Utc};
-use eyre::eyre;
+use crate::backend::{PvBackend, SimulatedPv};
+use crate::config::PvConfig;
+use crate::installation::PvInstallation;
+use chrono::{DateTime, TimeDelta, Utc};
 use s2energy::common::{
-    Commodity, CommodityQuantity, ControlType, Duration as S2Duration, Id, InstructionStatus,
-    InstructionStatusUpdate, Message, NumberRange, PowerForecast, PowerForecastElement,
-    PowerForecastValue, PowerMeasurement, PowerValue, ResourceManagerDetails, Role, RoleType,
-    SessionRequest, SessionRequestType,
+    CommodityQuantity, ControlType, Id, InstructionStatus, InstructionStatusUpdate, Message,
+    NumberRange, ResourceManagerDetails,
 };
 use s2energy::pebc;
-use s2energy::websockets_json::S2Connection;
-use serde::{Deserialize, Serialize};
+use simulator_common::{
+    rm_details, time, Connection, DeviceState, RmSimulator, Scheduler, TimelineEvent,
+};
 use std::collections::HashMap;
 use std::time::Duration;
 
-/// Start the PEBC mock PV Panel on the given S2 connection.
-pub async fn start_mock(mut connection: S2Connection) -> eyre::Result<()> {
-    let mut simulator = PvSimulator::new();
-
-    // Send ResourceManagerDetails to indicate some of our properties.
-    let rm_details = ResourceManagerDetails {
-        available_control_types: vec![ControlType::PowerEnvelopeBasedControl],
-        currency: None,
-        firmware_version: Some("1.0.0".into()),
-        instruction_processing_delay: S2Duration(1),
-        manufacturer: Some("ACME, Inc.".into()),
-        message_id: Id::generate(),
-        model: Some("Generic PV Installation Model X".into()),
-        name: Some("The Amazing ACEM, Inc. PV Installation Model X".into()),
-        provides_forecast: true,
-        provides_power_measurement_types: vec![CommodityQuantity::ElectricPowerL1],
-        resource_id: Id::generate(),
-        roles: vec![Role {
-            commodity: Commodity::Electricity,
-            role: RoleType::EnergyProducer,
-        }],
-        serial_number: Some("111-222-333-444-555".into()),
-    };
-    let control_type = connection.initialize_as_rm(rm_details).await?;
-    if control_type != ControlType::PowerEnvelopeBasedControl {
-        return Err(eyre!(
-            "The CEM wants a control type not supported by the PEBC PV simulator: {control_type:?}"
-        ));
-    }
-
-    // Communicate our power constraints to the CEM: in this example, we can always fully curtail our power.
-    let power_constraints = pebc::PowerConstraints {
-        allowed_limit_ranges: vec![
-            pebc::AllowedLimitRange {
-                // Upper limit
-                abnormal_condition_only: false,
-                commodity_quantity: CommodityQuantity::ElectricPowerL1,
-                limit_type: pebc::PowerEnvelopeLimitType::UpperLimit,
-                range_boundary: NumberRange::new(0.0, 0.0),
-            },
-            pebc::AllowedLimitRange {
-                // Lower limit
-                abnormal_condition_only: false,
-                commodity_quantity: CommodityQuantity::ElectricPowerL1,
-                limit_type: pebc::PowerEnvelopeLimitType::LowerLimit,
-                range_boundary: NumberRange {
-                    start_of_range: 0.0,
-                    end_of_range: -POWER_IN_W,
-                },
-            },
-        ],
-        consequence_type: pebc::PowerEnvelopeConsequenceType::Vanish,
-        id: Id::generate(),
-        message_id: Id::generate(),
-        valid_from: Utc::now(),
-        valid_until: None,
-    };
-    connection.send_message(power_constraints).await?;
-
-    // Send a power measurement every 60 seconds, and a new forecast every hour.
-    let mut measurement_timer = tokio::time::interval(Duration::from_secs(60));
-    let mut forecast_timer = tokio::time::interval(Duration::from_secs(60 * 60));
-    loop {
-        tokio::select! {
-            msg = connection.receive_message() => {
-                let instruction = match msg? {
-                    Message::PebcInstruction(instruction) => instruction,
-                    msg => {
-                        tracing::info!("Received message {msg:?}. Ignoring it, as it's not a PEBC.Instruction.");
-                        continue;
-                    }
-                };
-
-                // Store any power envelopes received.
-                let base_time = instruction.execution_time;
-                for envelope in &instruction.power_envelopes {
-                    if envelope.commodity_quantity != CommodityQuantity::ElectricPowerL1 {
-                        tracing::warn!("Received power envelope for irrelevant commodity quantity {:?}", envelope.commodity_quantity);
-                        continue;
-                    }
-
-                    for element in &envelope.power_envelope_elements {
-                        let end_time = base_time + TimeDelta::milliseconds(element.duration.0 as i64);
-                        simulator.add_constraint(base_time, end_time, element.lower_limit, element.upper_limit);
-                    }
-                }
-
-                // Confirm receipt and acceptance of the instruction.
-                let instruction_status = InstructionStatusUpdate {
-                    instruction_id: instruction.id,
-                    message_id: Id::generate(),
-                    status_type: InstructionStatus::Succeeded,
-                    timestamp: Utc::now()
-                };
-                connection.send_message(instruction_status).await?;
-            }
+/// How much the curtailment range has to drop, as a fraction of the rated power, before new power constraints are sent.
+const CURTAILMENT_RANGE_HYSTERESIS: f64 = 0.1;
 
-            _ = measurement_timer.tick() => {
-                // Send a measurement of current power production.
-                let measurement_timestamp = Utc::now();
-                let power_measurement = PowerMeasurement {
-                    measurement_timestamp,
-                    message_id: Id::generate(),
-                    values: vec![PowerValue {
-                        commodity_quantity: CommodityQuantity::ElectricPowerL1,
-                        value: simulator.get_current_power(),
-                    }]
-                };
-                tracing::info!("Sending power measurement: {power_measurement:?}");
-                connection.send_message(power_measurement).await?;
-            }
-
-            _ = forecast_timer.tick() => {
-                // Send a new forecast for the next 24 hours.
-                let forecast_elements = simulator.get_24h_forecast().iter().map(|&forecast_value| {
-                    PowerForecastElement {
-                        duration: S2Duration(1000 * 60 * 60),
-                        power_values: vec![PowerForecastValue::new(CommodityQuantity::ElectricPowerL1, forecast_value, None, None, None, None, None, None)]
-                    }
-                }).collect();
-                let forecast = PowerForecast { elements: forecast_elements, message_id: Id::generate(), start_time: Utc::now() };
-                tracing::info!("Sending power forecast: {forecast:?}");
-                connection.send_message(forecast).await?;
-            }
-
-            _ = tokio::signal::ctrl_c() => {
-                tracing::warn!("Received Ctrl-C signal, stopping simulation.");
-                break;
-            }
-        }
-    }
-
-    connection
-        .send_message(SessionRequest {
-            diagnostic_label: Some("Session terminated by user (Ctrl-C)".into()),
-            message_id: Id::generate(),
-            request: SessionRequestType::Terminate,
-        })
-        .await?;
-
-    Ok(())
+/// Start the PEBC mock PV Panel on the given S2 connection.
+pub async fn start_mock(connection: Connection, mut config: PvConfig) -> eyre::Result<()> {
+    let timeline = std::mem::take(&mut config.timeline);
+    simulator_common::run(connection, PvSimulator::new(config), timeline).await
 }
 
-/// The profile is scaled from 0.0 to 1.0, so we use this multiplier to turn it into Watts.
-const POWER_IN_W: f64 = 2000.;
-
+/// A single element of a power envelope, with its start and end time resolved.
 struct PvConstraint {
     lower_limit: f64,
     upper_limit: f64,
+    /// The commodity quantity of the power envelope this constraint is part of.
+    commodity_quantity: CommodityQuantity,
     start_time: DateTime<Utc>,
     end_time: DateTime<Utc>,
+    /// The power envelope this constraint is part of.
+    envelope_id: Id,
+    /// Sequence number of the instruction this constraint came from; higher numbers are more recent.
+    instruction_sequence: u64,
 }
 
 /// A very simple simulator for a PV panel.
 ///
 /// This can be used to retrieve current power generation and a 24h forecast.
-/// In real usecases, this would be replaced by communication with the inverter or panel itself.
+/// In real usecases, this would be replaced by communication with the inverter or panel itself, as is done when a real
+/// inverter is configured as the [`PvBackend`].
 struct PvSimulator {
-    profile: HashMap<DateTime<Utc>, f64>,
-    /// The delta between real time and simulated time.
-    time_delta: TimeDelta,
+    installation: PvInstallation,
+    /// What produces the power, which follows the power envelopes: the simulated inverter, or a real one.
+    backend: Box<dyn PvBackend>,
+    /// How often we send a power measurement.
+    update_interval: Duration,
+    /// When to send a new forecast (every hour, or right away when a scenario event starts), to renew our power
+    /// constraints (shortly before they expire) and to send new energy constraints (every day).
+    scheduler: Scheduler,
     /// Any constraints on our power output (as derived from instructions received by the RM).
     constraints: Vec<PvConstraint>,
+    /// The power constraints we sent to the CEM, by ID.
+    power_constraints: HashMap<Id, pebc::PowerConstraints>,
+    /// How long the power constraints we send are valid.
+    power_constraints_validity: TimeDelta,
+    /// If set, we send energy constraints that limit curtailment to this amount of energy (Wh) per day.
+    max_curtailed_energy_wh: Option<f64>,
+    /// The maximum production (in W) that could be curtailed according to the latest power constraints we sent.
+    curtailment_range_w: f64,
+    /// The number of instructions received so far, used to determine which instruction is the most recent.
+    instructions_received: u64,
+    /// Whether curtailed energy vanishes, or is deferred to be produced later.
+    consequence_type: pebc::PowerEnvelopeConsequenceType,
+    /// The power in the latest measurement; it isn't recalculated for the dashboard, as that affects deferred energy.
+    last_power_w: Option<f64>,
+    /// How much less we produced (in W) than we could in the latest measurement, due to the power envelopes.
+    last_curtailment_w: Option<f64>,
 }
 
 impl PvSimulator {
-    pub fn new() -> Self {
-        // Read the simulated values from a profile.
-        let mut csv_reader = csv::Reader::from_reader(include_str!("solar.csv").as_bytes());
-        let profile = csv_reader
-            .deserialize()
-            .filter_map(|result: Result<ProfileRow, _>| result.ok())
-            .map(|row| (row.timestamp, -row.value))
-            .collect();
-
-        // Calculate the time delta between simulated and real time.
-        let simulated_start_time: DateTime<Utc> =
-            DateTime::parse_from_rfc3339("2030-01-01T12:00:00Z")
-                .unwrap()
-                .into();
-        let time_delta = simulated_start_time - Utc::now();
+    pub fn new(mut config: PvConfig) -> Self {
+        let update_interval = config.update_interval;
+        let backend = match config.backend.take() {
+            Some(backend) => backend.start(),
+            None => Box::new(SimulatedPv::new(
+                config.inverter_ac_limit_w,
+                config.consequence_type == pebc::PowerEnvelopeConsequenceType::Defer,
+            )),
+        };
 
         Self {
-            profile,
-            time_delta,
+            backend,
+            update_interval,
+            // The initial messages contain power constraints, so the first renewal is due one period later. Only the
+            // forecasts get jitter, so the power constraints are always renewed before they expire.
+            scheduler: Scheduler::new(update_interval)
+                .every("forecast", config.forecast_interval)
+                .with_jitter(config.jitter, config.seed)
+                .every_after(
+                    "power constraints",
+                    config.power_constraints_validity.mul_f64(0.9),
+                )
+                .every("energy constraints", Duration::from_secs(24 * 60 * 60)),
             constraints: Vec::new(),
+            power_constraints: HashMap::new(),
+            power_constraints_validity: TimeDelta::from_std(config.power_constraints_validity)
+                .unwrap_or(TimeDelta::hours(1)),
+            max_curtailed_energy_wh: config.max_curtailed_energy_wh,
+            curtailment_range_w: 0.0,
+            instructions_received: 0,
+            consequence_type: config.consequence_type,
+            last_power_w: None,
+            last_curtailment_w: None,
+            installation: PvInstallation::new(config),
         }
     }
 
-    pub fn get_current_power(&self) -> f64 {
-        let simulated_current_time = Utc::now() + self.time_delta;
-        let rounded_time = simulated_current_time
-            .duration_round(TimeDelta::hours(1))
-            .unwrap();
-
-        let (lower_limit, upper_limit) = self.get_current_constraints();
+    /// Returns the current power in W, or `None` if the inverter hasn't been read recently.
+    pub fn get_current_power(&mut self) -> Option<f64> {
+        self.limit_inverter();
+        let available_w = self.installation.current_production_w();
+        if let Some(simulated) = self.backend.simulated() {
+            simulated.set_available_power_w(available_w);
+        }
 
-        self.profile
-            .get(&rounded_time)
-            .unwrap()
-            .max(lower_limit)
-            .min(upper_limit)
-            * POWER_IN_W
+        let power_w = self.backend.power_w();
+        self.last_curtailment_w = self.backend.curtailment_w();
+        // Production is negative in S2, but positive on the inverter.
+        power_w.map(|power_w| -power_w)
     }
 
-    /// Returns a 24h forecast: a `Vec` with 24 elements, one for each hour in order, starting at the next hour.
-    pub fn get_24h_forecast(&self) -> Vec<f64> {
-        let simulated_current_time = Utc::now() + self.time_delta;
-        let rounded_time = simulated_current_time
-            .duration_round(TimeDelta::hours(1))
-            .unwrap();
+    /// Limits the production of the inverter to the lower limit of the power envelopes that currently apply.
+    ///
+    /// A PV installation can't be made to produce more than the sun allows, so the upper limit is left to the sun.
+    fn limit_inverter(&mut self) {
+        let (lower_limit, _) = self.get_current_constraints();
+        self.backend
+            .set_limit((lower_limit > -1.0).then(|| -lower_limit * self.installation.peak_power_w));
+    }
 
-        (0..24)
+    /// Returns energy constraints for the next 24 hours, allowing at most `max_curtailed_energy_wh` to be curtailed.
+    ///
+    /// The lower average power is what we would produce without any curtailment (we can't produce more than that),
+    /// and the upper average power is what we'd produce after curtailing the maximum amount of energy. There is one
+    /// energy constraint for each commodity quantity we report.
+    pub fn get_energy_constraints(
+        &self,
+        max_curtailed_energy_wh: f64,
+    ) -> Vec<pebc::EnergyConstraint> {
+        let simulated_current_time = self.installation.simulated_now();
+        let expected_energy_wh: f64 = (0..24)
             .map(|offset| {
-                let offset_time = rounded_time + TimeDelta::hours(offset + 1);
-                self.profile
-                    .get(&offset_time)
-                    .unwrap()
-                    * POWER_IN_W
+                self.installation
+                    .production_at(simulated_current_time + TimeDelta::hours(offset))
+                    * self.installation.peak_power_w
+            })
+            .sum();
+        let min_energy_wh = (expected_energy_wh - max_curtailed_energy_wh).max(0.0);
+
+        // Production is negative in S2, so the lower average power corresponds to the most production.
+        let valid_from = time::now();
+        self.installation
+            .phases
+            .commodity_quantities()
+            .into_iter()
+            .map(|commodity_quantity| pebc::EnergyConstraint {
+                commodity_quantity,
+                id: Id::generate(),
+                lower_average_power: -expected_energy_wh
+                    / 24.
+                    / self.installation.phases.phase_factor(),
+                message_id: Id::generate(),
+                upper_average_power: -min_energy_wh / 24. / self.installation.phases.phase_factor(),
+                valid_from,
+                valid_until: valid_from + TimeDelta::hours(24),
             })
             .collect()
     }
 
+    /// Returns the power constraints to send to the CEM, and remembers them so instructions can be checked against them.
+    ///
+    /// The power constraints are valid for a limited time, and only allow curtailing the production we expect during that time.
+    pub fn get_power_constraints(&mut self) -> pebc::PowerConstraints {
+        let valid_from = time::now();
+        let valid_until = valid_from + self.power_constraints_validity;
+        self.curtailment_range_w = self.expected_curtailment_range_w();
+
+        let power_constraints = pebc::PowerConstraints {
+            // An upper and lower limit range for every commodity quantity we report.
+            allowed_limit_ranges: self
+                .installation
+                .phases
+                .commodity_quantities()
+                .into_iter()
+                .flat_map(|commodity_quantity| {
+                    [
+                        pebc::AllowedLimitRange {
+                            // Upper limit
+                            abnormal_condition_only: false,
+                            commodity_quantity,
+                            limit_type: pebc::PowerEnvelopeLimitType::UpperLimit,
+                            range_boundary: NumberRange::new(0.0, 0.0),
+                        },
+                        pebc::AllowedLimitRange {
+                            // Lower limit
+                            abnormal_condition_only: false,
+                            commodity_quantity,
+                            limit_type: pebc::PowerEnvelopeLimitType::LowerLimit,
+                            range_boundary: NumberRange {
+                                start_of_range: -self.curtailment_range_w
+                                    / self.installation.phases.phase_factor(),
+                                end_of_range: 0.0,
+                            },
+                        },
+                    ]
+                })
+                .collect(),
+            consequence_type: self.consequence_type,
+            id: Id::generate(),
+            message_id: Id::generate(),
+            valid_from,
+            valid_until: Some(valid_until),
+        };
+
+        // Forget about power constraints that have expired, and remember the new ones.
+        self.power_constraints.retain(|_, constraints| {
+            constraints
+                .valid_until
+                .is_none_or(|valid_until| valid_until > valid_from)
+        });
+        self.power_constraints
+            .insert(power_constraints.id.clone(), power_constraints.clone());
+
+        power_constraints
+    }
+
+    /// Returns whether the production we expect has changed enough that the latest power constraints are outdated.
+    ///
+    /// The CEM needs a larger curtailment range right away, or it can't curtail all we produce. A smaller range only
+    /// makes the latest constraints allow more than we can curtail, so it's sent once it dropped by a margin (or when the
+    /// constraints are renewed); otherwise production hovering around a rounding step would make the constraints flap.
+    pub fn power_constraints_outdated(&mut self) -> bool {
+        let curtailment_range_w = self.expected_curtailment_range_w();
+        let margin_w = CURTAILMENT_RANGE_HYSTERESIS * self.installation.rated_power_w();
+        curtailment_range_w > self.curtailment_range_w
+            || curtailment_range_w < self.curtailment_range_w - margin_w
+    }
+
+    /// Returns the maximum production we expect while new power constraints are valid, rounded up to 100 W.
+    ///
+    /// That's the most power that could be curtailed, so this determines the allowed range of the lower limit.
+    fn expected_curtailment_range_w(&mut self) -> f64 {
+        // A real inverter may produce up to its rated power, whatever the production model expects.
+        if self.backend.simulated().is_none() {
+            return self.installation.rated_power_w();
+        }
+        let simulated_current_time = self.installation.simulated_now();
+        let steps = self.power_constraints_validity.num_minutes() / 15;
+        let max_production = (0..=steps)
+            .map(|step| {
+                self.installation
+                    .production_at(simulated_current_time + TimeDelta::minutes(step * 15))
+            })
+            .fold(0.0, f64::max);
+
+        ((max_production * self.installation.peak_power_w / 100.0).ceil() * 100.0)
+            .min(self.installation.inverter_ac_limit_w)
+    }
+
+    /// Checks whether the given instruction fits within the power constraints it refers to.
+    ///
+    /// Returns a description of the problem if it doesn't.
+    pub fn validate_instruction(&self, instruction: &pebc::Instruction) -> Result<(), String> {
+        let Some(power_constraints) = self
+            .power_constraints
+            .get(&instruction.power_constraints_id)
+        else {
+            return Err(format!(
+                "it refers to unknown power constraints {:?}",
+                instruction.power_constraints_id
+            ));
+        };
+        if power_constraints
+            .valid_until
+            .is_some_and(|valid_until| valid_until < instruction.execution_time)
+        {
+            return Err(format!(
+                "the power constraints {:?} it refers to expire before its execution time",
+                instruction.power_constraints_id
+            ));
+        }
+
+        for envelope in &instruction.power_envelopes {
+            // Only the ranges for this commodity quantity apply, and some only during abnormal conditions.
+            let ranges_for = |limit_type: pebc::PowerEnvelopeLimitType| {
+                power_constraints
+                    .allowed_limit_ranges
+                    .iter()
+                    .filter(move |range| {
+                        range.commodity_quantity == envelope.commodity_quantity
+                            && range.limit_type == limit_type
+                            && (!range.abnormal_condition_only || instruction.abnormal_condition)
+                    })
+            };
+            let fits = |limit_type: pebc::PowerEnvelopeLimitType, value: f64| {
+                ranges_for(limit_type).any(|range| {
+                    let boundary = &range.range_boundary;
+                    let (start, end) = if boundary.start_of_range <= boundary.end_of_range {
+                        (boundary.start_of_range, boundary.end_of_range)
+                    } else {
+                        (boundary.end_of_range, boundary.start_of_range)
+                    };
+                    start <= value && value <= end
+                })
+            };
+
+            for element in &envelope.power_envelope_elements {
+                if element.lower_limit > element.upper_limit {
+                    return Err(format!(
+                        "envelope {:?} has a lower limit ({}) above its upper limit ({})",
+                        envelope.id, element.lower_limit, element.upper_limit
+                    ));
+                }
+                if !fits(
+                    pebc::PowerEnvelopeLimitType::LowerLimit,
+                    element.lower_limit,
+                ) {
+                    return Err(format!(
+                        "lower limit {} in envelope {:?} is outside the allowed limit ranges for {:?}",
+                        element.lower_limit, envelope.id, envelope.commodity_quantity
+                    ));
+                }
+                if !fits(
+                    pebc::PowerEnvelopeLimitType::UpperLimit,
+                    element.upper_limit,
+                ) {
+                    return Err(format!(
+                        "upper limit {} in envelope {:?} is outside the allowed limit ranges for {:?}",
+                        element.upper_limit, envelope.id, envelope.commodity_quantity
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the lower and upper limit on our total power that currently apply, as a fraction of peak power.
+    ///
+    /// When envelopes from multiple instructions overlap, the most recently received instruction wins. If there are
+    /// envelopes for multiple phases, the phase with the strictest limits determines how much we can produce.
     fn get_current_constraints(&self) -> (f64, f64) {
-        for constraint in &self.constraints {
-            if constraint.start_time <= Utc::now() && constraint.end_time >= Utc::now() {
-                return (constraint.lower_limit, constraint.upper_limit);
+        let now = time::now();
+        let mut limits = (-1.0_f64, 1.0_f64);
+        for commodity_quantity in self.installation.phases.commodity_quantities() {
+            let current_constraint = self
+                .constraints
+                .iter()
+                .filter(|constraint| {
+                    constraint.commodity_quantity == commodity_quantity
+                        && constraint.start_time <= now
+                        && now < constraint.end_time
+                })
+                .max_by_key(|constraint| constraint.instruction_sequence);
+
+            if let Some(constraint) = current_constraint {
+                tracing::debug!("Following power envelope {:?}", constraint.envelope_id);
+                limits.0 = limits.0.max(constraint.lower_limit);
+                limits.1 = limits.1.min(constraint.upper_limit);
             }
         }
 
-        (-1.0, 1.0)
+        limits
     }
 
-    pub fn add_constraint(
-        &mut self,
-        start_time: DateTime<Utc>,
-        end_time: DateTime<Utc>,
-        lower_limit: f64,
-        upper_limit: f64,
-    ) {
-        self.constraints.push(PvConstraint {
-            lower_limit: lower_limit / POWER_IN_W,
-            upper_limit: upper_limit / POWER_IN_W,
-            start_time,
-            end_time,
-        });
+    /// Stores the power envelopes from the given instruction as constraints on our power output.
+    pub fn add_instruction(&mut self, instruction: &pebc::Instruction) {
+        self.instructions_received += 1;
+
+        for envelope in &instruction.power_envelopes {
+            if !self
+                .installation
+                .phases
+                .commodity_quantities()
+                .contains(&envelope.commodity_quantity)
+            {
+                tracing::warn!(
+                    "Received power envelope for irrelevant commodity quantity {:?}",
+                    envelope.commodity_quantity
+                );
+                continue;
+            }
+
+            // Elements follow each other, so every element starts where the previous one ended.
+            let mut start_time = instruction.execution_time;
+            for element in &envelope.power_envelope_elements {
+                let end_time = start_time + TimeDelta::milliseconds(element.duration.0 as i64);
+                self.constraints.push(PvConstraint {
+                    // Limits are stored for our total power, so they can be compared to our production model.
+                    lower_limit: element.lower_limit * self.installation.phases.phase_factor()
+                        / self.installation.peak_power_w,
+                    upper_limit: element.upper_limit * self.installation.phases.phase_factor()
+                        / self.installation.peak_power_w,
+                    commodity_quantity: envelope.commodity_quantity,
+                    start_time,
+                    end_time,
+                    envelope_id: envelope.id.clone(),
+                    instruction_sequence: self.instructions_received,
+                });
+                start_time = end_time;
+            }
+            tracing::info!(
+                "Stored power envelope {:?}, active from {} until {start_time}",
+                envelope.id,
+                instruction.execution_time
+            );
+        }
+
         // Also clean up any old constraints that have already ended.
         self.constraints
-            .retain(|constraint| constraint.end_time > Utc::now());
+            .retain(|constraint| constraint.end_time > time::now());
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ProfileRow {
-    timestamp: DateTime<Utc>,
-    value: f64,
+impl RmSimulator for PvSimulator {
+    fn resource_manager_details(&self) -> ResourceManagerDetails {
+        // Send ResourceManagerDetails to indicate some of our properties.
+        ResourceManagerDetails {
+            provides_power_measurement_types: self.installation.measured_commodity_quantities(),
+            ..rm_details::pv(ControlType::PowerEnvelopeBasedControl)
+        }
+    }
+
+    fn initial_messages(&mut self, _control_type: ControlType) -> eyre::Result<Vec<Message>> {
+        // Communicate our power constraints to the CEM: in this example, we can always fully curtail our power.
+        Ok(vec![self.get_power_constraints().into()])
+    }
+
+    fn deactivate_control_type(&mut self) {
+        // Without power envelopes, we produce as much as we can again.
+        self.constraints.clear();
+        self.limit_inverter();
+    }
+
+    fn on_pebc_instruction(
+        &mut self,
+        instruction: &pebc::Instruction,
+    ) -> eyre::Result<Vec<Message>> {
+        // Check the instruction against the power constraints we sent, and reject it if it doesn't fit.
+        if let Err(reason) = self.validate_instruction(instruction) {
+            tracing::warn!("Rejecting instruction {:?}: {reason}", instruction.id);
+            let instruction_status = InstructionStatusUpdate {
+                instruction_id: instruction.id.clone(),
+                message_id: Id::generate(),
+                status_type: InstructionStatus::Rejected,
+                timestamp: time::now(),
+            };
+            return Ok(vec![instruction_status.into()]);
+        }
+
+        // Store any power envelopes received, and pass the limit on to the inverter right away if it changes now.
+        self.add_instruction(instruction);
+        self.limit_inverter();
+
+        // Confirm receipt and acceptance of the instruction.
+        let instruction_status = InstructionStatusUpdate {
+            instruction_id: instruction.id.clone(),
+            message_id: Id::generate(),
+            status_type: InstructionStatus::Succeeded,
+            timestamp: time::now(),
+        };
+        Ok(vec![instruction_status.into()])
+    }
+
+    /// Sends a power measurement every update (every minute by default), and new forecasts and constraints when they're due.
+    async fn periodic_update(&mut self) -> eyre::Result<Vec<Message>> {
+        let mut messages = Vec::new();
+
+        // Scenario events change our production, so the CEM needs a new forecast right away.
+        let new_events = self.installation.new_scenario_events();
+        for event in &new_events {
+            tracing::info!("Scenario event started: {event:?}");
+        }
+        if !new_events.is_empty() {
+            self.scheduler.reset_immediately("forecast");
+        }
+        let due = self.scheduler.tick();
+
+        // Send a measurement of current power production.
+        let current_power = self.get_current_power();
+        self.last_power_w = current_power;
+        match current_power {
+            Some(current_power) => {
+                let power_measurement = self.installation.power_measurement(current_power);
+                tracing::info!("Sending power measurement: {power_measurement:?}");
+                messages.push(power_measurement.into());
+            }
+            None => tracing::warn!(
+                "The inverter hasn't been read recently, so no power measurement is sent"
+            ),
+        }
+        if self.backend.simulated().is_some() {
+            self.installation.log_string_production();
+        }
+
+        // If the amount we can curtail has changed, the CEM needs new power constraints.
+        if self.power_constraints_outdated() {
+            let power_constraints = self.get_power_constraints();
+            tracing::info!(
+                "Curtailment range changed, sending new power constraints: {power_constraints:?}"
+            );
+            messages.push(power_constraints.into());
+        }
+
+        // Renew our power constraints before they expire.
+        if due.contains(&"power constraints") {
+            let power_constraints = self.get_power_constraints();
+            tracing::info!("Renewing power constraints: {power_constraints:?}");
+            messages.push(power_constraints.into());
+        }
+
+        if due.contains(&"forecast") {
+            // Send a new forecast for the next 24 hours.
+            let forecast = self.installation.power_forecast().await;
+            tracing::info!("Sending power forecast: {forecast:?}");
+            messages.push(forecast.into());
+        }
+
+        // If we have a limit on curtailment, send new energy constraints every day.
+        if let Some(max_curtailed_energy_wh) = self.max_curtailed_energy_wh {
+            if due.contains(&"energy constraints") {
+                for energy_constraint in self.get_energy_constraints(max_curtailed_energy_wh) {
+                    tracing::info!("Sending energy constraint: {energy_constraint:?}");
+                    messages.push(energy_constraint.into());
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+    fn update_interval(&self) -> Duration {
+        self.update_interval
+    }
+
+    fn device_state(&self) -> DeviceState {
+        DeviceState {
+            power_w: self.last_power_w,
+            curtailment_w: self.last_curtailment_w,
+            rated_power_w: Some(self.installation.rated_power_w()),
+            ..DeviceState::default()
+        }
+    }
+
+    fn handle_event(&mut self, event: &TimelineEvent) -> eyre::Result<Vec<Message>> {
+        self.installation.handle_event(event)
+    }
 }
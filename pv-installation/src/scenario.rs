@@ -0,0 +1,148 @@
+use chrono::{DateTime, Utc};
+use eyre::{bail, Context};
+use serde::Deserialize;
+use std::path::Path;
+
+/// A list of transient events that affect the production of the installation at specific moments in simulated time.
+///
+/// Events can't be foreseen: they only affect forecasts once they have started. From that moment on, forecasts take
+/// the rest of the event into account.
+#[derive(Debug, Default)]
+pub struct Scenario {
+    events: Vec<ScenarioEvent>,
+}
+
+/// A single event in a scenario.
+#[derive(Debug, Clone)]
+pub struct ScenarioEvent {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub kind: ScenarioEventKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScenarioEventKind {
+    /// A passing cloud, which only lets through the given fraction of the production.
+    Cloud { transmittance: f64 },
+    /// The inverter trips, so there is no production at all.
+    InverterTrip,
+    /// The grid operator limits production to the given fraction of peak power.
+    Curtailment { max_production: f64 },
+    /// The panels produce the given fraction of peak power, whatever the weather; used for irradiance set by hand.
+    Production { production: f64 },
+}
+
+impl Scenario {
+    /// Reads a scenario from the CSV file at the given path.
+    ///
+    /// The file should have a `start,end,event,value` header, followed by one row per event with an RFC 3339 start and
+    /// end time, the type of event (`CLOUD`, `INVERTER_TRIP` or `CURTAILMENT`), and its value as a fraction: the
+    /// fraction of production a cloud lets through, or the fraction of peak power production is curtailed to.
+    pub fn from_path(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("Could not open scenario {}", path.display()))?;
+        parse_scenario(file).wrap_err_with(|| format!("Invalid scenario {}", path.display()))
+    }
+
+    /// Adds an event that wasn't in the scenario file, such as an outage from the timeline.
+    pub fn add_event(&mut self, event: ScenarioEvent) {
+        self.events.push(event);
+    }
+
+    /// Applies the events at `time` to the given production (a fraction of peak power).
+    ///
+    /// Only events that have started at `now` are taken into account, so forecasts don't know about future events.
+    pub fn apply(&self, production: f64, time: DateTime<Utc>, now: DateTime<Utc>) -> f64 {
+        let active = || {
+            self.events
+                .iter()
+                .filter(move |event| event.start <= now && event.start <= time && time < event.end)
+        };
+        // Production set by hand replaces that of the model, and the other events apply on top of it.
+        let production = active()
+            .filter_map(|event| match event.kind {
+                ScenarioEventKind::Production { production } => Some(production),
+                _ => None,
+            })
+            .next_back()
+            .unwrap_or(production);
+        active().fold(production, |production, event| match event.kind {
+            ScenarioEventKind::Cloud { transmittance } => production * transmittance,
+            ScenarioEventKind::InverterTrip => 0.0,
+            ScenarioEventKind::Curtailment { max_production } => production.min(max_production),
+            ScenarioEventKind::Production { .. } => production,
+        })
+    }
+
+    /// Returns the events that started from `from`, up to but not including `to`.
+    ///
+    /// Asking for consecutive ranges, each starting where the previous one ended, returns every event exactly once.
+    pub fn events_started_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> impl Iterator<Item = &ScenarioEvent> {
+        self.events
+            .iter()
+            .filter(move |event| from <= event.start && event.start < to)
+    }
+}
+
+/// Parses a scenario from CSV, validating every row.
+fn parse_scenario(reader: impl std::io::Read) -> eyre::Result<Scenario> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader
+        .headers()
+        .wrap_err("Could not read the header row")?
+        .clone();
+
+    let mut events = Vec::new();
+    for record in csv_reader.records() {
+        let record = record.wrap_err("Could not read row")?;
+        let line = record
+            .position()
+            .map(|position| position.line())
+            .unwrap_or_default();
+        let row: ScenarioRow = record.deserialize(Some(&headers)).wrap_err_with(|| {
+            format!("Malformed row on line {line}; expected two RFC 3339 timestamps, an event type and an optional number")
+        })?;
+
+        if row.end <= row.start {
+            bail!("Event on line {line} ends before it starts");
+        }
+        let fraction = |name: &str| match row.value {
+            Some(value) if (0.0..=1.0).contains(&value) => Ok(value),
+            Some(value) => bail!("Value {value} on line {line} should be a fraction (0.0 to 1.0)"),
+            None => bail!("Event on line {line} needs a value: the {name}"),
+        };
+        let kind = match row.event.as_str() {
+            "CLOUD" => ScenarioEventKind::Cloud {
+                transmittance: fraction("fraction of production the cloud lets through")?,
+            },
+            "INVERTER_TRIP" => ScenarioEventKind::InverterTrip,
+            "CURTAILMENT" => ScenarioEventKind::Curtailment {
+                max_production: fraction("maximum production as a fraction of peak power")?,
+            },
+            other => bail!(
+                "Unknown event {other} on line {line}; should be CLOUD, INVERTER_TRIP or CURTAILMENT"
+            ),
+        };
+
+        events.push(ScenarioEvent {
+            start: row.start,
+            end: row.end,
+            kind,
+        });
+    }
+
+    Ok(Scenario { events })
+}
+
+#[derive(Deserialize, Debug)]
+struct ScenarioRow {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    event: String,
+    value: Option<f64>,
+}
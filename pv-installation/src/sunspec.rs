@@ -0,0 +1,263 @@
+use crate::modbus::{ModbusClient, RegisterFormat, RegisterKind};
+use eyre::{bail, eyre};
+
+/// The addresses SunSpec devices commonly start their register map at.
+const BASE_ADDRESSES: [u16; 3] = [40000, 0, 50000];
+/// "SunS", which every SunSpec register map starts with.
+const MARKER: [u16; 2] = [0x5375, 0x6e53];
+/// The model ID that ends the register map.
+const END_MODEL: u16 = 0xFFFF;
+/// How many models to look at before giving up, in case the register map doesn't end.
+const MAX_MODELS: usize = 100;
+
+/// The value of an `int16` or `sunssf` point that the device doesn't implement.
+const NOT_IMPLEMENTED: u16 = 0x8000;
+/// The value of a `uint16` point that the device doesn't implement.
+const UNSIGNED_NOT_IMPLEMENTED: u16 = 0xFFFF;
+
+const COMMON: u16 = 1;
+/// The inverter models with integer values and scale factors, for single phase, split phase and three phases.
+const INTEGER_INVERTERS: [u16; 3] = [101, 102, 103];
+/// The inverter models with floating point values.
+const FLOAT_INVERTERS: [u16; 3] = [111, 112, 113];
+const NAMEPLATE: u16 = 120;
+const SETTINGS: u16 = 121;
+const CONTROLS: u16 = 123;
+
+/// Where a point is, relative to the start of the data of its model (after its ID and length).
+mod offset {
+    /// `Mn`, `Md` and `SN` in the common model, which are strings of 16 registers.
+    pub const MANUFACTURER: u16 = 0;
+    pub const MODEL: u16 = 16;
+    pub const SERIAL_NUMBER: u16 = 48;
+    /// `W` and `W_SF` in the integer inverter models.
+    pub const INTEGER_POWER: u16 = 12;
+    /// `W` in the float inverter models.
+    pub const FLOAT_POWER: u16 = 20;
+    /// `WRtg` and `WRtg_SF` in the nameplate model.
+    pub const RATED_POWER: u16 = 1;
+    /// `WMax` and `WMax_SF` in the settings model.
+    pub const MAX_POWER: u16 = 0;
+    pub const MAX_POWER_SF: u16 = 20;
+    /// `WMaxLimPct` to `WMaxLim_Ena` in the immediate controls model.
+    pub const LIMIT_PERCENTAGE: u16 = 3;
+    pub const LIMIT_ENABLED: u16 = 7;
+    pub const LIMIT_PERCENTAGE_SF: u16 = 21;
+}
+
+/// A model in the register map of a device.
+#[derive(Debug, Clone, Copy)]
+struct Model {
+    id: u16,
+    /// The address of the first register of its data, after its ID and length.
+    address: u16,
+}
+
+/// A solar inverter that implements the [SunSpec](https://sunspec.org) information models, so its power, rated power
+/// and curtailment are found in the same way whatever its brand.
+///
+/// The power comes from one of the inverter models (101 to 103, or 111 to 113 with floating point values), and the
+/// inverter is curtailed through `WMaxLimPct` in the immediate controls model (123), which is a percentage of its
+/// maximum power from the settings model (121), its rated power from the nameplate model (120), or else the rated power
+/// of the configuration.
+#[derive(Debug)]
+pub struct SunSpecInverter {
+    inverter: Model,
+    /// The immediate controls model, if the inverter can be curtailed.
+    controls: Option<Model>,
+    /// The power that 100% `WMaxLimPct` is, in W.
+    max_power_w: f64,
+    /// The scale factor of `WMaxLimPct`.
+    limit_percentage_sf: i16,
+}
+
+impl SunSpecInverter {
+    /// Finds the models of the inverter, at `base_address` or else at the addresses SunSpec devices commonly use.
+    pub async fn discover(
+        client: &mut ModbusClient,
+        base_address: Option<u16>,
+        rated_power_w: f64,
+    ) -> eyre::Result<Self> {
+        let base_address = match base_address {
+            Some(base_address) => base_address,
+            None => find_base_address(client).await?,
+        };
+        let models = read_models(client, base_address).await?;
+        let model = |ids: &[u16]| models.iter().copied().find(|model| ids.contains(&model.id));
+
+        let inverter = model(&[INTEGER_INVERTERS, FLOAT_INVERTERS].concat()).ok_or_else(|| {
+            eyre!("The SunSpec device has no inverter model (101 to 103 or 111 to 113)")
+        })?;
+        if let Some(common) = model(&[COMMON]) {
+            let manufacturer = read_string(client, common.address + offset::MANUFACTURER).await?;
+            let model = read_string(client, common.address + offset::MODEL).await?;
+            let serial_number = read_string(client, common.address + offset::SERIAL_NUMBER).await?;
+            tracing::info!(
+                "Found SunSpec inverter {manufacturer} {model} ({serial_number}) with models {:?}",
+                models.iter().map(|model| model.id).collect::<Vec<_>>()
+            );
+        }
+
+        // The maximum power in the settings can be set lower than the rated power, and the limit is relative to it.
+        let max_power_w = match (model(&[SETTINGS]), model(&[NAMEPLATE])) {
+            (Some(settings), _) => {
+                let max_power = read(client, settings.address + offset::MAX_POWER, 1).await?[0];
+                let scale_factor =
+                    read(client, settings.address + offset::MAX_POWER_SF, 1).await?[0];
+                scaled(unsigned(max_power), scale_factor)
+            }
+            (None, Some(nameplate)) => {
+                let registers = read(client, nameplate.address + offset::RATED_POWER, 2).await?;
+                scaled(unsigned(registers[0]), registers[1])
+            }
+            (None, None) => None,
+        }
+        .unwrap_or(rated_power_w);
+
+        let controls = model(&[CONTROLS]);
+        let limit_percentage_sf = match controls {
+            Some(controls) => {
+                let scale_factor =
+                    read(client, controls.address + offset::LIMIT_PERCENTAGE_SF, 1).await?[0];
+                if scale_factor == NOT_IMPLEMENTED {
+                    0
+                } else {
+                    scale_factor as i16
+                }
+            }
+            None => {
+                tracing::warn!(
+                    "The SunSpec inverter has no immediate controls (model 123), so it can't be curtailed"
+                );
+                0
+            }
+        };
+
+        Ok(Self {
+            inverter,
+            controls,
+            max_power_w,
+            limit_percentage_sf,
+        })
+    }
+
+    /// Whether the inverter can be curtailed.
+    pub fn can_limit(&self) -> bool {
+        self.controls.is_some()
+    }
+
+    /// Reads the AC power the inverter produces, in W.
+    pub async fn read_power_w(&self, client: &mut ModbusClient) -> eyre::Result<f64> {
+        let power_w = if FLOAT_INVERTERS.contains(&self.inverter.id) {
+            let registers = read(client, self.inverter.address + offset::FLOAT_POWER, 2).await?;
+            Some(RegisterFormat::F32.decode(&registers)).filter(|power_w| !power_w.is_nan())
+        } else {
+            let registers = read(client, self.inverter.address + offset::INTEGER_POWER, 2).await?;
+            scaled(signed(registers[0]), registers[1])
+        };
+        power_w.ok_or_else(|| eyre!("The SunSpec inverter doesn't report its power"))
+    }
+
+    /// Limits the AC power of the inverter to `limit_w` through `WMaxLimPct`, or lifts the limit with `None`.
+    pub async fn write_limit(
+        &self,
+        client: &mut ModbusClient,
+        limit_w: Option<f64>,
+    ) -> eyre::Result<()> {
+        let Some(controls) = self.controls else {
+            bail!("The SunSpec inverter has no immediate controls");
+        };
+        match limit_w {
+            Some(limit_w) => {
+                let percentage = (limit_w / self.max_power_w * 100.0).clamp(0.0, 100.0);
+                let value =
+                    (percentage / 10f64.powi(self.limit_percentage_sf as i32)).round() as u16;
+                // The limit applies right away and for good, without ramping, until it's lifted.
+                client
+                    .write_registers(
+                        controls.address + offset::LIMIT_PERCENTAGE,
+                        &[value, 0, 0, 0, 1],
+                    )
+                    .await
+            }
+            None => {
+                client
+                    .write_registers(controls.address + offset::LIMIT_ENABLED, &[0])
+                    .await
+            }
+        }
+    }
+}
+
+/// Finds the address the SunSpec register map starts at.
+async fn find_base_address(client: &mut ModbusClient) -> eyre::Result<u16> {
+    for base_address in BASE_ADDRESSES {
+        // Devices return an exception for addresses they don't have, so that just means it's somewhere else.
+        if let Ok(marker) = read(client, base_address, 2).await {
+            if marker == MARKER {
+                return Ok(base_address);
+            }
+        }
+    }
+    bail!("The device isn't a SunSpec device: none of the addresses {BASE_ADDRESSES:?} start with \"SunS\"")
+}
+
+/// Reads the IDs, addresses and lengths of the models in the register map that starts at `base_address`.
+async fn read_models(client: &mut ModbusClient, base_address: u16) -> eyre::Result<Vec<Model>> {
+    if read(client, base_address, 2).await? != MARKER {
+        bail!("The register map at {base_address} doesn't start with \"SunS\"");
+    }
+    let mut models = Vec::new();
+    let mut address = base_address + 2;
+    while models.len() < MAX_MODELS {
+        let header = read(client, address, 2).await?;
+        if header[0] == END_MODEL {
+            return Ok(models);
+        }
+        models.push(Model {
+            id: header[0],
+            address: address + 2,
+        });
+        address = address
+            .checked_add(2 + header[1])
+            .ok_or_else(|| eyre!("The SunSpec register map doesn't end"))?;
+    }
+    bail!("The SunSpec register map doesn't end after {MAX_MODELS} models")
+}
+
+/// Reads a string of 16 registers, without the null characters it's padded with.
+async fn read_string(client: &mut ModbusClient, address: u16) -> eyre::Result<String> {
+    let registers = read(client, address, 16).await?;
+    let bytes: Vec<_> = registers
+        .iter()
+        .flat_map(|register| register.to_be_bytes())
+        .collect();
+    Ok(String::from_utf8_lossy(&bytes)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string())
+}
+
+async fn read(client: &mut ModbusClient, address: u16, count: u16) -> eyre::Result<Vec<u16>> {
+    client
+        .read_registers(RegisterKind::Holding, address, count)
+        .await
+}
+
+/// The value of an `int16` point, unless it isn't implemented.
+fn signed(register: u16) -> Option<f64> {
+    (register != NOT_IMPLEMENTED).then_some(register as i16 as f64)
+}
+
+/// The value of a `uint16` point, unless it isn't implemented.
+fn unsigned(register: u16) -> Option<f64> {
+    (register != UNSIGNED_NOT_IMPLEMENTED).then_some(register as f64)
+}
+
+/// A value times 10 to the power of its `sunssf` scale factor, unless either isn't implemented.
+fn scaled(value: Option<f64>, scale_factor: u16) -> Option<f64> {
+    if scale_factor == NOT_IMPLEMENTED {
+        return None;
+    }
+    Some(value? * 10f64.powi(scale_factor as i16 as i32))
+}
@@ -0,0 +1,585 @@
+use clap::{Args, Parser, Subcommand};
+use simulator_common::{telemetry, ConfigFile, Settings};
+use std::path::PathBuf;
+
+/// The formats of the Modbus registers of a real inverter.
+const REGISTER_FORMATS: [&str; 5] = ["u16", "i16", "u32", "i32", "f32"];
+
+/// Simulated S2 resource managers, to test your CEM with.
+///
+/// Every option can also be set through the environment variable shown with it, which is how the simulators are
+/// configured in docker-compose.yml, or in a configuration file. Command line options take precedence over
+/// environment variables, which take precedence over the configuration file.
+#[derive(Parser, Debug)]
+#[command(name = "s2-sim", version)]
+struct Cli {
+    /// A TOML or YAML file with settings, named like the environment variables (e.g. `cem_url = "ws://..."`).
+    #[arg(long, global = true, env = "CONFIG_PATH")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Simulate a home battery with a capacity of 20 kWh.
+    Battery(Box<BatteryArgs>),
+    /// Simulate a PV installation.
+    Pv(Box<PvArgs>),
+    /// Generate a PV production profile from typical meteorological year (TMY) data, and write it to stdout.
+    ///
+    /// The TMY data should be a CSV file with a `month,day,hour,dni,dhi` header. The generated profile can be used
+    /// with `s2-sim pv --profile-path`.
+    GenerateProfile(Box<GenerateProfileArgs>),
+}
+
+/// Options every simulator has.
+#[derive(Args, Debug)]
+struct CommonArgs {
+    /// The URL of the CEM; this should be a WebSocket endpoint.
+    #[arg(long, env = "CEM_URL")]
+    cem_url: Option<String>,
+    /// A token to authenticate with the CEM, which is sent in an `Authorization: Bearer` header.
+    #[arg(long, env = "CEM_TOKEN", hide_env_values = true)]
+    cem_token: Option<String>,
+    /// Send the token in this query parameter of the CEM URL instead of in a header.
+    #[arg(long, env = "CEM_TOKEN_QUERY_PARAMETER", requires = "cem_token")]
+    cem_token_query_parameter: Option<String>,
+    /// Instead of connecting to the CEM, wait for the CEM to connect on this address, e.g. 0.0.0.0:8080.
+    #[arg(long, env = "LISTEN_ADDRESS", conflicts_with = "cem_url")]
+    listen_address: Option<String>,
+    /// What the S2 messages go over: WEBSOCKET, or MQTT through the broker in --mqtt-broker [default: WEBSOCKET]
+    #[arg(long, env = "TRANSPORT")]
+    transport: Option<String>,
+    /// How often the simulator sends measurements or status updates, in seconds [default: 60]
+    #[arg(long, env = "UPDATE_INTERVAL")]
+    update_interval: Option<String>,
+    /// How often the simulator sends a new forecast, in seconds [default: 3600]
+    #[arg(long, env = "FORECAST_INTERVAL")]
+    forecast_interval: Option<String>,
+    /// Send every forecast up to this many seconds earlier or later than the forecast interval, picked at random
+    /// [default: 0]
+    #[arg(long, env = "JITTER")]
+    jitter: Option<String>,
+    /// The seed for random behaviour, such as the clouds and the jitter, to repeat an earlier run [default: a new seed
+    /// every run]
+    #[arg(long, env = "SEED")]
+    seed: Option<String>,
+    /// How much faster than real time the simulation runs, e.g. 60 to simulate an hour every minute [default: 1]
+    #[arg(long, env = "TIME_SCALE")]
+    time_scale: Option<String>,
+    /// A YAML file with events that happen during the simulation, such as outages; see timeline-example.yaml.
+    #[arg(long, env = "TIMELINE_PATH")]
+    timeline: Option<String>,
+    /// Record every message that is sent or received to a new JSON Lines file in this directory.
+    #[arg(long, env = "RECORDING_DIRECTORY")]
+    recording_directory: Option<String>,
+    /// Also store every message that is sent or received in this SQLite database.
+    #[arg(long, env = "ARCHIVE_PATH")]
+    archive_path: Option<String>,
+    /// Write the state of the device to a new CSV file in this directory at every update.
+    #[arg(long, env = "CSV_DIRECTORY")]
+    csv_directory: Option<String>,
+    /// How long the CEM has to acknowledge a message with a reception status, in seconds [default: 30]
+    #[arg(long, env = "RECEPTION_STATUS_TIMEOUT")]
+    reception_status_timeout: Option<String>,
+    /// How often to send a message again when the CEM doesn't acknowledge it in time [default: 0]
+    #[arg(long, env = "RETRANSMISSIONS")]
+    retransmissions: Option<String>,
+    /// How long to wait before reconnecting when the connection with the CEM is lost, in seconds [default: 5]
+    #[arg(long, env = "RECONNECT_DELAY")]
+    reconnect_delay: Option<String>,
+    /// How long the CEM may stay silent before the connection is considered dead, in seconds; 0 turns this off [default: 30]
+    #[arg(long, env = "KEEPALIVE_TIMEOUT")]
+    keepalive_timeout: Option<String>,
+    /// Announce this S2 version in the handshake instead of the one the simulator speaks, e.g. 0.0.1-beta.
+    #[arg(long, env = "S2_VERSION")]
+    s2_version: Option<String>,
+    /// Export traces to this OpenTelemetry collector over OTLP/HTTP, e.g. http://localhost:4318.
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// The service name of the exported traces [default: battery or pv-installation]
+    #[arg(long, env = "OTEL_SERVICE_NAME")]
+    service_name: Option<String>,
+    /// Serve the dashboard (/), health endpoints (/health and /ready) and endpoints to inject events on this address,
+    /// e.g. 0.0.0.0:8081.
+    #[arg(long, env = "HTTP_ADDRESS")]
+    http_address: Option<String>,
+    /// Show a live view of the simulator in the terminal instead of the log; press q to stop.
+    #[arg(long, env = "TUI")]
+    tui: bool,
+    /// Read commands from stdin that change the simulation, such as `soc 0.3` or `cloud 0.5`; type help for a list.
+    #[arg(long, env = "COMMANDS")]
+    commands: bool,
+    /// Only set up a session to check that the CEM is reachable and accepts the initial messages, print a summary and
+    /// exit.
+    #[arg(long, env = "VALIDATE_ONLY")]
+    validate_only: bool,
+    /// Also write the log to this file, which is rotated according to the other log options.
+    #[arg(long, env = "LOG_PATH")]
+    log_path: Option<String>,
+    /// When to start a new log file: DAILY, HOURLY or NEVER [default: DAILY]
+    #[arg(long, env = "LOG_ROTATION", requires = "log_path")]
+    log_rotation: Option<String>,
+    /// Start a new log file when it grows beyond this many MB; 0 for no limit [default: 10]
+    #[arg(long, env = "LOG_MAX_SIZE_MB", requires = "log_path")]
+    log_max_size_mb: Option<String>,
+    /// How many rotated log files to keep [default: 7]
+    #[arg(long, env = "LOG_MAX_FILES", requires = "log_path")]
+    log_max_files: Option<String>,
+    /// Publish the state of the device, measurements and instructions to this MQTT broker, e.g. localhost:1883.
+    #[arg(long, env = "MQTT_BROKER")]
+    mqtt_broker: Option<String>,
+    /// The user name to log in to the MQTT broker with.
+    #[arg(long, env = "MQTT_USERNAME", requires = "mqtt_broker")]
+    mqtt_username: Option<String>,
+    /// The password to log in to the MQTT broker with.
+    #[arg(
+        long,
+        env = "MQTT_PASSWORD",
+        hide_env_values = true,
+        requires = "mqtt_username"
+    )]
+    mqtt_password: Option<String>,
+    /// The prefix of the MQTT topics [default: s2-simulator]
+    #[arg(long, env = "MQTT_TOPIC_PREFIX", requires = "mqtt_broker")]
+    mqtt_topic_prefix: Option<String>,
+    /// Announce the device to Home Assistant through MQTT discovery under this prefix, usually homeassistant.
+    #[arg(long, env = "MQTT_DISCOVERY_PREFIX", requires = "mqtt_broker")]
+    mqtt_discovery_prefix: Option<String>,
+    /// Write the state of the device, measurements and instructions to this InfluxDB, e.g. http://localhost:8086.
+    #[arg(long, env = "INFLUX_URL", requires = "influx_bucket")]
+    influx_url: Option<String>,
+    /// The bucket to write to; for InfluxDB 1.8, database/retention-policy.
+    #[arg(long, env = "INFLUX_BUCKET", requires = "influx_url")]
+    influx_bucket: Option<String>,
+    /// The organization the bucket belongs to.
+    #[arg(long, env = "INFLUX_ORG", requires = "influx_url")]
+    influx_org: Option<String>,
+    /// The API token to write with.
+    #[arg(
+        long,
+        env = "INFLUX_TOKEN",
+        hide_env_values = true,
+        requires = "influx_url"
+    )]
+    influx_token: Option<String>,
+    /// The value of the source tag of the points, to tell the simulators apart [default: s2-simulator]
+    #[arg(long, env = "INFLUX_SOURCE", requires = "influx_url")]
+    influx_source: Option<String>,
+    /// Wait for a mosaik co-simulation to connect on this address, e.g. 0.0.0.0:5678, which then moves simulated time on.
+    #[arg(long, env = "MOSAIK_ADDRESS")]
+    mosaik_address: Option<String>,
+    /// The time at which simulated time starts in the co-simulation, e.g. 2025-06-01T00:00:00Z [default: now]
+    #[arg(long, env = "MOSAIK_START", requires = "mosaik_address")]
+    mosaik_start: Option<String>,
+    /// Wait this many milliseconds of real time after every step, for the CEM to respond [default: 0]
+    #[arg(long, env = "MOSAIK_STEP_DELAY_MS", requires = "mosaik_address")]
+    mosaik_step_delay_ms: Option<String>,
+    /// Measure the power with a WiFi power meter instead of simulating it: SHELLY or TASMOTA.
+    #[arg(long, env = "POWER_METER", ignore_case = true, value_parser = ["shelly", "tasmota"])]
+    power_meter: Option<String>,
+    /// Poll the power meter at this URL, e.g. http://192.168.1.60.
+    #[arg(long, env = "POWER_METER_URL", requires = "power_meter")]
+    power_meter_url: Option<String>,
+    /// Or take the readings of the power meter from this topic on the broker in --mqtt-broker.
+    #[arg(
+        long,
+        env = "POWER_METER_TOPIC",
+        requires_all = ["power_meter", "mqtt_broker"],
+        conflicts_with = "power_meter_url"
+    )]
+    power_meter_topic: Option<String>,
+    /// How often the power meter is polled, or publishes, in seconds [default: 10]
+    #[arg(long, env = "POWER_METER_INTERVAL", requires = "power_meter")]
+    power_meter_interval: Option<String>,
+    /// What the readings of the power meter are multiplied by, e.g. -1 if it measures production as positive
+    /// [default: 1]
+    #[arg(
+        long,
+        env = "POWER_METER_SCALE",
+        requires = "power_meter",
+        allow_hyphen_values = true
+    )]
+    power_meter_scale: Option<String>,
+}
+
+impl Settings for CommonArgs {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "CEM_URL" => self.cem_url.clone(),
+            "CEM_TOKEN" => self.cem_token.clone(),
+            "CEM_TOKEN_QUERY_PARAMETER" => self.cem_token_query_parameter.clone(),
+            "LISTEN_ADDRESS" => self.listen_address.clone(),
+            "TRANSPORT" => self.transport.clone(),
+            "UPDATE_INTERVAL" => self.update_interval.clone(),
+            "FORECAST_INTERVAL" => self.forecast_interval.clone(),
+            "JITTER" => self.jitter.clone(),
+            "SEED" => self.seed.clone(),
+            "TIME_SCALE" => self.time_scale.clone(),
+            "TIMELINE_PATH" => self.timeline.clone(),
+            "RECORDING_DIRECTORY" => self.recording_directory.clone(),
+            "ARCHIVE_PATH" => self.archive_path.clone(),
+            "CSV_DIRECTORY" => self.csv_directory.clone(),
+            "RECEPTION_STATUS_TIMEOUT" => self.reception_status_timeout.clone(),
+            "RETRANSMISSIONS" => self.retransmissions.clone(),
+            "RECONNECT_DELAY" => self.reconnect_delay.clone(),
+            "KEEPALIVE_TIMEOUT" => self.keepalive_timeout.clone(),
+            "S2_VERSION" => self.s2_version.clone(),
+            "OTEL_EXPORTER_OTLP_ENDPOINT" => self.otlp_endpoint.clone(),
+            "OTEL_SERVICE_NAME" => self.service_name.clone(),
+            "HTTP_ADDRESS" => self.http_address.clone(),
+            "TUI" => self.tui.then(|| "true".to_string()),
+            "COMMANDS" => self.commands.then(|| "true".to_string()),
+            "VALIDATE_ONLY" => self.validate_only.then(|| "true".to_string()),
+            "LOG_PATH" => self.log_path.clone(),
+            "LOG_ROTATION" => self.log_rotation.clone(),
+            "LOG_MAX_SIZE_MB" => self.log_max_size_mb.clone(),
+            "LOG_MAX_FILES" => self.log_max_files.clone(),
+            "MQTT_BROKER" => self.mqtt_broker.clone(),
+            "MQTT_USERNAME" => self.mqtt_username.clone(),
+            "MQTT_PASSWORD" => self.mqtt_password.clone(),
+            "MQTT_TOPIC_PREFIX" => self.mqtt_topic_prefix.clone(),
+            "MQTT_DISCOVERY_PREFIX" => self.mqtt_discovery_prefix.clone(),
+            "INFLUX_URL" => self.influx_url.clone(),
+            "INFLUX_BUCKET" => self.influx_bucket.clone(),
+            "INFLUX_ORG" => self.influx_org.clone(),
+            "INFLUX_TOKEN" => self.influx_token.clone(),
+            "INFLUX_SOURCE" => self.influx_source.clone(),
+            "MOSAIK_ADDRESS" => self.mosaik_address.clone(),
+            "MOSAIK_START" => self.mosaik_start.clone(),
+            "MOSAIK_STEP_DELAY_MS" => self.mosaik_step_delay_ms.clone(),
+            "POWER_METER" => self.power_meter.as_ref().map(|meter| meter.to_uppercase()),
+            "POWER_METER_URL" => self.power_meter_url.clone(),
+            "POWER_METER_TOPIC" => self.power_meter_topic.clone(),
+            "POWER_METER_INTERVAL" => self.power_meter_interval.clone(),
+            "POWER_METER_SCALE" => self.power_meter_scale.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct BatteryArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    /// The control type to offer to the CEM.
+    #[arg(long, env = "CONTROL_TYPE", ignore_case = true, value_parser = ["frbc"])]
+    control_type: Option<String>,
+    /// Simulate the failure of one of the battery modules after this many seconds.
+    #[arg(long, env = "MODULE_FAILURE_AFTER")]
+    module_failure_after: Option<String>,
+    /// The currency (ISO 4217) of the running costs of the operation modes [default: EUR]
+    #[arg(long, env = "CURRENCY")]
+    currency: Option<String>,
+    /// The wear costs per kWh that is charged or discharged, used for the running costs [default: 0.03]
+    #[arg(long, env = "WEAR_COST_PER_KWH")]
+    wear_cost_per_kwh: Option<String>,
+    /// Simulate this many batteries, each with its own connection with the CEM [default: 1]
+    #[arg(long, env = "INSTANCES")]
+    instances: Option<String>,
+    /// The capacity of the battery, in Wh [default: 20000]
+    #[arg(long, env = "BATTERY_CAPACITY_WH")]
+    battery_capacity_wh: Option<String>,
+    /// The maximum power the battery charges or discharges with, in W [default: 5000]
+    #[arg(long, env = "BATTERY_MAX_POWER_W")]
+    battery_max_power_w: Option<String>,
+    /// Drive a real battery through its local API instead of simulating one: SIMULATION, SONNEN for a sonnenBatterie,
+    /// HTTP for a JSON API with the --battery-*-path and pointer options, or VICTRON for a Victron GX device in ESS
+    /// mode 3 [default: SIMULATION]
+    #[arg(
+        long,
+        env = "BATTERY_BACKEND",
+        ignore_case = true,
+        value_parser = ["simulation", "http", "sonnen", "victron"]
+    )]
+    battery_backend: Option<String>,
+    /// The URL of the API of the battery, e.g. http://192.168.1.30.
+    #[arg(long, env = "BATTERY_API_URL")]
+    battery_api_url: Option<String>,
+    /// The token to authenticate with the API of the battery; a sonnenBatterie needs one.
+    #[arg(long, env = "BATTERY_API_TOKEN", hide_env_values = true)]
+    battery_api_token: Option<String>,
+    /// How often the battery is read, in seconds [default: 5]
+    #[arg(long, env = "BATTERY_POLL_INTERVAL")]
+    battery_poll_interval: Option<String>,
+    /// HTTP only: the path of the status of the battery [default: /status]
+    #[arg(long, env = "BATTERY_STATUS_PATH")]
+    battery_status_path: Option<String>,
+    /// HTTP only: the path to post the power setpoint to [default: /setpoint]
+    #[arg(long, env = "BATTERY_SETPOINT_PATH")]
+    battery_setpoint_path: Option<String>,
+    /// HTTP only: the JSON pointer to the state of charge in the status [default: /state_of_charge]
+    #[arg(long, env = "BATTERY_SOC_POINTER")]
+    battery_soc_pointer: Option<String>,
+    /// HTTP only: what the state of charge is multiplied by to get a fraction, e.g. 0.01 for a percentage [default: 1]
+    #[arg(long, env = "BATTERY_SOC_SCALE")]
+    battery_soc_scale: Option<String>,
+    /// HTTP only: the JSON pointer to the power in the status, positive when charging [default: /power_w]
+    #[arg(long, env = "BATTERY_POWER_POINTER")]
+    battery_power_pointer: Option<String>,
+    /// HTTP only: what the power is multiplied by to get W, e.g. -1 if it's positive when discharging [default: 1]
+    #[arg(long, env = "BATTERY_POWER_SCALE", allow_hyphen_values = true)]
+    battery_power_scale: Option<String>,
+    /// VICTRON only: the MQTT broker of the GX device, e.g. 192.168.1.40:1883.
+    #[arg(long, env = "VICTRON_BROKER")]
+    victron_broker: Option<String>,
+    /// VICTRON only: the portal ID of the GX device [default: found automatically]
+    #[arg(long, env = "VICTRON_PORTAL_ID")]
+    victron_portal_id: Option<String>,
+    /// VICTRON only: the VE.Bus instance of the inverter/charger [default: found automatically]
+    #[arg(long, env = "VICTRON_VEBUS_INSTANCE")]
+    victron_vebus_instance: Option<String>,
+}
+
+impl Settings for BatteryArgs {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "CONTROL_TYPE" => self.control_type.clone(),
+            "MODULE_FAILURE_AFTER" => self.module_failure_after.clone(),
+            "CURRENCY" => self.currency.clone(),
+            "WEAR_COST_PER_KWH" => self.wear_cost_per_kwh.clone(),
+            "INSTANCES" => self.instances.clone(),
+            "BATTERY_CAPACITY_WH" => self.battery_capacity_wh.clone(),
+            "BATTERY_MAX_POWER_W" => self.battery_max_power_w.clone(),
+            "BATTERY_BACKEND" => self
+                .battery_backend
+                .as_ref()
+                .map(|backend| backend.to_uppercase()),
+            "BATTERY_API_URL" => self.battery_api_url.clone(),
+            "BATTERY_API_TOKEN" => self.battery_api_token.clone(),
+            "BATTERY_POLL_INTERVAL" => self.battery_poll_interval.clone(),
+            "BATTERY_STATUS_PATH" => self.battery_status_path.clone(),
+            "BATTERY_SETPOINT_PATH" => self.battery_setpoint_path.clone(),
+            "BATTERY_SOC_POINTER" => self.battery_soc_pointer.clone(),
+            "BATTERY_SOC_SCALE" => self.battery_soc_scale.clone(),
+            "BATTERY_POWER_POINTER" => self.battery_power_pointer.clone(),
+            "BATTERY_POWER_SCALE" => self.battery_power_scale.clone(),
+            "VICTRON_BROKER" => self.victron_broker.clone(),
+            "VICTRON_PORTAL_ID" => self.victron_portal_id.clone(),
+            "VICTRON_VEBUS_INSTANCE" => self.victron_vebus_instance.clone(),
+            name => self.common.get(name),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct PvArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    /// The control type to offer to the CEM: curtailable (PEBC), curtailable in steps (OMBC) or not controllable.
+    #[arg(long, env = "CONTROL_TYPE", ignore_case = true, value_parser = ["pebc", "ombc", "not_controlable"])]
+    control_type: Option<String>,
+    #[command(flatten)]
+    installation: PvInstallationArgs,
+    /// How the installation is connected to the grid: SINGLE (L1), THREE (per phase) or THREE_SYMMETRIC [default: SINGLE]
+    #[arg(long, env = "PHASES")]
+    phases: Option<String>,
+    /// Extra commodity quantities to report with every measurement, comma-separated (e.g. HEAT.TEMPERATURE).
+    #[arg(long, env = "ADDITIONAL_MEASUREMENTS")]
+    additional_measurements: Option<String>,
+    /// A CSV file with events (passing clouds, inverter trips, curtailment) to inject into the simulation.
+    #[arg(long, env = "SCENARIO_PATH")]
+    scenario_path: Option<String>,
+    /// The moment in simulated time at which the simulation starts, as an RFC 3339 timestamp or NOW
+    /// [default: 2030-01-01T12:00:00Z]
+    #[arg(long, env = "SIMULATION_START")]
+    simulation_start: Option<String>,
+    /// Where forecasts come from: MODEL (perfect foresight) or OPEN_METEO [default: MODEL]
+    #[arg(long, env = "FORECAST_SOURCE")]
+    forecast_source: Option<String>,
+    /// The standard deviation of the forecast error for the first hour, as a fraction of the expected value.
+    #[arg(long, env = "FORECAST_UNCERTAINTY")]
+    forecast_uncertainty: Option<String>,
+    /// PEBC only: the maximum amount of energy (Wh) that may be curtailed per day.
+    #[arg(long, env = "MAX_CURTAILED_ENERGY_WH")]
+    max_curtailed_energy_wh: Option<String>,
+    /// PEBC only: what happens to curtailed energy, VANISH or DEFER (it's produced later) [default: VANISH]
+    #[arg(long, env = "CONSEQUENCE_TYPE")]
+    consequence_type: Option<String>,
+    /// PEBC only: how long power constraints are valid, in seconds [default: 3600]
+    #[arg(long, env = "POWER_CONSTRAINTS_VALIDITY")]
+    power_constraints_validity: Option<String>,
+    /// PEBC only: measure and curtail a real inverter over Modbus TCP instead of simulating production: SIMULATION,
+    /// MODBUS with the --modbus-* registers, or SUNSPEC for inverters with SunSpec models [default: SIMULATION]
+    #[arg(long, env = "PV_BACKEND", ignore_case = true, value_parser = ["simulation", "modbus", "sunspec"])]
+    pv_backend: Option<String>,
+    /// The address of the inverter, e.g. 192.168.1.50:502.
+    #[arg(long, env = "MODBUS_ADDRESS")]
+    modbus_address: Option<String>,
+    /// The Modbus unit ID of the inverter [default: 1]
+    #[arg(long, env = "MODBUS_UNIT_ID")]
+    modbus_unit_id: Option<String>,
+    /// SUNSPEC only: the register where the SunSpec models start [default: 40000, 0 and 50000 are tried]
+    #[arg(long, env = "SUNSPEC_BASE_ADDRESS")]
+    sunspec_base_address: Option<String>,
+    /// MODBUS only: the register with the AC power, which is its value times --modbus-power-scale in W.
+    #[arg(long, env = "MODBUS_POWER_REGISTER")]
+    modbus_power_register: Option<String>,
+    /// The type of the power register: HOLDING or INPUT [default: HOLDING]
+    #[arg(long, env = "MODBUS_POWER_REGISTER_TYPE", ignore_case = true, value_parser = ["holding", "input"])]
+    modbus_power_register_type: Option<String>,
+    /// The format of the power register: U16, I16, U32, I32 or F32 [default: I16]
+    #[arg(long, env = "MODBUS_POWER_FORMAT", ignore_case = true, value_parser = REGISTER_FORMATS)]
+    modbus_power_format: Option<String>,
+    /// What the value of the power register is multiplied by to get W, e.g. -1 if it's negative for production
+    /// [default: 1]
+    #[arg(long, env = "MODBUS_POWER_SCALE", allow_hyphen_values = true)]
+    modbus_power_scale: Option<String>,
+    /// MODBUS only: the holding register that limits the AC power, to follow power envelopes.
+    #[arg(long, env = "MODBUS_LIMIT_REGISTER")]
+    modbus_limit_register: Option<String>,
+    /// The format of the limit register: U16, I16, U32, I32 or F32 [default: U16]
+    #[arg(long, env = "MODBUS_LIMIT_FORMAT", ignore_case = true, value_parser = REGISTER_FORMATS)]
+    modbus_limit_format: Option<String>,
+    /// What the value of the limit register is multiplied by to get W [default: 1]
+    #[arg(long, env = "MODBUS_LIMIT_SCALE", allow_hyphen_values = true)]
+    modbus_limit_scale: Option<String>,
+    /// How often the inverter is read, in seconds [default: 5]
+    #[arg(long, env = "MODBUS_POLL_INTERVAL")]
+    modbus_poll_interval: Option<String>,
+}
+
+/// Options that describe the PV installation itself, which are also used to generate profiles.
+#[derive(Args, Debug)]
+struct PvInstallationArgs {
+    /// How production is determined: PROFILE, SYNTHETIC, PHYSICAL or STRINGS [default: PROFILE]
+    #[arg(long, env = "PV_MODEL")]
+    model: Option<String>,
+    /// PROFILE only: use your own profile instead of the built-in solar.csv.
+    #[arg(long, env = "PV_PROFILE_PATH")]
+    profile_path: Option<String>,
+    /// STRINGS only: the strings, as name:peak_power_w:tilt:azimuth or name:peak_power_w:profile_path, comma-separated.
+    #[arg(long, env = "PV_STRINGS")]
+    strings: Option<String>,
+    /// The peak power of the installation, in W [default: 2000]
+    #[arg(long, env = "PEAK_POWER_W")]
+    peak_power_w: Option<String>,
+    /// The maximum AC output of the inverter, in W; production above it is clipped [default: the peak power]
+    #[arg(long, env = "INVERTER_AC_LIMIT_W")]
+    inverter_ac_limit_w: Option<String>,
+    /// The inverter temperature (°C) above which the inverter limits its output.
+    #[arg(long, env = "INVERTER_DERATING_TEMPERATURE")]
+    inverter_derating_temperature: Option<String>,
+    /// The fraction of production that remains in every month due to soiling and snow, 12 comma-separated factors.
+    #[arg(long, env = "MONTHLY_DERATING")]
+    monthly_derating: Option<String>,
+    /// The latitude of the installation, in degrees [default: 52.1]
+    #[arg(long, env = "LATITUDE", allow_hyphen_values = true)]
+    latitude: Option<String>,
+    /// The longitude of the installation, in degrees [default: 5.2]
+    #[arg(long, env = "LONGITUDE", allow_hyphen_values = true)]
+    longitude: Option<String>,
+    /// The tilt of the panels, in degrees [default: 35]
+    #[arg(long, env = "PANEL_TILT")]
+    panel_tilt: Option<String>,
+    /// The azimuth of the panels, in degrees (180 is south) [default: 180]
+    #[arg(long, env = "PANEL_AZIMUTH")]
+    panel_azimuth: Option<String>,
+}
+
+impl Settings for PvArgs {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "CONTROL_TYPE" => self.control_type.clone(),
+            "PHASES" => self.phases.clone(),
+            "ADDITIONAL_MEASUREMENTS" => self.additional_measurements.clone(),
+            "SCENARIO_PATH" => self.scenario_path.clone(),
+            "SIMULATION_START" => self.simulation_start.clone(),
+            "FORECAST_SOURCE" => self.forecast_source.clone(),
+            "FORECAST_UNCERTAINTY" => self.forecast_uncertainty.clone(),
+            "MAX_CURTAILED_ENERGY_WH" => self.max_curtailed_energy_wh.clone(),
+            "CONSEQUENCE_TYPE" => self.consequence_type.clone(),
+            "POWER_CONSTRAINTS_VALIDITY" => self.power_constraints_validity.clone(),
+            "PV_BACKEND" => self
+                .pv_backend
+                .as_ref()
+                .map(|backend| backend.to_uppercase()),
+            "MODBUS_ADDRESS" => self.modbus_address.clone(),
+            "MODBUS_UNIT_ID" => self.modbus_unit_id.clone(),
+            "SUNSPEC_BASE_ADDRESS" => self.sunspec_base_address.clone(),
+            "MODBUS_POWER_REGISTER" => self.modbus_power_register.clone(),
+            "MODBUS_POWER_REGISTER_TYPE" => self
+                .modbus_power_register_type
+                .as_ref()
+                .map(|register_type| register_type.to_uppercase()),
+            "MODBUS_POWER_FORMAT" => self
+                .modbus_power_format
+                .as_ref()
+                .map(|format| format.to_uppercase()),
+            "MODBUS_POWER_SCALE" => self.modbus_power_scale.clone(),
+            "MODBUS_LIMIT_REGISTER" => self.modbus_limit_register.clone(),
+            "MODBUS_LIMIT_FORMAT" => self
+                .modbus_limit_format
+                .as_ref()
+                .map(|format| format.to_uppercase()),
+            "MODBUS_LIMIT_SCALE" => self.modbus_limit_scale.clone(),
+            "MODBUS_POLL_INTERVAL" => self.modbus_poll_interval.clone(),
+            name => self
+                .installation
+                .get(name)
+                .or_else(|| self.common.get(name)),
+        }
+    }
+}
+
+impl Settings for PvInstallationArgs {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "PV_MODEL" => self.model.clone(),
+            "PV_PROFILE_PATH" => self.profile_path.clone(),
+            "PV_STRINGS" => self.strings.clone(),
+            "PEAK_POWER_W" => self.peak_power_w.clone(),
+            "INVERTER_AC_LIMIT_W" => self.inverter_ac_limit_w.clone(),
+            "INVERTER_DERATING_TEMPERATURE" => self.inverter_derating_temperature.clone(),
+            "MONTHLY_DERATING" => self.monthly_derating.clone(),
+            "LATITUDE" => self.latitude.clone(),
+            "LONGITUDE" => self.longitude.clone(),
+            "PANEL_TILT" => self.panel_tilt.clone(),
+            "PANEL_AZIMUTH" => self.panel_azimuth.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct GenerateProfileArgs {
+    /// The CSV file with TMY data.
+    tmy: PathBuf,
+    /// The year the timestamps in the profile are in.
+    #[arg(default_value_t = 2030)]
+    year: i32,
+    #[command(flatten)]
+    installation: PvInstallationArgs,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let cli = Cli::parse();
+    let config_file = match &cli.config {
+        Some(path) => ConfigFile::from_path(path)?,
+        None => ConfigFile::default(),
+    };
+
+    match cli.command {
+        Command::Battery(args) => {
+            let settings = (*args).or(config_file);
+            let _telemetry = telemetry::init(&settings, "battery")?;
+            battery::run(&settings).await
+        }
+        Command::Pv(args) => {
+            let settings = (*args).or(config_file);
+            let _telemetry = telemetry::init(&settings, "pv-installation")?;
+            pv_installation::run(&settings).await
+        }
+        // The profile is written to stdout, so don't log anything.
+        Command::GenerateProfile(args) => {
+            let GenerateProfileArgs {
+                tmy,
+                year,
+                installation,
+            } = *args;
+            pv_installation::generate_profile(tmy, year, &installation.or(config_file))
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::connection::Direction;
+use chrono::Utc;
+use eyre::Context;
+use rusqlite::Connection as Database;
+use s2energy::common::Id;
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait for other simulators that write to the same archive, such as the other instances of a battery.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An SQLite database that stores every message that is sent or received, to analyse a run afterwards with SQL.
+///
+/// Every session with the CEM gets a row in `sessions`, and its messages are stored in `messages`, with the message
+/// itself as JSON so SQLite's JSON functions can look inside it. Several simulators can share the same archive.
+pub(crate) struct Archive {
+    database: Database,
+    /// The ID of the current session, which links its messages together.
+    session_id: String,
+}
+
+impl Archive {
+    /// Opens the archive at the given path, creating it if it doesn't exist yet, and starts a session in it.
+    pub(crate) fn open(path: &Path) -> eyre::Result<Self> {
+        let database = Database::open(path)
+            .wrap_err_with(|| format!("Could not open archive {}", path.display()))?;
+        database.busy_timeout(BUSY_TIMEOUT)?;
+        database
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS sessions (
+                    id TEXT PRIMARY KEY,
+                    started_at TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY,
+                    session_id TEXT NOT NULL REFERENCES sessions (id),
+                    timestamp TEXT NOT NULL,
+                    direction TEXT NOT NULL,
+                    message_type TEXT,
+                    message_id TEXT,
+                    message TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS messages_by_session ON messages (session_id, timestamp);",
+            )
+            .wrap_err_with(|| format!("Could not set up archive {}", path.display()))?;
+        let mut archive = Self {
+            database,
+            session_id: String::new(),
+        };
+        archive.start_session()?;
+        tracing::info!("Archiving all S2 messages to {}", path.display());
+        Ok(archive)
+    }
+
+    /// Starts a new session, to which the messages that follow belong.
+    pub(crate) fn start_session(&mut self) -> eyre::Result<()> {
+        self.session_id = Id::generate().to_string();
+        self.database
+            .execute(
+                "INSERT INTO sessions (id, started_at) VALUES (?1, ?2)",
+                (&self.session_id, Utc::now().to_rfc3339()),
+            )
+            .wrap_err("Could not write to the archive")?;
+        Ok(())
+    }
+
+    /// Stores a message that was sent to or received from the CEM.
+    pub(crate) fn store(&self, direction: Direction, message: &Value) -> eyre::Result<()> {
+        // Like the recordings, this is about what happened on the wire, so it uses real time.
+        self.database
+            .execute(
+                "INSERT INTO messages (session_id, timestamp, direction, message_type, message_id, message)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                (
+                    &self.session_id,
+                    Utc::now().to_rfc3339(),
+                    direction.as_str(),
+                    message["message_type"].as_str(),
+                    message["message_id"].as_str(),
+                    message.to_string(),
+                ),
+            )
+            .wrap_err("Could not write to the archive")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use crate::connection::{self, Receipt};
+use crate::validation::Validator;
+use crate::Connection;
+use eyre::eyre;
+use s2energy::common::{
+    ControlType, Id, ReceptionStatusValues, SessionRequest, SessionRequestType,
+};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often to look whether the CEM acknowledged all messages.
+const ACKNOWLEDGEMENT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Finishes checking the CEM, once the session is set up: waits until the CEM acknowledged every message that was sent,
+/// or until the reception status timeout passed, prints a summary of what the CEM answered and ends the session.
+///
+/// Messages the CEM sends in the meantime are checked and acknowledged, but not passed on to the simulator. The result
+/// is an error if the CEM rejected or ignored any of the messages, or sent an invalid one itself.
+pub(crate) async fn finish(
+    mut connection: Connection,
+    mut validator: Validator,
+    control_type: ControlType,
+) -> eyre::Result<()> {
+    let timeout = connection.reception_status_timeout();
+    let deadline = Instant::now() + timeout;
+    let mut received = Vec::new();
+    while !all_acknowledged(connection.receipts().unwrap_or_default()) && Instant::now() < deadline
+    {
+        // Reception statuses are handled while waiting for a message, so look at the receipts every now and then.
+        tokio::select! {
+            message = connection.receive() => {
+                let message = message?;
+                let result = validator.validate(&message);
+                let problem = result.as_ref().err().map(ToString::to_string);
+                received.push((connection::message_type(&message), problem));
+                connection.acknowledge(&message, result).await?;
+            }
+            _ = tokio::time::sleep(ACKNOWLEDGEMENT_CHECK_INTERVAL) => {}
+        }
+    }
+
+    let receipts = connection.receipts().unwrap_or_default();
+    println!("Checked the CEM; it selected control type {control_type:?}.");
+    println!("Messages sent to the CEM:");
+    let mut problems = 0;
+    for receipt in receipts {
+        let answer = match &receipt.status {
+            Some(status) if status.status == ReceptionStatusValues::Ok => "OK".to_string(),
+            Some(status) => {
+                problems += 1;
+                format!(
+                    "{:?}: {}",
+                    status.status,
+                    status.diagnostic_label.as_deref().unwrap_or("no details")
+                )
+            }
+            None => {
+                problems += 1;
+                format!("no reception status within {timeout:?}")
+            }
+        };
+        println!("  {:<32} {answer}", receipt.message_type);
+    }
+    if !received.is_empty() {
+        println!("Messages received from the CEM after the handshake:");
+    }
+    for (message_type, problem) in &received {
+        if problem.is_some() {
+            problems += 1;
+        }
+        println!(
+            "  {message_type:<32} {}",
+            problem.as_deref().unwrap_or("valid")
+        );
+    }
+
+    let terminate = SessionRequest {
+        diagnostic_label: Some("Session terminated by the simulator (the check is done)".into()),
+        message_id: Id::generate(),
+        request: SessionRequestType::Terminate,
+    };
+    connection.send_message(terminate).await?;
+    connection.close().await;
+    match problems {
+        0 => {
+            println!("The CEM is reachable and compatible with this simulator.");
+            Ok(())
+        }
+        1 => Err(eyre!("Found a problem while checking the CEM")),
+        problems => Err(eyre!("Found {problems} problems while checking the CEM")),
+    }
+}
+
+fn all_acknowledged(receipts: &[Receipt]) -> bool {
+    receipts.iter().all(|receipt| receipt.status.is_some())
+}
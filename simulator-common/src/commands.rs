@@ -0,0 +1,109 @@
+use crate::monitor::Monitor;
+use crate::TimelineEvent;
+use serde_json::{Map, Value};
+use std::io::BufRead;
+use std::sync::Arc;
+
+/// How long an event lasts when a command leaves out its duration.
+const DEFAULT_DURATION: &str = "15m";
+
+/// The commands, with the event they inject and the fields of that event, in the order they're given.
+const COMMANDS: [(&str, &str, &[&str]); 7] = [
+    ("soc", "state_of_charge", &["value"]),
+    ("capacity", "capacity", &["value"]),
+    ("outage", "outage", &["duration"]),
+    ("spike", "demand_spike", &["power_w", "duration"]),
+    ("irradiance", "irradiance", &["w_per_m2", "duration"]),
+    ("cloud", "cloud", &["transmittance", "duration"]),
+    ("disconnect", "disconnect", &[]),
+];
+
+/// Reads commands from stdin and injects the events they stand for, so a presenter can change the simulation live,
+/// like with the endpoints of the HTTP server.
+///
+/// Every line is a command followed by the fields of its event, such as `soc 0.3`, `cloud 0.5 10m` or
+/// `spike 2000 5m`. A duration can be left out, in which case the event lasts 15 minutes. `help` lists the commands.
+pub(crate) fn spawn(monitor: Arc<Monitor>) {
+    // A thread of its own rather than tokio's stdin, which would keep the runtime from shutting down while it waits for
+    // a line.
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            match line.trim() {
+                "" => {}
+                "help" => tracing::info!("Commands: {}", usage()),
+                line => match parse(line) {
+                    Ok(event) => inject(&monitor, event),
+                    Err(problem) => tracing::warn!("{problem}"),
+                },
+            }
+        }
+    });
+}
+
+fn parse(line: &str) -> Result<TimelineEvent, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let arguments: Vec<&str> = words.collect();
+    let Some((_, name, fields)) = COMMANDS.iter().find(|(name, ..)| *name == command) else {
+        return Err(format!(
+            "Unknown command {line:?}; the commands are {}",
+            usage()
+        ));
+    };
+    let missing_duration =
+        arguments.len() + 1 == fields.len() && fields.last() == Some(&"duration");
+    if arguments.len() != fields.len() && !missing_duration {
+        return Err(format!("Usage: {}", synopsis(command, fields)));
+    }
+
+    let mut event = Map::new();
+    event.insert("event".into(), (*name).into());
+    let values = arguments.iter().copied().chain(Some(DEFAULT_DURATION));
+    for (field, value) in fields.iter().zip(values) {
+        // Durations can be a number of seconds or a text like 15m, like in a timeline.
+        let value = match value.parse::<f64>() {
+            Ok(number) => Value::from(number),
+            Err(_) => Value::from(value),
+        };
+        event.insert((*field).into(), value);
+    }
+    let event: TimelineEvent = serde_json::from_value(Value::Object(event))
+        .map_err(|error| format!("Invalid command {line:?}: {error}"))?;
+    event
+        .validate()
+        .map_err(|problem| format!("Invalid command {line:?}: {problem}"))?;
+    Ok(event)
+}
+
+/// Lists all commands with their arguments.
+fn usage() -> String {
+    let commands: Vec<String> = COMMANDS
+        .iter()
+        .map(|(name, _, fields)| synopsis(name, fields))
+        .collect();
+    commands.join(", ")
+}
+
+/// Describes a command with its arguments, like `cloud <transmittance> [<duration>]`.
+fn synopsis(name: &str, fields: &[&str]) -> String {
+    let mut synopsis = name.to_string();
+    for field in fields {
+        if *field == "duration" {
+            synopsis.push_str(" [<duration>]");
+        } else {
+            synopsis.push_str(&format!(" <{field}>"));
+        }
+    }
+    synopsis
+}
+
+/// Passes the event to the simulation loop, once the session with the CEM is running.
+fn inject(monitor: &Monitor, event: TimelineEvent) {
+    if !monitor.health.is_ready() {
+        tracing::warn!("Ignoring {event:?}, as the session with the CEM hasn't started yet");
+        return;
+    }
+    // The simulation loop logs the event when it handles it.
+    let _ = monitor.events.send(event);
+}
@@ -0,0 +1,108 @@
+use crate::Settings;
+use eyre::{bail, eyre, Context};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Settings read from a TOML or YAML configuration file.
+///
+/// The file contains the same settings as the environment variables, such as `cem_url` or `peak_power_w` (the names
+/// are case-insensitive). Lists are joined with commas, so `monthly_derating = [0.9, 1.0, ...]` works as well.
+///
+/// A file can also list several devices under `devices`, each with settings of its own, to run them in one process.
+#[derive(Debug, Default, Clone)]
+pub struct ConfigFile {
+    values: HashMap<String, String>,
+    devices: Vec<ConfigFile>,
+}
+
+impl ConfigFile {
+    /// Reads the configuration file at the given path; its extension determines whether it's TOML or YAML.
+    pub fn from_path(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read configuration file {}", path.display()))?;
+        let mut values: HashMap<String, ConfigValue> =
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("toml") => toml::from_str(&contents).map_err(eyre::Report::from),
+                Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(eyre::Report::from),
+                _ => bail!(
+                    "Unknown format of configuration file {}; should be .toml, .yaml or .yml",
+                    path.display()
+                ),
+            }
+            .wrap_err_with(|| format!("Invalid configuration file {}", path.display()))?;
+
+        let devices = match values.remove("devices") {
+            Some(ConfigValue::List(devices)) => devices
+                .into_iter()
+                .enumerate()
+                .map(|(index, device)| match device {
+                    ConfigValue::Table(values) => Self::from_values(values, Vec::new()),
+                    _ => bail!("Device {} should be a table of settings", index + 1),
+                })
+                .collect::<eyre::Result<_>>(),
+            Some(_) => Err(eyre!("devices should be a list")),
+            None => Ok(Vec::new()),
+        }
+        .wrap_err_with(|| format!("Invalid configuration file {}", path.display()))?;
+        Self::from_values(values, devices)
+            .wrap_err_with(|| format!("Invalid configuration file {}", path.display()))
+    }
+
+    /// Returns the devices listed in the file, each with its own settings; the other settings apply to all of them.
+    pub fn devices(&self) -> &[ConfigFile] {
+        &self.devices
+    }
+
+    fn from_values(
+        values: HashMap<String, ConfigValue>,
+        devices: Vec<ConfigFile>,
+    ) -> eyre::Result<Self> {
+        let values = values
+            .into_iter()
+            .map(|(name, value)| {
+                if let ConfigValue::Table(_) = value {
+                    bail!("{name} should be a single value or a list, not a table");
+                }
+                Ok((name.to_uppercase().replace('-', "_"), value.to_string()))
+            })
+            .collect::<eyre::Result<_>>()?;
+        Ok(Self { values, devices })
+    }
+}
+
+impl Settings for ConfigFile {
+    fn get(&self, name: &str) -> Option<String> {
+        self.values.get(name).cloned()
+    }
+}
+
+/// A value in a configuration file.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ConfigValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<ConfigValue>),
+    Table(HashMap<String, ConfigValue>),
+}
+
+impl std::fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{value}"),
+            Self::Integer(value) => write!(f, "{value}"),
+            Self::Float(value) => write!(f, "{value}"),
+            Self::String(value) => write!(f, "{value}"),
+            Self::List(values) => {
+                let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+                write!(f, "{}", values.join(","))
+            }
+            // Only devices are tables, and they aren't settings themselves.
+            Self::Table(_) => write!(f, "(table)"),
+        }
+    }
+}
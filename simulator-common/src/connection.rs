@@ -0,0 +1,834 @@
+use crate::archive::Archive;
+use crate::monitor::Monitor;
+use crate::mosaik::Cosimulation;
+use crate::transport::{MqttTransport, Transport};
+use crate::tui::Tui;
+use crate::validation::Rejection;
+use crate::{Settings, TimelineEvent};
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Context};
+use rumqttc::MqttOptions;
+use s2energy::common::{
+    ControlType, EnergyManagementRole, Handshake, Id, Message, ReceptionStatus,
+    ReceptionStatusValues, ResourceManagerDetails, SessionRequest, SessionRequestType,
+};
+use semver::{Version, VersionReq};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::MaybeTlsStream;
+use tracing::{Instrument, Span};
+
+/// How many unsent messages are kept while the connection is down; the oldest ones are dropped beyond this.
+const MAX_QUEUED_MESSAGES: usize = 10_000;
+
+/// How many pings are sent within the keepalive timeout, so a single lost pong doesn't end the connection.
+const KEEPALIVE_PINGS: u32 = 3;
+
+/// How long a simulator that listens for the CEM waits for it to connect again, before it goes on simulating for a
+/// while and tries again.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The S2 connection with the CEM, which records all messages that are sent and received if recording is enabled.
+///
+/// Unlike `S2Connection` from the s2energy crate, this keeps the session going when the CEM sends something that
+/// isn't a valid S2 message: it answers with an error reception status where possible, and waits for the next message.
+///
+/// Messages go through a queue, so the messages the simulator produces while the connection is down are delivered in
+/// order once a new session is set up, instead of getting lost.
+pub struct Connection {
+    socket: Transport,
+    /// How to connect to the CEM, to connect again after the connection was lost.
+    endpoint: Endpoint,
+    options: ConnectionOptions,
+    /// Whether the connection with the CEM is open.
+    connected: bool,
+    /// Whether the session is set up, so queued messages can be sent.
+    ready: bool,
+    /// The messages that haven't been sent yet, in the order they were produced.
+    queue: VecDeque<Message>,
+    /// The JSON Lines files the messages are recorded to, if recording is enabled.
+    recording: Option<Recording>,
+    /// The SQLite database the messages are stored in, if archiving is enabled.
+    archive: Option<Archive>,
+    monitor: Arc<Monitor>,
+    /// The events that are injected through the HTTP server or stdin, until the simulation loop takes them.
+    injected_events: Option<UnboundedReceiver<TimelineEvent>>,
+    /// The messages the CEM hasn't acknowledged with a reception status yet, by message ID.
+    outstanding: HashMap<Id, Outstanding>,
+    /// When the CEM last sent anything, including pongs, in real time.
+    last_heard: Instant,
+    /// When the CEM was last pinged, in real time.
+    last_ping: Instant,
+    /// The terminal UI, if it's shown.
+    tui: Option<Tui>,
+    /// The co-simulation that moves simulated time on, if any, until the simulation loop takes it.
+    cosimulation: Option<Cosimulation>,
+    /// What the CEM answered to every message that was sent, if they're kept to check the CEM.
+    receipts: Option<Vec<Receipt>>,
+}
+
+/// How the connection deals with a CEM that doesn't respond, and which version of S2 it speaks.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionOptions {
+    /// How long the CEM has to acknowledge a message.
+    reception_status_timeout: Duration,
+    /// How often a message is sent again if the CEM doesn't acknowledge it.
+    retransmissions: u32,
+    /// How long to wait before connecting again after the connection was lost.
+    reconnect_delay: Duration,
+    /// How long the CEM may stay silent before the connection is considered dead, if keepalive is enabled.
+    keepalive_timeout: Option<Duration>,
+    /// The S2 version the simulator announces in its handshake.
+    protocol_version: Version,
+}
+
+impl ConnectionOptions {
+    /// Reads the options from the `RECEPTION_STATUS_TIMEOUT`, `RETRANSMISSIONS`, `RECONNECT_DELAY`,
+    /// `KEEPALIVE_TIMEOUT` and `S2_VERSION` settings.
+    pub(crate) fn from_settings(settings: &impl Settings) -> eyre::Result<Self> {
+        let reception_status_timeout =
+            Duration::from_secs(settings.get_or("RECEPTION_STATUS_TIMEOUT", 30)?);
+        if reception_status_timeout.is_zero() {
+            return Err(eyre!(
+                "RECEPTION_STATUS_TIMEOUT should be at least 1 second"
+            ));
+        }
+        // A timeout of 0 turns keepalive off.
+        let keepalive_timeout = Duration::from_secs(settings.get_or("KEEPALIVE_TIMEOUT", 30)?);
+        let schema_version = s2energy::s2_schema_version();
+        let protocol_version = match settings.get("S2_VERSION") {
+            Some(version) => {
+                let version = Version::parse(&version)
+                    .wrap_err_with(|| format!("Invalid S2 version in S2_VERSION ({version})"))?;
+                // Pinning a version only changes what the simulator announces, to test how the CEM falls back.
+                if version != schema_version {
+                    tracing::warn!(
+                        "Announcing S2 version {version}, while the messages follow the schema of version \
+                         {schema_version}"
+                    );
+                }
+                version
+            }
+            None => schema_version,
+        };
+        Ok(Self {
+            reception_status_timeout,
+            retransmissions: settings.get_or("RETRANSMISSIONS", 0)?,
+            reconnect_delay: Duration::from_secs(settings.get_or("RECONNECT_DELAY", 5)?),
+            keepalive_timeout: (!keepalive_timeout.is_zero()).then_some(keepalive_timeout),
+            protocol_version,
+        })
+    }
+}
+
+/// How the simulator and the CEM connect: the simulator connects to the CEM, the CEM connects to the simulator, or
+/// both connect to an MQTT broker.
+///
+/// Either way, the simulator is the resource manager once the transport is open, so the handshake and the rest of the
+/// session are the same.
+pub(crate) enum Endpoint {
+    /// The simulator connects to the CEM with this request.
+    Dial {
+        request: Box<Request>,
+        /// The URL of the CEM without the token, for error messages.
+        url: String,
+    },
+    /// The simulator waits for the CEM to connect on this listener.
+    Listen(TcpListener),
+    /// The simulator exchanges messages with the CEM on the S2 topics under this prefix, on an MQTT broker.
+    Mqtt {
+        options: Box<MqttOptions>,
+        prefix: String,
+    },
+}
+
+impl Endpoint {
+    /// Connects to the CEM, or waits until the CEM connects.
+    pub(crate) async fn open(&self) -> eyre::Result<Transport> {
+        match self {
+            Endpoint::Dial { request, url } => {
+                let (socket, _) = tokio_tungstenite::connect_async(Request::clone(request))
+                    .await
+                    .wrap_err_with(|| format!("Could not connect to the CEM at {url}"))?;
+                Ok(Transport::WebSocket(Box::new(socket)))
+            }
+            Endpoint::Listen(listener) => {
+                let (stream, address) = listener
+                    .accept()
+                    .await
+                    .wrap_err("Could not accept a connection from the CEM")?;
+                let socket = tokio_tungstenite::accept_async(MaybeTlsStream::Plain(stream))
+                    .await
+                    .wrap_err_with(|| format!("Could not accept the connection from {address}"))?;
+                tracing::info!("The CEM connected from {address}");
+                Ok(Transport::WebSocket(Box::new(socket)))
+            }
+            Endpoint::Mqtt { options, prefix } => Ok(Transport::Mqtt(
+                MqttTransport::connect(MqttOptions::clone(options), prefix).await?,
+            )),
+        }
+    }
+}
+
+/// A message that was sent to the CEM, and the reception status the CEM answered with, if any.
+pub(crate) struct Receipt {
+    pub(crate) message_type: String,
+    pub(crate) id: Id,
+    pub(crate) status: Option<ReceptionStatus>,
+}
+
+/// A message that was sent to the CEM, but hasn't been acknowledged yet.
+struct Outstanding {
+    message: Message,
+    /// When the message was last sent, in real time.
+    sent_at: Instant,
+    /// How often the message has been sent again.
+    retransmissions: u32,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        socket: Transport,
+        endpoint: Endpoint,
+        options: ConnectionOptions,
+        recording: Option<Recording>,
+        archive: Option<Archive>,
+        monitor: Arc<Monitor>,
+        injected_events: UnboundedReceiver<TimelineEvent>,
+    ) -> Self {
+        monitor.health.set_connected(true);
+        Self {
+            socket,
+            endpoint,
+            options,
+            connected: true,
+            ready: false,
+            queue: VecDeque::new(),
+            recording,
+            archive,
+            monitor,
+            injected_events: Some(injected_events),
+            outstanding: HashMap::new(),
+            last_heard: Instant::now(),
+            last_ping: Instant::now(),
+            tui: None,
+            cosimulation: None,
+            receipts: None,
+        }
+    }
+
+    /// Shows the terminal UI until the connection is dropped, at the end of the simulation.
+    pub(crate) fn with_tui(mut self, tui: Option<Tui>) -> Self {
+        self.tui = tui;
+        self
+    }
+
+    /// Lets the co-simulation move simulated time on, once the simulation loop runs.
+    pub(crate) fn with_cosimulation(mut self, cosimulation: Option<Cosimulation>) -> Self {
+        self.cosimulation = cosimulation;
+        self
+    }
+
+    /// Keeps what the CEM answers to every message from now on, to report on it when checking the CEM.
+    pub(crate) fn keep_receipts(&mut self) {
+        self.receipts = Some(Vec::new());
+    }
+
+    /// What the CEM answered to the messages that were sent, if they're kept.
+    pub(crate) fn receipts(&self) -> Option<&[Receipt]> {
+        self.receipts.as_deref()
+    }
+
+    pub(crate) fn reception_status_timeout(&self) -> Duration {
+        self.options.reception_status_timeout
+    }
+
+    pub(crate) fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    pub(crate) fn take_injected_events(&mut self) -> Option<UnboundedReceiver<TimelineEvent>> {
+        self.injected_events.take()
+    }
+
+    pub(crate) fn take_cosimulation(&mut self) -> Option<Cosimulation> {
+        self.cosimulation.take()
+    }
+
+    /// Performs the handshake with the CEM as a resource manager, and returns the control type the CEM selected.
+    ///
+    /// If the CEM doesn't support the S2 version of the simulator, or selects another one, the simulator terminates the
+    /// session and the result is an error.
+    pub async fn initialize_as_rm(
+        &mut self,
+        rm_details: ResourceManagerDetails,
+    ) -> eyre::Result<ControlType> {
+        let supported_version = self.options.protocol_version.clone();
+        self.send_now(Handshake::new(
+            EnergyManagementRole::Rm,
+            vec![supported_version.to_string()],
+        ))
+        .await?;
+
+        let mut need_handshake = true;
+        let mut need_handshake_response = true;
+        loop {
+            match self.receive_message().await? {
+                Message::Handshake(handshake) if need_handshake => {
+                    need_handshake = false;
+                    // The CEM doesn't have to say which versions it supports; then only its selection counts.
+                    let versions = handshake.supported_protocol_versions;
+                    if !versions.is_empty()
+                        && !versions
+                            .iter()
+                            .any(|version| is_compatible(version, &supported_version))
+                    {
+                        return Err(self
+                            .refuse_version(format!(
+                                "The CEM supports S2 versions {}, but this simulator speaks {supported_version}",
+                                versions.join(", ")
+                            ))
+                            .await);
+                    }
+                }
+                Message::HandshakeResponse(response) if need_handshake_response => {
+                    need_handshake_response = false;
+                    let selected_version = &response.selected_protocol_version;
+                    VersionReq::parse(selected_version).wrap_err_with(|| {
+                        format!("The CEM selected an invalid S2 version ({selected_version})")
+                    })?;
+                    if !is_compatible(selected_version, &supported_version) {
+                        return Err(self
+                            .refuse_version(format!(
+                                "The CEM selected S2 version {selected_version}, but this simulator speaks \
+                                 {supported_version}"
+                            ))
+                            .await);
+                    }
+                    tracing::info!("Negotiated S2 version {selected_version} with the CEM");
+                }
+                Message::SelectControlType(select_control_type)
+                    if !need_handshake && !need_handshake_response =>
+                {
+                    return Ok(select_control_type.control_type);
+                }
+                message @ (Message::Handshake(_)
+                | Message::HandshakeResponse(_)
+                | Message::SelectControlType(_)) => {
+                    return Err(eyre!(
+                        "Received a message in the wrong order during the handshake: {message:?}"
+                    ));
+                }
+                _ => continue,
+            }
+
+            // The CEM needs our details once both sides know they speak the same version of S2.
+            if !need_handshake && !need_handshake_response {
+                self.send_now(rm_details.clone()).await?;
+            }
+        }
+    }
+
+    /// Terminates the session because the CEM speaks another version of S2, and returns the reason as an error.
+    async fn refuse_version(&mut self, reason: String) -> eyre::Report {
+        let terminate = SessionRequest {
+            diagnostic_label: Some(reason.clone()),
+            message_id: Id::generate(),
+            request: SessionRequestType::Terminate,
+        };
+        if let Err(error) = self.send_now(terminate).await {
+            tracing::warn!("Could not terminate the session: {error:#}");
+        }
+        eyre!(reason)
+    }
+
+    /// Sends a message to the CEM, which should acknowledge it with a reception status.
+    ///
+    /// While the connection is down or the session isn't set up, the message is queued, and it's sent after the
+    /// messages that were queued before it.
+    pub async fn send_message(&mut self, message: impl Into<Message>) -> eyre::Result<()> {
+        let mut message = message.into();
+        if let (Some(meter), Message::PowerMeasurement(measurement)) =
+            (&self.monitor.meter, &mut message)
+        {
+            if !meter.measure(measurement) {
+                tracing::debug!(
+                    "Not sending a power measurement, as the power meter hasn't been read recently"
+                );
+                return Ok(());
+            }
+        }
+        if self.queue.len() >= MAX_QUEUED_MESSAGES {
+            if let Some(dropped) = self.queue.pop_front() {
+                tracing::warn!(
+                    "Dropping {} {:?}, as {MAX_QUEUED_MESSAGES} messages are waiting to be sent",
+                    message_type(&dropped),
+                    dropped.id()
+                );
+            }
+        }
+        self.queue.push_back(message);
+        self.flush().await
+    }
+
+    /// Sends a message to the CEM right away, ahead of the queued messages, as needed to set up a session.
+    pub(crate) async fn send_now(&mut self, message: impl Into<Message>) -> eyre::Result<()> {
+        let message = message.into();
+        self.deliver(&message).await?;
+        self.track(message);
+        Ok(())
+    }
+
+    /// Marks the session as set up, and sends the messages that were queued in the meantime.
+    pub(crate) async fn session_ready(&mut self) -> eyre::Result<()> {
+        self.ready = true;
+        if !self.queue.is_empty() {
+            tracing::info!("Sending {} queued messages", self.queue.len());
+        }
+        self.flush().await
+    }
+
+    /// Sends the queued messages, as long as the connection is up and the session is set up.
+    async fn flush(&mut self) -> eyre::Result<()> {
+        while self.connected && self.ready {
+            let Some(message) = self.queue.pop_front() else {
+                break;
+            };
+            if let Err(error) = self.deliver(&message).await {
+                if self.connected {
+                    return Err(error);
+                }
+                // The connection was lost, so the message is sent after reconnecting.
+                self.queue.push_front(message);
+                break;
+            }
+            self.track(message);
+        }
+        Ok(())
+    }
+
+    /// Records a message and sends it to the CEM.
+    async fn deliver(&mut self, message: &Message) -> eyre::Result<()> {
+        let span = message_span("s2.send", message);
+        self.send_unrecorded(message).instrument(span).await?;
+        self.record(Direction::Sent, message)
+    }
+
+    /// Keeps track of a message that was sent, until the CEM acknowledges it.
+    fn track(&mut self, message: Message) {
+        if let Some(id) = message.id() {
+            if let Some(receipts) = &mut self.receipts {
+                receipts.push(Receipt {
+                    message_type: message_type(&message),
+                    id: id.clone(),
+                    status: None,
+                });
+            }
+            let outstanding = Outstanding {
+                message,
+                sent_at: Instant::now(),
+                retransmissions: 0,
+            };
+            self.outstanding.insert(id, outstanding);
+        }
+    }
+
+    /// Whether the connection with the CEM is open.
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub(crate) fn reconnect_delay(&self) -> Duration {
+        self.options.reconnect_delay
+    }
+
+    /// Connects to the CEM again after the connection was lost, or waits a while for the CEM to connect again if the
+    /// simulator listens for it; the session still needs to be set up afterwards.
+    pub(crate) async fn reconnect(&mut self) -> eyre::Result<()> {
+        let connecting = self.endpoint.open();
+        let socket = match (&self.endpoint, self.options.keepalive_timeout) {
+            // The simulation goes on in between, so it doesn't wait for the CEM forever.
+            (Endpoint::Listen(_), _) => tokio::time::timeout(ACCEPT_TIMEOUT, connecting)
+                .await
+                .map_err(|_| eyre!("The CEM didn't connect again within {ACCEPT_TIMEOUT:?}"))?,
+            // A CEM that accepts the connection but never finishes the WebSocket handshake is just as dead.
+            (Endpoint::Dial { .. } | Endpoint::Mqtt { .. }, Some(timeout)) => {
+                tokio::time::timeout(timeout, connecting)
+                    .await
+                    .map_err(|_| eyre!("The CEM didn't respond within {timeout:?}"))?
+            }
+            (Endpoint::Dial { .. } | Endpoint::Mqtt { .. }, None) => connecting.await,
+        }?;
+        self.socket = socket;
+        self.connected = true;
+        self.last_heard = Instant::now();
+        self.last_ping = Instant::now();
+        // The new session starts from scratch, so the CEM won't acknowledge messages from the old one anymore.
+        self.outstanding.clear();
+        // Every session gets a file of its own, and a row in the archive.
+        if let Some(recording) = &mut self.recording {
+            recording.start_session()?;
+        }
+        if let Some(archive) = &mut self.archive {
+            archive.start_session()?;
+        }
+        self.monitor.health.set_connected(true);
+        tracing::info!("Connected to the CEM again");
+        Ok(())
+    }
+
+    /// Marks the connection as lost, so the simulation loop can reconnect.
+    fn lost(&mut self, reason: impl Display) {
+        if self.connected {
+            tracing::warn!("Lost the connection with the CEM: {reason}");
+        }
+        self.connected = false;
+        self.ready = false;
+        self.monitor.health.set_connected(false);
+    }
+
+    /// Handles the messages the CEM didn't acknowledge within the timeout: they're sent again if retransmissions are
+    /// enabled, and otherwise given up on.
+    pub(crate) async fn check_reception_statuses(&mut self) -> eyre::Result<()> {
+        let timeout = self.options.reception_status_timeout;
+        let retransmissions = self.options.retransmissions;
+        let overdue: Vec<Id> = self
+            .outstanding
+            .iter()
+            .filter(|(_, outstanding)| outstanding.sent_at.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in overdue {
+            if !self.connected {
+                break;
+            }
+            let Some(mut outstanding) = self.outstanding.remove(&id) else {
+                continue;
+            };
+            let message_type = message_type(&outstanding.message);
+            if outstanding.retransmissions >= retransmissions {
+                tracing::warn!(
+                    "The CEM didn't acknowledge {message_type} {id:?} within {timeout:?}, giving up on it"
+                );
+                continue;
+            }
+            outstanding.retransmissions += 1;
+            tracing::warn!(
+                "The CEM didn't acknowledge {message_type} {id:?} within {timeout:?}, sending it again ({}/{retransmissions})",
+                outstanding.retransmissions
+            );
+            // The message keeps its ID, so the CEM can tell it has seen it before.
+            if let Err(error) = self.deliver(&outstanding.message).await {
+                if self.connected {
+                    return Err(error);
+                }
+                break;
+            }
+            outstanding.sent_at = Instant::now();
+            self.outstanding.insert(id, outstanding);
+        }
+        Ok(())
+    }
+
+    /// Pings the CEM while waiting for the next frame, and marks the connection as lost if the CEM stays silent for
+    /// longer than the keepalive timeout, so a CEM that stopped responding doesn't go unnoticed.
+    async fn next_frame(&mut self) -> eyre::Result<Option<eyre::Result<WebSocketMessage>>> {
+        let Some(timeout) = self
+            .options
+            .keepalive_timeout
+            .filter(|_| self.socket.answers_pings())
+        else {
+            return Ok(self.socket.next().await);
+        };
+        let ping_interval = timeout / KEEPALIVE_PINGS;
+        loop {
+            let deadline = (self.last_ping + ping_interval).min(self.last_heard + timeout);
+            tokio::select! {
+                frame = self.socket.next() => {
+                    self.last_heard = Instant::now();
+                    return Ok(frame);
+                }
+                _ = tokio::time::sleep_until(deadline) => {}
+            }
+
+            if self.last_heard.elapsed() >= timeout {
+                self.lost(format!("the CEM didn't respond for {timeout:?}"));
+                return Err(eyre!("The CEM stopped responding"));
+            }
+            if self.last_ping.elapsed() >= ping_interval {
+                if let Err(error) = self.socket.send(WebSocketMessage::Ping(Vec::new())).await {
+                    self.lost(&error);
+                    return Err(error).wrap_err("Could not ping the CEM");
+                }
+                self.last_ping = Instant::now();
+            }
+        }
+    }
+
+    /// Waits for a valid S2 message from the CEM, and acknowledges it with a reception status.
+    ///
+    /// Reception statuses are handled here, so they are neither returned nor recorded.
+    pub async fn receive_message(&mut self) -> eyre::Result<Message> {
+        let message = self.receive().await?;
+        self.acknowledge(&message, Ok(())).await?;
+        Ok(message)
+    }
+
+    /// Waits for a valid S2 message from the CEM, which still needs to be acknowledged with
+    /// [`acknowledge`](Self::acknowledge).
+    pub(crate) async fn receive(&mut self) -> eyre::Result<Message> {
+        if !self.connected {
+            return Err(eyre!("Not connected to the CEM"));
+        }
+        loop {
+            let text = match self.next_frame().await? {
+                Some(Ok(WebSocketMessage::Text(text))) => text,
+                Some(Ok(WebSocketMessage::Binary(_))) => {
+                    tracing::warn!("Ignoring a binary message from the CEM; S2 messages are text");
+                    continue;
+                }
+                Some(Ok(WebSocketMessage::Close(_))) | None => {
+                    self.lost("the CEM closed the connection");
+                    return Err(eyre!("The CEM closed the connection"));
+                }
+                // Tungstenite answers pings, and pongs only matter for keepalive
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => {
+                    self.lost(&error);
+                    return Err(error).wrap_err("Could not receive a message from the CEM");
+                }
+            };
+
+            let json: serde_json::Value = match serde_json::from_str(&text) {
+                Ok(json) => json,
+                Err(error) => {
+                    // Without a message ID there is nothing to send a reception status for.
+                    tracing::warn!(
+                        "Ignoring a message from the CEM that isn't valid JSON ({error}): {text}"
+                    );
+                    continue;
+                }
+            };
+            let message_id = json
+                .get("message_id")
+                .and_then(|id| serde_json::from_value::<Id>(id.clone()).ok());
+            let message: Message = match serde_json::from_value(json) {
+                Ok(message) => message,
+                Err(error) => {
+                    tracing::warn!("Rejecting an invalid message from the CEM ({error}): {text}");
+                    if let Some(message_id) = message_id {
+                        let status = ReceptionStatus::new(
+                            Some(error.to_string()),
+                            ReceptionStatusValues::InvalidMessage,
+                            message_id,
+                        );
+                        self.send_status(status).await?;
+                    }
+                    continue;
+                }
+            };
+
+            if let Message::ReceptionStatus(status) = &message {
+                self.record(Direction::Received, &message)?;
+                let acknowledged = self.outstanding.remove(&status.subject_message_id);
+                if let Some(receipts) = &mut self.receipts {
+                    let receipt = receipts
+                        .iter_mut()
+                        .find(|receipt| receipt.id == status.subject_message_id);
+                    if let Some(receipt) = receipt {
+                        receipt.status = Some(status.clone());
+                    }
+                }
+                if status.status != ReceptionStatusValues::Ok {
+                    let message_type = acknowledged
+                        .map(|outstanding| message_type(&outstanding.message))
+                        .unwrap_or_else(|| "message".into());
+                    tracing::warn!(
+                        "The CEM reported {:?} for {message_type} {:?}: {}",
+                        status.status,
+                        status.subject_message_id,
+                        status.diagnostic_label.as_deref().unwrap_or("no details")
+                    );
+                }
+                continue;
+            }
+
+            self.record(Direction::Received, &message)?;
+            self.monitor.health.message_received();
+            return Ok(message);
+        }
+    }
+
+    /// Sends the reception status for a message from the CEM: `OK`, or the reason it was rejected.
+    pub(crate) async fn acknowledge(
+        &mut self,
+        message: &Message,
+        result: Result<(), Rejection>,
+    ) -> eyre::Result<()> {
+        let Some(id) = message.id() else {
+            return Ok(());
+        };
+        let status = match result {
+            Ok(()) => ReceptionStatus::new(None, ReceptionStatusValues::Ok, id),
+            Err(rejection) => ReceptionStatus::new(Some(rejection.reason), rejection.status, id),
+        };
+        if let Err(error) = self.send_status(status).await {
+            // The reception status belongs to the session that was lost, so it doesn't matter anymore.
+            if self.connected {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the connection with a WebSocket close frame, or by disconnecting from the MQTT broker.
+    pub(crate) async fn close(&mut self) {
+        self.socket.close().await;
+        self.connected = false;
+        self.ready = false;
+        self.monitor.health.set_connected(false);
+    }
+
+    /// Sends a reception status right away, rather than queueing it, as the CEM doesn't acknowledge it in turn.
+    async fn send_status(&mut self, status: ReceptionStatus) -> eyre::Result<()> {
+        let message = status.into();
+        self.send_unrecorded(&message).await?;
+        self.record(Direction::Sent, &message)
+    }
+
+    async fn send_unrecorded(&mut self, message: &Message) -> eyre::Result<()> {
+        let text = serde_json::to_string(message)?;
+        if let Err(error) = self.socket.send(WebSocketMessage::Text(text)).await {
+            self.lost(&error);
+            return Err(error).wrap_err("Could not send a message to the CEM");
+        }
+        self.monitor.health.message_sent();
+        Ok(())
+    }
+
+    /// Records and archives the message if enabled, and passes it on to the dashboard and the MQTT bridge.
+    fn record(&mut self, direction: Direction, message: &Message) -> eyre::Result<()> {
+        let message = serde_json::to_value(message)?;
+        self.monitor.message(direction, &message);
+        if let Some(archive) = &self.archive {
+            archive.store(direction, &message)?;
+        }
+        let Some(recording) = &mut self.recording else {
+            return Ok(());
+        };
+        // The recording is about what happened on the wire, so it uses real time rather than simulated time.
+        let line = serde_json::to_string(&RecordedMessage {
+            timestamp: Utc::now(),
+            direction,
+            message,
+        })?;
+        writeln!(recording.file, "{line}").wrap_err("Could not write to the recording")
+    }
+}
+
+/// Returns the `message_type` of the message, such as `FRBC.StorageStatus`.
+pub(crate) fn message_type(message: &Message) -> String {
+    serde_json::to_value(message)
+        .ok()
+        .and_then(|json| json["message_type"].as_str().map(String::from))
+        .unwrap_or_else(|| "message".into())
+}
+
+/// Creates a span for handling the given message, with its type and ID as attributes for trace export.
+pub(crate) fn message_span(name: &'static str, message: &Message) -> Span {
+    let span = tracing::info_span!(
+        "s2_message",
+        otel.name = name,
+        s2.message_type = tracing::field::Empty,
+        s2.message_id = message.id().as_deref().map(String::as_str),
+    );
+    if !span.is_disabled() {
+        if let Ok(json) = serde_json::to_value(message) {
+            span.record("s2.message_type", json["message_type"].as_str());
+        }
+    }
+    span
+}
+
+/// Whether `version`, as the CEM wrote it, is compatible with the S2 version the simulator speaks. Following semver,
+/// `0.0.2-beta` only matches itself, and a requirement such as `^1.0` matches every 1.x version.
+fn is_compatible(version: &str, supported_version: &Version) -> bool {
+    VersionReq::parse(version).is_ok_and(|requirement| requirement.matches(supported_version))
+}
+
+/// The JSON Lines files in a directory that the messages are recorded to, a new one for every session.
+pub(crate) struct Recording {
+    directory: PathBuf,
+    /// The file of the current session.
+    file: File,
+}
+
+impl Recording {
+    /// Creates the directory if it isn't there, with the file for the first session.
+    pub(crate) fn create(directory: &Path) -> eyre::Result<Self> {
+        std::fs::create_dir_all(directory).wrap_err_with(|| {
+            format!(
+                "Could not create the recording directory {}",
+                directory.display()
+            )
+        })?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            file: create_recording(directory)?,
+        })
+    }
+
+    /// Starts a new file, for the session that follows.
+    pub(crate) fn start_session(&mut self) -> eyre::Result<()> {
+        self.file = create_recording(&self.directory)?;
+        Ok(())
+    }
+}
+
+/// Creates a new JSON Lines file for a session in the given directory.
+fn create_recording(directory: &Path) -> eyre::Result<File> {
+    let path = directory.join(format!(
+        "session-{}.jsonl",
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+    ));
+    let file = File::create_new(&path)
+        .wrap_err_with(|| format!("Could not create recording {}", path.display()))?;
+    tracing::info!("Recording all S2 messages to {}", path.display());
+    Ok(file)
+}
+
+/// A line in a recording.
+#[derive(Serialize)]
+struct RecordedMessage {
+    timestamp: DateTime<Utc>,
+    direction: Direction,
+    message: Value,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+}
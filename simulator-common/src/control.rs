@@ -0,0 +1,85 @@
+use crate::monitor::Monitor;
+use crate::TimelineEvent;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::{post, MethodRouter};
+use axum::Router;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// Endpoints to change the state of the simulated device while the CEM is connected, to try out edge cases by hand.
+///
+/// `POST /events` takes any event from the [`Timeline`](crate::Timeline) as JSON, like
+/// `{"event": "state_of_charge", "value": 0.1}`, and the simulator handles it right away. For convenience, there is a
+/// shorthand for every event that only takes its fields, like `POST /soc` with `{"value": 0.1}`.
+pub(crate) fn routes() -> Router<Arc<Monitor>> {
+    Router::new()
+        .route("/events", post(event))
+        .route("/soc", shorthand("state_of_charge"))
+        .route("/capacity", shorthand("capacity"))
+        .route("/outage", shorthand("outage"))
+        .route("/demand-spike", shorthand("demand_spike"))
+        .route("/irradiance", shorthand("irradiance"))
+        .route("/cloud", shorthand("cloud"))
+        .route("/disconnect", shorthand("disconnect"))
+}
+
+async fn event(State(monitor): State<Arc<Monitor>>, body: Bytes) -> (StatusCode, String) {
+    match parse_fields(&body) {
+        Ok(fields) => inject(&monitor, Value::Object(fields)),
+        Err(response) => response,
+    }
+}
+
+/// Handles posts of the fields of the given event; events without fields don't need a body.
+fn shorthand(name: &'static str) -> MethodRouter<Arc<Monitor>> {
+    post(
+        move |State(monitor): State<Arc<Monitor>>, body: Bytes| async move {
+            match parse_fields(&body) {
+                Ok(mut fields) => {
+                    fields.insert("event".into(), name.into());
+                    inject(&monitor, Value::Object(fields))
+                }
+                Err(response) => response,
+            }
+        },
+    )
+}
+
+fn parse_fields(body: &[u8]) -> Result<Map<String, Value>, (StatusCode, String)> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Map::new());
+    }
+    serde_json::from_slice(body).map_err(|error| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("The body should be a JSON object: {error}"),
+        )
+    })
+}
+
+/// Passes the event to the simulation loop, if it's valid and the session with the CEM is running.
+fn inject(monitor: &Monitor, event: Value) -> (StatusCode, String) {
+    let event: TimelineEvent = match serde_json::from_value(event) {
+        Ok(event) => event,
+        Err(error) => return (StatusCode::BAD_REQUEST, format!("Invalid event: {error}")),
+    };
+    if let Err(problem) = event.validate() {
+        return (StatusCode::BAD_REQUEST, format!("Invalid event: {problem}"));
+    }
+    if !monitor.health.is_ready() {
+        return (
+            StatusCode::CONFLICT,
+            "The session with the CEM hasn't started yet".into(),
+        );
+    }
+    let description = format!("{event:?}");
+    match monitor.events.send(event) {
+        Ok(()) => (StatusCode::ACCEPTED, description),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The simulation has stopped".into(),
+        ),
+    }
+}
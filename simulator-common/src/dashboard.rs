@@ -0,0 +1,108 @@
+use crate::connection::Direction;
+use crate::monitor::Monitor;
+use axum::extract::State;
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// How many messages the dashboard shows in its message log.
+const MESSAGE_LOG_LENGTH: usize = 100;
+/// How many of the latest instructions and their status updates the dashboard shows.
+const INSTRUCTION_LOG_LENGTH: usize = 20;
+
+/// The state of a simulated device, as shown on the dashboard.
+///
+/// Simulators fill in what applies to their device, and leave the rest empty.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeviceState {
+    /// The state of charge as a fraction between 0 and 1.
+    pub state_of_charge: Option<f64>,
+    /// The name of the active operation mode.
+    pub operation_mode: Option<String>,
+    /// The current power in W; positive for consumption, negative for production.
+    pub power_w: Option<f64>,
+    /// How much less the device produces than it could because of the instructions of the CEM, in W.
+    pub curtailment_w: Option<f64>,
+    /// The most the device can currently consume or produce in W, to put the power in perspective.
+    pub rated_power_w: Option<f64>,
+}
+
+/// The live state of the simulator behind the dashboard, which the connection and the simulation loop keep up-to-date.
+#[derive(Default)]
+pub(crate) struct Dashboard {
+    state: Mutex<DashboardState>,
+}
+
+#[derive(Default, Clone, Serialize)]
+pub(crate) struct DashboardState {
+    pub(crate) device: DeviceState,
+    /// The latest instructions from the CEM and the status updates for them, newest last.
+    pub(crate) instructions: VecDeque<LoggedMessage>,
+    /// The latest messages that were sent and received, newest last.
+    pub(crate) messages: VecDeque<LoggedMessage>,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct LoggedMessage {
+    /// When the message was sent or received, in real time like the recordings.
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) direction: Direction,
+    pub(crate) message_type: String,
+    pub(crate) message: Value,
+}
+
+impl Dashboard {
+    pub(crate) fn state(&self) -> DashboardState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_device_state(&self, device: DeviceState) {
+        self.state.lock().unwrap().device = device;
+    }
+
+    pub(crate) fn log(&self, direction: Direction, message: &Value) {
+        let message_type = message["message_type"].as_str().unwrap_or_default();
+        let logged = LoggedMessage {
+            timestamp: Utc::now(),
+            direction,
+            message_type: message_type.into(),
+            message: message.clone(),
+        };
+        let mut state = self.state.lock().unwrap();
+        if message_type.ends_with(".Instruction") || message_type == "InstructionStatusUpdate" {
+            push_bounded(
+                &mut state.instructions,
+                logged.clone(),
+                INSTRUCTION_LOG_LENGTH,
+            );
+        }
+        push_bounded(&mut state.messages, logged, MESSAGE_LOG_LENGTH);
+    }
+}
+
+pub(crate) fn push_bounded<T>(log: &mut VecDeque<T>, item: T, length: usize) {
+    if log.len() == length {
+        log.pop_front();
+    }
+    log.push_back(item);
+}
+
+/// The dashboard at `/`, which shows the state from `/api/state`.
+pub(crate) fn routes() -> Router<Arc<Monitor>> {
+    Router::new()
+        .route("/", get(page))
+        .route("/api/state", get(state))
+}
+
+async fn page() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+async fn state(State(monitor): State<Arc<Monitor>>) -> Json<DashboardState> {
+    Json(monitor.dashboard.state())
+}
@@ -0,0 +1,140 @@
+use crate::monitor::Monitor;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use s2energy::common::ControlType;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How many update intervals may pass without an update before the simulator is considered stuck.
+const MISSED_UPDATES: u32 = 3;
+
+/// The state of the connection and the simulator, as reported by the health endpoints.
+///
+/// The connection and the simulation loop keep this up-to-date. Timestamps are in real time, as they're about the
+/// process rather than the simulated device.
+#[derive(Default)]
+pub(crate) struct Health {
+    state: Mutex<HealthState>,
+}
+
+#[derive(Default, Clone, Serialize)]
+pub(crate) struct HealthState {
+    /// Whether the WebSocket connection with the CEM is open.
+    pub(crate) connected: bool,
+    /// The control type the CEM selected, once the handshake is done.
+    pub(crate) control_type: Option<ControlType>,
+    pub(crate) last_message_received: Option<DateTime<Utc>>,
+    pub(crate) last_message_sent: Option<DateTime<Utc>>,
+    /// When the simulator last sent its periodic update.
+    last_update: Option<DateTime<Utc>>,
+    /// How often the simulator sends its periodic update, in real time.
+    #[serde(skip)]
+    update_interval: Option<Duration>,
+}
+
+impl Health {
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.update(|state| state.connected = connected);
+    }
+
+    pub(crate) fn set_control_type(&self, control_type: ControlType, update_interval: Duration) {
+        self.update(|state| {
+            state.control_type = Some(control_type);
+            state.update_interval = Some(update_interval);
+        });
+    }
+
+    pub(crate) fn message_received(&self) {
+        self.update(|state| state.last_message_received = Some(Utc::now()));
+    }
+
+    pub(crate) fn message_sent(&self) {
+        self.update(|state| state.last_message_sent = Some(Utc::now()));
+    }
+
+    pub(crate) fn updated(&self) {
+        self.update(|state| state.last_update = Some(Utc::now()));
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.state().is_ready()
+    }
+
+    fn update(&self, change: impl FnOnce(&mut HealthState)) {
+        change(&mut self.state.lock().unwrap());
+    }
+
+    pub(crate) fn state(&self) -> HealthState {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+impl HealthState {
+    /// Whether the simulation loop still sends its periodic updates.
+    fn is_live(&self) -> bool {
+        match (self.last_update, self.update_interval) {
+            (Some(last_update), Some(update_interval)) => {
+                let deadline = last_update + update_interval * MISSED_UPDATES;
+                Utc::now() < deadline + chrono::Duration::seconds(1)
+            }
+            // The session hasn't started yet
+            _ => true,
+        }
+    }
+
+    /// Whether the session with the CEM is set up.
+    fn is_ready(&self) -> bool {
+        self.connected && self.control_type.is_some()
+    }
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    live: bool,
+    ready: bool,
+    #[serde(flatten)]
+    state: HealthState,
+}
+
+/// The health endpoints:
+///
+/// - `/health` responds with 200 as long as the simulator is running, and 503 once it has stopped sending its
+///   periodic updates, so it can be used as a liveness probe;
+/// - `/ready` responds with 200 once the session with the CEM is set up, and 503 otherwise, for a readiness probe.
+///
+/// Both respond with the state of the connection and the simulator as JSON.
+pub(crate) fn routes() -> Router<Arc<Monitor>> {
+    Router::new()
+        .route("/health", get(liveness))
+        .route("/ready", get(readiness))
+}
+
+async fn liveness(State(monitor): State<Arc<Monitor>>) -> (StatusCode, Json<HealthResponse>) {
+    respond(&monitor.health, HealthState::is_live)
+}
+
+async fn readiness(State(monitor): State<Arc<Monitor>>) -> (StatusCode, Json<HealthResponse>) {
+    respond(&monitor.health, HealthState::is_ready)
+}
+
+fn respond(
+    health: &Health,
+    check: impl Fn(&HealthState) -> bool,
+) -> (StatusCode, Json<HealthResponse>) {
+    let state = health.state();
+    let status = if check(&state) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let response = HealthResponse {
+        live: state.is_live(),
+        ready: state.is_ready(),
+        state,
+    };
+    (status, Json(response))
+}
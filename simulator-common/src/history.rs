@@ -0,0 +1,57 @@
+use crate::{time, DeviceState};
+use chrono::Utc;
+use eyre::Context;
+use std::fs::File;
+use std::path::Path;
+
+/// The state of the device over time, written to a CSV file at every periodic update so a run can be plotted without
+/// parsing S2 messages.
+///
+/// Every row has the simulated time, like the S2 messages, and the columns of [`DeviceState`]; what doesn't apply to
+/// the device is left empty.
+pub(crate) struct History {
+    writer: csv::Writer<File>,
+}
+
+impl History {
+    /// Creates a new CSV file in the given directory.
+    pub(crate) fn create(directory: &Path) -> eyre::Result<Self> {
+        std::fs::create_dir_all(directory).wrap_err_with(|| {
+            format!("Could not create the CSV directory {}", directory.display())
+        })?;
+        let path = directory.join(format!(
+            "history-{}.csv",
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        let file = File::create_new(&path)
+            .wrap_err_with(|| format!("Could not create {}", path.display()))?;
+        let mut writer = csv::Writer::from_writer(file);
+        writer.write_record([
+            "timestamp",
+            "power_w",
+            "state_of_charge",
+            "curtailment_w",
+            "operation_mode",
+        ])?;
+        writer.flush()?;
+        tracing::info!("Writing the history of the device to {}", path.display());
+        Ok(Self { writer })
+    }
+
+    /// Adds the current state of the device.
+    pub(crate) fn write(&mut self, device: &DeviceState) -> eyre::Result<()> {
+        let number = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+        self.writer
+            .write_record([
+                time::now().to_rfc3339(),
+                number(device.power_w),
+                number(device.state_of_charge),
+                number(device.curtailment_w),
+                device.operation_mode.clone().unwrap_or_default(),
+            ])
+            .wrap_err("Could not write to the CSV file")?;
+        // Flush every row, so the file can be followed while the simulation runs.
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use crate::DeviceState;
+use serde_json::{json, Value};
+
+/// A sensor for a field of the [`DeviceState`].
+struct Sensor {
+    field: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    /// Turns the state JSON into the value of the sensor.
+    value_template: &'static str,
+}
+
+const SENSORS: [Sensor; 4] = [
+    Sensor {
+        field: "state_of_charge",
+        name: "State of charge",
+        device_class: Some("battery"),
+        unit: Some("%"),
+        value_template: "{{ (value_json.state_of_charge * 100) | round(1) }}",
+    },
+    Sensor {
+        field: "operation_mode",
+        name: "Operation mode",
+        device_class: None,
+        unit: None,
+        value_template: "{{ value_json.operation_mode }}",
+    },
+    Sensor {
+        field: "power_w",
+        name: "Power",
+        device_class: Some("power"),
+        unit: Some("W"),
+        value_template: "{{ value_json.power_w | round(0) }}",
+    },
+    Sensor {
+        field: "curtailment_w",
+        name: "Curtailment",
+        device_class: Some("power"),
+        unit: Some("W"),
+        value_template: "{{ value_json.curtailment_w | round(0) }}",
+    },
+];
+
+/// Returns the MQTT discovery messages (topic and payload) that make Home Assistant show the state of the device.
+///
+/// There is a sensor for every field of the state that applies to the device, that is, every field that is set. The
+/// sensors belong to one device, named after the topic prefix, and read the state from `<topic_prefix>/state`.
+pub(crate) fn discovery_messages(
+    discovery_prefix: &str,
+    topic_prefix: &str,
+    state: &DeviceState,
+) -> Vec<(&'static str, String, String)> {
+    let state = serde_json::to_value(state).unwrap_or_default();
+    // Home Assistant only allows letters, digits, underscores and hyphens in the node ID.
+    let node_id: String = topic_prefix
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    SENSORS
+        .iter()
+        .filter(|sensor| !state[sensor.field].is_null())
+        .map(|sensor| {
+            let mut config = json!({
+                "name": sensor.name,
+                "unique_id": format!("{node_id}_{}", sensor.field),
+                "state_topic": format!("{topic_prefix}/state"),
+                "value_template": sensor.value_template,
+                "availability_topic": format!("{topic_prefix}/availability"),
+                "device": {
+                    "identifiers": [node_id],
+                    "name": topic_prefix,
+                    "manufacturer": "S2 example implementations",
+                    "model": "S2 simulator",
+                },
+            });
+            if let Some(device_class) = sensor.device_class {
+                config["device_class"] = device_class.into();
+            }
+            if let Some(unit) = sensor.unit {
+                config["unit_of_measurement"] = unit.into();
+                config["state_class"] = "measurement".into();
+            }
+            let topic = format!(
+                "{discovery_prefix}/sensor/{node_id}/{}/config",
+                sensor.field
+            );
+            (sensor.field, topic, Value::to_string(&config))
+        })
+        .collect()
+}
@@ -0,0 +1,27 @@
+use crate::control;
+use crate::dashboard;
+use crate::health;
+use crate::monitor::Monitor;
+use eyre::Context;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Starts an HTTP server on the given address with the health endpoints, the dashboard and the control endpoints.
+pub(crate) async fn serve(address: &str, monitor: Arc<Monitor>) -> eyre::Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .wrap_err_with(|| format!("Could not start the HTTP server on {address}"))?;
+    let router = health::routes()
+        .merge(dashboard::routes())
+        .merge(control::routes())
+        .with_state(monitor);
+    tracing::info!(
+        "Serving the dashboard on http://{address}/, and health endpoints on /health and /ready"
+    );
+    tokio::spawn(async move {
+        if let Err(error) = axum::serve(listener, router).await {
+            tracing::error!("The HTTP server stopped: {error}");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,244 @@
+use crate::connection::Direction;
+use crate::{time, DeviceState, Settings};
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Context};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// How often the points are written to InfluxDB, in one request.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How many points are kept while InfluxDB can't be reached; the oldest are dropped beyond that.
+const MAX_PENDING: usize = 10_000;
+/// How long a write may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writes the state of the simulator to InfluxDB in its line protocol, so a run can be followed and plotted in
+/// Grafana.
+///
+/// Every point is tagged with `source`, and has one of these measurements:
+///
+/// - `device_state`: the fields of the [`DeviceState`] at every periodic update, like the CSV history;
+/// - `power_measurement`: the power measurements the simulator sends to the CEM, with a `value` per
+///   `commodity_quantity`;
+/// - `instruction`: the instructions from the CEM, with their `message_type` and `id`;
+/// - `instruction_status`: the status updates the simulator sends for them, with their `status` and `instruction_id`.
+///
+/// Like the S2 messages, the points have the simulated time, so a scenario that runs faster than real time is plotted
+/// over the hours it simulates. They're written in the background; while InfluxDB can't be reached, they're kept up to
+/// a point and written once it's back.
+pub(crate) struct InfluxSink {
+    points: UnboundedSender<String>,
+    /// The tags every point gets, in line protocol.
+    tags: String,
+}
+
+impl InfluxSink {
+    /// Writes to the InfluxDB at the URL in the `INFLUX_URL` setting, such as `http://localhost:8086`, if it's set.
+    ///
+    /// The points go to the bucket in `INFLUX_BUCKET` (for InfluxDB 1.8, `database/retention-policy`) of the
+    /// organization in `INFLUX_ORG`, if any, authorized with the token in `INFLUX_TOKEN`. `INFLUX_SOURCE` sets the
+    /// `source` tag, to tell the simulators apart [default: `s2-simulator`].
+    pub(crate) fn from_settings(settings: &impl Settings) -> eyre::Result<Option<Self>> {
+        let Some(url) = settings.get("INFLUX_URL") else {
+            return Ok(None);
+        };
+        let bucket = settings
+            .get("INFLUX_BUCKET")
+            .ok_or_else(|| eyre!("Could not read the bucket to write to from INFLUX_BUCKET"))?;
+        let source = settings
+            .get("INFLUX_SOURCE")
+            .unwrap_or_else(|| "s2-simulator".into());
+        let writer = Writer::new(
+            &url,
+            bucket,
+            settings.get("INFLUX_ORG"),
+            settings.get("INFLUX_TOKEN"),
+        )?;
+        let (points, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(writer.run(receiver));
+        tracing::info!("Writing the state of the simulator to InfluxDB at {url}");
+        Ok(Some(Self {
+            points,
+            tags: tag("source", &source),
+        }))
+    }
+
+    /// Writes the state of the device, after a periodic update.
+    pub(crate) fn write_state(&self, device: &DeviceState) {
+        let mut fields: Vec<String> = [
+            ("power_w", device.power_w),
+            ("state_of_charge", device.state_of_charge),
+            ("curtailment_w", device.curtailment_w),
+            ("rated_power_w", device.rated_power_w),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| number(key, value?))
+        .collect();
+        if let Some(operation_mode) = &device.operation_mode {
+            fields.push(text("operation_mode", operation_mode));
+        }
+        self.write("device_state", &self.tags, fields, time::now());
+    }
+
+    /// Writes a message that was sent to or received from the CEM, if it's a measurement or about an instruction.
+    pub(crate) fn write_message(&self, direction: Direction, message: &Value) {
+        let message_type = message["message_type"].as_str().unwrap_or_default();
+        match (direction, message_type) {
+            (Direction::Sent, "PowerMeasurement") => {
+                let at = timestamp(&message["measurement_timestamp"]);
+                for value in message["values"].as_array().into_iter().flatten() {
+                    let (Some(quantity), Some(value)) = (
+                        value["commodity_quantity"].as_str(),
+                        value["value"].as_f64(),
+                    ) else {
+                        continue;
+                    };
+                    let tags = format!("{}{}", self.tags, tag("commodity_quantity", quantity));
+                    let fields = number("value", value).into_iter().collect();
+                    self.write("power_measurement", &tags, fields, at);
+                }
+            }
+            (Direction::Received, message_type) if message_type.ends_with(".Instruction") => {
+                let tags = format!("{}{}", self.tags, tag("message_type", message_type));
+                let id = message["id"].as_str().unwrap_or_default();
+                self.write("instruction", &tags, vec![text("id", id)], time::now());
+            }
+            (Direction::Sent, "InstructionStatusUpdate") => {
+                let status = message["status_type"].as_str().unwrap_or_default();
+                let tags = format!("{}{}", self.tags, tag("status", status));
+                let instruction_id = message["instruction_id"].as_str().unwrap_or_default();
+                let fields = vec![text("instruction_id", instruction_id)];
+                self.write(
+                    "instruction_status",
+                    &tags,
+                    fields,
+                    timestamp(&message["timestamp"]),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn write(&self, measurement: &str, tags: &str, fields: Vec<String>, at: DateTime<Utc>) {
+        // A point needs at least one field.
+        if fields.is_empty() {
+            return;
+        }
+        let line = format!(
+            "{measurement}{tags} {} {}",
+            fields.join(","),
+            at.timestamp_millis()
+        );
+        // The writer only stops when the simulator does.
+        let _ = self.points.send(line);
+    }
+}
+
+/// Writes the points to InfluxDB in batches, in the background.
+struct Writer {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    token: Option<String>,
+}
+
+impl Writer {
+    fn new(
+        url: &str,
+        bucket: String,
+        org: Option<String>,
+        token: Option<String>,
+    ) -> eyre::Result<Self> {
+        // InfluxDB 2 and 3 take the points at this endpoint, and so does InfluxDB 1.8 for compatibility.
+        let mut url = reqwest::Url::parse(&format!("{}/api/v2/write", url.trim_end_matches('/')))
+            .wrap_err_with(|| format!("Invalid value for INFLUX_URL ({url})"))?;
+        url.query_pairs_mut()
+            .append_pair("bucket", &bucket)
+            .append_pair("precision", "ms");
+        if let Some(org) = org {
+            url.query_pairs_mut().append_pair("org", &org);
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self { client, url, token })
+    }
+
+    /// Writes the points that came in every [`FLUSH_INTERVAL`], until the simulator stops.
+    async fn run(self, mut points: UnboundedReceiver<String>) {
+        let mut pending: Vec<String> = Vec::new();
+        let mut failing = false;
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                point = points.recv() => match point {
+                    Some(point) => pending.push(point),
+                    None => return,
+                },
+                _ = flush.tick(), if !pending.is_empty() => {
+                    match self.write(&pending).await {
+                        Ok(()) => {
+                            if failing {
+                                tracing::info!("Writing to InfluxDB again");
+                                failing = false;
+                            }
+                            pending.clear();
+                        }
+                        Err(error) => {
+                            // Warn once, rather than every second while InfluxDB is down.
+                            if !failing {
+                                tracing::warn!("Could not write to InfluxDB: {error:#}");
+                                failing = true;
+                            }
+                            let excess = pending.len().saturating_sub(MAX_PENDING);
+                            pending.drain(..excess);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn write(&self, points: &[String]) -> eyre::Result<()> {
+        let mut request = self.client.post(self.url.clone()).body(points.join("\n"));
+        if let Some(token) = &self.token {
+            request = request.header(reqwest::header::AUTHORIZATION, format!("Token {token}"));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(eyre!("InfluxDB answered {status}: {}", body.trim()));
+        }
+        Ok(())
+    }
+}
+
+/// A tag in line protocol, with the comma that separates it from what comes before.
+fn tag(key: &str, value: &str) -> String {
+    let value = value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ");
+    format!(",{key}={value}")
+}
+
+/// A number field in line protocol, unless the number can't be written.
+fn number(key: &str, value: f64) -> Option<String> {
+    value.is_finite().then(|| format!("{key}={value}"))
+}
+
+/// A string field in line protocol.
+fn text(key: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("{key}=\"{value}\"")
+}
+
+/// The time in a message, or the current time if it doesn't have a valid one.
+fn timestamp(value: &Value) -> DateTime<Utc> {
+    value
+        .as_str()
+        .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+        .map_or_else(time::now, |timestamp| timestamp.to_utc())
+}
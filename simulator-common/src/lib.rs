@@ -0,0 +1,736 @@
+//! Shared plumbing for the S2 resource manager simulators.
+//!
+//! Every simulator follows the same pattern: it announces itself to the CEM, sends some initial information once the
+//! CEM has selected a control type, and then reacts to messages from the CEM while periodically sending updates such as
+//! measurements. The simulators implement [`RmSimulator`] for their own behaviour, and [`run`] takes care of the rest.
+//! Their configuration is read from [`Settings`], such as environment variables or a [`ConfigFile`], and they take the
+//! current time from [`time::now`], so the simulation can run faster than real time, or be driven by a co-simulation.
+
+use archive::Archive;
+use connection::{ConnectionOptions, Endpoint, Recording};
+use eyre::{eyre, Context};
+use history::History;
+use influx::InfluxSink;
+use meter::PowerMeter;
+use monitor::Monitor;
+use mosaik::{Cosimulation, Step};
+use mqtt::MqttBridge;
+use s2energy::common::{
+    ControlType, Id, Message, ResourceManagerDetails, SessionRequest, SessionRequestType,
+};
+use s2energy::{ddbc, frbc, ombc, pebc};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tracing::Instrument;
+use tui::Tui;
+use validation::{Rejection, Validator};
+
+mod archive;
+mod check;
+mod commands;
+mod config_file;
+mod connection;
+mod control;
+mod dashboard;
+mod health;
+mod history;
+mod home_assistant;
+mod http;
+mod influx;
+mod limited_consumer;
+mod meter;
+mod monitor;
+mod mosaik;
+mod mqtt;
+pub mod random;
+pub mod rm_details;
+mod schedule;
+mod settings;
+pub mod telemetry;
+pub mod time;
+mod timeline;
+mod transport;
+mod tui;
+mod validation;
+
+pub use config_file::ConfigFile;
+pub use connection::Connection;
+pub use dashboard::DeviceState;
+pub use limited_consumer::{LimitableDevice, LimitedConsumer};
+pub use schedule::{Schedule, Scheduler};
+pub use settings::{EnvSettings, Or, Settings};
+pub use timeline::{Timeline, TimelineEvent};
+
+/// How often the simulation loop checks for messages the CEM hasn't acknowledged in time.
+const RECEPTION_STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The behaviour of a simulated resource manager.
+pub trait RmSimulator {
+    /// The details that are sent to the CEM when the session starts.
+    ///
+    /// The available control types in these details are the only ones the CEM can select.
+    fn resource_manager_details(&self) -> ResourceManagerDetails;
+
+    /// Returns the messages the CEM needs right after it selected a control type, such as a system description.
+    fn initial_messages(&mut self, control_type: ControlType) -> eyre::Result<Vec<Message>>;
+
+    /// Called when the CEM selects a control type again during the session, before the initial messages for the new
+    /// selection are requested. The device should go back to what it does without instructions, as the instructions of
+    /// the previous selection no longer apply.
+    fn deactivate_control_type(&mut self) {}
+
+    /// Handles a message from the CEM, and returns the messages that should be sent in response.
+    ///
+    /// By default, this passes instructions to the handler for their type, such as
+    /// [`on_frbc_instruction`](RmSimulator::on_frbc_instruction), and everything else to
+    /// [`on_unknown`](RmSimulator::on_unknown), so a simulator only implements the handlers for its control type. The
+    /// handshake, control type selection and session requests are taken care of before this is called.
+    fn process_message(&mut self, message: &Message) -> eyre::Result<Vec<Message>> {
+        match message {
+            Message::FrbcInstruction(instruction) => self.on_frbc_instruction(instruction),
+            Message::OmbcInstruction(instruction) => self.on_ombc_instruction(instruction),
+            Message::PebcInstruction(instruction) => self.on_pebc_instruction(instruction),
+            Message::DdbcInstruction(instruction) => self.on_ddbc_instruction(instruction),
+            message => self.on_unknown(message),
+        }
+    }
+
+    /// Handles an FRBC.Instruction, and returns the messages that should be sent in response, such as an instruction
+    /// status.
+    fn on_frbc_instruction(
+        &mut self,
+        instruction: &frbc::Instruction,
+    ) -> eyre::Result<Vec<Message>> {
+        self.on_unknown(&Message::FrbcInstruction(instruction.clone()))
+    }
+
+    /// Handles an OMBC.Instruction, like [`on_frbc_instruction`](RmSimulator::on_frbc_instruction).
+    fn on_ombc_instruction(
+        &mut self,
+        instruction: &ombc::Instruction,
+    ) -> eyre::Result<Vec<Message>> {
+        self.on_unknown(&Message::OmbcInstruction(instruction.clone()))
+    }
+
+    /// Handles a PEBC.Instruction, like [`on_frbc_instruction`](RmSimulator::on_frbc_instruction).
+    fn on_pebc_instruction(
+        &mut self,
+        instruction: &pebc::Instruction,
+    ) -> eyre::Result<Vec<Message>> {
+        self.on_unknown(&Message::PebcInstruction(instruction.clone()))
+    }
+
+    /// Handles a DDBC.Instruction, like [`on_frbc_instruction`](RmSimulator::on_frbc_instruction).
+    fn on_ddbc_instruction(
+        &mut self,
+        instruction: &ddbc::Instruction,
+    ) -> eyre::Result<Vec<Message>> {
+        self.on_unknown(&Message::DdbcInstruction(instruction.clone()))
+    }
+
+    /// Handles a message from the CEM that the simulator has no handler for. By default, it's logged and ignored.
+    fn on_unknown(&mut self, message: &Message) -> eyre::Result<Vec<Message>> {
+        tracing::info!(
+            "Ignoring {} {:?}, which this simulator doesn't handle",
+            connection::message_type(message),
+            message.id()
+        );
+        Ok(vec![])
+    }
+
+    /// Called when the CEM asks to terminate the session or to reconnect, before the session is ended or kept going.
+    /// There's nothing to send in response, as the session is about to end.
+    fn on_session_request(&mut self, _request: &SessionRequest) {}
+
+    /// Called every [`update_interval`](RmSimulator::update_interval), starting right after the initial messages.
+    /// Returns the messages that should be sent, such as measurements and forecasts.
+    fn periodic_update(&mut self) -> impl Future<Output = eyre::Result<Vec<Message>>>;
+
+    /// How often [`periodic_update`](RmSimulator::periodic_update) is called.
+    fn update_interval(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    /// Handles an event from the [`Timeline`], and returns the messages that should be sent to inform the CEM.
+    ///
+    /// Simulators only need to handle the events that make sense for their device; the others are ignored.
+    fn handle_event(&mut self, event: &TimelineEvent) -> eyre::Result<Vec<Message>> {
+        tracing::warn!("Ignoring timeline event {event:?}, which doesn't apply to this simulator");
+        Ok(vec![])
+    }
+
+    /// The current state of the device, as shown on the dashboard.
+    fn device_state(&self) -> DeviceState {
+        DeviceState::default()
+    }
+}
+
+/// Connects to the CEM at the WebSocket URL in the `CEM_URL` setting.
+///
+/// If the `CEM_TOKEN` setting is set, the token is sent in an `Authorization: Bearer` header. For CEMs that expect the
+/// token in the URL instead, set `CEM_TOKEN_QUERY_PARAMETER` to the name of the query parameter to put it in.
+///
+/// If the `LISTEN_ADDRESS` setting is set instead of `CEM_URL`, the simulator waits for the CEM to connect on that
+/// address, for deployments where the CEM sets up the connection. When that connection is lost, the simulator waits
+/// for the CEM to connect again instead of reconnecting itself. If the `TRANSPORT` setting is `MQTT`, the simulator
+/// exchanges the S2 messages with the CEM through the MQTT broker in `MQTT_BROKER` instead of a WebSocket.
+///
+/// If the `RECORDING_DIRECTORY` setting is set, every message that is sent or received is recorded to a new JSON Lines
+/// file in that directory. If the `ARCHIVE_PATH` setting is set, they're also stored in that SQLite database. If the
+/// `CSV_DIRECTORY` setting is set, the state of the device is written to a new CSV file in that directory at every
+/// periodic update. If the `HTTP_ADDRESS` setting is set, the health endpoints and the dashboard are served on
+/// that address, with endpoints to inject events while the simulation runs. If the `MQTT_BROKER` setting is set, the
+/// state of the device and the messages are also published to that MQTT broker. If the `INFLUX_URL` setting is set, the
+/// state of the device, the measurements and the instructions are written to that InfluxDB. If the `TUI` setting is
+/// `true`, a live view of the simulator is shown in the terminal instead of the log, until the simulation ends. If the
+/// `COMMANDS` setting is `true`, commands on stdin such as `soc 0.3` inject events, like the HTTP server. If the
+/// `VALIDATE_ONLY` setting is `true`, [`run`] only sets up a session to check the CEM.
+///
+/// If the `POWER_METER` setting is `SHELLY` or `TASMOTA`, the power of the device is measured with that WiFi power meter
+/// instead of simulated, polled at `POWER_METER_URL` or read from `POWER_METER_TOPIC` on the MQTT broker.
+///
+/// If the `MOSAIK_ADDRESS` setting is set, a mosaik co-simulation can connect on that address, to move simulated time on
+/// and read the power of the device; see [`time::init`].
+pub async fn connect(settings: &impl Settings) -> eyre::Result<Connection> {
+    let endpoint = endpoint(settings).await?;
+
+    // Create the recording first, so a problem with it is reported before anything is sent.
+    let recording = settings
+        .get("RECORDING_DIRECTORY")
+        .map(|directory| Recording::create(directory.as_ref()))
+        .transpose()?;
+    let archive = settings
+        .get("ARCHIVE_PATH")
+        .map(|path| Archive::open(path.as_ref()))
+        .transpose()?;
+    let history = settings
+        .get("CSV_DIRECTORY")
+        .map(|directory| History::create(directory.as_ref()))
+        .transpose()?;
+    // The health endpoints are up before connecting, so they can report that the simulator isn't ready yet.
+    let mqtt = MqttBridge::from_settings(settings)?;
+    let influx = InfluxSink::from_settings(settings)?;
+    let meter = PowerMeter::from_settings(settings)?;
+    let (monitor, injected_events) = Monitor::new(mqtt, history, influx, meter);
+    let monitor = Arc::new(monitor);
+    if let Some(address) = settings.get("HTTP_ADDRESS") {
+        http::serve(&address, monitor.clone()).await?;
+    }
+    // mosaik can set up the co-simulation while the simulator connects to the CEM.
+    let cosimulation = Cosimulation::from_settings(settings, monitor.clone()).await?;
+    let options = ConnectionOptions::from_settings(settings)?;
+    // The terminal UI reads the keyboard itself.
+    let tui = settings.get_or("TUI", false)?;
+    if settings.get_or("COMMANDS", false)? {
+        if tui {
+            return Err(eyre!(
+                "COMMANDS can't be used with TUI, which reads the keyboard itself"
+            ));
+        }
+        commands::spawn(monitor.clone());
+    }
+    // The terminal UI is shown while connecting, and restored if that fails.
+    let tui = if tui {
+        Some(Tui::start(monitor.clone())?)
+    } else {
+        None
+    };
+    // Waiting for the CEM to connect can take a while, so it can be stopped.
+    let socket = tokio::select! {
+        socket = endpoint.open() => socket?,
+        signal = shutdown_signal(&monitor.stop) => {
+            return Err(eyre!("Received {signal} before the connection with the CEM was set up"));
+        }
+    };
+    let mut connection = Connection::new(
+        socket,
+        endpoint,
+        options,
+        recording,
+        archive,
+        monitor,
+        injected_events,
+    )
+    .with_tui(tui)
+    .with_cosimulation(cosimulation);
+    if settings.get_or("VALIDATE_ONLY", false)? {
+        connection.keep_receipts();
+    }
+    Ok(connection)
+}
+
+/// Sets up how the simulator connects with the CEM, from the `TRANSPORT`, `CEM_URL` and `LISTEN_ADDRESS` settings.
+async fn endpoint(settings: &impl Settings) -> eyre::Result<Endpoint> {
+    let transport = settings
+        .get("TRANSPORT")
+        .unwrap_or_else(|| "WEBSOCKET".into())
+        .to_uppercase();
+    match transport.as_str() {
+        "WEBSOCKET" => {}
+        "MQTT" => {
+            if settings.get("CEM_URL").is_some() || settings.get("LISTEN_ADDRESS").is_some() {
+                return Err(eyre!(
+                    "CEM_URL and LISTEN_ADDRESS can't be used with TRANSPORT=MQTT, which connects through MQTT_BROKER"
+                ));
+            }
+            let broker = settings.get("MQTT_BROKER").ok_or_else(|| {
+                eyre!("TRANSPORT=MQTT needs the address of the broker in MQTT_BROKER")
+            })?;
+            let prefix = mqtt::topic_prefix(settings)?;
+            // The bridge may use the same broker, so the S2 client needs a name of its own.
+            let options = mqtt::options(settings, &broker, &format!("{prefix}-s2"))?;
+            return Ok(Endpoint::Mqtt {
+                options: Box::new(options),
+                prefix,
+            });
+        }
+        other => {
+            return Err(eyre!(
+                "Invalid value for TRANSPORT ({other}); should be WEBSOCKET or MQTT"
+            ))
+        }
+    }
+
+    match (settings.get("CEM_URL"), settings.get("LISTEN_ADDRESS")) {
+        (Some(_), Some(_)) => Err(eyre!(
+            "CEM_URL and LISTEN_ADDRESS can't both be set; the simulator either connects to the CEM or waits for the \
+             CEM to connect"
+        )),
+        (Some(url), None) => Ok(Endpoint::Dial {
+            request: Box::new(cem_request(settings, &url)?),
+            url,
+        }),
+        (None, Some(address)) => {
+            let listener = TcpListener::bind(&address)
+                .await
+                .wrap_err_with(|| format!("Could not listen on {address}"))?;
+            tracing::info!("Waiting for the CEM to connect on ws://{address}");
+            Ok(Endpoint::Listen(listener))
+        }
+        (None, None) => Err(eyre!(
+            "Could not read CEM URL from CEM_URL (or set LISTEN_ADDRESS to let the CEM connect)"
+        )),
+    }
+}
+
+/// Builds the request to connect to the CEM at `url` with, with the token in the `CEM_TOKEN` setting if it's set.
+fn cem_request(settings: &impl Settings, url: &str) -> eyre::Result<Request> {
+    // The token is a secret, so error messages only mention the URL without it.
+    let request = match (
+        settings.get("CEM_TOKEN"),
+        settings.get("CEM_TOKEN_QUERY_PARAMETER"),
+    ) {
+        (Some(token), Some(parameter)) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!("{url}{separator}{parameter}={}", percent_encode(&token))
+                .into_client_request()
+                .wrap_err_with(|| format!("Invalid CEM URL {url}"))?
+        }
+        (Some(token), None) => {
+            let mut request = url
+                .into_client_request()
+                .wrap_err_with(|| format!("Invalid CEM URL {url}"))?;
+            let header = HeaderValue::from_str(&format!("Bearer {token}"))
+                .wrap_err("CEM_TOKEN contains characters that are not allowed in a header")?;
+            request.headers_mut().insert(AUTHORIZATION, header);
+            request
+        }
+        (None, _) => url
+            .into_client_request()
+            .wrap_err_with(|| format!("Invalid CEM URL {url}"))?,
+    };
+    Ok(request)
+}
+
+/// Percent-encodes everything except unreserved characters, so the value can be used in a query string.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Runs the given simulator on the S2 connection until the CEM terminates the session, or until the simulator is
+/// stopped with Ctrl-C or SIGTERM.
+///
+/// This performs the initial handshake with the CEM, and checks that the control type it selected is one the simulator
+/// supports. The events in the timeline are passed to the simulator as they happen, except for disconnects, which
+/// drop the connection. Events that are injected through the HTTP server or stdin are handled the same way. When the
+/// simulation is stopped, the CEM is told that the session is terminated before the connection is closed, so stopping
+/// a container (which sends SIGTERM) ends the session cleanly.
+///
+/// When the CEM selects a control type again during the session, the simulator starts over with the initial messages
+/// for the new selection.
+///
+/// In a co-simulation, the co-simulation moves simulated time on: the simulator sends its periodic update at every step,
+/// after the events in the timeline up to the step, and it stops when the co-simulation ends.
+///
+/// When the connection with the CEM is lost, the CEM stops responding to pings or it asks to reconnect, the simulation
+/// goes on: the simulator reconnects after a delay, sets up a new session, and then sends the messages it produced in the meantime.
+/// Like a real device, it keeps its resource ID in every session, so the CEM can tell it's the same RM.
+///
+/// When the connection was made to check the CEM (see [`connect`]), this stops once the session is set up and the CEM
+/// acknowledged the initial messages, and prints a summary of what the CEM answered instead of simulating.
+pub async fn run(
+    mut connection: Connection,
+    mut simulator: impl RmSimulator,
+    timeline: Timeline,
+) -> eyre::Result<()> {
+    // The update interval is in simulated time, which can run faster than real time.
+    let update_interval = time::real_duration(simulator.update_interval());
+    let rm_details = simulator.resource_manager_details();
+    let resource_id = rm_details.resource_id;
+    let mut validator = Validator::new(rm_details.available_control_types);
+    let Some(control_type) = set_up_session(
+        &mut connection,
+        &mut simulator,
+        &resource_id,
+        update_interval,
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+    validator.start_session(control_type);
+    if connection.receipts().is_some() {
+        return check::finish(connection, validator, control_type).await;
+    }
+
+    let mut update_timer = tokio::time::interval(update_interval);
+    let mut reception_status_timer = tokio::time::interval(RECEPTION_STATUS_CHECK_INTERVAL);
+    let mut reconnect_at = None;
+    let session_start = time::now();
+    let mut events = timeline.events().peekable();
+    let mut injected_events = connection
+        .take_injected_events()
+        .ok_or_else(|| eyre!("The simulation already ran on this connection"))?;
+    let stop = connection.monitor().stop.clone();
+    let mut cosimulation = connection.take_cosimulation();
+    // The step of the co-simulation that simulated time moved on to, until the simulator is updated for it.
+    let mut current_step: Option<Step> = None;
+    loop {
+        // Whatever happened last may have changed the state of the device.
+        connection
+            .monitor()
+            .set_device_state(simulator.device_state());
+        if !connection.is_connected() && reconnect_at.is_none() {
+            let delay = connection.reconnect_delay();
+            tracing::warn!("Reconnecting to the CEM in {delay:?}");
+            reconnect_at = Some(tokio::time::Instant::now() + delay);
+        }
+
+        // Messages and updates interrupt the sleep until the next event, so recalculate how long it still takes.
+        let until_next_event = events.peek().and_then(|(at, _)| {
+            let remaining = (session_start + *at - time::now())
+                .to_std()
+                .unwrap_or_default();
+            // In a co-simulation, simulated time only moves on at the next step.
+            (remaining.is_zero() || !time::is_cosimulated()).then(|| time::real_duration(remaining))
+        });
+        let event_due = until_next_event.is_some_and(|until| until.is_zero());
+        let next_event = tokio::time::sleep(until_next_event.unwrap_or_default());
+        let reconnect =
+            tokio::time::sleep_until(reconnect_at.unwrap_or_else(tokio::time::Instant::now));
+        tokio::select! {
+            message = connection.receive(), if connection.is_connected() => {
+                let message = match message {
+                    Ok(message) => message,
+                    // The next iteration schedules reconnecting.
+                    Err(_) if !connection.is_connected() => continue,
+                    Err(error) => return Err(error),
+                };
+                if let Err(rejection) = validator.validate(&message) {
+                    tracing::warn!(
+                        "Rejecting {} {:?} from the CEM: {rejection}",
+                        connection::message_type(&message),
+                        message.id()
+                    );
+                    connection.acknowledge(&message, Err(rejection)).await?;
+                    continue;
+                }
+                // Session requests are about the connection rather than the device, so they're handled here.
+                if let Message::SessionRequest(request) = &message {
+                    connection.acknowledge(&message, Ok(())).await?;
+                    simulator.on_session_request(request);
+                    let reason = request.diagnostic_label.as_deref().unwrap_or("no reason given");
+                    match request.request {
+                        SessionRequestType::Terminate => {
+                            tracing::warn!("The CEM terminated the session ({reason}), stopping simulation.");
+                            break;
+                        }
+                        SessionRequestType::Reconnect => {
+                            // The next iteration schedules reconnecting, like after a lost connection.
+                            tracing::warn!("The CEM asked to reconnect ({reason}), closing the connection.");
+                            connection.close().await;
+                            continue;
+                        }
+                    }
+                }
+                if let Message::SelectControlType(select_control_type) = &message {
+                    connection.acknowledge(&message, Ok(())).await?;
+                    validator.select_control_type(select_control_type.control_type);
+                    change_control_type(
+                        &mut connection,
+                        &mut simulator,
+                        select_control_type.control_type,
+                        update_interval,
+                    )
+                    .await?;
+                    continue;
+                }
+                let span = connection::message_span("s2.receive", &message);
+                async {
+                    // A message the simulator can't handle is reported to the CEM, rather than ending the simulation.
+                    let responses = match simulator.process_message(&message) {
+                        Ok(responses) => responses,
+                        Err(error) => {
+                            tracing::warn!(
+                                "Could not process {} {:?}: {error:#}",
+                                connection::message_type(&message),
+                                message.id()
+                            );
+                            let rejection = Rejection::permanent_error(format!("{error:#}"));
+                            return connection.acknowledge(&message, Err(rejection)).await;
+                        }
+                    };
+                    connection.acknowledge(&message, Ok(())).await?;
+                    for response in responses {
+                        connection.send_message(response).await?;
+                    }
+                    eyre::Ok(())
+                }
+                .instrument(span)
+                .await?;
+            }
+
+            _ = reconnect, if reconnect_at.is_some() => {
+                reconnect_at = None;
+                if let Err(error) = connection.reconnect().await {
+                    tracing::warn!("{error:#}");
+                    continue;
+                }
+                match set_up_session(&mut connection, &mut simulator, &resource_id, update_interval).await {
+                    Ok(Some(control_type)) => validator.start_session(control_type),
+                    Ok(None) => return Ok(()),
+                    Err(error) if !connection.is_connected() => tracing::warn!("{error:#}"),
+                    Err(error) => return Err(error),
+                }
+            }
+
+            _ = update_timer.tick(), if cosimulation.is_none() => {
+                periodic_update(&mut connection, &mut simulator).await?;
+            }
+
+            step = next_step(&mut cosimulation), if current_step.is_none() => match step {
+                Some(step) => {
+                    time::advance_to(step.elapsed);
+                    current_step = Some(step);
+                }
+                None => {
+                    tracing::warn!("The co-simulation ended, stopping simulation.");
+                    connection.send_message(termination("the co-simulation ended")).await?;
+                    break;
+                }
+            },
+
+            // The events in the timeline up to the step go first.
+            _ = std::future::ready(()), if current_step.is_some() && !event_due => {
+                periodic_update(&mut connection, &mut simulator).await?;
+                // What the co-simulation reads next is the state after the update.
+                connection.monitor().set_device_state(simulator.device_state());
+                if let Some(step) = current_step.take() {
+                    step.finish(simulator.update_interval());
+                }
+            }
+
+            _ = reception_status_timer.tick() => connection.check_reception_statuses().await?,
+
+            _ = next_event, if until_next_event.is_some() => {
+                let Some((_, event)) = events.next() else { continue };
+                if *event == TimelineEvent::Disconnect {
+                    // Dropping the connection closes it without telling the CEM, like a device that loses its network.
+                    tracing::warn!("Disconnecting from the CEM, as scheduled in the timeline");
+                    return Ok(());
+                }
+                tracing::info!("Timeline event: {event:?}");
+                for message in simulator.handle_event(event)? {
+                    connection.send_message(message).await?;
+                }
+            }
+
+            Some(event) = injected_events.recv() => {
+                if event == TimelineEvent::Disconnect {
+                    tracing::warn!("Disconnecting from the CEM, as requested through an injected event");
+                    return Ok(());
+                }
+                tracing::info!("Injected event: {event:?}");
+                for message in simulator.handle_event(&event)? {
+                    connection.send_message(message).await?;
+                }
+            }
+
+            signal = shutdown_signal(&stop) => {
+                tracing::warn!("Received {signal}, stopping simulation.");
+                connection.send_message(termination(signal)).await?;
+                break;
+            }
+        }
+    }
+
+    connection.close().await;
+    Ok(())
+}
+
+/// Sends the periodic update of the simulator to the CEM, and records the state of the device after it.
+async fn periodic_update(
+    connection: &mut Connection,
+    simulator: &mut impl RmSimulator,
+) -> eyre::Result<()> {
+    for update in simulator.periodic_update().await? {
+        connection.send_message(update).await?;
+    }
+    connection.monitor().health.updated();
+    connection
+        .monitor()
+        .add_to_history(&simulator.device_state())
+}
+
+/// Waits for the next step of the co-simulation, or returns `None` once it ended; forever if there's none.
+async fn next_step(cosimulation: &mut Option<Cosimulation>) -> Option<Step> {
+    match cosimulation {
+        Some(cosimulation) => cosimulation.next_step().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The request to terminate the session when the simulator stops, because of `reason`.
+fn termination(reason: &str) -> SessionRequest {
+    SessionRequest {
+        diagnostic_label: Some(format!("Session terminated by the simulator ({reason})")),
+        message_id: Id::generate(),
+        request: SessionRequestType::Terminate,
+    }
+}
+
+/// Performs the handshake with the CEM as the RM with `resource_id` and sends the initial messages for the control
+/// type it selected, and then the messages that were queued in the meantime.
+///
+/// Returns the selected control type, or `None` if the simulator was stopped before the session was set up.
+async fn set_up_session(
+    connection: &mut Connection,
+    simulator: &mut impl RmSimulator,
+    resource_id: &Id,
+    update_interval: Duration,
+) -> eyre::Result<Option<ControlType>> {
+    let rm_details = ResourceManagerDetails {
+        resource_id: resource_id.clone(),
+        ..simulator.resource_manager_details()
+    };
+    let available_control_types = rm_details.available_control_types.clone();
+    let stop = connection.monitor().stop.clone();
+    let control_type = tokio::select! {
+        control_type = connection.initialize_as_rm(rm_details) => {
+            control_type.wrap_err("Error communicating initial info with CEM")?
+        }
+        // There's no session to terminate yet.
+        signal = shutdown_signal(&stop) => {
+            tracing::warn!("Received {signal}, stopping simulation before the session was set up.");
+            return Ok(None);
+        }
+    };
+    if !is_supported(&available_control_types, control_type) {
+        return Err(eyre!(
+            "The CEM wants a control type not supported by this simulator: {control_type:?}"
+        ));
+    }
+
+    for message in simulator.initial_messages(control_type)? {
+        connection.send_now(message).await?;
+    }
+    connection
+        .monitor()
+        .health
+        .set_control_type(control_type, update_interval);
+    connection.session_ready().await?;
+    Ok(Some(control_type))
+}
+
+/// Switches to the control type the CEM selected during the session: the simulator drops what it was doing under the
+/// previous selection, and sends the initial messages for the new one as if the session had just started.
+///
+/// Selecting `NoSelection` turns control off, so then there's nothing to send until the CEM selects a control type
+/// again. The [`Validator`] already rejected control types the simulator doesn't support.
+async fn change_control_type(
+    connection: &mut Connection,
+    simulator: &mut impl RmSimulator,
+    control_type: ControlType,
+    update_interval: Duration,
+) -> eyre::Result<()> {
+    let available_control_types = simulator.resource_manager_details().available_control_types;
+    let deactivating = control_type == ControlType::NoSelection
+        && !available_control_types.contains(&ControlType::NotControlable);
+    tracing::info!("The CEM selected control type {control_type:?}, starting over");
+    simulator.deactivate_control_type();
+    if !deactivating {
+        for message in simulator.initial_messages(control_type)? {
+            connection.send_message(message).await?;
+        }
+    }
+    connection
+        .monitor()
+        .health
+        .set_control_type(control_type, update_interval);
+    Ok(())
+}
+
+/// Whether the CEM may select the given control type for a device with these available control types.
+fn is_supported(available_control_types: &[ControlType], control_type: ControlType) -> bool {
+    // A CEM doesn't have to select a control type for a device that can't be controlled.
+    available_control_types.contains(&control_type)
+        || (control_type == ControlType::NoSelection
+            && available_control_types.contains(&ControlType::NotControlable))
+}
+
+/// Waits until the process is asked to stop, with Ctrl-C or SIGTERM (as sent by `docker stop`) or from the terminal UI,
+/// and returns how it was asked.
+async fn shutdown_signal(stop: &Notify) -> &'static str {
+    tokio::select! {
+        signal = signal() => signal,
+        _ = stop.notified() => "a stop from the terminal UI",
+    }
+}
+
+/// Waits for Ctrl-C or SIGTERM, and returns which signal was received.
+async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "Ctrl-C",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(error) => {
+                tracing::warn!("Could not listen for SIGTERM: {error}");
+                let _ = tokio::signal::ctrl_c().await;
+                "Ctrl-C"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
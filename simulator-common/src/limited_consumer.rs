@@ -0,0 +1,261 @@
+//! An RM for a real device that consumes power and can be limited in it, such as an EEBus heat pump or a Matter EV
+//! charger, which the gateways in this repository share.
+
+use crate::{rm_details, time, DeviceState, RmSimulator};
+use chrono::{DateTime, TimeDelta, Utc};
+use s2energy::common::{
+    CommodityQuantity, ControlType, Id, InstructionStatus, InstructionStatusUpdate, Message,
+    NumberRange, PowerMeasurement, PowerValue, ResourceManagerDetails, RoleType,
+};
+use s2energy::pebc;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// The commodity quantity the power of the device is reported and limited in, as the device reports its total power.
+const COMMODITY_QUANTITY: CommodityQuantity = CommodityQuantity::ElectricPower3PhaseSymmetric;
+
+/// An upper limit on the power of the device from a power envelope, with its start and end time resolved.
+struct Limit {
+    upper_limit_w: f64,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    /// Sequence number of the instruction this limit came from; higher numbers are more recent.
+    instruction_sequence: u64,
+}
+
+/// A real device that consumes power and can be limited in it, which a [`LimitedConsumer`] passes the power envelopes
+/// of the CEM on to.
+pub trait LimitableDevice {
+    /// What the device is called, such as `EEBus device`: the name the RM announces it with.
+    fn name(&self) -> &str;
+
+    /// Adds what's known about the device, such as its manufacturer and model, to the details the RM announces it
+    /// with.
+    fn describe(&self, details: ResourceManagerDetails) -> ResourceManagerDetails {
+        details
+    }
+
+    /// The power the device consumes in W, unless it hasn't reported it recently.
+    fn power_w(&self) -> Option<f64>;
+
+    /// Limits the power the device consumes to `limit_w`, or lifts the limit with `None`.
+    fn set_limit(&self, limit_w: Option<f64>);
+}
+
+/// An RM that passes the power envelopes of the CEM on to a device as a limit on its power consumption, and reports
+/// the power the device measures.
+///
+/// To the CEM, the device is a consumer that can be limited to anything between 0 W and its maximum power. The CEM
+/// can't make it consume more, so the lower limit is always 0 W. Whenever the power envelopes change the limit, the
+/// new limit is passed on to the device, such as with the LPC use case of EEBus.
+pub struct LimitedConsumer<D> {
+    device: D,
+    /// The most the device consumes, which is the highest limit the CEM can set.
+    max_power_w: f64,
+    update_interval: Duration,
+    /// The IDs of the power constraints we sent to the CEM, which never expire.
+    power_constraints: HashSet<Id>,
+    /// The limits from the power envelopes we received.
+    limits: Vec<Limit>,
+    /// The number of instructions received so far, used to determine which instruction is the most recent.
+    instructions_received: u64,
+    /// The limit the device currently has, if any.
+    limit_w: Option<f64>,
+    /// The power in the latest measurement.
+    last_power_w: Option<f64>,
+}
+
+impl<D: LimitableDevice> LimitedConsumer<D> {
+    pub fn new(device: D, max_power_w: f64, update_interval: Duration) -> Self {
+        Self {
+            device,
+            max_power_w,
+            update_interval,
+            power_constraints: HashSet::new(),
+            limits: Vec::new(),
+            instructions_received: 0,
+            limit_w: None,
+            last_power_w: None,
+        }
+    }
+
+    fn power_constraints(&mut self) -> pebc::PowerConstraints {
+        let power_constraints = pebc::PowerConstraints {
+            allowed_limit_ranges: vec![
+                pebc::AllowedLimitRange {
+                    abnormal_condition_only: false,
+                    commodity_quantity: COMMODITY_QUANTITY,
+                    limit_type: pebc::PowerEnvelopeLimitType::UpperLimit,
+                    range_boundary: NumberRange::new(0.0, self.max_power_w),
+                },
+                pebc::AllowedLimitRange {
+                    abnormal_condition_only: false,
+                    commodity_quantity: COMMODITY_QUANTITY,
+                    limit_type: pebc::PowerEnvelopeLimitType::LowerLimit,
+                    range_boundary: NumberRange::new(0.0, 0.0),
+                },
+            ],
+            consequence_type: pebc::PowerEnvelopeConsequenceType::Defer,
+            id: Id::generate(),
+            message_id: Id::generate(),
+            valid_from: time::now(),
+            valid_until: None,
+        };
+        self.power_constraints.insert(power_constraints.id.clone());
+        power_constraints
+    }
+
+    /// Checks whether the given instruction fits within the power constraints, and returns the problem if it doesn't.
+    fn validate_instruction(&self, instruction: &pebc::Instruction) -> Result<(), String> {
+        if !self
+            .power_constraints
+            .contains(&instruction.power_constraints_id)
+        {
+            return Err(format!(
+                "it refers to unknown power constraints {:?}",
+                instruction.power_constraints_id
+            ));
+        }
+        for envelope in &instruction.power_envelopes {
+            if envelope.commodity_quantity != COMMODITY_QUANTITY {
+                return Err(format!(
+                    "envelope {:?} is for {:?}, while the device is limited in {COMMODITY_QUANTITY:?}",
+                    envelope.id, envelope.commodity_quantity
+                ));
+            }
+            for element in &envelope.power_envelope_elements {
+                if element.lower_limit != 0.0 {
+                    return Err(format!(
+                        "envelope {:?} has lower limit {}, while the device can only be limited from above",
+                        envelope.id, element.lower_limit
+                    ));
+                }
+                if !(0.0..=self.max_power_w).contains(&element.upper_limit) {
+                    return Err(format!(
+                        "upper limit {} in envelope {:?} is outside 0 to {} W",
+                        element.upper_limit, envelope.id, self.max_power_w
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the upper limits of the power envelopes in the given instruction.
+    fn add_instruction(&mut self, instruction: &pebc::Instruction) {
+        self.instructions_received += 1;
+        for envelope in &instruction.power_envelopes {
+            // Elements follow each other, so every element starts where the previous one ended.
+            let mut start_time = instruction.execution_time;
+            for element in &envelope.power_envelope_elements {
+                let end_time = start_time + TimeDelta::milliseconds(element.duration.0 as i64);
+                self.limits.push(Limit {
+                    upper_limit_w: element.upper_limit,
+                    start_time,
+                    end_time,
+                    instruction_sequence: self.instructions_received,
+                });
+                start_time = end_time;
+            }
+        }
+        self.limits.retain(|limit| limit.end_time > time::now());
+    }
+
+    /// Passes the limit that applies now on to the device, which is the one from the most recent instruction. Outside
+    /// the power envelopes, the device has no limit.
+    fn update_limit(&mut self) {
+        let now = time::now();
+        let limit_w = self
+            .limits
+            .iter()
+            .filter(|limit| limit.start_time <= now && now < limit.end_time)
+            .max_by_key(|limit| limit.instruction_sequence)
+            .map(|limit| limit.upper_limit_w);
+        self.limit_w = limit_w;
+        self.device.set_limit(limit_w);
+    }
+}
+
+impl<D: LimitableDevice> RmSimulator for LimitedConsumer<D> {
+    fn resource_manager_details(&self) -> ResourceManagerDetails {
+        self.device.describe(ResourceManagerDetails {
+            name: Some(self.device.name().into()),
+            provides_forecast: false,
+            ..rm_details::new(
+                vec![ControlType::PowerEnvelopeBasedControl],
+                RoleType::EnergyConsumer,
+            )
+        })
+    }
+
+    fn initial_messages(&mut self, _control_type: ControlType) -> eyre::Result<Vec<Message>> {
+        Ok(vec![self.power_constraints().into()])
+    }
+
+    fn deactivate_control_type(&mut self) {
+        // Without power envelopes, the device may consume as much as it wants again.
+        self.limits.clear();
+        self.update_limit();
+    }
+
+    fn on_pebc_instruction(
+        &mut self,
+        instruction: &pebc::Instruction,
+    ) -> eyre::Result<Vec<Message>> {
+        let status_type = match self.validate_instruction(instruction) {
+            Ok(()) => {
+                self.add_instruction(instruction);
+                self.update_limit();
+                InstructionStatus::Succeeded
+            }
+            Err(reason) => {
+                tracing::warn!("Rejecting instruction {:?}: {reason}", instruction.id);
+                InstructionStatus::Rejected
+            }
+        };
+        let instruction_status = InstructionStatusUpdate {
+            instruction_id: instruction.id.clone(),
+            message_id: Id::generate(),
+            status_type,
+            timestamp: time::now(),
+        };
+        Ok(vec![instruction_status.into()])
+    }
+
+    /// Sends the power the device measured every update, and follows the power envelopes as they move on to their
+    /// next elements.
+    async fn periodic_update(&mut self) -> eyre::Result<Vec<Message>> {
+        self.update_limit();
+
+        self.last_power_w = self.device.power_w();
+        let Some(power_w) = self.last_power_w else {
+            tracing::warn!(
+                "The {} hasn't reported its power recently, so no power measurement is sent",
+                self.device.name()
+            );
+            return Ok(vec![]);
+        };
+        let power_measurement = PowerMeasurement {
+            measurement_timestamp: time::now(),
+            message_id: Id::generate(),
+            values: vec![PowerValue {
+                commodity_quantity: COMMODITY_QUANTITY,
+                value: power_w,
+            }],
+        };
+        tracing::info!("Sending power measurement: {power_measurement:?}");
+        Ok(vec![power_measurement.into()])
+    }
+
+    fn update_interval(&self) -> Duration {
+        self.update_interval
+    }
+
+    fn device_state(&self) -> DeviceState {
+        DeviceState {
+            power_w: self.last_power_w,
+            rated_power_w: Some(self.limit_w.unwrap_or(self.max_power_w)),
+            ..DeviceState::default()
+        }
+    }
+}
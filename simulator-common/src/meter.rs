@@ -0,0 +1,271 @@
+use crate::{mqtt, Settings};
+use eyre::{eyre, Context};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use s2energy::common::{CommodityQuantity, PowerMeasurement};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How long to wait before reconnecting when the connection with the broker is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A WiFi power meter, such as a Shelly or a Tasmota plug, that measures the power of the device instead of the
+/// simulation.
+///
+/// Its readings replace the electric power in the power measurements the simulator sends, and the power on the
+/// dashboard, while the flexibility the simulator describes to the CEM stays simulated. The meter is read in the
+/// background, through its local HTTP API or the MQTT broker it publishes to. Readings count for three intervals, after
+/// which no power measurements are sent until the meter is read again.
+pub(crate) struct PowerMeter {
+    readings: watch::Receiver<Option<Reading>>,
+    interval: Duration,
+}
+
+/// The brand of the power meter, which decides where the power is in what it reports.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Shelly,
+    Tasmota,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    power_w: f64,
+    at: Instant,
+}
+
+impl PowerMeter {
+    /// Starts reading the meter in the `POWER_METER` setting (`SHELLY` or `TASMOTA`), if it's set.
+    ///
+    /// The meter is polled at `POWER_METER_URL` every `POWER_METER_INTERVAL` seconds [default: 10], or its readings are
+    /// taken from `POWER_METER_TOPIC` on the broker in `MQTT_BROKER`, which the meter should publish to at least that
+    /// often. Every reading is multiplied by `POWER_METER_SCALE` [default: 1], such as -1 for a meter that measures
+    /// production as positive power.
+    pub(crate) fn from_settings(settings: &impl Settings) -> eyre::Result<Option<Self>> {
+        let kind = match settings.get("POWER_METER").as_deref() {
+            None => return Ok(None),
+            Some("SHELLY") => Kind::Shelly,
+            Some("TASMOTA") => Kind::Tasmota,
+            Some(other) => {
+                return Err(eyre!(
+                    "Invalid value for POWER_METER ({other}); should be SHELLY or TASMOTA"
+                ))
+            }
+        };
+        let interval = Duration::from_secs(settings.get_or("POWER_METER_INTERVAL", 10)?);
+        if interval.is_zero() {
+            return Err(eyre!("POWER_METER_INTERVAL should be at least 1 second"));
+        }
+        let scale: f64 = settings.get_or("POWER_METER_SCALE", 1.0)?;
+
+        let (sender, readings) = watch::channel(None);
+        let publish = move |power_w: f64| {
+            tracing::debug!("The power meter measured {:.0} W", power_w * scale);
+            sender.send_replace(Some(Reading {
+                power_w: power_w * scale,
+                at: Instant::now(),
+            }));
+        };
+        match (settings.get("POWER_METER_URL"), settings.get("POWER_METER_TOPIC")) {
+            (Some(url), None) => {
+                let client = reqwest::Client::builder()
+                    .timeout(Duration::from_secs(10))
+                    .build()
+                    .wrap_err("Could not set up HTTP client")?;
+                let url = url.trim_end_matches('/').to_string();
+                tracing::info!("Measuring the power with the {kind:?} power meter at {url}");
+                tokio::spawn(poll(kind, client, url, interval, publish));
+            }
+            (None, Some(topic)) => {
+                let broker = settings.get("MQTT_BROKER").ok_or_else(|| {
+                    eyre!("POWER_METER_TOPIC needs the address of the broker in MQTT_BROKER")
+                })?;
+                // The bridge may use the same broker, so the meter needs a client of its own.
+                let prefix = mqtt::topic_prefix(settings)?;
+                let options = mqtt::options(settings, &broker, &format!("{prefix}-meter"))?;
+                let (client, event_loop) = AsyncClient::new(options, 10);
+                tracing::info!("Measuring the power with the {kind:?} power meter on {topic}");
+                tokio::spawn(subscribe(kind, client, event_loop, broker, topic, publish));
+            }
+            (Some(_), Some(_)) => {
+                return Err(eyre!(
+                    "POWER_METER_URL and POWER_METER_TOPIC can't both be set; the meter is either polled or publishes"
+                ))
+            }
+            (None, None) => {
+                return Err(eyre!(
+                    "POWER_METER is set, but neither POWER_METER_URL nor POWER_METER_TOPIC says where the meter is"
+                ))
+            }
+        }
+
+        Ok(Some(Self { readings, interval }))
+    }
+
+    /// The power at the latest reading in W, positive for consumption, unless it's too old.
+    pub(crate) fn power_w(&self) -> Option<f64> {
+        let reading = (*self.readings.borrow())?;
+        (reading.at.elapsed() < 3 * self.interval).then_some(reading.power_w)
+    }
+
+    /// Replaces the electric power in the measurement with the latest reading, split equally over the phases it's
+    /// measured on. Returns false if there's no recent reading, so the measurement shouldn't be sent.
+    pub(crate) fn measure(&self, measurement: &mut PowerMeasurement) -> bool {
+        let Some(power_w) = self.power_w() else {
+            return false;
+        };
+        let is_phase = |quantity: &CommodityQuantity| {
+            matches!(
+                quantity,
+                CommodityQuantity::ElectricPowerL1
+                    | CommodityQuantity::ElectricPowerL2
+                    | CommodityQuantity::ElectricPowerL3
+            )
+        };
+        let phases = measurement
+            .values
+            .iter()
+            .filter(|value| is_phase(&value.commodity_quantity))
+            .count();
+        for value in &mut measurement.values {
+            if is_phase(&value.commodity_quantity) {
+                value.value = power_w / phases as f64;
+            } else if value.commodity_quantity == CommodityQuantity::ElectricPower3PhaseSymmetric {
+                value.value = power_w;
+            }
+        }
+        true
+    }
+}
+
+/// Reads the meter through its local HTTP API every `interval`.
+async fn poll(
+    kind: Kind,
+    client: reqwest::Client,
+    url: String,
+    interval: Duration,
+    publish: impl Fn(f64),
+) {
+    // Shelly devices of the first generation don't have the RPC API of the later ones.
+    let mut shelly_gen1 = false;
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let path = match kind {
+            Kind::Shelly if shelly_gen1 => "/status",
+            Kind::Shelly => "/rpc/Shelly.GetStatus",
+            Kind::Tasmota => "/cm?cmnd=Status%208",
+        };
+        let result = async {
+            let response = client.get(format!("{url}{path}")).send().await?;
+            if matches!(kind, Kind::Shelly)
+                && !shelly_gen1
+                && response.status() == reqwest::StatusCode::NOT_FOUND
+            {
+                tracing::info!(
+                    "The Shelly at {url} has no RPC API, so it's read as a first generation device"
+                );
+                shelly_gen1 = true;
+                return Ok(None);
+            }
+            let status: Value = response.error_for_status()?.json().await?;
+            kind.power_w(&status)
+                .map(Some)
+                .ok_or_else(|| eyre!("Could not find the power in {status}"))
+        }
+        .await;
+
+        match result {
+            Ok(Some(power_w)) => publish(power_w),
+            // Read again right away, from the API of the first generation.
+            Ok(None) => interval.reset_immediately(),
+            Err(error) => tracing::warn!("Could not read the power meter at {url}: {error:#}"),
+        }
+    }
+}
+
+/// Takes the readings of the meter from what it publishes on `topic`.
+async fn subscribe(
+    kind: Kind,
+    client: AsyncClient,
+    mut event_loop: rumqttc::EventLoop,
+    broker: String,
+    topic: String,
+    publish: impl Fn(f64),
+) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // The session isn't kept by the broker, so the topic is subscribed to again.
+                if let Err(error) = client.try_subscribe(&topic, QoS::AtMostOnce) {
+                    tracing::warn!("Could not subscribe to {topic}: {error}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) => {
+                let power_w = serde_json::from_slice(&message.payload)
+                    .ok()
+                    .and_then(|payload| kind.power_w(&payload));
+                match power_w {
+                    Some(power_w) => publish(power_w),
+                    None => tracing::debug!(
+                        "Could not find the power in {}",
+                        String::from_utf8_lossy(&message.payload)
+                    ),
+                }
+            }
+            Ok(_) => {}
+            Err(error) => {
+                tracing::warn!(
+                    "No connection with MQTT broker {broker} for the power meter: {error}"
+                );
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+impl Kind {
+    /// Finds the power in W in what the meter reports, over HTTP or MQTT.
+    fn power_w(self, status: &Value) -> Option<f64> {
+        match self {
+            // Tasmota reports the power of every channel in its sensor readings, in the status over HTTP and in the
+            // telemetry over MQTT.
+            Kind::Tasmota => {
+                let energy = status.get("StatusSNS").unwrap_or(status).get("ENERGY")?;
+                match &energy["Power"] {
+                    Value::Array(powers) => powers.iter().map(Value::as_f64).sum(),
+                    power => power.as_f64(),
+                }
+            }
+            Kind::Shelly => {
+                // First generation devices publish the power of a relay as a plain number over MQTT, and later ones
+                // the status of a single component.
+                if let Some(power_w) = status.as_f64().or_else(|| shelly_component_power(status)) {
+                    return Some(power_w);
+                }
+                // The status of the whole device has the components of later generations, such as `switch:0`, or
+                // the meters of the first.
+                let components = status
+                    .as_object()?
+                    .iter()
+                    .filter(|(name, _)| name.contains(':'))
+                    .filter_map(|(_, component)| shelly_component_power(component));
+                let meters = ["meters", "emeters"]
+                    .into_iter()
+                    .filter_map(|field| status[field].as_array())
+                    .flatten()
+                    .filter_map(|meter| meter["power"].as_f64());
+                components
+                    .chain(meters)
+                    .reduce(|total, power_w| total + power_w)
+            }
+        }
+    }
+}
+
+/// The active power of a component of a Shelly, such as a switch or an energy meter.
+fn shelly_component_power(component: &Value) -> Option<f64> {
+    ["apower", "act_power", "total_act_power"]
+        .into_iter()
+        .find_map(|field| component[field].as_f64())
+}
@@ -0,0 +1,97 @@
+use crate::connection::Direction;
+use crate::dashboard::Dashboard;
+use crate::health::Health;
+use crate::history::History;
+use crate::influx::InfluxSink;
+use crate::meter::PowerMeter;
+use crate::mqtt::MqttBridge;
+use crate::{DeviceState, TimelineEvent};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+/// What the connection and the simulation loop report about the simulator, for the HTTP server, the MQTT bridge and
+/// InfluxDB, and the way back for events that are injected through the HTTP server or stdin.
+pub(crate) struct Monitor {
+    pub(crate) health: Health,
+    pub(crate) dashboard: Dashboard,
+    pub(crate) mqtt: Option<MqttBridge>,
+    /// The CSV file the state of the device is written to at every periodic update, if enabled.
+    history: Option<Mutex<History>>,
+    /// The InfluxDB the state of the device and the messages are written to, if enabled.
+    influx: Option<InfluxSink>,
+    /// The power meter that measures the device instead of the simulation, if there is one.
+    pub(crate) meter: Option<PowerMeter>,
+    pub(crate) events: UnboundedSender<TimelineEvent>,
+    /// Notified when the simulation is stopped from the terminal UI.
+    pub(crate) stop: Arc<Notify>,
+}
+
+impl Monitor {
+    /// Creates a monitor, and the receiver of the events that are injected through the HTTP server or stdin.
+    pub(crate) fn new(
+        mqtt: Option<MqttBridge>,
+        history: Option<History>,
+        influx: Option<InfluxSink>,
+        meter: Option<PowerMeter>,
+    ) -> (Self, UnboundedReceiver<TimelineEvent>) {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let monitor = Self {
+            health: Health::default(),
+            dashboard: Dashboard::default(),
+            mqtt,
+            history: history.map(Mutex::new),
+            influx,
+            meter,
+            events,
+            stop: Arc::default(),
+        };
+        (monitor, receiver)
+    }
+
+    pub(crate) fn set_device_state(&self, device: DeviceState) {
+        let device = self.measured(device);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_state(&device);
+        }
+        self.dashboard.set_device_state(device);
+    }
+
+    /// Adds the state of the device to the history and InfluxDB, after a periodic update.
+    pub(crate) fn add_to_history(&self, device: &DeviceState) -> eyre::Result<()> {
+        if self.history.is_none() && self.influx.is_none() {
+            return Ok(());
+        }
+        let device = self.measured(device.clone());
+        if let Some(influx) = &self.influx {
+            influx.write_state(&device);
+        }
+        match &self.history {
+            Some(history) => history.lock().unwrap().write(&device),
+            None => Ok(()),
+        }
+    }
+
+    /// The state of the device with the power the meter measured, if there is one.
+    fn measured(&self, device: DeviceState) -> DeviceState {
+        match &self.meter {
+            Some(meter) => DeviceState {
+                power_w: meter.power_w(),
+                ..device
+            },
+            None => device,
+        }
+    }
+
+    /// Passes on a message that was sent to or received from the CEM.
+    pub(crate) fn message(&self, direction: Direction, message: &Value) {
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_message(direction, message);
+        }
+        if let Some(influx) = &self.influx {
+            influx.write_message(direction, message);
+        }
+        self.dashboard.log(direction, message);
+    }
+}
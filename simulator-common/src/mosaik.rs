@@ -0,0 +1,259 @@
+use crate::monitor::Monitor;
+use crate::Settings;
+use eyre::{bail, eyre, Context};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+/// The version of the mosaik API the simulator speaks.
+const API_VERSION: &str = "3.0";
+/// The model of the simulated device, which is the only model.
+const MODEL: &str = "Device";
+/// The ID of the entity of the simulated device.
+const ENTITY: &str = "device";
+/// The attributes of the device that other simulators can read.
+const ATTRIBUTES: [&str; 4] = ["P_MW", "SOC", "curtailment_MW", "operation_mode"];
+/// The longest message mosaik may send, in bytes, as a guard against a peer that doesn't speak its API.
+const MAX_MESSAGE_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// A co-simulation with [mosaik](https://mosaik.offis.de), which moves simulated time on and reads the power of the
+/// device for other simulators, such as a grid simulator.
+///
+/// mosaik connects to the simulator, which it knows as a simulator with one entity `device` of the model `Device`. At
+/// every step, simulated time moves on to the time of the step and the simulator sends its periodic update to the CEM,
+/// and the next step is an update interval later. Between steps, the simulator keeps handling the messages of the CEM.
+/// The device has these attributes, which other simulators can take as their inputs:
+///
+/// - `P_MW`: the power of the device in MW, positive for consumption like the loads of a grid simulator;
+/// - `SOC`: the state of charge of a storage device, as a fraction;
+/// - `curtailment_MW`: how much less the device produces than it could because of the instructions of the CEM;
+/// - `operation_mode`: the name of the active operation mode.
+pub(crate) struct Cosimulation {
+    steps: mpsc::Receiver<Step>,
+}
+
+/// A step of the co-simulation, to the given time since the start of simulated time.
+pub(crate) struct Step {
+    pub(crate) elapsed: Duration,
+    /// Where to tell when the next step should be, in simulated time from this one.
+    next: oneshot::Sender<Duration>,
+}
+
+impl Step {
+    /// Ends the step, and asks for the next one after `interval`.
+    pub(crate) fn finish(self, interval: Duration) {
+        // Without mosaik, there's no next step to ask for.
+        let _ = self.next.send(interval);
+    }
+}
+
+impl Cosimulation {
+    /// Waits for mosaik to connect on the address in the `MOSAIK_ADDRESS` setting in the background, if it's set.
+    ///
+    /// After every step, the simulator waits `MOSAIK_STEP_DELAY_MS` milliseconds of real time [default: 0] before
+    /// mosaik moves on, to give the CEM time to respond to the update.
+    pub(crate) async fn from_settings(
+        settings: &impl Settings,
+        monitor: Arc<Monitor>,
+    ) -> eyre::Result<Option<Self>> {
+        let Some(address) = settings.get("MOSAIK_ADDRESS") else {
+            return Ok(None);
+        };
+        let step_delay = Duration::from_millis(settings.get_or("MOSAIK_STEP_DELAY_MS", 0)?);
+        let listener = TcpListener::bind(&address)
+            .await
+            .wrap_err_with(|| format!("Could not listen on {address}"))?;
+        tracing::info!("Waiting for mosaik to connect on {address}");
+        let (steps, receiver) = mpsc::channel(1);
+        let session = Session {
+            monitor,
+            steps,
+            step_delay,
+            time_resolution: 1.0,
+            created: false,
+        };
+        tokio::spawn(async move {
+            // There's one co-simulation, and the simulation ends with it when the steps are dropped.
+            let result = async {
+                let (stream, peer) = listener.accept().await?;
+                tracing::info!("mosaik connected from {peer}");
+                session.run(stream).await
+            }
+            .await;
+            match result {
+                Ok(()) => tracing::info!("The co-simulation ended"),
+                Err(error) => tracing::warn!("The co-simulation failed: {error:#}"),
+            }
+        });
+        Ok(Some(Self { steps: receiver }))
+    }
+
+    /// Waits for the next step, or returns `None` once the co-simulation ended.
+    pub(crate) async fn next_step(&mut self) -> Option<Step> {
+        self.steps.recv().await
+    }
+}
+
+/// Answers the requests of mosaik, in its low-level API: every message is a 4-byte big-endian length followed by a
+/// JSON array of the type, the ID and the content of the message.
+struct Session {
+    monitor: Arc<Monitor>,
+    steps: mpsc::Sender<Step>,
+    step_delay: Duration,
+    /// How many seconds of simulated time a step of mosaik is.
+    time_resolution: f64,
+    /// Whether mosaik created the entity of the device.
+    created: bool,
+}
+
+impl Session {
+    /// Answers every request until mosaik stops the co-simulation or disconnects.
+    async fn run(mut self, mut stream: TcpStream) -> eyre::Result<()> {
+        loop {
+            let Some(request) = read(&mut stream).await? else {
+                return Ok(());
+            };
+            // A request is [0, id, [method, args, kwargs]].
+            let (Some(0), Some(id), Some(method)) = (
+                request[0].as_u64(),
+                request[1].as_u64(),
+                request[2][0].as_str(),
+            ) else {
+                bail!("mosaik sent something other than a request: {request}");
+            };
+            let args = request[2][1].as_array().cloned().unwrap_or_default();
+            let reply = match self.handle(method, &args, &request[2][2]).await {
+                Ok(result) => json!([1, id, result]),
+                Err(error) => {
+                    tracing::warn!("Could not answer {method} from mosaik: {error:#}");
+                    json!([2, id, format!("{error:#}")])
+                }
+            };
+            write(&mut stream, &reply).await?;
+            if method == "stop" {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        method: &str,
+        args: &[Value],
+        kwargs: &Value,
+    ) -> eyre::Result<Value> {
+        match method {
+            "init" => {
+                let time_resolution = kwargs["time_resolution"]
+                    .as_f64()
+                    .or_else(|| args.get(1).and_then(Value::as_f64))
+                    .unwrap_or(1.0);
+                if !(time_resolution > 0.0 && time_resolution.is_finite()) {
+                    bail!("The time resolution should be a positive number of seconds, not {time_resolution}");
+                }
+                self.time_resolution = time_resolution;
+                Ok(json!({
+                    "api_version": API_VERSION,
+                    "type": "time-based",
+                    "models": {
+                        MODEL: {
+                            "public": true,
+                            "params": [],
+                            "attrs": ATTRIBUTES,
+                        },
+                    },
+                    "extra_methods": [],
+                }))
+            }
+            "create" => {
+                let model = args.get(1).and_then(Value::as_str).unwrap_or_default();
+                if model != MODEL {
+                    bail!("This simulator only has the model {MODEL}, not {model}");
+                }
+                if self.created || args.first().and_then(Value::as_u64) != Some(1) {
+                    bail!("This simulator simulates one device, so it has one entity");
+                }
+                self.created = true;
+                Ok(json!([{ "eid": ENTITY, "type": MODEL }]))
+            }
+            "setup_done" | "stop" => Ok(Value::Null),
+            "step" => {
+                let time = args
+                    .first()
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| eyre!("The step has no time"))?;
+                let (next, interval) = oneshot::channel();
+                let step = Step {
+                    elapsed: Duration::from_secs_f64(time as f64 * self.time_resolution),
+                    next,
+                };
+                let ended = || eyre!("The simulation ended");
+                self.steps.send(step).await.map_err(|_| ended())?;
+                let interval = interval.await.map_err(|_| ended())?;
+                tokio::time::sleep(self.step_delay).await;
+                let steps = (interval.as_secs_f64() / self.time_resolution)
+                    .ceil()
+                    .max(1.0);
+                Ok(json!(time + steps as u64))
+            }
+            "get_data" => {
+                let requested = args
+                    .first()
+                    .and_then(Value::as_object)
+                    .ok_or_else(|| eyre!("get_data needs the attributes to get"))?;
+                let device = self.monitor.dashboard.state().device;
+                let mut data = Map::new();
+                for (entity, attributes) in requested {
+                    if entity != ENTITY {
+                        bail!("There's no entity {entity}, only {ENTITY}");
+                    }
+                    let mut values = Map::new();
+                    for attribute in attributes.as_array().into_iter().flatten() {
+                        let attribute = attribute.as_str().unwrap_or_default();
+                        let value = match attribute {
+                            "P_MW" => json!(device.power_w.map(|power_w| power_w / 1e6)),
+                            "SOC" => json!(device.state_of_charge),
+                            "curtailment_MW" => json!(device
+                                .curtailment_w
+                                .map(|curtailment_w| curtailment_w / 1e6)),
+                            "operation_mode" => json!(device.operation_mode),
+                            _ => bail!("The device has no attribute {attribute}"),
+                        };
+                        values.insert(attribute.into(), value);
+                    }
+                    data.insert(entity.clone(), Value::Object(values));
+                }
+                Ok(Value::Object(data))
+            }
+            _ => bail!("This simulator doesn't support {method}"),
+        }
+    }
+}
+
+/// Reads a message from mosaik, or returns `None` if mosaik closed the connection.
+async fn read(stream: &mut TcpStream) -> eyre::Result<Option<Value>> {
+    let length = match stream.read_u32().await {
+        Ok(length) => length,
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    if length > MAX_MESSAGE_LENGTH {
+        bail!("mosaik sent a message of {length} bytes, which is more than this simulator reads");
+    }
+    let mut message = vec![0; length as usize];
+    stream.read_exact(&mut message).await?;
+    let message =
+        serde_json::from_slice(&message).wrap_err("mosaik sent a message that isn't JSON")?;
+    Ok(Some(message))
+}
+
+/// Writes a message to mosaik.
+async fn write(stream: &mut TcpStream, message: &Value) -> eyre::Result<()> {
+    let message = serde_json::to_vec(message)?;
+    stream.write_u32(message.len() as u32).await?;
+    stream.write_all(&message).await?;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use crate::connection::Direction;
+use crate::home_assistant;
+use crate::{DeviceState, Settings};
+use eyre::{eyre, Context};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait before reconnecting when the connection with the broker is lost.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Publishes the state of the simulator to an MQTT broker, so it can be followed from home and building automation.
+///
+/// Everything is published under a topic prefix:
+///
+/// - `<prefix>/availability`: `online` while the simulator is connected to the broker, and `offline` otherwise;
+/// - `<prefix>/state`: the [`DeviceState`] as JSON, whenever it changes;
+/// - `<prefix>/measurements`: the power measurements the simulator sends to the CEM;
+/// - `<prefix>/instructions`: the instructions from the CEM, and the status updates the simulator sends for them.
+///
+/// The availability and the state are retained, so new subscribers see them right away. Measurements and instructions
+/// are the S2 messages as JSON. With a discovery prefix, the bridge also announces the state to Home Assistant.
+pub(crate) struct MqttBridge {
+    client: AsyncClient,
+    prefix: String,
+    /// The prefix of the topics for Home Assistant MQTT discovery, if enabled.
+    discovery_prefix: Option<String>,
+    /// What was published since the last time the bridge connected to the broker.
+    published: Arc<Mutex<Published>>,
+}
+
+#[derive(Default)]
+struct Published {
+    /// The state that was published last, to only publish changes.
+    state: Option<DeviceState>,
+    /// The fields of the state that were announced to Home Assistant.
+    announced: HashSet<&'static str>,
+}
+
+impl MqttBridge {
+    /// Connects to the broker in the `MQTT_BROKER` setting (`host` or `host:port`), if it's set.
+    ///
+    /// `MQTT_USERNAME` and `MQTT_PASSWORD` are used to log in, and `MQTT_TOPIC_PREFIX` sets the prefix of the topics
+    /// [default: `s2-simulator`]. If `MQTT_DISCOVERY_PREFIX` is set, usually to `homeassistant`, the state is announced
+    /// for Home Assistant MQTT discovery. The connection is kept up in the background, so the simulator keeps running
+    /// while the broker is unavailable.
+    pub(crate) fn from_settings(settings: &impl Settings) -> eyre::Result<Option<Self>> {
+        let Some(broker) = settings.get("MQTT_BROKER") else {
+            return Ok(None);
+        };
+        let prefix = topic_prefix(settings)?;
+        let mut options = options(settings, &broker, &prefix)?;
+        let availability = format!("{prefix}/availability");
+        options.set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        let (client, mut event_loop) = AsyncClient::new(options, 100);
+        let online_client = client.clone();
+        let published = Arc::new(Mutex::new(Published::default()));
+        let reconnected = published.clone();
+        tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        tracing::info!("Connected to MQTT broker {broker}");
+                        let result = online_client.try_publish(
+                            &availability,
+                            QoS::AtLeastOnce,
+                            true,
+                            "online",
+                        );
+                        if let Err(error) = result {
+                            tracing::warn!("Could not publish to MQTT: {error}");
+                        }
+                        // The broker may have lost the retained messages, so publish them again.
+                        *reconnected.lock().unwrap() = Published::default();
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        tracing::warn!("No connection with MQTT broker {broker}: {error}");
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+        tracing::info!("Publishing the state of the simulator to MQTT under {prefix}/");
+        Ok(Some(Self {
+            client,
+            prefix,
+            discovery_prefix: settings.get("MQTT_DISCOVERY_PREFIX"),
+            published,
+        }))
+    }
+
+    pub(crate) fn publish_state(&self, state: &DeviceState) {
+        let mut published = self.published.lock().unwrap();
+        if published.state.as_ref() == Some(state) {
+            return;
+        }
+        published.state = Some(state.clone());
+        if let Some(discovery_prefix) = &self.discovery_prefix {
+            let messages =
+                home_assistant::discovery_messages(discovery_prefix, &self.prefix, state);
+            for (field, topic, payload) in messages {
+                if published.announced.insert(field) {
+                    self.publish_to(topic, true, payload);
+                }
+            }
+        }
+        match serde_json::to_string(state) {
+            Ok(payload) => self.publish("state", true, payload),
+            Err(error) => tracing::warn!("Could not publish the state to MQTT: {error}"),
+        }
+    }
+
+    pub(crate) fn publish_message(&self, direction: Direction, message: &Value) {
+        let message_type = message["message_type"].as_str().unwrap_or_default();
+        let topic = match (direction, message_type) {
+            (Direction::Sent, "PowerMeasurement") => "measurements",
+            (Direction::Received, message_type) if message_type.ends_with(".Instruction") => {
+                "instructions"
+            }
+            (Direction::Sent, "InstructionStatusUpdate") => "instructions",
+            _ => return,
+        };
+        self.publish(topic, false, message.to_string());
+    }
+
+    fn publish(&self, topic: &str, retain: bool, payload: String) {
+        self.publish_to(format!("{}/{topic}", self.prefix), retain, payload);
+    }
+
+    fn publish_to(&self, topic: String, retain: bool, payload: String) {
+        // While the broker is unavailable, the queue fills up and messages are dropped; the event loop warns about that.
+        if let Err(error) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            tracing::debug!("Could not publish to MQTT topic {topic}: {error}");
+        }
+    }
+}
+
+/// The prefix of the topics in the `MQTT_TOPIC_PREFIX` setting [default: `s2-simulator`].
+pub(crate) fn topic_prefix(settings: &impl Settings) -> eyre::Result<String> {
+    let prefix = settings
+        .get("MQTT_TOPIC_PREFIX")
+        .unwrap_or_else(|| "s2-simulator".into());
+    let prefix = prefix.trim_end_matches('/').to_string();
+    if prefix.is_empty() || prefix.contains(['+', '#']) {
+        return Err(eyre!(
+            "Invalid MQTT_TOPIC_PREFIX ({prefix}); it can't be empty or contain wildcards"
+        ));
+    }
+    Ok(prefix)
+}
+
+/// The options to connect to `broker` (`host` or `host:port`) with, logging in with `MQTT_USERNAME` and
+/// `MQTT_PASSWORD`. The client ID is derived from `name`.
+pub(crate) fn options(
+    settings: &impl Settings,
+    broker: &str,
+    name: &str,
+) -> eyre::Result<MqttOptions> {
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .wrap_err_with(|| format!("Invalid port in MQTT_BROKER ({broker})"))?;
+            (host.to_string(), port)
+        }
+        None => (broker.to_string(), 1883),
+    };
+    // Client IDs have to be unique on a broker, and several simulators may run on the same machine.
+    let client_id = format!("{}-{}", name.replace('/', "-"), std::process::id());
+    let mut options = MqttOptions::new(client_id, host, port);
+    if let Some(username) = settings.get("MQTT_USERNAME") {
+        options.set_credentials(username, settings.get("MQTT_PASSWORD").unwrap_or_default());
+    }
+    Ok(options)
+}
@@ -0,0 +1,72 @@
+//! Reproducible randomness for the simulators.
+//!
+//! Everything a simulator does at random, such as passing clouds, is derived from a single seed. Running a simulation
+//! again with the same `SEED` setting gives exactly the same behaviour, which is useful for regression tests of a CEM.
+
+use crate::Settings;
+use eyre::Context;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const GOLDEN_GAMMA: u64 = 0x9E3779B97F4A7C15;
+
+/// Returns the seed in the `SEED` setting, or picks a new one if it isn't set.
+///
+/// The seed is logged either way, so a run with interesting behaviour can be repeated.
+pub fn seed(settings: &impl Settings) -> eyre::Result<u64> {
+    let seed = settings
+        .get("SEED")
+        .map(|seed| seed.parse())
+        .transpose()
+        .wrap_err("Could not parse SEED as a whole number")?
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+        });
+    tracing::info!("Using seed {seed} for random behaviour; set SEED={seed} to repeat this run");
+    Ok(seed)
+}
+
+/// A pseudo-random number generator (SplitMix64), which generates the same sequence for the same seed.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Returns the next pseudo-random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        mix(self.state)
+    }
+
+    /// Returns the next pseudo-random value from 0.0 (inclusive) to 1.0 (exclusive).
+    pub fn next_f64(&mut self) -> f64 {
+        to_unit(self.next_u64())
+    }
+}
+
+/// Returns a pseudo-random value from 0.0 (inclusive) to 1.0 (exclusive) for the given index, based on the seed.
+///
+/// Unlike [`Rng`], this gives the same value for an index no matter which values were asked for before, so a model can
+/// use it to pick a random value for a moment in time (e.g. the index of the hour).
+pub fn value_at(seed: u64, index: i64) -> f64 {
+    to_unit(mix(
+        seed.wrapping_add((index as u64).wrapping_mul(GOLDEN_GAMMA))
+    ))
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Turns the 53 most significant bits into a value from 0.0 to 1.0.
+fn to_unit(value: u64) -> f64 {
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
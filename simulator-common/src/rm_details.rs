@@ -0,0 +1,63 @@
+//! Presets for the [`ResourceManagerDetails`] a simulator announces itself with.
+//!
+//! Every preset fills in the details that are the same for most devices of its kind, with new IDs. Simulators change
+//! what's different for them with struct update syntax:
+//!
+//! ```
+//! # use s2energy::common::{ControlType, Currency, ResourceManagerDetails};
+//! # use simulator_common::rm_details;
+//! let details = ResourceManagerDetails {
+//!     currency: Some(Currency::Eur),
+//!     ..rm_details::battery()
+//! };
+//! ```
+
+use s2energy::common::{
+    Commodity, CommodityQuantity, ControlType, Duration, Id, ResourceManagerDetails, Role, RoleType,
+};
+
+/// Details for a device with the given control types and role, without a manufacturer, model or serial number.
+///
+/// The device says it sends forecasts and measures its power on three symmetric phases, and takes a millisecond to carry
+/// out an instruction.
+pub fn new(available_control_types: Vec<ControlType>, role: RoleType) -> ResourceManagerDetails {
+    ResourceManagerDetails {
+        available_control_types,
+        currency: None,
+        firmware_version: None,
+        instruction_processing_delay: Duration(1),
+        manufacturer: None,
+        message_id: Id::generate(),
+        model: None,
+        name: None,
+        provides_forecast: true,
+        provides_power_measurement_types: vec![CommodityQuantity::ElectricPower3PhaseSymmetric],
+        resource_id: Id::generate(),
+        roles: vec![Role::new(Commodity::Electricity, role)],
+        serial_number: None,
+    }
+}
+
+/// Details for a battery, which is controlled with FRBC and takes 10 ms to carry out an instruction.
+pub fn battery() -> ResourceManagerDetails {
+    ResourceManagerDetails {
+        instruction_processing_delay: Duration(10),
+        ..new(
+            vec![ControlType::FillRateBasedControl],
+            RoleType::EnergyConsumer,
+        )
+    }
+}
+
+/// Details for a PV installation that's controlled with the given control type, or `NotControlable`, made by the
+/// example manufacturer ACME, Inc.
+pub fn pv(control_type: ControlType) -> ResourceManagerDetails {
+    ResourceManagerDetails {
+        firmware_version: Some("1.0.0".into()),
+        manufacturer: Some("ACME, Inc.".into()),
+        model: Some("Generic PV Installation Model X".into()),
+        name: Some("The Amazing ACEM, Inc. PV Installation Model X".into()),
+        serial_number: Some("111-222-333-444-555".into()),
+        ..new(vec![control_type], RoleType::EnergyProducer)
+    }
+}
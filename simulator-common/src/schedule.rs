@@ -0,0 +1,161 @@
+//! Doing things every so many periodic updates, such as sending a forecast every hour.
+//!
+//! Simulators send everything from [`RmSimulator::periodic_update`](crate::RmSimulator::periodic_update), so these
+//! count updates instead of running timers of their own. That keeps everything a simulator sends in step with its
+//! updates, and follows the time scale of the simulation, as the updates do.
+
+use crate::random::Rng;
+use std::time::Duration;
+
+/// Keeps track of something a simulator does every so many periodic updates, such as sending a forecast.
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    /// The number of updates between two occurrences.
+    every: u32,
+    /// The number of updates left until the next occurrence.
+    remaining: u32,
+}
+
+impl Schedule {
+    /// Creates a schedule that is due at the first update, and then once every `period`.
+    ///
+    /// The period is rounded down to a whole number of updates, but is at least one update.
+    pub fn new(period: Duration, update_interval: Duration) -> Self {
+        let every = ((period.as_secs_f64() / update_interval.as_secs_f64()) as u32).max(1);
+        Self {
+            every,
+            remaining: 0,
+        }
+    }
+
+    /// Creates a schedule that is first due after one `period`, for things the initial messages already took care of.
+    pub fn starting_after(period: Duration, update_interval: Duration) -> Self {
+        let schedule = Self::new(period, update_interval);
+        Self {
+            remaining: schedule.every,
+            ..schedule
+        }
+    }
+
+    /// Counts an update, and returns whether the schedule is due.
+    pub fn is_due(&mut self) -> bool {
+        if self.remaining == 0 {
+            self.remaining = self.every - 1;
+            true
+        } else {
+            self.remaining -= 1;
+            false
+        }
+    }
+
+    /// Makes the schedule due at the next update, after which it continues once every period.
+    pub fn reset_immediately(&mut self) {
+        self.remaining = 0;
+    }
+}
+
+/// The periodic tasks of a simulator, such as sending measurements, forecasts and constraints, by name.
+///
+/// Every task has a [`Schedule`] of its own. [`tick`](Scheduler::tick) counts an update for all of them at once, so a
+/// simulator calls it once in every periodic update and then does the tasks that are due:
+///
+/// ```
+/// # use simulator_common::Scheduler;
+/// # use std::time::Duration;
+/// let mut scheduler = Scheduler::new(Duration::from_secs(60))
+///     .every("measurement", Duration::from_secs(60))
+///     .every("forecast", Duration::from_secs(3600));
+/// assert_eq!(scheduler.tick(), ["measurement", "forecast"]);
+/// assert_eq!(scheduler.tick(), ["measurement"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    update_interval: Duration,
+    tasks: Vec<Task>,
+    /// Picks the jitter of the tasks; `None` if no task has any.
+    rng: Option<Rng>,
+}
+
+#[derive(Debug, Clone)]
+struct Task {
+    name: &'static str,
+    schedule: Schedule,
+    /// How many updates an occurrence may be early or late.
+    jitter: u32,
+}
+
+impl Scheduler {
+    /// Creates a scheduler without tasks, for a simulator with the given update interval.
+    pub fn new(update_interval: Duration) -> Self {
+        Self {
+            update_interval,
+            tasks: Vec::new(),
+            rng: None,
+        }
+    }
+
+    /// Adds a task that is due at the first update, and then once every `period`, like [`Schedule::new`].
+    pub fn every(mut self, name: &'static str, period: Duration) -> Self {
+        self.add(name, Schedule::new(period, self.update_interval));
+        self
+    }
+
+    /// Adds a task that is first due after one `period`, for things the initial messages already took care of, like
+    /// [`Schedule::starting_after`].
+    pub fn every_after(mut self, name: &'static str, period: Duration) -> Self {
+        self.add(name, Schedule::starting_after(period, self.update_interval));
+        self
+    }
+
+    /// Lets the tasks added so far happen up to `jitter` earlier or later than their period, picked at random for every
+    /// occurrence, like a real device that doesn't send its messages on the dot.
+    ///
+    /// The jitter is rounded down to a whole number of updates, so it needs to be at least the update interval to make
+    /// a difference. The randomness is derived from the `seed` (see [`random`](crate::random)), so runs with the same
+    /// seed are the same.
+    pub fn with_jitter(mut self, jitter: Duration, seed: u64) -> Self {
+        let jitter = (jitter.as_secs_f64() / self.update_interval.as_secs_f64()) as u32;
+        for task in &mut self.tasks {
+            task.jitter = jitter;
+        }
+        self.rng.get_or_insert_with(|| Rng::new(seed));
+        self
+    }
+
+    fn add(&mut self, name: &'static str, schedule: Schedule) {
+        self.tasks.push(Task {
+            name,
+            schedule,
+            jitter: 0,
+        });
+    }
+
+    /// Counts an update, and returns the names of the tasks that are due, in the order they were added.
+    pub fn tick(&mut self) -> Vec<&'static str> {
+        let mut due = Vec::new();
+        for task in &mut self.tasks {
+            if !task.schedule.is_due() {
+                continue;
+            }
+            due.push(task.name);
+            if let (Some(rng), jitter @ 1..) = (&mut self.rng, task.jitter) {
+                // Anywhere from `jitter` updates early to `jitter` updates late, but never before the next update.
+                let offset =
+                    (rng.next_f64() * f64::from(2 * jitter + 1)) as i64 - i64::from(jitter);
+                let remaining = i64::from(task.schedule.remaining) + offset;
+                task.schedule.remaining = remaining.max(0) as u32;
+            }
+        }
+        due
+    }
+
+    /// Makes the task with this name due at the next update, after which it continues once every period. Does nothing
+    /// if there's no task with this name.
+    pub fn reset_immediately(&mut self, name: &str) {
+        for task in &mut self.tasks {
+            if task.name == name {
+                task.schedule.reset_immediately();
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use eyre::Context;
+use std::str::FromStr;
+
+/// A source of configuration values for the simulators, looked up by the name of their environment variable.
+///
+/// The simulators are configured through environment variables by default, but the same settings can come from
+/// elsewhere, such as command line arguments.
+pub trait Settings {
+    /// Returns the value of the given setting, if it is set.
+    fn get(&self, name: &str) -> Option<String>;
+
+    /// Reads and parses the given setting, or returns `default` if it isn't set.
+    fn get_or<T>(&self, name: &str, default: T) -> eyre::Result<T>
+    where
+        T: FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .wrap_err_with(|| format!("Invalid value for {name} ({value})")),
+            None => Ok(default),
+        }
+    }
+
+    /// Combines these settings with `fallback`, which is used for the settings that aren't set here.
+    fn or<S: Settings>(self, fallback: S) -> Or<Self, S>
+    where
+        Self: Sized,
+    {
+        Or {
+            settings: self,
+            fallback,
+        }
+    }
+}
+
+/// Settings with a fallback for the settings that aren't set; see [`Settings::or`].
+pub struct Or<A, B> {
+    settings: A,
+    fallback: B,
+}
+
+impl<A: Settings, B: Settings> Settings for Or<A, B> {
+    fn get(&self, name: &str) -> Option<String> {
+        self.settings.get(name).or_else(|| self.fallback.get(name))
+    }
+}
+
+/// Settings read from environment variables.
+pub struct EnvSettings;
+
+impl Settings for EnvSettings {
+    fn get(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+}
@@ -0,0 +1,134 @@
+use crate::tui::LogWriter;
+use crate::Settings;
+use eyre::{eyre, Context};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use rolling_file::{RollingConditionBasic, RollingFileAppender};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Keeps the trace export running; the remaining spans are exported when this is dropped.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(error) = provider.shutdown() {
+                eprintln!("Could not export the remaining traces: {error}");
+            }
+        }
+    }
+}
+
+/// Sets up logging, and exports traces over OTLP (HTTP) if `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+///
+/// If the `TUI` setting is `true`, the log is shown in the terminal UI while it's shown, rather than written to stdout.
+/// If the `LOG_PATH` setting is set, the log is also written to that file; see [`log_file`] for how it's rotated.
+///
+/// Every message from the CEM gets a span that covers processing it and sending the replies, with the S2 message ID
+/// as the `s2.message_id` attribute, so traces of the RM can be matched with those of the CEM. The service is named
+/// after `OTEL_SERVICE_NAME`, or `default_service_name` if that isn't set.
+pub fn init(settings: &impl Settings, default_service_name: &str) -> eyre::Result<Telemetry> {
+    let mut log = tracing_subscriber::fmt::layer().with_writer(LogWriter::new);
+    if settings.get_or("TUI", false)? {
+        // Colours would show up as escape codes in the terminal UI.
+        log = log.with_ansi(false);
+    }
+    let file_log = log_file(settings)?.map(|file| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(Mutex::new(file))
+            .with_ansi(false)
+    });
+    let Some(endpoint) = settings.get("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+        tracing_subscriber::registry()
+            .with(LevelFilter::INFO)
+            .with(log)
+            .with(file_log)
+            .init();
+        return Ok(Telemetry { provider: None });
+    };
+
+    // Like other OpenTelemetry SDKs, the endpoint is the base URL of the collector.
+    let traces_endpoint = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&traces_endpoint)
+        .build()
+        .wrap_err_with(|| format!("Invalid value for OTEL_EXPORTER_OTLP_ENDPOINT ({endpoint})"))?;
+    let service_name = settings
+        .get("OTEL_SERVICE_NAME")
+        .unwrap_or_else(|| default_service_name.into());
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+
+    // The same level as the default subscriber, which also keeps the spans of libraries out of the traces.
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(log)
+        .with(file_log)
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("s2-simulator")))
+        .init();
+    tracing::info!("Exporting traces to {traces_endpoint}");
+    Ok(Telemetry {
+        provider: Some(provider),
+    })
+}
+
+/// Opens the log file in the `LOG_PATH` setting, if it's set.
+///
+/// The file is rotated every day, or every hour or never according to `LOG_ROTATION` (`DAILY`, `HOURLY` or `NEVER`),
+/// and whenever it grows beyond `LOG_MAX_SIZE_MB` (10 by default; 0 turns this off). Rotated files get a number, like
+/// `simulator.log.1` for the newest, and only the newest `LOG_MAX_FILES` (7 by default) are kept. An existing file is
+/// appended to, so the log survives restarts.
+fn log_file(
+    settings: &impl Settings,
+) -> eyre::Result<Option<RollingFileAppender<RollingConditionBasic>>> {
+    let Some(path) = settings.get("LOG_PATH") else {
+        return Ok(None);
+    };
+    let condition = RollingConditionBasic::new();
+    let condition = match settings
+        .get("LOG_ROTATION")
+        .unwrap_or_else(|| "DAILY".into())
+        .to_uppercase()
+        .as_str()
+    {
+        "DAILY" => condition.daily(),
+        "HOURLY" => condition.hourly(),
+        "NEVER" => condition,
+        other => {
+            return Err(eyre!(
+                "Invalid value for LOG_ROTATION ({other}); should be DAILY, HOURLY or NEVER"
+            ))
+        }
+    };
+    let max_size_mb: u64 = settings.get_or("LOG_MAX_SIZE_MB", 10)?;
+    let condition = match max_size_mb {
+        0 => condition,
+        max_size_mb => condition.max_size(max_size_mb * 1024 * 1024),
+    };
+    let max_files = settings.get_or("LOG_MAX_FILES", 7)?;
+
+    let path = Path::new(&path);
+    if let Some(directory) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(directory).wrap_err_with(|| {
+            format!("Could not create the log directory {}", directory.display())
+        })?;
+    }
+    // Without a buffer, every line is in the file as soon as it's logged, also when the simulator crashes.
+    let file = RollingFileAppender::new_with_buffer_capacity(path, condition, max_files, 0)
+        .wrap_err_with(|| format!("Could not open log file {}", path.display()))?;
+    Ok(Some(file))
+}
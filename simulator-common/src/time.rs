@@ -0,0 +1,119 @@
+//! Simulated time, which can run faster than real time.
+//!
+//! Simulators use [`now`] instead of `Utc::now()` for everything: the state of the simulated device, timers and the
+//! timestamps in messages. With a time scale of 60, a simulated hour takes a minute, so a 24-hour scenario can be run
+//! in 24 minutes. In a co-simulation, such as with mosaik, the co-simulation moves simulated time on instead.
+
+use crate::Settings;
+use chrono::{DateTime, TimeDelta, Utc};
+use eyre::eyre;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+static CLOCK: OnceLock<Clock> = OnceLock::new();
+
+struct Clock {
+    /// The moment the clock was started, in real time.
+    real_start: Instant,
+    /// The moment the clock was started, in simulated time (which is the real time at that moment).
+    simulated_start: DateTime<Utc>,
+    /// How much faster than real time simulated time runs.
+    time_scale: f64,
+    /// How far a co-simulation moved simulated time on since the start, in milliseconds, if a co-simulation drives the
+    /// clock instead of real time.
+    cosimulated_ms: Option<AtomicI64>,
+}
+
+/// Starts simulated time, running at the speed in the `TIME_SCALE` setting (1 by default, which is real time).
+///
+/// If the `MOSAIK_ADDRESS` setting is set, a mosaik co-simulation moves simulated time on instead, from the time in the
+/// `MOSAIK_START` setting (an RFC 3339 timestamp) or the real time at the start.
+///
+/// This should be called before the simulation starts. If it isn't called, simulated time is real time. When several
+/// simulators run in one process, they share the clock that was started first, so they need the same time scale.
+pub fn init(settings: &impl Settings) -> eyre::Result<()> {
+    let time_scale: f64 = settings.get_or("TIME_SCALE", 1.0)?;
+    if !(time_scale > 0.0 && time_scale.is_finite()) {
+        return Err(eyre!("TIME_SCALE should be a positive number"));
+    }
+    let cosimulated = settings.get("MOSAIK_ADDRESS").is_some();
+    if cosimulated && time_scale != 1.0 {
+        return Err(eyre!(
+            "TIME_SCALE can't be used with MOSAIK_ADDRESS, as the co-simulation moves simulated time on"
+        ));
+    }
+    let simulated_start = match settings.get("MOSAIK_START").filter(|_| cosimulated) {
+        Some(start) => DateTime::parse_from_rfc3339(&start)
+            .map_err(|_| {
+                eyre!("Invalid value for MOSAIK_START ({start}); should be a time such as 2025-06-01T00:00:00Z")
+            })?
+            .to_utc(),
+        None => Utc::now(),
+    };
+
+    let mut started = false;
+    let clock = CLOCK.get_or_init(|| {
+        started = true;
+        Clock {
+            real_start: Instant::now(),
+            simulated_start,
+            time_scale,
+            cosimulated_ms: cosimulated.then(|| AtomicI64::new(0)),
+        }
+    });
+    if clock.time_scale != time_scale {
+        return Err(eyre!(
+            "Simulated time already runs at TIME_SCALE {}, so it can't run at {time_scale}",
+            clock.time_scale
+        ));
+    }
+    if clock.cosimulated_ms.is_some() != cosimulated {
+        return Err(eyre!(
+            "Simulators in the same process share the clock, so a co-simulation can drive all of them or none"
+        ));
+    }
+    if started && time_scale != 1.0 {
+        tracing::info!("Running the simulation {time_scale} times as fast as real time");
+    }
+    Ok(())
+}
+
+/// Returns the current simulated time.
+pub fn now() -> DateTime<Utc> {
+    match CLOCK.get() {
+        Some(Clock {
+            simulated_start,
+            cosimulated_ms: Some(cosimulated_ms),
+            ..
+        }) => *simulated_start + TimeDelta::milliseconds(cosimulated_ms.load(Ordering::Relaxed)),
+        Some(clock) => {
+            let elapsed = clock.real_start.elapsed().mul_f64(clock.time_scale);
+            clock.simulated_start
+                + TimeDelta::from_std(elapsed).expect("Simulated time is out of range")
+        }
+        None => Utc::now(),
+    }
+}
+
+/// Returns how long the given duration in simulated time takes in real time.
+pub fn real_duration(simulated: Duration) -> Duration {
+    match CLOCK.get() {
+        Some(clock) => simulated.div_f64(clock.time_scale),
+        None => simulated,
+    }
+}
+
+/// Whether a co-simulation moves simulated time on, rather than real time.
+pub(crate) fn is_cosimulated() -> bool {
+    CLOCK
+        .get()
+        .is_some_and(|clock| clock.cosimulated_ms.is_some())
+}
+
+/// Moves simulated time on to the given time since the start, in a co-simulation.
+pub(crate) fn advance_to(elapsed: Duration) {
+    if let Some(cosimulated_ms) = CLOCK.get().and_then(|clock| clock.cosimulated_ms.as_ref()) {
+        cosimulated_ms.store(elapsed.as_millis() as i64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,142 @@
+use eyre::{bail, Context};
+use serde::{Deserialize, Deserializer};
+use std::path::Path;
+use std::time::Duration;
+
+/// Events that happen at fixed moments during a simulation, read from a YAML file.
+///
+/// Every simulator can react to the events that make sense for it through
+/// [`RmSimulator::handle_event`](crate::RmSimulator::handle_event), and ignores the others. Because the moments are
+/// relative to the start of the session, a timeline gives the same sequence of events in every run.
+#[derive(Debug, Default, Clone)]
+pub struct Timeline {
+    /// The events, sorted by the moment they happen.
+    entries: Vec<TimelineEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct TimelineEntry {
+    /// How long after the start of the session the event happens, in simulated time.
+    #[serde(deserialize_with = "deserialize_duration")]
+    at: Duration,
+    #[serde(flatten)]
+    event: TimelineEvent,
+}
+
+/// Something that happens to a simulated device.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    /// The state of charge of a storage device jumps to the given fraction (0.0 to 1.0).
+    StateOfCharge { value: f64 },
+    /// The usable capacity of a storage device changes to the given fraction of its nominal capacity.
+    Capacity { value: f64 },
+    /// The device stops working for a while.
+    Outage {
+        #[serde(deserialize_with = "deserialize_duration")]
+        duration: Duration,
+    },
+    /// The demand of the device rises by the given power for a while.
+    DemandSpike {
+        power_w: f64,
+        #[serde(deserialize_with = "deserialize_duration")]
+        duration: Duration,
+    },
+    /// The irradiance on the panels of a PV installation is the given value (W/m²) for a while, whatever the weather.
+    Irradiance {
+        w_per_m2: f64,
+        #[serde(deserialize_with = "deserialize_duration")]
+        duration: Duration,
+    },
+    /// A cloud passes over the panels of a PV installation, and only lets through the given fraction (0.0 to 1.0) of
+    /// the production for a while.
+    Cloud {
+        transmittance: f64,
+        #[serde(deserialize_with = "deserialize_duration")]
+        duration: Duration,
+    },
+    /// The connection with the CEM is dropped without terminating the session, which stops the simulator.
+    Disconnect,
+}
+
+impl Timeline {
+    /// Reads a timeline from the YAML file at the given path.
+    ///
+    /// The file contains a list of events, each with an `at` time (e.g. `90s`, `15m` or `2h`, or a number of seconds)
+    /// and an `event`, plus the fields of that event; see `timeline-example.yaml`.
+    pub fn from_path(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read timeline {}", path.display()))?;
+        let mut entries: Vec<TimelineEntry> = serde_yaml::from_str(&contents)
+            .wrap_err_with(|| format!("Invalid timeline {}", path.display()))?;
+
+        for entry in &entries {
+            if let Err(problem) = entry.event.validate() {
+                bail!(
+                    "Invalid timeline {}: in the event at {:?}, {problem}",
+                    path.display(),
+                    entry.at
+                )
+            }
+        }
+        entries.sort_by_key(|entry| entry.at);
+        Ok(Self { entries })
+    }
+
+    /// Returns the events with the moment they happen, in order.
+    pub fn events(&self) -> impl Iterator<Item = (Duration, &TimelineEvent)> {
+        self.entries.iter().map(|entry| (entry.at, &entry.event))
+    }
+}
+
+impl TimelineEvent {
+    /// Checks that the values of the event make sense, and describes the problem if they don't.
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        match *self {
+            TimelineEvent::StateOfCharge { value }
+            | TimelineEvent::Capacity { value }
+            | TimelineEvent::Cloud {
+                transmittance: value,
+                ..
+            } if !(0.0..=1.0).contains(&value) => {
+                Err("the value should be a fraction (0.0 to 1.0)")
+            }
+            TimelineEvent::Irradiance { w_per_m2, .. } if !(0.0..).contains(&w_per_m2) => {
+                Err("the irradiance can't be negative")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Deserializes a duration written as a number of seconds, or as a number followed by `s`, `m` or `h`.
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDuration {
+        Seconds(f64),
+        Text(String),
+    }
+
+    let (value, unit) = match RawDuration::deserialize(deserializer)? {
+        RawDuration::Seconds(seconds) => (seconds, 1.0),
+        RawDuration::Text(text) => {
+            let text = text.trim();
+            let (value, unit) = match text.char_indices().last() {
+                Some((index, 's')) => (&text[..index], 1.0),
+                Some((index, 'm')) => (&text[..index], 60.0),
+                Some((index, 'h')) => (&text[..index], 3600.0),
+                _ => (text, 1.0),
+            };
+            let value = value.trim().parse().map_err(|_| {
+                serde::de::Error::custom(format!(
+                    "invalid duration {text}; should be a number of seconds, or a number followed by s, m or h"
+                ))
+            })?;
+            (value, unit)
+        }
+    };
+    Duration::try_from_secs_f64(value * unit)
+        .map_err(|_| serde::de::Error::custom("durations can't be negative"))
+}
@@ -0,0 +1,165 @@
+use eyre::{eyre, Context};
+use futures_util::{SinkExt, StreamExt};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// The largest S2 message that can be sent or received over MQTT; forecasts easily exceed the default of 10 kB.
+const MAX_MQTT_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// What the S2 messages go over: a WebSocket, or a pair of topics on an MQTT broker.
+///
+/// Both look like a WebSocket to the connection: messages from MQTT come in as text frames, so everything on top of the
+/// transport, from the handshake to reception statuses, works the same.
+pub(crate) enum Transport {
+    WebSocket(Box<WebSocketStream<MaybeTlsStream<TcpStream>>>),
+    Mqtt(MqttTransport),
+}
+
+impl Transport {
+    /// Waits for the next frame from the CEM; `None` once the connection is closed.
+    pub(crate) async fn next(&mut self) -> Option<eyre::Result<WebSocketMessage>> {
+        match self {
+            Transport::WebSocket(socket) => socket
+                .next()
+                .await
+                .map(|frame| frame.map_err(eyre::Report::from)),
+            Transport::Mqtt(mqtt) => mqtt.next().await,
+        }
+    }
+
+    pub(crate) async fn send(&mut self, frame: WebSocketMessage) -> eyre::Result<()> {
+        match self {
+            Transport::WebSocket(socket) => Ok(socket.send(frame).await?),
+            Transport::Mqtt(mqtt) => mqtt.send(frame).await,
+        }
+    }
+
+    pub(crate) async fn close(&mut self) {
+        // The other side may have closed the connection already, so errors don't matter here.
+        match self {
+            Transport::WebSocket(socket) => {
+                let _ = WebSocketStream::close(socket, None).await;
+            }
+            Transport::Mqtt(mqtt) => {
+                let _ = mqtt.client.disconnect().await;
+            }
+        }
+    }
+
+    /// Whether the CEM answers pings. Over MQTT, the client keeps the connection with the broker alive by itself.
+    pub(crate) fn answers_pings(&self) -> bool {
+        matches!(self, Transport::WebSocket(_))
+    }
+}
+
+/// Exchanges S2 messages with the CEM through an MQTT broker: the simulator publishes its messages to
+/// `<prefix>/s2/rm-to-cem`, and the CEM publishes its messages to `<prefix>/s2/cem-to-rm`.
+///
+/// The client runs in the background. When its connection with the broker is lost, the transport is done, like a
+/// WebSocket that is closed, and the connection connects again with a new one.
+pub(crate) struct MqttTransport {
+    client: AsyncClient,
+    /// The topic the messages for the CEM are published to.
+    to_cem: String,
+    incoming: UnboundedReceiver<Incoming>,
+    event_loop: JoinHandle<()>,
+}
+
+enum Incoming {
+    /// The client subscribed to the messages from the CEM.
+    Subscribed,
+    Message(Vec<u8>),
+    /// The connection with the broker was lost, for this reason.
+    Lost(String),
+}
+
+impl MqttTransport {
+    /// Connects to the broker, and waits until the client is subscribed to the messages from the CEM, so it doesn't
+    /// miss the answer to the first message it sends.
+    pub(crate) async fn connect(mut options: MqttOptions, prefix: &str) -> eyre::Result<Self> {
+        let (host, port) = options.broker_address();
+        options.set_max_packet_size(MAX_MQTT_MESSAGE_SIZE, MAX_MQTT_MESSAGE_SIZE);
+        let from_cem = format!("{prefix}/s2/cem-to-rm");
+        let (client, mut event_loop) = AsyncClient::new(options, 100);
+        let (sender, mut incoming) = mpsc::unbounded_channel();
+        let subscriber = client.clone();
+        let event_loop = tokio::spawn(async move {
+            loop {
+                let incoming = match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        if let Err(error) = subscriber.try_subscribe(&from_cem, QoS::AtLeastOnce) {
+                            Incoming::Lost(error.to_string())
+                        } else {
+                            continue;
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::SubAck(_))) => Incoming::Subscribed,
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        Incoming::Message(publish.payload.to_vec())
+                    }
+                    Ok(_) => continue,
+                    Err(error) => Incoming::Lost(error.to_string()),
+                };
+                let lost = matches!(incoming, Incoming::Lost(_));
+                if sender.send(incoming).is_err() || lost {
+                    break;
+                }
+            }
+        });
+
+        match incoming.recv().await {
+            Some(Incoming::Subscribed) => {}
+            Some(Incoming::Lost(error)) => {
+                return Err(eyre!(
+                    "Could not connect to MQTT broker {host}:{port}: {error}"
+                ))
+            }
+            _ => return Err(eyre!("Could not connect to MQTT broker {host}:{port}")),
+        }
+        tracing::info!(
+            "Exchanging S2 messages with the CEM through MQTT broker {host}:{port}, on {prefix}/s2/"
+        );
+        Ok(Self {
+            client,
+            to_cem: format!("{prefix}/s2/rm-to-cem"),
+            incoming,
+            event_loop,
+        })
+    }
+
+    async fn next(&mut self) -> Option<eyre::Result<WebSocketMessage>> {
+        loop {
+            return match self.incoming.recv().await? {
+                Incoming::Message(payload) => Some(Ok(match String::from_utf8(payload) {
+                    Ok(text) => WebSocketMessage::Text(text),
+                    Err(error) => WebSocketMessage::Binary(error.into_bytes()),
+                })),
+                Incoming::Lost(error) => Some(Err(eyre!(
+                    "Lost the connection with the MQTT broker: {error}"
+                ))),
+                Incoming::Subscribed => continue,
+            };
+        }
+    }
+
+    async fn send(&mut self, frame: WebSocketMessage) -> eyre::Result<()> {
+        // Pings and close frames are about the WebSocket, so there's nothing to send for them.
+        let WebSocketMessage::Text(text) = frame else {
+            return Ok(());
+        };
+        self.client
+            .publish(&self.to_cem, QoS::AtLeastOnce, false, text)
+            .await
+            .wrap_err("Could not publish to the MQTT broker")
+    }
+}
+
+impl Drop for MqttTransport {
+    fn drop(&mut self) {
+        self.event_loop.abort();
+    }
+}
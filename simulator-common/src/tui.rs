@@ -0,0 +1,268 @@
+use crate::dashboard::{push_bounded, LoggedMessage};
+use crate::monitor::Monitor;
+use crate::time;
+use chrono::{Local, Utc};
+use eyre::Context;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Gauge, List, ListItem, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the terminal UI is redrawn.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+/// How many log lines are kept while the terminal UI is shown.
+const LOG_LENGTH: usize = 200;
+
+/// Whether the terminal UI is shown, in which case log lines go to [`LOG`] instead of stdout.
+static SHOWN: AtomicBool = AtomicBool::new(false);
+/// The latest log lines, newest last.
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// A live view of the simulator in the terminal, instead of scrolling logs: the state of charge and the power of the
+/// device, its operation mode, the latest instructions, the state of the connection and the latest log lines.
+///
+/// It's drawn on a separate thread, which stops and restores the terminal when this is dropped. Pressing `q` (or
+/// Ctrl-C, as the terminal doesn't turn that into a signal while the UI is shown) stops the simulation.
+pub(crate) struct Tui {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Tui {
+    /// Takes over the terminal and starts drawing the state of the simulator in it.
+    pub(crate) fn start(monitor: Arc<Monitor>) -> eyre::Result<Self> {
+        let terminal = ratatui::try_init().wrap_err("Could not start the terminal UI")?;
+        SHOWN.store(true, Ordering::SeqCst);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                if let Err(error) = draw_until_stopped(terminal, &monitor, &stop) {
+                    tracing::error!("The terminal UI stopped: {error}");
+                }
+            }
+        });
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        ratatui::restore();
+        SHOWN.store(false, Ordering::SeqCst);
+        // Show what was logged while the UI was shown, as it would have been without it.
+        let mut stdout = io::stdout().lock();
+        for line in LOG.lock().unwrap().drain(..) {
+            let _ = writeln!(stdout, "{line}");
+        }
+    }
+}
+
+fn draw_until_stopped(
+    mut terminal: DefaultTerminal,
+    monitor: &Monitor,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    while !stop.load(Ordering::SeqCst) {
+        terminal.draw(|frame| draw(frame, monitor))?;
+        if !event::poll(REFRESH_INTERVAL)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || ctrl_c) {
+                monitor.stop.notify_one();
+            }
+        }
+    }
+    Ok(())
+}
+
+fn draw(frame: &mut Frame, monitor: &Monitor) {
+    let health = monitor.health.state();
+    let dashboard = monitor.dashboard.state();
+    let device = &dashboard.device;
+    let [connection_area, gauges_area, device_area, instructions_area, log_area] =
+        Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Length(12),
+            Constraint::Min(3),
+        ])
+        .areas(frame.area());
+
+    let connection = match (health.connected, health.control_type) {
+        (false, _) => Span::styled("Disconnected", Style::new().fg(Color::Red).bold()),
+        (true, None) => Span::styled("Setting up the session", Style::new().fg(Color::Yellow)),
+        (true, Some(control_type)) => Span::styled(
+            format!("Connected, control type {control_type:?}"),
+            Style::new().fg(Color::Green).bold(),
+        ),
+    };
+    let last_message = match health.last_message_received {
+        Some(received) => format!(
+            "last message from the CEM {} s ago",
+            (Utc::now() - received).num_seconds()
+        ),
+        None => "no messages from the CEM yet".into(),
+    };
+    let connection = Line::from(vec![
+        connection,
+        Span::raw(format!(
+            " · {last_message} · simulated time {}",
+            time::now().format("%Y-%m-%d %H:%M:%S")
+        )),
+    ]);
+    frame.render_widget(
+        Paragraph::new(connection).block(Block::bordered().title(" S2 simulator (q to quit) ")),
+        connection_area,
+    );
+
+    let [state_of_charge_area, power_area] =
+        Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(gauges_area);
+    let state_of_charge = device.state_of_charge.unwrap_or_default().clamp(0.0, 1.0);
+    let label = match device.state_of_charge {
+        Some(state_of_charge) => format!("{:.1} %", state_of_charge * 100.0),
+        None => "–".into(),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(" State of charge "))
+            .gauge_style(Color::Cyan)
+            .ratio(state_of_charge)
+            .label(label),
+        state_of_charge_area,
+    );
+    let power = device.power_w.unwrap_or_default();
+    let ratio = match device.rated_power_w {
+        Some(rated_power) if rated_power > 0.0 => (power.abs() / rated_power).clamp(0.0, 1.0),
+        _ => 0.0,
+    };
+    // The gauge looks the same for consumption and production, so the label tells them apart.
+    let label = match device.power_w {
+        Some(power) if power < 0.0 => format!("{:.0} W (producing)", power),
+        Some(power) if power > 0.0 => format!("{:.0} W (consuming)", power),
+        Some(_) => "0 W".into(),
+        None => "–".into(),
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(" Power "))
+            .gauge_style(if power < 0.0 {
+                Color::Yellow
+            } else {
+                Color::Magenta
+            })
+            .ratio(ratio)
+            .label(label),
+        power_area,
+    );
+
+    let curtailment = match device.curtailment_w {
+        Some(curtailment) => format!(" · curtailing {curtailment:.0} W"),
+        None => String::new(),
+    };
+    let operation_mode = device.operation_mode.as_deref().unwrap_or("–");
+    frame.render_widget(
+        Paragraph::new(format!("{operation_mode}{curtailment}"))
+            .block(Block::bordered().title(" Operation mode ")),
+        device_area,
+    );
+
+    let instructions: Vec<ListItem> = dashboard
+        .instructions
+        .iter()
+        .rev()
+        .map(instruction_line)
+        .collect();
+    frame.render_widget(
+        List::new(instructions).block(Block::bordered().title(" Latest instructions ")),
+        instructions_area,
+    );
+
+    draw_log(frame, log_area);
+}
+
+/// Describes an instruction or an instruction status update in a single line.
+fn instruction_line(logged: &LoggedMessage) -> ListItem<'static> {
+    let message = &logged.message;
+    let details = match message["status_type"].as_str() {
+        Some(status) => format!(
+            "{status} for {}",
+            message["instruction_id"].as_str().unwrap_or_default()
+        ),
+        None => message["id"].as_str().unwrap_or_default().to_string(),
+    };
+    ListItem::new(Line::from(vec![
+        Span::styled(
+            logged
+                .timestamp
+                .with_timezone(&Local)
+                .format("%H:%M:%S ")
+                .to_string(),
+            Style::new().dim(),
+        ),
+        Span::raw(format!(
+            "{:<8} {:<24} {details}",
+            logged.direction.as_str(),
+            logged.message_type
+        )),
+    ]))
+}
+
+fn draw_log(frame: &mut Frame, area: Rect) {
+    let height = area.height.saturating_sub(2) as usize;
+    let log = LOG.lock().unwrap();
+    let lines: Vec<Line> = log
+        .iter()
+        .skip(log.len().saturating_sub(height))
+        .map(|line| Line::raw(line.clone()))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Log ")),
+        area,
+    );
+}
+
+/// Where the log goes: to the terminal UI while it's shown, and to stdout otherwise.
+pub(crate) struct LogWriter;
+
+impl LogWriter {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !SHOWN.load(Ordering::SeqCst) {
+            return io::stdout().write(buf);
+        }
+        let mut log = LOG.lock().unwrap();
+        for line in String::from_utf8_lossy(buf).lines() {
+            push_bounded(&mut log, line.to_string(), LOG_LENGTH);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
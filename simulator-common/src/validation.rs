@@ -0,0 +1,239 @@
+use crate::time;
+use chrono::{DateTime, TimeDelta, Utc};
+use s2energy::common::{ControlType, Id, Message, ReceptionStatusValues, RevokableObjects};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+/// How far the execution time of an instruction may be from the current time. Anything further away most likely comes
+/// from a clock that is off, or from a mix-up between seconds and milliseconds.
+const MAX_TIMESTAMP_SKEW: TimeDelta = TimeDelta::days(7);
+
+/// Why a message from the CEM is rejected, as reported to the CEM in the reception status.
+#[derive(Debug)]
+pub(crate) struct Rejection {
+    pub(crate) status: ReceptionStatusValues,
+    pub(crate) reason: String,
+}
+
+impl Rejection {
+    /// A message that is valid S2, but doesn't make sense in this session.
+    fn invalid_content(reason: impl Into<String>) -> Self {
+        Self {
+            status: ReceptionStatusValues::InvalidContent,
+            reason: reason.into(),
+        }
+    }
+
+    /// A message that the simulator couldn't process.
+    pub(crate) fn permanent_error(reason: impl Into<String>) -> Self {
+        Self {
+            status: ReceptionStatusValues::PermanentError,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?})", self.reason, self.status)
+    }
+}
+
+/// Checks the messages from the CEM before they're processed, and keeps track of what they may refer to.
+///
+/// Messages that can be deserialized can still be nonsense: an instruction for a control type that isn't selected, an
+/// operation mode factor outside of 0 to 1, or an execution time decades ago. Those are rejected with an
+/// `INVALID_CONTENT` reception status, so the CEM learns about it instead of the simulator acting on them. Whether the
+/// device can execute an instruction is up to the simulator, which answers with an instruction status.
+pub(crate) struct Validator {
+    available_control_types: Vec<ControlType>,
+    /// The control type the CEM selected in the current session.
+    control_type: Option<ControlType>,
+    /// The instructions the CEM sent in the current session, by ID, so they can be revoked.
+    instructions: HashMap<Id, RevokableObjects>,
+}
+
+impl Validator {
+    pub(crate) fn new(available_control_types: Vec<ControlType>) -> Self {
+        Self {
+            available_control_types,
+            control_type: None,
+            instructions: HashMap::new(),
+        }
+    }
+
+    /// Starts over for a new session, in which the CEM selected the given control type.
+    pub(crate) fn start_session(&mut self, control_type: ControlType) {
+        self.control_type = Some(control_type);
+        self.instructions.clear();
+    }
+
+    /// Switches to the control type the CEM selected during the session; the instructions for the previous selection
+    /// no longer apply.
+    pub(crate) fn select_control_type(&mut self, control_type: ControlType) {
+        self.start_session(control_type);
+    }
+
+    /// Checks a message from the CEM, and returns why it should be rejected if it's invalid.
+    pub(crate) fn validate(&mut self, message: &Message) -> Result<(), Rejection> {
+        match message {
+            Message::SelectControlType(select_control_type) => {
+                let control_type = select_control_type.control_type;
+                // Any device can be switched to no control at all.
+                if control_type != ControlType::NoSelection
+                    && !crate::is_supported(&self.available_control_types, control_type)
+                {
+                    return Err(Rejection::invalid_content(format!(
+                        "This simulator doesn't support control type {control_type:?}"
+                    )));
+                }
+                Ok(())
+            }
+            Message::FrbcInstruction(instruction) => {
+                check_factor(instruction.operation_mode_factor)?;
+                self.instruction(
+                    ControlType::FillRateBasedControl,
+                    RevokableObjects::FrbcInstruction,
+                    &instruction.id,
+                    instruction.execution_time,
+                )
+            }
+            Message::OmbcInstruction(instruction) => {
+                check_factor(instruction.operation_mode_factor)?;
+                self.instruction(
+                    ControlType::OperationModeBasedControl,
+                    RevokableObjects::OmbcInstruction,
+                    &instruction.id,
+                    instruction.execution_time,
+                )
+            }
+            Message::DdbcInstruction(instruction) => {
+                check_factor(instruction.operation_mode_factor)?;
+                self.instruction(
+                    ControlType::DemandDrivenBasedControl,
+                    RevokableObjects::DdbcInstruction,
+                    &instruction.id,
+                    instruction.execution_time,
+                )
+            }
+            Message::PebcInstruction(instruction) => {
+                let inverted = instruction.power_envelopes.iter().any(|envelope| {
+                    envelope
+                        .power_envelope_elements
+                        .iter()
+                        .any(|element| element.lower_limit > element.upper_limit)
+                });
+                if inverted {
+                    return Err(Rejection::invalid_content(
+                        "A power envelope has a lower limit above its upper limit",
+                    ));
+                }
+                self.instruction(
+                    ControlType::PowerEnvelopeBasedControl,
+                    RevokableObjects::PebcInstruction,
+                    &instruction.id,
+                    instruction.execution_time,
+                )
+            }
+            Message::PpbcScheduleInstruction(instruction) => self.instruction(
+                ControlType::PowerProfileBasedControl,
+                RevokableObjects::PpbcScheduleInstruction,
+                &instruction.id,
+                instruction.execution_time,
+            ),
+            Message::PpbcStartInterruptionInstruction(instruction) => self.instruction(
+                ControlType::PowerProfileBasedControl,
+                RevokableObjects::PpbcStartInterruptionInstruction,
+                &instruction.id,
+                instruction.execution_time,
+            ),
+            Message::PpbcEndInterruptionInstruction(instruction) => self.instruction(
+                ControlType::PowerProfileBasedControl,
+                RevokableObjects::PpbcEndInterruptionInstruction,
+                &instruction.id,
+                instruction.execution_time,
+            ),
+            Message::RevokeObject(revoke) => {
+                match self.instructions.get(&revoke.object_id) {
+                    Some(object_type) if *object_type == revoke.object_type => {}
+                    Some(object_type) => {
+                        return Err(Rejection::invalid_content(format!(
+                            "{} is a {}, not a {}",
+                            revoke.object_id.as_str(),
+                            object_type.to_string(),
+                            revoke.object_type.to_string()
+                        )))
+                    }
+                    // This also covers the revokable objects that only the RM sends, such as system descriptions.
+                    None => {
+                        return Err(Rejection::invalid_content(format!(
+                            "There is no {} {} to revoke",
+                            revoke.object_type.to_string(),
+                            revoke.object_id.as_str()
+                        )))
+                    }
+                }
+                self.instructions.remove(&revoke.object_id);
+                Ok(())
+            }
+            Message::ResourceManagerDetails(_)
+            | Message::PowerMeasurement(_)
+            | Message::PowerForecast(_)
+            | Message::InstructionStatusUpdate(_)
+            | Message::FrbcSystemDescription(_)
+            | Message::FrbcActuatorStatus(_)
+            | Message::FrbcStorageStatus(_)
+            | Message::FrbcLeakageBehaviour(_)
+            | Message::FrbcUsageForecast(_)
+            | Message::FrbcFillLevelTargetProfile(_)
+            | Message::FrbcTimerStatus(_)
+            | Message::OmbcSystemDescription(_)
+            | Message::OmbcStatus(_)
+            | Message::OmbcTimerStatus(_)
+            | Message::PebcPowerConstraints(_)
+            | Message::PebcEnergyConstraint(_)
+            | Message::PpbcPowerProfileDefinition(_)
+            | Message::PpbcPowerProfileStatus(_)
+            | Message::DdbcSystemDescription(_)
+            | Message::DdbcActuatorStatus(_)
+            | Message::DdbcAverageDemandRateForecast(_)
+            | Message::DdbcTimerStatus(_) => Err(Rejection::invalid_content(
+                "Only a resource manager sends this message",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks an instruction for the given control type, and remembers it if it's valid.
+    fn instruction(
+        &mut self,
+        control_type: ControlType,
+        object_type: RevokableObjects,
+        id: &Id,
+        execution_time: DateTime<Utc>,
+    ) -> Result<(), Rejection> {
+        if self.control_type != Some(control_type) {
+            return Err(Rejection::invalid_content(format!(
+                "The instruction is for {control_type:?}, but the selected control type is {:?}",
+                self.control_type.unwrap_or(ControlType::NoSelection)
+            )));
+        }
+        let now = time::now();
+        if (execution_time - now).abs() > MAX_TIMESTAMP_SKEW {
+            return Err(Rejection::invalid_content(format!(
+                "The execution time {execution_time} is too far from the current time {now}"
+            )));
+        }
+        self.instructions.insert(id.clone(), object_type);
+        Ok(())
+    }
+}
+
+fn check_factor(operation_mode_factor: f64) -> Result<(), Rejection> {
+    if !(0.0..=1.0).contains(&operation_mode_factor) {
+        return Err(Rejection::invalid_content(format!(
+            "The operation mode factor {operation_mode_factor} is outside of 0 to 1"
+        )));
+    }
+    Ok(())
+}